@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use secrecy::Secret;
+
+use crate::{enable_audit_device_request, list_audit_devices_request, BytesBody, HttpRequest};
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AuditDevices {
+    pub data: BTreeMap<String, AuditDevice>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AuditDevice {
+    #[serde(rename = "type")]
+    pub type_: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub options: Option<BTreeMap<String, String>>,
+    #[serde(default)]
+    pub local: bool,
+    #[serde(default)]
+    pub path: String,
+}
+
+/// List the enabled audit devices
+#[async_trait::async_trait]
+pub trait ListAuditDevices {
+    /// List the enabled audit devices by their path
+    async fn audit_devices(
+        &mut self,
+        token: Secret<String>,
+    ) -> anyhow::Result<BTreeMap<String, AuditDevice>>;
+}
+
+#[async_trait::async_trait]
+impl<T> ListAuditDevices for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn audit_devices(
+        &mut self,
+        token: Secret<String>,
+    ) -> anyhow::Result<BTreeMap<String, AuditDevice>> {
+        let http_req = list_audit_devices_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("listing audit devices: {}", body));
+        }
+
+        let devices: AuditDevices =
+            serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?;
+
+        Ok(devices.data)
+    }
+}
+
+/// Enable an audit device
+#[async_trait::async_trait]
+pub trait EnableAuditDevice {
+    /// Enable an audit device of the given type at the path
+    async fn enable_audit_device(
+        &mut self,
+        token: Secret<String>,
+        path: &str,
+        device: &AuditDevice,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<T> EnableAuditDevice for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn enable_audit_device(
+        &mut self,
+        token: Secret<String>,
+        path: &str,
+        device: &AuditDevice,
+    ) -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "type": device.type_,
+            "description": device.description,
+            "options": device.options.clone().unwrap_or_default(),
+            "local": device.local,
+        });
+
+        let http_req = enable_audit_device_request(
+            token,
+            path,
+            Full::new(Bytes::from(body.to_string())).boxed(),
+        )?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!("enabling audit device {}: {}", path, body));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, str::FromStr};
+
+    use http::{Method, StatusCode};
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{AuditDevice, EnableAuditDevice, HttpForwarderService, ListAuditDevices};
+
+    async fn client(mock_server: &MockServer) -> HttpForwarderService<crate::BytesBody> {
+        HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn listing_audit_devices_works() {
+        let mock_server = MockServer::start().await;
+
+        let device = serde_json::json!({
+            "type": "file",
+            "description": "",
+            "options": { "file_path": "/vault/audit/audit.log" },
+            "local": false,
+            "path": "file/",
+        });
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/sys/audit"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "file/": device,
+                    "request_id": "",
+                    "lease_id": "",
+                    "renewable": false,
+                    "lease_duration": 0,
+                    "data": { "file/": device },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let devices = client(&mock_server)
+            .await
+            .audit_devices(Secret::from_str("abc").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices["file/"].type_, "file");
+        assert_eq!(
+            devices["file/"].options.as_ref().unwrap()["file_path"],
+            "/vault/audit/audit.log"
+        );
+    }
+
+    #[tokio::test]
+    async fn enabling_audit_device_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/audit/file"))
+            .and(header("X-Vault-Token", "abc"))
+            .and(body_json(serde_json::json!({
+                "type": "file",
+                "description": "",
+                "options": { "file_path": "/vault/audit.log" },
+                "local": false,
+            })))
+            .respond_with(ResponseTemplate::new(StatusCode::NO_CONTENT))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = client(&mock_server)
+            .await
+            .enable_audit_device(
+                Secret::from_str("abc").unwrap(),
+                "file/",
+                &AuditDevice {
+                    type_: "file".to_string(),
+                    description: String::new(),
+                    options: Some(BTreeMap::from([(
+                        "file_path".to_string(),
+                        "/vault/audit.log".to_string(),
+                    )])),
+                    local: false,
+                    path: String::new(),
+                },
+            )
+            .await;
+
+        assert!(outcome.is_ok());
+    }
+}
@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use secrecy::{ExposeSecret, Secret};
+
+use crate::{kubernetes_login_request, BytesBody, HttpRequest};
+
+/// Path of the service account token mounted into pods
+pub const SERVICE_ACCOUNT_TOKEN_PATH: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+
+#[derive(Debug, serde::Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
+/// Log in with the kubernetes auth method
+#[async_trait::async_trait]
+pub trait KubernetesLogin {
+    /// Exchange the service account token for a vault token of the role
+    async fn kubernetes_login(
+        &mut self,
+        mount: &str,
+        role: &str,
+        jwt: Secret<String>,
+    ) -> anyhow::Result<Secret<String>>;
+}
+
+#[async_trait::async_trait]
+impl<T> KubernetesLogin for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn kubernetes_login(
+        &mut self,
+        mount: &str,
+        role: &str,
+        jwt: Secret<String>,
+    ) -> anyhow::Result<Secret<String>> {
+        let body = serde_json::json!({
+            "role": role,
+            "jwt": jwt.expose_secret(),
+        });
+
+        let http_req =
+            kubernetes_login_request(mount, Full::new(Bytes::from(body.to_string())).boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8_lossy(&body);
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!(
+                "logging in with role {} at auth/{}: {}",
+                role,
+                mount,
+                body
+            ));
+        }
+
+        let response: LoginResponse =
+            serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?;
+
+        Ok(Secret::new(response.auth.client_token))
+    }
+}
+
+/// Kubernetes auth method of a vault trusting the cluster vault-mgmt runs in,
+/// used to get a token with the service account token of vault-mgmt
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KubernetesAuth {
+    /// mount path of the auth method, e.g. `kubernetes`
+    pub mount: String,
+    pub role: String,
+    /// file containing the service account token
+    pub jwt_path: PathBuf,
+}
+
+impl KubernetesAuth {
+    pub fn new(role: &str) -> Self {
+        Self {
+            mount: "kubernetes".to_string(),
+            role: role.to_string(),
+            jwt_path: PathBuf::from(SERVICE_ACCOUNT_TOKEN_PATH),
+        }
+    }
+
+    /// Set the mount path of the auth method
+    pub fn mount(mut self, mount: &str) -> Self {
+        self.mount = mount.trim_matches('/').to_string();
+        self
+    }
+
+    /// Set the file containing the service account token
+    pub fn jwt_path(mut self, jwt_path: PathBuf) -> Self {
+        self.jwt_path = jwt_path;
+        self
+    }
+
+    /// Log in with the service account token
+    pub async fn login(
+        &self,
+        client: &mut (impl KubernetesLogin + Send),
+    ) -> anyhow::Result<Secret<String>> {
+        let jwt = tokio::fs::read_to_string(&self.jwt_path)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "reading service account token {}: {}",
+                    self.jwt_path.display(),
+                    e
+                )
+            })?;
+
+        client
+            .kubernetes_login(&self.mount, &self.role, Secret::new(jwt.trim().to_string()))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
+    use secrecy::ExposeSecret;
+    use wiremock::{
+        matchers::{body_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{HttpForwarderService, KubernetesAuth};
+
+    #[tokio::test]
+    async fn kubernetes_login_returns_client_token() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/auth/workload-cluster/login"))
+            .and(body_json(serde_json::json!({
+                "role": "vault-mgmt",
+                "jwt": "sa-token",
+            })))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "auth": {
+                        "client_token": "hvs.abc",
+                        "policies": ["unseal-keys"],
+                        "lease_duration": 3600,
+                        "renewable": true,
+                    },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let jwt = std::env::temp_dir().join(format!("vault-mgmt-jwt-{}", std::process::id()));
+        tokio::fs::write(&jwt, "sa-token\n").await.unwrap();
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let token = KubernetesAuth::new("vault-mgmt")
+            .mount("/workload-cluster/")
+            .jwt_path(jwt.clone())
+            .login(&mut client)
+            .await
+            .unwrap();
+
+        tokio::fs::remove_file(jwt).await.unwrap();
+
+        assert_eq!(token.expose_secret(), "hvs.abc");
+    }
+}
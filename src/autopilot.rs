@@ -0,0 +1,371 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use secrecy::Secret;
+
+use crate::{
+    autopilot_configuration_request, autopilot_state_request, set_autopilot_configuration_request,
+    BytesBody, HttpRequest,
+};
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AutopilotState {
+    pub request_id: String,
+    pub lease_id: String,
+    pub renewable: bool,
+    pub lease_duration: u64,
+    pub data: AutopilotStateData,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AutopilotStateData {
+    pub healthy: bool,
+    pub failure_tolerance: u32,
+    pub leader: String,
+    pub voters: Vec<String>,
+    pub servers: BTreeMap<String, AutopilotServer>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AutopilotServer {
+    pub id: String,
+    pub name: String,
+    pub address: String,
+    pub node_status: String,
+    pub healthy: bool,
+    pub status: String,
+    pub version: Option<String>,
+    pub last_contact: Option<String>,
+    pub last_index: Option<u64>,
+    pub stable_since: Option<String>,
+}
+
+/// Get the autopilot state of the raft cluster
+#[async_trait::async_trait]
+pub trait GetAutopilotState {
+    /// Get the autopilot state of the raft cluster
+    async fn autopilot_state(&mut self, token: Secret<String>) -> anyhow::Result<AutopilotState>;
+}
+
+#[async_trait::async_trait]
+impl<T> GetAutopilotState for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn autopilot_state(&mut self, token: Secret<String>) -> anyhow::Result<AutopilotState> {
+        let http_req = autopilot_state_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("getting autopilot state: {}", body));
+        }
+
+        Ok(serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?)
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AutopilotConfigurationResponse {
+    pub data: AutopilotConfiguration,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct AutopilotConfiguration {
+    pub cleanup_dead_servers: bool,
+    pub last_contact_threshold: String,
+    pub dead_server_last_contact_threshold: String,
+    pub max_trailing_logs: u64,
+    pub min_quorum: u32,
+    pub server_stabilization_time: String,
+    #[serde(default)]
+    pub disable_upgrade_migration: bool,
+}
+
+/// Changes to the autopilot configuration, unset values are left unchanged
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AutopilotConfigurationUpdate {
+    pub cleanup_dead_servers: Option<bool>,
+    pub last_contact_threshold: Option<Duration>,
+    pub dead_server_last_contact_threshold: Option<Duration>,
+    pub max_trailing_logs: Option<u64>,
+    pub min_quorum: Option<u32>,
+    pub server_stabilization_time: Option<Duration>,
+}
+
+impl AutopilotConfigurationUpdate {
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut body = serde_json::Map::new();
+        let seconds = |d: &Duration| serde_json::Value::from(format!("{}s", d.as_secs()));
+
+        if let Some(cleanup) = self.cleanup_dead_servers {
+            body.insert("cleanup_dead_servers".to_string(), cleanup.into());
+        }
+        if let Some(threshold) = &self.last_contact_threshold {
+            body.insert("last_contact_threshold".to_string(), seconds(threshold));
+        }
+        if let Some(threshold) = &self.dead_server_last_contact_threshold {
+            body.insert(
+                "dead_server_last_contact_threshold".to_string(),
+                seconds(threshold),
+            );
+        }
+        if let Some(logs) = self.max_trailing_logs {
+            body.insert("max_trailing_logs".to_string(), logs.into());
+        }
+        if let Some(quorum) = self.min_quorum {
+            body.insert("min_quorum".to_string(), quorum.into());
+        }
+        if let Some(time) = &self.server_stabilization_time {
+            body.insert("server_stabilization_time".to_string(), seconds(time));
+        }
+
+        serde_json::Value::Object(body)
+    }
+}
+
+/// Get and set the autopilot configuration of the raft cluster
+#[async_trait::async_trait]
+pub trait AutopilotConfig {
+    /// Get the autopilot configuration of the raft cluster
+    async fn autopilot_configuration(
+        &mut self,
+        token: Secret<String>,
+    ) -> anyhow::Result<AutopilotConfiguration>;
+
+    /// Change the given values of the autopilot configuration
+    async fn set_autopilot_configuration(
+        &mut self,
+        token: Secret<String>,
+        update: &AutopilotConfigurationUpdate,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<T> AutopilotConfig for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn autopilot_configuration(
+        &mut self,
+        token: Secret<String>,
+    ) -> anyhow::Result<AutopilotConfiguration> {
+        let http_req = autopilot_configuration_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("getting autopilot configuration: {}", body));
+        }
+
+        let response: AutopilotConfigurationResponse =
+            serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?;
+
+        Ok(response.data)
+    }
+
+    async fn set_autopilot_configuration(
+        &mut self,
+        token: Secret<String>,
+        update: &AutopilotConfigurationUpdate,
+    ) -> anyhow::Result<()> {
+        let http_req = set_autopilot_configuration_request(
+            token,
+            Full::new(Bytes::from(update.to_json().to_string())).boxed(),
+        )?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!("setting autopilot configuration: {}", body));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        AutopilotConfig, AutopilotConfigurationUpdate, GetAutopilotState, HttpForwarderService,
+    };
+
+    fn autopilot_state(healthy: bool) -> serde_json::Value {
+        serde_json::json!({
+            "request_id": "",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": {
+                "healthy": healthy,
+                "failure_tolerance": 1,
+                "leader": "vault-0",
+                "voters": ["vault-0", "vault-1", "vault-2"],
+                "servers": {
+                    "vault-0": {
+                        "id": "vault-0",
+                        "name": "vault-0",
+                        "address": "vault-0.vault-internal:8201",
+                        "node_status": "alive",
+                        "healthy": true,
+                        "status": "leader",
+                        "version": "1.13.0",
+                        "last_contact": "0s",
+                        "last_index": 42,
+                    },
+                    "vault-1": {
+                        "id": "vault-1",
+                        "name": "vault-1",
+                        "address": "vault-1.vault-internal:8201",
+                        "node_status": "alive",
+                        "healthy": healthy,
+                        "status": "voter",
+                        "version": "1.13.0",
+                        "last_contact": "1.2s",
+                        "last_index": 42,
+                    },
+                    "vault-2": {
+                        "id": "vault-2",
+                        "name": "vault-2",
+                        "address": "vault-2.vault-internal:8201",
+                        "node_status": "alive",
+                        "healthy": true,
+                        "status": "voter",
+                        "version": "1.13.0",
+                        "last_contact": "0.8s",
+                        "last_index": 42,
+                    },
+                },
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn getting_autopilot_state_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/storage/raft/autopilot/state"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(autopilot_state(false)))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let state = client
+            .autopilot_state(Secret::from_str("abc").unwrap())
+            .await
+            .unwrap();
+
+        assert!(!state.data.healthy);
+        assert_eq!(state.data.leader, "vault-0");
+        assert_eq!(state.data.servers.len(), 3);
+        assert!(!state.data.servers["vault-1"].healthy);
+    }
+
+    #[tokio::test]
+    async fn getting_autopilot_configuration_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/storage/raft/autopilot/configuration"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "data": {
+                    "cleanup_dead_servers": false,
+                    "last_contact_threshold": "10s",
+                    "dead_server_last_contact_threshold": "24h0m0s",
+                    "max_trailing_logs": 1000,
+                    "min_quorum": 0,
+                    "server_stabilization_time": "10s",
+                    "disable_upgrade_migration": false,
+                },
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let config = client
+            .autopilot_configuration(Secret::from_str("abc").unwrap())
+            .await
+            .unwrap();
+
+        assert!(!config.cleanup_dead_servers);
+        assert_eq!(config.server_stabilization_time, "10s");
+        assert_eq!(config.max_trailing_logs, 1000);
+    }
+
+    #[tokio::test]
+    async fn setting_autopilot_configuration_only_sends_given_values() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/v1/sys/storage/raft/autopilot/configuration"))
+            .and(header("X-Vault-Token", "abc"))
+            .and(body_json(serde_json::json!({
+                "cleanup_dead_servers": true,
+                "dead_server_last_contact_threshold": "600s",
+                "min_quorum": 3,
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        client
+            .set_autopilot_configuration(
+                Secret::from_str("abc").unwrap(),
+                &AutopilotConfigurationUpdate {
+                    cleanup_dead_servers: Some(true),
+                    dead_server_last_contact_threshold: Some(Duration::from_secs(600)),
+                    min_quorum: Some(3),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use aws_sdk_secretsmanager::error::DisplayErrorContext;
+use aws_sdk_ssm::types::{ParameterStringFilter, ParameterType};
+
+use crate::{keys_from_document, KeyProvider, KeyRequest, Keys, KeysFrom};
+
+/// Credentials and region are read from the environment, the shared config files or the
+/// instance metadata, as with the aws CLI
+async fn aws_config() -> aws_config::SdkConfig {
+    aws_config::load_from_env().await
+}
+
+/// `aws-sm://<name or arn>`, the secret string holds the keys one per line or as JSON object,
+/// see `keys_from_document`
+pub struct AwsSecretsManagerKeyProvider;
+
+#[async_trait::async_trait]
+impl KeyProvider for AwsSecretsManagerKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let client = aws_sdk_secretsmanager::Client::new(&aws_config().await);
+
+        let secret = client
+            .get_secret_value()
+            .secret_id(from.location())
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", DisplayErrorContext(e)))?;
+        let text = secret.secret_string().ok_or(anyhow::anyhow!(
+            "secret {} does not have a secret string",
+            from.location()
+        ))?;
+
+        keys_from_document(text, request.kind)
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        let client = aws_sdk_secretsmanager::Client::new(&aws_config().await);
+
+        // only the metadata, so the keys are not read
+        client
+            .describe_secret()
+            .secret_id(from.location())
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", DisplayErrorContext(e)))?;
+
+        Ok(())
+    }
+}
+
+/// `aws-ssm://<path>`, a `SecureString`, `String` or `StringList` parameter holding the keys,
+/// see `keys_from_document`
+pub struct AwsSsmKeyProvider;
+
+impl AwsSsmKeyProvider {
+    /// Name of the parameter, hierarchies have to start with a slash,
+    /// so `aws-ssm://vault/keys` and `aws-ssm:///vault/keys` are the same
+    fn name(from: &KeysFrom) -> String {
+        let location = from.location();
+
+        if location.contains('/') && !location.starts_with('/') {
+            format!("/{}", location)
+        } else {
+            location.to_string()
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for AwsSsmKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let client = aws_sdk_ssm::Client::new(&aws_config().await);
+        let name = Self::name(from);
+
+        let output = client
+            .get_parameter()
+            .name(&name)
+            .with_decryption(true)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", DisplayErrorContext(e)))?;
+        let parameter = output
+            .parameter()
+            .ok_or(anyhow::anyhow!("parameter {} not found", name))?;
+        let value = parameter
+            .value()
+            .ok_or(anyhow::anyhow!("parameter {} does not have a value", name))?;
+
+        match parameter.r#type() {
+            Some(ParameterType::StringList) => {
+                keys_from_document(&value.replace(',', "\n"), request.kind)
+            }
+            _ => keys_from_document(value, request.kind),
+        }
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        let client = aws_sdk_ssm::Client::new(&aws_config().await);
+        let name = Self::name(from);
+
+        // only the metadata, so the keys are not read
+        let output = client
+            .describe_parameters()
+            .parameter_filters(
+                ParameterStringFilter::builder()
+                    .key("Name")
+                    .option("Equals")
+                    .values(&name)
+                    .build()?,
+            )
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("{}", DisplayErrorContext(e)))?;
+
+        if output.parameters().is_empty() {
+            anyhow::bail!("parameter {} not found", name);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AwsSsmKeyProvider, KeysFrom};
+
+    #[test]
+    fn ssm_parameter_hierarchies_start_with_a_slash() {
+        for (location, name) in [
+            ("vault/unseal-keys", "/vault/unseal-keys"),
+            ("/vault/unseal-keys", "/vault/unseal-keys"),
+            ("vault-unseal-keys", "vault-unseal-keys"),
+        ] {
+            assert_eq!(
+                AwsSsmKeyProvider::name(&KeysFrom::new("aws-ssm", location)),
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,503 @@
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use secrecy::{ExposeSecret, Secret};
+
+use crate::{
+    keys_from_document, BytesBody, HttpForwarderService, HttpRequest, KeyProvider, KeyRequest,
+    Keys, KeysFrom,
+};
+
+const KEY_VAULT_DOMAIN: &str = "vault.azure.net";
+const KEY_VAULT_API_VERSION: &str = "7.4";
+const DEFAULT_AUTHORITY_HOST: &str = "login.microsoftonline.com";
+/// Instance metadata service providing the tokens of managed identities
+const IMDS_ADDRESS: &str = "169.254.169.254";
+const IMDS_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `azure-kv://<vault>/<secret>[/<version>]`, the secret value holds the keys one per line
+/// or as JSON object, see `keys_from_document`
+///
+/// The vault is a name in the public cloud or a host name, e.g. `vault-mgmt.vault.azure.cn`.
+/// Tokens are requested with workload identity (`AZURE_FEDERATED_TOKEN_FILE`), a client secret
+/// (`AZURE_CLIENT_SECRET`) or the managed identity of the node, in this order.
+pub struct AzureKeyVaultKeyProvider;
+
+/// Secret in an Azure Key Vault
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AzureSecretRef {
+    pub host: String,
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl AzureSecretRef {
+    fn parse(from: &KeysFrom) -> anyhow::Result<Self> {
+        let valid =
+            |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        let parts: Vec<&str> = from.location().trim_end_matches('/').split('/').collect();
+
+        let (vault, name, version) = match parts[..] {
+            [vault, name] => (vault, name, None),
+            [vault, name, version] => (vault, name, Some(version.to_string())),
+            _ => anyhow::bail!(
+                "expected azure-kv://<vault>/<secret>[/<version>], got {}",
+                from
+            ),
+        };
+        if vault.is_empty() || !valid(name) || version.as_deref().is_some_and(|v| !valid(v)) {
+            anyhow::bail!(
+                "expected azure-kv://<vault>/<secret>[/<version>], got {}",
+                from
+            );
+        }
+
+        let host = match vault.contains('.') {
+            true => vault.to_string(),
+            false => format!("{}.{}", vault, KEY_VAULT_DOMAIN),
+        };
+
+        Ok(Self {
+            host,
+            name: name.to_string(),
+            version,
+        })
+    }
+
+    /// Resource the tokens are requested for, the domain of the vault in its cloud,
+    /// e.g. `https://vault.azure.cn` for `vault-mgmt.vault.azure.cn`
+    pub fn resource(&self) -> String {
+        let domain = self
+            .host
+            .split_once('.')
+            .map_or(KEY_VAULT_DOMAIN, |(_, domain)| domain);
+
+        format!("https://{}", domain)
+    }
+
+    /// OAuth scope of the tokens for `resource`
+    pub fn scope(&self) -> String {
+        format!("{}/.default", self.resource())
+    }
+
+    fn path(&self) -> String {
+        match &self.version {
+            Some(version) => format!(
+                "/secrets/{}/{}?api-version={}",
+                self.name, version, KEY_VAULT_API_VERSION
+            ),
+            None => format!(
+                "/secrets/{}?api-version={}",
+                self.name, KEY_VAULT_API_VERSION
+            ),
+        }
+    }
+}
+
+async fn https_client(host: &str) -> anyhow::Result<HttpForwarderService<BytesBody>> {
+    let stream = tokio::net::TcpStream::connect((host, 443))
+        .await
+        .map_err(|e| anyhow::anyhow!("connecting to {}: {}", host, e))?;
+
+    HttpForwarderService::https(host, stream).await
+}
+
+/// Percent-encode a form value
+fn form_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// How to get a token for Key Vault, read from the `AZURE_*` environment variables
+#[derive(Clone, Debug)]
+pub enum AzureCredential {
+    /// federated service account token of AKS workload identity
+    WorkloadIdentity {
+        authority_host: String,
+        tenant_id: String,
+        client_id: String,
+        token_file: String,
+    },
+    ClientSecret {
+        authority_host: String,
+        tenant_id: String,
+        client_id: String,
+        secret: Secret<String>,
+    },
+    /// managed identity of the node, the client id selects a user-assigned identity
+    ManagedIdentity { client_id: Option<String> },
+}
+
+impl AzureCredential {
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let authority_host = var("AZURE_AUTHORITY_HOST")
+            .map(|h| {
+                h.trim_start_matches("https://")
+                    .trim_end_matches('/')
+                    .to_string()
+            })
+            .unwrap_or(DEFAULT_AUTHORITY_HOST.to_string());
+
+        match (
+            var("AZURE_TENANT_ID"),
+            var("AZURE_CLIENT_ID"),
+            var("AZURE_FEDERATED_TOKEN_FILE"),
+            var("AZURE_CLIENT_SECRET"),
+        ) {
+            (Some(tenant_id), Some(client_id), Some(token_file), _) => Self::WorkloadIdentity {
+                authority_host,
+                tenant_id,
+                client_id,
+                token_file,
+            },
+            (Some(tenant_id), Some(client_id), None, Some(secret)) => Self::ClientSecret {
+                authority_host,
+                tenant_id,
+                client_id,
+                secret: Secret::new(secret),
+            },
+            (_, client_id, _, _) => Self::ManagedIdentity { client_id },
+        }
+    }
+
+    /// Get a token for the Key Vault of the secret, in the cloud of its host
+    pub async fn token(&self, target: &AzureSecretRef) -> anyhow::Result<Secret<String>> {
+        match self {
+            Self::WorkloadIdentity {
+                authority_host,
+                tenant_id,
+                client_id,
+                token_file,
+            } => {
+                let assertion = tokio::fs::read_to_string(token_file)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("reading {}: {}", token_file, e))?;
+                let form = format!(
+                    "grant_type=client_credentials&client_id={}&scope={}&client_assertion_type={}&client_assertion={}",
+                    form_encode(client_id),
+                    form_encode(&target.scope()),
+                    form_encode("urn:ietf:params:oauth:client-assertion-type:jwt-bearer"),
+                    form_encode(assertion.trim())
+                );
+
+                request_token(
+                    &mut https_client(authority_host).await?,
+                    authority_host,
+                    tenant_id,
+                    Secret::new(form),
+                )
+                .await
+            }
+            Self::ClientSecret {
+                authority_host,
+                tenant_id,
+                client_id,
+                secret,
+            } => {
+                let form = format!(
+                    "grant_type=client_credentials&client_id={}&scope={}&client_secret={}",
+                    form_encode(client_id),
+                    form_encode(&target.scope()),
+                    form_encode(secret.expose_secret())
+                );
+
+                request_token(
+                    &mut https_client(authority_host).await?,
+                    authority_host,
+                    tenant_id,
+                    Secret::new(form),
+                )
+                .await
+            }
+            Self::ManagedIdentity { client_id } => {
+                // outside of Azure the address is usually not routed, so connecting would hang
+                let stream = tokio::time::timeout(
+                    IMDS_CONNECT_TIMEOUT,
+                    tokio::net::TcpStream::connect((IMDS_ADDRESS, 80)),
+                )
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "no managed identity: {} not reachable, set AZURE_TENANT_ID, AZURE_CLIENT_ID and AZURE_FEDERATED_TOKEN_FILE or AZURE_CLIENT_SECRET",
+                        IMDS_ADDRESS
+                    )
+                })?
+                .map_err(|e| anyhow::anyhow!("connecting to {}: {}", IMDS_ADDRESS, e))?;
+
+                request_managed_identity_token(
+                    &mut HttpForwarderService::http(stream).await?,
+                    &target.resource(),
+                    client_id.as_deref(),
+                )
+                .await
+            }
+        }
+    }
+}
+
+fn access_token(status: http::StatusCode, body: &[u8]) -> anyhow::Result<Secret<String>> {
+    let body = String::from_utf8(body.to_vec())?;
+    if !status.is_success() {
+        anyhow::bail!("requesting a token for Key Vault: {}", body);
+    }
+
+    let response: serde_json::Value = serde_json::from_str(&body)?;
+    response["access_token"]
+        .as_str()
+        .map(|t| Secret::new(t.to_string()))
+        .ok_or(anyhow::anyhow!("token response has no access_token"))
+}
+
+/// Request a token with the client credentials flow of Entra ID
+pub async fn request_token(
+    client: &mut impl HttpRequest<BytesBody>,
+    authority_host: &str,
+    tenant_id: &str,
+    form: Secret<String>,
+) -> anyhow::Result<Secret<String>> {
+    let req = hyper::Request::builder()
+        .uri(format!("/{}/oauth2/v2.0/token", tenant_id))
+        .method(hyper::Method::POST)
+        .header("Host", authority_host)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(Full::new(Bytes::from(form.expose_secret().clone())).boxed())?;
+
+    let (parts, body) = client.send_request(req).await?.into_parts();
+
+    access_token(parts.status, &body)
+}
+
+/// Request a token of the managed identity from the instance metadata service
+pub async fn request_managed_identity_token(
+    client: &mut impl HttpRequest<BytesBody>,
+    resource: &str,
+    client_id: Option<&str>,
+) -> anyhow::Result<Secret<String>> {
+    let mut uri = format!(
+        "/metadata/identity/oauth2/token?api-version=2018-02-01&resource={}",
+        form_encode(resource)
+    );
+    if let Some(client_id) = client_id {
+        uri.push_str(&format!("&client_id={}", form_encode(client_id)));
+    }
+
+    let req = hyper::Request::builder()
+        .uri(uri)
+        .method(hyper::Method::GET)
+        .header("Host", IMDS_ADDRESS)
+        .header("Metadata", "true")
+        .body(Empty::<Bytes>::new().boxed())?;
+
+    let (parts, body) = client.send_request(req).await?.into_parts();
+
+    access_token(parts.status, &body)
+}
+
+/// Get the response of Key Vault for the path, e.g. the value of a secret
+pub async fn key_vault_get(
+    client: &mut impl HttpRequest<BytesBody>,
+    host: &str,
+    path: &str,
+    token: Secret<String>,
+) -> anyhow::Result<serde_json::Value> {
+    let req = hyper::Request::builder()
+        .uri(path)
+        .method(hyper::Method::GET)
+        .header("Host", host)
+        .header("Authorization", format!("Bearer {}", token.expose_secret()))
+        .body(Empty::<Bytes>::new().boxed())?;
+
+    let (parts, body) = client.send_request(req).await?.into_parts();
+    let body = String::from_utf8(body.to_vec())?;
+
+    if !parts.status.is_success() {
+        anyhow::bail!("getting {}{}: {}", host, path, body);
+    }
+
+    serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for AzureKeyVaultKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let secret = AzureSecretRef::parse(from)?;
+        let token = AzureCredential::from_env().token(&secret).await?;
+
+        let response = key_vault_get(
+            &mut https_client(&secret.host).await?,
+            &secret.host,
+            &secret.path(),
+            token,
+        )
+        .await?;
+        let value = response["value"]
+            .as_str()
+            .ok_or(anyhow::anyhow!("secret {} has no value", secret.name))?;
+
+        keys_from_document(value, request.kind)
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        let secret = AzureSecretRef::parse(from)?;
+        let token = AzureCredential::from_env().token(&secret).await?;
+
+        // only the versions of the secret, so the keys are not read
+        key_vault_get(
+            &mut https_client(&secret.host).await?,
+            &secret.host,
+            &format!(
+                "/secrets/{}/versions?api-version={}&maxresults=1",
+                secret.name, KEY_VAULT_API_VERSION
+            ),
+            token,
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use http::StatusCode;
+    use secrecy::{ExposeSecret, Secret};
+    use wiremock::{
+        matchers::{body_string, header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::form_encode;
+    use crate::{
+        key_vault_get, request_managed_identity_token, request_token, AzureSecretRef,
+        HttpForwarderService, KeysFrom,
+    };
+
+    async fn client(mock_server: &MockServer) -> HttpForwarderService<crate::BytesBody> {
+        HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn secret_refs_are_parsed() {
+        let parse = |location: &str| AzureSecretRef::parse(&KeysFrom::new("azure-kv", location));
+
+        let secret = parse("vault-mgmt/unseal-keys").unwrap();
+        assert_eq!(secret.host, "vault-mgmt.vault.azure.net");
+        assert_eq!(secret.resource(), "https://vault.azure.net");
+        assert_eq!(secret.path(), "/secrets/unseal-keys?api-version=7.4");
+
+        let secret = parse("vault-mgmt.vault.azure.cn/unseal-keys/0123abcd").unwrap();
+        assert_eq!(secret.host, "vault-mgmt.vault.azure.cn");
+        assert_eq!(secret.resource(), "https://vault.azure.cn");
+        assert_eq!(secret.scope(), "https://vault.azure.cn/.default");
+        assert_eq!(
+            secret.path(),
+            "/secrets/unseal-keys/0123abcd?api-version=7.4"
+        );
+
+        assert!(parse("vault-mgmt").is_err());
+        assert!(parse("/unseal-keys").is_err());
+        assert!(parse("vault-mgmt/unseal_keys").is_err());
+        assert!(parse("vault-mgmt/unseal-keys/1/2").is_err());
+    }
+
+    #[test]
+    fn form_values_are_encoded() {
+        assert_eq!(form_encode("abc-._~"), "abc-._~");
+        assert_eq!(
+            form_encode("https://vault.azure.net/.default"),
+            "https%3A%2F%2Fvault.azure.net%2F.default"
+        );
+        assert_eq!(form_encode("a b+c"), "a%20b%2Bc");
+    }
+
+    #[tokio::test]
+    async fn tokens_are_requested_and_secrets_read() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/tenant/oauth2/v2.0/token"))
+            .and(body_string("grant_type=client_credentials&client_secret=s"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK)
+                    .set_body_json(serde_json::json!({ "access_token": "token" })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/metadata/identity/oauth2/token"))
+            .and(header("Metadata", "true"))
+            .and(query_param("resource", "https://vault.azure.cn"))
+            .and(query_param("client_id", "identity"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK)
+                    .set_body_json(serde_json::json!({ "access_token": "managed" })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/secrets/unseal-keys"))
+            .and(header("Authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(
+                serde_json::json!({ "value": "abc\ndef", "id": "https://v/secrets/unseal-keys/1" }),
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = client(&mock_server).await;
+
+        let token = request_token(
+            &mut client,
+            "login.microsoftonline.com",
+            "tenant",
+            Secret::new("grant_type=client_credentials&client_secret=s".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(token.expose_secret(), "token");
+
+        let managed =
+            request_managed_identity_token(&mut client, "https://vault.azure.cn", Some("identity"))
+                .await
+                .unwrap();
+        assert_eq!(managed.expose_secret(), "managed");
+
+        let secret = key_vault_get(
+            &mut client,
+            "vault-mgmt.vault.azure.net",
+            "/secrets/unseal-keys?api-version=7.4",
+            token,
+        )
+        .await
+        .unwrap();
+        assert_eq!(secret["value"], "abc\ndef");
+
+        assert!(key_vault_get(
+            &mut client,
+            "vault-mgmt.vault.azure.net",
+            "/secrets/other?api-version=7.4",
+            Secret::from_str("token").unwrap(),
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Fault injected into a connection to a vault pod
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Kill the connection, all following reads return EOF and writes fail
+    Disconnect,
+    /// Delay the request before sending it
+    Delay(Duration),
+    /// Do not send the request and answer with the given HTTP status code instead
+    Status(u16),
+}
+
+#[derive(Debug, Default)]
+struct ChaosState {
+    faults: VecDeque<Option<Fault>>,
+    disconnected: bool,
+    requests: usize,
+}
+
+/// Fault injection for the transport to the vault pods
+///
+/// Faults are queued and applied to the following requests in order,
+/// requests without a queued fault are passed through.
+/// All streams wrapped by the same `Chaos` (and its clones) share the queue.
+#[derive(Clone, Debug, Default)]
+pub struct Chaos {
+    state: Arc<Mutex<ChaosState>>,
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a fault for the next request without a fault
+    pub fn inject(&self, fault: Fault) -> &Self {
+        self.state.lock().unwrap().faults.push_back(Some(fault));
+        self
+    }
+
+    /// Queue a request to be passed through without a fault
+    pub fn pass(&self) -> &Self {
+        self.state.lock().unwrap().faults.push_back(None);
+        self
+    }
+
+    /// Kill all connections immediately
+    pub fn disconnect(&self) {
+        self.state.lock().unwrap().disconnected = true;
+    }
+
+    /// Allow new connections after `disconnect` or a `Fault::Disconnect`
+    pub fn reconnect(&self) {
+        self.state.lock().unwrap().disconnected = false;
+    }
+
+    /// Number of requests started on the wrapped streams
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Wrap a stream to inject the faults
+    pub fn stream<T>(&self, stream: T) -> ChaosStream<T> {
+        ChaosStream {
+            inner: stream,
+            chaos: self.clone(),
+            phase: Phase::Idle,
+            disconnected: self.state.lock().unwrap().disconnected,
+        }
+    }
+
+    fn next_request(&self) -> Option<Fault> {
+        let mut state = self.state.lock().unwrap();
+        state.requests += 1;
+        state.faults.pop_front().flatten()
+    }
+
+    fn is_disconnected(&self) -> bool {
+        self.state.lock().unwrap().disconnected
+    }
+}
+
+enum Phase {
+    /// no request in flight
+    Idle,
+    /// the request is being written
+    Writing,
+    /// the request is delayed
+    Delaying(Pin<Box<tokio::time::Sleep>>),
+    /// the request is discarded and answered by the remaining response
+    Answering(VecDeque<u8>),
+    /// the response is being read
+    Reading,
+}
+
+/// Stream with injected faults, see `Chaos`
+pub struct ChaosStream<T> {
+    inner: T,
+    chaos: Chaos,
+    phase: Phase,
+    disconnected: bool,
+}
+
+impl<T> ChaosStream<T> {
+    fn disconnected(&mut self) -> bool {
+        self.disconnected |= self.chaos.is_disconnected();
+        self.disconnected
+    }
+}
+
+fn status_response(status: u16) -> VecDeque<u8> {
+    let body = format!("{{\"errors\":[\"injected fault: status {}\"]}}", status);
+    format!(
+        "HTTP/1.1 {} Injected Fault\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+    .into()
+}
+
+impl<T> AsyncRead for ChaosStream<T>
+where
+    T: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.disconnected() {
+            // EOF
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Phase::Answering(response) = &mut self.phase {
+            let n = response.len().min(buf.remaining());
+            let chunk: Vec<u8> = response.drain(..n).collect();
+            buf.put_slice(&chunk);
+
+            if response.is_empty() {
+                self.phase = Phase::Idle;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Phase::Writing = self.phase {
+            self.phase = Phase::Reading;
+        }
+
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T> AsyncWrite for ChaosStream<T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        if self.disconnected() {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+
+        if let Phase::Idle | Phase::Reading = self.phase {
+            self.phase = match self.chaos.next_request() {
+                None => Phase::Writing,
+                Some(Fault::Disconnect) => {
+                    self.chaos.disconnect();
+                    self.disconnected = true;
+                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+                }
+                Some(Fault::Delay(delay)) => Phase::Delaying(Box::pin(tokio::time::sleep(delay))),
+                Some(Fault::Status(status)) => Phase::Answering(status_response(status)),
+            };
+        }
+
+        match &mut self.phase {
+            Phase::Delaying(sleep) => {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                self.phase = Phase::Writing;
+            }
+            // discard the request and wake up a pending read for the injected response
+            Phase::Answering(_) => {
+                cx.waker().wake_by_ref();
+                return Poll::Ready(Ok(buf.len()));
+            }
+            _ => {}
+        }
+
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        if self.disconnected() {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::StatusCode;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{Chaos, Fault, GetSealStatus, HttpForwarderService};
+
+    async fn mock_seal_status() -> MockServer {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/seal-status"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "type": "shamir",
+                    "initialized": true,
+                    "sealed": false,
+                    "t": 2,
+                    "n": 3,
+                    "progress": 0,
+                    "nonce": "",
+                    "version": "1.13.0",
+                    "build_date": "2023-03-01T14:58:13Z",
+                    "migration": false,
+                    "recovery_seal": false,
+                    "storage_type": "raft",
+                })),
+            )
+            .mount(&mock_server)
+            .await;
+
+        mock_server
+    }
+
+    async fn client(
+        chaos: &Chaos,
+        mock_server: &MockServer,
+    ) -> HttpForwarderService<crate::BytesBody> {
+        HttpForwarderService::http(
+            chaos.stream(
+                tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                    .await
+                    .unwrap(),
+            ),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn chaos_passes_requests_without_faults() {
+        let mock_server = mock_seal_status().await;
+        let chaos = Chaos::new();
+
+        let mut client = client(&chaos, &mock_server).await;
+
+        assert!(client.seal_status().await.is_ok());
+        assert!(client.seal_status().await.is_ok());
+        assert_eq!(chaos.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn chaos_injects_status() {
+        let mock_server = mock_seal_status().await;
+        let chaos = Chaos::new();
+        chaos.inject(Fault::Status(500));
+
+        let mut client = client(&chaos, &mock_server).await;
+
+        assert!(client.seal_status().await.is_err());
+        assert!(client.seal_status().await.is_ok());
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn chaos_injects_disconnect() {
+        let mock_server = mock_seal_status().await;
+        let chaos = Chaos::new();
+        chaos.pass().inject(Fault::Disconnect);
+
+        let mut client = client(&chaos, &mock_server).await;
+
+        assert!(client.seal_status().await.is_ok());
+        assert!(client.seal_status().await.is_err());
+        assert!(client.seal_status().await.is_err());
+
+        chaos.reconnect();
+
+        let mut client = self::client(&chaos, &mock_server).await;
+
+        assert!(client.seal_status().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn chaos_injects_delay() {
+        let mock_server = mock_seal_status().await;
+        let chaos = Chaos::new();
+        chaos.inject(Fault::Delay(Duration::from_millis(200)));
+
+        let mut client = client(&chaos, &mock_server).await;
+
+        let start = std::time::Instant::now();
+        assert!(client.seal_status().await.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::runtime::wait::conditions::is_pod_running;
+use secrecy::Secret;
+use tracing::*;
+
+use crate::{
+    await_condition, is_active, is_pod_active, is_pod_exporting_seal_status, is_pod_unsealed,
+    is_seal_status_sealed, GetLeader, GetSealStatus, Init, InitRequest, PodApi, RaftJoinRequest,
+    RaftSnapshot, StatefulSetApi, Unseal, VAULT_PORT,
+};
+
+/// Time to wait for the target to seal itself after the snapshot was restored
+const RESEAL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A statefulset in a namespace, written as `namespace/name`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatefulSetRef {
+    pub namespace: String,
+    pub name: String,
+}
+
+impl std::str::FromStr for StatefulSetRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('/') {
+            Some((namespace, name))
+                if !namespace.is_empty() && !name.is_empty() && !name.contains('/') =>
+            {
+                Ok(Self {
+                    namespace: namespace.to_string(),
+                    name: name.to_string(),
+                })
+            }
+            _ => anyhow::bail!("expected namespace/statefulset, got {}", s),
+        }
+    }
+}
+
+impl std::fmt::Display for StatefulSetRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.namespace, self.name)
+    }
+}
+
+/// Names of the pods of a statefulset, ordered by ordinal
+fn pod_names(sts: &StatefulSet) -> anyhow::Result<Vec<String>> {
+    let name = sts
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("statefulset does not have a name"))?;
+    let replicas = sts.spec.as_ref().and_then(|s| s.replicas).unwrap_or(1);
+
+    Ok((0..replicas).map(|i| format!("{}-{}", name, i)).collect())
+}
+
+impl StatefulSetApi {
+    /// Clone the data of a vault cluster into this statefulset, which has to be deployed but not initialized
+    ///
+    /// - Take a raft snapshot of the active pod of the source
+    /// - Initialize and unseal the first pod of the target with temporary keys
+    /// - Restore the snapshot with force, replacing the keyring of the target
+    /// - Unseal the first pod with the keys of the source
+    /// - Repeat for the other pods of the target
+    ///     - Join pod to the raft cluster of the first pod if it is not initialized
+    ///     - Unseal pod with the keys of the source
+    ///
+    /// Afterwards the target uses the unseal keys and tokens of the source.
+    pub async fn clone_from(
+        &self,
+        source: &StatefulSet,
+        source_pods: &PodApi,
+        target: &StatefulSet,
+        target_pods: &PodApi,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+    ) -> anyhow::Result<()> {
+        let mut active = None;
+        for pod in pod_names(source)? {
+            if is_active(&source_pods.api.get(&pod).await?).unwrap_or(false) {
+                active = Some(pod);
+                break;
+            }
+        }
+        let active = active.ok_or(anyhow::anyhow!("source does not have an active pod"))?;
+
+        info!("taking raft snapshot of pod {}", active);
+        let snapshot = source_pods
+            .http(&active, VAULT_PORT)
+            .await?
+            .raft_snapshot(token)
+            .await?;
+        info!("took raft snapshot of {} bytes", snapshot.len());
+
+        let targets = pod_names(target)?;
+        let Some(first) = targets.first() else {
+            anyhow::bail!("target statefulset does not have any replicas");
+        };
+
+        info!("waiting for pod {}", first);
+        await_condition(
+            target_pods.api.clone(),
+            first,
+            is_pod_running(),
+            &target_pods.tuning,
+        )
+        .await?;
+        await_condition(
+            target_pods.api.clone(),
+            first,
+            is_pod_exporting_seal_status(),
+            &target_pods.tuning,
+        )
+        .await?;
+
+        let mut pf = target_pods.http(first, VAULT_PORT).await?;
+        if pf.seal_status().await?.initialized {
+            anyhow::bail!(
+                "pod {} is already initialized, refusing to overwrite its data",
+                first
+            );
+        }
+
+        info!("initializing pod {} with temporary keys", first);
+        let init = pf.init(InitRequest::default()).await?;
+        if !init.keys.is_empty() {
+            pf.unseal(&init.keys).await?;
+        }
+        await_condition(
+            target_pods.api.clone(),
+            first,
+            is_pod_active(),
+            &target_pods.tuning,
+        )
+        .await?;
+
+        info!("restoring raft snapshot on pod {}", first);
+        let mut pf = target_pods.http(first, VAULT_PORT).await?;
+        pf.raft_snapshot_restore(init.root_token, snapshot, true)
+            .await?;
+
+        // the restored keyring does not match the temporary keys, so the pod seals itself
+        if tokio::time::timeout(
+            RESEAL_TIMEOUT,
+            pf.await_seal_status(is_seal_status_sealed()),
+        )
+        .await
+        .is_err()
+        {
+            debug!("pod {} did not seal after the restore", first);
+        }
+
+        info!("unsealing pod {} with the keys of the source", first);
+        pf.unseal(keys).await?;
+        await_condition(
+            target_pods.api.clone(),
+            first,
+            is_pod_unsealed(),
+            &target_pods.tuning,
+        )
+        .await?;
+
+        let leader = pf.leader().await?;
+        if leader.leader_address.is_empty() {
+            anyhow::bail!("pod {} did not become the leader", first);
+        }
+        let join = RaftJoinRequest::new(&leader.leader_address);
+
+        for pod in &targets[1..] {
+            self.scale_up_pod(target_pods, pod, &join, keys).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::StatefulSetRef;
+
+    #[test]
+    fn statefulset_ref_is_parsed() {
+        assert_eq!(
+            StatefulSetRef::from_str("vault/vault").unwrap(),
+            StatefulSetRef {
+                namespace: "vault".to_string(),
+                name: "vault".to_string(),
+            }
+        );
+        assert!(StatefulSetRef::from_str("vault").is_err());
+        assert!(StatefulSetRef::from_str("/vault").is_err());
+        assert!(StatefulSetRef::from_str("vault/vault/0").is_err());
+    }
+}
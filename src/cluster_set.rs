@@ -0,0 +1,314 @@
+use std::{future::Future, str::FromStr};
+
+use futures_util::future::join_all;
+use serde::{Deserialize, Deserializer};
+use tracing::*;
+
+use crate::{Flavor, KeysFrom, KeysSecretUri, UpgradeReport, UpgradeReporter};
+
+/// Vault cluster listed in the `clusters` of the config file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// name to select the cluster with `--cluster`
+    pub name: String,
+    pub namespace: String,
+    pub statefulset: String,
+    #[serde(default)]
+    pub flavor: Flavor,
+    /// uri to the vault kv secret containing the unseal keys, see `KeysSecretUri`
+    #[serde(default, deserialize_with = "keys_secret_uri")]
+    pub keys_secret_uri: Option<KeysSecretUri>,
+    /// command that writes the unseal keys to its stdout
+    #[serde(default)]
+    pub key_cmd: Option<String>,
+    /// uri of the key source, see `KeysFrom`, instead of `keys_secret_uri` or `key_cmd`
+    #[serde(default, deserialize_with = "keys_from")]
+    pub keys_from: Option<KeysFrom>,
+    /// name of the vault container, detected if not set, overrides `--container-name`
+    #[serde(default)]
+    pub container: Option<String>,
+}
+
+impl ClusterConfig {
+    /// Source of the unseal keys, `keys_secret_uri` takes precedence over `key_cmd`
+    pub fn keys_source(&self) -> anyhow::Result<Option<KeysFrom>> {
+        match (&self.keys_from, &self.keys_secret_uri, &self.key_cmd) {
+            (Some(_), Some(_), _) | (Some(_), _, Some(_)) => anyhow::bail!(
+                "keys_from of cluster {} cannot be combined with keys_secret_uri or key_cmd",
+                self.name
+            ),
+            (Some(from), None, None) => Ok(Some(from.clone())),
+            (None, Some(uri), _) => Ok(Some(uri.clone().into())),
+            (None, None, Some(cmd)) => Ok(Some(KeysFrom::cmd(cmd))),
+            (None, None, None) => Ok(None),
+        }
+    }
+}
+
+/// What happened to a single cluster of a `ClusterSet`
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ClusterReport {
+    pub name: String,
+    pub namespace: String,
+    pub statefulset: String,
+    /// the cluster was not started because an earlier cluster failed
+    pub skipped: bool,
+    pub completed: bool,
+    pub error: Option<String>,
+    pub upgrade: UpgradeReport,
+}
+
+/// Aggregated report of the upgrades of a `ClusterSet`
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ClusterSetReport {
+    /// all clusters were upgraded
+    pub completed: bool,
+    pub clusters: Vec<ClusterReport>,
+}
+
+impl ClusterSetReport {
+    /// Fail with the names of the clusters that were not upgraded
+    pub fn result(&self) -> anyhow::Result<()> {
+        let failed = self
+            .clusters
+            .iter()
+            .filter(|c| !c.completed)
+            .map(|c| c.name.as_str())
+            .collect::<Vec<_>>();
+
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(anyhow::anyhow!(
+                "clusters not upgraded: {}",
+                failed.join(", ")
+            )),
+        }
+    }
+}
+
+/// Several vault clusters upgraded together, e.g. all clusters of the config file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClusterSet {
+    clusters: Vec<ClusterConfig>,
+}
+
+impl ClusterSet {
+    pub fn new(clusters: Vec<ClusterConfig>) -> Self {
+        Self { clusters }
+    }
+
+    pub fn clusters(&self) -> &[ClusterConfig] {
+        &self.clusters
+    }
+
+    /// Only keep the clusters with the given names, in the order of the set
+    pub fn select(&self, names: &[String]) -> anyhow::Result<Self> {
+        for name in names {
+            if !self.clusters.iter().any(|c| &c.name == name) {
+                anyhow::bail!("cluster {} is not in the config file", name);
+            }
+        }
+
+        Ok(Self {
+            clusters: self
+                .clusters
+                .iter()
+                .filter(|c| names.contains(&c.name))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Upgrade each cluster with `upgrade`, which reports to the given reporter
+    ///
+    /// One after another, the clusters after a failed one are skipped.
+    /// In parallel, all clusters are upgraded regardless of failures.
+    pub async fn upgrade_with<F, Fut>(&self, parallel: bool, upgrade: F) -> ClusterSetReport
+    where
+        F: Fn(ClusterConfig, UpgradeReporter) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let run = |cluster: ClusterConfig| {
+            let reporter = UpgradeReporter::new();
+            let upgraded = upgrade(cluster.clone(), reporter.clone());
+
+            async move {
+                info!(
+                    "upgrading cluster {} (statefulset {} in namespace {})",
+                    cluster.name, cluster.statefulset, cluster.namespace
+                );
+                let upgraded = upgraded.await;
+                if let Err(e) = &upgraded {
+                    error!("upgrading cluster {}: {:#}", cluster.name, e);
+                }
+
+                ClusterReport {
+                    completed: upgraded.is_ok(),
+                    error: upgraded.err().map(|e| format!("{:#}", e)),
+                    upgrade: reporter.report(),
+                    ..Self::not_started(&cluster)
+                }
+            }
+        };
+
+        let clusters = match parallel {
+            true => join_all(self.clusters.iter().cloned().map(run)).await,
+            false => {
+                let mut reports = vec![];
+                for cluster in &self.clusters {
+                    match reports.last() {
+                        Some(ClusterReport {
+                            completed: false, ..
+                        }) => reports.push(ClusterReport {
+                            skipped: true,
+                            ..Self::not_started(cluster)
+                        }),
+                        _ => reports.push(run(cluster.clone()).await),
+                    }
+                }
+                reports
+            }
+        };
+
+        ClusterSetReport {
+            completed: clusters.iter().all(|c| c.completed),
+            clusters,
+        }
+    }
+
+    fn not_started(cluster: &ClusterConfig) -> ClusterReport {
+        ClusterReport {
+            name: cluster.name.clone(),
+            namespace: cluster.namespace.clone(),
+            statefulset: cluster.statefulset.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+fn keys_secret_uri<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<KeysSecretUri>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|uri| KeysSecretUri::from_str(&uri).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn keys_from<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<KeysFrom>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|uri| KeysFrom::from_str(&uri).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use secrecy::Secret;
+
+    use crate::{
+        rolling_upgrade, ClusterConfig, ClusterSet, ClusterUpgradeOptions, ConfigFile, SimCluster,
+        VaultVersion,
+    };
+
+    fn cluster_set() -> ClusterSet {
+        let config: ConfigFile = serde_yaml::from_str(
+            r#"
+clusters:
+  - name: eu
+    namespace: vault-eu
+    statefulset: vault
+    key_cmd: cat keys
+  - name: us
+    namespace: vault-us
+    statefulset: vault
+    flavor: openbao
+    keys_secret_uri: https://vault.example.com/v1/secret/data/unseal-keys
+"#,
+        )
+        .unwrap();
+
+        ClusterSet::new(config.clusters)
+    }
+
+    async fn upgrade(cluster: &SimCluster, options: ClusterUpgradeOptions) -> anyhow::Result<()> {
+        rolling_upgrade(
+            cluster,
+            &VaultVersion::from_str("1.14.0").unwrap(),
+            Secret::from_str("token").unwrap(),
+            &[Secret::from_str("key").unwrap()],
+            &options,
+        )
+        .await
+    }
+
+    #[test]
+    fn selecting_clusters_keeps_their_order() {
+        let set = cluster_set();
+
+        let selected = set.select(&["us".to_string(), "eu".to_string()]).unwrap();
+        assert_eq!(
+            selected
+                .clusters()
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["eu", "us"]
+        );
+
+        assert!(set.select(&["ap".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn sequential_upgrade_skips_clusters_after_failure() {
+        let eu = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .never_unseals("vault-1");
+        let us = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+
+        let report = cluster_set()
+            .upgrade_with(false, |cluster: ClusterConfig, reporter| {
+                let sim = match cluster.name.as_str() {
+                    "eu" => &eu,
+                    _ => &us,
+                };
+                let mut options = ClusterUpgradeOptions::default();
+                options.pod.report = Some(reporter);
+                upgrade(sim, options)
+            })
+            .await;
+
+        assert!(!report.completed);
+        assert!(report.result().is_err());
+        assert!(report.clusters[0].error.is_some());
+        assert!(!report.clusters[0].upgrade.completed);
+        assert!(report.clusters[1].skipped);
+        assert_eq!(us.actions(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn parallel_upgrade_aggregates_reports() {
+        let eu = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let us = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+
+        let report = cluster_set()
+            .upgrade_with(true, |cluster: ClusterConfig, reporter| {
+                let sim = match cluster.name.as_str() {
+                    "eu" => &eu,
+                    _ => &us,
+                };
+                let mut options = ClusterUpgradeOptions::default();
+                options.pod.report = Some(reporter);
+                upgrade(sim, options)
+            })
+            .await;
+
+        assert!(report.completed);
+        assert!(report.result().is_ok());
+        for cluster in &report.clusters {
+            assert!(cluster.upgrade.completed);
+            assert_eq!(cluster.upgrade.pods.len(), 3);
+        }
+    }
+}
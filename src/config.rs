@@ -0,0 +1,301 @@
+use std::{collections::BTreeSet, path::Path, time::Duration};
+
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::{
+    runtime::{utils::ResetTimerBackoff, watcher},
+    Api, Client,
+};
+use serde::{Deserialize, Deserializer};
+
+use crate::{ClusterConfig, Finding, KeyProviders, Severity};
+
+/// Settings read from the YAML file passed with `--config`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConfigFile {
+    /// tuning of the watches on the Kubernetes API
+    pub kube: KubeTuning,
+    /// clusters upgraded by `upgrade --all` or `--cluster`, see `ClusterSet`
+    pub clusters: Vec<ClusterConfig>,
+}
+
+impl ConfigFile {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("reading config file {}: {}", path.display(), e))?;
+
+        serde_yaml::from_str(&content)
+            .map_err(|e| anyhow::anyhow!("parsing config file {}: {}", path.display(), e))
+    }
+}
+
+/// How the watches on the Kubernetes API are restarted, modelled after the reflector of client-go.
+///
+/// Large clusters throttle aggressive clients with API priority and fairness,
+/// so the backoff can be raised and the lists split into pages.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KubeTuning {
+    /// server-side timeout of a watch, after which it is restarted
+    #[serde(deserialize_with = "duration")]
+    pub watch_timeout: Duration,
+    /// objects per page when (re)listing, all at once if unset
+    pub page_size: Option<u32>,
+    /// delay before the first restart of a failed watch
+    #[serde(deserialize_with = "duration")]
+    pub initial_backoff: Duration,
+    /// upper bound of the delay between restarts
+    #[serde(deserialize_with = "duration")]
+    pub max_backoff: Duration,
+    /// factor the delay grows by after each failed restart
+    pub backoff_multiplier: f64,
+    /// the delay starts at `initial_backoff` again once a watch ran this long without errors
+    #[serde(deserialize_with = "duration")]
+    pub backoff_reset: Duration,
+}
+
+impl Default for KubeTuning {
+    fn default() -> Self {
+        Self {
+            watch_timeout: Duration::from_secs(290),
+            page_size: None,
+            initial_backoff: Duration::from_millis(800),
+            max_backoff: Duration::from_secs(30),
+            backoff_multiplier: 2.0,
+            backoff_reset: Duration::from_secs(120),
+        }
+    }
+}
+
+impl KubeTuning {
+    pub fn watcher_config(&self) -> watcher::Config {
+        let config = watcher::Config::default().timeout(self.watch_timeout.as_secs() as u32);
+
+        match self.page_size {
+            Some(size) => config.page_size(size),
+            None => config,
+        }
+    }
+
+    pub fn backoff(&self) -> ResetTimerBackoff<backoff::ExponentialBackoff> {
+        ResetTimerBackoff::new(
+            backoff::ExponentialBackoff {
+                initial_interval: self.initial_backoff,
+                max_interval: self.max_backoff,
+                multiplier: self.backoff_multiplier,
+                randomization_factor: 1.0,
+                max_elapsed_time: None,
+                ..Default::default()
+            },
+            self.backoff_reset,
+        )
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // the client gives up reading after 295s, a longer watch would always fail
+        if self.watch_timeout.is_zero() || self.watch_timeout > Duration::from_secs(290) {
+            anyhow::bail!("watch_timeout must be between 1s and 290s");
+        }
+        if self.initial_backoff > self.max_backoff {
+            anyhow::bail!("initial_backoff must not exceed max_backoff");
+        }
+        if self.backoff_multiplier < 1.0 {
+            anyhow::bail!("backoff_multiplier must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+/// Check the config file without reaching out to any cluster,
+/// see `check_config_clusters` for the checks that do
+pub fn check_config(config: &ConfigFile) -> Vec<Finding> {
+    let mut findings = vec![match config.kube.validate() {
+        Ok(()) => Finding::new("kube", Severity::Ok, "tuning is valid".to_string()),
+        Err(e) => Finding::new("kube", Severity::Error, e.to_string()),
+    }];
+
+    let mut names = BTreeSet::new();
+    for cluster in &config.clusters {
+        let check = format!("cluster {}", cluster.name);
+
+        if !names.insert(&cluster.name) {
+            findings.push(Finding::new(
+                &check,
+                Severity::Error,
+                "name is used by several clusters, --cluster cannot select it".to_string(),
+            ));
+        }
+
+        findings.push(match cluster.keys_source() {
+            Ok(Some(from)) if cluster.keys_secret_uri.is_some() && cluster.key_cmd.is_some() => {
+                Finding::new(
+                    &check,
+                    Severity::Warning,
+                    format!("key_cmd is ignored, the keys are read from {}", from),
+                )
+            }
+            Ok(Some(from)) => {
+                Finding::new(&check, Severity::Ok, format!("keys are read from {}", from))
+            }
+            Ok(None) => Finding::new(
+                &check,
+                Severity::Warning,
+                "no unseal keys configured, the pods have to be unsealed externally".to_string(),
+            ),
+            Err(e) => Finding::new(&check, Severity::Error, e.to_string()),
+        });
+    }
+
+    findings
+}
+
+/// Check that the statefulsets of the clusters exist and the sources of their unseal keys
+/// are reachable with the configured providers (see `KeyProvider::check`), without reading
+/// the keys or changing anything
+pub async fn check_config_clusters(
+    client: Client,
+    config: &ConfigFile,
+    providers: &KeyProviders,
+) -> Vec<Finding> {
+    let mut findings = vec![match client.apiserver_version().await {
+        Ok(version) => Finding::new(
+            "kubernetes",
+            Severity::Ok,
+            format!("connected to kubernetes {}", version.git_version),
+        ),
+        Err(e) => {
+            return vec![Finding::new(
+                "kubernetes",
+                Severity::Error,
+                format!("connecting to kubernetes: {}", e),
+            )]
+        }
+    }];
+
+    for cluster in &config.clusters {
+        let check = format!("cluster {}", cluster.name);
+
+        let stss: Api<StatefulSet> = Api::namespaced(client.clone(), &cluster.namespace);
+        findings.push(match stss.get_opt(&cluster.statefulset).await {
+            Ok(Some(_)) => Finding::new(
+                &check,
+                Severity::Ok,
+                format!(
+                    "statefulset {} found in namespace {}",
+                    cluster.statefulset, cluster.namespace
+                ),
+            ),
+            Ok(None) => Finding::new(
+                &check,
+                Severity::Error,
+                format!(
+                    "statefulset {} not found in namespace {}",
+                    cluster.statefulset, cluster.namespace
+                ),
+            ),
+            Err(e) => Finding::new(
+                &check,
+                Severity::Error,
+                format!(
+                    "getting statefulset {} in namespace {}: {}",
+                    cluster.statefulset, cluster.namespace, e
+                ),
+            ),
+        });
+
+        if let Ok(Some(from)) = cluster.keys_source() {
+            findings.push(match providers.check(&from).await {
+                Ok(()) => Finding::new(&check, Severity::Ok, format!("{} is reachable", from)),
+                Err(e) => Finding::new(&check, Severity::Error, format!("{}: {:#}", from, e)),
+            });
+        }
+    }
+
+    findings
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(deserializer)?;
+
+    humantime::parse_duration(&value).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{check_config, ConfigFile, KubeTuning, Severity};
+
+    #[test]
+    fn config_file_overrides_kube_tuning() {
+        let config: ConfigFile = serde_yaml::from_str(
+            "kube:\n  watch_timeout: 2m\n  page_size: 100\n  max_backoff: 5m\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.kube,
+            KubeTuning {
+                watch_timeout: Duration::from_secs(120),
+                page_size: Some(100),
+                max_backoff: Duration::from_secs(300),
+                ..Default::default()
+            }
+        );
+        assert!(config.kube.validate().is_ok());
+
+        assert_eq!(
+            serde_yaml::from_str::<ConfigFile>("{}").unwrap(),
+            ConfigFile::default()
+        );
+        assert!(serde_yaml::from_str::<ConfigFile>("kube:\n  relist: 1s\n").is_err());
+        assert!(KubeTuning {
+            watch_timeout: Duration::from_secs(600),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn config_check_reports_misconfigured_clusters() {
+        let config: ConfigFile = serde_yaml::from_str(
+            r#"
+kube:
+  initial_backoff: 1m
+  max_backoff: 30s
+clusters:
+  - name: eu
+    namespace: vault-eu
+    statefulset: vault
+    key_cmd: sh -c 'cat keys'
+  - name: eu
+    namespace: vault-eu2
+    statefulset: vault
+    key_cmd: cat keys
+    keys_from: env://VAULT_UNSEAL_KEYS
+  - name: us
+    namespace: vault-us
+    statefulset: vault
+"#,
+        )
+        .unwrap();
+
+        let findings = check_config(&config)
+            .into_iter()
+            .map(|f| (f.check, f.severity))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            findings,
+            vec![
+                ("kube".to_string(), Severity::Error),
+                ("cluster eu".to_string(), Severity::Ok),
+                ("cluster eu".to_string(), Severity::Error),
+                ("cluster eu".to_string(), Severity::Error),
+                ("cluster us".to_string(), Severity::Warning),
+            ]
+        );
+    }
+}
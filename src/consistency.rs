@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+
+use clap::ValueEnum;
+use http::{HeaderValue, Response};
+use hyper::Request;
+
+/// Header with the replication state after a write, returned by Vault Enterprise
+pub const HEADER_VAULT_INDEX: &str = "X-Vault-Index";
+
+/// Header telling a performance standby what to do if it has not reached the requested state
+pub const HEADER_VAULT_INCONSISTENT: &str = "X-Vault-Inconsistent";
+
+/// What a performance standby does with a request for a state it has not reached yet
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Inconsistent {
+    /// forward the request to the active node
+    #[default]
+    ForwardActiveNode,
+    /// fail the request with 412 Precondition Failed
+    Fail,
+}
+
+impl std::fmt::Display for Inconsistent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// Read-your-writes consistency for Vault Enterprise performance standbys
+///
+/// Remembers the `X-Vault-Index` of the last response and sends it with the following
+/// requests, so a read right after a write observes the write even if a performance
+/// standby serves it. Open source Vault does not return the header and is not affected.
+/// Clones share the index, so it follows the requests across the connections to the pods,
+/// see `HttpForwarderService::set_read_your_writes`.
+#[derive(Clone, Debug)]
+pub struct ReadYourWrites {
+    index: Arc<Mutex<Option<HeaderValue>>>,
+    inconsistent: Inconsistent,
+}
+
+impl ReadYourWrites {
+    pub fn new(inconsistent: Inconsistent) -> Self {
+        Self {
+            index: Arc::default(),
+            inconsistent,
+        }
+    }
+
+    /// Replication state of the last write, if the server returned one
+    pub fn index(&self) -> Option<HeaderValue> {
+        self.index.lock().unwrap().clone()
+    }
+
+    /// Request the replication state of the last write
+    pub(crate) fn prepare<B>(&self, req: &mut Request<B>) {
+        let Some(index) = self.index() else {
+            return;
+        };
+
+        let headers = req.headers_mut();
+        headers.insert(HEADER_VAULT_INDEX, index);
+        headers.insert(
+            HEADER_VAULT_INCONSISTENT,
+            HeaderValue::from_str(&self.inconsistent.to_string())
+                .expect("values are valid header values"),
+        );
+    }
+
+    /// Remember the replication state the server returned
+    pub(crate) fn observe<B>(&self, response: &Response<B>) {
+        if let Some(index) = response.headers().get(HEADER_VAULT_INDEX) {
+            *self.index.lock().unwrap() = Some(index.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{vault_request, HttpForwarderService, HttpRequest, Inconsistent, ReadYourWrites};
+
+    #[tokio::test]
+    async fn index_of_write_is_sent_with_following_requests() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/secret/data/smoke"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).insert_header("X-Vault-Index", "state"),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/secret/data/smoke"))
+            .and(header("X-Vault-Index", "state"))
+            .and(header("X-Vault-Inconsistent", "forward-active-node"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let read_your_writes = ReadYourWrites::new(Inconsistent::default());
+
+        // every request uses its own connection, like the requests to the pods
+        for method in [Method::POST, Method::GET] {
+            let mut client = HttpForwarderService::http(
+                tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                    .await
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+            client.set_read_your_writes(Some(read_your_writes.clone()));
+
+            let response = client
+                .send_request(
+                    vault_request()
+                        .uri("/v1/secret/data/smoke")
+                        .method(method)
+                        .body(Empty::<Bytes>::new().boxed())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+
+        assert_eq!(read_your_writes.index().unwrap(), "state");
+    }
+}
@@ -0,0 +1,313 @@
+use std::time::Duration;
+
+use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
+use kube::{runtime::wait::Condition, Api, Client};
+use secrecy::Secret;
+use tracing::*;
+
+use crate::{
+    is_statefulset_ready, raft_configuration_all_voters, raft_configuration_any_leader,
+    GetRaftConfiguration, GetSealStatus, PodApi, PodSealStatus, PodSelector, RaftConfiguration,
+    Transport, VAULT_PORT,
+};
+
+/// How to connect to the pods while waiting in `await_cluster_converged`
+#[derive(Clone, Debug)]
+pub struct ConvergeOptions {
+    /// token for reading the raft configuration
+    pub token: Secret<String>,
+    pub tls: bool,
+    pub domain: String,
+    pub selector: PodSelector,
+    pub transport: Transport,
+    /// how long to wait before failing with the checks still pending
+    pub timeout: Duration,
+    /// time between the checks of the cluster
+    pub interval: Duration,
+}
+
+impl ConvergeOptions {
+    pub fn new(token: Secret<String>) -> Self {
+        Self {
+            token,
+            tls: true,
+            domain: "vault".to_string(),
+            selector: PodSelector::default(),
+            transport: Transport::default(),
+            timeout: Duration::from_secs(30 * 60),
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Wait until the cluster has converged after a rollout done by another system, e.g. `helm upgrade`:
+/// the statefulset is ready, all its pods are unsealed, the raft configuration has a leader
+/// and all raft servers are voters
+///
+/// Nothing is changed. Fails after `timeout` with the checks still pending.
+pub async fn await_cluster_converged(
+    client: Client,
+    namespace: &str,
+    statefulset: &str,
+    opts: &ConvergeOptions,
+) -> anyhow::Result<()> {
+    let stss: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+    let pods = PodApi::new(
+        Api::namespaced(client, namespace),
+        opts.tls,
+        opts.domain.clone(),
+    )
+    .selector(opts.selector.clone())
+    .transport(opts.transport);
+
+    let deadline = tokio::time::Instant::now() + opts.timeout;
+    loop {
+        let pending = converge_pending_of(&stss, statefulset, &pods, opts).await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        if tokio::time::Instant::now() + opts.interval > deadline {
+            anyhow::bail!(
+                "statefulset {} did not converge within {}: {}",
+                statefulset,
+                humantime::format_duration(opts.timeout),
+                pending.join(", ")
+            );
+        }
+
+        info!(
+            "waiting for statefulset {} to converge: {}",
+            statefulset,
+            pending.join(", ")
+        );
+        tokio::time::sleep(opts.interval).await;
+    }
+}
+
+async fn converge_pending_of(
+    stss: &Api<StatefulSet>,
+    statefulset: &str,
+    pods: &PodApi,
+    opts: &ConvergeOptions,
+) -> anyhow::Result<Vec<String>> {
+    let sts = stss.get_opt(statefulset).await?;
+    let list = pods.api.list(&opts.selector.to_list_params()).await?;
+
+    let mut statuses = Vec::new();
+    for pod in list.iter() {
+        let status = match pod.metadata.name.as_ref() {
+            Some(name) => match pods.http(name, VAULT_PORT).await {
+                Ok(mut pf) => pf.seal_status().await.ok(),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        statuses.push(status);
+    }
+
+    // any unsealed pod answers with the configuration of the leader
+    let mut raft = Err("no unsealed pod to read the raft configuration from".to_string());
+    let unsealed = list
+        .iter()
+        .zip(&statuses)
+        .filter(|(_, s)| s.as_ref().is_some_and(|s| !s.sealed))
+        .filter_map(|(p, _)| p.metadata.name.as_ref());
+    for name in unsealed {
+        let config = match pods.http(name, VAULT_PORT).await {
+            Ok(mut pf) => pf.raft_configuration(opts.token.clone()).await,
+            Err(e) => Err(e),
+        };
+        match config {
+            Ok(config) => {
+                raft = Ok(config);
+                break;
+            }
+            Err(e) => raft = Err(format!("reading the raft configuration: {}", e)),
+        }
+    }
+
+    let pods: Vec<_> = list.items.iter().zip(statuses).collect();
+    Ok(converge_pending(sts.as_ref(), &pods, raft.as_ref()))
+}
+
+/// Why the cluster has not converged yet, empty once it has, see `await_cluster_converged`
+pub fn converge_pending(
+    sts: Option<&StatefulSet>,
+    pods: &[(&Pod, Option<PodSealStatus>)],
+    raft: Result<&RaftConfiguration, &String>,
+) -> Vec<String> {
+    let mut pending = Vec::new();
+
+    let Some(sts) = sts else {
+        return vec!["statefulset not found".to_string()];
+    };
+    let replicas = sts.spec.as_ref().and_then(|s| s.replicas).unwrap_or(1);
+    if !is_statefulset_ready().matches_object(Some(sts)) {
+        let ready = sts
+            .status
+            .as_ref()
+            .and_then(|s| s.ready_replicas)
+            .unwrap_or(0);
+        pending.push(format!("{} of {} replicas ready", ready, replicas));
+    }
+
+    if (pods.len() as i32) < replicas {
+        pending.push(format!("{} of {} pods exist", pods.len(), replicas));
+    }
+    for (pod, status) in pods {
+        let name = pod.metadata.name.as_deref().unwrap_or_default();
+        match status {
+            None => pending.push(format!("{} does not report its seal status", name)),
+            Some(status) if status.sealed => pending.push(format!("{} is sealed", name)),
+            Some(_) => {}
+        }
+    }
+
+    match raft {
+        Ok(config) => {
+            if !raft_configuration_any_leader().matches_object(Some(config)) {
+                pending.push("raft has no leader".to_string());
+            }
+            if !raft_configuration_all_voters().matches_object(Some(config)) {
+                let non_voters: Vec<_> = config
+                    .data
+                    .config
+                    .servers
+                    .iter()
+                    .filter(|s| !s.voter)
+                    .map(|s| s.node_id.as_str())
+                    .collect();
+                pending.push(format!(
+                    "raft servers {} are not voters yet",
+                    non_voters.join(", ")
+                ));
+            }
+        }
+        Err(e) => pending.push(e.clone()),
+    }
+
+    pending
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
+
+    use crate::{converge_pending, PodSealStatus, RaftConfiguration};
+
+    fn statefulset(ready: i32) -> StatefulSet {
+        serde_json::from_value(serde_json::json!({
+            "metadata": { "name": "vault" },
+            "spec": {
+                "replicas": 2,
+                "selector": {},
+                "serviceName": "vault-internal",
+                "template": {},
+            },
+            "status": { "replicas": 2, "readyReplicas": ready, "availableReplicas": ready },
+        }))
+        .unwrap()
+    }
+
+    fn pod(name: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({ "metadata": { "name": name } })).unwrap()
+    }
+
+    fn seal_status(sealed: bool) -> Option<PodSealStatus> {
+        Some(
+            serde_json::from_value(serde_json::json!({
+                "type": "shamir",
+                "initialized": true,
+                "sealed": sealed,
+                "t": 3,
+                "n": 5,
+                "progress": 0,
+                "nonce": "",
+                "version": "1.14.0",
+                "build_date": "2023-06-19T11:40:23Z",
+                "migration": false,
+                "recovery_seal": false,
+                "storage_type": "raft",
+            }))
+            .unwrap(),
+        )
+    }
+
+    fn raft(servers: &[(&str, bool, bool)]) -> RaftConfiguration {
+        serde_json::from_value(serde_json::json!({
+            "request_id": "",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": {
+                "config": {
+                    "index": 0,
+                    "servers": servers
+                        .iter()
+                        .map(|(node_id, leader, voter)| serde_json::json!({
+                            "node_id": node_id,
+                            "address": format!("{}.vault-internal:8201", node_id),
+                            "leader": leader,
+                            "protocol_version": "3",
+                            "voter": voter,
+                        }))
+                        .collect::<Vec<_>>(),
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn cluster_converges_once_everything_is_ready_unsealed_and_voting() {
+        let (vault_0, vault_1) = (pod("vault-0"), pod("vault-1"));
+        let converged = raft(&[("vault-0", true, true), ("vault-1", false, true)]);
+
+        assert!(converge_pending(
+            Some(&statefulset(2)),
+            &[
+                (&vault_0, seal_status(false)),
+                (&vault_1, seal_status(false))
+            ],
+            Ok(&converged),
+        )
+        .is_empty());
+
+        assert_eq!(
+            converge_pending(None, &[], Ok(&converged)),
+            vec!["statefulset not found"]
+        );
+
+        let no_raft = "no unsealed pod to read the raft configuration from".to_string();
+        assert_eq!(
+            converge_pending(
+                Some(&statefulset(1)),
+                &[(&vault_0, seal_status(true))],
+                Err(&no_raft),
+            ),
+            vec![
+                "1 of 2 replicas ready",
+                "1 of 2 pods exist",
+                "vault-0 is sealed",
+                no_raft.as_str(),
+            ]
+        );
+
+        assert_eq!(
+            converge_pending(
+                Some(&statefulset(2)),
+                &[(&vault_0, seal_status(false)), (&vault_1, None)],
+                Ok(&raft(&[
+                    ("vault-0", false, true),
+                    ("vault-1", false, false)
+                ])),
+            ),
+            vec![
+                "vault-1 does not report its seal status",
+                "raft has no leader",
+                "raft servers vault-1 are not voters yet",
+            ]
+        );
+    }
+}
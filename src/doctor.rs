@@ -0,0 +1,1023 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use k8s_openapi::{
+    api::{
+        core::v1::{ConfigMap, Pod},
+        networking::v1::{NetworkPolicy, NetworkPolicyPeer, NetworkPolicyPort},
+    },
+    apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+};
+use kube::{api::ListParams, Api};
+use prettytable::{color, Attr, Cell, Row, Table};
+
+use crate::{find_vault_container, pod_ips, unbracketed_host, VAULT_PORT};
+
+/// Port used for raft and request forwarding between the vault pods
+pub const VAULT_CLUSTER_PORT: u16 = 8201;
+
+/// Severity of a finding reported by `doctor`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Ok => "ok".fmt(f),
+            Severity::Warning => "warning".fmt(f),
+            Severity::Error => "error".fmt(f),
+        }
+    }
+}
+
+/// Result of a single check done by `doctor`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub check: String,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(check: &str, severity: Severity, message: String) -> Self {
+        Self {
+            check: check.to_string(),
+            severity,
+            message,
+        }
+    }
+}
+
+/// Construct a table from the findings of `doctor`
+pub fn construct_doctor_table(findings: &[Finding]) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row!["CHECK", "SEVERITY", "MESSAGE"]);
+
+    for finding in findings {
+        let severity = Cell::new(&finding.severity.to_string()).with_style(Attr::ForegroundColor(
+            match finding.severity {
+                Severity::Ok => color::GREEN,
+                Severity::Warning => color::YELLOW,
+                Severity::Error => color::RED,
+            },
+        ));
+
+        table.add_row(Row::new(vec![
+            Cell::new(&finding.check),
+            severity,
+            Cell::new(&finding.message),
+        ]));
+    }
+
+    table
+}
+
+/// Check if a label selector matches the given labels
+pub fn selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool {
+    let labels_match = selector
+        .match_labels
+        .iter()
+        .flatten()
+        .all(|(k, v)| labels.get(k) == Some(v));
+
+    let expressions_match = selector.match_expressions.iter().flatten().all(|e| {
+        let values = e.values.clone().unwrap_or_default();
+        match e.operator.as_str() {
+            "In" => labels.get(&e.key).is_some_and(|v| values.contains(v)),
+            "NotIn" => labels.get(&e.key).is_none_or(|v| !values.contains(v)),
+            "Exists" => labels.contains_key(&e.key),
+            "DoesNotExist" => !labels.contains_key(&e.key),
+            _ => false,
+        }
+    });
+
+    labels_match && expressions_match
+}
+
+/// Whether traffic is allowed by the network policies
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verdict {
+    Denied,
+    /// depends on information not available in the namespace (e.g. namespace labels or IP blocks)
+    Unknown,
+    Allowed,
+}
+
+/// Source or destination of the traffic to check
+#[derive(Copy, Clone)]
+enum Peer<'a> {
+    /// anything outside of the cluster or in an unknown location
+    Anywhere,
+    /// a vault pod in the same namespace
+    VaultPod(&'a BTreeMap<String, String>),
+}
+
+fn port_matches(ports: Option<&Vec<NetworkPolicyPort>>, pod: &Pod, port: u16) -> bool {
+    let port = i32::from(port);
+
+    let named_port = |name: &str| {
+        pod.spec
+            .iter()
+            .flat_map(|s| s.containers.iter())
+            .flat_map(|c| c.ports.iter().flatten())
+            .find(|p| p.name.as_deref() == Some(name))
+            .map(|p| p.container_port)
+    };
+
+    match ports {
+        None => true,
+        Some(ports) if ports.is_empty() => true,
+        Some(ports) => ports.iter().any(|p| {
+            if p.protocol.as_deref().is_some_and(|proto| proto != "TCP") {
+                return false;
+            }
+
+            match &p.port {
+                None => true,
+                Some(IntOrString::Int(n)) => match p.end_port {
+                    Some(end) => *n <= port && port <= end,
+                    None => *n == port,
+                },
+                Some(IntOrString::String(name)) => named_port(name) == Some(port),
+            }
+        }),
+    }
+}
+
+fn peers_match(peers: Option<&Vec<NetworkPolicyPeer>>, peer: Peer) -> Verdict {
+    let peers = match peers {
+        None => return Verdict::Allowed,
+        Some(peers) if peers.is_empty() => return Verdict::Allowed,
+        Some(peers) => peers,
+    };
+
+    peers
+        .iter()
+        .map(|p| match peer {
+            Peer::Anywhere => Verdict::Unknown,
+            Peer::VaultPod(labels) => {
+                if p.ip_block.is_some() {
+                    return Verdict::Unknown;
+                }
+
+                let pod_matches = p
+                    .pod_selector
+                    .as_ref()
+                    .is_none_or(|s| selector_matches(s, labels));
+
+                match &p.namespace_selector {
+                    None if p.pod_selector.is_none() => Verdict::Denied,
+                    None => match pod_matches {
+                        true => Verdict::Allowed,
+                        false => Verdict::Denied,
+                    },
+                    Some(ns) if ns == &LabelSelector::default() => match pod_matches {
+                        true => Verdict::Allowed,
+                        false => Verdict::Denied,
+                    },
+                    Some(_) => match pod_matches {
+                        true => Verdict::Unknown,
+                        false => Verdict::Denied,
+                    },
+                }
+            }
+        })
+        .max()
+        .unwrap_or(Verdict::Denied)
+}
+
+fn has_policy_type(policy: &NetworkPolicy, type_: &str) -> bool {
+    let spec = match &policy.spec {
+        Some(spec) => spec,
+        None => return false,
+    };
+
+    match &spec.policy_types {
+        Some(types) => types.iter().any(|t| t == type_),
+        None => type_ == "Ingress" || (type_ == "Egress" && spec.egress.is_some()),
+    }
+}
+
+fn selecting_policies<'a>(
+    policies: &'a [NetworkPolicy],
+    pod: &Pod,
+    type_: &str,
+) -> Vec<&'a NetworkPolicy> {
+    let labels = pod.metadata.labels.clone().unwrap_or_default();
+
+    policies
+        .iter()
+        .filter(|p| has_policy_type(p, type_))
+        .filter(|p| {
+            p.spec
+                .as_ref()
+                .is_some_and(|s| selector_matches(&s.pod_selector, &labels))
+        })
+        .collect()
+}
+
+fn ingress_verdict(policies: &[&NetworkPolicy], pod: &Pod, port: u16, from: Peer) -> Verdict {
+    if policies.is_empty() {
+        return Verdict::Allowed;
+    }
+
+    policies
+        .iter()
+        .flat_map(|p| p.spec.iter().flat_map(|s| s.ingress.iter().flatten()))
+        .filter(|rule| port_matches(rule.ports.as_ref(), pod, port))
+        .map(|rule| peers_match(rule.from.as_ref(), from))
+        .max()
+        .unwrap_or(Verdict::Denied)
+}
+
+fn egress_verdict(policies: &[&NetworkPolicy], pod: &Pod, port: u16, to: Peer) -> Verdict {
+    if policies.is_empty() {
+        return Verdict::Allowed;
+    }
+
+    policies
+        .iter()
+        .flat_map(|p| p.spec.iter().flat_map(|s| s.egress.iter().flatten()))
+        .filter(|rule| port_matches(rule.ports.as_ref(), pod, port))
+        .map(|rule| peers_match(rule.to.as_ref(), to))
+        .max()
+        .unwrap_or(Verdict::Denied)
+}
+
+fn policy_names(policies: &[&NetworkPolicy]) -> String {
+    policies
+        .iter()
+        .filter_map(|p| p.metadata.name.clone())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Check the network policies for rules that would block the traffic needed by vault and vault-mgmt
+///
+/// - connections to the vault API on the pods (used when port-forwarding is not available)
+/// - raft and request forwarding traffic between the vault pods
+pub fn check_network_policies(policies: &[NetworkPolicy], pods: &[Pod]) -> Vec<Finding> {
+    const CHECK: &str = "network-policy";
+
+    let mut findings = Vec::new();
+
+    for pod in pods {
+        let name = pod.metadata.name.clone().unwrap_or_default();
+        let labels = pod.metadata.labels.clone().unwrap_or_default();
+
+        let ingress = selecting_policies(policies, pod, "Ingress");
+        let egress = selecting_policies(policies, pod, "Egress");
+
+        match ingress_verdict(&ingress, pod, VAULT_PORT, Peer::Anywhere) {
+            Verdict::Allowed => {}
+            Verdict::Unknown => findings.push(Finding::new(
+                CHECK,
+                Severity::Warning,
+                format!(
+                    "{}: ingress to port {} is only allowed from selected sources by {}, connecting via pod IP may time out",
+                    name, VAULT_PORT, policy_names(&ingress)
+                ),
+            )),
+            Verdict::Denied => findings.push(Finding::new(
+                CHECK,
+                Severity::Warning,
+                format!(
+                    "{}: ingress to port {} is blocked by {}, connecting via pod IP will time out",
+                    name, VAULT_PORT, policy_names(&ingress)
+                ),
+            )),
+        }
+
+        for (direction, verdict, names) in [
+            (
+                "ingress",
+                ingress_verdict(&ingress, pod, VAULT_CLUSTER_PORT, Peer::VaultPod(&labels)),
+                policy_names(&ingress),
+            ),
+            (
+                "egress",
+                egress_verdict(&egress, pod, VAULT_CLUSTER_PORT, Peer::VaultPod(&labels)),
+                policy_names(&egress),
+            ),
+        ] {
+            match verdict {
+                Verdict::Allowed => {}
+                Verdict::Unknown => findings.push(Finding::new(
+                    CHECK,
+                    Severity::Warning,
+                    format!(
+                        "{}: {} on port {} between vault pods depends on namespace labels or IP blocks in {}",
+                        name, direction, VAULT_CLUSTER_PORT, names
+                    ),
+                )),
+                Verdict::Denied => findings.push(Finding::new(
+                    CHECK,
+                    Severity::Error,
+                    format!(
+                        "{}: {} on port {} between vault pods is blocked by {}, raft will not work",
+                        name, direction, VAULT_CLUSTER_PORT, names
+                    ),
+                )),
+            }
+        }
+    }
+
+    if findings.is_empty() {
+        findings.push(Finding::new(
+            CHECK,
+            Severity::Ok,
+            "no network policy is blocking vault traffic".to_string(),
+        ));
+    }
+
+    findings
+}
+
+/// List the network policies in the namespace and check them against the vault pods
+pub async fn diagnose_network_policies(
+    api: &Api<NetworkPolicy>,
+    pods: &[Pod],
+) -> anyhow::Result<Vec<Finding>> {
+    let policies = match api.list(&ListParams::default()).await {
+        Ok(policies) => policies.items,
+        Err(e) => {
+            return Ok(vec![Finding::new(
+                "network-policy",
+                Severity::Warning,
+                format!("could not list network policies: {}", e),
+            )])
+        }
+    };
+
+    Ok(check_network_policies(&policies, pods))
+}
+
+/// `retry_join` stanza of the raft storage configuration
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetryJoin {
+    pub leader_api_addr: Option<String>,
+    pub auto_join: Option<String>,
+    pub auto_join_scheme: Option<String>,
+    pub auto_join_port: Option<String>,
+}
+
+/// Read a quoted HCL string starting after the opening quote
+fn hcl_string(s: &str) -> String {
+    let mut value = String::new();
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(escaped) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            '"' => break,
+            c => value.push(c),
+        }
+    }
+
+    value
+}
+
+/// Extract the `retry_join` stanzas from a HCL vault configuration
+///
+/// This is not a full HCL parser, it only understands `key = "value"` and `key = number` assignments.
+pub fn parse_retry_join(config: &str) -> Vec<RetryJoin> {
+    let mut stanzas = Vec::new();
+    let mut rest = config;
+
+    while let Some(start) = rest.find("retry_join") {
+        rest = &rest[start + "retry_join".len()..];
+
+        let open = match rest.trim_start().strip_prefix('{') {
+            Some(open) => open,
+            None => continue,
+        };
+
+        let mut stanza = RetryJoin::default();
+
+        for line in open.lines() {
+            let line = line.trim();
+            if line.starts_with('}') {
+                break;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => continue,
+            };
+
+            let value = match value.strip_prefix('"') {
+                Some(quoted) => hcl_string(quoted),
+                None => value.trim_end_matches(',').to_string(),
+            };
+
+            match key {
+                "leader_api_addr" => stanza.leader_api_addr = Some(value),
+                "auto_join" => stanza.auto_join = Some(value),
+                "auto_join_scheme" => stanza.auto_join_scheme = Some(value),
+                "auto_join_port" => stanza.auto_join_port = Some(value),
+                _ => {}
+            }
+        }
+
+        stanzas.push(stanza);
+    }
+
+    stanzas
+}
+
+/// Split a go-discover configuration (`key=value key="quoted value"`) into its parts
+pub fn parse_auto_join(auto_join: &str) -> BTreeMap<String, String> {
+    let mut parts = BTreeMap::new();
+    let mut rest = auto_join.trim_start();
+
+    while let Some((key, value)) = rest.split_once('=') {
+        let (value, remaining) = match value.strip_prefix('"') {
+            Some(quoted) => {
+                let value = hcl_string(quoted);
+                // skip the value including the escape characters and the closing quote
+                let mut escaped = false;
+                let end = quoted
+                    .char_indices()
+                    .find(|(_, c)| {
+                        let end = !escaped && *c == '"';
+                        escaped = !escaped && *c == '\\';
+                        end
+                    })
+                    .map_or(quoted.len(), |(i, _)| i + 1);
+                (value, &quoted[end..])
+            }
+            None => match value.split_once(' ') {
+                Some((value, remaining)) => (value.to_string(), remaining),
+                None => (value.to_string(), ""),
+            },
+        };
+
+        parts.insert(key.trim().to_string(), value);
+        rest = remaining.trim_start();
+    }
+
+    parts
+}
+
+/// Parse a label selector in its string representation (e.g. `a=b,c!=d,e in (f,g),!h`)
+pub fn parse_label_selector(selector: &str) -> anyhow::Result<LabelSelector> {
+    let mut requirements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in selector.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                requirements.push(&selector[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    requirements.push(&selector[start..]);
+
+    let mut match_labels = BTreeMap::new();
+    let mut match_expressions = Vec::new();
+
+    let set = |values: &str| -> anyhow::Result<Vec<String>> {
+        Ok(values
+            .trim()
+            .strip_prefix('(')
+            .and_then(|v| v.strip_suffix(')'))
+            .ok_or(anyhow::anyhow!("invalid set in label selector: {}", values))?
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .collect())
+    };
+
+    for requirement in requirements
+        .into_iter()
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+    {
+        let expression = |key: &str, operator: &str, values: Option<Vec<String>>| {
+            k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement {
+                key: key.trim().to_string(),
+                operator: operator.to_string(),
+                values,
+            }
+        };
+
+        if let Some((key, value)) = requirement.split_once("!=") {
+            match_expressions.push(expression(
+                key,
+                "NotIn",
+                Some(vec![value.trim().to_string()]),
+            ));
+        } else if let Some((key, value)) =
+            requirement.split_once("==").or(requirement.split_once('='))
+        {
+            match_labels.insert(key.trim().to_string(), value.trim().to_string());
+        } else if let Some((key, values)) = requirement.split_once(" notin ") {
+            match_expressions.push(expression(key, "NotIn", Some(set(values)?)));
+        } else if let Some((key, values)) = requirement.split_once(" in ") {
+            match_expressions.push(expression(key, "In", Some(set(values)?)));
+        } else if let Some(key) = requirement.strip_prefix('!') {
+            match_expressions.push(expression(key, "DoesNotExist", None));
+        } else {
+            match_expressions.push(expression(requirement, "Exists", None));
+        }
+    }
+
+    Ok(LabelSelector {
+        match_labels: Some(match_labels),
+        match_expressions: Some(match_expressions),
+    })
+}
+
+/// Value of an environment variable of the vault container, see `find_vault_container`
+fn vault_env(pod: &Pod, container: Option<&str>, name: &str) -> Option<String> {
+    let containers = &pod.spec.as_ref()?.containers;
+
+    find_vault_container(containers, container)
+        .ok()?
+        .env
+        .iter()
+        .flatten()
+        .find(|e| e.name == name)
+        .and_then(|e| e.value.clone())
+}
+
+/// Check the `retry_join` configuration against the actual vault pods
+///
+/// - `leader_api_addr` has to point to an existing vault pod
+/// - `auto_join` with the k8s provider has to find all vault pods
+/// - `auto_join_scheme` and `auto_join_port` have to match the API address of the pods,
+///   read from the vault container named `container` or the detected one
+pub fn check_retry_join(
+    stanzas: &[RetryJoin],
+    pods: &[Pod],
+    container: Option<&str>,
+) -> Vec<Finding> {
+    const CHECK: &str = "retry-join";
+
+    let mut findings = Vec::new();
+
+    if stanzas.is_empty() {
+        return vec![Finding::new(
+            CHECK,
+            Severity::Ok,
+            "no retry_join configured, pods have to be joined manually".to_string(),
+        )];
+    }
+
+    let names: Vec<String> = pods
+        .iter()
+        .filter_map(|p| p.metadata.name.clone())
+        .collect();
+
+    let api_addr = pods
+        .iter()
+        .find_map(|p| vault_env(p, container, "VAULT_API_ADDR"))
+        .and_then(|a| a.parse::<http::Uri>().ok());
+
+    let mut joinable = std::collections::BTreeSet::new();
+
+    for stanza in stanzas {
+        if let Some(addr) = &stanza.leader_api_addr {
+            let host = addr
+                .parse::<http::Uri>()
+                .ok()
+                .and_then(|u| u.host().map(|h| unbracketed_host(h).to_string()))
+                .unwrap_or_default();
+            let pod = match host.parse::<IpAddr>() {
+                // an IPv4 or IPv6 literal has to be one of the IPs of a pod
+                Ok(ip) => pods
+                    .iter()
+                    .find(|p| {
+                        pod_ips(p)
+                            .iter()
+                            .any(|i| i.parse::<IpAddr>().ok() == Some(ip))
+                    })
+                    .and_then(|p| p.metadata.name.clone())
+                    .unwrap_or_default(),
+                Err(_) => host.split('.').next().unwrap_or_default().to_string(),
+            };
+
+            if names.contains(&pod) {
+                joinable.insert(pod);
+            } else {
+                findings.push(Finding::new(
+                    CHECK,
+                    Severity::Warning,
+                    format!(
+                        "leader_api_addr {} does not point to any vault pod ({})",
+                        addr,
+                        names.join(", ")
+                    ),
+                ));
+            }
+        }
+
+        if let Some(auto_join) = &stanza.auto_join {
+            let parts = parse_auto_join(auto_join);
+
+            if parts.get("provider").map(String::as_str) != Some("k8s") {
+                findings.push(Finding::new(
+                    CHECK,
+                    Severity::Ok,
+                    format!("auto_join provider of {} can not be verified", auto_join),
+                ));
+                continue;
+            }
+
+            let selector = match parts.get("label_selector").map(|s| parse_label_selector(s)) {
+                Some(Ok(selector)) => selector,
+                Some(Err(e)) => {
+                    findings.push(Finding::new(CHECK, Severity::Error, e.to_string()));
+                    continue;
+                }
+                None => LabelSelector::default(),
+            };
+
+            for pod in pods {
+                let name = pod.metadata.name.clone().unwrap_or_default();
+
+                let namespace_matches = match parts.get("namespace") {
+                    Some(ns) => pod.metadata.namespace.as_ref() == Some(ns),
+                    None => true,
+                };
+
+                if namespace_matches
+                    && selector_matches(&selector, &pod.metadata.labels.clone().unwrap_or_default())
+                {
+                    joinable.insert(name);
+                }
+            }
+
+            if let (Some(scheme), Some(api_addr)) = (&stanza.auto_join_scheme, &api_addr) {
+                if Some(scheme.as_str()) != api_addr.scheme_str() {
+                    findings.push(Finding::new(
+                        CHECK,
+                        Severity::Error,
+                        format!(
+                            "auto_join_scheme {} does not match the API address {} of the pods",
+                            scheme, api_addr
+                        ),
+                    ));
+                }
+            }
+
+            let port = stanza
+                .auto_join_port
+                .clone()
+                .unwrap_or(VAULT_PORT.to_string());
+            let api_port = api_addr
+                .as_ref()
+                .and_then(|a| a.port_u16())
+                .unwrap_or(VAULT_PORT);
+            if port != api_port.to_string() {
+                findings.push(Finding::new(
+                    CHECK,
+                    Severity::Error,
+                    format!(
+                        "auto_join_port {} does not match the API port {} of the pods",
+                        port, api_port
+                    ),
+                ));
+            }
+        }
+    }
+
+    if joinable.is_empty() {
+        findings.push(Finding::new(
+            CHECK,
+            Severity::Error,
+            "retry_join does not match any vault pod, new pods will not be able to join"
+                .to_string(),
+        ));
+    } else {
+        for name in names.iter().filter(|n| !joinable.contains(*n)) {
+            findings.push(Finding::new(
+                CHECK,
+                Severity::Warning,
+                format!("{}: not reachable via retry_join", name),
+            ));
+        }
+    }
+
+    if findings.is_empty() {
+        findings.push(Finding::new(
+            CHECK,
+            Severity::Ok,
+            format!("retry_join matches all vault pods ({})", names.join(", ")),
+        ));
+    }
+
+    findings
+}
+
+/// Read the vault configuration from the ConfigMaps mounted into the vault pods
+/// and check its `retry_join` stanzas
+pub async fn diagnose_retry_join(
+    api: &Api<ConfigMap>,
+    pods: &[Pod],
+    container: Option<&str>,
+) -> anyhow::Result<Vec<Finding>> {
+    let names: std::collections::BTreeSet<String> = pods
+        .iter()
+        .flat_map(|p| p.spec.iter().flat_map(|s| s.volumes.iter().flatten()))
+        .filter_map(|v| v.config_map.as_ref().and_then(|c| c.name.clone()))
+        .collect();
+
+    let mut stanzas = Vec::new();
+
+    for name in names {
+        let cm = match api.get(&name).await {
+            Ok(cm) => cm,
+            Err(e) => {
+                return Ok(vec![Finding::new(
+                    "retry-join",
+                    Severity::Warning,
+                    format!("could not read config map {}: {}", name, e),
+                )])
+            }
+        };
+
+        for config in cm.data.iter().flat_map(|d| d.values()) {
+            stanzas.append(&mut parse_retry_join(config));
+        }
+    }
+
+    Ok(check_retry_join(&stanzas, pods, container))
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::{
+        api::{
+            core::v1::{Pod, PodIP},
+            networking::v1::{
+                NetworkPolicy, NetworkPolicyIngressRule, NetworkPolicyPeer, NetworkPolicyPort,
+                NetworkPolicySpec,
+            },
+        },
+        apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+    };
+
+    use crate::{
+        check_network_policies, check_retry_join, parse_auto_join, parse_retry_join, Severity,
+    };
+
+    async fn pod() -> Pod {
+        pod_n(0).await
+    }
+
+    async fn pod_n(n: u8) -> Pod {
+        let file = tokio::fs::read_to_string(format!(
+            "tests/resources/installed/{}{}.yaml",
+            "api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-", n
+        ))
+        .await
+        .unwrap();
+
+        serde_yaml::from_str(&file).unwrap()
+    }
+
+    async fn pods() -> Vec<Pod> {
+        vec![pod_n(0).await, pod_n(1).await, pod_n(2).await]
+    }
+
+    fn config(instance: &str) -> String {
+        format!(
+            r#"
+storage "raft" {{
+  path = "/vault/data"
+
+  retry_join {{
+    auto_join = "provider=k8s label_selector=\"app.kubernetes.io/name=vault,component=server,app.kubernetes.io/instance={}\" namespace=\"vault-mgmt-e2e\""
+    auto_join_scheme = "http"
+  }}
+}}
+"#,
+            instance
+        )
+    }
+
+    fn vault_selector() -> LabelSelector {
+        LabelSelector {
+            match_labels: Some(
+                [("app.kubernetes.io/name".to_string(), "vault".to_string())].into(),
+            ),
+            ..Default::default()
+        }
+    }
+
+    fn policy(ingress: Vec<NetworkPolicyIngressRule>) -> NetworkPolicy {
+        NetworkPolicy {
+            metadata: kube::core::ObjectMeta {
+                name: Some("test".to_string()),
+                ..Default::default()
+            },
+            spec: Some(NetworkPolicySpec {
+                pod_selector: vault_selector(),
+                ingress: Some(ingress),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn port(port: IntOrString) -> NetworkPolicyPort {
+        NetworkPolicyPort {
+            port: Some(port),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn network_policies_are_ok_without_policies() {
+        let findings = check_network_policies(&[], &[pod().await]);
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Ok);
+    }
+
+    #[tokio::test]
+    async fn network_policies_report_blocked_raft() {
+        let findings = check_network_policies(
+            &[policy(vec![NetworkPolicyIngressRule {
+                ports: Some(vec![port(IntOrString::Int(8200))]),
+                from: None,
+            }])],
+            &[pod().await],
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert!(findings[0].message.contains("8201"));
+    }
+
+    #[tokio::test]
+    async fn network_policies_allow_raft_from_vault_pods_by_port_name() {
+        let findings = check_network_policies(
+            &[policy(vec![
+                NetworkPolicyIngressRule {
+                    ports: Some(vec![port(IntOrString::Int(8200))]),
+                    from: None,
+                },
+                NetworkPolicyIngressRule {
+                    ports: Some(vec![port(IntOrString::String(
+                        "https-internal".to_string(),
+                    ))]),
+                    from: Some(vec![NetworkPolicyPeer {
+                        pod_selector: Some(vault_selector()),
+                        ..Default::default()
+                    }]),
+                },
+            ])],
+            &[pod().await],
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Ok);
+    }
+
+    #[tokio::test]
+    async fn network_policies_warn_about_blocked_api() {
+        let findings = check_network_policies(
+            &[policy(vec![NetworkPolicyIngressRule {
+                ports: Some(vec![port(IntOrString::Int(8201))]),
+                from: None,
+            }])],
+            &[pod().await],
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert!(findings[0].message.contains("8200"));
+    }
+
+    #[test]
+    fn parsing_retry_join_works() {
+        let stanzas = parse_retry_join(&config("vault-mgmt-e2e-2274"));
+
+        assert_eq!(stanzas.len(), 1);
+        assert_eq!(stanzas[0].auto_join_scheme.as_deref(), Some("http"));
+
+        let auto_join = parse_auto_join(stanzas[0].auto_join.as_ref().unwrap());
+
+        assert_eq!(auto_join.get("provider").unwrap(), "k8s");
+        assert_eq!(
+            auto_join.get("label_selector").unwrap(),
+            "app.kubernetes.io/name=vault,component=server,app.kubernetes.io/instance=vault-mgmt-e2e-2274"
+        );
+        assert_eq!(auto_join.get("namespace").unwrap(), "vault-mgmt-e2e");
+    }
+
+    #[tokio::test]
+    async fn retry_join_matching_all_pods_is_ok() {
+        let findings = check_retry_join(
+            &parse_retry_join(&config("vault-mgmt-e2e-2274")),
+            &pods().await,
+            None,
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Ok);
+    }
+
+    #[tokio::test]
+    async fn retry_join_matching_no_pods_is_an_error() {
+        let findings = check_retry_join(
+            &parse_retry_join(&config("vault-mgmt-e2e-1234")),
+            &pods().await,
+            None,
+        );
+
+        assert!(findings.iter().any(|f| f.severity == Severity::Error));
+    }
+
+    #[tokio::test]
+    async fn retry_join_with_wrong_scheme_is_an_error() {
+        let findings = check_retry_join(
+            &parse_retry_join(
+                &config("vault-mgmt-e2e-2274").replace(r#"scheme = "http""#, r#"scheme = "https""#),
+            ),
+            &pods().await,
+            None,
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert!(findings[0].message.contains("auto_join_scheme"));
+    }
+
+    #[tokio::test]
+    async fn retry_join_with_stale_leader_api_addr_warns() {
+        let findings = check_retry_join(
+            &parse_retry_join(
+                r#"
+storage "raft" {
+  retry_join {
+    leader_api_addr = "http://vault-mgmt-e2e-2274-0.vault-mgmt-e2e-2274-internal:8200"
+  }
+  retry_join {
+    leader_api_addr = "http://vault-mgmt-e2e-2274-1.vault-mgmt-e2e-2274-internal:8200"
+  }
+  retry_join {
+    leader_api_addr = "http://vault-mgmt-e2e-2274-3.vault-mgmt-e2e-2274-internal:8200"
+  }
+}
+"#,
+            ),
+            &pods().await,
+            None,
+        );
+
+        assert_eq!(findings.len(), 2);
+        assert!(findings.iter().all(|f| f.severity == Severity::Warning));
+        assert!(findings[0].message.contains("vault-mgmt-e2e-2274-3"));
+        assert!(findings[1].message.starts_with("vault-mgmt-e2e-2274-2"));
+    }
+
+    #[tokio::test]
+    async fn retry_join_with_ip_leader_api_addr_matches_dual_stack_pods() {
+        let mut pods = pods().await;
+        for (n, pod) in pods.iter_mut().enumerate() {
+            let status = pod.status.as_mut().unwrap();
+            status.pod_ips.as_mut().unwrap().push(PodIP {
+                ip: Some(format!("fd00:10:42::{}", n + 1)),
+            });
+        }
+
+        let findings = check_retry_join(
+            &parse_retry_join(
+                r#"
+storage "raft" {
+  retry_join {
+    leader_api_addr = "http://10.42.2.27:8200"
+  }
+  retry_join {
+    leader_api_addr = "http://[fd00:10:42:0::2]:8200"
+  }
+  retry_join {
+    leader_api_addr = "http://[fd00:10:42::9]:8200"
+  }
+}
+"#,
+            ),
+            &pods,
+            None,
+        );
+
+        assert_eq!(findings.len(), 2, "{:?}", findings);
+        assert!(findings.iter().all(|f| f.severity == Severity::Warning));
+        assert!(findings[0].message.contains("fd00:10:42::9"));
+        assert!(findings[1].message.starts_with("vault-mgmt-e2e-2274-2"));
+    }
+}
@@ -6,7 +6,7 @@ use secrecy::{ExposeSecret, Secret};
 use std::collections::HashMap;
 use tokio::io::AsyncWriteExt;
 
-use crate::{list_vault_pods, LABEL_KEY_VAULT_ACTIVE, LABEL_KEY_VAULT_SEALED};
+use crate::{vault_container_name, PodSelector, LABEL_KEY_VAULT_ACTIVE, LABEL_KEY_VAULT_SEALED};
 
 #[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ExecIn {
@@ -25,6 +25,7 @@ impl std::fmt::Display for ExecIn {
 }
 
 impl ExecIn {
+    #[deprecated = "use `PodSelector::role` and `PodSelector::to_label_selector` instead"]
     pub fn to_label_selector(&self) -> String {
         match self {
             ExecIn::Active => format!("{}=true", LABEL_KEY_VAULT_ACTIVE),
@@ -34,22 +35,33 @@ impl ExecIn {
     }
 }
 
-#[tracing::instrument(skip_all, fields(cmd, exec_in = %exec_in))]
+/// Execute the command in the first vault pod with the role, see `exec_with`
+#[deprecated = "use `exec_with` and `PodSelector` instead"]
 pub async fn exec(
     api: &Api<Pod>,
     cmd: String,
     exec_in: ExecIn,
     env: HashMap<String, Secret<String>>,
 ) -> anyhow::Result<()> {
-    let pods = api
-        .list(&list_vault_pods().labels(&exec_in.to_label_selector()))
-        .await?;
+    exec_with(api, cmd, &PodSelector::default().role(exec_in), env).await
+}
+
+/// Execute the command in the first pod of the selector
+#[tracing::instrument(skip_all, fields(cmd, selector = %selector))]
+pub async fn exec_with(
+    api: &Api<Pod>,
+    cmd: String,
+    selector: &PodSelector,
+    env: HashMap<String, Secret<String>>,
+) -> anyhow::Result<()> {
+    let pods = api.list(&selector.to_list_params()).await?;
     let pod = pods
         .items
         .first()
         .ok_or(anyhow::anyhow!("no matching vault pod found"))?;
 
-    let (stdout, stderr) = exec_pod(api, pod, cmd, env).await?;
+    let attached = attach(api, pod, selector.container.as_deref(), cmd, env).await?;
+    let (stdout, stderr) = get_output(attached).await?;
 
     tokio::io::stdout().write_all(stdout.as_bytes()).await?;
     tokio::io::stderr().write_all(stderr.as_bytes()).await?;
@@ -69,6 +81,56 @@ pub async fn exec_pod(
     cmd: String,
     env: HashMap<String, Secret<String>>,
 ) -> anyhow::Result<(String, String)> {
+    let attached = attach(api, pod, None, cmd, env).await?;
+
+    get_output(attached).await
+}
+
+/// Execute the command in the pod like `exec_pod`, failing if it exits non-zero
+///
+/// The command runs in the container named `container`, or the detected vault container.
+#[tracing::instrument(
+    skip_all,
+    fields(pod = %pod.metadata.name.clone().ok_or(anyhow::anyhow!("pod does not have a name"))?,
+    cmd = %cmd,
+    env_vars = ?env.keys()),
+)]
+pub async fn exec_pod_checked(
+    api: &Api<Pod>,
+    pod: &Pod,
+    container: Option<&str>,
+    cmd: String,
+    env: HashMap<String, Secret<String>>,
+) -> anyhow::Result<(String, String)> {
+    let mut attached = attach(api, pod, container, cmd.clone(), env).await?;
+    let status = attached.take_status();
+
+    let (stdout, stderr) = get_output(attached).await?;
+
+    if let Some(status) = status {
+        if let Some(status) = status.await {
+            if status.status.as_deref() != Some("Success") {
+                anyhow::bail!(
+                    "{} failed: {} {}",
+                    cmd,
+                    status.message.unwrap_or_default(),
+                    stderr
+                );
+            }
+        }
+    }
+
+    Ok((stdout, stderr))
+}
+
+/// Start a shell in the vault container of the pod and pass the command to it
+async fn attach(
+    api: &Api<Pod>,
+    pod: &Pod,
+    container: Option<&str>,
+    cmd: String,
+    env: HashMap<String, Secret<String>>,
+) -> anyhow::Result<AttachedProcess> {
     let mut attached = api
         .exec(
             &pod.metadata
@@ -76,7 +138,9 @@ pub async fn exec_pod(
                 .clone()
                 .ok_or(anyhow::anyhow!("pod does not have a name"))?,
             vec!["sh"],
-            &AttachParams::default().stdin(true),
+            &AttachParams::default()
+                .stdin(true)
+                .container(vault_container_name(pod, container)?),
         )
         .await?;
 
@@ -93,9 +157,7 @@ pub async fn exec_pod(
 
     stdin_writer.write_all(cmd_with_env_vars.as_bytes()).await?;
 
-    let (stdout, stderr) = get_output(attached).await?;
-
-    Ok((stdout, stderr))
+    Ok(attached)
 }
 
 #[tracing::instrument(skip_all)]
@@ -0,0 +1,638 @@
+use std::future::Future;
+
+use http::{HeaderMap, Method, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::{body::Bytes, Request};
+use hyper_util::rt::TokioIo;
+use kube::api::AttachParams;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tracing::*;
+
+use crate::{vault_container_name, PodApi};
+
+/// Tool run in the vault container to send the requests of the exec transport
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExecHttpTool {
+    Curl,
+    Wget,
+}
+
+/// Headers set by the tools themselves
+const SKIPPED_HEADERS: [&str; 4] = ["host", "content-length", "connection", "transfer-encoding"];
+
+/// Shell script reading `$1` headers from stdin and running wget with the rest of the
+/// arguments for the url `$2`, leaving the body in stdin
+const WGET_SCRIPT: &str = r#"n=$1; url=$2; shift 2; i=0
+while [ "$i" -lt "$n" ]; do IFS= read -r h; set -- "$@" --header "$h"; i=$((i+1)); done
+exec wget "$@" "$url""#;
+
+impl ExecHttpTool {
+    /// Shell command printing the path of the first available tool
+    pub const DETECT: &'static str = "command -v curl || command -v wget";
+
+    /// Tool printed by `DETECT`
+    pub fn detect(output: &str) -> anyhow::Result<Self> {
+        let path = output.lines().next().unwrap_or_default().trim();
+
+        match path.rsplit('/').next() {
+            Some("curl") => Ok(Self::Curl),
+            Some("wget") => Ok(Self::Wget),
+            _ => anyhow::bail!("neither curl nor wget found in the vault container"),
+        }
+    }
+
+    /// Command line and stdin of the tool sending the request to the url
+    ///
+    /// curl reads its options from stdin, so the token is not visible in the command line.
+    /// wget is wrapped in a shell reading the headers from stdin for the same reason.
+    /// It only sends GET, HEAD (with `--spider`) and POST requests, which vault accepts
+    /// instead of PUT.
+    pub fn command(
+        &self,
+        method: &Method,
+        url: &str,
+        headers: &HeaderMap,
+        body: &[u8],
+    ) -> anyhow::Result<(Vec<String>, Vec<u8>)> {
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| !SKIPPED_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| Ok(format!("{}: {}", name, value.to_str()?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        match self {
+            Self::Curl => {
+                let body = std::str::from_utf8(body).map_err(|_| {
+                    anyhow::anyhow!("the exec transport cannot send binary request bodies")
+                })?;
+
+                let mut config = vec![
+                    "silent".to_string(),
+                    "show-error".to_string(),
+                    "include".to_string(),
+                    "http1.1".to_string(),
+                    // the connection does not leave the network namespace of the pod
+                    "insecure".to_string(),
+                    format!("request = {}", curl_quoted(method.as_str())),
+                    format!("url = {}", curl_quoted(url)),
+                    format!("header = {}", curl_quoted("Expect:")),
+                ];
+                config.extend(
+                    headers
+                        .iter()
+                        .map(|header| format!("header = {}", curl_quoted(header))),
+                );
+                if !body.is_empty() {
+                    config.push(format!("data-binary = {}", curl_quoted(body)));
+                }
+
+                Ok((
+                    vec!["curl".to_string(), "--config".to_string(), "-".to_string()],
+                    (config.join("\n") + "\n").into_bytes(),
+                ))
+            }
+            Self::Wget => {
+                let (post, spider) = match *method {
+                    Method::GET => (false, false),
+                    Method::HEAD => (false, true),
+                    Method::POST | Method::PUT => (true, false),
+                    _ => anyhow::bail!("wget cannot send {} requests", method),
+                };
+
+                let mut args = vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    WGET_SCRIPT.to_string(),
+                    "wget".to_string(),
+                    headers.len().to_string(),
+                    url.to_string(),
+                    "-S".to_string(),
+                    "-O".to_string(),
+                    "-".to_string(),
+                    "--no-check-certificate".to_string(),
+                ];
+                if post {
+                    args.push("--post-file".to_string());
+                    args.push("/dev/stdin".to_string());
+                }
+                if spider {
+                    args.push("--spider".to_string());
+                }
+
+                let mut stdin = headers
+                    .iter()
+                    .flat_map(|header| [header.as_bytes(), b"\n"].concat())
+                    .collect::<Vec<_>>();
+                stdin.extend_from_slice(body);
+
+                Ok((args, stdin))
+            }
+        }
+    }
+
+    /// Response of the output of the tool
+    ///
+    /// wget does not print the body of error responses, so they get a vault error body
+    /// naming the status instead.
+    pub fn response(
+        &self,
+        stdout: Vec<u8>,
+        stderr: &str,
+        success: bool,
+    ) -> anyhow::Result<Response<Bytes>> {
+        match self {
+            Self::Curl => {
+                if !success {
+                    anyhow::bail!("curl failed: {}", stderr.trim());
+                }
+
+                curl_response(stdout)
+            }
+            // wget prints the headers to stderr and fails for error statuses
+            Self::Wget => {
+                let status = stderr
+                    .lines()
+                    .rev()
+                    .find_map(|line| status_of_line(line.trim()))
+                    .ok_or(anyhow::anyhow!("wget failed: {}", stderr.trim()))?;
+
+                // e.g. the connection was lost while reading the body
+                if status.is_success() && !success {
+                    anyhow::bail!("wget failed after a {} response: {}", status, stderr.trim());
+                }
+
+                let body = match (status.is_success(), stdout.is_empty()) {
+                    (false, true) => serde_json::to_vec(&serde_json::json!({
+                        "errors": [format!("{} (wget does not return the response body)", status)],
+                    }))?,
+                    _ => stdout,
+                };
+
+                Ok(Response::builder().status(status).body(Bytes::from(body))?)
+            }
+        }
+    }
+}
+
+/// Quote the value for a curl config file
+fn curl_quoted(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Status of a status line like `HTTP/1.1 200 OK`
+fn status_of_line(line: &str) -> Option<StatusCode> {
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+
+    StatusCode::from_bytes(parts.next()?.as_bytes()).ok()
+}
+
+/// Response of the output of `curl --include`, skipping informational responses
+fn curl_response(output: Vec<u8>) -> anyhow::Result<Response<Bytes>> {
+    let mut output = Bytes::from(output);
+
+    loop {
+        let end = output
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or(anyhow::anyhow!("curl printed no response headers"))?;
+        let head = String::from_utf8_lossy(&output[..end]).to_string();
+        let body = output.split_off(end + 4);
+
+        let mut lines = head.lines();
+        let status = lines
+            .next()
+            .and_then(status_of_line)
+            .ok_or(anyhow::anyhow!("curl printed no status line"))?;
+        if status.is_informational() {
+            output = body;
+            continue;
+        }
+
+        let mut response = Response::builder().status(status);
+        for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+            let name = name.trim().to_lowercase();
+            if !SKIPPED_HEADERS.contains(&name.as_str()) {
+                response = response.header(name, value.trim());
+            }
+        }
+
+        return Ok(response.body(body)?);
+    }
+}
+
+/// Buffer of the in-memory stream between the client and `serve_over_exec`
+const EXEC_HTTP_BUFFER: usize = 64 * 1024;
+
+/// Serve the HTTP requests written to the returned stream with `send`
+///
+/// A failing `send` is answered with `502 Bad Gateway` and the error as body.
+pub fn serve_over_exec<F, Fut>(send: F) -> DuplexStream
+where
+    F: Fn(Request<Bytes>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<Response<Bytes>>> + Send + 'static,
+{
+    let (client, server) = tokio::io::duplex(EXEC_HTTP_BUFFER);
+
+    let service = hyper::service::service_fn(move |req: Request<hyper::body::Incoming>| {
+        let send = send.clone();
+        async move {
+            let (parts, body) = req.into_parts();
+            let body = body.collect().await?.to_bytes();
+
+            let response = match send(Request::from_parts(parts, body)).await {
+                Ok(response) => response,
+                Err(e) => Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Bytes::from(format!("{:#}", e)))
+                    .expect("response is valid"),
+            };
+
+            Ok::<_, hyper::Error>(response.map(Full::<Bytes>::new))
+        }
+    });
+
+    tokio::spawn(async move {
+        if let Err(e) = hyper::server::conn::http1::Builder::new()
+            .serve_connection(TokioIo::new(server), service)
+            .await
+        {
+            debug!("serving requests over exec: {}", e);
+        }
+    });
+
+    client
+}
+
+impl PodApi {
+    /// Run the command in the container of the pod with the stdin,
+    /// returning stdout, stderr and if it succeeded
+    async fn run_in_container(
+        &self,
+        pod: &str,
+        container: &str,
+        command: Vec<String>,
+        stdin: Vec<u8>,
+    ) -> anyhow::Result<(Vec<u8>, String, bool)> {
+        let mut attached = self
+            .api
+            .exec(
+                pod,
+                command,
+                &AttachParams::default()
+                    .stdin(true)
+                    .stdout(true)
+                    .stderr(true)
+                    .container(container),
+            )
+            .await?;
+
+        let mut writer = attached
+            .stdin()
+            .ok_or(anyhow::anyhow!("no stdin available"))?;
+        let mut stdout = attached
+            .stdout()
+            .ok_or(anyhow::anyhow!("no stdout available"))?;
+        let mut stderr = attached
+            .stderr()
+            .ok_or(anyhow::anyhow!("no stderr available"))?;
+        let status = attached.take_status();
+
+        let write = async {
+            writer.write_all(&stdin).await?;
+            writer.shutdown().await
+        };
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let (written, read_out, read_err) = tokio::join!(
+            write,
+            stdout.read_to_end(&mut out),
+            stderr.read_to_end(&mut err)
+        );
+        written?;
+        read_out?;
+        read_err?;
+
+        let success = match status {
+            Some(status) => status
+                .await
+                .is_some_and(|s| s.status.as_deref() == Some("Success")),
+            None => false,
+        };
+        attached.join().await?;
+
+        Ok((out, String::from_utf8_lossy(&err).to_string(), success))
+    }
+
+    /// Get a stream speaking plain HTTP to the port of the pod, sending each request with
+    /// `curl` or `wget` run in the vault container, whichever it has
+    ///
+    /// The tool connects with TLS if the api uses TLS, without verifying the certificate
+    /// as the connection does not leave the pod.
+    pub async fn exec_http_stream(&self, pod: &str, port: u16) -> anyhow::Result<DuplexStream> {
+        let container = vault_container_name(
+            &self.api.get(pod).await?,
+            self.selector.container.as_deref(),
+        )?;
+
+        let detect = vec![
+            "sh".to_string(),
+            "-c".to_string(),
+            ExecHttpTool::DETECT.to_string(),
+        ];
+        let (found, _, _) = self
+            .run_in_container(pod, &container, detect, vec![])
+            .await?;
+        let tool = ExecHttpTool::detect(&String::from_utf8_lossy(&found))?;
+        debug!("sending requests to {} with {:?} over exec", pod, tool);
+
+        let scheme = if self.tls { "https" } else { "http" };
+        let pods = self.clone();
+        let pod = pod.to_string();
+
+        Ok(serve_over_exec(move |req: Request<Bytes>| {
+            let (pods, pod, container) = (pods.clone(), pod.clone(), container.clone());
+            async move {
+                let (parts, body) = req.into_parts();
+                let path = parts
+                    .uri
+                    .path_and_query()
+                    .map(|p| p.as_str())
+                    .unwrap_or("/");
+                let url = format!("{}://127.0.0.1:{}{}", scheme, port, path);
+
+                let (command, stdin) = tool.command(&parts.method, &url, &parts.headers, &body)?;
+                let (stdout, stderr, success) = pods
+                    .run_in_container(&pod, &container, command, stdin)
+                    .await?;
+
+                tool.response(stdout, &stderr, success)
+            }
+        }))
+    }
+}
+
+/// Shell command tunneling stdin and stdout to the port with `nc` or `socat`
+pub fn exec_tunnel_command(port: u16) -> Vec<String> {
+    vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        format!(
+            "if command -v nc >/dev/null; then exec nc 127.0.0.1 {port}; \
+             elif command -v socat >/dev/null; then exec socat - TCP:127.0.0.1:{port}; \
+             else echo 'neither nc nor socat found in the vault container' >&2; exit 127; fi",
+            port = port
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{HeaderMap, Method, Response, StatusCode};
+    use http_body_util::{BodyExt, Empty};
+    use hyper::{body::Bytes, Request};
+
+    use crate::{
+        exec_tunnel_command, serve_over_exec, ExecHttpTool, HttpForwarderService, HttpRequest,
+    };
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", "127.0.0.1".parse().unwrap());
+        headers.insert("X-Vault-Token", "s.secret".parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn available_tool_is_detected() {
+        assert_eq!(
+            ExecHttpTool::detect("/usr/bin/curl\n").unwrap(),
+            ExecHttpTool::Curl
+        );
+        assert_eq!(
+            ExecHttpTool::detect("/bin/wget\n").unwrap(),
+            ExecHttpTool::Wget
+        );
+        assert!(ExecHttpTool::detect("").is_err());
+    }
+
+    #[test]
+    fn curl_reads_the_request_from_stdin() {
+        let (command, stdin) = ExecHttpTool::Curl
+            .command(
+                &Method::PUT,
+                "https://127.0.0.1:8200/v1/sys/unseal",
+                &headers(),
+                br#"{"key": "a\"b"}"#,
+            )
+            .unwrap();
+
+        assert_eq!(command, vec!["curl", "--config", "-"]);
+        let config = String::from_utf8(stdin).unwrap();
+        assert!(config.contains("request = \"PUT\"\n"));
+        assert!(config.contains("url = \"https://127.0.0.1:8200/v1/sys/unseal\"\n"));
+        assert!(config.contains("header = \"x-vault-token: s.secret\"\n"));
+        assert!(config.contains(r#"data-binary = "{\"key\": \"a\\\"b\"}""#));
+        assert!(!config.contains("host:"));
+
+        assert!(ExecHttpTool::Curl
+            .command(&Method::PUT, "http://127.0.0.1:8200/", &headers(), &[0xff])
+            .is_err());
+    }
+
+    #[test]
+    fn wget_posts_the_body_from_stdin() {
+        let (command, stdin) = ExecHttpTool::Wget
+            .command(
+                &Method::PUT,
+                "http://127.0.0.1:8200/v1/sys/unseal",
+                &headers(),
+                b"{}",
+            )
+            .unwrap();
+
+        assert_eq!(
+            command[3..],
+            [
+                "wget",
+                "1",
+                "http://127.0.0.1:8200/v1/sys/unseal",
+                "-S",
+                "-O",
+                "-",
+                "--no-check-certificate",
+                "--post-file",
+                "/dev/stdin",
+            ]
+        );
+        assert_eq!(stdin, b"x-vault-token: s.secret\n{}");
+
+        assert!(ExecHttpTool::Wget
+            .command(&Method::DELETE, "http://127.0.0.1:8200/", &headers(), b"")
+            .is_err());
+    }
+
+    #[test]
+    fn wget_reads_the_token_from_stdin() {
+        for method in [Method::GET, Method::PUT] {
+            let (command, _) = ExecHttpTool::Wget
+                .command(
+                    &method,
+                    "http://127.0.0.1:8200/v1/sys/health",
+                    &headers(),
+                    b"",
+                )
+                .unwrap();
+
+            assert!(command.iter().all(|arg| !arg.contains("s.secret")));
+        }
+    }
+
+    #[test]
+    fn responses_are_parsed_from_the_output() {
+        let response = ExecHttpTool::Curl
+            .response(
+                b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 400 Bad Request\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\n\r\n{\"errors\":[]}".to_vec(),
+                "",
+                true,
+            )
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["content-type"], "application/json");
+        assert!(!response.headers().contains_key("transfer-encoding"));
+        assert_eq!(response.body(), "{\"errors\":[]}");
+
+        assert!(ExecHttpTool::Curl
+            .response(vec![], "curl: (7) Failed to connect", false)
+            .is_err());
+
+        let response = ExecHttpTool::Wget
+            .response(
+                vec![],
+                "Connecting to 127.0.0.1:8200\n  HTTP/1.1 404 Not Found\nwget: server returned error: HTTP/1.1 404 Not Found\n",
+                false,
+            )
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert!(ExecHttpTool::Wget
+            .response(vec![], "wget: can't connect to remote host", false)
+            .is_err());
+    }
+
+    #[test]
+    fn wget_sends_head_requests_as_spider() {
+        let (command, _) = ExecHttpTool::Wget
+            .command(
+                &Method::HEAD,
+                "http://127.0.0.1:8200/v1/sys/health",
+                &headers(),
+                b"",
+            )
+            .unwrap();
+
+        assert_eq!(command.last().unwrap(), "--spider");
+    }
+
+    #[tokio::test]
+    async fn wget_failures_are_served_over_exec() {
+        let stream = serve_over_exec(|req: Request<Bytes>| async move {
+            match req.uri().path() {
+                // the body was cut off after the status line
+                "/v1/sys/raft/snapshot" => ExecHttpTool::Wget.response(
+                    b"partial".to_vec(),
+                    "  HTTP/1.1 200 OK\nwget: error getting response: Connection reset by peer\n",
+                    false,
+                ),
+                _ => ExecHttpTool::Wget.response(
+                    vec![],
+                    "  HTTP/1.1 403 Forbidden\nwget: server returned error: HTTP/1.1 403 Forbidden\n",
+                    false,
+                ),
+            }
+        });
+        let mut http = HttpForwarderService::http(stream).await.unwrap();
+
+        for (path, status, body) in [
+            (
+                "/v1/sys/raft/snapshot",
+                StatusCode::BAD_GATEWAY,
+                "wget failed after a 200 OK response",
+            ),
+            (
+                "/v1/sys/step-down",
+                StatusCode::FORBIDDEN,
+                "403 Forbidden (wget does not return the response body)",
+            ),
+        ] {
+            let response = http
+                .send_request(
+                    Request::get(path)
+                        .header("Host", "127.0.0.1")
+                        .body(Empty::<Bytes>::new().boxed())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+            assert!(
+                String::from_utf8_lossy(response.body()).contains(body),
+                "{:?}",
+                response.body()
+            );
+        }
+    }
+
+    #[test]
+    fn tunnel_uses_the_available_tool() {
+        let command = exec_tunnel_command(8200).join(" ");
+
+        assert!(command.contains("exec nc 127.0.0.1 8200"));
+        assert!(command.contains("exec socat - TCP:127.0.0.1:8200"));
+    }
+
+    #[tokio::test]
+    async fn requests_are_served_over_exec() {
+        let stream = serve_over_exec(|req: Request<Bytes>| async move {
+            match req.uri().path() {
+                "/v1/sys/health" => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .body(Bytes::from(req.method().to_string()))?),
+                _ => anyhow::bail!("no curl or wget"),
+            }
+        });
+        let mut http = HttpForwarderService::http(stream).await.unwrap();
+
+        for (path, status, body) in [
+            ("/v1/sys/health", StatusCode::OK, "GET"),
+            ("/v1/sys/other", StatusCode::BAD_GATEWAY, "no curl or wget"),
+        ] {
+            let response = http
+                .send_request(
+                    Request::get(path)
+                        .header("Host", "127.0.0.1")
+                        .body(Empty::<Bytes>::new().boxed())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), status);
+            assert_eq!(response.body(), body);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::time::{Duration, SystemTime};
+
+use clap::ValueEnum;
+
+/// How to format durations and timestamps in tables
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TimeFormat {
+    /// human-friendly, e.g. `3d4h` for a duration and the age for a timestamp
+    #[default]
+    Relative,
+    /// ISO 8601, e.g. `PT273600S` for a duration and `2024-08-12T10:00:00Z` for a timestamp
+    Iso,
+}
+
+impl std::fmt::Display for TimeFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// Format a duration, showing the two most significant units in relative format
+pub fn format_duration(duration: Duration, format: TimeFormat) -> String {
+    let secs = duration.as_secs();
+
+    match format {
+        TimeFormat::Iso => format!("PT{}S", secs),
+        TimeFormat::Relative => {
+            let units = [
+                (secs / 86400, "d"),
+                (secs % 86400 / 3600, "h"),
+                (secs % 3600 / 60, "m"),
+                (secs % 60, "s"),
+            ];
+
+            let formatted: String = units
+                .iter()
+                .skip_while(|(value, _)| *value == 0)
+                .take(2)
+                .filter(|(value, _)| *value != 0)
+                .map(|(value, unit)| format!("{}{}", value, unit))
+                .collect();
+
+            if formatted.is_empty() {
+                "0s".to_string()
+            } else {
+                formatted
+            }
+        }
+    }
+}
+
+/// Format a timestamp, as the time elapsed until `now` in relative format
+pub fn format_timestamp(time: SystemTime, now: SystemTime, format: TimeFormat) -> String {
+    match format {
+        TimeFormat::Iso => humantime::format_rfc3339_seconds(time).to_string(),
+        TimeFormat::Relative => {
+            format_duration(now.duration_since(time).unwrap_or(Duration::ZERO), format)
+        }
+    }
+}
+
+/// Parse a timestamp reported by the vault API
+///
+/// Vault reports the zero time (`0001-01-01T00:00:00Z`) for events that never happened,
+/// these and unparseable timestamps are returned as `None`.
+pub fn parse_vault_timestamp(time: &str) -> Option<SystemTime> {
+    humantime::parse_rfc3339_weak(time)
+        .ok()
+        .filter(|t| *t > SystemTime::UNIX_EPOCH)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{format_duration, format_timestamp, parse_vault_timestamp, TimeFormat};
+
+    #[test]
+    fn formatting_relative_durations_works() {
+        for (secs, expected) in [
+            (0, "0s"),
+            (42, "42s"),
+            (192, "3m12s"),
+            (3600, "1h"),
+            (3660, "1h1m"),
+            (3661, "1h1m"),
+            (86400 + 59, "1d"),
+            (3 * 86400 + 4 * 3600 + 5, "3d4h"),
+        ] {
+            assert_eq!(
+                format_duration(Duration::from_secs(secs), TimeFormat::Relative),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn formatting_iso_durations_works() {
+        assert_eq!(
+            format_duration(Duration::from_millis(192_500), TimeFormat::Iso),
+            "PT192S"
+        );
+    }
+
+    #[test]
+    fn formatting_timestamps_works() {
+        let time = humantime::parse_rfc3339("2024-08-12T10:00:00Z").unwrap();
+        let now = time + Duration::from_secs(300);
+
+        assert_eq!(format_timestamp(time, now, TimeFormat::Relative), "5m");
+        assert_eq!(
+            format_timestamp(time, now, TimeFormat::Iso),
+            "2024-08-12T10:00:00Z"
+        );
+        // clock skew between the cluster and the local machine
+        assert_eq!(format_timestamp(now, time, TimeFormat::Relative), "0s");
+    }
+
+    #[test]
+    fn parsing_vault_timestamps_works() {
+        assert_eq!(
+            parse_vault_timestamp("2024-08-12T10:00:00.123456789Z"),
+            Some(
+                humantime::parse_rfc3339("2024-08-12T10:00:00Z").unwrap()
+                    + Duration::from_nanos(123456789)
+            )
+        );
+        assert_eq!(parse_vault_timestamp("0001-01-01T00:00:00Z"), None);
+        assert_eq!(parse_vault_timestamp(""), None);
+        assert!(parse_vault_timestamp("2024-08-12T10:00:00Z").unwrap() < SystemTime::now());
+    }
+}
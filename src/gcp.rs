@@ -0,0 +1,205 @@
+use base64::Engine;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use secrecy::{ExposeSecret, Secret};
+
+use crate::{
+    keys_from_document, BytesBody, HttpForwarderService, HttpRequest, KeyProvider, KeyRequest,
+    Keys, KeysFrom,
+};
+
+const SECRET_MANAGER_HOST: &str = "secretmanager.googleapis.com";
+const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// `gcp-sm://projects/<project>/secrets/<secret>[/versions/<version>]`, the payload holds the keys
+/// one per line or as JSON object, see `keys_from_document`. The version defaults to `latest`.
+pub struct GcpSecretManagerKeyProvider;
+
+impl GcpSecretManagerKeyProvider {
+    /// Resource names of the secret and of its version
+    fn names(from: &KeysFrom) -> anyhow::Result<(String, String)> {
+        let location = from.location().trim_matches('/');
+        let parts: Vec<&str> = location.split('/').collect();
+
+        match parts[..] {
+            ["projects", project, "secrets", secret] if !project.is_empty() && !secret.is_empty() => {
+                Ok((location.to_string(), format!("{}/versions/latest", location)))
+            }
+            ["projects", project, "secrets", secret, "versions", version]
+                if !project.is_empty() && !secret.is_empty() && !version.is_empty() =>
+            {
+                Ok((
+                    format!("projects/{}/secrets/{}", project, secret),
+                    location.to_string(),
+                ))
+            }
+            _ => anyhow::bail!(
+                "expected gcp-sm://projects/<project>/secrets/<secret>[/versions/<version>], got {}",
+                from
+            ),
+        }
+    }
+
+    /// Token of the application default credentials, e.g. workload identity on GKE
+    /// or `gcloud auth application-default login`
+    async fn token() -> anyhow::Result<Secret<String>> {
+        let provider = gcp_auth::provider().await?;
+        let token = provider.token(&[CLOUD_PLATFORM_SCOPE]).await?;
+
+        Ok(Secret::new(token.as_str().to_string()))
+    }
+
+    async fn client() -> anyhow::Result<HttpForwarderService<BytesBody>> {
+        let stream = tokio::net::TcpStream::connect((SECRET_MANAGER_HOST, 443)).await?;
+
+        HttpForwarderService::https(SECRET_MANAGER_HOST, stream).await
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for GcpSecretManagerKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let (_, version) = Self::names(from)?;
+        let token = Self::token().await?;
+
+        let payload = access_secret_version(&mut Self::client().await?, &version, token).await?;
+
+        keys_from_document(&payload, request.kind)
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        let (secret, _) = Self::names(from)?;
+        let token = Self::token().await?;
+
+        // only the metadata of the secret, so the keys are not read
+        secret_manager_get(&mut Self::client().await?, &secret, token).await?;
+
+        Ok(())
+    }
+}
+
+async fn secret_manager_get(
+    client: &mut impl HttpRequest<BytesBody>,
+    path: &str,
+    token: Secret<String>,
+) -> anyhow::Result<serde_json::Value> {
+    let req = hyper::Request::builder()
+        .uri(format!("/v1/{}", path))
+        .method(hyper::Method::GET)
+        .header("Host", SECRET_MANAGER_HOST)
+        .header("Authorization", format!("Bearer {}", token.expose_secret()))
+        .body(Empty::<Bytes>::new().boxed())?;
+
+    let (parts, body) = client.send_request(req).await?.into_parts();
+    let body = String::from_utf8(body.to_vec())?;
+
+    if !parts.status.is_success() {
+        anyhow::bail!("getting {}: {}", path, body);
+    }
+
+    serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))
+}
+
+/// Read the payload of the secret version, e.g. `projects/x/secrets/y/versions/latest`
+pub async fn access_secret_version(
+    client: &mut impl HttpRequest<BytesBody>,
+    version: &str,
+    token: Secret<String>,
+) -> anyhow::Result<String> {
+    let response = secret_manager_get(client, &format!("{}:access", version), token).await?;
+
+    let data = response["payload"]["data"]
+        .as_str()
+        .ok_or(anyhow::anyhow!("secret version {} has no payload", version))?;
+    let payload = base64::engine::general_purpose::STANDARD
+        .decode(data)
+        .map_err(|e| anyhow::anyhow!("decoding the payload of {}: {}", version, e))?;
+
+    Ok(String::from_utf8(payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use http::StatusCode;
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        access_secret_version, GcpSecretManagerKeyProvider, HttpForwarderService, KeysFrom,
+    };
+
+    #[test]
+    fn secret_names_are_parsed() {
+        let names =
+            |location: &str| GcpSecretManagerKeyProvider::names(&KeysFrom::new("gcp-sm", location));
+
+        assert_eq!(
+            names("projects/p/secrets/vault-keys").unwrap(),
+            (
+                "projects/p/secrets/vault-keys".to_string(),
+                "projects/p/secrets/vault-keys/versions/latest".to_string()
+            )
+        );
+        assert_eq!(
+            names("projects/p/secrets/vault-keys/versions/3").unwrap(),
+            (
+                "projects/p/secrets/vault-keys".to_string(),
+                "projects/p/secrets/vault-keys/versions/3".to_string()
+            )
+        );
+        assert!(names("vault-keys").is_err());
+        assert!(names("projects/p/secrets/").is_err());
+        assert!(names("projects/p/secrets/vault-keys/versions").is_err());
+    }
+
+    #[tokio::test]
+    async fn payload_of_secret_version_is_decoded() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path(
+                "/v1/projects/p/secrets/vault-keys/versions/latest:access",
+            ))
+            .and(header("Authorization", "Bearer token"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "name": "projects/p/secrets/vault-keys/versions/1",
+                    // "abc\ndef\n"
+                    "payload": { "data": "YWJjCmRlZgo=" },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let payload = access_secret_version(
+            &mut client,
+            "projects/p/secrets/vault-keys/versions/latest",
+            Secret::from_str("token").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(payload, "abc\ndef\n");
+
+        assert!(access_secret_version(
+            &mut client,
+            "projects/p/secrets/other/versions/latest",
+            Secret::from_str("token").unwrap(),
+        )
+        .await
+        .is_err());
+    }
+}
@@ -1,14 +1,65 @@
+use std::sync::{Arc, OnceLock};
+
+use clap::ValueEnum;
 use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
-use kube::{api::ListParams, Api};
+use kube::{api::AttachParams, Api};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::*;
 
-use crate::{BytesBody, HttpForwarderService};
+use crate::{
+    exec_tunnel_command, registration_label, unbracketed_host, vault_container_name,
+    ActiveStrategy, BytesBody, GetSealStatus, HttpForwarderService, Inconsistent, KubeTuning,
+    PodSelector, ReadYourWrites, Takeover,
+};
 
 pub const LABEL_KEY_VAULT_ACTIVE: &str = "vault-active";
 pub const LABEL_KEY_VAULT_SEALED: &str = "vault-sealed";
 
-pub fn list_vault_pods() -> ListParams {
-    ListParams::default().labels("app.kubernetes.io/name=vault")
+#[deprecated = "use `PodSelector::to_list_params` instead"]
+pub fn list_vault_pods() -> kube::api::ListParams {
+    PodSelector::default().to_list_params()
+}
+
+/// Field manager used for server-side apply
+pub const FIELD_MANAGER: &str = "vault-mgmt";
+
+/// IPs of the pod, the primary one first, followed by the other families on dual-stack clusters
+pub fn pod_ips(pod: &Pod) -> Vec<String> {
+    let Some(status) = pod.status.as_ref() else {
+        return Vec::new();
+    };
+
+    let mut ips: Vec<String> = status.pod_ip.iter().cloned().collect();
+    for ip in status
+        .pod_ips
+        .iter()
+        .flatten()
+        .filter_map(|ip| ip.ip.as_ref())
+    {
+        if !ips.contains(ip) {
+            ips.push(ip.clone());
+        }
+    }
+
+    ips
+}
+
+/// Connect to the port on the first of the IPs accepting the connection
+pub async fn connect_any(ips: &[String], port: u16) -> anyhow::Result<tokio::net::TcpStream> {
+    let mut err = anyhow::anyhow!("no IP to connect to");
+
+    for ip in ips {
+        let addr = (unbracketed_host(ip), port);
+        match tokio::net::TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => {
+                debug!("connecting to {} failed: {}", ip, e);
+                err = anyhow::anyhow!("{}: {}", ip, e);
+            }
+        }
+    }
+
+    Err(err)
 }
 
 /// Check if the vault pod is sealed based on its labels
@@ -16,9 +67,9 @@ pub fn list_vault_pods() -> ListParams {
 pub fn is_sealed(pod: &Pod) -> anyhow::Result<bool> {
     match pod.metadata.labels.as_ref() {
         None => Err(anyhow::anyhow!("pod does not have labels")),
-        Some(labels) => match labels.get(LABEL_KEY_VAULT_SEALED) {
-            Some(x) if x.as_str() == "true" => Ok(true),
-            Some(x) if x.as_str() == "false" => Ok(false),
+        Some(_) => match registration_label(pod, "sealed") {
+            Some("true") => Ok(true),
+            Some("false") => Ok(false),
             _ => Err(anyhow::anyhow!(
                 "pod does not have a {} label",
                 LABEL_KEY_VAULT_SEALED
@@ -32,9 +83,9 @@ pub fn is_sealed(pod: &Pod) -> anyhow::Result<bool> {
 pub fn is_active(pod: &Pod) -> anyhow::Result<bool> {
     match pod.metadata.labels.as_ref() {
         None => Err(anyhow::anyhow!("pod does not have labels")),
-        Some(labels) => match labels.get(LABEL_KEY_VAULT_ACTIVE) {
-            Some(x) if x.as_str() == "true" => Ok(true),
-            Some(x) if x.as_str() == "false" => Ok(false),
+        Some(_) => match registration_label(pod, "active") {
+            Some("true") => Ok(true),
+            Some("false") => Ok(false),
             _ => Err(anyhow::anyhow!(
                 "pod does not have a {} label",
                 LABEL_KEY_VAULT_ACTIVE
@@ -43,17 +94,216 @@ pub fn is_active(pod: &Pod) -> anyhow::Result<bool> {
     }
 }
 
+/// Reasons of a waiting container that cannot pull its image
+const IMAGE_PULL_FAILURES: [&str; 3] = ["ErrImagePull", "ImagePullBackOff", "InvalidImageName"];
+
+/// Returns the image and the reason (with message) if a container of the pod cannot pull its image
+pub fn image_pull_failure(pod: &Pod) -> Option<(String, String)> {
+    pod.status
+        .as_ref()?
+        .container_statuses
+        .as_ref()?
+        .iter()
+        .find_map(|status| {
+            let waiting = status.state.as_ref()?.waiting.as_ref()?;
+            let reason = waiting.reason.as_deref()?;
+
+            if !IMAGE_PULL_FAILURES.contains(&reason) {
+                return None;
+            }
+
+            Some((
+                status.image.clone(),
+                match &waiting.message {
+                    Some(message) => format!("{}: {}", reason, message),
+                    None => reason.to_string(),
+                },
+            ))
+        })
+}
+
+/// How to connect to the vault API of a pod
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// use port-forwarding and fall back to the other transports if it fails
+    #[default]
+    Auto,
+    /// forward the port through the kubernetes API
+    PortForward,
+    /// connect to the pod IP directly, only works when running inside the cluster
+    PodIp,
+    /// run `curl` or `wget` in the vault container for each request, raw connections
+    /// (e.g. of `port-forward`) are tunneled through `nc` or `socat` instead
+    Exec,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// Bidirectional byte stream to a port on a pod
+pub trait PodStream: AsyncRead + AsyncWrite + Unpin + Sync + Send {}
+
+impl<T> PodStream for T where T: AsyncRead + AsyncWrite + Unpin + Sync + Send {}
+
+/// Returns true if running inside a kubernetes cluster
+fn is_in_cluster() -> bool {
+    std::env::var("KUBERNETES_SERVICE_HOST").is_ok()
+}
+
 /// Wrapper around the kube::Api type for the Vault pod
 #[derive(Clone)]
 pub struct PodApi {
     pub api: Api<Pod>,
-    tls: bool,
+    pub(crate) tls: bool,
     domain: String,
+    wait_for_sidecars: bool,
+    pub(crate) use_eviction: bool,
+    pub(crate) partition: bool,
+    pub(crate) events: bool,
+    transport: Transport,
+    pub(crate) takeover: Takeover,
+    pub(crate) active_strategy: ActiveStrategy,
+    pub(crate) active_service: Option<String>,
+    pub(crate) statefulset: Option<(Api<StatefulSet>, String)>,
+    pub selector: PodSelector,
+    /// watch timeout and backoff of the waits for the pods, see `await_condition`
+    pub tuning: KubeTuning,
+    /// replication state passed on between the requests to the pods, see `ReadYourWrites`
+    read_your_writes: Option<ReadYourWrites>,
+    /// accessor of the token, set once it is looked up, see `token_accessor`
+    token_accessor: Arc<OnceLock<String>>,
+    #[cfg(feature = "chaos")]
+    chaos: Option<crate::Chaos>,
 }
 
 impl PodApi {
     pub fn new(api: Api<Pod>, tls: bool, domain: String) -> Self {
-        Self { api, tls, domain }
+        Self {
+            api,
+            tls,
+            domain,
+            wait_for_sidecars: false,
+            use_eviction: false,
+            partition: false,
+            events: false,
+            transport: Transport::default(),
+            takeover: Takeover::default(),
+            active_strategy: ActiveStrategy::default(),
+            active_service: None,
+            statefulset: None,
+            selector: PodSelector::default(),
+            tuning: KubeTuning::default(),
+            read_your_writes: None,
+            token_accessor: Arc::default(),
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    /// Set which pods are managed
+    pub fn selector(mut self, selector: PodSelector) -> Self {
+        self.selector = selector;
+        self
+    }
+
+    /// Set the watch timeout and backoff of the waits for the pods
+    pub fn tuning(mut self, tuning: KubeTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Send the replication state of writes with the following requests to the pods,
+    /// so performance standbys of Vault Enterprise serve reads after the writes
+    pub fn read_your_writes(mut self, inconsistent: Option<Inconsistent>) -> Self {
+        self.read_your_writes = inconsistent.map(ReadYourWrites::new);
+        self
+    }
+
+    /// Set how to connect to the vault API of the pods
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    /// Also wait for service mesh sidecars to be ready when waiting for a pod to be ready
+    /// By default, only the vault container has to be ready in pods with a service mesh sidecar
+    pub fn wait_for_sidecars(mut self, wait: bool) -> Self {
+        self.wait_for_sidecars = wait;
+        self
+    }
+
+    /// Delete pods through the Eviction API, so PodDisruptionBudgets are honored
+    pub fn use_eviction(mut self, use_eviction: bool) -> Self {
+        self.use_eviction = use_eviction;
+        self
+    }
+
+    /// Let the statefulset controller replace the pods by lowering the partition of the
+    /// RollingUpdate strategy one pod at a time instead of deleting them, see
+    /// `StatefulSetApi::hold_partition`. Restarts are not supported.
+    pub fn partition(mut self, partition: bool) -> Self {
+        self.partition = partition;
+        self
+    }
+
+    /// Publish Kubernetes events for the actions of upgrades and restarts
+    pub fn events(mut self, events: bool) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Set how to wait for another pod to take over after stepping down the active pod
+    pub fn takeover(mut self, takeover: Takeover) -> Self {
+        self.takeover = takeover;
+        self
+    }
+
+    /// Set how to upgrade or restart the active pod
+    pub fn active_strategy(mut self, strategy: ActiveStrategy) -> Self {
+        self.active_strategy = strategy;
+        self
+    }
+
+    /// Log the mutating requests to the pods with the accessor of the token, see `token_accessor`
+    ///
+    /// The accessor is kept by the clones of this api, the first one set is used.
+    pub fn token_accessor(&self, accessor: String) {
+        let _ = self.token_accessor.set(accessor);
+    }
+
+    /// Accessor of the token, if it was set
+    pub fn known_token_accessor(&self) -> Option<String> {
+        self.token_accessor.get().cloned()
+    }
+
+    /// Wait for the endpoints of the given Service (usually `<release>-active`)
+    /// to point at the new leader after stepping down the active pod
+    pub fn active_service(mut self, service: Option<String>) -> Self {
+        self.active_service = service;
+        self
+    }
+
+    /// Watch the replicas of the statefulset managing the pods during a rollout
+    pub fn statefulset(mut self, api: Api<StatefulSet>, name: &str) -> Self {
+        self.statefulset = Some((api, name.to_string()));
+        self
+    }
+
+    pub fn waits_for_sidecars(&self) -> bool {
+        self.wait_for_sidecars
+    }
+
+    /// Inject faults into the connections to the pods
+    #[cfg(feature = "chaos")]
+    pub fn chaos(mut self, chaos: crate::Chaos) -> Self {
+        self.chaos = Some(chaos);
+        self
     }
 }
 
@@ -73,12 +323,166 @@ impl PodApi {
         ))
     }
 
+    /// Get a stream to a port on a pod by connecting to the pod IP
+    /// This only works if the pod IP is reachable, e.g. when running inside the cluster
+    /// On dual-stack clusters the IPs of the other family are tried if the primary one fails
+    pub async fn pod_ip_stream(
+        &self,
+        pod: &str,
+        port: u16,
+    ) -> anyhow::Result<tokio::net::TcpStream> {
+        let ips = pod_ips(&self.api.get(pod).await?);
+
+        connect_any(&ips, port)
+            .await
+            .map_err(|e| anyhow::anyhow!("connecting to pod {}: {}", pod, e))
+    }
+
+    /// Get a stream to a port on a pod by running `nc` or `socat` in the vault container
+    /// and tunneling the connection through its stdin and stdout, see `exec_tunnel_command`
+    pub async fn exec_stream(
+        &self,
+        pod: &str,
+        port: u16,
+    ) -> anyhow::Result<impl AsyncRead + AsyncWrite + Unpin> {
+        let container = vault_container_name(
+            &self.api.get(pod).await?,
+            self.selector.container.as_deref(),
+        )?;
+
+        let mut attached = self
+            .api
+            .exec(
+                pod,
+                exec_tunnel_command(port),
+                &AttachParams::default()
+                    .stdin(true)
+                    .stderr(false)
+                    .container(container),
+            )
+            .await?;
+
+        let stdin = attached
+            .stdin()
+            .ok_or(anyhow::anyhow!("no stdin available"))?;
+        let stdout = attached
+            .stdout()
+            .ok_or(anyhow::anyhow!("no stdout available"))?;
+
+        Ok(tokio::io::join(stdout, stdin))
+    }
+
+    /// Get a stream to a port on a pod using the configured transport
+    pub async fn stream(&self, pod: &str, port: u16) -> anyhow::Result<Box<dyn PodStream>> {
+        match self.transport {
+            Transport::Auto => {
+                first_connected(pod, &auto_transports(is_in_cluster()), |transport| {
+                    self.stream_with(transport, pod, port)
+                })
+                .await
+            }
+            transport => self.stream_with(transport, pod, port).await,
+        }
+    }
+
+    async fn stream_with(
+        &self,
+        transport: Transport,
+        pod: &str,
+        port: u16,
+    ) -> anyhow::Result<Box<dyn PodStream>> {
+        match transport {
+            Transport::PortForward | Transport::Auto => {
+                Ok(Box::new(self.portforward(pod, port).await?))
+            }
+            Transport::PodIp => Ok(Box::new(self.pod_ip_stream(pod, port).await?)),
+            Transport::Exec => Ok(Box::new(self.exec_stream(pod, port).await?)),
+        }
+    }
+
+    /// Connect to the vault API of the pod using the configured transport
+    ///
+    /// With `Transport::Auto` every transport is checked with the TLS handshake, or a
+    /// request without TLS, before falling back to the next one, so a port-forward that is
+    /// established but does not pass any data is not used.
     pub async fn http(
         &self,
         pod: &str,
         port: u16,
     ) -> anyhow::Result<HttpForwarderService<BytesBody>> {
-        let pf = self.portforward(pod, port).await?;
+        let mut http = match self.transport {
+            Transport::Auto => {
+                first_connected(
+                    pod,
+                    &auto_transports(is_in_cluster()),
+                    |transport| async move {
+                        tokio::time::timeout(
+                            AUTO_CONNECT_TIMEOUT,
+                            self.checked_http(transport, pod, port),
+                        )
+                        .await
+                        .map_err(|_| anyhow::anyhow!("timed out"))?
+                    },
+                )
+                .await?
+            }
+            transport => self.http_with(transport, pod, port).await?,
+        };
+        http.set_token_accessor(self.known_token_accessor());
+        http.set_read_your_writes(self.read_your_writes.clone());
+
+        Ok(http)
+    }
+
+    /// Connect like `http_with`, checking that the connection passes data
+    async fn checked_http(
+        &self,
+        transport: Transport,
+        pod: &str,
+        port: u16,
+    ) -> anyhow::Result<HttpForwarderService<BytesBody>> {
+        let mut http = self.http_with(transport, pod, port).await?;
+
+        // the TLS handshake already passed data, and exec runs a command for each request
+        if !self.tls && transport != Transport::Exec {
+            http.seal_status().await?;
+        }
+
+        Ok(http)
+    }
+
+    async fn http_with(
+        &self,
+        transport: Transport,
+        pod: &str,
+        port: u16,
+    ) -> anyhow::Result<HttpForwarderService<BytesBody>> {
+        // the tools run by exec speak TLS themselves
+        if transport == Transport::Exec {
+            let stream = self.exec_http_stream(pod, port).await?;
+
+            #[cfg(feature = "chaos")]
+            if let Some(chaos) = &self.chaos {
+                return HttpForwarderService::http(chaos.stream(stream)).await;
+            }
+
+            return HttpForwarderService::http(stream).await;
+        }
+
+        let pf = self.stream_with(transport, pod, port).await?;
+
+        // faults are injected above TLS, so injected responses can be read
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.chaos {
+            if self.tls {
+                let tls =
+                    crate::http::setup_tls_with(&self.domain, pf, &crate::TlsOptions::default())
+                        .await?;
+                return HttpForwarderService::http(chaos.stream(tls)).await;
+            }
+
+            return HttpForwarderService::http(chaos.stream(pf)).await;
+        }
 
         if self.tls {
             return HttpForwarderService::https(&self.domain, pf).await;
@@ -88,13 +492,146 @@ impl PodApi {
     }
 }
 
+/// Time to connect with one transport before `Transport::Auto` falls back to the next one
+const AUTO_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Transports tried by `Transport::Auto` in order, the pod IP only works inside the cluster
+pub fn auto_transports(in_cluster: bool) -> Vec<Transport> {
+    let mut transports = vec![Transport::PortForward];
+    if in_cluster {
+        transports.push(Transport::PodIp);
+    }
+    transports.push(Transport::Exec);
+
+    transports
+}
+
+/// Connect to the pod with the first transport that works, in order
+pub async fn first_connected<T, F, Fut>(
+    pod: &str,
+    transports: &[Transport],
+    connect: F,
+) -> anyhow::Result<T>
+where
+    F: Fn(Transport) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<T>>,
+{
+    let mut errors = vec![];
+    for transport in transports {
+        match connect(*transport).await {
+            Ok(connected) => return Ok(connected),
+            Err(e) => {
+                warn!("connecting to {} with {} failed: {:#}", pod, transport, e);
+                errors.push(format!("{}: {:#}", transport, e));
+            }
+        }
+    }
+
+    anyhow::bail!(
+        "connecting to {} failed with all transports ({})",
+        pod,
+        errors.join("; ")
+    )
+}
+
 /// Wrapper around the kube::Api type for the Vault statefulset
 pub struct StatefulSetApi {
     pub api: Api<StatefulSet>,
+    /// name of the vault container in the pod template, detected if not set
+    pub container: Option<String>,
 }
 
 impl From<Api<StatefulSet>> for StatefulSetApi {
     fn from(api: Api<StatefulSet>) -> Self {
-        Self { api }
+        Self {
+            api,
+            container: None,
+        }
+    }
+}
+
+impl StatefulSetApi {
+    /// Set the name of the vault container, see `find_vault_container`
+    pub fn container(mut self, container: Option<String>) -> Self {
+        self.container = container;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{Pod, PodIP, PodStatus};
+
+    use crate::{auto_transports, connect_any, first_connected, pod_ips, Transport};
+
+    #[test]
+    fn primary_pod_ip_comes_first() {
+        let pod = Pod {
+            status: Some(PodStatus {
+                pod_ip: Some("fd00:10:42::1".to_string()),
+                pod_ips: Some(vec![
+                    PodIP {
+                        ip: Some("10.42.2.27".to_string()),
+                    },
+                    PodIP {
+                        ip: Some("fd00:10:42::1".to_string()),
+                    },
+                ]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(pod_ips(&pod), vec!["fd00:10:42::1", "10.42.2.27"]);
+        assert!(pod_ips(&Pod::default()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn connecting_falls_back_to_the_next_ip() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let ips = ["127.0.0.2".to_string(), "127.0.0.1".to_string()];
+        let stream = connect_any(&ips, port).await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+
+        drop(listener);
+        assert!(connect_any(&ips, port).await.is_err());
+        assert!(connect_any(&[], port).await.is_err());
+    }
+
+    #[test]
+    fn auto_transport_tries_pod_ips_only_in_the_cluster() {
+        assert_eq!(
+            auto_transports(false),
+            vec![Transport::PortForward, Transport::Exec]
+        );
+        assert_eq!(
+            auto_transports(true),
+            vec![Transport::PortForward, Transport::PodIp, Transport::Exec]
+        );
+    }
+
+    #[tokio::test]
+    async fn connecting_falls_back_to_the_next_transport() {
+        let connected =
+            first_connected("vault-0", &auto_transports(true), |transport| async move {
+                match transport {
+                    Transport::Exec => Ok(transport),
+                    _ => anyhow::bail!("no data"),
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(connected, Transport::Exec);
+
+        let err = first_connected("vault-0", &auto_transports(false), |transport| async move {
+            Err::<(), _>(anyhow::anyhow!("{} blocked", transport))
+        })
+        .await
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("port-forward: port-forward blocked"));
+        assert!(err.contains("exec: exec blocked"));
     }
 }
@@ -0,0 +1,151 @@
+use std::{collections::BTreeMap, str::FromStr, time::SystemTime};
+
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    api::{Patch, PatchParams},
+    core::ObjectMeta,
+};
+
+use crate::{PodApi, VaultVersion};
+
+/// Annotation with the RFC 3339 timestamp of the last upgrade
+pub const ANNOTATION_LAST_UPGRADE: &str = "vault-mgmt.io/last-upgrade";
+/// Annotation with the vault version before the last upgrade
+pub const ANNOTATION_PREVIOUS_VERSION: &str = "vault-mgmt.io/previous-version";
+/// Annotation with the identity that did the last upgrade, e.g. `alice@laptop/1234`
+pub const ANNOTATION_UPGRADED_BY: &str = "vault-mgmt.io/upgraded-by";
+/// Annotation with the accessor of the vault token used for the last upgrade
+pub const ANNOTATION_TOKEN_ACCESSOR: &str = "vault-mgmt.io/token-accessor";
+
+/// Provenance of the last upgrade, stored in annotations of the pods and the statefulset
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeHistory {
+    pub last_upgrade: SystemTime,
+    pub previous_version: String,
+    pub upgraded_by: String,
+    /// accessor of the vault token used for the upgrade, if it was looked up
+    pub token_accessor: Option<String>,
+}
+
+impl UpgradeHistory {
+    /// History of an upgrade from `previous_version` done now by this process
+    pub fn now(previous_version: &str) -> Self {
+        Self {
+            last_upgrade: SystemTime::now(),
+            previous_version: previous_version.to_string(),
+            upgraded_by: crate::holder_identity(),
+            token_accessor: None,
+        }
+    }
+
+    /// Record the accessor of the vault token used for the upgrade
+    pub fn token_accessor(mut self, accessor: Option<String>) -> Self {
+        self.token_accessor = accessor;
+        self
+    }
+
+    /// Read the history from the annotations, `None` if the object was never upgraded
+    pub fn from_metadata(metadata: &ObjectMeta) -> Option<Self> {
+        let annotations = metadata.annotations.as_ref()?;
+        let get = |key: &str| annotations.get(key).cloned();
+
+        Some(Self {
+            last_upgrade: humantime::parse_rfc3339_weak(&get(ANNOTATION_LAST_UPGRADE)?).ok()?,
+            previous_version: get(ANNOTATION_PREVIOUS_VERSION)?,
+            upgraded_by: get(ANNOTATION_UPGRADED_BY).unwrap_or_else(|| "unknown".to_string()),
+            token_accessor: get(ANNOTATION_TOKEN_ACCESSOR),
+        })
+    }
+
+    pub fn to_annotations(&self) -> BTreeMap<String, String> {
+        let mut annotations = BTreeMap::from([
+            (
+                ANNOTATION_LAST_UPGRADE.to_string(),
+                humantime::format_rfc3339_seconds(self.last_upgrade).to_string(),
+            ),
+            (
+                ANNOTATION_PREVIOUS_VERSION.to_string(),
+                self.previous_version.clone(),
+            ),
+            (ANNOTATION_UPGRADED_BY.to_string(), self.upgraded_by.clone()),
+        ]);
+        if let Some(accessor) = &self.token_accessor {
+            annotations.insert(ANNOTATION_TOKEN_ACCESSOR.to_string(), accessor.clone());
+        }
+
+        annotations
+    }
+}
+
+/// Version the pods upgraded by this process had before, `None` if it did not upgrade any
+pub fn previous_version_of(pods: &[Pod]) -> anyhow::Result<Option<VaultVersion>> {
+    let identity = crate::holder_identity();
+
+    pods.iter()
+        .filter_map(|p| UpgradeHistory::from_metadata(&p.metadata))
+        .find(|h| h.upgraded_by == identity)
+        .map(|h| VaultVersion::from_str(&h.previous_version))
+        .transpose()
+}
+
+impl PodApi {
+    /// Annotate the pod and the statefulset with the history of the upgrade
+    pub(crate) async fn write_history(
+        &self,
+        pod: &str,
+        history: &UpgradeHistory,
+    ) -> anyhow::Result<()> {
+        let patch = Patch::Merge(serde_json::json!({
+            "metadata": {
+                "annotations": history.to_annotations(),
+            },
+        }));
+
+        self.api.patch(pod, &PatchParams::default(), &patch).await?;
+
+        if let Some((api, name)) = &self.statefulset {
+            api.patch(name, &PatchParams::default(), &patch).await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use kube::core::ObjectMeta;
+
+    use crate::{UpgradeHistory, ANNOTATION_TOKEN_ACCESSOR};
+
+    #[test]
+    fn history_round_trips_through_annotations() {
+        let history = UpgradeHistory {
+            last_upgrade: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            previous_version: "1.13.0".to_string(),
+            upgraded_by: "alice@laptop/1234".to_string(),
+            token_accessor: Some("8609694a-cdbc-db9b-d345-e782dbb562ed".to_string()),
+        };
+
+        let metadata = ObjectMeta {
+            annotations: Some(history.to_annotations()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            UpgradeHistory::from_metadata(&metadata),
+            Some(history.clone())
+        );
+
+        let history = history.token_accessor(None);
+        let annotations = history.to_annotations();
+        assert!(!annotations.contains_key(ANNOTATION_TOKEN_ACCESSOR));
+        let metadata = ObjectMeta {
+            annotations: Some(annotations),
+            ..Default::default()
+        };
+        assert_eq!(UpgradeHistory::from_metadata(&metadata), Some(history));
+        assert_eq!(UpgradeHistory::from_metadata(&ObjectMeta::default()), None);
+    }
+}
@@ -0,0 +1,365 @@
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+
+use futures_util::future::BoxFuture;
+use http::{Method, StatusCode, Uri};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{runtime::wait::Condition, Api};
+use tokio::process::Command;
+use tracing::*;
+
+use crate::{
+    exec_pod_checked, unbracketed_host, BytesBody, HttpForwarderService, HttpRequest, PodSealStatus,
+};
+
+/// Time to connect to the url of a hook or gate
+const URL_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time to wait for the response of the url of a hook or gate
+const URL_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+type HookFn = dyn Fn(Pod) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync;
+
+type GateFn = dyn Fn(Pod, PodSealStatus) -> BoxFuture<'static, anyhow::Result<bool>> + Send + Sync;
+
+/// Local shell command with the name and namespace of the pod
+/// in `VAULT_MGMT_POD` and `VAULT_MGMT_NAMESPACE`
+fn pod_command(cmd: &str, pod: Pod) -> Command {
+    let mut command = Command::new("sh");
+    // a command that does not finish within the timeout of the hook is killed
+    command
+        .kill_on_drop(true)
+        .arg("-c")
+        .arg(cmd)
+        .env("VAULT_MGMT_POD", pod.metadata.name.unwrap_or_default())
+        .env(
+            "VAULT_MGMT_NAMESPACE",
+            pod.metadata.namespace.unwrap_or_default(),
+        );
+    command
+}
+
+/// Send a request to the url, with the name and namespace of the pod as json body
+/// (`{"pod": "vault-1", "namespace": "vault"}`) for requests other than GET
+async fn send_to_url(uri: &Uri, method: Method, pod: &Pod) -> anyhow::Result<StatusCode> {
+    let host = uri
+        .host()
+        .ok_or(anyhow::anyhow!("{} does not have a host", uri))?;
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+    let stream = tokio::time::timeout(
+        URL_CONNECT_TIMEOUT,
+        tokio::net::TcpStream::connect((unbracketed_host(host), port)),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("connecting to {}: timed out", uri))?
+    .map_err(|e| anyhow::anyhow!("connecting to {}: {}", uri, e))?;
+    let mut client: HttpForwarderService<BytesBody> = match https {
+        true => HttpForwarderService::https(unbracketed_host(host), stream).await?,
+        false => HttpForwarderService::http(stream).await?,
+    };
+
+    let body = match method {
+        Method::GET => Empty::<Bytes>::new().boxed(),
+        _ => Full::new(Bytes::from(serde_json::to_vec(&serde_json::json!({
+            "pod": pod.metadata.name,
+            "namespace": pod.metadata.namespace,
+        }))?))
+        .boxed(),
+    };
+    let req = hyper::Request::builder()
+        .uri(uri.path_and_query().map(|p| p.as_str()).unwrap_or("/"))
+        .method(method)
+        .header("Host", uri.authority().map(|a| a.as_str()).unwrap_or(host))
+        .header("Content-Type", "application/json")
+        .body(body)?;
+
+    let response = tokio::time::timeout(URL_REQUEST_TIMEOUT, client.send_request(req))
+        .await
+        .map_err(|_| {
+            anyhow::anyhow!(
+                "{} did not respond within {}",
+                uri,
+                humantime::format_duration(URL_REQUEST_TIMEOUT)
+            )
+        })??;
+
+    Ok(response.status())
+}
+
+/// Runs for each pod during an upgrade or restart, e.g. to drain a load balancer
+/// before the pod is deleted. The upgrade fails if the hook fails.
+#[derive(Clone)]
+pub struct PodHook(Arc<HookFn>);
+
+impl PodHook {
+    pub fn new<F, Fut>(hook: F) -> Self
+    where
+        F: Fn(Pod) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        Self(Arc::new(move |pod| Box::pin(hook(pod))))
+    }
+
+    /// Run the shell command locally, with the name and namespace of the pod
+    /// in `VAULT_MGMT_POD` and `VAULT_MGMT_NAMESPACE`
+    pub fn local(cmd: &str) -> Self {
+        let cmd = cmd.to_string();
+
+        Self::new(move |pod| {
+            let cmd = cmd.clone();
+            async move {
+                let output = pod_command(&cmd, pod).output().await?;
+
+                debug!("{}", String::from_utf8_lossy(&output.stdout));
+
+                if !output.status.success() {
+                    anyhow::bail!(
+                        "{} exited with {}: {}",
+                        cmd,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                }
+
+                Ok(())
+            }
+        })
+    }
+
+    /// POST the name and namespace of the pod to the url, e.g. to update the health-check
+    /// targets of an external load balancer. Fails unless the response is a success.
+    pub fn url(uri: Uri) -> Self {
+        Self::new(move |pod| {
+            let uri = uri.clone();
+            async move {
+                let status = send_to_url(&uri, Method::POST, &pod).await?;
+                if !status.is_success() {
+                    anyhow::bail!("{} responded with {}", uri, status);
+                }
+
+                Ok(())
+            }
+        })
+    }
+
+    /// Run the shell command in the vault container of the pod,
+    /// the container named `container` if it is set
+    pub fn in_pod(api: Api<Pod>, container: Option<String>, cmd: &str) -> Self {
+        let cmd = cmd.to_string();
+
+        Self::new(move |pod| {
+            let (api, container) = (api.clone(), container.clone());
+            let cmd = cmd.clone();
+            async move {
+                let (stdout, _) =
+                    exec_pod_checked(&api, &pod, container.as_deref(), cmd, HashMap::new()).await?;
+                debug!("{}", stdout);
+
+                Ok(())
+            }
+        })
+    }
+
+    pub(crate) async fn run(&self, pod: Pod) -> anyhow::Result<()> {
+        (self.0)(pod).await
+    }
+}
+
+impl std::fmt::Debug for PodHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PodHook")
+    }
+}
+
+impl PartialEq for PodHook {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for PodHook {}
+
+/// Has to pass for each upgraded or restarted pod before the next pod is touched,
+/// e.g. checking the error rate in a monitoring system. It is checked until it passes.
+#[derive(Clone)]
+pub struct HealthGate {
+    name: String,
+    check: Arc<GateFn>,
+}
+
+impl HealthGate {
+    /// The check returns `Ok(false)` while the gate does not pass yet, an error fails the upgrade
+    pub fn new<F, Fut>(name: &str, check: F) -> Self
+    where
+        F: Fn(Pod, PodSealStatus) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<bool>> + Send + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            check: Arc::new(move |pod, status| Box::pin(check(pod, status))),
+        }
+    }
+
+    /// Passes once the condition matches the pod
+    pub fn pod(name: &str, condition: impl Condition<Pod> + Send + Sync + 'static) -> Self {
+        Self::new(name, move |pod, _| {
+            std::future::ready(Ok(condition.matches_object(Some(&pod))))
+        })
+    }
+
+    /// Passes once the condition matches the seal status of the pod
+    pub fn seal_status(
+        name: &str,
+        condition: impl Condition<PodSealStatus> + Send + Sync + 'static,
+    ) -> Self {
+        Self::new(name, move |_, status| {
+            std::future::ready(Ok(condition.matches_object(Some(&status))))
+        })
+    }
+
+    /// Passes once the local shell command exits zero, it gets the pod like `PodHook::local`
+    pub fn local(cmd: &str) -> Self {
+        let command = cmd.to_string();
+
+        Self::new(cmd, move |pod, _| {
+            let cmd = command.clone();
+            async move {
+                let output = pod_command(&cmd, pod).output().await?;
+                debug!("{}", String::from_utf8_lossy(&output.stdout));
+
+                Ok(output.status.success())
+            }
+        })
+    }
+
+    /// Passes once a GET of the url succeeds, e.g. the health of an external load balancer.
+    /// Connection errors count as not passing.
+    pub fn url(uri: Uri) -> Self {
+        Self::new(&uri.to_string(), move |pod, _| {
+            let uri = uri.clone();
+            async move {
+                match send_to_url(&uri, Method::GET, &pod).await {
+                    Ok(status) => Ok(status.is_success()),
+                    Err(e) => {
+                        debug!("{}", e);
+                        Ok(false)
+                    }
+                }
+            }
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) async fn check(&self, pod: Pod, status: PodSealStatus) -> anyhow::Result<bool> {
+        (self.check)(pod, status).await
+    }
+}
+
+impl std::fmt::Debug for HealthGate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HealthGate({})", self.name)
+    }
+}
+
+impl PartialEq for HealthGate {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.check, &other.check)
+    }
+}
+
+impl Eq for HealthGate {}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::Pod;
+    use kube::core::ObjectMeta;
+
+    use http::{Method, StatusCode};
+    use wiremock::{
+        matchers::{body_json, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{HealthGate, PodHook, PodSealStatus};
+
+    fn pod() -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some("vault-1".to_string()),
+                namespace: Some("vault".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn local_hook_gets_pod_and_fails_on_non_zero_exit() {
+        PodHook::local(r#"test "$VAULT_MGMT_NAMESPACE/$VAULT_MGMT_POD" = vault/vault-1"#)
+            .run(pod())
+            .await
+            .unwrap();
+
+        let err = PodHook::local("echo draining failed >&2; exit 3")
+            .run(pod())
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("draining failed"));
+    }
+
+    #[tokio::test]
+    async fn url_hook_posts_pod_and_url_gate_passes_on_success() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/targets"))
+            .and(body_json(
+                serde_json::json!({ "pod": "vault-1", "namespace": "vault" }),
+            ))
+            .respond_with(ResponseTemplate::new(StatusCode::NO_CONTENT))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method(Method::GET))
+            .and(path("/healthy"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .mount(&mock_server)
+            .await;
+
+        let url = |p: &str| format!("{}{}", mock_server.uri(), p).parse().unwrap();
+
+        PodHook::url(url("/targets")).run(pod()).await.unwrap();
+        assert!(PodHook::url(url("/missing")).run(pod()).await.is_err());
+
+        let status: PodSealStatus = serde_json::from_value(serde_json::json!({
+            "type": "shamir",
+            "initialized": true,
+            "sealed": false,
+            "t": 1,
+            "n": 1,
+            "progress": 0,
+            "nonce": "",
+            "version": "1.13.0",
+            "build_date": "",
+            "migration": false,
+            "recovery_seal": false,
+            "storage_type": "raft",
+        }))
+        .unwrap();
+        assert!(HealthGate::url(url("/healthy"))
+            .check(pod(), status.clone())
+            .await
+            .unwrap());
+        assert!(!HealthGate::url(url("/unhealthy"))
+            .check(pod(), status)
+            .await
+            .unwrap());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{convert::Infallible, path::PathBuf, sync::Arc};
 
 use http::Response;
 use http_body_util::{combinators::BoxBody, BodyExt, Empty};
@@ -7,12 +7,21 @@ use hyper::{
     Request,
 };
 use hyper_util::rt::TokioIo;
-use rustls::crypto::ring;
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    ClientConfig, DigitallySignedStruct, SignatureScheme,
+};
 use secrecy::{ExposeSecret, Secret};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio_rustls::rustls::{pki_types, RootCertStore};
+use tokio_rustls::rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    RootCertStore,
+};
 use tracing::*;
 
+use crate::ReadYourWrites;
+
 pub type BytesBody = BoxBody<Bytes, Infallible>;
 
 /// Send HTTP requests
@@ -34,6 +43,10 @@ where
     B: Body,
 {
     sender: hyper::client::conn::http1::SendRequest<B>,
+    /// accessor of the token of the requests, see `set_token_accessor`
+    token_accessor: Option<String>,
+    /// replication state passed on to the following requests, see `set_read_your_writes`
+    read_your_writes: Option<ReadYourWrites>,
 }
 
 impl<B> HttpForwarderService<B>
@@ -55,19 +68,44 @@ where
             }
         });
 
-        Ok(Self { sender })
+        Ok(Self {
+            sender,
+            token_accessor: None,
+            read_your_writes: None,
+        })
+    }
+
+    /// Log the mutating requests with the accessor of their token, see `token_accessor`,
+    /// to correlate them with the audit devices of vault
+    pub fn set_token_accessor(&mut self, accessor: Option<String>) {
+        self.token_accessor = accessor;
+    }
+
+    /// Send the replication state of earlier writes with the requests, see `ReadYourWrites`
+    pub fn set_read_your_writes(&mut self, read_your_writes: Option<ReadYourWrites>) {
+        self.read_your_writes = read_your_writes;
     }
 
     /// Wrap the connection stream in TLS and forward HTTP requests over it
     /// The domain is used to verify the TLS certificate
     /// The native root certificates are used to verify the TLS certificate
-    ///
-    /// TODO: allow customizing the TLS configuration
     pub async fn https<T>(domain: &str, stream: T) -> anyhow::Result<HttpForwarderService<B>>
     where
         T: AsyncRead + AsyncWrite + Unpin + Sync + Send + 'static,
     {
-        let tls_stream = setup_tls(domain, stream).await?;
+        HttpForwarderService::https_with(domain, stream, &TlsOptions::default()).await
+    }
+
+    /// Like `https`, but with the TLS settings of the options
+    pub async fn https_with<T>(
+        domain: &str,
+        stream: T,
+        options: &TlsOptions,
+    ) -> anyhow::Result<HttpForwarderService<B>>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Sync + Send + 'static,
+    {
+        let tls_stream = setup_tls_with(domain, stream, options).await?;
 
         HttpForwarderService::http(tls_stream).await
     }
@@ -78,10 +116,20 @@ impl<B> HttpRequest<B> for HttpForwarderService<B>
 where
     B: Body<Data = Bytes, Error = Infallible> + Send + 'static,
 {
-    async fn send_request(&mut self, req: Request<B>) -> hyper::Result<Response<Bytes>> {
+    async fn send_request(&mut self, mut req: Request<B>) -> hyper::Result<Response<Bytes>> {
+        if let Some(accessor) = &self.token_accessor {
+            log_token_accessor(&req, accessor);
+        }
+        if let Some(read_your_writes) = &self.read_your_writes {
+            read_your_writes.prepare(&mut req);
+        }
         let (parts, body) = self.sender.send_request(req).await?.into_parts();
         let body = body.boxed().collect().await?.to_bytes();
-        Ok(Response::from_parts(parts, body))
+        let response = Response::from_parts(parts, body);
+        if let Some(read_your_writes) = &self.read_your_writes {
+            read_your_writes.observe(&response);
+        }
+        Ok(response)
     }
 
     async fn ready(&mut self) -> anyhow::Result<()> {
@@ -92,30 +140,169 @@ where
     }
 }
 
-pub(crate) async fn setup_tls<T>(
+/// Log the accessor of the token of a mutating request made with a token,
+/// to correlate the request with the audit devices of vault
+fn log_token_accessor<B>(req: &Request<B>, accessor: &str) {
+    if matches!(*req.method(), hyper::Method::GET | hyper::Method::HEAD)
+        || !req.headers().contains_key("X-Vault-Token")
+    {
+        return;
+    }
+
+    info!(
+        "{} {} with token accessor {}",
+        req.method(),
+        req.uri().path(),
+        accessor
+    );
+}
+
+/// Host without the brackets of an IPv6 literal, e.g. `fd00::1` for `[fd00::1]`,
+/// as needed to resolve it, bind to it or use it as TLS server name
+pub fn unbracketed_host(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+/// TLS settings of a connection to a vault outside of the cluster, e.g. the one storing the keys
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TlsOptions {
+    /// verify the certificate with the certificates of this PEM file instead of the native roots
+    pub ca_file: Option<PathBuf>,
+    /// do not verify the certificate at all
+    pub insecure: bool,
+    /// authenticate with this PEM certificate chain and PEM private key
+    pub client_cert: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsOptions {
+    pub fn ca_file(mut self, ca_file: PathBuf) -> Self {
+        self.ca_file = Some(ca_file);
+        self
+    }
+
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    pub fn client_cert(mut self, cert: PathBuf, key: PathBuf) -> Self {
+        self.client_cert = Some((cert, key));
+        self
+    }
+
+    fn root_cert_store(&self) -> anyhow::Result<RootCertStore> {
+        let mut root_cert_store = RootCertStore::empty();
+
+        let Some(ca_file) = &self.ca_file else {
+            for cert in rustls_native_certs::load_native_certs()
+                .map_err(|e| anyhow::anyhow!("could not load platform certs: {}", e))?
+            {
+                root_cert_store.add(cert)?;
+            }
+            return Ok(root_cert_store);
+        };
+
+        let read_error = |e| anyhow::anyhow!("reading ca file {}: {}", ca_file.display(), e);
+        for cert in CertificateDer::pem_file_iter(ca_file).map_err(read_error)? {
+            root_cert_store.add(cert.map_err(read_error)?)?;
+        }
+        if root_cert_store.is_empty() {
+            anyhow::bail!("ca file {} contains no certificates", ca_file.display());
+        }
+
+        Ok(root_cert_store)
+    }
+
+    fn client_config(&self) -> anyhow::Result<ClientConfig> {
+        let provider = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match self.insecure {
+            true => builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerification(provider))),
+            false => builder.with_root_certificates(self.root_cert_store()?),
+        };
+
+        let Some((cert, key)) = &self.client_cert else {
+            return Ok(builder.with_no_client_auth());
+        };
+        let certs = CertificateDer::pem_file_iter(cert)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow::anyhow!("reading client certificate {}: {}", cert.display(), e))?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| anyhow::anyhow!("reading client key {}: {}", key.display(), e))?;
+
+        Ok(builder.with_client_auth_cert(certs, key)?)
+    }
+}
+
+/// Accepts any certificate of the server, see `TlsOptions::insecure`
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+pub(crate) async fn setup_tls_with<T>(
     domain: &str,
     stream: T,
+    options: &TlsOptions,
 ) -> anyhow::Result<tokio_rustls::client::TlsStream<T>>
 where
     T: AsyncRead + AsyncWrite + Unpin + Sync + Send + 'static,
 {
-    let mut root_cert_store = RootCertStore::empty();
-
-    for cert in rustls_native_certs::load_native_certs()
-        .map_err(|e| anyhow::anyhow!("could not load platform certs: {}", e))?
-    {
-        root_cert_store.add(cert).unwrap();
-    }
-
-    let tls = tokio_rustls::rustls::ClientConfig::builder_with_provider(Arc::new(
-        ring::default_provider(),
-    ))
-    .with_safe_default_protocol_versions()?
-    .with_root_certificates(root_cert_store)
-    .with_no_client_auth();
+    let tls = options.client_config()?;
 
     let tls_stream = tokio_rustls::TlsConnector::from(Arc::new(tls))
-        .connect(pki_types::ServerName::try_from(domain)?.to_owned(), stream)
+        .connect(
+            ServerName::try_from(unbracketed_host(domain))?.to_owned(),
+            stream,
+        )
         .await?;
 
     Ok(tls_stream)
@@ -141,6 +328,14 @@ pub(crate) fn seal_status_request(body: BytesBody) -> http::Result<Request<Bytes
         .body(body)
 }
 
+const LEADER_URL: &str = "/v1/sys/leader";
+pub(crate) fn leader_request(body: BytesBody) -> http::Result<Request<BytesBody>> {
+    vault_request()
+        .uri(LEADER_URL)
+        .method(hyper::Method::GET)
+        .body(body)
+}
+
 const UNSEAL_URL: &str = "/v1/sys/unseal";
 pub(crate) fn unseal_request(body: BytesBody) -> http::Result<Request<BytesBody>> {
     vault_request()
@@ -159,6 +354,27 @@ pub(crate) fn get_unseal_keys_request(
         .body(Empty::<Bytes>::new().boxed())
 }
 
+pub(crate) fn put_keys_request(
+    path: &str,
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(path)
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
+pub(crate) fn kubernetes_login_request(
+    mount: &str,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request()
+        .uri(format!("/v1/auth/{}/login", mount.trim_matches('/')))
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
 const INIT_URL: &str = "/v1/sys/init";
 pub(crate) fn init_request(body: BytesBody) -> http::Result<Request<BytesBody>> {
     vault_request()
@@ -186,6 +402,130 @@ pub(crate) fn raft_configuration_request(
         .body(body)
 }
 
+const RAFT_REMOVE_PEER_URL: &str = "/v1/sys/storage/raft/remove-peer";
+pub(crate) fn raft_remove_peer_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(RAFT_REMOVE_PEER_URL)
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
+const RAFT_SNAPSHOT_URL: &str = "/v1/sys/storage/raft/snapshot";
+pub(crate) fn raft_snapshot_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(RAFT_SNAPSHOT_URL)
+        .method(hyper::Method::GET)
+        .body(body)
+}
+
+const RAFT_SNAPSHOT_FORCE_URL: &str = "/v1/sys/storage/raft/snapshot-force";
+pub(crate) fn raft_snapshot_restore_request(
+    token: Secret<String>,
+    force: bool,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(match force {
+            true => RAFT_SNAPSHOT_FORCE_URL,
+            false => RAFT_SNAPSHOT_URL,
+        })
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
+const AUDIT_URL: &str = "/v1/sys/audit";
+pub(crate) fn list_audit_devices_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(AUDIT_URL)
+        .method(hyper::Method::GET)
+        .body(body)
+}
+
+pub(crate) fn enable_audit_device_request(
+    token: Secret<String>,
+    path: &str,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(format!("{}/{}", AUDIT_URL, path.trim_matches('/')))
+        .method(hyper::Method::PUT)
+        .body(body)
+}
+
+const AUTOPILOT_STATE_URL: &str = "/v1/sys/storage/raft/autopilot/state";
+pub(crate) fn autopilot_state_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(AUTOPILOT_STATE_URL)
+        .method(hyper::Method::GET)
+        .body(body)
+}
+
+const AUTOPILOT_CONFIGURATION_URL: &str = "/v1/sys/storage/raft/autopilot/configuration";
+pub(crate) fn autopilot_configuration_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(AUTOPILOT_CONFIGURATION_URL)
+        .method(hyper::Method::GET)
+        .body(body)
+}
+
+pub(crate) fn set_autopilot_configuration_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(AUTOPILOT_CONFIGURATION_URL)
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
+const TOKEN_LOOKUP_SELF_URL: &str = "/v1/auth/token/lookup-self";
+pub(crate) fn token_lookup_self_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(TOKEN_LOOKUP_SELF_URL)
+        .method(hyper::Method::GET)
+        .body(body)
+}
+
+const TOKEN_RENEW_SELF_URL: &str = "/v1/auth/token/renew-self";
+pub(crate) fn token_renew_self_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(TOKEN_RENEW_SELF_URL)
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
+const TOKEN_REVOKE_SELF_URL: &str = "/v1/auth/token/revoke-self";
+pub(crate) fn token_revoke_self_request(
+    token: Secret<String>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    vault_request_with_token(token)
+        .uri(TOKEN_REVOKE_SELF_URL)
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
 const STEP_DOWN_URL: &str = "/v1/sys/step-down";
 pub(crate) fn step_down_request(
     token: Secret<String>,
@@ -197,6 +537,32 @@ pub(crate) fn step_down_request(
         .body(body)
 }
 
+const METRICS_URL: &str = "/v1/sys/metrics?format=prometheus";
+pub(crate) fn metrics_request(
+    token: Option<Secret<String>>,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    // metrics can be configured to be readable without a token
+    match token {
+        Some(token) => vault_request_with_token(token),
+        None => vault_request(),
+    }
+    .uri(METRICS_URL)
+    .method(hyper::Method::GET)
+    .body(body)
+}
+
+pub(crate) fn quit_sidecar_request(
+    path: &str,
+    body: BytesBody,
+) -> http::Result<Request<BytesBody>> {
+    hyper::Request::builder()
+        .header("Host", "127.0.0.1")
+        .uri(path)
+        .method(hyper::Method::POST)
+        .body(body)
+}
+
 #[cfg(test)]
 mod tests {
     use http::StatusCode;
@@ -204,7 +570,18 @@ mod tests {
     use hyper::body::Bytes;
     use wiremock::{matchers::any, Mock, MockServer, ResponseTemplate};
 
-    use crate::http::{HttpForwarderService, HttpRequest};
+    use crate::http::{
+        setup_tls_with, unbracketed_host, HttpForwarderService, HttpRequest, TlsOptions,
+    };
+
+    #[test]
+    fn brackets_of_ipv6_literals_are_removed() {
+        assert_eq!(unbracketed_host("[fd00::1]"), "fd00::1");
+        assert_eq!(unbracketed_host("fd00::1"), "fd00::1");
+        assert_eq!(unbracketed_host("10.42.2.27"), "10.42.2.27");
+        assert_eq!(unbracketed_host("vault.example.com"), "vault.example.com");
+        assert_eq!(unbracketed_host("[fd00::1"), "[fd00::1");
+    }
 
     #[tokio::test]
     async fn http_forward_works() {
@@ -258,4 +635,53 @@ mod tests {
 
         assert!(parts.status.is_success() || parts.status.is_redirection());
     }
+
+    /// Address of a TLS server with the certificate of `tests/resources/tls`, for `localhost`
+    async fn tls_server() -> std::net::SocketAddr {
+        use tokio_rustls::rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+
+        let certs = CertificateDer::pem_file_iter("tests/resources/tls/server.pem")
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file("tests/resources/tls/server.key").unwrap();
+        let config = rustls::ServerConfig::builder_with_provider(std::sync::Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(std::sync::Arc::new(config));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move { acceptor.accept(stream).await });
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn tls_options_verify_with_a_custom_ca_or_not_at_all() {
+        let addr = tls_server().await;
+        let connect = |options: TlsOptions| async move {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            setup_tls_with("localhost", stream, &options).await
+        };
+
+        let ca = TlsOptions::default().ca_file("tests/resources/tls/ca.pem".into());
+        assert!(connect(ca).await.is_ok());
+        assert!(connect(TlsOptions::default().insecure(true)).await.is_ok());
+        // the test ca is not one of the native roots
+        assert!(connect(TlsOptions::default()).await.is_err());
+
+        let missing = TlsOptions::default().ca_file("tests/resources/tls/missing.pem".into());
+        assert!(connect(missing).await.is_err());
+    }
 }
@@ -3,7 +3,7 @@ use http_body_util::{BodyExt, Full};
 use hyper::body::Bytes;
 use k8s_openapi::api::core::v1::Pod;
 use kube::Api;
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
 use tracing::*;
 
 use crate::{init_request, raft_join_request, BytesBody, HttpRequest, PodApi, VAULT_PORT};
@@ -35,10 +35,40 @@ impl Default for InitRequest {
     }
 }
 
+impl InitRequest {
+    /// Split the unseal key into shares, for the default shamir seal
+    pub fn shamir(shares: u8, threshold: u8) -> Self {
+        Self {
+            secret_shares: shares,
+            secret_threshold: threshold,
+            ..Default::default()
+        }
+    }
+
+    /// Split the recovery key into shares, for auto-unseal which has no unseal key shares
+    pub fn auto_unseal(recovery_shares: u8, recovery_threshold: u8) -> Self {
+        Self {
+            secret_shares: 0,
+            secret_threshold: 0,
+            recovery_shares,
+            recovery_threshold,
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct InitResult {
+    /// unseal keys, empty with auto-unseal
+    #[serde(default)]
     pub keys: Vec<Secret<String>>,
+    #[serde(default)]
     pub keys_base64: Vec<Secret<String>>,
+    /// recovery keys, only returned with auto-unseal
+    #[serde(default)]
+    pub recovery_keys: Vec<Secret<String>>,
+    #[serde(default)]
+    pub recovery_keys_base64: Vec<Secret<String>>,
     pub root_token: Secret<String>,
 }
 
@@ -73,11 +103,81 @@ where
     }
 }
 
+/// Parameters to join a vault process to a raft cluster
+///
+/// Either `leader_api_addr` or `auto_join` has to be set.
+#[derive(Clone, Debug, Default)]
+pub struct RaftJoinRequest {
+    /// address of a leader node (e.g. `https://vault-0.vault-internal:8200`)
+    pub leader_api_addr: Option<String>,
+    /// PEM encoded CA certificate used to verify the leader's TLS certificate
+    pub leader_ca_cert: Option<String>,
+    /// PEM encoded client certificate used for TLS client authentication against the leader
+    pub leader_client_cert: Option<String>,
+    /// PEM encoded private key of the client certificate
+    pub leader_client_key: Option<Secret<String>>,
+    /// keep retrying to join in the background until successful
+    pub retry: bool,
+    /// go-discover configuration to find the leader (e.g. `provider=k8s label_selector="app.kubernetes.io/name=vault"`)
+    pub auto_join: Option<String>,
+    /// URI scheme used for addresses found by `auto_join`
+    pub auto_join_scheme: Option<String>,
+    /// port used for addresses found by `auto_join`
+    pub auto_join_port: Option<u16>,
+}
+
+impl RaftJoinRequest {
+    pub fn new(leader_api_addr: &str) -> Self {
+        Self {
+            leader_api_addr: Some(leader_api_addr.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut body = serde_json::Map::new();
+
+        let mut insert = |k: &str, v: Option<serde_json::Value>| {
+            if let Some(v) = v {
+                body.insert(k.to_string(), v);
+            }
+        };
+
+        insert(
+            "leader_api_addr",
+            self.leader_api_addr.clone().map(Into::into),
+        );
+        insert(
+            "leader_ca_cert",
+            self.leader_ca_cert.clone().map(Into::into),
+        );
+        insert(
+            "leader_client_cert",
+            self.leader_client_cert.clone().map(Into::into),
+        );
+        insert(
+            "leader_client_key",
+            self.leader_client_key
+                .as_ref()
+                .map(|k| k.expose_secret().clone().into()),
+        );
+        insert("retry", self.retry.then_some(true.into()));
+        insert("auto_join", self.auto_join.clone().map(Into::into));
+        insert(
+            "auto_join_scheme",
+            self.auto_join_scheme.clone().map(Into::into),
+        );
+        insert("auto_join_port", self.auto_join_port.map(Into::into));
+
+        serde_json::Value::Object(body)
+    }
+}
+
 /// Join a vault process to a raft cluster
 #[async_trait::async_trait]
 pub trait RaftJoin {
     /// Join a vault process to a raft cluster
-    async fn raft_join(&mut self, join_to: &str) -> anyhow::Result<()>;
+    async fn raft_join(&mut self, req: RaftJoinRequest) -> anyhow::Result<()>;
 }
 
 #[async_trait::async_trait]
@@ -85,10 +185,14 @@ impl<T> RaftJoin for T
 where
     T: HttpRequest<BytesBody> + Send + Sync + 'static,
 {
-    async fn raft_join(&mut self, join_to: &str) -> anyhow::Result<()> {
-        let body = serde_json::json!({
-            "leader_api_addr": join_to,
-        });
+    async fn raft_join(&mut self, req: RaftJoinRequest) -> anyhow::Result<()> {
+        if req.leader_api_addr.is_none() && req.auto_join.is_none() {
+            return Err(anyhow::anyhow!(
+                "raft-joining: neither leader address nor auto join configured"
+            ));
+        }
+
+        let body = req.to_json();
 
         let http_req = raft_join_request(Full::new(Bytes::from(body.to_string())).boxed())?;
 
@@ -159,7 +263,7 @@ pub async fn raft_join(
     domain: String,
     api: &Api<Pod>,
     pod_name: &str,
-    join_to: &str,
+    req: RaftJoinRequest,
 ) -> anyhow::Result<()> {
     let pod = api.get(pod_name).await?;
 
@@ -169,7 +273,10 @@ pub async fn raft_join(
             .name
             .clone()
             .ok_or(anyhow::anyhow!("pod does not have a name"))?,
-        join_to,
+        req.leader_api_addr
+            .as_deref()
+            .or(req.auto_join.as_deref())
+            .unwrap_or_default(),
     );
 
     let pods = PodApi::new(api.clone(), true, domain);
@@ -185,38 +292,22 @@ pub async fn raft_join(
         .await?;
     pf.ready().await?;
 
-    let body = serde_json::json!({
-        "leader_api_addr": join_to,
-    });
-
-    let http_req = Request::builder()
-        .uri("/v1/sys/storage/raft/join")
-        .header("Host", "127.0.0.1")
-        .header("X-Vault-Request", "true")
-        .method(hyper::Method::POST)
-        .body(Full::new(Bytes::from(body.to_string())).boxed())?;
-
-    let (parts, body) = pf.send_request(http_req).await?.into_parts();
-
-    let body = String::from_utf8(body.to_vec())?;
-
-    if parts.status != hyper::StatusCode::OK {
-        return Err(anyhow::anyhow!("{}", body));
-    }
-
-    Ok(())
+    pf.raft_join(req).await
 }
 
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use http::{Method, StatusCode};
+    use secrecy::Secret;
     use wiremock::{
         matchers::{body_json, header, method, path},
         Mock, MockServer, ResponseTemplate,
     };
 
     use crate::{
-        HttpForwarderService, {Init, InitRequest, RaftJoin},
+        HttpForwarderService, {Init, InitRequest, RaftJoin, RaftJoinRequest},
     };
 
     #[tokio::test]
@@ -273,8 +364,66 @@ mod tests {
         .await
         .unwrap();
 
-        let outcome = client.raft_join("other-instance").await;
+        let outcome = client
+            .raft_join(RaftJoinRequest::new("other-instance"))
+            .await;
 
         assert!(outcome.is_ok());
     }
+
+    #[tokio::test]
+    async fn raft_join_sends_tls_parameters() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/sys/storage/raft/join"))
+            .and(body_json(serde_json::json!({
+                "leader_api_addr": "https://other-instance:8200",
+                "leader_ca_cert": "ca",
+                "leader_client_cert": "cert",
+                "leader_client_key": "key",
+                "retry": true,
+            })))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let outcome = client
+            .raft_join(RaftJoinRequest {
+                leader_ca_cert: Some("ca".to_string()),
+                leader_client_cert: Some("cert".to_string()),
+                leader_client_key: Some(Secret::from_str("key").unwrap()),
+                retry: true,
+                ..RaftJoinRequest::new("https://other-instance:8200")
+            })
+            .await;
+
+        assert!(outcome.is_ok());
+    }
+
+    #[tokio::test]
+    async fn raft_join_requires_leader_or_auto_join() {
+        let mock_server = MockServer::start().await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let outcome = client.raft_join(RaftJoinRequest::default()).await;
+
+        assert!(outcome.is_err());
+    }
 }
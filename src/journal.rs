@@ -0,0 +1,217 @@
+use std::{
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// State of an action in the `Journal`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JournalState {
+    /// written before the action is executed
+    Intended,
+    Completed,
+    Failed,
+}
+
+impl std::fmt::Display for JournalState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JournalState::Intended => "intended".fmt(f),
+            JournalState::Completed => "completed".fmt(f),
+            JournalState::Failed => "failed".fmt(f),
+        }
+    }
+}
+
+/// Line of the `Journal`
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct JournalEntry {
+    pub time: String,
+    pub state: JournalState,
+    /// e.g. `upgrade` or a `PodStep` like `delete`
+    pub action: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod: Option<String>,
+    /// e.g. the target version or the error of a failed action
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl std::fmt::Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.time, self.state, self.action)?;
+        if let Some(pod) = &self.pod {
+            write!(f, " {}", pod)?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Local append-only journal of the actions of an upgrade or restart, one json object per line
+///
+/// Each action is written before it is executed and again once it completed or failed, so if
+/// vault-mgmt or its connection to the cluster dies, the journal shows what was done and which
+/// action was in progress. Clones append to the same file.
+#[derive(Clone)]
+pub struct Journal(Arc<Mutex<std::fs::File>>);
+
+impl Journal {
+    /// Open the journal for appending, creating it if it does not exist
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow::anyhow!("opening journal {}: {}", path.display(), e))?;
+
+        Ok(Self(Arc::new(Mutex::new(file))))
+    }
+
+    /// Write that the action is about to be executed, the action must not run if this fails
+    pub fn intend(
+        &self,
+        action: &str,
+        pod: Option<&str>,
+        detail: Option<String>,
+    ) -> anyhow::Result<()> {
+        self.append(JournalState::Intended, action, pod, detail)
+    }
+
+    /// Write the result of the action
+    pub fn finish<T>(
+        &self,
+        action: &str,
+        pod: Option<&str>,
+        result: &anyhow::Result<T>,
+    ) -> anyhow::Result<()> {
+        match result {
+            Ok(_) => self.append(JournalState::Completed, action, pod, None),
+            Err(e) => self.append(JournalState::Failed, action, pod, Some(format!("{:#}", e))),
+        }
+    }
+
+    fn append(
+        &self,
+        state: JournalState,
+        action: &str,
+        pod: Option<&str>,
+        detail: Option<String>,
+    ) -> anyhow::Result<()> {
+        let entry = JournalEntry {
+            time: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            state,
+            action: action.to_string(),
+            pod: pod.map(str::to_string),
+            detail,
+        };
+
+        let mut file = self.0.lock().expect("journal lock is not poisoned");
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        // the entry has to survive the process dying right after it
+        file.sync_data()?;
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Journal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Journal")
+    }
+}
+
+impl PartialEq for Journal {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Journal {}
+
+/// Read the entries of a journal
+pub fn read_journal(path: &Path) -> anyhow::Result<Vec<JournalEntry>> {
+    std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("reading journal {}: {}", path.display(), e))?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .map_err(|e| anyhow::anyhow!("line {} of journal {}: {}", i + 1, path.display(), e))
+        })
+        .collect()
+}
+
+/// Actions that were intended but neither completed nor failed,
+/// i.e. were in progress when the journal stopped
+pub fn unfinished_actions(entries: &[JournalEntry]) -> Vec<&JournalEntry> {
+    let mut unfinished: Vec<&JournalEntry> = vec![];
+
+    for entry in entries {
+        match entry.state {
+            JournalState::Intended => unfinished.push(entry),
+            JournalState::Completed | JournalState::Failed => {
+                if let Some(i) = unfinished
+                    .iter()
+                    .rposition(|e| e.action == entry.action && e.pod == entry.pod)
+                {
+                    unfinished.remove(i);
+                }
+            }
+        }
+    }
+
+    unfinished
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{read_journal, unfinished_actions, Journal, JournalState};
+
+    #[test]
+    fn journal_shows_unfinished_actions() {
+        let path =
+            std::env::temp_dir().join(format!("vault-mgmt-journal-{}", rand::random::<u32>()));
+
+        let journal = Journal::open(&path).unwrap();
+        journal
+            .intend("upgrade", None, Some("target 1.14.0".to_string()))
+            .unwrap();
+        journal.intend("delete", Some("vault-1"), None).unwrap();
+        journal
+            .finish("delete", Some("vault-1"), &Ok::<_, anyhow::Error>(()))
+            .unwrap();
+        journal.intend("unseal", Some("vault-1"), None).unwrap();
+
+        // a second run appends to the same journal
+        let journal = Journal::open(&path).unwrap();
+        journal
+            .finish(
+                "unseal",
+                Some("vault-0"),
+                &Err::<(), _>(anyhow::anyhow!("sealed")),
+            )
+            .unwrap();
+
+        let entries = read_journal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[4].state, JournalState::Failed);
+        assert_eq!(entries[4].detail.as_deref(), Some("sealed"));
+
+        let unfinished = unfinished_actions(&entries)
+            .into_iter()
+            .map(|e| (e.action.as_str(), e.pod.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            unfinished,
+            vec![("upgrade", None), ("unseal", Some("vault-1"))]
+        );
+    }
+}
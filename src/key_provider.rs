@@ -0,0 +1,853 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use k8s_openapi::api::core::v1::Secret as K8sSecret;
+use kube::{Api, Client};
+use secrecy::{zeroize::Zeroize, ExposeSecret, Secret};
+
+use crate::{
+    get_unseal_keys, percent_encode, GetUnsealKeys, GetUnsealKeysFromVault, KeyKind, Keys,
+    KeysSecretUri, KubernetesAuth, TlsOptions,
+};
+
+/// Uri of a key source, the scheme selects the `KeyProvider` reading it, e.g.
+/// `vault+https://vault.example.com/v1/secret/data/vault/unseal-keys`, `k8s://vault/unseal-keys`,
+/// `file:///etc/vault/keys`, `gpg:///etc/vault/keys.gpg`, `cmd://pass show vault`,
+/// `env://VAULT_UNSEAL_KEYS` or `fd://0`
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct KeysFrom {
+    scheme: String,
+    location: String,
+}
+
+impl KeysFrom {
+    pub fn new(scheme: &str, location: &str) -> Self {
+        Self {
+            scheme: scheme.to_lowercase(),
+            location: location.to_string(),
+        }
+    }
+
+    /// Keys written to stdout by the shell command, one per line
+    pub fn cmd(cmd: &str) -> Self {
+        Self::new("cmd", cmd)
+    }
+
+    pub fn scheme(&self) -> &str {
+        &self.scheme
+    }
+
+    /// Everything after `://`, its meaning depends on the provider
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// The keys are read from a vault, which needs a token unless kubernetes auth is used
+    pub fn needs_token(&self) -> bool {
+        self.scheme.starts_with("vault+")
+    }
+
+    /// Read the keys from this field of the vault secret, see `KeysSecretUri::field`
+    pub fn vault_field(self, field: &str) -> anyhow::Result<Self> {
+        if !self.needs_token() {
+            anyhow::bail!("{} is not a vault secret, only those have fields", self);
+        }
+
+        let separator = match self.location.contains('?') {
+            true => '&',
+            false => '?',
+        };
+        let location = format!(
+            "{}{}field={}",
+            self.location,
+            separator,
+            percent_encode(field)
+        );
+
+        Ok(Self::new(&self.scheme, &location))
+    }
+}
+
+impl FromStr for KeysFrom {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, location) = s.split_once("://").ok_or(anyhow::anyhow!(
+            "keys uri {} has no scheme, expected e.g. k8s://vault/unseal-keys",
+            s
+        ))?;
+
+        let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.');
+        if scheme.is_empty() || !scheme.chars().all(valid) {
+            anyhow::bail!("keys uri {} has an invalid scheme {}", s, scheme);
+        }
+        if location.is_empty() {
+            anyhow::bail!("keys uri {} has no location", s);
+        }
+
+        Ok(Self::new(scheme, location))
+    }
+}
+
+impl std::fmt::Display for KeysFrom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}", self.scheme, self.location)
+    }
+}
+
+impl From<KeysSecretUri> for KeysFrom {
+    fn from(uri: KeysSecretUri) -> Self {
+        let uri = uri.to_string();
+        let (scheme, location) = uri.split_once("://").expect("scheme is validated");
+
+        Self::new(&format!("vault+{}", scheme), location)
+    }
+}
+
+/// What a provider needs besides the uri to read the keys
+#[derive(Clone, Debug)]
+pub struct KeyRequest {
+    pub kind: KeyKind,
+    /// token for the vault storing the keys, see `KeysFrom::needs_token`
+    pub token: Secret<String>,
+    /// log in to the vault storing the keys instead of using the token
+    pub auth: Option<KubernetesAuth>,
+}
+
+impl KeyRequest {
+    pub fn new(kind: KeyKind, token: Secret<String>) -> Self {
+        Self {
+            kind,
+            token,
+            auth: None,
+        }
+    }
+
+    pub fn auth(mut self, auth: Option<KubernetesAuth>) -> Self {
+        self.auth = auth;
+        self
+    }
+}
+
+/// Source of unseal or recovery keys, registered for a uri scheme in `KeyProviders`
+#[async_trait::async_trait]
+pub trait KeyProvider: Send + Sync {
+    /// Read the keys of the requested kind from the source at the uri
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys>;
+
+    /// Check that the source can be reached without reading the keys, e.g. for `config validate`
+    async fn check(&self, _from: &KeysFrom) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Key providers by the uri scheme they read
+///
+/// The default registry knows `vault+https`, `vault+http`, `k8s`, `file`, `age`, `gpg`, `cmd`, `env`,
+/// and `fd`, with the `aws` feature also `aws-sm` and `aws-ssm`, with the `gcp` feature also
+/// `gcp-sm`, with the `azure` feature also `azure-kv`,
+/// more providers can be registered by library users.
+#[derive(Clone)]
+pub struct KeyProviders {
+    providers: BTreeMap<String, Arc<dyn KeyProvider>>,
+}
+
+impl Default for KeyProviders {
+    fn default() -> Self {
+        let providers = Self::empty()
+            .register("vault+https", VaultKeyProvider::default())
+            .register("vault+http", VaultKeyProvider::default())
+            .register("k8s", KubernetesKeyProvider)
+            .register("file", FileKeyProvider)
+            .register("age", AgeKeyProvider)
+            .register("gpg", GpgKeyProvider)
+            .register("cmd", CommandKeyProvider)
+            .register("env", EnvKeyProvider)
+            .register("fd", FdKeyProvider::default());
+
+        #[cfg(feature = "aws")]
+        let providers = providers
+            .register("aws-sm", crate::AwsSecretsManagerKeyProvider)
+            .register("aws-ssm", crate::AwsSsmKeyProvider);
+
+        #[cfg(feature = "gcp")]
+        let providers = providers.register("gcp-sm", crate::GcpSecretManagerKeyProvider);
+
+        #[cfg(feature = "azure")]
+        let providers = providers.register("azure-kv", crate::AzureKeyVaultKeyProvider);
+
+        providers
+    }
+}
+
+impl KeyProviders {
+    /// Registry without any provider
+    pub fn empty() -> Self {
+        Self {
+            providers: BTreeMap::new(),
+        }
+    }
+
+    /// Read the uris with this scheme with the provider, replacing a provider of the scheme
+    pub fn register(mut self, scheme: &str, provider: impl KeyProvider + 'static) -> Self {
+        self.providers
+            .insert(scheme.to_lowercase(), Arc::new(provider));
+        self
+    }
+
+    pub fn schemes(&self) -> impl Iterator<Item = &str> {
+        self.providers.keys().map(String::as_str)
+    }
+
+    fn provider(&self, from: &KeysFrom) -> anyhow::Result<&Arc<dyn KeyProvider>> {
+        self.providers.get(from.scheme()).ok_or(anyhow::anyhow!(
+            "no key provider for {}, supported schemes: {}",
+            from.scheme(),
+            self.schemes().collect::<Vec<_>>().join(", ")
+        ))
+    }
+
+    /// Read the keys with the provider of the scheme of the uri, failing if there are none
+    pub async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let keys = self
+            .provider(from)?
+            .get_keys(from, request)
+            .await
+            .map_err(|e| e.context(format!("reading {} keys from {}", request.kind, from)))?;
+
+        if keys.keys.is_empty() {
+            anyhow::bail!("no {} keys found in {}", request.kind, from);
+        }
+
+        Ok(keys)
+    }
+
+    /// Check the source with the provider of the scheme of the uri, see `KeyProvider::check`
+    pub async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        self.provider(from)?.check(from).await
+    }
+}
+
+/// Keys of the kind, one per non-empty line
+pub fn keys_from_lines(text: &str, kind: KeyKind) -> Keys {
+    Keys {
+        kind,
+        keys: text
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(|l| Secret::new(l.to_string()))
+            .collect(),
+    }
+}
+
+/// Keys of the kind, exactly one per line
+///
+/// Unlike `keys_from_lines`, empty lines and whitespace around a key are refused instead of
+/// skipped, so a truncated or garbled stream from a wrapper is not mistaken for fewer keys.
+pub fn keys_from_strict_lines(text: &str, kind: KeyKind) -> anyhow::Result<Keys> {
+    let keys = text
+        .strip_suffix('\n')
+        .unwrap_or(text)
+        .split('\n')
+        .enumerate()
+        .map(|(i, line)| match line {
+            "" => anyhow::bail!("line {} is empty, expected one key per line", i + 1),
+            line if line.trim() != line => anyhow::bail!(
+                "line {} has whitespace around the key, expected one key per line",
+                i + 1
+            ),
+            line => Ok(Secret::new(line.to_string())),
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    Ok(Keys { kind, keys })
+}
+
+/// Keys of the kind stored in a cloud secret: either one per non-empty line, or a JSON object
+/// with the keys in the field of the kind (`keys` or `recovery_keys`), as lines or an array
+pub fn keys_from_document(text: &str, kind: KeyKind) -> anyhow::Result<Keys> {
+    let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(text) else {
+        return Ok(keys_from_lines(text, kind));
+    };
+
+    keys_from_field(fields, kind.secret_field(), kind)
+}
+
+/// Keys of the kind in a field of a JSON object, as lines or an array
+pub fn keys_from_field(
+    mut fields: serde_json::Map<String, serde_json::Value>,
+    field: &str,
+    kind: KeyKind,
+) -> anyhow::Result<Keys> {
+    match fields.remove(field) {
+        Some(serde_json::Value::String(lines)) => Ok(keys_from_lines(&lines, kind)),
+        Some(serde_json::Value::Array(keys)) => Ok(Keys {
+            kind,
+            keys: keys
+                .into_iter()
+                .map(|k| match k {
+                    serde_json::Value::String(k) => Ok(Secret::new(k)),
+                    _ => Err(anyhow::anyhow!("{} has to be a list of strings", field)),
+                })
+                .collect::<anyhow::Result<_>>()?,
+        }),
+        Some(_) => anyhow::bail!("{} has to be a string or a list of strings", field),
+        None => anyhow::bail!("secret does not have a {} field", field),
+    }
+}
+
+/// `vault+https://<host>/v1/<kv secret>`, see `KeysSecretUri`
+#[derive(Clone, Debug, Default)]
+pub struct VaultKeyProvider {
+    tls: TlsOptions,
+}
+
+impl VaultKeyProvider {
+    /// TLS settings of the connections to the vault storing the keys
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    pub(crate) fn secret_uri(from: &KeysFrom) -> anyhow::Result<KeysSecretUri> {
+        let scheme = from.scheme().trim_start_matches("vault+");
+
+        Ok(KeysSecretUri::from_str(&format!(
+            "{}://{}",
+            scheme,
+            from.location()
+        ))?)
+    }
+
+    /// Uri of the secret and a client for the vault storing it
+    pub(crate) fn client(
+        &self,
+        from: &KeysFrom,
+    ) -> anyhow::Result<(KeysSecretUri, GetUnsealKeysFromVault)> {
+        let uri = Self::secret_uri(from)?;
+        let client = uri.client().tls(self.tls.clone());
+
+        Ok((uri, client))
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for VaultKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let (uri, mut client) = self.client(from)?;
+        let token = match &request.auth {
+            Some(auth) => client.login(auth).await?,
+            None => request.token.clone(),
+        };
+
+        let field = uri.field().unwrap_or(request.kind.secret_field());
+        client
+            .get_keys_of_field(uri.path(), token, request.kind, field)
+            .await
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        self.client(from)?.1.check_reachable().await
+    }
+}
+
+/// `k8s://<namespace>/<secret>[/<key>]`, the key defaults to `keys` or `recovery_keys`
+pub struct KubernetesKeyProvider;
+
+impl KubernetesKeyProvider {
+    pub(crate) fn parse(from: &KeysFrom) -> anyhow::Result<(&str, &str, Option<&str>)> {
+        let mut parts = from.location().splitn(3, '/');
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(namespace), Some(name), key) if !namespace.is_empty() && !name.is_empty() => {
+                Ok((namespace, name, key.filter(|k| !k.is_empty())))
+            }
+            _ => anyhow::bail!("expected k8s://<namespace>/<secret>[/<key>], got {}", from),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for KubernetesKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let (namespace, name, key) = Self::parse(from)?;
+        let key = key.unwrap_or(request.kind.secret_field());
+
+        let api: Api<K8sSecret> = Api::namespaced(Client::try_default().await?, namespace);
+        let secret = api.get(name).await?;
+        let value = secret
+            .data
+            .as_ref()
+            .and_then(|d| d.get(key))
+            .ok_or(anyhow::anyhow!(
+                "secret {} does not have a {} field",
+                name,
+                key
+            ))?;
+
+        Ok(keys_from_lines(
+            &String::from_utf8(value.0.clone())?,
+            request.kind,
+        ))
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        let (namespace, name, _) = Self::parse(from)?;
+
+        let api: Api<K8sSecret> = Api::namespaced(Client::try_default().await?, namespace);
+        // only the metadata, so the keys are not read
+        api.get_metadata(name).await?;
+
+        Ok(())
+    }
+}
+
+/// `file:///<path>`, one key per line
+pub struct FileKeyProvider;
+
+impl FileKeyProvider {
+    fn path(from: &KeysFrom) -> PathBuf {
+        PathBuf::from(from.location())
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for FileKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let text = tokio::fs::read_to_string(Self::path(from)).await?;
+
+        Ok(keys_from_lines(&text, request.kind))
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        tokio::fs::metadata(Self::path(from)).await?;
+
+        Ok(())
+    }
+}
+
+/// `age://<path>?identity=<identity file>`, a file encrypted with age holding one key per line
+///
+/// The file is decrypted by the `age` program, the plaintext is only kept in memory.
+pub struct AgeKeyProvider;
+
+impl AgeKeyProvider {
+    /// Paths of the encrypted file and of the identity file
+    fn paths(from: &KeysFrom) -> anyhow::Result<(PathBuf, PathBuf)> {
+        match from.location().split_once("?identity=") {
+            Some((path, identity)) if !path.is_empty() && !identity.is_empty() => {
+                Ok((PathBuf::from(path), PathBuf::from(identity)))
+            }
+            _ => anyhow::bail!(
+                "expected age://<path>?identity=<identity file>, got {}",
+                from
+            ),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for AgeKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let (path, identity) = Self::paths(from)?;
+
+        let text = decrypt(
+            tokio::process::Command::new("age")
+                .arg("--decrypt")
+                .arg("--identity")
+                .arg(&identity)
+                .arg(&path),
+            &path,
+        )
+        .await?;
+
+        Ok(keys_from_lines(text.expose_secret(), request.kind))
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        let (path, identity) = Self::paths(from)?;
+
+        which::which("age").map_err(|e| anyhow::anyhow!("program age not found: {}", e))?;
+        tokio::fs::metadata(&path).await?;
+        tokio::fs::metadata(&identity).await?;
+
+        Ok(())
+    }
+}
+
+/// `gpg://<path>`, a file encrypted with gpg holding one key per line
+///
+/// The file is decrypted by `gpg` with the keys of the gpg agent (e.g. on a smartcard),
+/// the plaintext is only kept in memory.
+pub struct GpgKeyProvider;
+
+#[async_trait::async_trait]
+impl KeyProvider for GpgKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let path = PathBuf::from(from.location());
+
+        let text = decrypt(
+            tokio::process::Command::new("gpg")
+                .args(["--batch", "--quiet", "--decrypt"])
+                .arg(&path),
+            &path,
+        )
+        .await?;
+
+        Ok(keys_from_lines(text.expose_secret(), request.kind))
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        which::which("gpg").map_err(|e| anyhow::anyhow!("program gpg not found: {}", e))?;
+        tokio::fs::metadata(from.location()).await?;
+
+        Ok(())
+    }
+}
+
+/// Run the command decrypting the file to its stdout, the plaintext is never written to disk
+async fn decrypt(cmd: &mut tokio::process::Command, path: &Path) -> anyhow::Result<Secret<String>> {
+    let output = cmd
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("decrypting {}: {}", path.display(), e))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "decrypting {}: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(Secret::new(String::from_utf8(output.stdout).map_err(
+        |_| anyhow::anyhow!("decrypted {} is not utf-8", path.display()),
+    )?))
+}
+
+/// `cmd://<shell command>`, writing one key per line to stdout, see `--key-cmd`
+pub struct CommandKeyProvider;
+
+#[async_trait::async_trait]
+impl KeyProvider for CommandKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        Ok(Keys {
+            kind: request.kind,
+            keys: get_unseal_keys(from.location()).await?,
+        })
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        // the command runs in a shell, so only its program can be resolved
+        let program = from
+            .location()
+            .split_whitespace()
+            .next()
+            .unwrap_or_default();
+        which::which(program)
+            .map_err(|e| anyhow::anyhow!("program {} not found: {}", program, e))?;
+
+        Ok(())
+    }
+}
+
+/// `env://<variable>`, one key per line
+pub struct EnvKeyProvider;
+
+#[async_trait::async_trait]
+impl KeyProvider for EnvKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let text = std::env::var(from.location())
+            .map_err(|e| anyhow::anyhow!("reading {}: {}", from.location(), e))?;
+
+        Ok(keys_from_lines(&text, request.kind))
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        std::env::var_os(from.location())
+            .map(|_| ())
+            .ok_or(anyhow::anyhow!("{} is not set", from.location()))
+    }
+}
+
+/// `fd://<n>`, one key per line written to an inherited file descriptor, `fd://0` is stdin
+///
+/// Lets wrappers pipe the keys in without temporary files, command lines or environment
+/// variables. The descriptor is read once until it is closed and the text is kept in memory
+/// for the following reads, e.g. of several clusters. The keys are parsed strictly with
+/// `keys_from_strict_lines`.
+#[derive(Default)]
+pub struct FdKeyProvider {
+    read: tokio::sync::Mutex<BTreeMap<u32, Secret<String>>>,
+}
+
+impl FdKeyProvider {
+    fn fd(from: &KeysFrom) -> anyhow::Result<u32> {
+        from.location()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("expected fd://<file descriptor>, got {}", from))
+    }
+
+    fn path(fd: u32) -> PathBuf {
+        PathBuf::from(format!("/dev/fd/{}", fd))
+    }
+}
+
+#[async_trait::async_trait]
+impl KeyProvider for FdKeyProvider {
+    async fn get_keys(&self, from: &KeysFrom, request: &KeyRequest) -> anyhow::Result<Keys> {
+        let fd = Self::fd(from)?;
+        let mut read = self.read.lock().await;
+
+        let text = match read.entry(fd) {
+            Entry::Occupied(read) => read.into_mut(),
+            Entry::Vacant(unread) => {
+                let path = Self::path(fd);
+                let mut bytes = tokio::fs::read(&path)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("reading {}: {}", path.display(), e))?;
+
+                match String::from_utf8(bytes) {
+                    Ok(text) => unread.insert(Secret::new(text)),
+                    Err(e) => {
+                        bytes = e.into_bytes();
+                        bytes.zeroize();
+                        anyhow::bail!("keys read from {} are not utf-8", from);
+                    }
+                }
+            }
+        };
+
+        keys_from_strict_lines(text.expose_secret(), request.kind)
+    }
+
+    async fn check(&self, from: &KeysFrom) -> anyhow::Result<()> {
+        // only the descriptor, reading would consume the keys
+        tokio::fs::metadata(Self::path(Self::fd(from)?)).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use secrecy::{ExposeSecret, Secret};
+
+    use crate::{
+        keys_from_document, keys_from_strict_lines, AgeKeyProvider, KeyKind, KeyProvider,
+        KeyProviders, KeyRequest, Keys, KeysFrom, KeysSecretUri, KubernetesKeyProvider,
+    };
+
+    fn request(kind: KeyKind) -> KeyRequest {
+        KeyRequest::new(kind, Secret::from_str("token").unwrap())
+    }
+
+    fn exposed(keys: &Keys) -> Vec<&str> {
+        keys.keys
+            .iter()
+            .map(|k| k.expose_secret().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn keys_uris_are_parsed() {
+        let from = KeysFrom::from_str("cmd://pass show vault/keys").unwrap();
+        assert_eq!(from.scheme(), "cmd");
+        assert_eq!(from.location(), "pass show vault/keys");
+        assert_eq!(from.to_string(), "cmd://pass show vault/keys");
+
+        assert!(KeysFrom::from_str("/etc/vault/keys").is_err());
+        assert!(KeysFrom::from_str("file://").is_err());
+        assert!(KeysFrom::from_str("a b://c").is_err());
+
+        let uri = KeysSecretUri::from_str("https://vault.example.com/v1/secret/data/keys").unwrap();
+        let from = KeysFrom::from(uri);
+        assert_eq!(
+            from.to_string(),
+            "vault+https://vault.example.com/v1/secret/data/keys"
+        );
+        assert!(from.needs_token());
+
+        assert!(KubernetesKeyProvider::parse(&KeysFrom::new("k8s", "vault")).is_err());
+        assert_eq!(
+            KubernetesKeyProvider::parse(&KeysFrom::new("k8s", "vault/keys/recovery")).unwrap(),
+            ("vault", "keys", Some("recovery"))
+        );
+    }
+
+    #[test]
+    fn vault_field_is_percent_encoded() {
+        let from = KeysFrom::from_str("vault+https://vault.example.com/v1/secret/keys?version=2")
+            .unwrap()
+            .vault_field("a&b#c=d e")
+            .unwrap();
+        assert_eq!(
+            from.location(),
+            "vault.example.com/v1/secret/keys?version=2&field=a%26b%23c%3Dd%20e"
+        );
+
+        let uri = KeysSecretUri::from_str(&format!("https://{}", from.location())).unwrap();
+        assert_eq!(uri.field(), Some("a&b#c=d e"));
+        assert_eq!(uri.path().as_str(), "/v1/secret/keys?version=2");
+    }
+
+    #[tokio::test]
+    async fn builtin_providers_read_keys() {
+        let providers = KeyProviders::default();
+
+        let keys = providers
+            .get_keys(
+                &KeysFrom::cmd("printf 'a\\nb\\n'"),
+                &request(KeyKind::Unseal),
+            )
+            .await
+            .unwrap();
+        assert_eq!(exposed(&keys), vec!["a", "b"]);
+
+        std::env::set_var("VAULT_MGMT_TEST_RECOVERY_KEYS", "c\n\n d \n");
+        let keys = providers
+            .get_keys(
+                &KeysFrom::from_str("env://VAULT_MGMT_TEST_RECOVERY_KEYS").unwrap(),
+                &request(KeyKind::Recovery),
+            )
+            .await
+            .unwrap();
+        assert_eq!(keys.kind, KeyKind::Recovery);
+        assert_eq!(exposed(&keys), vec!["c", "d"]);
+
+        let path = std::env::temp_dir().join(format!("vault-mgmt-keys-{}", rand::random::<u32>()));
+        tokio::fs::write(&path, "e\n").await.unwrap();
+        let from = KeysFrom::new("file", &path.display().to_string());
+        assert!(providers.check(&from).await.is_ok());
+        let keys = providers
+            .get_keys(&from, &request(KeyKind::Unseal))
+            .await
+            .unwrap();
+        assert_eq!(exposed(&keys), vec!["e"]);
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(providers.check(&from).await.is_err());
+
+        // no keys is an error, not an empty list
+        assert!(providers
+            .get_keys(&KeysFrom::cmd("true"), &request(KeyKind::Unseal))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn encrypted_files_are_decrypted_by_their_program() {
+        assert_eq!(
+            AgeKeyProvider::paths(&KeysFrom::new(
+                "age",
+                "/etc/vault/keys.age?identity=/root/.age/key.txt"
+            ))
+            .unwrap(),
+            (
+                PathBuf::from("/etc/vault/keys.age"),
+                PathBuf::from("/root/.age/key.txt")
+            )
+        );
+        assert!(AgeKeyProvider::paths(&KeysFrom::new("age", "/etc/vault/keys.age")).is_err());
+        assert!(
+            AgeKeyProvider::paths(&KeysFrom::new("age", "/etc/vault/keys.age?identity=")).is_err()
+        );
+
+        let providers = KeyProviders::default();
+        let missing = KeysFrom::new("gpg", "/nonexistent/vault-mgmt-keys.gpg");
+        assert!(providers.check(&missing).await.is_err());
+        assert!(providers
+            .get_keys(&missing, &request(KeyKind::Unseal))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn custom_providers_can_be_registered() {
+        struct Fixed;
+
+        #[async_trait::async_trait]
+        impl KeyProvider for Fixed {
+            async fn get_keys(
+                &self,
+                from: &KeysFrom,
+                request: &KeyRequest,
+            ) -> anyhow::Result<Keys> {
+                Ok(Keys {
+                    kind: request.kind,
+                    keys: vec![Secret::new(from.location().to_string())],
+                })
+            }
+        }
+
+        let from = KeysFrom::from_str("fixed://key").unwrap();
+        assert!(KeyProviders::default()
+            .get_keys(&from, &request(KeyKind::Unseal))
+            .await
+            .is_err());
+
+        let providers = KeyProviders::default().register("FIXED", Fixed);
+        assert!(providers.schemes().any(|s| s == "fixed"));
+        let keys = providers
+            .get_keys(&from, &request(KeyKind::Unseal))
+            .await
+            .unwrap();
+        assert_eq!(exposed(&keys), vec!["key"]);
+    }
+
+    #[test]
+    fn keys_are_read_from_lines_or_json_documents() {
+        let keys = keys_from_document("a\nb\n", KeyKind::Unseal).unwrap();
+        assert_eq!(exposed(&keys), vec!["a", "b"]);
+
+        let document = r#"{"keys": "a\nb", "recovery_keys": ["c", "d"]}"#;
+        let keys = keys_from_document(document, KeyKind::Unseal).unwrap();
+        assert_eq!(exposed(&keys), vec!["a", "b"]);
+        let keys = keys_from_document(document, KeyKind::Recovery).unwrap();
+        assert_eq!(keys.kind, KeyKind::Recovery);
+        assert_eq!(exposed(&keys), vec!["c", "d"]);
+
+        assert!(keys_from_document(r#"{"other": "a"}"#, KeyKind::Unseal).is_err());
+        assert!(keys_from_document(r#"{"keys": [1, 2]}"#, KeyKind::Unseal).is_err());
+        assert!(keys_from_document(r#"{"keys": 1}"#, KeyKind::Unseal).is_err());
+    }
+
+    #[tokio::test]
+    async fn keys_are_read_strictly_from_file_descriptors() {
+        use std::os::fd::AsRawFd;
+
+        let keys = keys_from_strict_lines("a\nb\n", KeyKind::Unseal).unwrap();
+        assert_eq!(exposed(&keys), vec!["a", "b"]);
+        assert!(keys_from_strict_lines("a\n\nb", KeyKind::Unseal).is_err());
+        assert!(keys_from_strict_lines("a \nb", KeyKind::Unseal).is_err());
+        assert!(keys_from_strict_lines("a\r\nb", KeyKind::Unseal).is_err());
+
+        let path = std::env::temp_dir().join(format!("vault-mgmt-keys-{}", rand::random::<u32>()));
+        std::fs::write(&path, "c\nd\n").unwrap();
+        let file = std::fs::File::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let from = KeysFrom::from_str(&format!("fd://{}", file.as_raw_fd())).unwrap();
+        let providers = KeyProviders::default();
+        // the descriptor is at its end after the first read, the keys are kept in memory
+        for _ in 0..2 {
+            let keys = providers
+                .get_keys(&from, &request(KeyKind::Unseal))
+                .await
+                .unwrap();
+            assert_eq!(exposed(&keys), vec!["c", "d"]);
+        }
+
+        assert!(KeyProviders::default()
+            .get_keys(&KeysFrom::new("fd", "stdin"), &request(KeyKind::Unseal))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use kube::api::{Patch, PatchParams};
+use tracing::*;
+
+use crate::{Flavor, GetLeader, GetSealStatus, LeaderStatus, PodApi, PodSealStatus, VAULT_PORT};
+
+/// Labels set by vault's Kubernetes service registration for the given status
+/// `leader` is only available if the pod is unsealed
+pub fn service_registration_labels(
+    flavor: Flavor,
+    status: &PodSealStatus,
+    leader: Option<&LeaderStatus>,
+) -> BTreeMap<String, String> {
+    let active = leader.map(|l| l.is_self).unwrap_or(false);
+    let perf_standby = leader.and_then(|l| l.performance_standby).unwrap_or(false);
+
+    BTreeMap::from([
+        (flavor.label_key("active"), active.to_string()),
+        (
+            flavor.label_key("initialized"),
+            status.initialized.to_string(),
+        ),
+        (flavor.label_key("perf-standby"), perf_standby.to_string()),
+        (flavor.label_key("sealed"), status.sealed.to_string()),
+        (flavor.label_key("version"), status.version.clone()),
+    ])
+}
+
+impl PodApi {
+    /// Set the service registration labels of the pod from its live seal status
+    /// Returns true if any label was changed
+    pub async fn sync_labels(&self, name: &str) -> anyhow::Result<bool> {
+        let mut pf = self.http(name, VAULT_PORT).await?;
+
+        let status = pf.seal_status().await?;
+        let leader = match status.sealed {
+            true => None,
+            false => Some(pf.leader().await?),
+        };
+
+        let labels = service_registration_labels(self.selector.flavor, &status, leader.as_ref());
+
+        let pod = self.api.get(name).await?;
+        let current = pod.metadata.labels.unwrap_or_default();
+
+        if labels.iter().all(|(k, v)| current.get(k) == Some(v)) {
+            return Ok(false);
+        }
+
+        debug!("updating labels of pod {}: {:?}", name, labels);
+        self.api
+            .patch(
+                name,
+                &PatchParams::default(),
+                &Patch::Merge(serde_json::json!({
+                    "metadata": {
+                        "labels": labels,
+                    },
+                })),
+            )
+            .await?;
+
+        Ok(true)
+    }
+
+    /// Set the service registration labels of all running vault pods
+    /// Pods whose API can not be reached are skipped
+    pub async fn sync_all_labels(&self) -> anyhow::Result<()> {
+        for pod in self.api.list(&self.selector.to_list_params()).await? {
+            let name = pod
+                .metadata
+                .name
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+            let running = pod
+                .status
+                .and_then(|s| s.phase)
+                .is_some_and(|p| p == "Running");
+            if !running {
+                continue;
+            }
+
+            match self.sync_labels(&name).await {
+                Ok(true) => info!("updated labels of pod {}", name),
+                Ok(false) => {}
+                Err(e) => debug!("could not sync labels of pod {}: {}", name, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep the service registration labels of all vault pods up to date,
+    /// for charts without the label-updating service registration
+    pub async fn sync_labels_every(&self, interval: Duration) {
+        loop {
+            if let Err(e) = self.sync_all_labels().await {
+                warn!("syncing labels: {}", e);
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{service_registration_labels, Flavor, LeaderStatus, PodSealStatus};
+
+    fn seal_status(sealed: bool) -> PodSealStatus {
+        serde_json::from_value(serde_json::json!({
+            "type": "shamir",
+            "initialized": true,
+            "sealed": sealed,
+            "t": 3,
+            "n": 5,
+            "progress": 0,
+            "nonce": "",
+            "version": "1.13.0",
+            "build_date": "2023-03-01T14:58:13Z",
+            "migration": false,
+            "recovery_seal": false,
+            "storage_type": "raft",
+        }))
+        .unwrap()
+    }
+
+    fn leader(is_self: bool) -> LeaderStatus {
+        serde_json::from_value(serde_json::json!({
+            "ha_enabled": true,
+            "is_self": is_self,
+            "leader_address": "https://vault-0.vault-internal:8200",
+            "leader_cluster_address": "https://vault-0.vault-internal:8201",
+            "performance_standby": false,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn labels_of_sealed_pod() {
+        let labels = service_registration_labels(Flavor::Vault, &seal_status(true), None);
+
+        assert_eq!(labels["vault-sealed"], "true");
+        assert_eq!(labels["vault-active"], "false");
+        assert_eq!(labels["vault-initialized"], "true");
+        assert_eq!(labels["vault-version"], "1.13.0");
+    }
+
+    #[test]
+    fn labels_of_active_and_standby_pod() {
+        let active =
+            service_registration_labels(Flavor::Vault, &seal_status(false), Some(&leader(true)));
+        let standby =
+            service_registration_labels(Flavor::Openbao, &seal_status(false), Some(&leader(false)));
+
+        assert_eq!(active["vault-sealed"], "false");
+        assert_eq!(active["vault-active"], "true");
+        assert_eq!(standby["openbao-active"], "false");
+        assert_eq!(standby["openbao-perf-standby"], "false");
+    }
+}
@@ -1,25 +1,116 @@
 #[macro_use]
 extern crate prettytable;
 
+mod audit;
+mod auth;
+mod autopilot;
+#[cfg(feature = "aws")]
+mod aws;
+#[cfg(feature = "azure")]
+mod azure;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod clone;
+mod cluster_set;
+mod config;
+mod consistency;
+mod converge;
+mod doctor;
 mod exec;
+mod exec_http;
+mod format;
+#[cfg(feature = "gcp")]
+mod gcp;
 mod helpers;
+mod history;
+mod hooks;
 mod http;
 mod init;
+mod journal;
+mod key_provider;
+mod labels;
+mod lock;
+mod logs;
+mod mesh;
+mod metrics;
+mod operator;
+mod operator_scope;
+mod plugin;
+mod port_forward;
+mod progress;
+mod proxy;
+mod report;
+mod resources;
+mod revision;
+mod runbook;
+mod scale;
+mod selector;
 mod show;
+#[cfg(any(test, feature = "test-util"))]
+mod sim;
+mod snapshot;
 mod status;
 mod step_down;
+mod store_keys;
+mod token;
 mod unseal;
 mod upgrade;
 mod version;
 mod wait;
 
 pub use crate::http::*;
+pub use audit::*;
+pub use auth::*;
+pub use autopilot::*;
+#[cfg(feature = "aws")]
+pub use aws::*;
+#[cfg(feature = "azure")]
+pub use azure::*;
+#[cfg(feature = "chaos")]
+pub use chaos::*;
+pub use clone::*;
+pub use cluster_set::*;
+pub use config::*;
+pub use consistency::*;
+pub use converge::*;
+pub use doctor::*;
 pub use exec::*;
+pub use exec_http::*;
+pub use format::*;
+#[cfg(feature = "gcp")]
+pub use gcp::*;
 pub use helpers::*;
+pub use history::*;
+pub use hooks::*;
 pub use init::*;
+pub use journal::*;
+pub use key_provider::*;
+pub use labels::*;
+pub use lock::*;
+pub use logs::*;
+pub use mesh::*;
+pub use metrics::*;
+pub use operator::*;
+pub use operator_scope::*;
+pub use plugin::*;
+pub use port_forward::*;
+pub use progress::*;
+pub use proxy::*;
+pub use report::*;
+pub use resources::*;
+pub use revision::*;
+pub use runbook::*;
+pub use scale::*;
+pub use selector::*;
 pub use show::*;
+#[cfg(any(test, feature = "test-util"))]
+pub use sim::*;
+pub use snapshot::*;
 pub use status::*;
 pub use step_down::*;
+pub use store_keys::*;
+pub use token::*;
 pub use unseal::*;
+pub use upgrade::*;
 pub use version::*;
 pub use wait::*;
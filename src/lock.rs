@@ -0,0 +1,270 @@
+use std::time::{Duration, Instant};
+
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::MicroTime,
+    chrono::{DateTime, Utc},
+};
+use kube::{
+    api::{DeleteParams, PostParams, Preconditions},
+    core::ObjectMeta,
+    Api, Client,
+};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+/// Time after which the lock of a crashed holder can be taken over
+const LEASE_DURATION: Duration = Duration::from_secs(60);
+
+/// Name of the Lease locking upgrades of the statefulset
+pub fn upgrade_lock_name(statefulset: &str) -> String {
+    format!("{}-vault-mgmt-upgrade", statefulset)
+}
+
+/// Identity of this process as holder of the lock, e.g. `alice@laptop/1234`
+pub(crate) fn holder_identity() -> String {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+    let host = std::env::var("HOSTNAME").unwrap_or_else(|_| "unknown".to_string());
+
+    format!("{}@{}/{}", user, host, std::process::id())
+}
+
+fn is_expired(spec: &LeaseSpec, now: DateTime<Utc>) -> bool {
+    let Some(renewed) = spec.renew_time.as_ref().or(spec.acquire_time.as_ref()) else {
+        return true;
+    };
+    let duration = spec
+        .lease_duration_seconds
+        .map(|s| s as i64)
+        .unwrap_or(LEASE_DURATION.as_secs() as i64);
+
+    renewed.0 + k8s_openapi::chrono::Duration::seconds(duration) < now
+}
+
+/// Lock held while upgrading a statefulset, so only one upgrade runs at a time
+///
+/// The Lease is renewed in the background until the lock is released.
+/// If the holder crashes, the lock expires after `LEASE_DURATION`.
+/// If another process takes over the lock or it expires, the renewal stops and `lost`
+/// is cancelled, so the rollout stops before the next pod.
+pub struct UpgradeLock {
+    api: Api<Lease>,
+    name: String,
+    holder: String,
+    lost: CancellationToken,
+    renew: JoinHandle<()>,
+}
+
+impl UpgradeLock {
+    /// Acquire the lock for the statefulset, failing if another upgrade holds it.
+    /// `force` takes over the lock regardless of its holder.
+    pub async fn acquire(
+        client: Client,
+        namespace: &str,
+        statefulset: &str,
+        force: bool,
+    ) -> anyhow::Result<Self> {
+        let api: Api<Lease> = Api::namespaced(client, namespace);
+        let name = upgrade_lock_name(statefulset);
+        let holder = holder_identity();
+        let now = Utc::now();
+
+        let spec = LeaseSpec {
+            holder_identity: Some(holder.clone()),
+            lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+            acquire_time: Some(MicroTime(now)),
+            renew_time: Some(MicroTime(now)),
+            ..Default::default()
+        };
+
+        match api.get_opt(&name).await? {
+            None => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(name.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(spec),
+                };
+                api.create(&PostParams::default(), &lease)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("acquiring upgrade lock {}/{}: {}", namespace, name, e)
+                    })?;
+            }
+            Some(mut lease) => {
+                let current = lease.spec.clone().unwrap_or_default();
+
+                if !force && !is_expired(&current, now) {
+                    anyhow::bail!(
+                        "statefulset {} is locked by {} since {} (lease {}/{}), use --force-unlock if it is no longer upgrading",
+                        statefulset,
+                        current.holder_identity.as_deref().unwrap_or("unknown"),
+                        current
+                            .acquire_time
+                            .map(|t| t.0.to_rfc3339())
+                            .unwrap_or_default(),
+                        namespace,
+                        name
+                    );
+                }
+
+                if force {
+                    warn!(
+                        "taking over upgrade lock {}/{} from {}",
+                        namespace,
+                        name,
+                        current.holder_identity.as_deref().unwrap_or("unknown")
+                    );
+                }
+
+                // the resource version makes this fail if another process took the lock meanwhile
+                lease.spec = Some(spec);
+                api.replace(&name, &PostParams::default(), &lease)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("acquiring upgrade lock {}/{}: {}", namespace, name, e)
+                    })?;
+            }
+        }
+
+        info!("acquired upgrade lock {}/{} as {}", namespace, name, holder);
+
+        let lost = CancellationToken::new();
+        let renew = tokio::spawn(renew(
+            api.clone(),
+            name.clone(),
+            holder.clone(),
+            lost.clone(),
+        ));
+
+        Ok(Self {
+            api,
+            name,
+            holder,
+            lost,
+            renew,
+        })
+    }
+
+    /// Cancelled once the lock is taken over by another process or expired
+    pub fn lost(&self) -> CancellationToken {
+        self.lost.clone()
+    }
+
+    /// Stop renewing the lock and delete the Lease, unless another process took it over
+    pub async fn release(self) -> anyhow::Result<()> {
+        self.renew.abort();
+
+        let lease = self.api.get_opt(&self.name).await?;
+        let holder = lease
+            .as_ref()
+            .and_then(|l| l.spec.as_ref())
+            .and_then(|s| s.holder_identity.as_deref());
+
+        if holder != Some(self.holder.as_str()) {
+            warn!(
+                "upgrade lock {} was taken over by {}, not releasing it",
+                self.name,
+                holder.unwrap_or("nobody")
+            );
+            return Ok(());
+        }
+
+        // the resource version makes this fail if another process took the lock meanwhile
+        let preconditions = Preconditions {
+            resource_version: lease.and_then(|l| l.metadata.resource_version),
+            uid: None,
+        };
+        self.api
+            .delete(
+                &self.name,
+                &DeleteParams::default().preconditions(preconditions),
+            )
+            .await?;
+        debug!("released upgrade lock {}", self.name);
+
+        Ok(())
+    }
+}
+
+impl Drop for UpgradeLock {
+    fn drop(&mut self) {
+        self.renew.abort();
+    }
+}
+
+/// Renew the Lease until aborted, cancel `lost` and stop if another process holds it
+/// or it could not be renewed before it expired
+async fn renew(api: Api<Lease>, name: String, holder: String, lost: CancellationToken) {
+    let mut renewed = Instant::now();
+    loop {
+        tokio::time::sleep(LEASE_DURATION / 3).await;
+
+        let result = async {
+            let Some(mut lease) = api.get_opt(&name).await? else {
+                return Ok(Some("nobody".to_string()));
+            };
+            let spec = lease.spec.get_or_insert_with(Default::default);
+            if spec.holder_identity.as_deref() != Some(holder.as_str()) {
+                return Ok(Some(
+                    spec.holder_identity
+                        .clone()
+                        .unwrap_or_else(|| "nobody".to_string()),
+                ));
+            }
+            spec.renew_time = Some(MicroTime(Utc::now()));
+            // the resource version makes this fail if another process took the lock meanwhile
+            api.replace(&name, &PostParams::default(), &lease).await?;
+            anyhow::Ok(None)
+        }
+        .await;
+
+        match result {
+            Ok(None) => renewed = Instant::now(),
+            Ok(Some(other)) => {
+                error!(
+                    "upgrade lock {} was taken over by {}, stopping before the next pod",
+                    name, other
+                );
+                lost.cancel();
+                return;
+            }
+            Err(e) if renewed.elapsed() >= LEASE_DURATION => {
+                error!(
+                    "upgrade lock {} expired, renewing failed: {}, stopping before the next pod",
+                    name, e
+                );
+                lost.cancel();
+                return;
+            }
+            Err(e) => warn!("renewing upgrade lock {}: {}", name, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::{
+        api::coordination::v1::LeaseSpec,
+        apimachinery::pkg::apis::meta::v1::MicroTime,
+        chrono::{Duration, Utc},
+    };
+
+    use super::is_expired;
+
+    #[test]
+    fn lease_expires_after_its_duration() {
+        let now = Utc::now();
+        let spec = |renewed| LeaseSpec {
+            lease_duration_seconds: Some(60),
+            renew_time: Some(MicroTime(now - Duration::seconds(renewed))),
+            ..Default::default()
+        };
+
+        assert!(!is_expired(&spec(30), now));
+        assert!(is_expired(&spec(90), now));
+        assert!(is_expired(&LeaseSpec::default(), now));
+    }
+}
@@ -0,0 +1,191 @@
+use futures_util::{stream, AsyncBufReadExt, StreamExt};
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{Api, LogParams};
+use tokio::io::AsyncWriteExt;
+
+use crate::{vault_container_name, ExecIn, PodSelector};
+
+/// Which vault pods to show the logs of
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LogsOf {
+    /// a single pod by name
+    Pod(String),
+    /// all pods matching the selector
+    Selected(ExecIn),
+    /// all vault pods
+    All,
+}
+
+/// Event in the vault server log worth highlighting
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LogEvent {
+    Sealed,
+    Unsealed,
+    Leadership,
+}
+
+impl LogEvent {
+    /// Classify a line of the vault server log
+    pub fn classify(line: &str) -> Option<Self> {
+        let line = line.to_lowercase();
+
+        if line.contains("vault is unsealed") || line.contains("post-unseal setup complete") {
+            Some(LogEvent::Unsealed)
+        } else if line.contains("vault is sealed")
+            || line.contains("marked as sealed")
+            || line.contains("pre-seal teardown")
+        {
+            Some(LogEvent::Sealed)
+        } else if line.contains("acquired lock, enabling active operation")
+            || line.contains("entering standby mode")
+            || line.contains("stepping down")
+            || line.contains("entering leader state")
+            || line.contains("lost leadership")
+        {
+            Some(LogEvent::Leadership)
+        } else {
+            None
+        }
+    }
+
+    fn color(&self) -> &'static str {
+        match self {
+            LogEvent::Sealed => "\x1b[1;31m",
+            LogEvent::Unsealed => "\x1b[1;32m",
+            LogEvent::Leadership => "\x1b[1;33m",
+        }
+    }
+}
+
+/// Highlight seal, unseal and leadership events with ANSI colors
+pub fn highlight_log_line(line: &str) -> String {
+    match LogEvent::classify(line) {
+        Some(event) => format!("{}{}\x1b[0m", event.color(), line),
+        None => line.to_string(),
+    }
+}
+
+/// Stream the logs of the vault container of the selected pods to stdout
+///
+/// Lines are prefixed with the pod name if more than one pod is selected.
+pub async fn logs(
+    api: &Api<Pod>,
+    selector: &PodSelector,
+    of: LogsOf,
+    follow: bool,
+    tail_lines: Option<i64>,
+    highlight: bool,
+) -> anyhow::Result<()> {
+    let pods = match of {
+        LogsOf::Pod(name) => vec![api.get(&name).await?],
+        LogsOf::Selected(exec_in) => {
+            api.list(&selector.clone().role(exec_in).to_list_params())
+                .await?
+                .items
+        }
+        LogsOf::All => api.list(&selector.to_list_params()).await?.items,
+    };
+
+    if pods.is_empty() {
+        anyhow::bail!("no matching vault pod found");
+    }
+
+    let prefix = pods.len() > 1;
+
+    let mut streams = vec![];
+    for pod in pods.iter() {
+        let name = pod
+            .metadata
+            .name
+            .clone()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+        let lp = LogParams {
+            container: Some(vault_container_name(pod, selector.container.as_deref())?),
+            follow,
+            tail_lines,
+            ..Default::default()
+        };
+
+        let lines = api.log_stream(&name, &lp).await?.lines();
+
+        streams.push(lines.map(move |line| (name.clone(), line)).boxed());
+    }
+
+    let mut lines = stream::select_all(streams);
+    let mut stdout = tokio::io::stdout();
+
+    while let Some((pod, line)) = lines.next().await {
+        let line = line?;
+        let line = if highlight {
+            highlight_log_line(&line)
+        } else {
+            line
+        };
+
+        let line = if prefix {
+            format!("[{}] {}\n", pod, line)
+        } else {
+            format!("{}\n", line)
+        };
+
+        stdout.write_all(line.as_bytes()).await?;
+    }
+
+    stdout.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{highlight_log_line, LogEvent};
+
+    #[test]
+    fn classifying_log_lines_works() {
+        for (line, expected) in [
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  core: vault is unsealed",
+                Some(LogEvent::Unsealed),
+            ),
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  core: post-unseal setup complete",
+                Some(LogEvent::Unsealed),
+            ),
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  core: vault is sealed",
+                Some(LogEvent::Sealed),
+            ),
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  core: acquired lock, enabling active operation",
+                Some(LogEvent::Leadership),
+            ),
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  core: entering standby mode",
+                Some(LogEvent::Leadership),
+            ),
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  storage.raft: entering leader state: leader=\"Node at vault-0.vault-internal:8201 [Leader]\"",
+                Some(LogEvent::Leadership),
+            ),
+            (
+                "2024-08-12T10:00:00.000Z [INFO]  core: successfully mounted: type=kv path=secret/",
+                None,
+            ),
+        ] {
+            assert_eq!(LogEvent::classify(line), expected, "{}", line);
+        }
+    }
+
+    #[test]
+    fn highlighting_only_changes_events() {
+        assert_eq!(
+            highlight_log_line("core: vault is sealed"),
+            "\x1b[1;31mcore: vault is sealed\x1b[0m"
+        );
+        assert_eq!(
+            highlight_log_line("core: successfully mounted"),
+            "core: successfully mounted"
+        );
+    }
+}
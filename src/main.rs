@@ -1,22 +1,58 @@
 use clap::builder::TypedValueParser;
-use clap::{CommandFactory, Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_complete::{generate, Shell};
-use k8s_openapi::api::apps::v1::StatefulSet;
+use k8s_openapi::api::{
+    apps::v1::StatefulSet,
+    core::v1::{ConfigMap, Pod},
+    networking::v1::NetworkPolicy,
+};
 use kube::{api::Api, core::ObjectMeta, Client};
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
 use self_update::cargo_crate_version;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
 use std::io;
 use std::str::FromStr;
-use tokio::task::spawn_blocking;
+use std::sync::Arc;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::spawn_blocking,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 use tracing_subscriber::{layer::SubscriberExt, EnvFilter, Registry};
 
 use vault_mgmt_lib::{
-    construct_table, is_statefulset_ready, GetUnsealKeys, GetUnsealKeysFromVault, StepDown,
-    VAULT_PORT, {exec, ExecIn}, {get_unseal_keys, list_sealed_pods, Unseal},
-    {list_vault_pods, PodApi, StatefulSetApi},
+    after_leader_change, await_cluster_converged, await_condition, check_config,
+    check_config_clusters, check_store_target, construct_audit_table,
+    construct_autopilot_configuration_table, construct_autopilot_state_table,
+    construct_doctor_table, construct_pods_table, construct_raft_configuration_table,
+    construct_revision_table, construct_seal_status_table, construct_seal_status_table_of,
+    construct_table, construct_target_table, construct_token_table, construct_upgrade_plan_table,
+    diagnose_network_policies, diagnose_resources, diagnose_retry_join, discover_clusters,
+    find_plugin, find_pod, find_vault_container, format_duration, forward_to_active,
+    image_with_version, is_active, is_statefulset_ready, list_pods_by_flavor,
+    list_sealed_pods_with, logs, plan_upgrade, raft_configuration_all_voters,
+    raft_configuration_any_leader, read_journal, run_operators, run_plugin, serve_metrics,
+    serve_operator_endpoints, statefulset_pod_selector, store_init_result, token_accessor,
+    unbracketed_host, unfinished_actions, upgrade_runbook, ActiveStrategy, AuditDevice,
+    AutopilotConfig, AutopilotConfigurationUpdate, AutopilotState, BytesBody, ClusterConfig,
+    ClusterSet, ClusterUpgradeOptions, ConfigFile, ConvergeOptions, DeadlineExceeded,
+    EnableAuditDevice, Finding, Flavor, GetAutopilotState, GetRaftConfiguration, GetSealStatus,
+    HealthGate, HttpForwarderService, ImagePullFailed, Inconsistent, Init, InitRequest, InitResult,
+    Journal, KeyKind, KeyProviders, KeyRequest, Keys, KeysFrom, KeysSecretUri, KubeTuning,
+    KubernetesAuth, ListAuditDevices, LogsOf, Mesh, Operator, OperatorStats, PluginContext,
+    PodHook, PodSelector, Proxy, QuitSidecar, RaftConfiguration, RaftJoinRequest, RunbookTarget,
+    ScaleOptions, Severity, SnapshotDestination, StatefulSetRef, StepDown, Takeover,
+    TakeoverCondition, TimeFormat, TlsOptions, TokenLookup, TokenRenew, TokenRevoke, Transport,
+    UnsealRateLimit, UpgradeInterrupted, UpgradeLockLost, UpgradeOptions, UpgradeReporter,
+    VaultKeyProvider, VaultVersion, DEFAULT_STATEFULSET_SELECTOR, DEFAULT_UNSEAL_CONCURRENCY,
+    SERVICE_ACCOUNT_TOKEN_PATH, VAULT_PORT, {exec_with, ExecIn}, {PodApi, StatefulSetApi},
 };
 
+/// How often the labels are updated with `--label-sync`
+const LABEL_SYNC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
 /// Manage your vault installation in Kubernetes
 #[derive(Parser, Debug)]
 #[command(name = "vault-mgmt", author, version, about, long_about = None)]
@@ -48,6 +84,127 @@ struct Cli {
     #[arg(long)]
     no_tls: bool,
 
+    /// Make the local service mesh sidecar exit after running the command.
+    /// This is needed when running as a Job in a meshed namespace, as the Job would not complete otherwise.
+    #[arg(long, value_name = "MESH", value_enum)]
+    quit_mesh_sidecar: Option<Mesh>,
+
+    /// How to connect to the vault API of the pods
+    #[arg(long, default_value_t = Transport::Auto, value_enum)]
+    transport: Transport,
+
+    /// Send the replication state of writes (`X-Vault-Index`) with the following requests,
+    /// so Vault Enterprise performance standbys serve reads after writes, e.g. raft reads
+    /// after a step-down. Sets what a performance standby does if it is behind.
+    #[arg(long, value_name = "INCONSISTENT", value_enum)]
+    read_your_writes: Option<Inconsistent>,
+
+    /// How to show durations and timestamps in tables
+    #[arg(long, default_value_t = TimeFormat::Relative, value_enum)]
+    time_format: TimeFormat,
+
+    /// Which helm chart the pods are installed by, determining their labels.
+    /// `all` shows the pods of all flavors grouped by the flavor detected for each pod
+    /// (only supported by `show` and `seal-status`)
+    #[arg(long, default_value_t = FlavorArg::Vault, value_enum)]
+    flavor: FlavorArg,
+
+    /// Only manage the pods of this helm release (`app.kubernetes.io/instance` label)
+    #[arg(long)]
+    instance: Option<String>,
+
+    /// Only manage the pods with this label, can be repeated
+    #[arg(long, value_name = "KEY=VALUE", value_parser = parse_label)]
+    pod_label: Vec<(String, String)>,
+
+    /// Name of the vault container in the pods and the statefulset.
+    /// By default the container named after the flavor (`vault` or `openbao`) is used,
+    /// or the only container of the pod.
+    #[arg(long)]
+    container_name: Option<String>,
+
+    /// Read the unseal or recovery keys from this uri instead of `--keys-secret-uri` or
+    /// `--key-cmd`: `vault+https://<host>/v1/<kv secret>`, `k8s://<namespace>/<secret>[/<key>]`,
+    /// `file:///<path>`, `gpg:///<path>`, `age:///<path>?identity=<identity file>`,
+    /// `cmd://<shell command>`, `env://<variable>` or `fd://<file descriptor>`;
+    /// with the `aws` feature also `aws-sm://<secret name or arn>` and `aws-ssm://<parameter>`,
+    /// with the `gcp` feature also `gcp-sm://projects/<project>/secrets/<secret>[/versions/<version>]`,
+    /// with the `azure` feature also `azure-kv://<vault>/<secret>[/<version>]`
+    #[arg(long, value_name = "URI")]
+    keys_from: Option<KeysFrom>,
+
+    /// Read the unseal or recovery keys from this file, one per line,
+    /// instead of `--keys-from`, `--keys-secret-uri` or `--key-cmd`
+    #[arg(long, value_name = "PATH", conflicts_with = "keys_from")]
+    keys_file: Option<std::path::PathBuf>,
+
+    /// Field of the vault secret of `--keys-secret-uri` or `--keys-from vault+https://...`
+    /// holding the keys, separated by newlines or as a json array, instead of `keys` or
+    /// `recovery_keys` (the same as `?field=<name>` in the uri)
+    #[arg(long, value_name = "FIELD")]
+    keys_secret_field: Option<String>,
+
+    /// Read the unseal or recovery keys from stdin, exactly one per line, instead of
+    /// `--keys-from`, `--keys-secret-uri` or `--key-cmd` (the same as `--keys-from fd://0`)
+    #[arg(long, conflicts_with_all = ["keys_from", "keys_file", "keys_fd"])]
+    keys_stdin: bool,
+
+    /// Read the unseal or recovery keys from this inherited file descriptor, exactly one per
+    /// line, e.g. a pipe of a wrapper (the same as `--keys-from fd://<n>`)
+    #[arg(long, value_name = "N", conflicts_with_all = ["keys_from", "keys_file"])]
+    keys_fd: Option<u32>,
+
+    /// Decrypt `--keys-file` locally with `age` (see `--age-identity`) or `gpg`
+    /// (with the keys of the gpg agent), the plaintext is never written to disk
+    #[arg(long, value_enum, requires = "keys_file")]
+    keys_decrypt: Option<KeysDecrypt>,
+
+    /// Identity file of age used with `--keys-decrypt age`
+    #[arg(long, value_name = "PATH")]
+    age_identity: Option<std::path::PathBuf>,
+
+    /// Verify the TLS certificate of the vault of `--keys-secret-uri` with the certificates of
+    /// this PEM file instead of the native root certificates.
+    /// The connections to the vault pods are not affected.
+    #[arg(long, value_name = "PATH")]
+    keys_ca_cert: Option<std::path::PathBuf>,
+
+    /// Do not verify the TLS certificate of the vault of `--keys-secret-uri`
+    #[arg(long, conflicts_with = "keys_ca_cert")]
+    keys_tls_skip_verify: bool,
+
+    /// Authenticate to the vault of `--keys-secret-uri` with this PEM client certificate
+    #[arg(long, value_name = "PATH", requires = "keys_client_key")]
+    keys_client_cert: Option<std::path::PathBuf>,
+
+    /// PEM private key of `--keys-client-cert`
+    #[arg(long, value_name = "PATH", requires = "keys_client_cert")]
+    keys_client_key: Option<std::path::PathBuf>,
+
+    /// Log in to the vault of `--keys-secret-uri` with its kubernetes auth method and this role,
+    /// instead of using the token. The service account token of vault-mgmt is used for the login.
+    #[arg(long)]
+    keys_auth_role: Option<String>,
+
+    /// Mount path of the kubernetes auth method used with `--keys-auth-role`
+    #[arg(long, default_value = "kubernetes")]
+    keys_auth_mount: String,
+
+    /// Service account token used with `--keys-auth-role`
+    #[arg(long, default_value = SERVICE_ACCOUNT_TOKEN_PATH)]
+    keys_auth_jwt_path: std::path::PathBuf,
+
+    /// Keep the vault-sealed and vault-active labels of the pods up to date from their seal status
+    /// while running the command. This is needed for charts without service registration.
+    #[arg(long)]
+    label_sync: bool,
+
+    /// YAML file with settings that are rarely changed, e.g. the backoff of the watches
+    /// on the Kubernetes API (`kube: {watch_timeout: 290s, page_size: 500, initial_backoff: 800ms,
+    /// max_backoff: 30s, backoff_multiplier: 2, backoff_reset: 2m}`)
+    #[arg(long)]
+    config: Option<std::path::PathBuf>,
+
     /// Subcommand to run
     #[command(subcommand)]
     command: Commands,
@@ -57,7 +214,28 @@ struct Cli {
 #[command(arg_required_else_help = true)]
 enum Commands {
     /// Show the current state of the vault pods
-    Show {},
+    ///
+    /// With a token, the raft configuration is used to show pods still joining the cluster.
+    Show {
+        /// vault token to read the raft configuration
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable if set
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+    },
+
+    /// Show the seal status of the vault pods as reported by their API
+    ///
+    /// Unlike `show`, this does not rely on the pod labels being up to date.
+    SealStatus {
+        /// only query the pod with this name
+        #[arg(short = 'p', long)]
+        pod: Option<String>,
+    },
+
+    /// Check the environment for common problems
+    ///
+    /// Exits with an error if any check reports an error.
+    Doctor {},
 
     /// Execute a command in the vault pod
     #[command(arg_required_else_help = true)]
@@ -82,6 +260,133 @@ enum Commands {
         /// environment variables to set from the current environment
         #[arg(short = 'k', long)]
         env_keys: Vec<String>,
+
+        /// only print the pod(s) the command would act on with their role and version
+        #[arg(long)]
+        print_target: bool,
+    },
+
+    /// Show the logs of the vault pods
+    #[command(group(clap::ArgGroup::new("pods").args(["pod", "all", "logs_in"])))]
+    Logs {
+        /// name of the pod to show the logs of
+        #[arg(short = 'p', long)]
+        pod: Option<String>,
+
+        /// show the logs of all vault pods
+        #[arg(short = 'a', long)]
+        all: bool,
+
+        /// which pods to show the logs of
+        #[arg(
+            short = 'i',
+            long = "in",
+            value_name = "IN",
+            default_value_t = ExecIn::Active,
+            value_enum
+        )]
+        logs_in: ExecIn,
+
+        /// follow the logs
+        #[arg(short = 'f', long)]
+        follow: bool,
+
+        /// number of lines from the end of the logs to show
+        #[arg(long)]
+        tail: Option<i64>,
+
+        /// highlight seal, unseal and leadership events
+        #[arg(long)]
+        highlight: bool,
+    },
+
+    /// Forward a local port to the active pod
+    ///
+    /// The active pod is looked up for every new connection, so the vault CLI can be pointed at
+    /// the local port even while the leadership changes.
+    PortForward {
+        /// local address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        address: String,
+
+        /// local port to listen on
+        #[arg(short = 'p', long, default_value_t = VAULT_PORT)]
+        local_port: u16,
+    },
+
+    /// Run a local HTTP proxy to the active pod
+    ///
+    /// Unlike `port-forward`, requests are retried on the new active pod when a standby pod
+    /// answers with a redirect or the connection breaks. The proxy serves plain HTTP,
+    /// point the vault CLI at it with `VAULT_ADDR=http://127.0.0.1:8200`.
+    Proxy {
+        /// local address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        address: String,
+
+        /// local port to listen on
+        #[arg(short = 'p', long, default_value_t = VAULT_PORT)]
+        local_port: u16,
+
+        /// how often a request is retried on a new connection
+        #[arg(long, default_value_t = 3)]
+        retries: usize,
+    },
+
+    /// Show the Prometheus metrics of the vault pods
+    ///
+    /// The metrics of all pods are merged and labelled with the pod name.
+    Metrics {
+        /// vault token to read the metrics
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable if set
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// only show the metrics of this pod
+        #[arg(short = 'p', long, conflicts_with = "serve")]
+        pod: Option<String>,
+
+        /// serve the metrics on `/metrics` at this local address (e.g. `127.0.0.1:9102`)
+        /// instead of printing them once
+        #[arg(long)]
+        serve: Option<String>,
+    },
+
+    /// Initialize vault in a pod of a new installation and print the key shares and root token,
+    /// or store them with `--store-keys`
+    #[command(arg_required_else_help = true)]
+    Init {
+        /// pod to initialize, given by name (`vault-0`) or ordinal (`0`)
+        #[arg(short = 'p', long)]
+        pod: String,
+
+        /// store the key shares and root token in this new secret instead of printing them:
+        /// `k8s://<namespace>/<secret>` or `vault+https://<host>/v1/<kv v2 secret>`.
+        /// The shares are read back with `--keys-from` and the same uri.
+        /// If storing fails, they are printed so they are not lost.
+        #[arg(long, value_name = "URI")]
+        store_keys: Option<KeysFrom>,
+
+        /// vault token for storing the keys with `--store-keys vault+https://...`
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// number of unseal key shares, for the shamir seal
+        #[arg(long, default_value_t = 3)]
+        key_shares: u8,
+
+        /// number of unseal key shares needed to unseal, for the shamir seal
+        #[arg(long, default_value_t = 2)]
+        key_threshold: u8,
+
+        /// number of recovery key shares, for auto-unseal
+        #[arg(long, default_value_t = 5)]
+        recovery_shares: u8,
+
+        /// number of recovery key shares needed e.g. to generate a root token, for auto-unseal
+        #[arg(long, default_value_t = 3)]
+        recovery_threshold: u8,
     },
 
     /// Unseal all sealed pods
@@ -96,13 +401,96 @@ enum Commands {
         /// for example: `https://vault.example.com/v1/secret/data/vault/unseal-keys`.
         /// the secret must store the keys separated by newlines in the data field `keys`.
         #[arg(long)]
-        keys_secret_uri: Option<String>,
+        keys_secret_uri: Option<KeysSecretUri>,
 
         /// command that writes unseal keys to its stdout.
         /// each line will be used as a key.
         /// the command will be executed locally
         #[arg(long)]
         key_cmd: Option<String>,
+
+        /// only unseal this pod, given by name (`vault-2`) or ordinal (`2`),
+        /// even if its `vault-sealed` label is not set yet
+        #[arg(short = 'p', long)]
+        pod: Option<String>,
+
+        /// how many sealed pods are unsealed at once, each over its own connection
+        #[arg(long, default_value_t = DEFAULT_UNSEAL_CONCURRENCY)]
+        concurrency: usize,
+
+        /// only print the pod(s) the command would act on with their role and version
+        #[arg(long)]
+        print_target: bool,
+    },
+
+    /// Run as operator, unsealing pods that got sealed until interrupted
+    Operator {
+        /// vault token to use for retrieving the unseal keys from `--keys-secret-uri`
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// uri to vault kv secret containing the unseal keys, see `unseal`
+        #[arg(long)]
+        keys_secret_uri: Option<KeysSecretUri>,
+
+        /// command that writes unseal keys to its stdout, see `unseal`
+        #[arg(long)]
+        key_cmd: Option<String>,
+
+        /// time between the checks for sealed pods
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        interval: std::time::Duration,
+
+        /// how often the same pod may be unsealed within `--unseal-window`.
+        /// A pod that keeps resealing (e.g. crash looping) is left sealed afterwards
+        /// and a warning event is published for it.
+        #[arg(long, default_value_t = 3)]
+        max_unseals: usize,
+
+        /// window of `--max-unseals`
+        #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+        unseal_window: std::time::Duration,
+
+        /// how many sealed pods are unsealed at once
+        #[arg(long, default_value_t = DEFAULT_UNSEAL_CONCURRENCY)]
+        unseal_concurrency: usize,
+
+        /// serve `/healthz`, `/readyz` and `/metrics` of the operator at this address
+        /// (e.g. `0.0.0.0:9102`), for liveness and readiness probes and Prometheus
+        #[arg(long)]
+        serve: Option<String>,
+
+        /// Keep all `clusters` of `--config` unsealed instead of the pods in `--namespace`.
+        /// Each cluster runs its own reconcile loop with its own unseal keys,
+        /// `--keys-from`, `--keys-secret-uri` or `--key-cmd` is used for clusters without them.
+        #[arg(long, conflicts_with = "cluster")]
+        all: bool,
+
+        /// Keep this cluster of the `clusters` of `--config` unsealed, can be repeated (see `--all`)
+        #[arg(long, value_name = "NAME")]
+        cluster: Vec<String>,
+
+        /// Keep the statefulsets matching `--statefulset-selector` in this namespace unsealed,
+        /// can be repeated. Each statefulset runs its own reconcile loop, its unseal keys are
+        /// read from the key source in its `vault-mgmt.io/keys-from` annotation
+        /// (see `--keys-from`), falling back to the key source of the operator.
+        #[arg(long, value_name = "NAMESPACE", conflicts_with = "all_namespaces")]
+        watch_namespace: Vec<String>,
+
+        /// Keep the statefulsets matching `--statefulset-selector` in all namespaces unsealed,
+        /// see `--watch-namespace`
+        #[arg(long)]
+        all_namespaces: bool,
+
+        /// label selector of the statefulsets of `--watch-namespace` and `--all-namespaces`
+        #[arg(long, default_value = DEFAULT_STATEFULSET_SELECTOR)]
+        statefulset_selector: String,
+
+        /// time between looking for added and removed statefulsets, and restarting
+        /// the reconcile loops of clusters that failed (e.g. because the keys could not be read)
+        #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+        rediscover: std::time::Duration,
     },
 
     /// Step down the active pod
@@ -111,11 +499,99 @@ enum Commands {
         /// if not provided, the token will be read from the VAULT_TOKEN environment variable
         #[arg(short = 't', long)]
         token: Option<Secret<String>>,
+
+        /// wait until a different pod has been elected as leader
+        #[arg(short = 'w', long)]
+        wait: bool,
+
+        /// how long to wait for a new leader, e.g. `30s` or `2m`
+        #[arg(long, default_value = "1m", value_parser = humantime::parse_duration, requires = "wait")]
+        timeout: std::time::Duration,
+
+        /// only print the pod(s) the command would act on with their role and version
+        #[arg(long)]
+        print_target: bool,
+
+        #[command(flatten)]
+        leader: LeaderHookArgs,
     },
 
     /// Wait until the statefulset is ready
     WaitUntilReady {},
 
+    /// Wait until all pods report the version via their seal status and are unsealed and ready,
+    /// e.g. when another system rolls out the new version
+    WaitUntilVersion {
+        /// version to wait for, e.g. `1.14.2`
+        version: String,
+
+        /// how long to wait, e.g. `30m`
+        #[arg(long, default_value = "30m", value_parser = humantime::parse_duration)]
+        timeout: std::time::Duration,
+
+        /// time between the checks of the pods
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        interval: std::time::Duration,
+    },
+
+    /// Wait until the statefulset is ready, all pods are unsealed, raft has a leader
+    /// and all raft servers are voters, e.g. after `helm upgrade` rolled out the pods
+    WaitUntilConverged {
+        /// vault token to read the raft configuration
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// how long to wait, e.g. `30m`
+        #[arg(long, default_value = "30m", value_parser = humantime::parse_duration)]
+        timeout: std::time::Duration,
+
+        /// time between the checks of the cluster
+        #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+        interval: std::time::Duration,
+    },
+
+    /// Verify that all pods were created from the current revision of the statefulset
+    ///
+    /// Compares the `controller-revision-hash` label of each pod with the `updateRevision`
+    /// of the statefulset, catching pods recreated from a stale revision during a rollout.
+    Verify {},
+
+    /// Check the config file without changing anything
+    #[command(arg_required_else_help = true)]
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+
+    /// List and enable audit devices
+    #[command(arg_required_else_help = true)]
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommands,
+    },
+
+    /// Look up, renew and revoke the token used for the other commands
+    #[command(arg_required_else_help = true)]
+    Token {
+        #[command(subcommand)]
+        command: TokenCommands,
+    },
+
+    /// Show the autopilot state and configuration, or change the configuration
+    #[command(arg_required_else_help = true)]
+    Autopilot {
+        #[command(subcommand)]
+        command: AutopilotCommands,
+    },
+
+    /// Inspect the raft storage of the cluster
+    #[command(arg_required_else_help = true)]
+    Raft {
+        #[command(subcommand)]
+        command: RaftCommands,
+    },
+
     /// Do a rolling upgrade of the vault pods without downtime
     ///
     /// This will upgrade the standby pods first by deleting the pods and them getting recreated
@@ -143,216 +619,2695 @@ enum Commands {
         /// for example: `https://vault.example.com/v1/secret/data/vault/unseal-keys`.
         /// the secret must store the keys separated by newlines in the data field `keys`.
         #[arg(long)]
-        keys_secret_uri: Option<String>,
+        keys_secret_uri: Option<KeysSecretUri>,
 
         /// command that writes unseal keys to its stdout.
         /// each line will be used as a key.
         /// the command will be executed locally
         #[arg(long)]
         key_cmd: Option<String>,
-    },
 
-    /// Generate autocompletion scripts for your shell
-    #[command(arg_required_else_help = true)]
-    Completion {
-        /// Shell to generate the autocompletion script for
-        shell: Shell,
-    },
+        /// Wait for service mesh sidecars to be ready as well.
+        /// By default, only the vault container has to be ready in pods with a sidecar.
+        #[arg(long)]
+        wait_for_sidecars: bool,
 
-    /// Update the vault-mgmt binary to the latest version
-    SelfUpdate {},
-}
+        /// Delete pods through the Eviction API, so PodDisruptionBudgets and other policies
+        /// are honored. A blocked eviction is retried until `--delete-timeout`.
+        #[arg(long)]
+        use_eviction: bool,
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let cli = Cli::parse();
+        /// Let kubernetes replace the pods by lowering the partition of the RollingUpdate
+        /// strategy one pod at a time, from the highest ordinal down, instead of deleting them.
+        /// The partition is set to the replicas before the image is changed.
+        #[arg(long, conflicts_with_all = [
+            "force_upgrade", "use_eviction", "plan", "emit_runbook", "revert_on_pull_failure",
+            "rollback_on_failure", "canary", "max_unavailable",
+        ])]
+        partition: bool,
 
-    rustls::crypto::ring::default_provider()
-        .install_default()
-        .unwrap();
+        /// Do not publish Kubernetes events (e.g. `PodDeleted`) for the actions on the
+        /// statefulset and its pods
+        #[arg(long)]
+        no_events: bool,
 
-    let env_filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new(format!("vault_mgmt={}", cli.log_level)));
-    tracing::subscriber::set_global_default(
-        Registry::default()
-            .with(env_filter)
-            .with(tracing_subscriber::fmt::layer()),
-    )?;
+        /// Only print the upgrade plan (see `plan`) without changing anything
+        #[arg(long)]
+        plan: bool,
 
-    match cli.command {
-        Commands::Completion { shell } => {
-            let mut cmd = Cli::command();
-            let name = cmd.get_name().to_string();
+        /// Write the kubectl and vault commands of the upgrade with explanations to this shell
+        /// script instead of running them, e.g. for manual execution after an approval
+        #[arg(long, value_name = "PATH", conflicts_with = "plan")]
+        emit_runbook: Option<std::path::PathBuf>,
 
-            generate(shell, &mut cmd, name, &mut io::stdout());
-        }
-        Commands::Show {} => {
-            let api = setup_api(&cli.namespace).await?;
-            let table = construct_table(&api).await?;
+        /// Write a report of the upgrade as JSON to this file, with the versions, unseal method
+        /// and duration of the steps of each pod and the estimated time the remaining pods need.
+        /// It is written even if the upgrade fails.
+        #[arg(long, value_name = "PATH")]
+        report_json: Option<std::path::PathBuf>,
 
-            table.printstd();
-        }
-        Commands::Exec {
-            cmd,
-            exec_in,
-            env,
-            env_keys,
-        } => {
-            let api = setup_api(&cli.namespace).await?;
-            let env = collect_env(env, env_keys)?;
-            exec(&api, cmd.join(" "), exec_in, env).await?;
-        }
-        Commands::StepDown { token } => {
-            let api = setup_api(&cli.namespace).await?;
-            let active = api
-                .list(&list_vault_pods().labels(&ExecIn::Active.to_label_selector()))
-                .await?;
-            let active = active.iter().next().ok_or(anyhow::anyhow!(
-                "no active vault pod found. is vault sealed?"
-            ))?;
+        /// Append every action to this local journal before it is executed and again once it
+        /// completed or failed, so an interrupted upgrade shows what was done and what was in
+        /// progress (see `journal`)
+        #[arg(long, value_name = "PATH")]
+        journal: Option<std::path::PathBuf>,
 
-            PodApi::new(api, !cli.no_tls, cli.domain)
-                .http(
-                    active
-                        .metadata
-                        .name
-                        .as_ref()
-                        .ok_or(anyhow::anyhow!("pod does not have a name"))?
-                        .as_str(),
-                    VAULT_PORT,
-                )
-                .await?
-                .step_down(get_token(token)?)
-                .await?;
-        }
-        Commands::WaitUntilReady {} => {
-            let api: Api<StatefulSet> = setup_api(&cli.namespace).await?;
-            kube::runtime::wait::await_condition(
-                api.clone(),
-                &cli.statefulset,
-                is_statefulset_ready(),
-            )
-            .await?;
-        }
-        Commands::Unseal {
-            token,
-            keys_secret_uri,
-            key_cmd,
-        } => {
-            let api = setup_api(&cli.namespace).await?;
-            let sealed = list_sealed_pods(&api).await?;
+        /// Set the vault image of the statefulset to this version before upgrading (see `set-image`)
+        #[arg(long)]
+        target_version: Option<String>,
 
-            if sealed.is_empty() {
-                return Ok(());
-            }
+        /// If a recreated pod cannot pull the image of `--target-version`, set the previous image
+        /// again and recreate the pod with it. Otherwise the upgrade stops with the pod failing.
+        #[arg(long, requires = "target_version")]
+        revert_on_pull_failure: bool,
 
-            let mut keys = Vec::new();
+        /// If the upgrade fails (e.g. a pod never unseals or never becomes ready), set the
+        /// statefulset to the previous version again and roll the upgraded pods back.
+        /// The previous version is read from the annotations of the upgraded pods.
+        #[arg(long, conflicts_with = "revert_on_pull_failure")]
+        rollback_on_failure: bool,
 
-            if let Some(path) = keys_secret_uri {
-                let token = get_token(token)?;
+        /// Take a raft snapshot of the active pod before upgrading any pod and store it in this
+        /// file or S3 object (`s3://bucket/key`, uploaded with the `aws` CLI).
+        /// The upgrade stops if the snapshot cannot be taken or stored.
+        #[arg(long, value_name = "PATH_OR_S3_URL")]
+        snapshot_before: Option<SnapshotDestination>,
 
-                let uri = http::Uri::from_str(&path)?;
+        /// Upgrade up to this many standby pods at the same time.
+        /// Limited to the number of voters raft can lose without losing quorum.
+        #[arg(long, default_value_t = 1)]
+        max_unavailable: usize,
 
-                let mut client = GetUnsealKeysFromVault::new(&uri)?;
+        /// Continue an interrupted upgrade, skipping the pods it already finished.
+        /// The progress is stored in an annotation of the statefulset.
+        #[arg(long)]
+        resume: bool,
 
-                let mut k = client
-                    .get_unseal_keys(
-                        uri.path_and_query()
-                            .ok_or(anyhow::anyhow!("keys secret uri is not valid: {}", path))?,
-                        token,
-                    )
-                    .await?;
+        /// Stop before the next pod if the upgrade cannot finish before this time, given as
+        /// timestamp (`2024-05-01T22:00:00Z`) or as duration from now (`2h`). The remaining
+        /// time is estimated from the pods done so far, continue later with `--resume`.
+        #[arg(long, value_name = "TIMESTAMP_OR_DURATION", value_parser = parse_deadline)]
+        deadline: Option<std::time::SystemTime>,
 
-                keys.append(&mut k);
-            } else if let Some(cmd) = key_cmd {
-                let mut k = get_unseal_keys(&cmd).await?;
+        /// Upgrade this many standby pods first and watch them for `--bake-time`
+        /// before upgrading the remaining pods. The upgrade stops if a canary pod
+        /// gets sealed, becomes unready or autopilot reports an unhealthy server.
+        #[arg(long, default_value_t = 0)]
+        canary: usize,
 
-                if k.is_empty() {
-                    anyhow::bail!("no unseal keys returned from command")
-                }
+        /// Time the canary pods have to stay healthy, e.g. `10m`
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "10m", requires = "canary")]
+        bake_time: std::time::Duration,
 
-                keys.append(&mut k);
-            } else {
-                anyhow::bail!("no keys secret uri or key cmd specified")
-            }
-
-            for pod in sealed.iter() {
-                PodApi::new(api.clone(), !cli.no_tls, cli.domain.clone())
-                    .http(
-                        pod.metadata
-                            .name
-                            .as_ref()
-                            .ok_or(anyhow::anyhow!("pod does not have a name"))?
-                            .as_str(),
-                        VAULT_PORT,
-                    )
-                    .await?
-                    .unseal(&keys)
-                    .await?;
-            }
-        }
-        Commands::Upgrade {
-            token,
-            do_not_unseal,
-            force_upgrade,
-            keys_secret_uri,
-            key_cmd,
-        } => {
-            let stss = setup_api(&cli.namespace).await?;
-            let pods = setup_api(&cli.namespace).await?;
+        /// Upgrade even if the target version is older than the version of a pod
+        #[arg(long)]
+        allow_downgrade: bool,
 
-            let mut keys = Vec::new();
+        /// Recreate outdated pods even if their template also changed besides the version
+        /// (e.g. env or resources), which the recreation applies as well.
+        /// Without it the upgrade stops before any pod is touched and lists the changes.
+        #[arg(long)]
+        ack_template_changes: bool,
 
-            let token = get_token(token)?;
+        /// Take over the lock of another upgrade of the statefulset.
+        /// Only use this if the other upgrade is no longer running,
+        /// otherwise the lock expires a minute after its holder stopped.
+        #[arg(long)]
+        force_unlock: bool,
 
-            if let Some(path) = keys_secret_uri {
-                let uri = http::Uri::from_str(&path)?;
+        /// Upgrade all `clusters` of `--config` instead of `--statefulset` in `--namespace`.
+        /// Each cluster uses its own flavor and unseal keys, `--report-json` gets a report
+        /// of all clusters.
+        #[arg(long, conflicts_with_all = [
+            "cluster", "plan", "emit_runbook", "revert_on_pull_failure", "rollback_on_failure",
+            "snapshot_before", "keys_secret_uri", "key_cmd", "journal",
+        ])]
+        all: bool,
 
-                let mut client = GetUnsealKeysFromVault::new(&uri)?;
+        /// Upgrade this cluster of the `clusters` of `--config`, can be repeated (see `--all`)
+        #[arg(long, value_name = "NAME", conflicts_with_all = [
+            "plan", "emit_runbook", "revert_on_pull_failure", "rollback_on_failure",
+            "snapshot_before", "keys_secret_uri", "key_cmd", "journal",
+        ])]
+        cluster: Vec<String>,
 
-                let mut k = client
-                    .get_unseal_keys(
-                        uri.path_and_query()
-                            .ok_or(anyhow::anyhow!("keys secret uri is not valid: {}", path))?,
-                        token.clone(),
-                    )
-                    .await?;
+        /// Upgrade the clusters of `--all` or `--cluster` at the same time.
+        /// Otherwise they are upgraded one after another, stopping at the first failure.
+        #[arg(long)]
+        parallel: bool,
 
-                keys.append(&mut k);
-            } else if let Some(cmd) = key_cmd {
-                let mut k = get_unseal_keys(&cmd).await?;
+        #[command(flatten)]
+        takeover: TakeoverArgs,
 
-                if k.is_empty() {
-                    anyhow::bail!("no unseal keys returned from command")
-                }
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
 
-                keys.append(&mut k);
-            } else if !do_not_unseal {
-                anyhow::bail!("no keys secret uri or key cmd specified")
-            }
+        #[command(flatten)]
+        hooks: HookArgs,
 
-            let sts = stss.get(&cli.statefulset).await?;
+        #[command(flatten)]
+        catch_up: RaftCatchUpArgs,
+    },
 
-            StatefulSetApi::from(stss.clone())
-                .upgrade(
-                    sts.clone(),
-                    &PodApi::new(pods.clone(), !cli.no_tls, cli.domain),
-                    token,
-                    !do_not_unseal,
-                    force_upgrade,
-                    &keys,
-                )
+    /// Show what an upgrade would do without changing anything
+    ///
+    /// Prints the pods in the order they would be upgraded, their current and target versions,
+    /// whether they would be skipped and whether they would be unsealed by vault-mgmt or externally.
+    Plan {
+        /// vault token to read the raft configuration, which orders the standby pods
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable if set
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// Plan for an upgrade with `--do-not-unseal`
+        #[arg(short = 'u', long)]
+        do_not_unseal: bool,
+
+        /// Plan for an upgrade with `--force-upgrade`
+        #[arg(short = 'f', long)]
+        force_upgrade: bool,
+
+        /// Plan for an upgrade with `--target-version`
+        #[arg(long)]
+        target_version: Option<String>,
+    },
+
+    /// Set the image of the vault container in the statefulset
+    ///
+    /// Patches the pod template via server-side apply. With the `OnDelete` update strategy
+    /// the pods keep running the old image until they are recreated, e.g. by `upgrade`.
+    #[command(arg_required_else_help = true)]
+    #[command(group(clap::ArgGroup::new("image_source").required(true)))]
+    SetImage {
+        /// Replace only the tag of the current image with this version
+        #[arg(long, group = "image_source")]
+        target_version: Option<String>,
+
+        /// Full image reference to set
+        #[arg(long, group = "image_source")]
+        image: Option<String>,
+    },
+
+    /// Do a rolling restart of the vault pods without downtime
+    ///
+    /// Uses the same order as `upgrade` (standby pods first, the active pod last after stepping down),
+    /// but restarts every pod regardless of its version, e.g. to pick up rotated certificates.
+    #[command(arg_required_else_help = true)]
+    Restart {
+        /// vault token to use for the step down (and retrieving the unseal keys if configured)
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// Do not unseal the pods after restarting.
+        /// If this is specified, the restart process will wait for the pods to be unsealed externally.
+        #[arg(short = 'u', long)]
+        do_not_unseal: bool,
+
+        /// uri to vault kv secret containing the unseal keys.
+        /// for example: `https://vault.example.com/v1/secret/data/vault/unseal-keys`.
+        /// the secret must store the keys separated by newlines in the data field `keys`.
+        #[arg(long)]
+        keys_secret_uri: Option<KeysSecretUri>,
+
+        /// command that writes unseal keys to its stdout.
+        /// each line will be used as a key.
+        /// the command will be executed locally
+        #[arg(long)]
+        key_cmd: Option<String>,
+
+        /// Wait for service mesh sidecars to be ready as well.
+        /// By default, only the vault container has to be ready in pods with a sidecar.
+        #[arg(long)]
+        wait_for_sidecars: bool,
+
+        /// Delete pods through the Eviction API, so PodDisruptionBudgets and other policies
+        /// are honored. A blocked eviction is retried until `--delete-timeout`.
+        #[arg(long)]
+        use_eviction: bool,
+
+        /// Do not publish Kubernetes events (e.g. `PodDeleted`) for the actions on the
+        /// statefulset and its pods
+        #[arg(long)]
+        no_events: bool,
+
+        /// Continue an interrupted restart, skipping the pods it already finished.
+        /// The progress is stored in an annotation of the statefulset.
+        #[arg(long)]
+        resume: bool,
+
+        /// Take over the lock of another upgrade or restart of the statefulset.
+        /// Only use this if the other rollout is no longer running,
+        /// otherwise the lock expires a minute after its holder stopped.
+        #[arg(long)]
+        force_unlock: bool,
+
+        /// Stop before the next pod if the restart cannot finish before this time, given as
+        /// timestamp (`2024-05-01T22:00:00Z`) or as duration from now (`2h`). The remaining
+        /// time is estimated from the pods done so far, continue later with `--resume`.
+        #[arg(long, value_name = "TIMESTAMP_OR_DURATION", value_parser = parse_deadline)]
+        deadline: Option<std::time::SystemTime>,
+
+        /// Append every action to this local journal before and after it is executed, see `upgrade`
+        #[arg(long, value_name = "PATH")]
+        journal: Option<std::path::PathBuf>,
+
+        #[command(flatten)]
+        takeover: TakeoverArgs,
+
+        #[command(flatten)]
+        timeouts: TimeoutArgs,
+
+        #[command(flatten)]
+        hooks: HookArgs,
+
+        #[command(flatten)]
+        catch_up: RaftCatchUpArgs,
+    },
+
+    /// Print a journal written by `upgrade --journal` or `restart --journal`,
+    /// followed by the actions that were in progress when it stopped
+    Journal {
+        /// path of the journal
+        path: std::path::PathBuf,
+    },
+
+    /// Scale the vault cluster to the given number of replicas
+    ///
+    /// On scale-up, the new pods are joined to the raft cluster and unsealed.
+    /// On scale-down, the pods to be removed are stepped down if active and removed from the raft configuration
+    /// before the replicas of the statefulset are reduced.
+    #[command(arg_required_else_help = true)]
+    Scale {
+        /// number of replicas to scale to
+        #[arg(short = 'r', long)]
+        replicas: i32,
+
+        /// vault token to use for the step down and removing raft peers (and retrieving the unseal keys if configured)
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// uri to vault kv secret containing the unseal keys.
+        /// for example: `https://vault.example.com/v1/secret/data/vault/unseal-keys`.
+        /// the secret must store the keys separated by newlines in the data field `keys`.
+        #[arg(long)]
+        keys_secret_uri: Option<KeysSecretUri>,
+
+        /// command that writes unseal keys to its stdout.
+        /// each line will be used as a key.
+        /// the command will be executed locally
+        #[arg(long)]
+        key_cmd: Option<String>,
+
+        #[command(flatten)]
+        join: RaftJoinArgs,
+
+        /// how long to wait for a new leader after stepping down an active pod to be removed,
+        /// e.g. `30s` or `2m`
+        #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+        timeout: std::time::Duration,
+
+        /// scale down even if fewer voters than the quorum of the current voters remain
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Clone the data of a cluster into another, deployed but uninitialized cluster
+    /// using a raft snapshot, e.g. for a staging environment.
+    /// Afterwards the target uses the unseal keys and tokens of the source.
+    Clone {
+        /// source statefulset as `namespace/name`
+        #[arg(long)]
+        from: StatefulSetRef,
+
+        /// target statefulset as `namespace/name`
+        #[arg(long)]
+        to: StatefulSetRef,
+
+        /// vault token of the source to take the snapshot (and retrieving the unseal keys if configured)
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// uri to vault kv secret containing the unseal keys of the source.
+        /// for example: `https://vault.example.com/v1/secret/data/vault/unseal-keys`.
+        /// the secret must store the keys separated by newlines in the data field `keys`.
+        #[arg(long)]
+        keys_secret_uri: Option<KeysSecretUri>,
+
+        /// command that writes unseal keys of the source to its stdout.
+        /// each line will be used as a key.
+        /// the command will be executed locally
+        #[arg(long)]
+        key_cmd: Option<String>,
+    },
+
+    /// Generate autocompletion scripts for your shell
+    #[command(arg_required_else_help = true)]
+    Completion {
+        /// Shell to generate the autocompletion script for
+        shell: Shell,
+    },
+
+    /// Update the vault-mgmt binary to the latest version
+    SelfUpdate {},
+
+    /// Run the plugin `vault-mgmt-<name>` found on PATH with the remaining arguments,
+    /// passing the namespace, statefulset and other settings in `VAULT_MGMT_*` variables
+    #[command(external_subcommand)]
+    Plugin(Vec<OsString>),
+}
+
+/// Parameters for joining new pods to the raft cluster
+#[derive(clap::Args, Debug)]
+struct RaftJoinArgs {
+    /// address of the leader to join, defaults to the current leader
+    #[arg(long)]
+    leader_api_addr: Option<String>,
+
+    /// path to a PEM encoded CA certificate to verify the leader's TLS certificate
+    #[arg(long)]
+    leader_ca_cert: Option<std::path::PathBuf>,
+
+    /// path to a PEM encoded client certificate for TLS client authentication against the leader
+    #[arg(long, requires = "leader_client_key")]
+    leader_client_cert: Option<std::path::PathBuf>,
+
+    /// path to the PEM encoded private key of the client certificate
+    #[arg(long, requires = "leader_client_cert")]
+    leader_client_key: Option<std::path::PathBuf>,
+
+    /// let vault keep retrying to join in the background
+    #[arg(long)]
+    retry_join: bool,
+
+    /// go-discover configuration used by vault to find the leader,
+    /// for example: `provider=k8s label_selector="app.kubernetes.io/name=vault"`
+    #[arg(long, conflicts_with = "leader_api_addr")]
+    auto_join: Option<String>,
+
+    /// URI scheme used for addresses found by `--auto-join`
+    #[arg(long, requires = "auto_join")]
+    auto_join_scheme: Option<String>,
+
+    /// port used for addresses found by `--auto-join`
+    #[arg(long, requires = "auto_join")]
+    auto_join_port: Option<u16>,
+}
+
+impl RaftJoinArgs {
+    async fn into_request(self) -> anyhow::Result<RaftJoinRequest> {
+        async fn read(path: Option<std::path::PathBuf>) -> anyhow::Result<Option<String>> {
+            Ok(match path {
+                Some(path) => Some(tokio::fs::read_to_string(&path).await.map_err(|e| {
+                    anyhow::anyhow!("reading {}: {}", path.display(), e.to_string())
+                })?),
+                None => None,
+            })
+        }
+
+        Ok(RaftJoinRequest {
+            leader_api_addr: self.leader_api_addr,
+            leader_ca_cert: read(self.leader_ca_cert).await?,
+            leader_client_cert: read(self.leader_client_cert).await?,
+            leader_client_key: read(self.leader_client_key).await?.map(Secret::from),
+            retry: self.retry_join,
+            auto_join: self.auto_join,
+            auto_join_scheme: self.auto_join_scheme,
+            auto_join_port: self.auto_join_port,
+        })
+    }
+}
+
+/// Parameters for handing over leadership from the active pod
+#[derive(clap::Args, Clone, Debug)]
+struct TakeoverArgs {
+    /// how to upgrade or restart the active pod
+    #[arg(long, default_value_t = ActiveStrategy::StepDownFirst, value_enum)]
+    active_strategy: ActiveStrategy,
+
+    /// how to determine that another pod took over after the step-down
+    #[arg(long, default_value_t = TakeoverCondition::Label, value_enum)]
+    takeover: TakeoverCondition,
+
+    /// do not wait for the standby label after the step-down, but for `--takeover-delay`.
+    /// useful for charts without service registration
+    #[arg(long, conflicts_with = "takeover")]
+    skip_standby_wait: bool,
+
+    /// delay to wait for with `--takeover delay` or `--skip-standby-wait`
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    takeover_delay: std::time::Duration,
+
+    /// time to wait for the takeover before stepping down again
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration)]
+    takeover_timeout: std::time::Duration,
+
+    /// how often to step down again if leadership did not move
+    #[arg(long, default_value_t = 2)]
+    step_down_retries: usize,
+
+    /// keep a new leader that is sealed, not ready or lagging behind instead of stepping it down
+    #[arg(long)]
+    allow_unhealthy_leader: bool,
+
+    /// raft entries the new leader may not have applied yet before it is stepped down
+    #[arg(long, default_value_t = 1000)]
+    max_leader_lag: u64,
+
+    /// after the takeover, wait until the endpoints of this service (e.g. vault-active)
+    /// point at the new leader, so clients are not routed to the pod being deleted
+    #[arg(long)]
+    active_service: Option<String>,
+}
+
+impl TakeoverArgs {
+    fn into_takeover(self) -> Takeover {
+        Takeover {
+            condition: match self.skip_standby_wait {
+                true => TakeoverCondition::Delay,
+                false => self.takeover,
+            },
+            delay: self.takeover_delay,
+            timeout: self.takeover_timeout,
+            retries: self.step_down_retries,
+            healthy_leader: !self.allow_unhealthy_leader,
+            max_leader_lag: self.max_leader_lag,
+        }
+    }
+}
+
+/// Timeouts of the waits during an upgrade or restart of a pod
+#[derive(clap::Args, Clone, Debug)]
+struct TimeoutArgs {
+    /// time to wait for the step-down request to the active pod
+    #[arg(long, default_value = "1m", value_parser = humantime::parse_duration)]
+    stepdown_timeout: std::time::Duration,
+
+    /// time to wait for a pod to be deleted
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    delete_timeout: std::time::Duration,
+
+    /// time to wait for a recreated pod to be running
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    running_timeout: std::time::Duration,
+
+    /// time to wait for a pod to be unsealed, also when waiting for an external unseal
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    unseal_timeout: std::time::Duration,
+
+    /// time to wait for a pod to be unsealed externally with `--do-not-unseal`,
+    /// defaults to `--unseal-timeout`
+    #[arg(long, value_parser = humantime::parse_duration)]
+    external_unseal_timeout: Option<std::time::Duration>,
+
+    /// time to wait for a pod to be ready after it was unsealed
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    pod_ready_timeout: std::time::Duration,
+}
+
+impl TimeoutArgs {
+    fn into_options(self) -> UpgradeOptions {
+        let options = UpgradeOptions::default()
+            .stepdown_timeout(self.stepdown_timeout)
+            .delete_timeout(self.delete_timeout)
+            .running_timeout(self.running_timeout)
+            .unseal_timeout(self.unseal_timeout)
+            .pod_ready_timeout(self.pod_ready_timeout);
+
+        match self.external_unseal_timeout {
+            Some(timeout) => options.external_unseal_timeout(timeout),
+            None => options,
+        }
+    }
+}
+
+/// Commands run for each pod during an upgrade or restart
+#[derive(clap::Args, Clone, Debug)]
+struct HookArgs {
+    /// shell command to run before each pod is stepped down and deleted, e.g. to drain a load balancer.
+    /// Locally, the name and namespace of the pod are passed in VAULT_MGMT_POD and VAULT_MGMT_NAMESPACE.
+    /// The upgrade stops if the command exits non-zero.
+    #[arg(long)]
+    pre_pod_hook: Option<String>,
+
+    /// shell command to run after each recreated pod was unsealed and is ready
+    #[arg(long)]
+    post_pod_hook: Option<String>,
+
+    /// run the hooks in the vault container of the pod instead of locally
+    #[arg(long)]
+    hooks_in_pod: bool,
+
+    /// shell command that has to exit zero after each pod before the next pod is touched,
+    /// e.g. checking error rates in a monitoring system. It runs locally with the same
+    /// variables as the hooks and is retried until `--gate-timeout`. Can be given multiple times.
+    #[arg(long)]
+    gate_cmd: Vec<String>,
+
+    /// time to wait for the gate commands to pass
+    #[arg(long, default_value = "10m", value_parser = humantime::parse_duration)]
+    gate_timeout: std::time::Duration,
+
+    #[command(flatten)]
+    leader: LeaderHookArgs,
+}
+
+impl HookArgs {
+    fn apply(
+        self,
+        options: UpgradeOptions,
+        pods: &Api<Pod>,
+        container: Option<String>,
+    ) -> UpgradeOptions {
+        let hook = |cmd: &str| match self.hooks_in_pod {
+            true => PodHook::in_pod(pods.clone(), container.clone(), cmd),
+            false => PodHook::local(cmd),
+        };
+
+        let mut options = options;
+        if let Some(cmd) = &self.pre_pod_hook {
+            options = options.pre_pod_hook(hook(cmd));
+        }
+        if let Some(cmd) = &self.post_pod_hook {
+            options = options.post_pod_hook(hook(cmd));
+        }
+        for cmd in &self.gate_cmd {
+            options = options.gate(HealthGate::local(cmd));
+        }
+        if let Some(cmd) = &self.leader.leader_hook {
+            options = options.leader_hook(hook(cmd));
+        }
+
+        self.leader.apply(options).gate_timeout(self.gate_timeout)
+    }
+}
+
+/// Keeping load balancers outside of kubernetes pointed at the leader after a step-down
+#[derive(clap::Args, Clone, Debug)]
+struct LeaderHookArgs {
+    /// shell command to run with the new active pod after a step-down, e.g. to update the
+    /// health-check targets of an external load balancer. It gets the same variables as the hooks.
+    #[arg(long)]
+    leader_hook: Option<String>,
+
+    /// url to POST the name and namespace of the new active pod to after a step-down,
+    /// as json `{"pod": ..., "namespace": ...}`
+    #[arg(long, conflicts_with = "leader_hook")]
+    leader_hook_url: Option<http::Uri>,
+
+    /// shell command that has to exit zero for the new active pod after a step-down,
+    /// e.g. until an external load balancer reports it healthy. Can be given multiple times.
+    #[arg(long)]
+    leader_gate_cmd: Vec<String>,
+
+    /// url that has to respond with a success to a GET after a step-down. Can be given multiple times.
+    #[arg(long)]
+    leader_gate_url: Vec<http::Uri>,
+
+    /// time to wait for each hook, including the leader hook, before failing
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    hook_timeout: std::time::Duration,
+}
+
+impl LeaderHookArgs {
+    /// Add the url hook and the gates, the command hook depends on where hooks run
+    fn apply(&self, options: UpgradeOptions) -> UpgradeOptions {
+        let mut options = options.hook_timeout(self.hook_timeout);
+        if let Some(uri) = &self.leader_hook_url {
+            options = options.leader_hook(PodHook::url(uri.clone()));
+        }
+        for cmd in &self.leader_gate_cmd {
+            options = options.leader_gate(HealthGate::local(cmd));
+        }
+        for uri in &self.leader_gate_url {
+            options = options.leader_gate(HealthGate::url(uri.clone()));
+        }
+
+        options
+    }
+}
+
+/// Waiting for upgraded or restarted standby pods to catch up with the raft log of the leader
+#[derive(clap::Args, Clone, Debug)]
+struct RaftCatchUpArgs {
+    /// number of raft entries a standby pod may lag behind the index the leader committed,
+    /// before the next pod is upgraded or restarted
+    #[arg(long, default_value_t = 0)]
+    max_raft_lag: u64,
+
+    /// do not wait for standby pods to catch up with the leader
+    #[arg(long, conflicts_with = "max_raft_lag")]
+    skip_raft_catch_up: bool,
+
+    /// time to wait for a standby pod to catch up with the leader
+    #[arg(long, default_value = "5m", value_parser = humantime::parse_duration)]
+    raft_catch_up_timeout: std::time::Duration,
+}
+
+impl RaftCatchUpArgs {
+    fn apply(self, options: ClusterUpgradeOptions) -> ClusterUpgradeOptions {
+        options
+            .max_raft_lag(match self.skip_raft_catch_up {
+                true => None,
+                false => Some(self.max_raft_lag),
+            })
+            .raft_catch_up_timeout(self.raft_catch_up_timeout)
+    }
+}
+
+#[derive(Subcommand, Debug)]
+#[command(arg_required_else_help = true)]
+enum RaftCommands {
+    /// Show the servers of the raft configuration
+    Configuration {
+        /// vault token to use for reading the raft configuration
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// pod to query, defaults to the active pod
+        #[arg(short = 'p', long)]
+        pod: Option<String>,
+
+        /// wait until the raft configuration satisfies the condition before printing it
+        #[arg(short = 'w', long, value_enum)]
+        wait_for: Option<RaftWaitFor>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+#[command(arg_required_else_help = true)]
+enum ConfigCommands {
+    /// Validate the config file and check that the statefulsets of its clusters exist and
+    /// the vaults storing their unseal keys are reachable, without reading the keys.
+    /// Fails if any check reports an error.
+    Validate {
+        /// config file to validate, `--config` if not given
+        path: Option<std::path::PathBuf>,
+
+        /// Only check the file itself, without connecting to kubernetes or the key vaults
+        #[arg(long)]
+        offline: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuditCommands {
+    /// Show the enabled audit devices
+    List {
+        /// vault token to use for listing the audit devices
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+    },
+
+    /// Enable an audit device
+    #[command(arg_required_else_help = true)]
+    Enable {
+        /// vault token to use for enabling the audit device
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// type of the audit device, e.g. `file`, `syslog` or `socket`
+        #[arg(value_name = "TYPE")]
+        type_: String,
+
+        /// path of the file for the `file` audit device (sets the `file_path` option)
+        #[arg(long)]
+        path: Option<String>,
+
+        /// path to enable the audit device at, defaults to the type
+        #[arg(long)]
+        mount_path: Option<String>,
+
+        /// description of the audit device
+        #[arg(long, default_value = "")]
+        description: String,
+
+        /// only enable the audit device on this cluster, not on replicated clusters
+        #[arg(long)]
+        local: bool,
+
+        /// further options of the audit device as key=value pairs
+        #[arg(short = 'o', long = "option")]
+        options: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+#[command(arg_required_else_help = true)]
+enum AutopilotCommands {
+    /// Show the health of the servers as seen by autopilot
+    State {
+        /// vault token to use for reading the autopilot state
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// pod to query, defaults to the active pod
+        #[arg(short = 'p', long)]
+        pod: Option<String>,
+    },
+
+    /// Show the autopilot configuration
+    Configuration {
+        /// vault token to use for reading the autopilot configuration
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+    },
+
+    /// Change values of the autopilot configuration, other values are left unchanged
+    Set {
+        /// vault token to use for changing the autopilot configuration
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// remove dead servers from the raft configuration
+        #[arg(long)]
+        cleanup_dead_servers: Option<bool>,
+
+        /// time after which a server without contact to the leader is considered dead
+        #[arg(long, value_parser = humantime::parse_duration)]
+        dead_server_last_contact_threshold: Option<std::time::Duration>,
+
+        /// time after which a server without contact to the leader is considered unhealthy
+        #[arg(long, value_parser = humantime::parse_duration)]
+        last_contact_threshold: Option<std::time::Duration>,
+
+        /// number of log entries a server may lag behind the leader to be considered healthy
+        #[arg(long)]
+        max_trailing_logs: Option<u64>,
+
+        /// minimum number of voters before dead servers are cleaned up
+        #[arg(long)]
+        min_quorum: Option<u32>,
+
+        /// time a new server has to be healthy before it is promoted to voter
+        #[arg(long, value_parser = humantime::parse_duration)]
+        server_stabilization_time: Option<std::time::Duration>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+#[command(arg_required_else_help = true)]
+enum TokenCommands {
+    /// Show the TTL and policies of the token
+    #[command(name = "lookup-self")]
+    Lookup {
+        /// vault token to look up
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+    },
+
+    /// Renew the token
+    #[command(name = "renew-self")]
+    Renew {
+        /// vault token to renew
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+
+        /// TTL to request for the token, defaults to the TTL of its role or auth method
+        #[arg(short = 'i', long, value_parser = humantime::parse_duration)]
+        increment: Option<std::time::Duration>,
+    },
+
+    /// Revoke the token, e.g. after an upgrade
+    #[command(name = "revoke-self")]
+    Revoke {
+        /// vault token to revoke
+        /// if not provided, the token will be read from the VAULT_TOKEN environment variable
+        #[arg(short = 't', long)]
+        token: Option<Secret<String>>,
+    },
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum RaftWaitFor {
+    /// any server is the leader
+    Leader,
+    /// all servers are voters
+    AllVoters,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .unwrap();
+
+    let env_filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("vault_mgmt={}", cli.log_level)));
+    tracing::subscriber::set_global_default(
+        Registry::default()
+            .with(env_filter)
+            .with(tracing_subscriber::fmt::layer()),
+    )?;
+
+    // an invalid config is reported by `config validate` instead
+    let tuning = match (&cli.config, matches!(cli.command, Commands::Config { .. })) {
+        (Some(path), false) => {
+            let tuning = ConfigFile::from_file(path)?.kube;
+            tuning.validate()?;
+            tuning
+        }
+        _ => KubeTuning::default(),
+    };
+
+    let quit_mesh_sidecar = cli.quit_mesh_sidecar;
+
+    let label_sync = match cli.label_sync {
+        true => {
+            let pods = PodApi::new(
+                setup_api(&cli.namespace).await?,
+                !cli.no_tls,
+                cli.domain.clone(),
+            )
+            .tuning(tuning.clone())
+            .transport(cli.transport)
+            .selector(cli.pod_selector());
+            pods.sync_all_labels().await?;
+
+            Some(tokio::spawn(async move {
+                pods.sync_labels_every(LABEL_SYNC_INTERVAL).await
+            }))
+        }
+        false => None,
+    };
+
+    let result = run(cli, tuning).await;
+
+    if let Some(label_sync) = label_sync {
+        label_sync.abort();
+    }
+
+    // a failing quit is no reason to fail the command or to hide its error
+    if let Some(mesh) = quit_mesh_sidecar {
+        let (port, _) = mesh.quit_endpoint();
+
+        let quit = async {
+            HttpForwarderService::http(tokio::net::TcpStream::connect(("127.0.0.1", port)).await?)
+                .await?
+                .quit_sidecar(mesh)
+                .await
+        };
+        if let Err(e) = quit.await {
+            tracing::warn!("quitting the mesh sidecar: {:#}", e);
+        }
+    }
+
+    result
+}
+
+/// Parse a `key=value` label
+fn parse_label(s: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or(anyhow::anyhow!("expected a KEY=VALUE label, got {}", s))?;
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Parse a deadline given as RFC 3339 timestamp or as duration from now
+fn parse_deadline(s: &str) -> anyhow::Result<std::time::SystemTime> {
+    if let Ok(deadline) = humantime::parse_rfc3339_weak(s) {
+        return Ok(deadline);
+    }
+
+    let duration = humantime::parse_duration(s)
+        .map_err(|_| anyhow::anyhow!("expected a timestamp or a duration, got {}", s))?;
+
+    Ok(std::time::SystemTime::now() + duration)
+}
+
+/// How `--keys-file` is encrypted
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum KeysDecrypt {
+    Age,
+    Gpg,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+enum FlavorArg {
+    Vault,
+    Openbao,
+    All,
+}
+
+impl std::fmt::Display for FlavorArg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl Cli {
+    /// Flavor of the managed pods, `all` is treated as vault
+    fn flavor(&self) -> Flavor {
+        match self.flavor {
+            FlavorArg::Vault | FlavorArg::All => Flavor::Vault,
+            FlavorArg::Openbao => Flavor::Openbao,
+        }
+    }
+
+    /// Label selector for the vault pods from the global options
+    /// With `--flavor all` the selector is used for each flavor in turn
+    fn pod_selector(&self) -> PodSelector {
+        self.pod_label.iter().fold(
+            PodSelector::default()
+                .flavor(self.flavor())
+                .instance(self.instance.clone())
+                .container(self.container_name.clone()),
+            |selector, (key, value)| selector.label(key, value),
+        )
+    }
+
+    /// Key source of `--keys-from`, `--keys-stdin`, `--keys-fd` or `--keys-file`
+    fn keys_from(&self) -> anyhow::Result<Option<KeysFrom>> {
+        if self.keys_stdin {
+            return Ok(Some(KeysFrom::new("fd", "0")));
+        }
+        if let Some(fd) = self.keys_fd {
+            return Ok(Some(KeysFrom::new("fd", &fd.to_string())));
+        }
+        let Some(path) = &self.keys_file else {
+            return Ok(self.keys_from.clone());
+        };
+        let path = path.display();
+
+        Ok(Some(match self.keys_decrypt {
+            None => KeysFrom::new("file", &path.to_string()),
+            Some(KeysDecrypt::Gpg) => KeysFrom::new("gpg", &path.to_string()),
+            Some(KeysDecrypt::Age) => {
+                let identity = self.age_identity.as_ref().ok_or(anyhow::anyhow!(
+                    "--keys-decrypt age needs the identity file of --age-identity"
+                ))?;
+                KeysFrom::new("age", &format!("{}?identity={}", path, identity.display()))
+            }
+        }))
+    }
+
+    /// Vault key provider with the TLS settings of `--keys-ca-cert`, `--keys-tls-skip-verify`
+    /// and `--keys-client-cert`
+    fn keys_vault(&self) -> VaultKeyProvider {
+        let mut tls = TlsOptions::default().insecure(self.keys_tls_skip_verify);
+        if let Some(ca_cert) = &self.keys_ca_cert {
+            tls = tls.ca_file(ca_cert.clone());
+        }
+        if let (Some(cert), Some(key)) = (&self.keys_client_cert, &self.keys_client_key) {
+            tls = tls.client_cert(cert.clone(), key.clone());
+        }
+
+        VaultKeyProvider::default().tls(tls)
+    }
+
+    /// Key providers, reading vault secrets with the TLS settings of `keys_vault`
+    fn key_providers(&self) -> KeyProviders {
+        KeyProviders::default()
+            .register("vault+https", self.keys_vault())
+            .register("vault+http", self.keys_vault())
+    }
+
+    /// Kubernetes auth method to get a token for `--keys-secret-uri`, if `--keys-auth-role` is set
+    fn keys_auth(&self) -> Option<KubernetesAuth> {
+        self.keys_auth_role.as_ref().map(|role| {
+            KubernetesAuth::new(role)
+                .mount(&self.keys_auth_mount)
+                .jwt_path(self.keys_auth_jwt_path.clone())
+        })
+    }
+}
+
+async fn run(cli: Cli, tuning: KubeTuning) -> anyhow::Result<()> {
+    let selector = cli.pod_selector();
+    let read_your_writes = cli.read_your_writes;
+    let keys_auth = cli.keys_auth();
+    let keys_vault = cli.keys_vault();
+    let key_providers = cli.key_providers();
+    let keys_from = cli.keys_from()?;
+    let keys_secret_field = cli.keys_secret_field.clone();
+    let flavor = cli.flavor();
+
+    let all_flavors = cli.flavor == FlavorArg::All;
+    if all_flavors
+        && !matches!(
+            cli.command,
+            Commands::Show { .. } | Commands::SealStatus { .. }
+        )
+    {
+        anyhow::bail!("--flavor all is only supported by show and seal-status");
+    }
+
+    match cli.command {
+        Commands::Plugin(args) => {
+            let (name, args) = args
+                .split_first()
+                .ok_or(anyhow::anyhow!("plugin name is missing"))?;
+            let path = find_plugin(&name.to_string_lossy())?;
+
+            let context = PluginContext {
+                namespace: cli.namespace,
+                statefulset: cli.statefulset,
+                domain: cli.domain,
+                tls: !cli.no_tls,
+                flavor,
+                selector,
+                transport: cli.transport,
+                log_level: cli.log_level.to_string().to_lowercase(),
+                config: cli.config,
+            };
+
+            let status = run_plugin(&path, args, &context).await?;
+            if !status.success() {
+                std::process::exit(status.code().unwrap_or(1));
+            }
+        }
+        Commands::Completion { shell } => {
+            let mut cmd = Cli::command();
+            let name = cmd.get_name().to_string();
+
+            generate(shell, &mut cmd, name, &mut io::stdout());
+        }
+        Commands::Show { token } if all_flavors => {
+            let api = setup_api(&cli.namespace).await?;
+            let pods = PodApi::new(api.clone(), !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .transport(cli.transport);
+            let token = get_token(token).ok();
+
+            for (flavor, group) in list_pods_by_flavor(&api, &selector).await? {
+                // the raft configuration is optional, the labels are shown without it
+                let (raft, autopilot) =
+                    match (&token, group.iter().find(|p| is_active(p).unwrap_or(false))) {
+                        (Some(token), Some(active)) => {
+                            raft_membership(
+                                &pods,
+                                active
+                                    .metadata
+                                    .name
+                                    .as_deref()
+                                    .ok_or(anyhow::anyhow!("pod does not have a name"))?,
+                                token.clone(),
+                            )
+                            .await
+                        }
+                        _ => (None, None),
+                    };
+
+                println!("{}:", flavor);
+                construct_pods_table(&group, raft.as_ref(), autopilot.as_ref(), cli.time_format)?
+                    .printstd();
+            }
+        }
+        Commands::Show { token } => {
+            let api = setup_api(&cli.namespace).await?;
+
+            // the raft configuration is optional, the labels are shown without it
+            let (raft, autopilot) = match get_token(token) {
+                Ok(token) => {
+                    let pods = PodApi::new(api.clone(), !cli.no_tls, cli.domain)
+                        .tuning(tuning.clone())
+                        .read_your_writes(read_your_writes)
+                        .selector(selector.clone())
+                        .transport(cli.transport);
+
+                    match get_active_pod_name(&api, &selector).await {
+                        Ok(active) => raft_membership(&pods, &active, token).await,
+                        Err(e) => {
+                            tracing::warn!("reading raft configuration: {}", e);
+                            (None, None)
+                        }
+                    }
+                }
+                Err(_) => (None, None),
+            };
+
+            let table = construct_table(
+                &api,
+                &selector,
+                raft.as_ref(),
+                autopilot.as_ref(),
+                cli.time_format,
+            )
+            .await?;
+
+            table.printstd();
+        }
+        Commands::SealStatus { pod } if all_flavors => {
+            let api = setup_api(&cli.namespace).await?;
+            let pods = PodApi::new(api.clone(), !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .transport(cli.transport);
+
+            let mut found = false;
+            for (flavor, group) in list_pods_by_flavor(&api, &selector).await? {
+                let names = group
+                    .into_iter()
+                    .filter_map(|p| p.metadata.name)
+                    .filter(|name| pod.is_none() || pod.as_ref() == Some(name))
+                    .collect::<Vec<_>>();
+
+                if names.is_empty() {
+                    continue;
+                }
+                found = true;
+
+                println!("{}:", flavor);
+                construct_seal_status_table_of(&pods, &names, cli.time_format)
+                    .await?
+                    .printstd();
+            }
+
+            if let (Some(pod), false) = (pod, found) {
+                anyhow::bail!("no vault pod named {} found", pod);
+            }
+        }
+        Commands::SealStatus { pod } => {
+            let api = setup_api(&cli.namespace).await?;
+            let table = construct_seal_status_table(
+                &PodApi::new(api, !cli.no_tls, cli.domain)
+                    .tuning(tuning.clone())
+                    .read_your_writes(read_your_writes)
+                    .selector(selector.clone())
+                    .transport(cli.transport),
+                pod.as_deref(),
+                cli.time_format,
+            )
+            .await?;
+
+            table.printstd();
+        }
+        Commands::Doctor {} => {
+            let pods: Api<Pod> = setup_api(&cli.namespace).await?;
+            let pods = pods.list(&selector.to_list_params()).await?.items;
+
+            let policies: Api<NetworkPolicy> = setup_api(&cli.namespace).await?;
+            let mut findings = diagnose_network_policies(&policies, &pods).await?;
+
+            let config_maps: Api<ConfigMap> = setup_api(&cli.namespace).await?;
+            findings.append(
+                &mut diagnose_retry_join(&config_maps, &pods, selector.container.as_deref())
+                    .await?,
+            );
+
+            findings.append(
+                &mut diagnose_resources(
+                    Client::try_default().await?,
+                    &cli.namespace,
+                    &pods,
+                    selector.container.as_deref(),
+                )
+                .await?,
+            );
+
+            construct_doctor_table(&findings).printstd();
+
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                anyhow::bail!("doctor found errors");
+            }
+        }
+        Commands::Exec {
+            cmd,
+            exec_in,
+            env,
+            env_keys,
+            print_target,
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+
+            if print_target {
+                // the command runs in the first matching pod
+                let pods = api
+                    .list(&selector.clone().role(exec_in).to_list_params())
+                    .await?;
+                return print_targets(&pods.items[..pods.items.len().min(1)]);
+            }
+
+            let env = collect_env(env, env_keys)?;
+            exec_with(&api, cmd.join(" "), &selector.clone().role(exec_in), env).await?;
+        }
+        Commands::Logs {
+            pod,
+            all,
+            logs_in,
+            follow,
+            tail,
+            highlight,
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+
+            let of = match (pod, all) {
+                (Some(pod), _) => LogsOf::Pod(pod),
+                (None, true) => LogsOf::All,
+                (None, false) => LogsOf::Selected(logs_in),
+            };
+
+            logs(&api, &selector, of, follow, tail, highlight).await?;
+        }
+        Commands::PortForward {
+            address,
+            local_port,
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+            let pods = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+
+            let listener =
+                tokio::net::TcpListener::bind((unbracketed_host(&address), local_port)).await?;
+            println!(
+                "forwarding {} to the active vault pod",
+                listener.local_addr()?
+            );
+
+            forward_to_active(pods, listener).await?;
+        }
+        Commands::Proxy {
+            address,
+            local_port,
+            retries,
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+            let pods = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+
+            let listener =
+                tokio::net::TcpListener::bind((unbracketed_host(&address), local_port)).await?;
+            println!(
+                "proxying http://{} to the active vault pod",
+                listener.local_addr()?
+            );
+
+            Proxy::new(pods).retries(retries).serve(listener).await?;
+        }
+        Commands::Metrics { token, pod, serve } => {
+            let api = setup_api(&cli.namespace).await?;
+            let pods = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+
+            // metrics might be readable without a token
+            let token = get_token(token).ok();
+
+            match serve {
+                Some(address) => {
+                    let listener = tokio::net::TcpListener::bind(address).await?;
+                    println!(
+                        "serving metrics on http://{}/metrics",
+                        listener.local_addr()?
+                    );
+
+                    serve_metrics(pods, token, listener).await?;
+                }
+                None => print!("{}", pods.scrape_metrics(pod.as_deref(), token).await?),
+            }
+        }
+        Commands::StepDown {
+            token,
+            wait,
+            timeout,
+            print_target,
+            leader,
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+
+            if print_target {
+                let pods = api
+                    .list(&selector.clone().role(ExecIn::Active).to_list_params())
+                    .await?;
+                return print_targets(&pods.items[..pods.items.len().min(1)]);
+            }
+
+            let active = get_active_pod_name(&api, &selector).await?;
+
+            let pods = PodApi::new(api.clone(), !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+            let mut pf = pods.http(&active, VAULT_PORT).await?;
+
+            let token = get_token(token)?;
+            attribute_token(&mut pf, token.clone()).await;
+
+            if wait {
+                tokio::time::timeout(timeout, pf.step_down_and_await_new_leader(token))
+                    .await
+                    .map_err(|_| {
+                        anyhow::anyhow!(
+                            "no new leader was elected within {}",
+                            humantime::format_duration(timeout)
+                        )
+                    })??;
+            } else {
+                pf.step_down(token).await?;
+            }
+
+            let mut options = leader.apply(UpgradeOptions::default());
+            if let Some(cmd) = &leader.leader_hook {
+                options = options.leader_hook(PodHook::local(cmd));
+            }
+            after_leader_change(&pods, &active, &options).await?;
+        }
+        Commands::Config {
+            command: ConfigCommands::Validate { path, offline },
+        } => {
+            let path = path.or(cli.config).ok_or(anyhow::anyhow!(
+                "no config file given, pass it or use --config"
+            ))?;
+
+            let findings = match ConfigFile::from_file(&path) {
+                Ok(config) => {
+                    let mut findings = vec![Finding::new(
+                        "config",
+                        Severity::Ok,
+                        format!("{} has {} clusters", path.display(), config.clusters.len()),
+                    )];
+                    findings.extend(check_config(&config));
+                    if !offline {
+                        findings.extend(
+                            check_config_clusters(
+                                Client::try_default().await?,
+                                &config,
+                                &key_providers,
+                            )
+                            .await,
+                        );
+                    }
+                    findings
+                }
+                Err(e) => vec![Finding::new("config", Severity::Error, e.to_string())],
+            };
+
+            construct_doctor_table(&findings).printstd();
+
+            if findings.iter().any(|f| f.severity == Severity::Error) {
+                anyhow::bail!("config file {} is not valid", path.display());
+            }
+        }
+        Commands::Audit { command } => {
+            let api = setup_api(&cli.namespace).await?;
+            let active = get_active_pod_name(&api, &selector).await?;
+
+            let mut pf = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .http(&active, VAULT_PORT)
+                .await?;
+
+            match command {
+                AuditCommands::List { token } => {
+                    let devices = pf.audit_devices(get_token(token)?).await?;
+
+                    construct_audit_table(&devices).printstd();
+                }
+                AuditCommands::Enable {
+                    token,
+                    type_,
+                    path,
+                    mount_path,
+                    description,
+                    local,
+                    options,
+                } => {
+                    let mut device_options = BTreeMap::new();
+                    for option in options {
+                        let (k, v) = option
+                            .split_once('=')
+                            .ok_or(anyhow::anyhow!("option {} is not a key=value pair", option))?;
+                        device_options.insert(k.to_string(), v.to_string());
+                    }
+                    if let Some(path) = path {
+                        device_options.insert("file_path".to_string(), path);
+                    }
+
+                    let mount_path = mount_path.unwrap_or(type_.clone());
+
+                    let token = get_token(token)?;
+                    attribute_token(&mut pf, token.clone()).await;
+                    pf.enable_audit_device(
+                        token,
+                        &mount_path,
+                        &AuditDevice {
+                            type_,
+                            description,
+                            options: Some(device_options),
+                            local,
+                            path: mount_path.clone(),
+                        },
+                    )
+                    .await?;
+
+                    println!("enabled audit device at {}", mount_path);
+                }
+            }
+        }
+        Commands::Autopilot { command } => {
+            let api = setup_api(&cli.namespace).await?;
+
+            let pod = match &command {
+                AutopilotCommands::State { pod: Some(pod), .. } => pod.clone(),
+                _ => get_active_pod_name(&api, &selector).await?,
+            };
+
+            let mut pf = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .http(&pod, VAULT_PORT)
+                .await?;
+
+            match command {
+                AutopilotCommands::State { token, .. } => {
+                    let state = pf.autopilot_state(get_token(token)?).await?;
+
+                    println!(
+                        "healthy: {}, failure tolerance: {}, leader: {}",
+                        state.data.healthy, state.data.failure_tolerance, state.data.leader
+                    );
+                    construct_autopilot_state_table(&state, cli.time_format).printstd();
+                }
+                AutopilotCommands::Configuration { token } => {
+                    let config = pf.autopilot_configuration(get_token(token)?).await?;
+
+                    construct_autopilot_configuration_table(&config).printstd();
+                }
+                AutopilotCommands::Set {
+                    token,
+                    cleanup_dead_servers,
+                    dead_server_last_contact_threshold,
+                    last_contact_threshold,
+                    max_trailing_logs,
+                    min_quorum,
+                    server_stabilization_time,
+                } => {
+                    let update = AutopilotConfigurationUpdate {
+                        cleanup_dead_servers,
+                        last_contact_threshold,
+                        dead_server_last_contact_threshold,
+                        max_trailing_logs,
+                        min_quorum,
+                        server_stabilization_time,
+                    };
+
+                    if update.is_empty() {
+                        anyhow::bail!("no autopilot configuration values given");
+                    }
+
+                    let token = get_token(token)?;
+                    attribute_token(&mut pf, token.clone()).await;
+                    pf.set_autopilot_configuration(token.clone(), &update)
+                        .await?;
+
+                    let config = pf.autopilot_configuration(token).await?;
+                    construct_autopilot_configuration_table(&config).printstd();
+                }
+            }
+        }
+        Commands::Token { command } => {
+            let api = setup_api(&cli.namespace).await?;
+            let active = get_active_pod_name(&api, &selector).await?;
+
+            let mut pf = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .http(&active, VAULT_PORT)
+                .await?;
+
+            match command {
+                TokenCommands::Lookup { token } => {
+                    let info = pf.token_lookup_self(get_token(token)?).await?;
+
+                    construct_token_table(&info, cli.time_format).printstd();
+                }
+                TokenCommands::Renew { token, increment } => {
+                    let auth = pf.token_renew_self(get_token(token)?, increment).await?;
+
+                    println!(
+                        "renewed token, ttl is now {}",
+                        format_duration(
+                            std::time::Duration::from_secs(auth.lease_duration),
+                            cli.time_format
+                        )
+                    );
+                }
+                TokenCommands::Revoke { token } => {
+                    pf.token_revoke_self(get_token(token)?).await?;
+
+                    println!("revoked token");
+                }
+            }
+        }
+        Commands::Raft {
+            command:
+                RaftCommands::Configuration {
+                    token,
+                    pod,
+                    wait_for,
+                },
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+            let token = get_token(token)?;
+
+            let pod = match pod {
+                Some(pod) => pod,
+                None => match get_active_pod_name(&api, &selector).await {
+                    Ok(pod) => pod,
+                    Err(_) => get_unsealed_pod_name(&api, &selector).await?,
+                },
+            };
+
+            let mut pf = PodApi::new(api, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .http(&pod, VAULT_PORT)
+                .await?;
+
+            let config = match wait_for {
+                Some(RaftWaitFor::Leader) => pf
+                    .await_raft_configuration(token, raft_configuration_any_leader())
+                    .await?
+                    .ok_or(anyhow::anyhow!("no raft configuration returned"))?,
+                Some(RaftWaitFor::AllVoters) => pf
+                    .await_raft_configuration(token, raft_configuration_all_voters())
+                    .await?
+                    .ok_or(anyhow::anyhow!("no raft configuration returned"))?,
+                None => pf.raft_configuration(token).await?,
+            };
+
+            construct_raft_configuration_table(&config).printstd();
+        }
+        Commands::Journal { path } => {
+            let entries = read_journal(&path)?;
+            for entry in &entries {
+                println!("{}", entry);
+            }
+
+            let unfinished = unfinished_actions(&entries);
+            if !unfinished.is_empty() {
+                println!();
+                println!("in progress when the journal stopped:");
+                for entry in unfinished {
+                    println!("{}", entry);
+                }
+            }
+        }
+        Commands::WaitUntilReady {} => {
+            let api: Api<StatefulSet> = setup_api(&cli.namespace).await?;
+            await_condition(
+                api.clone(),
+                &cli.statefulset,
+                is_statefulset_ready(),
+                &tuning,
+            )
+            .await?;
+        }
+        Commands::WaitUntilVersion {
+            version,
+            timeout,
+            interval,
+        } => {
+            let version = VaultVersion::from_str(&version)?;
+            let stss: Api<StatefulSet> = setup_api(&cli.namespace).await?;
+            let replicas = stss
+                .get(&cli.statefulset)
+                .await?
+                .spec
+                .and_then(|s| s.replicas);
+
+            let pods = PodApi::new(setup_api(&cli.namespace).await?, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+
+            tokio::time::timeout(timeout, pods.await_version(&version, replicas, interval))
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!(
+                        "pods did not run version {} within {}",
+                        version.version,
+                        humantime::format_duration(timeout)
+                    )
+                })??;
+        }
+        Commands::WaitUntilConverged {
+            token,
+            timeout,
+            interval,
+        } => {
+            let opts = ConvergeOptions {
+                tls: !cli.no_tls,
+                domain: cli.domain,
+                selector: selector.clone(),
+                transport: cli.transport,
+                timeout,
+                interval,
+                ..ConvergeOptions::new(get_token(token)?)
+            };
+
+            await_cluster_converged(
+                Client::try_default().await?,
+                &cli.namespace,
+                &cli.statefulset,
+                &opts,
+            )
+            .await?;
+        }
+        Commands::Verify {} => {
+            let stss = StatefulSetApi::from(setup_api::<StatefulSet>(&cli.namespace).await?)
+                .container(selector.container.clone());
+            let pods = PodApi::new(setup_api(&cli.namespace).await?, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone());
+
+            let mismatches = stss.verify_revisions(&cli.statefulset, &pods).await?;
+            if !mismatches.is_empty() {
+                construct_revision_table(&mismatches).printstd();
+                anyhow::bail!(
+                    "{} pods were not created from the update revision of statefulset {}",
+                    mismatches.len(),
+                    cli.statefulset
+                );
+            }
+
+            println!("all pods were created from the update revision");
+        }
+        Commands::Init {
+            pod,
+            store_keys,
+            token,
+            key_shares,
+            key_threshold,
+            recovery_shares,
+            recovery_threshold,
+        } => {
+            // the token is needed before init, once the shares exist they have to be stored
+            let request = KeyRequest::new(
+                KeyKind::Unseal,
+                match store_keys.as_ref().is_some_and(KeysFrom::needs_token) {
+                    true if keys_auth.is_none() => get_token(token)?,
+                    _ => token.unwrap_or_else(|| Secret::new(String::new())),
+                },
+            )
+            .auth(keys_auth.clone());
+
+            let api = setup_api(&cli.namespace).await?;
+            let pods = api.list(&selector.to_list_params()).await?;
+            let name = find_pod(&pods.items, &pod)?
+                .metadata
+                .name
+                .clone()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+            let mut pf = PodApi::new(api, !cli.no_tls, cli.domain.clone())
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .http(&name, VAULT_PORT)
                 .await?;
+            let status = pf.seal_status().await?;
+            if status.initialized {
+                anyhow::bail!("pod {} is already initialized", name);
+            }
+
+            // once the shares exist, they are printed if they cannot be stored
+            if let Some(to) = &store_keys {
+                check_store_target(to, &request, &keys_vault).await?;
+            }
+
+            let init = match status.is_auto_unseal() {
+                true => InitRequest::auto_unseal(recovery_shares, recovery_threshold),
+                false => InitRequest::shamir(key_shares, key_threshold),
+            };
+
+            tracing::info!("initializing pod {} with the {} seal", name, status.type_);
+            let result = pf.init(init).await?;
+
+            match store_keys {
+                Some(to) => {
+                    if let Err(e) = store_init_result(&to, &result, &request, &keys_vault).await {
+                        print_init_result(&result);
+                        return Err(e.context("the keys are printed above, store them yourself"));
+                    }
+                }
+                None => print_init_result(&result),
+            }
+        }
+        Commands::Unseal {
+            token,
+            keys_secret_uri,
+            key_cmd,
+            pod,
+            concurrency,
+            print_target,
+        } => {
+            let api = setup_api(&cli.namespace).await?;
+            let sealed = match &pod {
+                Some(pod) => {
+                    let pods = api.list(&selector.to_list_params()).await?;
+                    vec![find_pod(&pods.items, pod)?.clone()]
+                }
+                None => list_sealed_pods_with(&api, &selector).await?,
+            };
+
+            if print_target {
+                return print_targets(&sealed);
+            }
+
+            if sealed.is_empty() {
+                return Ok(());
+            }
+
+            let pods = PodApi::new(api.clone(), !cli.no_tls, cli.domain.clone())
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+
+            let status = pods.any_seal_status().await?;
+            if KeyKind::required_by(&status) == KeyKind::Recovery {
+                anyhow::bail!(
+                    "cluster uses auto-unseal ({}), it can not be unsealed with keys",
+                    status.type_
+                );
+            }
+
+            let from = keys_from_args(
+                keys_from.as_ref(),
+                keys_secret_uri,
+                key_cmd,
+                keys_secret_field.as_deref(),
+            )?;
+            let token = match from.as_ref().is_some_and(KeysFrom::needs_token) {
+                true if keys_auth.is_none() => get_token(token)?,
+                _ => token.unwrap_or_else(|| Secret::new(String::new())),
+            };
+            let keys = get_keys(
+                &key_providers,
+                &token,
+                keys_auth.as_ref(),
+                from,
+                true,
+                KeyKind::Unseal,
+            )
+            .await?;
+
+            let names = sealed
+                .iter()
+                .map(|pod| {
+                    pod.metadata
+                        .name
+                        .clone()
+                        .ok_or(anyhow::anyhow!("pod does not have a name"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+
+            let mut failed = vec![];
+            for (name, unsealed) in pods
+                .unseal_pods(names, keys.unseal_keys()?, concurrency)
+                .await
+            {
+                match unsealed {
+                    Ok(elapsed) => {
+                        tracing::info!("unsealed pod {} in {:.1}s", name, elapsed.as_secs_f64())
+                    }
+                    Err(e) => {
+                        tracing::warn!("unsealing pod {}: {}", name, e);
+                        failed.push(name);
+                    }
+                }
+            }
+            if !failed.is_empty() {
+                anyhow::bail!("unsealing failed for pods {}", failed.join(", "));
+            }
+        }
+        Commands::Operator {
+            token,
+            keys_secret_uri,
+            key_cmd,
+            interval,
+            max_unseals,
+            unseal_window,
+            unseal_concurrency,
+            serve,
+            all,
+            cluster,
+            watch_namespace,
+            all_namespaces,
+            statefulset_selector,
+            rediscover,
+        } => {
+            let limit = UnsealRateLimit {
+                max: max_unseals,
+                window: unseal_window,
+            };
+            let stats = Arc::new(OperatorStats::new(std::time::Instant::now()));
+
+            if let Some(address) = serve {
+                let listener = tokio::net::TcpListener::bind(address).await?;
+                println!(
+                    "serving /healthz, /readyz and /metrics on http://{}",
+                    listener.local_addr()?
+                );
+
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_operator_endpoints(stats, interval, listener).await {
+                        tracing::error!("serving operator endpoints: {}", e);
+                    }
+                });
+            }
+
+            let from = keys_from_args(
+                keys_from.as_ref(),
+                keys_secret_uri,
+                key_cmd,
+                keys_secret_field.as_deref(),
+            )?;
+
+            if all || !cluster.is_empty() || !watch_namespace.is_empty() || all_namespaces {
+                let configured = match (&cli.config, all || !cluster.is_empty()) {
+                    (_, false) => ClusterSet::default(),
+                    (Some(path), true) => ClusterSet::new(ConfigFile::from_file(path)?.clusters),
+                    (None, true) => {
+                        anyhow::bail!("--all and --cluster need the clusters of --config")
+                    }
+                };
+                let configured = match all {
+                    true => configured,
+                    false => configured.select(&cluster)?,
+                };
+                let watch = !watch_namespace.is_empty() || all_namespaces;
+                // the key sources of discovered statefulsets are only known later
+                let token = token
+                    .or_else(|| std::env::var("VAULT_TOKEN").ok().map(Secret::new))
+                    .unwrap_or_else(|| Secret::new(String::new()));
+                let client = Client::try_default().await?;
+
+                let discover = || {
+                    let (client, configured) = (client.clone(), configured.clone());
+                    let (namespaces, selector) = (&watch_namespace, &statefulset_selector);
+                    async move {
+                        let mut clusters = configured.clusters().to_vec();
+                        if watch {
+                            clusters.extend(discover_clusters(client, namespaces, selector).await?);
+                        }
+                        Ok(clusters)
+                    }
+                };
+
+                let start = |cluster: ClusterConfig| {
+                    let (tls, domain, transport) = (!cli.no_tls, cli.domain.clone(), cli.transport);
+                    let (pod_label, container_name) =
+                        (cli.pod_label.clone(), cli.container_name.clone());
+                    let (client, stats) = (client.clone(), stats.clone());
+                    let (token, keys_auth, from) = (token.clone(), keys_auth.clone(), from.clone());
+                    let key_providers = key_providers.clone();
+                    let tuning = tuning.clone();
+                    let span = tracing::info_span!("cluster", name = %cluster.name);
+
+                    async move {
+                        let stss: Api<StatefulSet> = setup_api(&cluster.namespace).await?;
+                        let sts = stss.get(&cluster.statefulset).await?;
+                        let selector = pod_label.iter().fold(
+                            statefulset_pod_selector(&sts, cluster.flavor)
+                                .container(cluster.container.clone().or(container_name)),
+                            |selector, (key, value)| selector.label(key, value),
+                        );
+                        let pods = PodApi::new(setup_api(&cluster.namespace).await?, tls, domain)
+                            .tuning(tuning.clone())
+                            .read_your_writes(read_your_writes)
+                            .selector(selector)
+                            .transport(transport);
+
+                        let from = cluster.keys_source()?.or(from);
+                        let keys = get_keys(
+                            &key_providers,
+                            &token,
+                            keys_auth.as_ref(),
+                            from,
+                            true,
+                            KeyKind::Unseal,
+                        )
+                        .await?;
+
+                        Operator::new(client, pods, keys.unseal_keys()?.to_vec(), limit)
+                            .concurrency(unseal_concurrency)
+                            .shared_stats(stats)
+                            .run(interval)
+                            .await
+                    }
+                    .instrument(span)
+                };
+
+                run_operators(discover, start, rediscover).await;
+                return Ok(());
+            }
+
+            let pods = PodApi::new(
+                setup_api(&cli.namespace).await?,
+                !cli.no_tls,
+                cli.domain.clone(),
+            )
+            .tuning(tuning.clone())
+            .read_your_writes(read_your_writes)
+            .selector(selector.clone())
+            .transport(cli.transport);
+
+            let token = match from.as_ref().is_some_and(KeysFrom::needs_token) {
+                true if keys_auth.is_none() => get_token(token)?,
+                _ => token.unwrap_or_else(|| Secret::new(String::new())),
+            };
+            let keys = get_keys(
+                &key_providers,
+                &token,
+                keys_auth.as_ref(),
+                from,
+                true,
+                KeyKind::Unseal,
+            )
+            .await?;
+
+            Operator::new(
+                Client::try_default().await?,
+                pods,
+                keys.unseal_keys()?.to_vec(),
+                limit,
+            )
+            .concurrency(unseal_concurrency)
+            .shared_stats(stats)
+            .run(interval)
+            .await?;
+        }
+        Commands::Upgrade {
+            token,
+            do_not_unseal,
+            force_upgrade,
+            keys_secret_uri,
+            key_cmd,
+            wait_for_sidecars,
+            use_eviction,
+            partition,
+            no_events,
+            plan,
+            emit_runbook,
+            report_json,
+            journal,
+            target_version,
+            revert_on_pull_failure,
+            rollback_on_failure,
+            snapshot_before,
+            max_unavailable,
+            resume,
+            deadline,
+            force_unlock,
+            canary,
+            bake_time,
+            allow_downgrade,
+            ack_template_changes,
+            all,
+            cluster,
+            parallel,
+            takeover,
+            timeouts,
+            hooks,
+            catch_up,
+        } => {
+            let stss = setup_api(&cli.namespace).await?;
+            let pods = setup_api(&cli.namespace).await?;
+
+            let target_version = target_version
+                .as_deref()
+                .map(VaultVersion::from_str)
+                .transpose()?;
+            let from = keys_from_args(
+                keys_from.as_ref(),
+                keys_secret_uri,
+                key_cmd,
+                keys_secret_field.as_deref(),
+            )?;
+
+            if all || !cluster.is_empty() {
+                let clusters = match &cli.config {
+                    Some(path) => ClusterSet::new(ConfigFile::from_file(path)?.clusters),
+                    None => anyhow::bail!("--all and --cluster need the clusters of --config"),
+                };
+                let clusters = match all {
+                    true => clusters,
+                    false => clusters.select(&cluster)?,
+                };
+                let token = get_token(token)?;
+                let interrupt = interrupt_on_signal();
+
+                let report = clusters
+                    .upgrade_with(parallel, |cluster, reporter| {
+                        let (tls, domain, transport) =
+                            (!cli.no_tls, cli.domain.clone(), cli.transport);
+                        let (pod_label, container_name) =
+                            (cli.pod_label.clone(), cli.container_name.clone());
+                        let tuning = tuning.clone();
+                        let token = token.clone();
+                        let (keys_auth, key_providers) = (keys_auth.clone(), key_providers.clone());
+                        let from = from.clone();
+                        let target_version = target_version.clone();
+                        let (takeover, timeouts) = (takeover.clone(), timeouts.clone());
+                        let (hooks, catch_up) = (hooks.clone(), catch_up.clone());
+                        let interrupt = interrupt.clone();
+
+                        async move {
+                            let stss: Api<StatefulSet> = setup_api(&cluster.namespace).await?;
+                            let pods: Api<Pod> = setup_api(&cluster.namespace).await?;
+
+                            // several clusters may share the namespace
+                            let mut sts = stss.get(&cluster.statefulset).await?;
+                            let selector = pod_label.iter().fold(
+                                statefulset_pod_selector(&sts, cluster.flavor)
+                                    .container(cluster.container.clone().or(container_name)),
+                                |selector, (key, value)| selector.label(key, value),
+                            );
+                            let pod_api = |pods| {
+                                PodApi::new(pods, tls, domain.clone())
+                                    .tuning(tuning.clone())
+                                    .read_your_writes(read_your_writes)
+                                    .selector(selector.clone())
+                                    .transport(transport)
+                            };
+
+                            let should_unseal =
+                                should_unseal(&pod_api(pods.clone()), do_not_unseal).await?;
+                            let keys = get_keys(
+                                &key_providers,
+                                &token,
+                                keys_auth.as_ref(),
+                                cluster.keys_source()?.or(from),
+                                should_unseal,
+                                KeyKind::Unseal,
+                            )
+                            .await?;
+
+                            if partition {
+                                sts = StatefulSetApi::from(stss.clone())
+                                    .container(selector.container.clone())
+                                    .hold_partition(&sts)
+                                    .await?;
+                            }
+                            if let Some(target_version) = &target_version {
+                                let current = VaultVersion::of_statefulset(
+                                    &sts,
+                                    selector.container.as_deref(),
+                                )?;
+                                if current.is_downgrade_to(target_version) && !allow_downgrade {
+                                    anyhow::bail!(
+                                        "refusing to downgrade statefulset {} from {} to {}, \
+                                         use --allow-downgrade to do it anyway",
+                                        cluster.statefulset,
+                                        current.version,
+                                        target_version.version
+                                    );
+                                }
+
+                                sts = StatefulSetApi::from(stss.clone())
+                                    .container(selector.container.clone())
+                                    .set_version(&sts, target_version)
+                                    .await?;
+                            }
+
+                            let options = ClusterUpgradeOptions::from(
+                                hooks.apply(
+                                    timeouts
+                                        .into_options()
+                                        .should_unseal(should_unseal)
+                                        .force_upgrade(force_upgrade)
+                                        .report(reporter),
+                                    &pods,
+                                    selector.container.clone(),
+                                ),
+                            )
+                            .max_unavailable(max_unavailable)
+                            .resume(resume)
+                            .deadline(deadline)
+                            .force_unlock(force_unlock)
+                            .canary(canary)
+                            .bake_time(bake_time)
+                            .allow_downgrade(allow_downgrade)
+                            .ack_template_changes(ack_template_changes)
+                            .interrupt(interrupt);
+                            // prompts of clusters upgraded in parallel would be interleaved
+                            let options = match parallel {
+                                true => catch_up.apply(options),
+                                false => catch_up.apply(options.confirm(confirm_on_terminal)),
+                            };
+
+                            let pod_api = pod_api(pods)
+                                .wait_for_sidecars(wait_for_sidecars)
+                                .use_eviction(use_eviction)
+                                .partition(partition)
+                                .events(!no_events)
+                                .active_strategy(takeover.active_strategy)
+                                .active_service(takeover.active_service.clone())
+                                .takeover(takeover.into_takeover());
+
+                            StatefulSetApi::from(stss.clone())
+                                .container(selector.container.clone())
+                                .upgrade_with(sts, &pod_api, token, keys.unseal_keys()?, &options)
+                                .await?;
+
+                            await_condition(
+                                stss,
+                                &cluster.statefulset,
+                                is_statefulset_ready(),
+                                &tuning,
+                            )
+                            .await?;
+
+                            Ok(())
+                        }
+                    })
+                    .await;
+
+                match &report_json {
+                    Some(path) => write_report(path, &report).await?,
+                    None => println!("{}", serde_json::to_string_pretty(&report)?),
+                }
+
+                return report.result();
+            }
+
+            if plan {
+                return print_plan(
+                    &cli.statefulset,
+                    stss,
+                    &PodApi::new(pods, !cli.no_tls, cli.domain)
+                        .tuning(tuning.clone())
+                        .read_your_writes(read_your_writes)
+                        .selector(selector.clone()),
+                    get_token(token).ok(),
+                    &UpgradeOptions::default()
+                        .should_unseal(!do_not_unseal)
+                        .force_upgrade(force_upgrade),
+                    target_version,
+                )
+                .await;
+            }
+
+            if let Some(path) = emit_runbook {
+                return write_runbook(
+                    &path,
+                    &cli.namespace,
+                    &cli.statefulset,
+                    flavor,
+                    stss,
+                    &PodApi::new(pods, !cli.no_tls, cli.domain)
+                        .tuning(tuning.clone())
+                        .read_your_writes(read_your_writes)
+                        .selector(selector.clone()),
+                    get_token(token).ok(),
+                    &timeouts
+                        .into_options()
+                        .should_unseal(!do_not_unseal)
+                        .force_upgrade(force_upgrade),
+                    target_version,
+                )
+                .await;
+            }
+
+            let token = get_token(token)?;
+            let journal = journal.as_deref().map(Journal::open).transpose()?;
+
+            let pod_api = PodApi::new(pods.clone(), !cli.no_tls, cli.domain.clone())
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport);
+
+            pod_api.ensure_not_dev_mode("upgrade").await?;
+
+            let vault_pods = pods.list(&selector.to_list_params()).await?.items;
+            for finding in diagnose_resources(
+                Client::try_default().await?,
+                &cli.namespace,
+                &vault_pods,
+                selector.container.as_deref(),
+            )
+            .await?
+            {
+                if finding.severity != Severity::Ok {
+                    tracing::warn!("{}", finding.message);
+                }
+            }
+
+            let should_unseal = should_unseal(&pod_api, do_not_unseal).await?;
+
+            let keys = get_keys(
+                &key_providers,
+                &token,
+                keys_auth.as_ref(),
+                from,
+                should_unseal,
+                KeyKind::Unseal,
+            )
+            .await?;
+
+            let mut sts = stss.get(&cli.statefulset).await?;
+            let previous = sts.clone();
+
+            if partition {
+                sts = StatefulSetApi::from(stss.clone())
+                    .container(selector.container.clone())
+                    .hold_partition(&sts)
+                    .await?;
+            }
+
+            if let Some(target_version) = target_version {
+                // refuse before patching, so the statefulset is not left with the older image
+                let current = VaultVersion::of_statefulset(&sts, selector.container.as_deref())?;
+                if current.is_downgrade_to(&target_version) && !allow_downgrade {
+                    anyhow::bail!(
+                        "refusing to downgrade statefulset {} from {} to {}, use --allow-downgrade to do it anyway",
+                        cli.statefulset,
+                        current.version,
+                        target_version.version
+                    );
+                }
+
+                let detail = Some(format!("version {}", target_version.version));
+                if let Some(journal) = &journal {
+                    journal.intend("set-image", None, detail)?;
+                }
+                let patched = StatefulSetApi::from(stss.clone())
+                    .container(selector.container.clone())
+                    .set_version(&sts, &target_version)
+                    .await;
+                if let Some(journal) = &journal {
+                    journal.finish("set-image", None, &patched)?;
+                }
+                sts = patched?;
+            }
+
+            let options = ClusterUpgradeOptions::from(
+                hooks.apply(
+                    timeouts
+                        .into_options()
+                        .should_unseal(should_unseal)
+                        .force_upgrade(force_upgrade),
+                    &pods,
+                    selector.container.clone(),
+                ),
+            )
+            .max_unavailable(max_unavailable)
+            .snapshot_before(snapshot_before)
+            .resume(resume)
+            .deadline(deadline)
+            .force_unlock(force_unlock)
+            .canary(canary)
+            .bake_time(bake_time)
+            .allow_downgrade(allow_downgrade)
+            .ack_template_changes(ack_template_changes)
+            .interrupt(interrupt_on_signal())
+            .confirm(confirm_on_terminal);
+            let mut options = catch_up.apply(options);
+            let reporter = UpgradeReporter::new();
+            options.pod.report = Some(reporter.clone());
+            options.pod.journal = journal;
+
+            let pod_api = PodApi::new(pods.clone(), !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .wait_for_sidecars(wait_for_sidecars)
+                .use_eviction(use_eviction)
+                .partition(partition)
+                .events(!no_events)
+                .active_strategy(takeover.active_strategy)
+                .active_service(takeover.active_service.clone())
+                .takeover(takeover.into_takeover());
+
+            let upgraded = StatefulSetApi::from(stss.clone())
+                .container(selector.container.clone())
+                .upgrade_with(
+                    sts.clone(),
+                    &pod_api,
+                    token.clone(),
+                    keys.unseal_keys()?,
+                    &options,
+                )
+                .await;
+
+            let stopped = upgraded.as_ref().is_err_and(|e| {
+                e.is::<DeadlineExceeded>()
+                    || e.is::<UpgradeInterrupted>()
+                    || e.is::<UpgradeLockLost>()
+            });
+            match &report_json {
+                Some(path) => write_report(path, &reporter.report()).await?,
+                // print the partial report for the change ticket
+                None if stopped => {
+                    println!("{}", serde_json::to_string_pretty(&reporter.report())?)
+                }
+                None => {}
+            }
 
-            kube::runtime::wait::await_condition(
+            match upgraded {
+                Err(e) if revert_on_pull_failure => match e.downcast_ref::<ImagePullFailed>() {
+                    Some(failed) => {
+                        tracing::error!("{}", failed);
+                        StatefulSetApi::from(stss.clone())
+                            .container(selector.container.clone())
+                            .revert_image(
+                                &previous,
+                                &pod_api,
+                                failed,
+                                token,
+                                keys.unseal_keys()?,
+                                &options.pod,
+                            )
+                            .await?;
+                        return Err(e.context("upgrade was reverted"));
+                    }
+                    None => return Err(e),
+                },
+                // a rollout stopped before the deadline, interrupted or without its lock
+                // is meant to be resumed
+                Err(e) if rollback_on_failure && !stopped => {
+                    tracing::error!("{:#}", e);
+                    let version = StatefulSetApi::from(stss.clone())
+                        .container(selector.container.clone())
+                        .roll_back(
+                            &previous,
+                            &pod_api,
+                            token,
+                            keys.unseal_keys()?,
+                            &options.pod,
+                        )
+                        .await?;
+                    return Err(
+                        e.context(format!("upgrade was rolled back to {}", version.version))
+                    );
+                }
+                upgraded => upgraded?,
+            }
+
+            await_condition(
                 stss.clone(),
                 &sts.metadata
                     .name
                     .clone()
                     .ok_or(anyhow::anyhow!("statefulset does not have a name"))?,
                 is_statefulset_ready(),
+                &tuning,
+            )
+            .await?;
+
+            for mismatch in StatefulSetApi::from(stss.clone())
+                .container(selector.container.clone())
+                .verify_revisions(&cli.statefulset, &pod_api)
+                .await?
+            {
+                tracing::warn!(
+                    "pod {} was created from revision {}, not from the update revision {}, check it with verify",
+                    mismatch.pod,
+                    mismatch.revision.as_deref().unwrap_or("unknown"),
+                    mismatch.update_revision
+                );
+            }
+        }
+        Commands::Plan {
+            token,
+            do_not_unseal,
+            force_upgrade,
+            target_version,
+        } => {
+            let stss = setup_api(&cli.namespace).await?;
+            let pods = setup_api(&cli.namespace).await?;
+
+            print_plan(
+                &cli.statefulset,
+                stss,
+                &PodApi::new(pods, !cli.no_tls, cli.domain)
+                    .tuning(tuning.clone())
+                    .read_your_writes(read_your_writes)
+                    .selector(selector.clone()),
+                get_token(token).ok(),
+                &UpgradeOptions::default()
+                    .should_unseal(!do_not_unseal)
+                    .force_upgrade(force_upgrade),
+                target_version
+                    .as_deref()
+                    .map(VaultVersion::from_str)
+                    .transpose()?,
+            )
+            .await?;
+        }
+        Commands::SetImage {
+            target_version,
+            image,
+        } => {
+            let stss: Api<StatefulSet> = setup_api(&cli.namespace).await?;
+
+            let sts = stss.get(&cli.statefulset).await?;
+            let stss = StatefulSetApi::from(stss).container(selector.container.clone());
+
+            let sts = match (target_version, image) {
+                (Some(target_version), _) => {
+                    stss.set_version(&sts, &VaultVersion::from_str(&target_version)?)
+                        .await?
+                }
+                (None, Some(image)) => stss.set_image(&sts, &image).await?,
+                (None, None) => anyhow::bail!("either --target-version or --image is required"),
+            };
+
+            println!(
+                "statefulset {} now uses version {}",
+                cli.statefulset,
+                VaultVersion::of_statefulset(&sts, selector.container.as_deref())?.version
+            );
+        }
+        Commands::Restart {
+            token,
+            do_not_unseal,
+            keys_secret_uri,
+            key_cmd,
+            wait_for_sidecars,
+            use_eviction,
+            no_events,
+            resume,
+            force_unlock,
+            deadline,
+            journal,
+            takeover,
+            timeouts,
+            hooks,
+            catch_up,
+        } => {
+            let stss: Api<StatefulSet> = setup_api(&cli.namespace).await?;
+            let pods = PodApi::new(setup_api(&cli.namespace).await?, !cli.no_tls, cli.domain)
+                .tuning(tuning.clone())
+                .read_your_writes(read_your_writes)
+                .selector(selector.clone())
+                .transport(cli.transport)
+                .wait_for_sidecars(wait_for_sidecars)
+                .use_eviction(use_eviction)
+                .events(!no_events)
+                .statefulset(stss.clone(), &cli.statefulset)
+                .active_strategy(takeover.active_strategy)
+                .active_service(takeover.active_service.clone())
+                .takeover(takeover.into_takeover());
+
+            pods.ensure_not_dev_mode("restart").await?;
+
+            let token = get_token(token)?;
+
+            let should_unseal = should_unseal(&pods, do_not_unseal).await?;
+
+            let keys = get_keys(
+                &key_providers,
+                &token,
+                keys_auth.as_ref(),
+                keys_from_args(
+                    keys_from.as_ref(),
+                    keys_secret_uri,
+                    key_cmd,
+                    keys_secret_field.as_deref(),
+                )?,
+                should_unseal,
+                KeyKind::Unseal,
+            )
+            .await?;
+
+            let mut pod_options = hooks.apply(
+                timeouts.into_options().should_unseal(should_unseal),
+                &pods.api,
+                pods.selector.container.clone(),
+            );
+            if let Some(path) = &journal {
+                pod_options = pod_options.journal(Journal::open(path)?);
+            }
+            let options = catch_up.apply(
+                ClusterUpgradeOptions::from(pod_options)
+                    .resume(resume)
+                    .force_unlock(force_unlock)
+                    .deadline(deadline)
+                    .interrupt(interrupt_on_signal())
+                    .confirm(confirm_on_terminal),
+            );
+
+            StatefulSetApi::from(stss.clone())
+                .container(selector.container.clone())
+                .restart_with(&pods, token, keys.unseal_keys()?, &options)
+                .await?;
+
+            await_condition(
+                stss.clone(),
+                &cli.statefulset,
+                is_statefulset_ready(),
+                &tuning,
+            )
+            .await?;
+        }
+        Commands::Scale {
+            replicas,
+            token,
+            keys_secret_uri,
+            key_cmd,
+            join,
+            timeout,
+            force,
+        } => {
+            let stss: Api<StatefulSet> = setup_api(&cli.namespace).await?;
+            let pods = setup_api(&cli.namespace).await?;
+
+            let token = get_token(token)?;
+
+            let sts = stss.get(&cli.statefulset).await?;
+
+            let current = sts.spec.as_ref().and_then(|s| s.replicas).unwrap_or(1);
+
+            // keys are only needed to unseal new pods
+            let keys = get_keys(
+                &key_providers,
+                &token,
+                keys_auth.as_ref(),
+                keys_from_args(
+                    keys_from.as_ref(),
+                    keys_secret_uri,
+                    key_cmd,
+                    keys_secret_field.as_deref(),
+                )?,
+                replicas > current,
+                KeyKind::Unseal,
+            )
+            .await?;
+
+            StatefulSetApi::from(stss.clone())
+                .container(selector.container.clone())
+                .scale(
+                    sts,
+                    &PodApi::new(pods, !cli.no_tls, cli.domain)
+                        .tuning(tuning.clone())
+                        .read_your_writes(read_your_writes)
+                        .selector(selector.clone())
+                        .transport(cli.transport),
+                    replicas,
+                    token,
+                    keys.unseal_keys()?,
+                    &join.into_request().await?,
+                    &ScaleOptions::default().timeout(timeout).force(force),
+                )
+                .await?;
+
+            await_condition(
+                stss.clone(),
+                &cli.statefulset,
+                is_statefulset_ready(),
+                &tuning,
+            )
+            .await?;
+        }
+        Commands::Clone {
+            from,
+            to,
+            token,
+            keys_secret_uri,
+            key_cmd,
+        } => {
+            let token = get_token(token)?;
+
+            let keys = get_keys(
+                &key_providers,
+                &token,
+                keys_auth.as_ref(),
+                keys_from_args(
+                    keys_from.as_ref(),
+                    keys_secret_uri,
+                    key_cmd,
+                    keys_secret_field.as_deref(),
+                )?,
+                true,
+                KeyKind::Unseal,
             )
             .await?;
+
+            let source_stss: Api<StatefulSet> = setup_api(&from.namespace).await?;
+            let target_stss: Api<StatefulSet> = setup_api(&to.namespace).await?;
+
+            let source = source_stss.get(&from.name).await?;
+            let target = target_stss.get(&to.name).await?;
+
+            StatefulSetApi::from(target_stss.clone())
+                .container(selector.container.clone())
+                .clone_from(
+                    &source,
+                    &PodApi::new(
+                        setup_api(&from.namespace).await?,
+                        !cli.no_tls,
+                        cli.domain.clone(),
+                    )
+                    .tuning(tuning.clone())
+                    .read_your_writes(read_your_writes)
+                    .selector(selector.clone())
+                    .transport(cli.transport),
+                    &target,
+                    &PodApi::new(setup_api(&to.namespace).await?, !cli.no_tls, cli.domain)
+                        .tuning(tuning.clone())
+                        .read_your_writes(read_your_writes)
+                        .selector(selector.clone())
+                        .transport(cli.transport),
+                    token,
+                    keys.unseal_keys()?,
+                )
+                .await?;
+
+            await_condition(target_stss, &to.name, is_statefulset_ready(), &tuning).await?;
         }
         Commands::SelfUpdate {} => {
             let mut status = self_update::backends::github::Update::configure();
@@ -379,6 +3334,325 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Get the name of the pod labelled as active
+async fn get_active_pod_name(api: &Api<Pod>, selector: &PodSelector) -> anyhow::Result<String> {
+    let active = api
+        .list(&selector.clone().role(ExecIn::Active).to_list_params())
+        .await?;
+    let active = active.iter().next().ok_or(anyhow::anyhow!(
+        "no active vault pod found. is vault sealed?"
+    ))?;
+
+    active
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))
+}
+
+/// Print the pods a command would act on, see `--print-target`
+fn print_targets(pods: &[Pod]) -> anyhow::Result<()> {
+    if pods.is_empty() {
+        println!("no matching vault pod found");
+        return Ok(());
+    }
+
+    construct_target_table(pods)?.printstd();
+
+    Ok(())
+}
+
+/// Print the upgrade plan of the statefulset
+async fn print_plan(
+    statefulset: &str,
+    stss: Api<StatefulSet>,
+    pods: &PodApi,
+    token: Option<Secret<String>>,
+    options: &UpgradeOptions,
+    target_version: Option<VaultVersion>,
+) -> anyhow::Result<()> {
+    let plan = match target_version {
+        // the statefulset is not patched, so plan against the given version directly
+        Some(target) => plan_upgrade(pods, &target, token, options).await?,
+        None => {
+            let sts = stss.get(statefulset).await?;
+
+            StatefulSetApi::from(stss)
+                .container(pods.selector.container.clone())
+                .plan_with(&sts, pods, token, options)
+                .await?
+        }
+    };
+
+    if plan.pods.is_empty() {
+        println!("no active and standby pods found, the upgrade would be skipped");
+        return Ok(());
+    }
+
+    construct_upgrade_plan_table(&plan).printstd();
+
+    Ok(())
+}
+
+/// Write the upgrade of the statefulset as a shell script, see `upgrade_runbook`
+#[allow(clippy::too_many_arguments)]
+async fn write_runbook(
+    path: &std::path::Path,
+    namespace: &str,
+    statefulset: &str,
+    flavor: Flavor,
+    stss: Api<StatefulSet>,
+    pods: &PodApi,
+    token: Option<Secret<String>>,
+    options: &UpgradeOptions,
+    target_version: Option<VaultVersion>,
+) -> anyhow::Result<()> {
+    let sts = stss.get(statefulset).await?;
+    let container = find_vault_container(
+        &sts.spec
+            .as_ref()
+            .and_then(|s| s.template.spec.as_ref())
+            .ok_or(anyhow::anyhow!("statefulset does not have a pod spec"))?
+            .containers,
+        pods.selector.container.as_deref(),
+    )?;
+
+    let (plan, image) = match target_version {
+        Some(target) => {
+            let image = container
+                .image
+                .as_deref()
+                .ok_or(anyhow::anyhow!("statefulset does not have a vault image"))?;
+
+            (
+                plan_upgrade(pods, &target, token, options).await?,
+                Some(image_with_version(image, &target)),
+            )
+        }
+        None => (
+            StatefulSetApi::from(stss)
+                .container(pods.selector.container.clone())
+                .plan_with(&sts, pods, token, options)
+                .await?,
+            None,
+        ),
+    };
+
+    let target = RunbookTarget {
+        namespace: namespace.to_string(),
+        statefulset: statefulset.to_string(),
+        flavor,
+        container: container.name.clone(),
+        image,
+    };
+
+    tokio::fs::write(path, upgrade_runbook(&plan, &target, options))
+        .await
+        .map_err(|e| anyhow::anyhow!("writing runbook {}: {}", path.display(), e))?;
+    println!(
+        "wrote runbook for {} pods to {}",
+        plan.pods.len(),
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// Write the report of an upgrade as JSON
+async fn write_report(
+    path: &std::path::Path,
+    report: &impl serde::Serialize,
+) -> anyhow::Result<()> {
+    tokio::fs::write(path, serde_json::to_string_pretty(report)?)
+        .await
+        .map_err(|e| anyhow::anyhow!("writing report {}: {}", path.display(), e))
+}
+
+/// Get the name of any pod labelled as unsealed
+async fn get_unsealed_pod_name(api: &Api<Pod>, selector: &PodSelector) -> anyhow::Result<String> {
+    let unsealed = api
+        .list(&selector.clone().unsealed().to_list_params())
+        .await?;
+    let unsealed = unsealed
+        .iter()
+        .next()
+        .ok_or(anyhow::anyhow!("no unsealed vault pod found"))?;
+
+    unsealed
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))
+}
+
+/// Print the key shares and root token like `vault operator init`
+fn print_init_result(result: &InitResult) {
+    for (i, key) in result.keys.iter().enumerate() {
+        println!("Unseal Key {}: {}", i + 1, key.expose_secret());
+    }
+    for (i, key) in result.recovery_keys.iter().enumerate() {
+        println!("Recovery Key {}: {}", i + 1, key.expose_secret());
+    }
+    println!("Initial Root Token: {}", result.root_token.expose_secret());
+}
+
+/// Key source of `--keys-from`, or of the older `--keys-secret-uri` and `--key-cmd`,
+/// reading the keys from `--keys-secret-field` of a vault secret
+fn keys_from_args(
+    keys_from: Option<&KeysFrom>,
+    keys_secret_uri: Option<KeysSecretUri>,
+    key_cmd: Option<String>,
+    keys_secret_field: Option<&str>,
+) -> anyhow::Result<Option<KeysFrom>> {
+    let from = match (keys_from, keys_secret_uri, key_cmd) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+            anyhow::bail!("--keys-from cannot be combined with --keys-secret-uri or --key-cmd")
+        }
+        (Some(from), None, None) => Some(from.clone()),
+        (None, Some(uri), _) => Some(uri.into()),
+        (None, None, Some(cmd)) => Some(KeysFrom::cmd(&cmd)),
+        (None, None, None) => None,
+    };
+
+    match (from, keys_secret_field) {
+        (Some(from), Some(field)) => Ok(Some(from.vault_field(field)?)),
+        (from, _) => Ok(from),
+    }
+}
+
+/// Retrieve the unseal or recovery keys with the key provider of the source
+async fn get_keys(
+    providers: &KeyProviders,
+    token: &Secret<String>,
+    keys_auth: Option<&KubernetesAuth>,
+    from: Option<KeysFrom>,
+    required: bool,
+    kind: KeyKind,
+) -> anyhow::Result<Keys> {
+    match from {
+        Some(from) => {
+            let request = KeyRequest::new(kind, token.clone()).auth(keys_auth.cloned());
+
+            providers.get_keys(&from, &request).await
+        }
+        None if required => anyhow::bail!("no --keys-from, keys secret uri or key cmd specified"),
+        None => Ok(Keys {
+            kind,
+            keys: Vec::new(),
+        }),
+    }
+}
+
+/// Check if vault-mgmt has to unseal the pods
+/// Clusters with auto-unseal (e.g. KMS or transit) unseal themselves and need no keys
+async fn should_unseal(pods: &PodApi, do_not_unseal: bool) -> anyhow::Result<bool> {
+    if do_not_unseal {
+        return Ok(false);
+    }
+
+    let status = pods.any_seal_status().await?;
+    if status.is_auto_unseal() {
+        tracing::info!(
+            "cluster uses auto-unseal ({}), waiting for the pods to unseal themselves",
+            status.type_
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Interrupt a rollout on SIGINT or SIGTERM, so it stops before the next pod
+/// and can be resumed. A second signal exits right away.
+fn interrupt_on_signal() -> CancellationToken {
+    let interrupt = CancellationToken::new();
+
+    let cancel = interrupt.clone();
+    tokio::spawn(async move {
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                tracing::warn!("listening for SIGTERM: {}", e);
+                return;
+            }
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        tracing::warn!(
+            "interrupted, finishing the pods in progress before stopping, interrupt again to exit right away"
+        );
+        cancel.cancel();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        std::process::exit(130);
+    });
+
+    interrupt
+}
+
+/// Read the raft configuration and autopilot state from the active pod for `show`
+///
+/// Both are optional, the pods are shown with their labels without them.
+async fn raft_membership(
+    pods: &PodApi,
+    active: &str,
+    token: Secret<String>,
+) -> (Option<RaftConfiguration>, Option<AutopilotState>) {
+    let mut pf = match pods.http(active, VAULT_PORT).await {
+        Ok(pf) => pf,
+        Err(e) => {
+            tracing::warn!("reading raft configuration: {}", e);
+            return (None, None);
+        }
+    };
+
+    let raft = pf
+        .raft_configuration(token.clone())
+        .await
+        .map_err(|e| tracing::warn!("reading raft configuration: {}", e))
+        .ok();
+    let autopilot = match raft {
+        Some(_) => pf
+            .autopilot_state(token)
+            .await
+            .map_err(|e| tracing::warn!("reading autopilot state: {}", e))
+            .ok(),
+        None => None,
+    };
+
+    (raft, autopilot)
+}
+
+/// Ask the question on the terminal, refusing if stdin is not a terminal
+fn confirm_on_terminal(question: &str) -> bool {
+    use std::io::IsTerminal;
+
+    if !io::stdin().is_terminal() {
+        return false;
+    }
+
+    eprint!("{} [y/N] ", question);
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Look up the accessor of the token, so the changes made with it are logged with the accessor
+async fn attribute_token(pf: &mut HttpForwarderService<BytesBody>, token: Secret<String>) {
+    match token_accessor(pf, token).await {
+        Ok(accessor) => {
+            tracing::info!("using the token with accessor {}", accessor);
+            pf.set_token_accessor(Some(accessor));
+        }
+        Err(e) => tracing::warn!("looking up the accessor of the token: {}", e),
+    }
+}
+
 fn get_token(arg: Option<Secret<String>>) -> anyhow::Result<Secret<String>> {
     match arg {
         Some(token) => Ok(token),
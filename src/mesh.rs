@@ -0,0 +1,273 @@
+use clap::ValueEnum;
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use k8s_openapi::api::core::v1::{Container, Pod};
+
+use crate::{quit_sidecar_request, BytesBody, Flavor, HttpRequest};
+
+/// Name of the vault server container in the pods of the statefulset
+pub const VAULT_CONTAINER_NAME: &str = "vault";
+
+/// Find the container running vault among the containers of a pod (template)
+///
+/// This is the container named `name_override`, or otherwise the container named after
+/// a flavor (e.g. `vault` or `openbao`), or the only container. Sidecars like the agent
+/// injector or log shippers are never taken for the vault container.
+fn find_container<'a>(
+    containers: &'a [Container],
+    name_override: Option<&str>,
+) -> Option<&'a Container> {
+    match name_override {
+        Some(name) => containers.iter().find(|c| c.name == name),
+        None => containers
+            .iter()
+            .find(|c| {
+                Flavor::value_variants()
+                    .iter()
+                    .any(|flavor| c.name == flavor.container_name())
+            })
+            .or(match containers {
+                [only] => Some(only),
+                _ => None,
+            }),
+    }
+}
+
+/// Find the container running vault, the one named `name_override` if it is set,
+/// see `PodSelector::container`
+pub fn find_vault_container<'a>(
+    containers: &'a [Container],
+    name_override: Option<&str>,
+) -> anyhow::Result<&'a Container> {
+    find_container(containers, name_override).ok_or_else(|| match name_override {
+        Some(name) => anyhow::anyhow!("container {} not found", name),
+        None => anyhow::anyhow!(
+            "vault container not found in {}, use --container-name to select it",
+            containers
+                .iter()
+                .map(|c| c.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    })
+}
+
+/// Service mesh injecting a sidecar proxy into the vault pods
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mesh {
+    Istio,
+    Linkerd,
+}
+
+impl std::fmt::Display for Mesh {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl Mesh {
+    /// Name of the injected sidecar container
+    pub fn sidecar_container_name(&self) -> &'static str {
+        match self {
+            Mesh::Istio => "istio-proxy",
+            Mesh::Linkerd => "linkerd-proxy",
+        }
+    }
+
+    /// Annotation set on pods that got a sidecar injected
+    pub fn injection_annotation(&self) -> &'static str {
+        match self {
+            Mesh::Istio => "sidecar.istio.io/status",
+            Mesh::Linkerd => "linkerd.io/proxy-version",
+        }
+    }
+
+    /// Local port and path of the sidecar's admin endpoint that makes it exit
+    pub fn quit_endpoint(&self) -> (u16, &'static str) {
+        match self {
+            Mesh::Istio => (15020, "/quitquitquit"),
+            Mesh::Linkerd => (4191, "/shutdown"),
+        }
+    }
+
+    /// Detect the service mesh of a pod based on its containers and annotations
+    pub fn detect(pod: &Pod) -> Option<Mesh> {
+        [Mesh::Istio, Mesh::Linkerd].into_iter().find(|mesh| {
+            let has_container = pod.spec.as_ref().is_some_and(|spec| {
+                spec.containers
+                    .iter()
+                    .chain(spec.init_containers.iter().flatten())
+                    .any(|c| c.name == mesh.sidecar_container_name())
+            });
+
+            let has_annotation = pod
+                .metadata
+                .annotations
+                .as_ref()
+                .is_some_and(|a| a.contains_key(mesh.injection_annotation()));
+
+            has_container || has_annotation
+        })
+    }
+}
+
+/// Name of the container running vault in the pod, see `find_vault_container`
+pub fn vault_container_name(pod: &Pod, name_override: Option<&str>) -> anyhow::Result<String> {
+    let containers = &pod
+        .spec
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a spec"))?
+        .containers;
+
+    Ok(find_vault_container(containers, name_override)?
+        .name
+        .clone())
+}
+
+/// Make a service mesh sidecar exit
+///
+/// This is needed when running as a Job in a meshed namespace,
+/// as the Job will never complete while the sidecar is still running.
+#[async_trait::async_trait]
+pub trait QuitSidecar {
+    /// Make a service mesh sidecar exit
+    async fn quit_sidecar(&mut self, mesh: Mesh) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<T> QuitSidecar for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn quit_sidecar(&mut self, mesh: Mesh) -> anyhow::Result<()> {
+        let (_, path) = mesh.quit_endpoint();
+
+        let http_req = quit_sidecar_request(path, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!("quitting {} sidecar: {}", mesh, body));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
+    use k8s_openapi::api::core::v1::{Container, Pod};
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use super::find_container;
+    use crate::{vault_container_name, HttpForwarderService, Mesh, QuitSidecar};
+
+    async fn pod() -> Pod {
+        let file = tokio::fs::read_to_string(format!(
+            "tests/resources/installed/{}.yaml",
+            "api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-0"
+        ))
+        .await
+        .unwrap();
+
+        serde_yaml::from_str(&file).unwrap()
+    }
+
+    #[tokio::test]
+    async fn detecting_mesh_works_without_sidecar() {
+        assert_eq!(Mesh::detect(&pod().await), None);
+    }
+
+    #[tokio::test]
+    async fn detecting_mesh_works_with_sidecar_container() {
+        let mut pod = pod().await;
+
+        pod.spec.as_mut().unwrap().containers.insert(
+            0,
+            Container {
+                name: "istio-proxy".to_string(),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(Mesh::detect(&pod), Some(Mesh::Istio));
+        assert_eq!(vault_container_name(&pod, None).unwrap(), "vault");
+    }
+
+    #[test]
+    fn vault_container_is_found_by_flavor_or_override() {
+        let containers = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| Container {
+                    name: name.to_string(),
+                    ..Default::default()
+                })
+                .collect::<Vec<_>>()
+        };
+        let find = |names: &[&str], name_override| {
+            find_container(&containers(names), name_override).map(|c| c.name.clone())
+        };
+
+        assert_eq!(
+            find(&["vault-agent", "vault"], None).as_deref(),
+            Some("vault")
+        );
+        assert_eq!(
+            find(&["fluent-bit", "openbao"], None).as_deref(),
+            Some("openbao")
+        );
+        assert_eq!(find(&["server"], None).as_deref(), Some("server"));
+        assert_eq!(find(&["fluent-bit", "server"], None), None);
+        assert_eq!(
+            find(&["fluent-bit", "server"], Some("server")).as_deref(),
+            Some("server")
+        );
+        assert_eq!(find(&["vault"], Some("server")), None);
+    }
+
+    #[tokio::test]
+    async fn detecting_mesh_works_with_annotation() {
+        let mut pod = pod().await;
+
+        pod.metadata
+            .annotations
+            .get_or_insert_with(Default::default)
+            .insert("linkerd.io/proxy-version".to_string(), "stable".to_string());
+
+        assert_eq!(Mesh::detect(&pod), Some(Mesh::Linkerd));
+    }
+
+    #[tokio::test]
+    async fn quit_sidecar_calls_api() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/quitquitquit"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let outcome = client.quit_sidecar(Mesh::Istio).await;
+
+        assert!(outcome.is_ok());
+    }
+}
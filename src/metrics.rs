@@ -0,0 +1,307 @@
+use std::{collections::BTreeMap, convert::Infallible, sync::Arc};
+
+use futures_util::future::join_all;
+use http::{header, Response, StatusCode};
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::{body::Bytes, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use secrecy::Secret;
+use tokio::net::TcpListener;
+use tracing::*;
+
+use crate::{metrics_request, BytesBody, HttpRequest, PodApi, VAULT_PORT};
+
+/// Get vault pod's telemetry in the Prometheus text format
+#[async_trait::async_trait]
+pub trait GetMetrics {
+    /// Get vault pod's telemetry in the Prometheus text format
+    async fn metrics(&mut self, token: Option<Secret<String>>) -> anyhow::Result<String>;
+}
+
+#[async_trait::async_trait]
+impl<T> GetMetrics for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn metrics(&mut self, token: Option<Secret<String>>) -> anyhow::Result<String> {
+        let http_req = metrics_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("getting metrics: {}", body));
+        }
+
+        Ok(body)
+    }
+}
+
+/// Merge the metrics of multiple pods, adding a `pod` label to every sample
+///
+/// The samples of a metric family are grouped together below a single `HELP` and `TYPE`,
+/// as required by the Prometheus text format.
+pub fn merge_metrics(metrics: &[(String, String)]) -> String {
+    // family name -> (HELP and TYPE lines, samples), in order of appearance
+    let mut order: Vec<String> = vec![];
+    let mut families: BTreeMap<String, (Vec<String>, Vec<String>)> = BTreeMap::new();
+
+    for (pod, text) in metrics {
+        let mut family = String::new();
+
+        for line in text.lines() {
+            let line = line.trim_end();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (name, is_meta) = match line.strip_prefix('#') {
+                Some(comment) => {
+                    let mut words = comment.split_whitespace();
+                    match (words.next(), words.next()) {
+                        (Some("HELP" | "TYPE"), Some(name)) => (name.to_string(), true),
+                        // other comments are dropped
+                        _ => continue,
+                    }
+                }
+                None => {
+                    let name = line
+                        .split(['{', ' '])
+                        .next()
+                        .unwrap_or_default()
+                        .to_string();
+
+                    // samples of histograms and summaries have suffixes
+                    if family.is_empty() || !name.starts_with(&family) {
+                        (name, false)
+                    } else {
+                        (family.clone(), false)
+                    }
+                }
+            };
+
+            family = name.clone();
+
+            let entry = families.entry(name.clone()).or_insert_with(|| {
+                order.push(name);
+                (vec![], vec![])
+            });
+
+            if is_meta {
+                if !entry.0.iter().any(|l| l == line) {
+                    entry.0.push(line.to_string());
+                }
+            } else {
+                entry.1.push(add_pod_label(line, pod));
+            }
+        }
+    }
+
+    let mut merged = String::new();
+    for name in order {
+        let (meta, samples) = &families[&name];
+        for line in meta.iter().chain(samples.iter()) {
+            merged.push_str(line);
+            merged.push('\n');
+        }
+    }
+
+    merged
+}
+
+/// Add a `pod` label to a sample line
+fn add_pod_label(line: &str, pod: &str) -> String {
+    match line.find(['{', ' ']) {
+        Some(i) if line[i..].starts_with("{}") => {
+            format!("{}{{pod=\"{}\"}}{}", &line[..i], pod, &line[i + 2..])
+        }
+        Some(i) if line[i..].starts_with('{') => {
+            format!("{}{{pod=\"{}\",{}", &line[..i], pod, &line[i + 1..])
+        }
+        Some(i) => format!("{}{{pod=\"{}\"}}{}", &line[..i], pod, &line[i..]),
+        None => line.to_string(),
+    }
+}
+
+impl PodApi {
+    /// Scrape the metrics of all vault pods (or only the named pod) concurrently and merge them
+    ///
+    /// Pods that cannot be scraped are logged and left out.
+    pub async fn scrape_metrics(
+        &self,
+        pod: Option<&str>,
+        token: Option<Secret<String>>,
+    ) -> anyhow::Result<String> {
+        let mut names = vec![];
+        for p in self.api.list(&self.selector.to_list_params()).await?.iter() {
+            let name = p
+                .metadata
+                .name
+                .clone()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+            if pod.is_none() || pod == Some(name.as_str()) {
+                names.push(name);
+            }
+        }
+
+        if let (Some(pod), true) = (pod, names.is_empty()) {
+            anyhow::bail!("no vault pod named {} found", pod);
+        }
+
+        let metrics = join_all(names.iter().map(|name| {
+            let token = token.clone();
+            async move { self.http(name, VAULT_PORT).await?.metrics(token).await }
+        }))
+        .await;
+
+        let mut scraped = vec![];
+        for (name, metrics) in names.into_iter().zip(metrics) {
+            match metrics {
+                Ok(metrics) => scraped.push((name, metrics)),
+                Err(e) => warn!("scraping metrics of pod {}: {}", name, e),
+            }
+        }
+
+        Ok(merge_metrics(&scraped))
+    }
+}
+
+/// Serve the merged metrics of all vault pods on `/metrics`, scraping the pods on every request
+pub async fn serve_metrics(
+    pods: PodApi,
+    token: Option<Secret<String>>,
+    listener: TcpListener,
+) -> anyhow::Result<()> {
+    let state = Arc::new((pods, token));
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                let state = state.clone();
+                async move {
+                    let mut res = Response::new(Full::new(Bytes::new()));
+
+                    if req.uri().path() != "/metrics" {
+                        *res.status_mut() = StatusCode::NOT_FOUND;
+                        return Ok::<_, Infallible>(res);
+                    }
+
+                    match state.0.scrape_metrics(None, state.1.clone()).await {
+                        Ok(metrics) => {
+                            *res.body_mut() = Full::new(Bytes::from(metrics));
+                            res.headers_mut().insert(
+                                header::CONTENT_TYPE,
+                                header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                            );
+                        }
+                        Err(e) => {
+                            warn!("scraping metrics: {}", e);
+                            *res.status_mut() = StatusCode::BAD_GATEWAY;
+                            *res.body_mut() = Full::new(Bytes::from(e.to_string()));
+                        }
+                    }
+
+                    Ok(res)
+                }
+            });
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("serving connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use http::StatusCode;
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{header, method, path, query_param},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{merge_metrics, GetMetrics, HttpForwarderService};
+
+    const METRICS: &str = "# HELP vault_core_unsealed Whether vault is unsealed
+# TYPE vault_core_unsealed gauge
+vault_core_unsealed{cluster=\"vault-cluster-211d673a\"} 1
+# HELP vault_core_handle_request vault_core_handle_request
+# TYPE vault_core_handle_request summary
+vault_core_handle_request{quantile=\"0.5\"} 0.1
+vault_core_handle_request_sum 12.5
+vault_core_handle_request_count 42
+# HELP vault_runtime_alloc_bytes vault_runtime_alloc_bytes
+# TYPE vault_runtime_alloc_bytes gauge
+vault_runtime_alloc_bytes 1.2e+07
+";
+
+    #[tokio::test]
+    async fn getting_metrics_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/metrics"))
+            .and(query_param("format", "prometheus"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_string(METRICS))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let metrics = client
+            .metrics(Some(Secret::from_str("abc").unwrap()))
+            .await
+            .unwrap();
+
+        assert_eq!(metrics, METRICS);
+    }
+
+    #[test]
+    fn merging_metrics_groups_families_and_adds_pod_label() {
+        let merged = merge_metrics(&[
+            ("vault-0".to_string(), METRICS.to_string()),
+            ("vault-1".to_string(), METRICS.to_string()),
+        ]);
+
+        assert_eq!(
+            merged,
+            "# HELP vault_core_unsealed Whether vault is unsealed
+# TYPE vault_core_unsealed gauge
+vault_core_unsealed{pod=\"vault-0\",cluster=\"vault-cluster-211d673a\"} 1
+vault_core_unsealed{pod=\"vault-1\",cluster=\"vault-cluster-211d673a\"} 1
+# HELP vault_core_handle_request vault_core_handle_request
+# TYPE vault_core_handle_request summary
+vault_core_handle_request{pod=\"vault-0\",quantile=\"0.5\"} 0.1
+vault_core_handle_request_sum{pod=\"vault-0\"} 12.5
+vault_core_handle_request_count{pod=\"vault-0\"} 42
+vault_core_handle_request{pod=\"vault-1\",quantile=\"0.5\"} 0.1
+vault_core_handle_request_sum{pod=\"vault-1\"} 12.5
+vault_core_handle_request_count{pod=\"vault-1\"} 42
+# HELP vault_runtime_alloc_bytes vault_runtime_alloc_bytes
+# TYPE vault_runtime_alloc_bytes gauge
+vault_runtime_alloc_bytes{pod=\"vault-0\"} 1.2e+07
+vault_runtime_alloc_bytes{pod=\"vault-1\"} 1.2e+07
+"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use http::{header, Response, StatusCode};
+use http_body_util::Full;
+use hyper::{body::Bytes, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use k8s_openapi::api::core::v1::Pod;
+use kube::{
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Client, Resource,
+};
+use secrecy::Secret;
+use tokio::net::TcpListener;
+use tracing::*;
+
+use crate::{list_sealed_pods_with, PodApi, DEFAULT_UNSEAL_CONCURRENCY};
+
+/// Name of the operator in the events it publishes
+const OPERATOR_NAME: &str = "vault-mgmt-operator";
+
+/// How often the same pod may be unsealed automatically
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UnsealRateLimit {
+    /// unseals of a pod allowed within `window`
+    pub max: usize,
+    pub window: Duration,
+}
+
+impl Default for UnsealRateLimit {
+    fn default() -> Self {
+        Self {
+            max: 3,
+            window: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Recent automatic unseals of each pod
+#[derive(Debug, Default)]
+pub struct UnsealHistory {
+    limit: UnsealRateLimit,
+    unseals: HashMap<String, VecDeque<Instant>>,
+}
+
+impl UnsealHistory {
+    pub fn new(limit: UnsealRateLimit) -> Self {
+        Self {
+            limit,
+            unseals: HashMap::new(),
+        }
+    }
+
+    /// Record an unseal of the pod, unless it was already unsealed
+    /// `max` times within the window. Returns whether the pod may be unsealed.
+    pub fn try_unseal(&mut self, pod: &str, now: Instant) -> bool {
+        let unseals = self.unseals.entry(pod.to_string()).or_default();
+
+        while unseals
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= self.limit.window)
+        {
+            unseals.pop_front();
+        }
+
+        if unseals.len() >= self.limit.max {
+            return false;
+        }
+
+        unseals.push_back(now);
+        true
+    }
+
+    /// Time until the pod may be unsealed again
+    pub fn retry_in(&self, pod: &str, now: Instant) -> Duration {
+        self.unseals
+            .get(pod)
+            .and_then(|u| u.front())
+            .map(|t| self.limit.window.saturating_sub(now.duration_since(*t)))
+            .unwrap_or_default()
+    }
+}
+
+/// Time a reconcile may take on top of the interval before the operator is considered stuck
+const RECONCILE_GRACE: Duration = Duration::from_secs(60);
+
+/// Counters of the operator since it started
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OperatorCounters {
+    pub reconciles: u64,
+    pub reconcile_errors: u64,
+    pub unseals: u64,
+    pub unseal_failures: u64,
+    /// unseals skipped because of the `UnsealRateLimit`
+    pub unseals_throttled: u64,
+    /// time spent unsealing pods successfully
+    pub unseal_seconds: f64,
+}
+
+/// State of the operator served on `/healthz`, `/readyz` and `/metrics`
+#[derive(Debug)]
+pub struct OperatorStats {
+    started: Instant,
+    counters: Mutex<OperatorCounters>,
+    last_reconcile: Mutex<Option<Instant>>,
+}
+
+impl OperatorStats {
+    pub fn new(started: Instant) -> Self {
+        Self {
+            started,
+            counters: Mutex::new(OperatorCounters::default()),
+            last_reconcile: Mutex::new(None),
+        }
+    }
+
+    fn record(&self, f: impl FnOnce(&mut OperatorCounters)) {
+        f(&mut self.counters.lock().expect("counters are not poisoned"));
+    }
+
+    fn reconciled(&self, now: Instant) {
+        self.record(|c| c.reconciles += 1);
+        *self.last_reconcile.lock().expect("time is not poisoned") = Some(now);
+    }
+
+    pub fn counters(&self) -> OperatorCounters {
+        self.counters
+            .lock()
+            .expect("counters are not poisoned")
+            .clone()
+    }
+
+    /// Alive unless the last successful reconcile (or the start) is older than `stale_after`,
+    /// e.g. because the Kubernetes API keeps failing or a reconcile hangs
+    pub fn is_healthy(&self, now: Instant, stale_after: Duration) -> bool {
+        let last = self
+            .last_reconcile
+            .lock()
+            .expect("time is not poisoned")
+            .unwrap_or(self.started);
+
+        now.duration_since(last) <= stale_after
+    }
+
+    /// Ready once a reconcile succeeded, as long as it is healthy
+    pub fn is_ready(&self, now: Instant, stale_after: Duration) -> bool {
+        self.last_reconcile
+            .lock()
+            .expect("time is not poisoned")
+            .is_some_and(|last| now.duration_since(last) <= stale_after)
+    }
+
+    /// Counters in the Prometheus text format
+    pub fn to_prometheus(&self) -> String {
+        let c = self.counters();
+        let mut text = String::new();
+
+        for (name, help, kind, value) in [
+            (
+                "vault_mgmt_operator_reconciles_total",
+                "Successful checks for sealed pods",
+                "counter",
+                c.reconciles.to_string(),
+            ),
+            (
+                "vault_mgmt_operator_reconcile_errors_total",
+                "Checks for sealed pods failed by the Kubernetes API",
+                "counter",
+                c.reconcile_errors.to_string(),
+            ),
+            (
+                "vault_mgmt_operator_unseals_total",
+                "Pods unsealed by the operator",
+                "counter",
+                c.unseals.to_string(),
+            ),
+            (
+                "vault_mgmt_operator_unseal_failures_total",
+                "Failed attempts to unseal a pod",
+                "counter",
+                c.unseal_failures.to_string(),
+            ),
+            (
+                "vault_mgmt_operator_unseals_throttled_total",
+                "Unseals skipped because the pod keeps resealing",
+                "counter",
+                c.unseals_throttled.to_string(),
+            ),
+            (
+                "vault_mgmt_operator_unseal_seconds_total",
+                "Time spent unsealing pods",
+                "counter",
+                c.unseal_seconds.to_string(),
+            ),
+        ] {
+            text.push_str(&format!(
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            ));
+        }
+
+        text
+    }
+}
+
+/// Serve `/healthz`, `/readyz` and `/metrics` of the operator reconciling every `interval`
+pub async fn serve_operator_endpoints(
+    stats: Arc<OperatorStats>,
+    interval: Duration,
+    listener: TcpListener,
+) -> anyhow::Result<()> {
+    let stale_after = interval * 2 + RECONCILE_GRACE;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let stats = stats.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                let stats = stats.clone();
+                async move {
+                    let now = Instant::now();
+                    let check = |ok: bool| match ok {
+                        true => (StatusCode::OK, "ok".to_string()),
+                        false => (StatusCode::SERVICE_UNAVAILABLE, "stale".to_string()),
+                    };
+                    let (status, body) = match req.uri().path() {
+                        "/healthz" => check(stats.is_healthy(now, stale_after)),
+                        "/readyz" => check(stats.is_ready(now, stale_after)),
+                        "/metrics" => (StatusCode::OK, stats.to_prometheus()),
+                        _ => (StatusCode::NOT_FOUND, String::new()),
+                    };
+
+                    let mut res = Response::new(Full::new(Bytes::from(body)));
+                    *res.status_mut() = status;
+                    if req.uri().path() == "/metrics" {
+                        res.headers_mut().insert(
+                            header::CONTENT_TYPE,
+                            header::HeaderValue::from_static("text/plain; version=0.0.4"),
+                        );
+                    }
+
+                    Ok::<_, Infallible>(res)
+                }
+            });
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                warn!("serving connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Keeps the vault pods unsealed, unsealing pods that got sealed (e.g. after a restart)
+///
+/// Pods that keep resealing are only unsealed `UnsealRateLimit::max` times within the window,
+/// afterwards a warning event is published for the pod instead of fighting e.g. a crash loop.
+pub struct Operator {
+    client: Client,
+    pods: PodApi,
+    keys: Vec<Secret<String>>,
+    history: UnsealHistory,
+    throttled: HashSet<String>,
+    stats: Arc<OperatorStats>,
+    concurrency: usize,
+}
+
+impl Operator {
+    pub fn new(
+        client: Client,
+        pods: PodApi,
+        keys: Vec<Secret<String>>,
+        limit: UnsealRateLimit,
+    ) -> Self {
+        Self {
+            client,
+            pods,
+            keys,
+            history: UnsealHistory::new(limit),
+            throttled: HashSet::new(),
+            stats: Arc::new(OperatorStats::new(Instant::now())),
+            concurrency: DEFAULT_UNSEAL_CONCURRENCY,
+        }
+    }
+
+    /// Unseal at most `concurrency` sealed pods at once
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency;
+        self
+    }
+
+    /// Count into `stats` shared with the operators of other clusters, see `run_operators`
+    pub fn shared_stats(mut self, stats: Arc<OperatorStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// State of the operator, see `serve_operator_endpoints`
+    pub fn stats(&self) -> Arc<OperatorStats> {
+        self.stats.clone()
+    }
+
+    /// Reconcile the pods every `interval`, failed reconciles are retried in the next interval
+    /// and make `/healthz` fail once the last successful one is too old
+    pub async fn run(mut self, interval: Duration) -> anyhow::Result<()> {
+        info!(
+            "unsealing sealed pods every {}",
+            humantime::format_duration(interval)
+        );
+
+        loop {
+            match self.reconcile().await {
+                Ok(()) => self.stats.reconciled(Instant::now()),
+                Err(e) => {
+                    self.stats.record(|c| c.reconcile_errors += 1);
+                    warn!("checking for sealed pods: {}", e);
+                }
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Unseal the sealed pods the rate limit allows to be unsealed, several at once
+    pub async fn reconcile(&mut self) -> anyhow::Result<()> {
+        let sealed = list_sealed_pods_with(&self.pods.api, &self.pods.selector).await?;
+        let now = Instant::now();
+
+        let mut unsealing = vec![];
+        for pod in sealed {
+            let name = pod
+                .metadata
+                .name
+                .clone()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+            if !self.history.try_unseal(&name, now) {
+                self.stats.record(|c| c.unseals_throttled += 1);
+                if self.throttled.insert(name.clone()) {
+                    let note = format!(
+                        "pod {} keeps resealing, it was unsealed {} times within {}, not unsealing it for {}",
+                        name,
+                        self.history.limit.max,
+                        humantime::format_duration(self.history.limit.window),
+                        humantime::format_duration(Duration::from_secs(
+                            self.history.retry_in(&name, now).as_secs()
+                        ))
+                    );
+                    warn!("{}", note);
+                    self.publish(&pod, EventType::Warning, "Resealing", note)
+                        .await;
+                }
+                continue;
+            }
+            self.throttled.remove(&name);
+
+            info!("unsealing pod {}", name);
+            unsealing.push(pod);
+        }
+
+        let names = unsealing
+            .iter()
+            .filter_map(|p| p.metadata.name.clone())
+            .collect();
+        let results = self
+            .pods
+            .unseal_pods(names, &self.keys, self.concurrency)
+            .await;
+
+        for (pod, (name, result)) in unsealing.iter().zip(results) {
+            match result {
+                Ok(elapsed) => {
+                    self.stats.record(|c| {
+                        c.unseals += 1;
+                        c.unseal_seconds += elapsed.as_secs_f64();
+                    });
+                    self.publish(
+                        pod,
+                        EventType::Normal,
+                        "Unsealed",
+                        format!("pod {} was unsealed by {}", name, OPERATOR_NAME),
+                    )
+                    .await
+                }
+                Err(e) => {
+                    self.stats.record(|c| c.unseal_failures += 1);
+                    warn!("unsealing pod {}: {}", name, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Publish an event for the pod, failures are only logged
+    async fn publish(&self, pod: &Pod, type_: EventType, reason: &str, note: String) {
+        let recorder = Recorder::new(
+            self.client.clone(),
+            Reporter::from(OPERATOR_NAME),
+            pod.object_ref(&()),
+        );
+
+        if let Err(e) = recorder
+            .publish(Event {
+                type_,
+                reason: reason.to_string(),
+                note: Some(note),
+                action: "Unseal".to_string(),
+                secondary: None,
+            })
+            .await
+        {
+            warn!("publishing event {}: {}", reason, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use http::StatusCode;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+
+    use crate::{
+        serve_operator_endpoints, HttpForwarderService, HttpRequest, OperatorStats, UnsealHistory,
+        UnsealRateLimit,
+    };
+
+    #[test]
+    fn unseals_are_limited_within_the_window() {
+        let mut history = UnsealHistory::new(UnsealRateLimit {
+            max: 2,
+            window: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+
+        assert!(history.try_unseal("vault-0", start));
+        assert!(history.try_unseal("vault-0", start + Duration::from_secs(10)));
+        assert!(!history.try_unseal("vault-0", start + Duration::from_secs(20)));
+        assert_eq!(
+            history.retry_in("vault-0", start + Duration::from_secs(20)),
+            Duration::from_secs(40)
+        );
+
+        // other pods have their own limit
+        assert!(history.try_unseal("vault-1", start + Duration::from_secs(20)));
+
+        // the first unseal left the window
+        assert!(history.try_unseal("vault-0", start + Duration::from_secs(60)));
+        assert!(!history.try_unseal("vault-0", start + Duration::from_secs(61)));
+    }
+
+    #[test]
+    fn operator_is_ready_after_a_reconcile_and_unhealthy_when_stale() {
+        let start = Instant::now();
+        let stale_after = Duration::from_secs(60);
+        let stats = OperatorStats::new(start);
+
+        assert!(stats.is_healthy(start + Duration::from_secs(30), stale_after));
+        assert!(!stats.is_ready(start + Duration::from_secs(30), stale_after));
+        assert!(!stats.is_healthy(start + Duration::from_secs(90), stale_after));
+
+        stats.reconciled(start + Duration::from_secs(80));
+        assert!(stats.is_healthy(start + Duration::from_secs(90), stale_after));
+        assert!(stats.is_ready(start + Duration::from_secs(90), stale_after));
+
+        assert!(!stats.is_healthy(start + Duration::from_secs(150), stale_after));
+        assert!(!stats.is_ready(start + Duration::from_secs(150), stale_after));
+    }
+
+    #[tokio::test]
+    async fn operator_endpoints_are_served() {
+        let stats = Arc::new(OperatorStats::new(Instant::now()));
+        stats.reconciled(Instant::now());
+        stats.record(|c| {
+            c.unseals += 2;
+            c.unseal_seconds += 1.5;
+        });
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_operator_endpoints(
+            stats,
+            Duration::from_secs(10),
+            listener,
+        ));
+
+        let mut client =
+            HttpForwarderService::http(tokio::net::TcpStream::connect(address).await.unwrap())
+                .await
+                .unwrap();
+        let request = |path: &str| {
+            hyper::Request::builder()
+                .uri(path)
+                .header("Host", "127.0.0.1")
+                .body(Empty::<Bytes>::new().boxed())
+                .unwrap()
+        };
+
+        assert_eq!(
+            client
+                .send_request(request("/healthz"))
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            client
+                .send_request(request("/readyz"))
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            client
+                .send_request(request("/other"))
+                .await
+                .unwrap()
+                .status(),
+            StatusCode::NOT_FOUND
+        );
+
+        let metrics = client.send_request(request("/metrics")).await.unwrap();
+        assert_eq!(metrics.status(), StatusCode::OK);
+        let body = String::from_utf8(metrics.body().to_vec()).unwrap();
+        assert!(body.contains("\nvault_mgmt_operator_reconciles_total 1\n"));
+        assert!(body.contains("\nvault_mgmt_operator_unseals_total 2\n"));
+        assert!(body.contains("\nvault_mgmt_operator_unseal_seconds_total 1.5\n"));
+        assert!(body.contains("# TYPE vault_mgmt_operator_reconcile_errors_total counter\n"));
+    }
+}
@@ -0,0 +1,335 @@
+use std::{collections::HashMap, future::Future, str::FromStr, time::Duration};
+
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::{api::ListParams, Api, Client};
+use tokio::task::JoinHandle;
+use tracing::*;
+
+use crate::{ClusterConfig, Flavor, KeysFrom, PodSelector, LABEL_KEY_NAME};
+
+/// Annotation of a statefulset with the uri of its key source, see `KeysFrom`
+pub const ANNOTATION_KEYS_FROM: &str = "vault-mgmt.io/keys-from";
+
+/// Statefulsets the operator discovers when watching namespaces without a selector
+pub const DEFAULT_STATEFULSET_SELECTOR: &str = "app.kubernetes.io/name in (vault,openbao)";
+
+/// Cluster of a statefulset found by `discover_clusters`, named `<namespace>/<statefulset>`
+///
+/// The flavor is taken from the `app.kubernetes.io/name` label, the key source from the
+/// `vault-mgmt.io/keys-from` annotation.
+pub fn cluster_of_statefulset(sts: &StatefulSet) -> anyhow::Result<ClusterConfig> {
+    let namespace = sts
+        .metadata
+        .namespace
+        .clone()
+        .ok_or(anyhow::anyhow!("statefulset does not have a namespace"))?;
+    let statefulset = sts
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("statefulset does not have a name"))?;
+
+    let flavor = match sts
+        .metadata
+        .labels
+        .as_ref()
+        .and_then(|l| l.get(LABEL_KEY_NAME))
+        .map(String::as_str)
+    {
+        Some("openbao") => Flavor::Openbao,
+        _ => Flavor::Vault,
+    };
+    let keys_from = sts
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|a| a.get(ANNOTATION_KEYS_FROM))
+        .map(|from| {
+            KeysFrom::from_str(from).map_err(|e| {
+                anyhow::anyhow!(
+                    "annotation {} of statefulset {}/{}: {}",
+                    ANNOTATION_KEYS_FROM,
+                    namespace,
+                    statefulset,
+                    e
+                )
+            })
+        })
+        .transpose()?;
+
+    Ok(ClusterConfig {
+        name: format!("{}/{}", namespace, statefulset),
+        namespace,
+        statefulset,
+        flavor,
+        keys_secret_uri: None,
+        key_cmd: None,
+        keys_from,
+        container: None,
+    })
+}
+
+/// Selector of the pods of the statefulset, so several clusters in a namespace
+/// are told apart by the labels of their pod template
+pub fn statefulset_pod_selector(sts: &StatefulSet, flavor: Flavor) -> PodSelector {
+    sts.spec
+        .iter()
+        .flat_map(|s| s.selector.match_labels.iter().flatten())
+        .filter(|(key, _)| key.as_str() != LABEL_KEY_NAME)
+        .fold(
+            PodSelector::default().flavor(flavor),
+            |selector, (key, value)| selector.label(key, value),
+        )
+}
+
+/// Clusters of the statefulsets matching the label selector in the namespaces,
+/// in all namespaces if none are given
+///
+/// Statefulsets with an invalid `vault-mgmt.io/keys-from` annotation are skipped with a warning.
+pub async fn discover_clusters(
+    client: Client,
+    namespaces: &[String],
+    label_selector: &str,
+) -> anyhow::Result<Vec<ClusterConfig>> {
+    let params = ListParams::default().labels(label_selector);
+
+    let mut stss = Vec::new();
+    match namespaces.is_empty() {
+        true => stss.extend(Api::<StatefulSet>::all(client).list(&params).await?),
+        false => {
+            for namespace in namespaces {
+                let api: Api<StatefulSet> = Api::namespaced(client.clone(), namespace);
+                stss.extend(api.list(&params).await?);
+            }
+        }
+    }
+
+    Ok(stss
+        .iter()
+        .filter_map(|sts| match cluster_of_statefulset(sts) {
+            Ok(cluster) => Some(cluster),
+            Err(e) => {
+                warn!("skipping statefulset: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Clusters to start and names of the clusters to stop, so the running clusters match the
+/// discovered ones. Clusters whose config changed (e.g. their key source) are restarted.
+pub fn operator_changes(
+    running: &HashMap<String, ClusterConfig>,
+    discovered: &[ClusterConfig],
+) -> (Vec<ClusterConfig>, Vec<String>) {
+    let start = discovered
+        .iter()
+        .filter(|c| running.get(&c.name) != Some(c))
+        .cloned()
+        .collect();
+
+    let mut stop: Vec<_> = running
+        .iter()
+        .filter(|(name, config)| !discovered.iter().any(|c| c == *config && &c.name == *name))
+        .map(|(name, _)| name.clone())
+        .collect();
+    stop.sort();
+
+    (start, stop)
+}
+
+/// Keep an independent operator running for each cluster returned by `discover`,
+/// which is called again every `rediscover` to pick up added and removed clusters
+///
+/// `start` runs the operator of a cluster, it is restarted after `rediscover` when it fails
+/// (e.g. because its keys cannot be read), without affecting the other clusters.
+pub async fn run_operators<D, DFut, F, Fut>(discover: D, start: F, rediscover: Duration)
+where
+    D: Fn() -> DFut,
+    DFut: Future<Output = anyhow::Result<Vec<ClusterConfig>>>,
+    F: Fn(ClusterConfig) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let mut running: HashMap<String, ClusterConfig> = HashMap::new();
+    let mut tasks: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        match discover().await {
+            Ok(discovered) => {
+                let (start_clusters, stop) = operator_changes(&running, &discovered);
+
+                for name in stop {
+                    info!("stopping operator of cluster {}", name);
+                    running.remove(&name);
+                    if let Some(task) = tasks.remove(&name) {
+                        task.abort();
+                    }
+                }
+
+                for cluster in start_clusters {
+                    info!(
+                        "starting operator of cluster {} (statefulset {} in namespace {})",
+                        cluster.name, cluster.statefulset, cluster.namespace
+                    );
+                    let name = cluster.name.clone();
+                    let operator = start(cluster.clone());
+                    running.insert(name.clone(), cluster);
+                    tasks.insert(
+                        name.clone(),
+                        tokio::spawn(async move {
+                            if let Err(e) = operator.await {
+                                warn!("operator of cluster {}: {}", name, e);
+                            }
+                        }),
+                    );
+                }
+
+                // failed operators are started again
+                tasks.retain(|name, task| match task.is_finished() {
+                    true => {
+                        running.remove(name);
+                        false
+                    }
+                    false => true,
+                });
+            }
+            Err(e) => warn!("discovering clusters: {}", e),
+        }
+
+        tokio::time::sleep(rediscover).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use k8s_openapi::api::apps::v1::StatefulSet;
+
+    use crate::{
+        cluster_of_statefulset, operator_changes, statefulset_pod_selector, ClusterConfig, ExecIn,
+        Flavor, KeysFrom,
+    };
+
+    fn statefulset(name: &str, annotations: serde_json::Value) -> StatefulSet {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": name,
+                "namespace": "team-a",
+                "labels": { "app.kubernetes.io/name": "openbao" },
+                "annotations": annotations,
+            },
+            "spec": {
+                "selector": {
+                    "matchLabels": {
+                        "app.kubernetes.io/name": "openbao",
+                        "app.kubernetes.io/instance": name,
+                        "component": "server",
+                    },
+                },
+                "serviceName": "openbao-internal",
+                "template": {},
+            },
+        }))
+        .unwrap()
+    }
+
+    fn cluster(name: &str, keys_from: Option<&str>) -> ClusterConfig {
+        ClusterConfig {
+            name: name.to_string(),
+            namespace: "team-a".to_string(),
+            statefulset: name.to_string(),
+            flavor: Flavor::Vault,
+            keys_secret_uri: None,
+            key_cmd: None,
+            keys_from: keys_from.map(|f| f.parse().unwrap()),
+            container: None,
+        }
+    }
+
+    #[test]
+    fn statefulsets_are_turned_into_clusters() {
+        let sts = statefulset(
+            "bao",
+            serde_json::json!({ "vault-mgmt.io/keys-from": "k8s://bao-keys" }),
+        );
+
+        let cluster = cluster_of_statefulset(&sts).unwrap();
+        assert_eq!(cluster.name, "team-a/bao");
+        assert_eq!(cluster.namespace, "team-a");
+        assert_eq!(cluster.statefulset, "bao");
+        assert_eq!(cluster.flavor, Flavor::Openbao);
+        assert_eq!(
+            cluster.keys_source().unwrap(),
+            Some(KeysFrom::new("k8s", "bao-keys"))
+        );
+
+        assert_eq!(
+            cluster_of_statefulset(&statefulset("bao", serde_json::json!({})))
+                .unwrap()
+                .keys_from,
+            None
+        );
+        assert!(cluster_of_statefulset(&statefulset(
+            "bao",
+            serde_json::json!({ "vault-mgmt.io/keys-from": "bao-keys" })
+        ))
+        .is_err());
+
+        assert_eq!(
+            statefulset_pod_selector(&sts, Flavor::Openbao).to_label_selector(),
+            "app.kubernetes.io/name=openbao,app.kubernetes.io/instance=bao,component=server"
+        );
+    }
+
+    #[test]
+    fn statefulsets_in_one_namespace_select_only_their_pods() {
+        let a = statefulset("bao-a", serde_json::json!({}));
+        let b = statefulset("bao-b", serde_json::json!({}));
+
+        let standby = |sts| {
+            statefulset_pod_selector(sts, Flavor::Openbao)
+                .role(ExecIn::Standby)
+                .to_label_selector()
+        };
+        assert_eq!(
+            standby(&a),
+            "app.kubernetes.io/name=openbao,openbao-active=false,app.kubernetes.io/instance=bao-a,component=server"
+        );
+        assert_eq!(
+            standby(&b),
+            "app.kubernetes.io/name=openbao,openbao-active=false,app.kubernetes.io/instance=bao-b,component=server"
+        );
+    }
+
+    #[test]
+    fn only_changed_clusters_are_started_and_stopped() {
+        let running: HashMap<_, _> = [
+            ("a".to_string(), cluster("a", None)),
+            ("b".to_string(), cluster("b", None)),
+            ("c".to_string(), cluster("c", None)),
+        ]
+        .into_iter()
+        .collect();
+
+        let (start, stop) = operator_changes(
+            &running,
+            &[
+                cluster("a", None),
+                cluster("b", Some("k8s://b-keys")),
+                cluster("d", None),
+            ],
+        );
+
+        assert_eq!(
+            start,
+            vec![cluster("b", Some("k8s://b-keys")), cluster("d", None)]
+        );
+        assert_eq!(stop, vec!["b".to_string(), "c".to_string()]);
+
+        assert_eq!(
+            operator_changes(&HashMap::new(), &[]),
+            (Vec::new(), Vec::new())
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use tokio::process::Command;
+use tracing::*;
+
+use crate::{Flavor, PodSelector, Transport};
+
+/// Prefix of the executables run for unknown subcommands,
+/// e.g. `vault-mgmt-backup` for `vault-mgmt backup`
+pub const PLUGIN_PREFIX: &str = "vault-mgmt-";
+
+/// Connection settings of vault-mgmt passed to a plugin in `VAULT_MGMT_*` environment variables
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginContext {
+    pub namespace: String,
+    pub statefulset: String,
+    pub domain: String,
+    pub tls: bool,
+    pub flavor: Flavor,
+    pub selector: PodSelector,
+    pub transport: Transport,
+    pub log_level: String,
+    /// config file given with `--config`
+    pub config: Option<PathBuf>,
+}
+
+impl PluginContext {
+    /// Environment variables of the plugin, the token is passed on in `VAULT_TOKEN` as usual
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("VAULT_MGMT_NAMESPACE", self.namespace.clone()),
+            ("VAULT_MGMT_STATEFULSET", self.statefulset.clone()),
+            ("VAULT_MGMT_DOMAIN", self.domain.clone()),
+            ("VAULT_MGMT_TLS", self.tls.to_string()),
+            ("VAULT_MGMT_FLAVOR", self.flavor.to_string()),
+            ("VAULT_MGMT_POD_SELECTOR", self.selector.to_label_selector()),
+            ("VAULT_MGMT_TRANSPORT", self.transport.to_string()),
+            ("VAULT_MGMT_LOG_LEVEL", self.log_level.clone()),
+        ];
+        if let Some(config) = &self.config {
+            env.push(("VAULT_MGMT_CONFIG", config.display().to_string()));
+        }
+
+        env
+    }
+}
+
+/// Find the executable `vault-mgmt-<name>` of the plugin in the given search path
+pub fn find_plugin_in(name: &str, paths: impl AsRef<OsStr>) -> anyhow::Result<PathBuf> {
+    // a name with a path would run an arbitrary executable
+    if name.is_empty() || Path::new(name).components().count() != 1 {
+        anyhow::bail!("{} is not a valid command", name);
+    }
+
+    let cwd = std::env::current_dir()?;
+    which::which_in(format!("{}{}", PLUGIN_PREFIX, name), Some(paths), cwd).map_err(|_| {
+        anyhow::anyhow!(
+            "unknown command {}, no plugin {}{} found on PATH",
+            name,
+            PLUGIN_PREFIX,
+            name
+        )
+    })
+}
+
+/// Find the executable `vault-mgmt-<name>` of the plugin on `PATH`
+pub fn find_plugin(name: &str) -> anyhow::Result<PathBuf> {
+    find_plugin_in(name, std::env::var_os("PATH").unwrap_or_default())
+}
+
+/// Run the plugin with the arguments after its name and the context in its environment,
+/// sharing stdin, stdout and stderr with vault-mgmt
+pub async fn run_plugin(
+    path: &Path,
+    args: &[OsString],
+    context: &PluginContext,
+) -> anyhow::Result<ExitStatus> {
+    debug!("running plugin {}", path.display());
+
+    Command::new(path)
+        .args(args)
+        .envs(context.env())
+        .status()
+        .await
+        .map_err(|e| anyhow::anyhow!("running plugin {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use crate::{find_plugin_in, run_plugin, Flavor, PluginContext, PodSelector, Transport};
+
+    #[tokio::test]
+    async fn plugin_is_found_in_path_and_gets_the_context() {
+        let dir =
+            std::env::temp_dir().join(format!("vault-mgmt-plugins-{}", rand::random::<u32>()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+
+        let out = dir.join("out");
+        let plugin = dir.join("vault-mgmt-hello");
+        tokio::fs::write(
+            &plugin,
+            format!(
+                "#!/bin/sh\necho \"$1 $VAULT_MGMT_NAMESPACE $VAULT_MGMT_FLAVOR\" > {}\n",
+                out.display()
+            ),
+        )
+        .await
+        .unwrap();
+        tokio::fs::set_permissions(&plugin, std::fs::Permissions::from_mode(0o755))
+            .await
+            .unwrap();
+
+        assert!(find_plugin_in("missing", &dir).is_err());
+        assert!(find_plugin_in("../vault-mgmt-hello", &dir).is_err());
+
+        let path = find_plugin_in("hello", &dir).unwrap();
+        assert_eq!(path, plugin);
+
+        let context = PluginContext {
+            namespace: "vault-eu".to_string(),
+            statefulset: "vault".to_string(),
+            domain: "vault".to_string(),
+            tls: true,
+            flavor: Flavor::Openbao,
+            selector: PodSelector::default(),
+            transport: Transport::Auto,
+            log_level: "info".to_string(),
+            config: None,
+        };
+        let status = run_plugin(&path, &["world".into()], &context)
+            .await
+            .unwrap();
+
+        assert!(status.success());
+        assert_eq!(
+            tokio::fs::read_to_string(&out).await.unwrap(),
+            "world vault-eu openbao\n"
+        );
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
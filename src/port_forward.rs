@@ -0,0 +1,54 @@
+use tokio::net::TcpListener;
+use tracing::*;
+
+use crate::{ExecIn, PodApi, VAULT_PORT};
+
+impl PodApi {
+    /// Get the name of the pod labelled as active
+    pub async fn active_pod_name(&self) -> anyhow::Result<String> {
+        let active = self
+            .api
+            .list(&self.selector.clone().role(ExecIn::Active).to_list_params())
+            .await?;
+
+        active
+            .items
+            .into_iter()
+            .next()
+            .ok_or(anyhow::anyhow!(
+                "no active vault pod found. is vault sealed?"
+            ))?
+            .metadata
+            .name
+            .ok_or(anyhow::anyhow!("pod does not have a name"))
+    }
+}
+
+/// Forward all connections accepted by the listener to the vault port of the active pod
+///
+/// The active pod is resolved for every new connection, so clients reconnecting after a
+/// leadership change reach the new active pod. Established connections are not moved.
+/// TLS is not terminated, the client talks to vault directly.
+pub async fn forward_to_active(pods: PodApi, listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (mut client, peer) = listener.accept().await?;
+
+        let pods = pods.clone();
+
+        tokio::spawn(async move {
+            let forward = async {
+                let active = pods.active_pod_name().await?;
+                debug!("forwarding connection from {} to pod {}", peer, active);
+
+                let mut upstream = pods.stream(&active, VAULT_PORT).await?;
+                tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+
+                anyhow::Ok(())
+            };
+
+            if let Err(e) = forward.await {
+                warn!("forwarding connection from {}: {}", peer, e);
+            }
+        });
+    }
+}
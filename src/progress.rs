@@ -0,0 +1,137 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+use kube::api::{Patch, PatchParams};
+
+use crate::PodApi;
+
+/// Annotation of the statefulset storing the progress of an interrupted rollout
+pub const ANNOTATION_UPGRADE_PROGRESS: &str = "vault-mgmt/upgrade-progress";
+
+/// How far a pod got during a rollout
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpgradePhase {
+    /// the pod is being upgraded or restarted
+    Started,
+    /// the pod was upgraded or restarted and is ready
+    Done,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PodProgress {
+    pub phase: UpgradePhase,
+    /// RFC 3339 timestamp of the last phase change
+    pub updated: String,
+}
+
+/// Progress of a rollout, persisted so an interrupted rollout can be resumed
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct UpgradeProgress {
+    /// what the rollout does, e.g. `upgrade to 1.14.0` or `restart`
+    pub rollout: String,
+    /// RFC 3339 timestamp of the start of the rollout
+    pub started: String,
+    #[serde(default)]
+    pub pods: BTreeMap<String, PodProgress>,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+impl UpgradeProgress {
+    pub fn new(rollout: &str) -> Self {
+        Self {
+            rollout: rollout.to_string(),
+            started: now(),
+            pods: BTreeMap::new(),
+        }
+    }
+
+    /// Set the phase of the pod
+    pub fn set(&mut self, pod: &str, phase: UpgradePhase) {
+        self.pods.insert(
+            pod.to_string(),
+            PodProgress {
+                phase,
+                updated: now(),
+            },
+        );
+    }
+
+    /// Returns true if the pod was already upgraded or restarted
+    pub fn is_done(&self, pod: &str) -> bool {
+        self.pods
+            .get(pod)
+            .is_some_and(|p| p.phase == UpgradePhase::Done)
+    }
+}
+
+impl PodApi {
+    /// Read the progress of an interrupted rollout from the annotation of the statefulset
+    pub(crate) async fn read_progress(&self) -> anyhow::Result<Option<UpgradeProgress>> {
+        let Some((api, name)) = &self.statefulset else {
+            return Ok(None);
+        };
+
+        let sts = api.get(name).await?;
+        match sts
+            .metadata
+            .annotations
+            .as_ref()
+            .and_then(|a| a.get(ANNOTATION_UPGRADE_PROGRESS))
+        {
+            Some(progress) => Ok(Some(serde_json::from_str(progress).map_err(|e| {
+                anyhow::anyhow!("parsing annotation {}: {}", ANNOTATION_UPGRADE_PROGRESS, e)
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the progress of a rollout in the annotation of the statefulset, `None` removes it
+    pub(crate) async fn write_progress(
+        &self,
+        progress: Option<&UpgradeProgress>,
+    ) -> anyhow::Result<()> {
+        let Some((api, name)) = &self.statefulset else {
+            return Ok(());
+        };
+
+        let value = progress.map(serde_json::to_string).transpose()?;
+
+        api.patch(
+            name,
+            &PatchParams::default(),
+            &Patch::Merge(serde_json::json!({
+                "metadata": {
+                    "annotations": {
+                        ANNOTATION_UPGRADE_PROGRESS: value,
+                    },
+                },
+            })),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{UpgradePhase, UpgradeProgress};
+
+    #[test]
+    fn progress_round_trips_through_json() {
+        let mut progress = UpgradeProgress::new("upgrade to 1.14.0");
+        progress.set("vault-1", UpgradePhase::Done);
+        progress.set("vault-2", UpgradePhase::Started);
+
+        let parsed: UpgradeProgress =
+            serde_json::from_str(&serde_json::to_string(&progress).unwrap()).unwrap();
+
+        assert_eq!(parsed, progress);
+        assert!(parsed.is_done("vault-1"));
+        assert!(!parsed.is_done("vault-2"));
+        assert!(!parsed.is_done("vault-0"));
+    }
+}
@@ -0,0 +1,297 @@
+use std::{convert::Infallible, sync::Arc, time::Duration};
+
+use http::{header, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Request,
+};
+use hyper_util::rt::TokioIo;
+use tokio::{net::TcpListener, sync::Mutex};
+use tracing::*;
+
+use crate::{BytesBody, HttpForwarderService, HttpRequest, PodApi, VAULT_PORT};
+
+/// Connect to the vault API that requests should be sent to
+#[async_trait::async_trait]
+pub trait Connect {
+    async fn connect(&self) -> anyhow::Result<HttpForwarderService<BytesBody>>;
+}
+
+/// Connect to the active pod
+#[async_trait::async_trait]
+impl Connect for PodApi {
+    async fn connect(&self) -> anyhow::Result<HttpForwarderService<BytesBody>> {
+        let active = self.active_pod_name().await?;
+        debug!("proxying to pod {}", active);
+
+        self.http(&active, VAULT_PORT).await
+    }
+}
+
+/// Local HTTP reverse proxy to the vault API
+///
+/// Each client connection gets its own upstream connection, which is re-established
+/// (re-resolving the active pod) when it breaks or a standby pod answers with a redirect.
+pub struct Proxy<C> {
+    connector: C,
+    retries: usize,
+}
+
+impl<C> Proxy<C>
+where
+    C: Connect + Send + Sync + 'static,
+{
+    pub fn new(connector: C) -> Self {
+        Self {
+            connector,
+            retries: 3,
+        }
+    }
+
+    /// Number of times a request is retried on a new upstream connection
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Serve HTTP on all connections accepted by the listener
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        let proxy = Arc::new(self);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let proxy = proxy.clone();
+
+            tokio::spawn(async move {
+                let upstream = Arc::new(Mutex::new(None));
+
+                let service = service_fn(move |req| {
+                    let proxy = proxy.clone();
+                    let upstream = upstream.clone();
+                    async move { Ok::<_, Infallible>(proxy.forward(&upstream, req).await) }
+                });
+
+                if let Err(e) = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    warn!("serving connection from {}: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    async fn forward(
+        &self,
+        upstream: &Mutex<Option<HttpForwarderService<BytesBody>>>,
+        req: Request<Incoming>,
+    ) -> Response<Full<Bytes>> {
+        let (parts, body) = req.into_parts();
+
+        // the body is buffered, so the request can be retried
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+        };
+
+        let path = parts
+            .uri
+            .path_and_query()
+            .map(|p| p.as_str())
+            .unwrap_or("/");
+
+        let mut upstream = upstream.lock().await;
+        let mut last_error = String::new();
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                // give the cluster time to settle after a leadership change
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+
+            if upstream.is_none() {
+                match self.connector.connect().await {
+                    Ok(conn) => *upstream = Some(conn),
+                    Err(e) => {
+                        warn!("connecting to vault: {}", e);
+                        last_error = e.to_string();
+                        continue;
+                    }
+                }
+            }
+
+            let mut builder = Request::builder().method(parts.method.clone()).uri(path);
+            for (name, value) in parts.headers.iter() {
+                if !is_hop_by_hop(name) && name != header::HOST {
+                    builder = builder.header(name, value);
+                }
+            }
+
+            let req = match builder
+                .header(header::HOST, "127.0.0.1")
+                .body(Full::new(body.clone()).boxed())
+            {
+                Ok(req) => req,
+                Err(e) => return error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+            };
+
+            let conn = upstream.as_mut().expect("connected above");
+
+            match conn.send_request(req).await {
+                Ok(res) if res.status() == StatusCode::TEMPORARY_REDIRECT => {
+                    info!(
+                        "request to {} was redirected by a standby pod, reconnecting",
+                        path
+                    );
+                    last_error = "redirected by a standby pod".to_string();
+                    *upstream = None;
+                }
+                Ok(res) => {
+                    let (mut parts, body) = res.into_parts();
+                    for name in HOP_BY_HOP_HEADERS {
+                        parts.headers.remove(name);
+                    }
+
+                    return Response::from_parts(parts, Full::new(body));
+                }
+                Err(e) => {
+                    warn!("sending request to {}: {}, reconnecting", path, e);
+                    last_error = e.to_string();
+                    *upstream = None;
+                }
+            }
+        }
+
+        error_response(StatusCode::BAD_GATEWAY, &last_error)
+    }
+}
+
+/// Headers that only apply to a single connection and must not be forwarded
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+fn is_hop_by_hop(name: &header::HeaderName) -> bool {
+    HOP_BY_HOP_HEADERS.contains(&name.as_str())
+}
+
+/// Error response in the format of the vault API
+fn error_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    let body = serde_json::json!({
+        "errors": [format!("vault-mgmt proxy: {}", message)],
+    });
+
+    let mut res = Response::new(Full::new(Bytes::from(body.to_string())));
+    *res.status_mut() = status;
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        header::HeaderValue::from_static("application/json"),
+    );
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use http::{Method, StatusCode};
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use wiremock::{
+        matchers::{method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{BytesBody, Connect, HttpForwarderService, HttpRequest, Proxy};
+
+    struct MockConnect(String);
+
+    #[async_trait::async_trait]
+    impl Connect for MockConnect {
+        async fn connect(&self) -> anyhow::Result<HttpForwarderService<BytesBody>> {
+            HttpForwarderService::http(tokio::net::TcpStream::connect(&self.0).await?).await
+        }
+    }
+
+    async fn proxy(mock_server: &MockServer) -> HttpForwarderService<BytesBody> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connector = MockConnect(mock_server.uri().strip_prefix("http://").unwrap().into());
+        tokio::spawn(Proxy::new(connector).serve(listener));
+
+        HttpForwarderService::http(tokio::net::TcpStream::connect(addr).await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn request() -> hyper::Request<BytesBody> {
+        hyper::Request::builder()
+            .method(Method::GET)
+            .uri("/v1/sys/health")
+            .header("Host", "127.0.0.1")
+            .body(Empty::<Bytes>::new().boxed())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn proxy_forwards_requests() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/health"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_string("healthy"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let res = proxy(&mock_server)
+            .await
+            .send_request(request())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "healthy");
+    }
+
+    #[tokio::test]
+    async fn proxy_retries_on_standby_redirect() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/health"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::TEMPORARY_REDIRECT).insert_header(
+                    "Location",
+                    "https://vault-1.vault-internal:8200/v1/sys/health",
+                ),
+            )
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&mock_server)
+            .await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/sys/health"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .mount(&mock_server)
+            .await;
+
+        let res = proxy(&mock_server)
+            .await
+            .send_request(request())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(mock_server.received_requests().await.unwrap().len(), 2);
+    }
+}
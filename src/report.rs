@@ -0,0 +1,296 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use kube::{
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Resource,
+};
+use tracing::*;
+
+use crate::{PodApi, VaultVersion};
+
+/// Name of vault-mgmt in the events it publishes
+const REPORTER_NAME: &str = "vault-mgmt";
+
+/// Step of upgrading or restarting a single pod
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PodStep {
+    PrePodHook,
+    StepDown,
+    Delete,
+    Running,
+    Unseal,
+    Ready,
+    PostPodHook,
+    HealthGates,
+}
+
+impl std::fmt::Display for PodStep {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PodStep::PrePodHook => "pre-pod-hook".fmt(f),
+            PodStep::StepDown => "step-down".fmt(f),
+            PodStep::Delete => "delete".fmt(f),
+            PodStep::Running => "running".fmt(f),
+            PodStep::Unseal => "unseal".fmt(f),
+            PodStep::Ready => "ready".fmt(f),
+            PodStep::PostPodHook => "post-pod-hook".fmt(f),
+            PodStep::HealthGates => "health-gates".fmt(f),
+        }
+    }
+}
+
+/// How a pod was unsealed
+#[derive(Copy, Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnsealMethod {
+    /// unsealed by vault-mgmt with the unseal keys
+    Keys,
+    /// unsealed by someone else or by auto-unseal
+    External,
+    /// the pod was not sealed
+    AlreadyUnsealed,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct StepReport {
+    pub step: PodStep,
+    pub seconds: f64,
+}
+
+/// What happened to a single pod
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct PodReport {
+    pub name: String,
+    pub previous_version: Option<String>,
+    pub new_version: Option<String>,
+    /// the pod already had the target version or a resumed rollout already finished it
+    pub skipped: bool,
+    pub unseal: Option<UnsealMethod>,
+    /// steps in the order they ran, a failed step is included
+    pub steps: Vec<StepReport>,
+}
+
+/// Machine-readable summary of an upgrade, e.g. to be archived by a CI pipeline
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct UpgradeReport {
+    pub target: Option<String>,
+    /// RFC 3339 timestamps of the start and end of the upgrade
+    pub started: Option<String>,
+    pub finished: Option<String>,
+    /// all pods were upgraded
+    pub completed: bool,
+    pub error: Option<String>,
+    /// accessor of the vault token used for the upgrade, to find its requests in the audit devices
+    pub token_accessor: Option<String>,
+    /// estimate after the last finished pod, based on the average time of the pods done
+    pub estimate: Option<EstimateReport>,
+    /// pods in the order they were processed
+    pub pods: Vec<PodReport>,
+}
+
+/// Estimated time the pods not started yet need
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct EstimateReport {
+    pub remaining_pods: usize,
+    pub remaining_seconds: f64,
+    /// RFC 3339 timestamp
+    pub finish: String,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+/// Collects the `UpgradeReport` while an upgrade runs, clones share the same report
+#[derive(Clone, Default)]
+pub struct UpgradeReporter(Arc<Mutex<UpgradeReport>>);
+
+impl UpgradeReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report collected so far, complete once the upgrade returned
+    pub fn report(&self) -> UpgradeReport {
+        self.0.lock().expect("report lock is not poisoned").clone()
+    }
+
+    pub(crate) fn start(&self, target: &VaultVersion) {
+        let mut report = self.0.lock().expect("report lock is not poisoned");
+        report.target = Some(target.version.clone());
+        report.started = Some(now());
+    }
+
+    pub(crate) fn token_accessor(&self, accessor: &str) {
+        let mut report = self.0.lock().expect("report lock is not poisoned");
+        report.token_accessor = Some(accessor.to_string());
+    }
+
+    pub(crate) fn finish(&self, result: &anyhow::Result<()>) {
+        let mut report = self.0.lock().expect("report lock is not poisoned");
+        report.finished = Some(now());
+        report.completed = result.is_ok();
+        report.error = result.as_ref().err().map(|e| format!("{:#}", e));
+    }
+
+    /// Change the report of the pod, adding it if it was not reported yet
+    pub(crate) fn pod(&self, name: &str, f: impl FnOnce(&mut PodReport)) {
+        let mut report = self.0.lock().expect("report lock is not poisoned");
+        let index = match report.pods.iter().position(|p| p.name == name) {
+            Some(index) => index,
+            None => {
+                report.pods.push(PodReport {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                report.pods.len() - 1
+            }
+        };
+
+        f(&mut report.pods[index]);
+    }
+
+    pub(crate) fn estimate(&self, remaining_pods: usize, remaining: Duration) {
+        let mut report = self.0.lock().expect("report lock is not poisoned");
+        report.estimate = Some(EstimateReport {
+            remaining_pods,
+            remaining_seconds: remaining.as_secs_f64(),
+            finish: humantime::format_rfc3339_seconds(SystemTime::now() + remaining).to_string(),
+        });
+    }
+
+    pub(crate) fn step(&self, name: &str, step: PodStep, duration: Duration) {
+        self.pod(name, |pod| {
+            pod.steps.push(StepReport {
+                step,
+                seconds: duration.as_secs_f64(),
+            })
+        });
+    }
+}
+
+impl std::fmt::Debug for UpgradeReporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UpgradeReporter")
+    }
+}
+
+impl PartialEq for UpgradeReporter {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for UpgradeReporter {}
+
+/// Action of an upgrade published as a Kubernetes event, see `UpgradeDriver::publish_event`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpgradeEvent {
+    /// published on the statefulset
+    UpgradeStarted {
+        target: String,
+    },
+    SteppedDown {
+        pod: String,
+    },
+    PodDeleted {
+        pod: String,
+    },
+    /// unsealed by vault-mgmt with the unseal keys
+    Unsealed {
+        pod: String,
+    },
+    /// published on the statefulset
+    UpgradeFailed {
+        error: String,
+    },
+}
+
+impl UpgradeEvent {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            UpgradeEvent::UpgradeStarted { .. } => "UpgradeStarted",
+            UpgradeEvent::SteppedDown { .. } => "SteppedDown",
+            UpgradeEvent::PodDeleted { .. } => "PodDeleted",
+            UpgradeEvent::Unsealed { .. } => "Unsealed",
+            UpgradeEvent::UpgradeFailed { .. } => "UpgradeFailed",
+        }
+    }
+
+    /// Pod the event is about, `None` for events of the statefulset
+    pub fn pod(&self) -> Option<&str> {
+        match self {
+            UpgradeEvent::SteppedDown { pod }
+            | UpgradeEvent::PodDeleted { pod }
+            | UpgradeEvent::Unsealed { pod } => Some(pod),
+            UpgradeEvent::UpgradeStarted { .. } | UpgradeEvent::UpgradeFailed { .. } => None,
+        }
+    }
+
+    pub fn is_failure(&self) -> bool {
+        matches!(self, UpgradeEvent::UpgradeFailed { .. })
+    }
+
+    pub fn note(&self) -> String {
+        match self {
+            UpgradeEvent::UpgradeStarted { target } => format!("upgrading to {}", target),
+            UpgradeEvent::SteppedDown { pod } => {
+                format!("pod {} stepped down, another pod took over", pod)
+            }
+            UpgradeEvent::PodDeleted { pod } => {
+                format!("pod {} deleted to be recreated by the statefulset", pod)
+            }
+            UpgradeEvent::Unsealed { pod } => format!("pod {} unsealed", pod),
+            UpgradeEvent::UpgradeFailed { error } => format!("upgrade failed: {}", error),
+        }
+    }
+}
+
+impl PodApi {
+    /// Publish the event on the pod or the statefulset, failures are only logged
+    pub(crate) async fn record_event(&self, event: &UpgradeEvent) {
+        if !self.events {
+            return;
+        }
+
+        let reference = match (event.pod(), &self.statefulset) {
+            (Some(pod), _) => self.api.get(pod).await.map(|p| p.object_ref(&())),
+            (None, Some((api, name))) => api.get(name).await.map(|s| s.object_ref(&())),
+            (None, None) => return,
+        };
+        let reference = match reference {
+            Ok(reference) => reference,
+            Err(e) => {
+                warn!("publishing event {}: {}", event.reason(), e);
+                return;
+            }
+        };
+
+        let recorder = Recorder::new(
+            self.api.clone().into_client(),
+            Reporter::from(REPORTER_NAME),
+            reference,
+        );
+
+        if let Err(e) = recorder
+            .publish(Event {
+                type_: if event.is_failure() {
+                    EventType::Warning
+                } else {
+                    EventType::Normal
+                },
+                reason: event.reason().to_string(),
+                note: Some(event.note()),
+                action: "Upgrade".to_string(),
+                secondary: None,
+            })
+            .await
+        {
+            warn!("publishing event {}: {}", event.reason(), e);
+        }
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::{
+    api::core::v1::{Node, Pod, ResourceRequirements},
+    apimachinery::pkg::api::resource::Quantity,
+};
+use kube::{
+    api::{ApiResource, DynamicObject, ListParams},
+    Api, Client,
+};
+use tracing::*;
+
+use crate::{vault_container_name, Finding, Severity};
+
+const CHECK: &str = "resources";
+
+/// Share of the memory limit above which a pod is likely to be OOMKilled
+/// while installing the raft snapshot when it rejoins
+const MEMORY_PRESSURE: f64 = 0.8;
+
+/// Parse a Kubernetes quantity, e.g. `500m` (cpu cores) or `1Gi` (bytes)
+pub fn parse_quantity(quantity: &str) -> Option<f64> {
+    let quantity = quantity.trim();
+    // the suffix is alphabetic, an exponent like `1e3` ends with a digit
+    let split = quantity
+        .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+        .len();
+    let (number, suffix) = quantity.split_at(split);
+
+    let factor = match suffix {
+        "" => 1.0,
+        "n" => 1e-9,
+        "u" => 1e-6,
+        "m" => 1e-3,
+        "k" => 1e3,
+        "M" => 1e6,
+        "G" => 1e9,
+        "T" => 1e12,
+        "P" => 1e15,
+        "E" => 1e18,
+        "Ki" => 1024.0,
+        "Mi" => 1024.0_f64.powi(2),
+        "Gi" => 1024.0_f64.powi(3),
+        "Ti" => 1024.0_f64.powi(4),
+        "Pi" => 1024.0_f64.powi(5),
+        "Ei" => 1024.0_f64.powi(6),
+        _ => return None,
+    };
+
+    number.parse::<f64>().ok().map(|n| n * factor)
+}
+
+fn quantity(quantities: Option<&BTreeMap<String, Quantity>>, resource: &str) -> Option<f64> {
+    quantities
+        .and_then(|q| q.get(resource))
+        .and_then(|q| parse_quantity(&q.0))
+}
+
+/// Requested amount of a resource, defaulting to the limit like the scheduler
+fn requested(resources: Option<&ResourceRequirements>, resource: &str) -> f64 {
+    resources
+        .and_then(|r| {
+            quantity(r.requests.as_ref(), resource).or(quantity(r.limits.as_ref(), resource))
+        })
+        .unwrap_or_default()
+}
+
+/// Sum of the requests of all containers of the pod
+fn pod_requests(pod: &Pod, resource: &str) -> f64 {
+    pod.spec
+        .iter()
+        .flat_map(|s| s.containers.iter())
+        .map(|c| requested(c.resources.as_ref(), resource))
+        .sum()
+}
+
+fn format_memory(bytes: f64) -> String {
+    format!("{:.0}Mi", bytes / 1024.0_f64.powi(2))
+}
+
+/// Check if the nodes can fit the recreated vault pods and if the vault containers
+/// were OOMKilled before or are close to their memory limit
+///
+/// `scheduled` are all pods running on the nodes of the vault pods,
+/// `memory_usage` is the current memory usage of the vault container by pod name.
+/// The vault container is the one named `container`, or detected if it is not set.
+pub fn check_resources(
+    pods: &[Pod],
+    nodes: &[Node],
+    scheduled: &[Pod],
+    memory_usage: &BTreeMap<String, f64>,
+    container: Option<&str>,
+) -> Vec<Finding> {
+    let mut findings = vec![];
+
+    for pod in pods {
+        let name = pod.metadata.name.clone().unwrap_or_default();
+        let vault = vault_container_name(pod, container).ok();
+
+        if let Some(node) = pod.spec.as_ref().and_then(|s| s.node_name.as_ref()) {
+            let allocatable = nodes
+                .iter()
+                .find(|n| n.metadata.name.as_ref() == Some(node))
+                .and_then(|n| n.status.as_ref())
+                .and_then(|s| s.allocatable.as_ref());
+
+            if allocatable.is_some() {
+                // the deleted pod frees its own requests
+                let others: Vec<&Pod> = scheduled
+                    .iter()
+                    .filter(|p| p.spec.as_ref().and_then(|s| s.node_name.as_ref()) == Some(node))
+                    .filter(|p| {
+                        p.metadata.name != pod.metadata.name
+                            || p.metadata.namespace != pod.metadata.namespace
+                    })
+                    .collect();
+
+                for (resource, format) in [
+                    ("cpu", (|v| format!("{:.2}", v)) as fn(f64) -> String),
+                    ("memory", format_memory as fn(f64) -> String),
+                ] {
+                    let Some(allocatable) = quantity(allocatable, resource) else {
+                        continue;
+                    };
+                    let free = allocatable
+                        - others
+                            .iter()
+                            .map(|p| pod_requests(p, resource))
+                            .sum::<f64>();
+                    let requests = pod_requests(pod, resource);
+
+                    if requests > free {
+                        findings.push(Finding::new(
+                            CHECK,
+                            Severity::Warning,
+                            format!(
+                                "pod {} requests {} {}, but node {} only has {} left for it, the recreated pod may not schedule",
+                                name,
+                                format(requests),
+                                resource,
+                                node,
+                                format(free.max(0.0))
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+
+        for status in pod
+            .status
+            .iter()
+            .flat_map(|s| s.container_statuses.iter().flatten())
+            .filter(|s| Some(&s.name) == vault.as_ref())
+        {
+            let oom_killed = status
+                .last_state
+                .as_ref()
+                .and_then(|s| s.terminated.as_ref())
+                .is_some_and(|t| t.reason.as_deref() == Some("OOMKilled"));
+
+            if oom_killed {
+                findings.push(Finding::new(
+                    CHECK,
+                    Severity::Warning,
+                    format!(
+                        "container {} of pod {} was OOMKilled before ({} restarts), installing the raft snapshot when rejoining needs even more memory",
+                        status.name, name, status.restart_count
+                    ),
+                ));
+            }
+        }
+
+        let limit = pod
+            .spec
+            .iter()
+            .flat_map(|s| s.containers.iter())
+            .find(|c| Some(&c.name) == vault.as_ref())
+            .and_then(|c| quantity(c.resources.as_ref()?.limits.as_ref(), "memory"));
+
+        if let (Some(limit), Some(usage)) = (limit, memory_usage.get(&name)) {
+            if *usage > limit * MEMORY_PRESSURE {
+                findings.push(Finding::new(
+                    CHECK,
+                    Severity::Warning,
+                    format!(
+                        "pod {} uses {} of its {} memory limit, it will likely be OOMKilled while installing the raft snapshot when rejoining",
+                        name,
+                        format_memory(*usage),
+                        format_memory(limit)
+                    ),
+                ));
+            }
+        }
+    }
+
+    if findings.is_empty() {
+        findings.push(Finding::new(
+            CHECK,
+            Severity::Ok,
+            "nodes have room for the recreated vault pods and no memory pressure was found"
+                .to_string(),
+        ));
+    }
+
+    findings
+}
+
+/// Current memory usage of the vault containers from the metrics API, empty if it is not available
+async fn memory_usage(
+    client: Client,
+    namespace: &str,
+    pods: &[Pod],
+    container: Option<&str>,
+) -> BTreeMap<String, f64> {
+    let resource = ApiResource {
+        group: "metrics.k8s.io".to_string(),
+        version: "v1beta1".to_string(),
+        api_version: "metrics.k8s.io/v1beta1".to_string(),
+        kind: "PodMetrics".to_string(),
+        plural: "pods".to_string(),
+    };
+    let api: Api<DynamicObject> = Api::namespaced_with(client, namespace, &resource);
+
+    let metrics = match api.list(&ListParams::default()).await {
+        Ok(metrics) => metrics.items,
+        Err(e) => {
+            debug!("metrics API is not available: {}", e);
+            return BTreeMap::new();
+        }
+    };
+
+    pods.iter()
+        .filter_map(|pod| {
+            let name = pod.metadata.name.clone()?;
+            let vault = vault_container_name(pod, container).ok()?;
+            let metric = metrics
+                .iter()
+                .find(|m| m.metadata.name.as_ref() == Some(&name))?;
+
+            metric.data["containers"]
+                .as_array()?
+                .iter()
+                .find(|c| c["name"].as_str() == Some(&vault))
+                .and_then(|c| parse_quantity(c["usage"]["memory"].as_str()?))
+                .map(|usage| (name, usage))
+        })
+        .collect()
+}
+
+/// Read the nodes, the pods scheduled on them and the memory usage of the vault pods
+/// and check if the vault pods can be recreated safely
+pub async fn diagnose_resources(
+    client: Client,
+    namespace: &str,
+    pods: &[Pod],
+    container: Option<&str>,
+) -> anyhow::Result<Vec<Finding>> {
+    let nodes = match Api::<Node>::all(client.clone())
+        .list(&ListParams::default())
+        .await
+    {
+        Ok(nodes) => nodes.items,
+        Err(e) => {
+            return Ok(vec![Finding::new(
+                CHECK,
+                Severity::Warning,
+                format!("could not list nodes: {}", e),
+            )])
+        }
+    };
+
+    let scheduled = match Api::<Pod>::all(client.clone())
+        .list(&ListParams::default().fields("status.phase!=Succeeded,status.phase!=Failed"))
+        .await
+    {
+        Ok(pods) => pods.items,
+        Err(e) => {
+            return Ok(vec![Finding::new(
+                CHECK,
+                Severity::Warning,
+                format!("could not list the pods of all namespaces: {}", e),
+            )])
+        }
+    };
+
+    let usage = memory_usage(client, namespace, pods, container).await;
+
+    Ok(check_resources(pods, &nodes, &scheduled, &usage, container))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use k8s_openapi::{
+        api::core::v1::{
+            Container, ContainerState, ContainerStateTerminated, ContainerStatus, Node, NodeStatus,
+            Pod, PodSpec, PodStatus, ResourceRequirements,
+        },
+        apimachinery::pkg::api::resource::Quantity,
+    };
+    use kube::api::ObjectMeta;
+
+    use crate::{check_resources, parse_quantity, Severity};
+
+    fn quantities(cpu: &str, memory: &str) -> Option<BTreeMap<String, Quantity>> {
+        Some(BTreeMap::from([
+            ("cpu".to_string(), Quantity(cpu.to_string())),
+            ("memory".to_string(), Quantity(memory.to_string())),
+        ]))
+    }
+
+    fn pod(name: &str, node: &str, cpu: &str, memory: &str) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("vault".to_string()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                node_name: Some(node.to_string()),
+                containers: vec![Container {
+                    name: "vault".to_string(),
+                    resources: Some(ResourceRequirements {
+                        requests: quantities(cpu, memory),
+                        limits: quantities(cpu, memory),
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn node(name: &str, cpu: &str, memory: &str) -> Node {
+        Node {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            status: Some(NodeStatus {
+                allocatable: quantities(cpu, memory),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parsing_quantities_works() {
+        assert_eq!(parse_quantity("500m"), Some(0.5));
+        assert_eq!(parse_quantity("2"), Some(2.0));
+        assert_eq!(parse_quantity("1Gi"), Some(1073741824.0));
+        assert_eq!(parse_quantity("128M"), Some(128e6));
+        assert_eq!(parse_quantity("1e3"), Some(1000.0));
+        assert_eq!(parse_quantity("1Ei"), Some(1024.0_f64.powi(6)));
+        assert_eq!(parse_quantity("1Xi"), None);
+    }
+
+    #[test]
+    fn resources_are_ok_if_node_has_room() {
+        let vault = pod("vault-0", "node-a", "500m", "256Mi");
+        let other = pod("app", "node-a", "1", "1Gi");
+
+        let findings = check_resources(
+            std::slice::from_ref(&vault),
+            &[node("node-a", "2", "2Gi")],
+            &[vault.clone(), other],
+            &BTreeMap::new(),
+            None,
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Ok);
+    }
+
+    #[test]
+    fn resources_warn_if_node_is_full() {
+        let vault = pod("vault-0", "node-a", "500m", "1Gi");
+        let other = pod("app", "node-a", "1", "1536Mi");
+
+        let findings = check_resources(
+            std::slice::from_ref(&vault),
+            &[node("node-a", "2", "2Gi")],
+            &[vault.clone(), other],
+            &BTreeMap::new(),
+            None,
+        );
+
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert!(findings[0].message.contains("1024Mi memory"));
+        assert!(findings[0].message.contains("only has 512Mi left"));
+    }
+
+    #[test]
+    fn resources_warn_about_oom_kills_and_memory_pressure() {
+        let mut vault = pod("vault-0", "node-a", "500m", "256Mi");
+        vault.status = Some(PodStatus {
+            container_statuses: Some(vec![ContainerStatus {
+                name: "vault".to_string(),
+                restart_count: 3,
+                last_state: Some(ContainerState {
+                    terminated: Some(ContainerStateTerminated {
+                        reason: Some("OOMKilled".to_string()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        });
+
+        let findings = check_resources(
+            std::slice::from_ref(&vault),
+            &[node("node-a", "2", "2Gi")],
+            &[vault.clone()],
+            &BTreeMap::from([("vault-0".to_string(), 240.0 * 1024.0 * 1024.0)]),
+            None,
+        );
+
+        assert_eq!(findings.len(), 2);
+        assert!(findings[0]
+            .message
+            .contains("OOMKilled before (3 restarts)"));
+        assert!(findings[1].message.contains("uses 240Mi of its 256Mi"));
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::BTreeMap;
+
+use k8s_openapi::{
+    api::{
+        apps::v1::StatefulSet,
+        core::v1::{Pod, ResourceRequirements},
+    },
+    apimachinery::pkg::api::resource::Quantity,
+};
+use prettytable::{color, Attr, Cell, Row, Table};
+
+use crate::{find_vault_container, parse_quantity, PodApi, StatefulSetApi};
+
+/// Label of the statefulset controller with the revision a pod was created from
+pub const LABEL_KEY_CONTROLLER_REVISION_HASH: &str = "controller-revision-hash";
+
+/// Pod that was not created from the update revision of its statefulset
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RevisionMismatch {
+    pub pod: String,
+    /// revision the pod was created from, `None` if the label is missing
+    pub revision: Option<String>,
+    pub update_revision: String,
+}
+
+/// Compare the revision of each pod with the update revision of the statefulset
+///
+/// With the `OnDelete` update strategy a pod recreated while the statefulset was changed
+/// can come up with a stale revision, even if it reports the expected version.
+pub fn revision_mismatches(
+    sts: &StatefulSet,
+    pods: &[Pod],
+) -> anyhow::Result<Vec<RevisionMismatch>> {
+    let update_revision = sts
+        .status
+        .as_ref()
+        .and_then(|s| s.update_revision.clone())
+        .ok_or(anyhow::anyhow!(
+            "statefulset does not have an update revision"
+        ))?;
+
+    let mut mismatches = vec![];
+    for pod in pods {
+        let name = pod
+            .metadata
+            .name
+            .clone()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+        let revision = pod
+            .metadata
+            .labels
+            .as_ref()
+            .and_then(|l| l.get(LABEL_KEY_CONTROLLER_REVISION_HASH))
+            .cloned();
+
+        if revision.as_ref() != Some(&update_revision) {
+            mismatches.push(RevisionMismatch {
+                pod: name,
+                revision,
+                update_revision: update_revision.clone(),
+            });
+        }
+    }
+
+    Ok(mismatches)
+}
+
+/// Field of a container that differs between a pod and the pod template of its statefulset
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateChange {
+    pub pod: String,
+    pub container: String,
+    /// e.g. `env` or `resources`, `container` if the pod does not have the container
+    pub field: &'static str,
+}
+
+impl std::fmt::Display for TemplateChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of container {} of pod {}",
+            self.field, self.container, self.pod
+        )
+    }
+}
+
+/// Compare the containers of the pod with the pod template of the statefulset,
+/// except for the image of the vault container, which changes with the version
+///
+/// Recreating the pod applies these changes along with the version, e.g. a new env or resources.
+/// Containers injected into the pod (e.g. by a service mesh) are not compared, neither are
+/// fields and entries the admission added to the pod (e.g. defaults of a LimitRange or an
+/// injected env), only what the template sets.
+/// The vault container is the one named `container`, or detected if it is not set.
+pub fn template_changes(
+    sts: &StatefulSet,
+    pod: &Pod,
+    container: Option<&str>,
+) -> anyhow::Result<Vec<TemplateChange>> {
+    let name = pod
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+    let template = sts
+        .spec
+        .as_ref()
+        .and_then(|s| s.template.spec.as_ref())
+        .ok_or(anyhow::anyhow!("statefulset does not have a pod template"))?;
+    let spec = pod
+        .spec
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod {} does not have a spec", name))?;
+
+    let vault = find_vault_container(&template.containers, container)?
+        .name
+        .as_str();
+    let init = template.init_containers.iter().flatten();
+    let pod_init = spec.init_containers.as_deref().unwrap_or_default();
+
+    let mut changes = vec![];
+    for (wanted, containers) in template
+        .containers
+        .iter()
+        .map(|c| (c, spec.containers.as_slice()))
+        .chain(init.map(|c| (c, pod_init)))
+    {
+        let change = |field| TemplateChange {
+            pod: name.clone(),
+            container: wanted.name.clone(),
+            field,
+        };
+
+        let Some(actual) = containers.iter().find(|c| c.name == wanted.name) else {
+            changes.push(change("container"));
+            continue;
+        };
+
+        // the image of the vault container changes with the version
+        let differs = [
+            (
+                "image",
+                wanted.name != vault && wanted.image != actual.image,
+            ),
+            (
+                "command",
+                wanted.command.is_some() && wanted.command != actual.command,
+            ),
+            ("args", wanted.args.is_some() && wanted.args != actual.args),
+            (
+                "env",
+                !contains_all(&wanted.env, &actual.env)
+                    || !contains_all(&wanted.env_from, &actual.env_from),
+            ),
+            (
+                "resources",
+                !has_resources(wanted.resources.as_ref(), actual.resources.as_ref()),
+            ),
+        ];
+        changes.extend(
+            differs
+                .into_iter()
+                .filter(|(_, differs)| *differs)
+                .map(|(field, _)| change(field)),
+        );
+    }
+
+    Ok(changes)
+}
+
+/// Whether the pod has every entry of the template, besides entries added by the admission
+fn contains_all<T: PartialEq>(wanted: &Option<Vec<T>>, actual: &Option<Vec<T>>) -> bool {
+    let actual = actual.as_deref().unwrap_or_default();
+    wanted.iter().flatten().all(|w| actual.contains(w))
+}
+
+/// Whether the pod has the requests and limits of the template, comparing the quantities
+/// by value (e.g. `1` and `1000m`), besides the defaults added by a LimitRange
+fn has_resources(
+    wanted: Option<&ResourceRequirements>,
+    actual: Option<&ResourceRequirements>,
+) -> bool {
+    let Some(wanted) = wanted else {
+        return true;
+    };
+
+    has_quantities(
+        wanted.limits.as_ref(),
+        actual.and_then(|a| a.limits.as_ref()),
+    ) && has_quantities(
+        wanted.requests.as_ref(),
+        actual.and_then(|a| a.requests.as_ref()),
+    )
+}
+
+fn has_quantities(
+    wanted: Option<&BTreeMap<String, Quantity>>,
+    actual: Option<&BTreeMap<String, Quantity>>,
+) -> bool {
+    wanted.into_iter().flatten().all(|(resource, quantity)| {
+        let Some(actual) = actual.and_then(|a| a.get(resource)) else {
+            return false;
+        };
+
+        match (parse_quantity(&quantity.0), parse_quantity(&actual.0)) {
+            (Some(w), Some(a)) => (w - a).abs() <= f64::EPSILON * w.abs().max(a.abs()),
+            _ => quantity == actual,
+        }
+    })
+}
+
+impl StatefulSetApi {
+    /// Find the pods of the statefulset not created from its update revision,
+    /// see `revision_mismatches`
+    pub async fn verify_revisions(
+        &self,
+        name: &str,
+        pods: &PodApi,
+    ) -> anyhow::Result<Vec<RevisionMismatch>> {
+        let sts = self.api.get(name).await?;
+        let pods = pods.api.list(&pods.selector.to_list_params()).await?;
+
+        revision_mismatches(&sts, &pods.items)
+    }
+}
+
+/// Construct a table of the pods with a stale revision
+pub fn construct_revision_table(mismatches: &[RevisionMismatch]) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row!["NAME", "REVISION", "UPDATE REVISION"]);
+
+    for mismatch in mismatches {
+        table.add_row(Row::new(vec![
+            Cell::new(&mismatch.pod),
+            Cell::new(mismatch.revision.as_deref().unwrap_or("-"))
+                .with_style(Attr::ForegroundColor(color::RED)),
+            Cell::new(&mismatch.update_revision),
+        ]));
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::{
+        apps::v1::StatefulSet,
+        core::v1::{Container, EnvVar, Pod, ResourceRequirements},
+    };
+    use k8s_openapi::apimachinery::pkg::api::resource::Quantity;
+
+    use crate::{
+        revision_mismatches, template_changes, RevisionMismatch, TemplateChange,
+        LABEL_KEY_CONTROLLER_REVISION_HASH,
+    };
+
+    async fn read(path: &str) -> String {
+        tokio::fs::read_to_string(format!("tests/resources/installed/{}.yaml", path))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pods_with_stale_revision_are_reported() {
+        let sts: StatefulSet = serde_yaml::from_str(
+            &read("apis/apps/v1/namespaces/vault-mgmt-e2e/statefulsets/vault-mgmt-e2e-2274").await,
+        )
+        .unwrap();
+
+        let mut pods = vec![];
+        for i in 0..3 {
+            let pod: Pod = serde_yaml::from_str(
+                &read(&format!(
+                    "api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-{}",
+                    i
+                ))
+                .await,
+            )
+            .unwrap();
+            pods.push(pod);
+        }
+
+        assert_eq!(revision_mismatches(&sts, &pods).unwrap(), vec![]);
+
+        let labels = pods[1].metadata.labels.as_mut().unwrap();
+        labels.insert(
+            LABEL_KEY_CONTROLLER_REVISION_HASH.to_string(),
+            "vault-mgmt-e2e-2274-6d4f8b9c7".to_string(),
+        );
+        pods[2]
+            .metadata
+            .labels
+            .as_mut()
+            .unwrap()
+            .remove(LABEL_KEY_CONTROLLER_REVISION_HASH);
+
+        assert_eq!(
+            revision_mismatches(&sts, &pods).unwrap(),
+            vec![
+                RevisionMismatch {
+                    pod: "vault-mgmt-e2e-2274-1".to_string(),
+                    revision: Some("vault-mgmt-e2e-2274-6d4f8b9c7".to_string()),
+                    update_revision: "vault-mgmt-e2e-2274-855dc4957b".to_string(),
+                },
+                RevisionMismatch {
+                    pod: "vault-mgmt-e2e-2274-2".to_string(),
+                    revision: None,
+                    update_revision: "vault-mgmt-e2e-2274-855dc4957b".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn template_changes_besides_the_vault_image_are_found() {
+        let mut sts: StatefulSet = serde_yaml::from_str(
+            &read("apis/apps/v1/namespaces/vault-mgmt-e2e/statefulsets/vault-mgmt-e2e-2274").await,
+        )
+        .unwrap();
+        let pod: Pod = serde_yaml::from_str(
+            &read("api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-0").await,
+        )
+        .unwrap();
+
+        assert_eq!(template_changes(&sts, &pod, None).unwrap(), vec![]);
+
+        let mut changed = sts.spec.as_ref().unwrap().template.spec.clone().unwrap();
+        changed.containers[0].image = Some("hashicorp/vault:1.14.0".to_string());
+        sts.spec.as_mut().unwrap().template.spec = Some(changed.clone());
+        assert_eq!(template_changes(&sts, &pod, None).unwrap(), vec![]);
+
+        let vault = &mut changed.containers[0];
+        vault.env.as_mut().unwrap().push(EnvVar {
+            name: "VAULT_LOG_LEVEL".to_string(),
+            value: Some("debug".to_string()),
+            value_from: None,
+        });
+        vault.args = Some(vec!["server".to_string()]);
+        changed.containers.push(Container {
+            name: "log-shipper".to_string(),
+            ..Default::default()
+        });
+        sts.spec.as_mut().unwrap().template.spec = Some(changed);
+
+        let change = |container: &str, field| TemplateChange {
+            pod: "vault-mgmt-e2e-2274-0".to_string(),
+            container: container.to_string(),
+            field,
+        };
+        assert_eq!(
+            template_changes(&sts, &pod, None).unwrap(),
+            vec![
+                change("vault", "args"),
+                change("vault", "env"),
+                change("log-shipper", "container"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn template_changes_ignore_defaults_of_the_admission() {
+        let mut sts: StatefulSet = serde_yaml::from_str(
+            &read("apis/apps/v1/namespaces/vault-mgmt-e2e/statefulsets/vault-mgmt-e2e-2274").await,
+        )
+        .unwrap();
+        let mut pod: Pod = serde_yaml::from_str(
+            &read("api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-0").await,
+        )
+        .unwrap();
+        let quantities = |quantities: &[(&str, &str)]| {
+            Some(
+                quantities
+                    .iter()
+                    .map(|(resource, q)| (resource.to_string(), Quantity(q.to_string())))
+                    .collect(),
+            )
+        };
+
+        let mut template = sts.spec.as_ref().unwrap().template.spec.clone().unwrap();
+        template.containers[0].resources = Some(ResourceRequirements {
+            limits: quantities(&[("cpu", "1"), ("memory", "1Gi")]),
+            ..Default::default()
+        });
+        sts.spec.as_mut().unwrap().template.spec = Some(template);
+
+        // normalized by the api server, requests defaulted by a LimitRange, env injected
+        let vault = &mut pod.spec.as_mut().unwrap().containers[0];
+        vault.resources = Some(ResourceRequirements {
+            limits: quantities(&[("cpu", "1000m"), ("memory", "1024Mi")]),
+            requests: quantities(&[("cpu", "500m"), ("memory", "256Mi")]),
+        });
+        vault.env.get_or_insert_with(Vec::new).push(EnvVar {
+            name: "HTTP_PROXY".to_string(),
+            value: Some("http://proxy:3128".to_string()),
+            value_from: None,
+        });
+        assert_eq!(template_changes(&sts, &pod, None).unwrap(), vec![]);
+
+        let vault = &mut pod.spec.as_mut().unwrap().containers[0];
+        vault.resources.as_mut().unwrap().limits = quantities(&[("cpu", "500m")]);
+        assert_eq!(
+            template_changes(&sts, &pod, None).unwrap(),
+            vec![TemplateChange {
+                pod: "vault-mgmt-e2e-2274-0".to_string(),
+                container: "vault".to_string(),
+                field: "resources",
+            }]
+        );
+    }
+}
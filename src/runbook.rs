@@ -0,0 +1,229 @@
+use std::{fmt::Write, time::Duration};
+
+use crate::{Flavor, PlannedAction, UpgradeOptions, UpgradePlan};
+
+/// Where the commands of a runbook are run against
+#[derive(Clone, Debug)]
+pub struct RunbookTarget {
+    pub namespace: String,
+    pub statefulset: String,
+    pub flavor: Flavor,
+    /// name of the vault container in the pods
+    pub container: String,
+    /// image the statefulset is set to before the pods are upgraded, if it changes
+    pub image: Option<String>,
+}
+
+impl RunbookTarget {
+    /// Name of the CLI in the vault container
+    fn cli(&self) -> &'static str {
+        match self.flavor {
+            Flavor::Vault => "vault",
+            Flavor::Openbao => "bao",
+        }
+    }
+
+    fn kubectl(&self) -> String {
+        format!("kubectl --namespace {}", self.namespace)
+    }
+
+    /// Wait until a label of the service registration has the value
+    fn await_label(&self, pod: &str, label: &str, value: &str, timeout: Duration) -> String {
+        format!(
+            "{} wait pod/{} --for=jsonpath='{{.metadata.labels.{}}}'={} --timeout={}s",
+            self.kubectl(),
+            pod,
+            self.flavor.label_key(label),
+            value,
+            timeout.as_secs()
+        )
+    }
+}
+
+/// Shell script running the steps of an upgrade with kubectl and the vault CLI
+///
+/// Every step is explained by a comment, so the script can be reviewed before an operator
+/// runs it manually. Unseal keys are read from `UNSEAL_KEYS` (one key per line) and the token
+/// for step-downs from `VAULT_TOKEN`, they are never written to the script and are passed
+/// to the pods on stdin, so they do not appear on any command line.
+pub fn upgrade_runbook(
+    plan: &UpgradePlan,
+    target: &RunbookTarget,
+    options: &UpgradeOptions,
+) -> String {
+    let kubectl = target.kubectl();
+    let cli = target.cli();
+    let mut script = String::new();
+
+    let mut line = |s: &str| writeln!(script, "{}", s).expect("writing to a string does not fail");
+
+    line("#!/bin/sh");
+    line(&format!(
+        "# Upgrade of statefulset {}/{} to {}",
+        target.namespace, target.statefulset, plan.target.version
+    ));
+    line("#");
+    line("# Generated by vault-mgmt upgrade --emit-runbook, review every step before running it.");
+    if plan
+        .pods
+        .iter()
+        .any(|p| p.action == PlannedAction::StepDownAndUpgrade)
+    {
+        line("# Requires VAULT_TOKEN with permission to step down the active pod.");
+    }
+    if plan
+        .pods
+        .iter()
+        .any(|p| p.unseal && p.action != PlannedAction::Skip)
+    {
+        line("# Requires UNSEAL_KEYS with the unseal keys, one key per line.");
+    }
+    line("set -eu");
+
+    if plan.pods.is_empty() {
+        line("");
+        line(
+            "# No active and standby pods found, the cluster cannot be upgraded without downtime.",
+        );
+        line("exit 1");
+        return script;
+    }
+
+    if let Some(image) = &target.image {
+        line("");
+        line("# Set the new image, the pods are only recreated when they are deleted");
+        line(&format!(
+            "{} set image statefulset/{} {}={}",
+            kubectl, target.statefulset, target.container, image
+        ));
+    }
+
+    for pod in &plan.pods {
+        line("");
+        line(&format!(
+            "### Pod {} ({}, {})",
+            pod.name,
+            if pod.active { "active" } else { "standby" },
+            pod.current.version
+        ));
+
+        if pod.action == PlannedAction::Skip {
+            line("# Already running the target version, nothing to do");
+            continue;
+        }
+
+        if pod.action == PlannedAction::StepDownAndUpgrade {
+            line("# Step down the active pod, so a standby pod takes over before it is deleted");
+            line(&format!(
+                "printf '%s' \"$VAULT_TOKEN\" | {} exec -i {} --container {} -- sh -c 'VAULT_TOKEN=$(cat) {} operator step-down'",
+                kubectl, pod.name, target.container, cli
+            ));
+            line(&target.await_label(&pod.name, "active", "false", options.stepdown_timeout));
+        }
+
+        line("# Delete the pod, the statefulset recreates it with the new image");
+        line(&format!(
+            "{} delete pod {} --timeout={}s",
+            kubectl,
+            pod.name,
+            options.delete_timeout.as_secs()
+        ));
+        line("# Wait until the recreated pod exists, is running and reports its seal status");
+        line(&format!(
+            "until {} get pod {} > /dev/null 2>&1; do sleep 5; done",
+            kubectl, pod.name
+        ));
+        line(&target.await_label(&pod.name, "sealed", "true", options.running_timeout));
+
+        if pod.unseal {
+            line("# Unseal the pod with every key, passed on stdin instead of the command line");
+            line("printf '%s\\n' \"$UNSEAL_KEYS\" | while read -r key; do");
+            line(&format!(
+                "  printf '%s\\n' \"$key\" | {} exec -i {} --container {} -- {} operator unseal - > /dev/null",
+                kubectl, pod.name, target.container, cli
+            ));
+            line("done");
+            line(&target.await_label(&pod.name, "sealed", "false", options.unseal_timeout));
+        } else {
+            line("# Wait until the pod is unsealed externally");
+            line(
+                &target.await_label(
+                    &pod.name,
+                    "sealed",
+                    "false",
+                    options
+                        .external_unseal_timeout
+                        .unwrap_or(options.unseal_timeout),
+                ),
+            );
+        }
+
+        line("# Wait until the pod is ready before continuing with the next pod");
+        line(&format!(
+            "{} wait pod/{} --for=condition=Ready --timeout={}s",
+            kubectl,
+            pod.name,
+            options.pod_ready_timeout.as_secs()
+        ));
+    }
+
+    script
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{
+        Flavor, PlannedAction, PlannedPod, RunbookTarget, UpgradeOptions, UpgradePlan, VaultVersion,
+    };
+
+    use super::upgrade_runbook;
+
+    #[test]
+    fn runbook_steps_down_the_active_pod_last() {
+        let pod = |name: &str, active, action| PlannedPod {
+            name: name.to_string(),
+            active,
+            current: VaultVersion::from_str("1.13.0").unwrap(),
+            action,
+            unseal: true,
+        };
+        let plan = UpgradePlan {
+            target: VaultVersion::from_str("1.14.0").unwrap(),
+            pods: vec![
+                pod("vault-1", false, PlannedAction::Upgrade),
+                pod("vault-2", false, PlannedAction::Skip),
+                pod("vault-0", true, PlannedAction::StepDownAndUpgrade),
+            ],
+        };
+        let target = RunbookTarget {
+            namespace: "vault".to_string(),
+            statefulset: "vault".to_string(),
+            flavor: Flavor::Vault,
+            container: "vault".to_string(),
+            image: Some("hashicorp/vault:1.14.0".to_string()),
+        };
+
+        let script = upgrade_runbook(&plan, &target, &UpgradeOptions::default());
+        let position = |s: &str| script.find(s).unwrap();
+
+        assert!(script.contains("set image statefulset/vault vault=hashicorp/vault:1.14.0"));
+        assert!(!script.contains("delete pod vault-2"));
+        assert!(position("delete pod vault-1") < position("step-down"));
+        assert!(position("step-down") < position("delete pod vault-0"));
+        assert!(script.contains(
+            "wait pod/vault-0 --for=jsonpath='{.metadata.labels.vault-active}'=false --timeout=60s"
+        ));
+        assert!(script.contains("exec -i vault-1 --container vault -- vault operator unseal -"));
+        assert!(!script.contains("unseal \"$key\""));
+        assert!(script.contains(
+            "printf '%s' \"$VAULT_TOKEN\" | kubectl --namespace vault exec -i vault-0 --container vault \
+             -- sh -c 'VAULT_TOKEN=$(cat) vault operator step-down'"
+        ));
+        assert!(!script
+            .lines()
+            .filter_map(|l| l.split_once(" exec "))
+            .any(|(_, argv)| argv.contains("\"$VAULT_TOKEN\"")));
+    }
+}
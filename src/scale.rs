@@ -0,0 +1,443 @@
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use k8s_openapi::api::apps::v1::StatefulSet;
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::wait::conditions::is_pod_running,
+};
+use secrecy::Secret;
+use tracing::*;
+
+use crate::{
+    await_condition, is_active, is_pod_exporting_seal_status, is_pod_unsealed,
+    raft_remove_peer_request, BytesBody, GetLeader, GetRaftConfiguration, GetSealStatus,
+    HttpRequest, PodApi, RaftConfiguration, RaftConfigurationServer, RaftJoin, RaftJoinRequest,
+    StatefulSetApi, StepDown, Unseal, VAULT_PORT,
+};
+
+/// Remove a server from the raft configuration
+#[async_trait::async_trait]
+pub trait RaftRemovePeer {
+    /// Remove a server from the raft configuration
+    async fn raft_remove_peer(
+        &mut self,
+        token: Secret<String>,
+        node_id: &str,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<T> RaftRemovePeer for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn raft_remove_peer(
+        &mut self,
+        token: Secret<String>,
+        node_id: &str,
+    ) -> anyhow::Result<()> {
+        let body = serde_json::json!({
+            "server_id": node_id,
+        });
+
+        let http_req =
+            raft_remove_peer_request(token, Full::new(Bytes::from(body.to_string())).boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!("removing raft peer {}: {}", node_id, body));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns true if the raft server belongs to the pod
+///
+/// Matches either the node id (the helm chart uses the pod name) or the cluster address of the pod.
+pub fn is_raft_server_of_pod(server: &RaftConfigurationServer, pod: &str) -> bool {
+    server.node_id == pod || server.address.starts_with(&format!("{}.", pod))
+}
+
+/// Find the raft server of a pod, see `is_raft_server_of_pod`
+pub fn raft_server_of_pod<'a>(
+    config: &'a RaftConfiguration,
+    pod: &str,
+) -> Option<&'a RaftConfigurationServer> {
+    config
+        .data
+        .config
+        .servers
+        .iter()
+        .find(|s| is_raft_server_of_pod(s, pod))
+}
+
+/// Find the raft node id of a pod, see `raft_server_of_pod`
+pub fn raft_node_id_of_pod(config: &RaftConfiguration, pod: &str) -> Option<String> {
+    raft_server_of_pod(config, pod).map(|s| s.node_id.clone())
+}
+
+/// Options of `StatefulSetApi::scale`
+#[derive(Clone, Debug)]
+pub struct ScaleOptions {
+    /// how long to wait for a new leader after stepping down an active pod to be removed
+    pub timeout: Duration,
+    /// remove voters even if the remaining ones are fewer than the quorum of the current ones
+    pub force: bool,
+}
+
+impl Default for ScaleOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(60),
+            force: false,
+        }
+    }
+}
+
+impl ScaleOptions {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+}
+
+/// Check that the voters staying in the raft configuration after removing the pods
+/// are at least the quorum of the current voters, unless forced
+pub fn check_scale_down_quorum(
+    config: &RaftConfiguration,
+    removed: &[String],
+    force: bool,
+) -> anyhow::Result<()> {
+    let voters = config.data.config.servers.iter().filter(|s| s.voter);
+    let current = voters.clone().count();
+    let remaining = voters
+        .filter(|s| !removed.iter().any(|pod| is_raft_server_of_pod(s, pod)))
+        .count();
+    let quorum = current / 2 + 1;
+
+    if remaining >= quorum {
+        return Ok(());
+    }
+
+    if force {
+        warn!(
+            "{} of {} voters remain, fewer than the quorum of {}",
+            remaining, current, quorum
+        );
+        return Ok(());
+    }
+
+    anyhow::bail!(
+        "{} of {} voters would remain, fewer than the quorum of {}, use --force to scale down anyway",
+        remaining,
+        current,
+        quorum
+    )
+}
+
+impl StatefulSetApi {
+    /// Scale a vault cluster to the given number of replicas
+    ///
+    /// - on scale-up
+    ///     - Increase replicas of the statefulset
+    ///     - Repeat for all new pods
+    ///         - Wait for pod to be running
+    ///         - Join pod to the raft cluster if it is not initialized
+    ///           (to the current leader, unless `join` specifies a leader address or auto join)
+    ///         - Unseal pod
+    ///         - Wait for pod to be unsealed
+    /// - on scale-down
+    ///     - Check that the remaining voters keep quorum, see `check_scale_down_quorum`
+    ///     - Repeat for all pods to be removed (highest ordinal first)
+    ///         - Step down pod if it is active, waiting up to `options.timeout` for a new leader
+    ///         - Remove pod from the raft configuration
+    ///     - Decrease replicas of the statefulset
+    #[allow(clippy::too_many_arguments)]
+    pub async fn scale(
+        &self,
+        sts: StatefulSet,
+        pods: &PodApi,
+        replicas: i32,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        join: &RaftJoinRequest,
+        options: &ScaleOptions,
+    ) -> anyhow::Result<()> {
+        if replicas < 1 {
+            anyhow::bail!("cannot scale to less than one replica");
+        }
+
+        let name = sts
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a name"))?;
+
+        let current = sts.spec.as_ref().and_then(|s| s.replicas).unwrap_or(1);
+
+        // the first pod is never removed, so it can be used to talk to the cluster
+        let first = format!("{}-0", name);
+
+        match replicas.cmp(&current) {
+            std::cmp::Ordering::Equal => {
+                info!("statefulset {} already has {} replicas", name, replicas);
+            }
+            std::cmp::Ordering::Greater => {
+                let mut join = join.clone();
+
+                // join the current leader unless told otherwise
+                if join.leader_api_addr.is_none() && join.auto_join.is_none() {
+                    let leader = pods.http(&first, VAULT_PORT).await?.leader().await?;
+                    if leader.leader_address.is_empty() {
+                        anyhow::bail!("cluster does not have a leader");
+                    }
+                    join.leader_api_addr = Some(leader.leader_address);
+                }
+
+                info!("scaling {} from {} to {} replicas", name, current, replicas);
+                self.set_replicas(name, replicas).await?;
+
+                for ordinal in current..replicas {
+                    self.scale_up_pod(pods, &format!("{}-{}", name, ordinal), &join, keys)
+                        .await?;
+                }
+            }
+            std::cmp::Ordering::Less => {
+                let config = pods
+                    .http(&first, VAULT_PORT)
+                    .await?
+                    .raft_configuration(token.clone())
+                    .await?;
+
+                let removed = (replicas..current)
+                    .rev()
+                    .map(|ordinal| format!("{}-{}", name, ordinal))
+                    .collect::<Vec<_>>();
+                check_scale_down_quorum(&config, &removed, options.force)?;
+
+                for pod in removed {
+                    if is_active(&pods.api.get(&pod).await?)? {
+                        info!("stepping down active pod {}", pod);
+                        let mut pf = pods.http(&pod, VAULT_PORT).await?;
+                        tokio::time::timeout(
+                            options.timeout,
+                            pf.step_down_and_await_new_leader(token.clone()),
+                        )
+                        .await
+                        .map_err(|_| {
+                            anyhow::anyhow!(
+                                "no new leader was elected within {} after stepping down pod {}",
+                                humantime::format_duration(options.timeout),
+                                pod
+                            )
+                        })??;
+                    }
+
+                    match raft_node_id_of_pod(&config, &pod) {
+                        Some(node_id) => {
+                            info!("removing raft peer {} of pod {}", node_id, pod);
+                            pods.http(&first, VAULT_PORT)
+                                .await?
+                                .raft_remove_peer(token.clone(), &node_id)
+                                .await?;
+                        }
+                        None => warn!("pod {} is not part of the raft configuration", pod),
+                    }
+                }
+
+                info!("scaling {} from {} to {} replicas", name, current, replicas);
+                self.set_replicas(name, replicas).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn set_replicas(&self, name: &str, replicas: i32) -> anyhow::Result<()> {
+        self.api
+            .patch(
+                name,
+                &PatchParams::default(),
+                &Patch::Merge(serde_json::json!({
+                    "spec": {
+                        "replicas": replicas,
+                    },
+                })),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn scale_up_pod(
+        &self,
+        pods: &PodApi,
+        name: &str,
+        join: &RaftJoinRequest,
+        keys: &[Secret<String>],
+    ) -> anyhow::Result<()> {
+        info!("waiting for pod {}", name);
+
+        // Wait for pod to be running
+        await_condition(pods.api.clone(), name, is_pod_running(), &pods.tuning).await?;
+
+        // Wait for pod to export its seal status
+        await_condition(
+            pods.api.clone(),
+            name,
+            is_pod_exporting_seal_status(),
+            &pods.tuning,
+        )
+        .await?;
+
+        let mut pf = pods.http(name, VAULT_PORT).await?;
+
+        let status = pf.seal_status().await?;
+
+        if !status.initialized {
+            info!("joining pod {} to raft cluster", name);
+            pf.raft_join(join.clone()).await?;
+        }
+
+        info!("unsealing pod {}", name);
+        pf.unseal(keys).await?;
+
+        await_condition(pods.api.clone(), name, is_pod_unsealed(), &pods.tuning).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use http::{Method, StatusCode};
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        check_scale_down_quorum, raft_node_id_of_pod, HttpForwarderService, RaftConfiguration,
+        RaftRemovePeer,
+    };
+
+    #[tokio::test]
+    async fn removing_raft_peer_calls_api() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/sys/storage/raft/remove-peer"))
+            .and(header("X-Vault-Token", "abc"))
+            .and(body_json(serde_json::json!({"server_id": "vault-2"})))
+            .respond_with(ResponseTemplate::new(StatusCode::NO_CONTENT))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let outcome = client
+            .raft_remove_peer(Secret::from_str("abc").unwrap(), "vault-2")
+            .await;
+
+        assert!(outcome.is_ok());
+    }
+
+    #[test]
+    fn finding_raft_node_id_of_pod_works() {
+        let config: RaftConfiguration = serde_json::from_value(serde_json::json!({
+            "request_id": "",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": {
+                "config": {
+                    "index": 0,
+                    "servers": [
+                        {
+                            "node_id": "vault-0",
+                            "address": "vault-0.vault-internal:8201",
+                            "leader": true,
+                            "protocol_version": "3",
+                            "voter": true,
+                        },
+                        {
+                            "node_id": "0a5e8c6b-1f3e-4bc0-8a5d-3c2b1f0e9d8c",
+                            "address": "vault-1.vault-internal:8201",
+                            "leader": false,
+                            "protocol_version": "3",
+                            "voter": true,
+                        },
+                    ],
+                },
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            raft_node_id_of_pod(&config, "vault-0"),
+            Some("vault-0".to_string())
+        );
+        assert_eq!(
+            raft_node_id_of_pod(&config, "vault-1"),
+            Some("0a5e8c6b-1f3e-4bc0-8a5d-3c2b1f0e9d8c".to_string())
+        );
+        assert_eq!(raft_node_id_of_pod(&config, "vault-2"), None);
+    }
+
+    #[test]
+    fn scale_down_keeps_quorum_of_voters() {
+        // five voters and a non-voter, the quorum is three voters
+        let servers = (0..6)
+            .map(|n| {
+                serde_json::json!({
+                    "node_id": format!("vault-{}", n),
+                    "address": format!("vault-{}.vault-internal:8201", n),
+                    "leader": n == 0,
+                    "protocol_version": "3",
+                    "voter": n < 5,
+                })
+            })
+            .collect::<Vec<_>>();
+        let config: RaftConfiguration = serde_json::from_value(serde_json::json!({
+            "request_id": "",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": { "config": { "index": 0, "servers": servers } },
+        }))
+        .unwrap();
+        let removed = |from: usize| {
+            (from..6)
+                .map(|n| format!("vault-{}", n))
+                .collect::<Vec<_>>()
+        };
+
+        check_scale_down_quorum(&config, &removed(3), false).unwrap();
+        assert!(check_scale_down_quorum(&config, &removed(2), false)
+            .unwrap_err()
+            .to_string()
+            .contains("2 of 5 voters would remain, fewer than the quorum of 3"));
+        check_scale_down_quorum(&config, &removed(1), true).unwrap();
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::{Api, ListParams};
+
+use crate::{pod_ordinal, ExecIn};
+
+pub const LABEL_KEY_NAME: &str = "app.kubernetes.io/name";
+pub const LABEL_KEY_INSTANCE: &str = "app.kubernetes.io/instance";
+
+/// Vault or one of its forks, determining the labels of the pods
+#[derive(
+    ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavor {
+    /// HashiCorp Vault, installed by the vault helm chart
+    #[default]
+    Vault,
+    /// OpenBao, installed by the openbao helm chart
+    Openbao,
+}
+
+impl std::fmt::Display for Flavor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl Flavor {
+    /// Value of the `app.kubernetes.io/name` label of the pods
+    pub fn name(&self) -> &'static str {
+        match self {
+            Flavor::Vault => "vault",
+            Flavor::Openbao => "openbao",
+        }
+    }
+
+    /// Name of the server container in the pods installed by the helm chart
+    pub fn container_name(&self) -> &'static str {
+        self.name()
+    }
+
+    /// Key of a label set by the service registration, e.g. `vault-active`
+    pub fn label_key(&self, label: &str) -> String {
+        format!("{}-{}", self.name(), label)
+    }
+
+    /// Detect the flavor of a pod
+    ///
+    /// The service registration labels reflect the running server, followed by the image
+    /// (e.g. OpenBao installed with the vault chart) and the `app.kubernetes.io/name` label.
+    pub fn of_pod(pod: &Pod) -> Option<Flavor> {
+        let labels = pod.metadata.labels.as_ref();
+
+        let by_registration = Flavor::value_variants()
+            .iter()
+            .find(|flavor| labels.is_some_and(|l| l.contains_key(&flavor.label_key("sealed"))));
+
+        let images = pod
+            .spec
+            .iter()
+            .flat_map(|s| s.containers.iter())
+            .filter_map(|c| c.image.as_deref())
+            .collect::<Vec<_>>();
+        // check openbao first, its images may be named after vault as well
+        let by_image = [Flavor::Openbao, Flavor::Vault]
+            .iter()
+            .find(|flavor| images.iter().any(|i| i.contains(flavor.name())));
+
+        let by_name = Flavor::value_variants().iter().find(|flavor| {
+            labels
+                .and_then(|l| l.get(LABEL_KEY_NAME))
+                .map(String::as_str)
+                == Some(flavor.name())
+        });
+
+        by_registration.or(by_image).or(by_name).copied()
+    }
+}
+
+/// Get a label set by the service registration (e.g. `active`) of the pod, regardless of the flavor
+pub fn registration_label<'a>(pod: &'a Pod, label: &str) -> Option<&'a str> {
+    let labels = pod.metadata.labels.as_ref()?;
+
+    Flavor::value_variants()
+        .iter()
+        .find_map(|flavor| labels.get(&flavor.label_key(label)))
+        .map(String::as_str)
+}
+
+/// Find a pod by its name or by the ordinal of its statefulset, e.g. `vault-2` or `2`
+pub fn find_pod<'a>(pods: &'a [Pod], pod: &str) -> anyhow::Result<&'a Pod> {
+    let ordinal = pod.parse::<i32>().ok();
+
+    pods.iter()
+        .find(|p| {
+            p.metadata.name.as_deref().is_some_and(|name| {
+                name == pod || ordinal.is_some() && pod_ordinal(name).ok() == ordinal
+            })
+        })
+        .ok_or(anyhow::anyhow!(
+            "no vault pod {} found, the pods are {}",
+            pod,
+            pods.iter()
+                .filter_map(|p| p.metadata.name.as_deref())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+}
+
+/// Label selector for the vault pods
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PodSelector {
+    pub flavor: Flavor,
+    /// helm release, all releases if not set
+    pub instance: Option<String>,
+    /// only pods with this role
+    pub role: Option<ExecIn>,
+    /// additional labels the pods must have
+    pub labels: BTreeMap<String, String>,
+    /// name of the vault container in the pods, detected if not set, see `find_vault_container`
+    pub container: Option<String>,
+}
+
+impl PodSelector {
+    pub fn flavor(mut self, flavor: Flavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    pub fn instance(mut self, instance: Option<String>) -> Self {
+        self.instance = instance;
+        self
+    }
+
+    pub fn role(mut self, role: ExecIn) -> Self {
+        self.role = Some(role);
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn container(mut self, container: Option<String>) -> Self {
+        self.container = container;
+        self
+    }
+
+    /// Only pods which are unsealed
+    pub fn unsealed(self) -> Self {
+        let key = self.flavor.label_key("sealed");
+        self.label(&key, "false")
+    }
+
+    pub fn to_label_selector(&self) -> String {
+        let mut selector = vec![format!("{}={}", LABEL_KEY_NAME, self.flavor.name())];
+
+        if let Some(instance) = &self.instance {
+            selector.push(format!("{}={}", LABEL_KEY_INSTANCE, instance));
+        }
+
+        if let Some(role) = &self.role {
+            selector.push(match role {
+                ExecIn::Active => format!("{}=true", self.flavor.label_key("active")),
+                ExecIn::Standby => format!("{}=false", self.flavor.label_key("active")),
+                ExecIn::Sealed => format!("{}=true", self.flavor.label_key("sealed")),
+            });
+        }
+
+        selector.extend(self.labels.iter().map(|(k, v)| format!("{}={}", k, v)));
+
+        selector.join(",")
+    }
+
+    pub fn to_list_params(&self) -> ListParams {
+        ListParams::default().labels(&self.to_label_selector())
+    }
+}
+
+/// List the pods of all flavors matching the rest of the selector,
+/// grouped by the flavor detected for each pod (see `Flavor::of_pod`)
+pub async fn list_pods_by_flavor(
+    api: &Api<Pod>,
+    selector: &PodSelector,
+) -> anyhow::Result<BTreeMap<Flavor, Vec<Pod>>> {
+    let mut groups: BTreeMap<Flavor, Vec<Pod>> = BTreeMap::new();
+
+    for flavor in Flavor::value_variants() {
+        let pods = api
+            .list(&selector.clone().flavor(*flavor).to_list_params())
+            .await?;
+
+        for pod in pods.items {
+            groups
+                .entry(Flavor::of_pod(&pod).unwrap_or(*flavor))
+                .or_default()
+                .push(pod);
+        }
+    }
+
+    Ok(groups)
+}
+
+impl std::fmt::Display for PodSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_label_selector().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::{Container, Pod, PodSpec};
+    use kube::api::ObjectMeta;
+
+    use crate::{find_pod, registration_label, ExecIn, Flavor, PodSelector};
+
+    #[test]
+    fn default_selector_matches_all_vault_pods() {
+        assert_eq!(
+            PodSelector::default().to_label_selector(),
+            "app.kubernetes.io/name=vault"
+        );
+    }
+
+    #[test]
+    fn selector_renders_all_parts() {
+        let selector = PodSelector::default()
+            .flavor(Flavor::Openbao)
+            .instance(Some("bao".to_string()))
+            .role(ExecIn::Standby)
+            .label("component", "server");
+
+        assert_eq!(
+            selector.to_label_selector(),
+            "app.kubernetes.io/name=openbao,app.kubernetes.io/instance=bao,openbao-active=false,component=server"
+        );
+    }
+
+    #[test]
+    fn unsealed_selector_uses_sealed_label_of_flavor() {
+        assert_eq!(
+            PodSelector::default().unsealed().to_label_selector(),
+            "app.kubernetes.io/name=vault,vault-sealed=false"
+        );
+    }
+
+    #[test]
+    fn registration_label_is_found_for_any_flavor() {
+        let pod = |key: &str| Pod {
+            metadata: ObjectMeta {
+                labels: Some([(key.to_string(), "true".to_string())].into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            registration_label(&pod("vault-active"), "active"),
+            Some("true")
+        );
+        assert_eq!(
+            registration_label(&pod("openbao-active"), "active"),
+            Some("true")
+        );
+        assert_eq!(registration_label(&pod("vault-sealed"), "active"), None);
+    }
+
+    #[test]
+    fn flavor_is_detected_from_labels_and_image() {
+        let pod = |labels: &[(&str, &str)], image: &str| Pod {
+            metadata: ObjectMeta {
+                labels: Some(
+                    labels
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect(),
+                ),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "vault".to_string(),
+                    image: Some(image.to_string()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Flavor::of_pod(&pod(
+                &[("app.kubernetes.io/name", "vault")],
+                "hashicorp/vault:1.16.0"
+            )),
+            Some(Flavor::Vault)
+        );
+        // openbao installed with the vault chart
+        assert_eq!(
+            Flavor::of_pod(&pod(
+                &[("app.kubernetes.io/name", "vault")],
+                "quay.io/openbao/openbao:2.0.0"
+            )),
+            Some(Flavor::Openbao)
+        );
+        assert_eq!(
+            Flavor::of_pod(&pod(
+                &[("openbao-sealed", "false")],
+                "registry.example.com/bao:2.0.0"
+            )),
+            Some(Flavor::Openbao)
+        );
+        assert_eq!(
+            Flavor::of_pod(&pod(&[], "registry.example.com/bao:2.0.0")),
+            None
+        );
+    }
+
+    #[test]
+    fn pods_are_found_by_name_or_ordinal() {
+        let pod = |name: &str| Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let pods = [pod("vault-0"), pod("vault-1"), pod("vault-12")];
+
+        let name = |p: anyhow::Result<&Pod>| p.unwrap().metadata.name.clone().unwrap();
+        assert_eq!(name(find_pod(&pods, "vault-1")), "vault-1");
+        assert_eq!(name(find_pod(&pods, "1")), "vault-1");
+        assert_eq!(name(find_pod(&pods, "12")), "vault-12");
+
+        let err = find_pod(&pods, "3").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "no vault pod 3 found, the pods are vault-0, vault-1, vault-12"
+        );
+    }
+}
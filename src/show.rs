@@ -1,30 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, SystemTime},
+};
+
+use futures_util::future::join_all;
 use k8s_openapi::api::core::v1::Pod;
 use kube::api::Api;
 use prettytable::{color, Attr, Cell, Row, Table};
 
-use crate::list_vault_pods;
+use crate::{
+    format_duration, format_timestamp, parse_vault_timestamp, raft_server_of_pod,
+    registration_label, AuditDevice, AutopilotConfiguration, AutopilotState, Edition, GetLeader,
+    GetSealStatus, PlannedAction, PodApi, PodSelector, RaftConfiguration, TimeFormat, TokenInfo,
+    UpgradeHistory, UpgradePlan, VaultVersion, VAULT_PORT,
+};
+
+/// Combined state of a vault pod in the cluster
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PodState {
+    /// vault is not initialized and the pod is not part of the raft cluster
+    Uninitialized,
+    /// the pod is part of the raft cluster, but not initialized or catching up as a non-voter
+    Joining,
+    /// unsealed non-voter, which is up to date with the raft log
+    NonVoter,
+    /// vault is initialized, but sealed
+    Sealed,
+    /// unsealed standby
+    Standby,
+    /// unsealed and active
+    Active,
+    /// the labels of the pod are missing
+    Unknown,
+}
+
+impl std::fmt::Display for PodState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PodState::Uninitialized => write!(f, "uninitialized"),
+            PodState::Joining => write!(f, "joining"),
+            PodState::NonVoter => write!(f, "non-voter"),
+            PodState::Sealed => write!(f, "sealed"),
+            PodState::Standby => write!(f, "standby"),
+            PodState::Active => write!(f, "active"),
+            PodState::Unknown => write!(f, "unknown"),
+        }
+    }
+}
 
+/// Determine the state of a vault pod from its labels and the raft membership
+///
+/// Without the raft configuration joining pods are shown as uninitialized or standby.
+/// Non-voters are only shown as joining while autopilot reports them as unhealthy,
+/// i.e. trailing the leader, without the autopilot state they are shown as non-voters.
+pub fn pod_state(
+    pod: &Pod,
+    raft: Option<&RaftConfiguration>,
+    autopilot: Option<&AutopilotState>,
+) -> PodState {
+    let label = |key: &str| registration_label(pod, key).and_then(|v| v.parse::<bool>().ok());
+
+    let server = match (raft, pod.metadata.name.as_ref()) {
+        (Some(raft), Some(name)) => raft_server_of_pod(raft, name),
+        _ => None,
+    };
+    let catching_up = server.is_some_and(|s| {
+        autopilot
+            .and_then(|a| a.data.servers.get(&s.node_id))
+            .is_some_and(|a| !a.healthy)
+    });
+
+    match (label("initialized"), label("sealed"), label("active")) {
+        (_, Some(false), Some(true)) => PodState::Active,
+        (Some(false), _, _) if server.is_some() => PodState::Joining,
+        (Some(false), _, _) => PodState::Uninitialized,
+        (_, Some(true), _) => PodState::Sealed,
+        (_, Some(false), _) if server.is_some_and(|s| !s.voter) && catching_up => PodState::Joining,
+        (_, Some(false), _) if server.is_some_and(|s| !s.voter) => PodState::NonVoter,
+        (_, Some(false), _) => PodState::Standby,
+        _ => PodState::Unknown,
+    }
+}
+
+/// Show the vault pods with their labels
+///
+/// The raft configuration and autopilot state are used to detect pods joining the cluster,
+/// see `pod_state`.
 #[tracing::instrument(skip_all)]
-pub async fn construct_table(api: &Api<Pod>) -> anyhow::Result<Table> {
+pub async fn construct_table(
+    api: &Api<Pod>,
+    selector: &PodSelector,
+    raft: Option<&RaftConfiguration>,
+    autopilot: Option<&AutopilotState>,
+    time_format: TimeFormat,
+) -> anyhow::Result<Table> {
+    let pods = api.list(&selector.to_list_params()).await?;
+
+    construct_pods_table(&pods.items, raft, autopilot, time_format)
+}
+
+/// Construct a table from the given vault pods with their labels
+pub fn construct_pods_table(
+    pods: &[Pod],
+    raft: Option<&RaftConfiguration>,
+    autopilot: Option<&AutopilotState>,
+    time_format: TimeFormat,
+) -> anyhow::Result<Table> {
+    let now = SystemTime::now();
+
     let mut table = Table::new();
     table.set_titles(row![
         "NAME",
         "STATUS",
+        "AGE",
+        "STATE",
         "IMAGE",
+        "EDITION",
         "INITIALIZED",
         "SEALED",
         "ACTIVE",
         "READY",
+        "LAST UPGRADE",
     ]);
 
-    let pods = api.list(&list_vault_pods()).await?;
-
-    let get_vault_label = |pod: &Pod, label: &str| match pod.metadata.labels {
-        Some(ref labels) => labels
-            .get(label)
-            .unwrap_or(&String::from("unknown"))
-            .to_string(),
-        None => String::from("unknown"),
+    let get_vault_label = |pod: &Pod, label: &str| {
+        registration_label(pod, label)
+            .unwrap_or("unknown")
+            .to_string()
     };
 
     for p in pods.iter() {
@@ -51,7 +153,25 @@ pub async fn construct_table(api: &Api<Pod>) -> anyhow::Result<Table> {
             .clone()
             .ok_or(anyhow::anyhow!("container does not have an image"))?;
 
-        let initialized = get_vault_label(p, "vault-initialized");
+        let edition = VaultVersion::try_from(p)
+            .map(|v| v.edition().to_string())
+            .unwrap_or("unknown".to_string());
+
+        let age = p
+            .metadata
+            .creation_timestamp
+            .as_ref()
+            .map(|t| format_timestamp(SystemTime::from(t.0), now, time_format))
+            .unwrap_or("unknown".to_string());
+
+        let state = pod_state(p, raft, autopilot);
+        let state = Cell::new(&state.to_string()).with_style(Attr::ForegroundColor(match state {
+            PodState::Active | PodState::Standby | PodState::NonVoter => color::GREEN,
+            PodState::Sealed | PodState::Uninitialized => color::RED,
+            PodState::Joining | PodState::Unknown => color::YELLOW,
+        }));
+
+        let initialized = get_vault_label(p, "initialized");
         let initialized =
             Cell::new(&initialized).with_style(Attr::ForegroundColor(match initialized.as_str() {
                 "true" => color::GREEN,
@@ -59,14 +179,14 @@ pub async fn construct_table(api: &Api<Pod>) -> anyhow::Result<Table> {
                 _ => color::YELLOW,
             }));
 
-        let sealed = get_vault_label(p, "vault-sealed");
+        let sealed = get_vault_label(p, "sealed");
         let sealed = Cell::new(&sealed).with_style(Attr::ForegroundColor(match sealed.as_str() {
             "true" => color::RED,
             "false" => color::GREEN,
             _ => color::YELLOW,
         }));
 
-        let active = get_vault_label(p, "vault-active");
+        let active = get_vault_label(p, "active");
         let active = Cell::new(&active).with_style(Attr::ForegroundColor(match active.as_str() {
             "true" => color::GREEN,
             "false" => color::WHITE,
@@ -103,16 +223,577 @@ pub async fn construct_table(api: &Api<Pod>) -> anyhow::Result<Table> {
             _ => color::YELLOW,
         }));
 
+        let last_upgrade = match UpgradeHistory::from_metadata(&p.metadata) {
+            Some(history) => format!(
+                "{} from {} by {}{}",
+                format_timestamp(history.last_upgrade, now, time_format),
+                history.previous_version,
+                history.upgraded_by,
+                history
+                    .token_accessor
+                    .map(|a| format!(" (token {})", a))
+                    .unwrap_or_default()
+            ),
+            None => "-".to_string(),
+        };
+
         table.add_row(Row::new(vec![
             Cell::new(&name),
             Cell::new(&status),
+            Cell::new(&age),
+            state,
             Cell::new(&image),
+            Cell::new(&edition),
             initialized,
             sealed,
             active,
             ready,
+            Cell::new(&last_upgrade),
+        ]));
+    }
+
+    Ok(table)
+}
+
+/// Seal status and HA mode of a single vault pod as reported by its API
+struct PodSealStatusRow {
+    initialized: bool,
+    sealed: bool,
+    version: String,
+    edition: Edition,
+    ha_mode: String,
+    active_time: Option<SystemTime>,
+}
+
+async fn query_seal_status(pods: &PodApi, name: &str) -> anyhow::Result<PodSealStatusRow> {
+    let mut pf = pods.http(name, VAULT_PORT).await?;
+
+    let status = pf.seal_status().await?;
+
+    let ha_mode = match status.ha_enabled {
+        Some(true) if status.sealed => "sealed".to_string(),
+        Some(true) => {
+            let leader = pf.leader().await?;
+
+            if leader.is_self {
+                "active".to_string()
+            } else if status.edition().has_performance_standbys()
+                && leader.performance_standby.unwrap_or(false)
+            {
+                "perf-standby".to_string()
+            } else {
+                "standby".to_string()
+            }
+        }
+        Some(false) => "disabled".to_string(),
+        None => "unknown".to_string(),
+    };
+
+    Ok(PodSealStatusRow {
+        initialized: status.initialized,
+        sealed: status.sealed,
+        edition: status.edition(),
+        version: status.version,
+        ha_mode,
+        active_time: status
+            .active_time
+            .as_deref()
+            .and_then(parse_vault_timestamp),
+    })
+}
+
+/// Query the seal status of all vault pods (or only the named pod) concurrently
+/// and construct a table from the responses
+#[tracing::instrument(skip_all)]
+pub async fn construct_seal_status_table(
+    pods: &PodApi,
+    pod: Option<&str>,
+    time_format: TimeFormat,
+) -> anyhow::Result<Table> {
+    let mut names = Vec::new();
+    for p in pods.api.list(&pods.selector.to_list_params()).await?.iter() {
+        let name = p
+            .metadata
+            .name
+            .clone()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+        if pod.is_none() || pod == Some(name.as_str()) {
+            names.push(name);
+        }
+    }
+
+    if let (Some(pod), true) = (pod, names.is_empty()) {
+        anyhow::bail!("no vault pod named {} found", pod);
+    }
+
+    construct_seal_status_table_of(pods, &names, time_format).await
+}
+
+/// Query the seal status of the named pods concurrently and construct a table from the responses
+pub async fn construct_seal_status_table_of(
+    pods: &PodApi,
+    names: &[String],
+    time_format: TimeFormat,
+) -> anyhow::Result<Table> {
+    let mut table = Table::new();
+    table.set_titles(row![
+        "NAME",
+        "INITIALIZED",
+        "SEALED",
+        "VERSION",
+        "EDITION",
+        "HA MODE",
+        "ACTIVE SINCE",
+    ]);
+
+    let statuses = join_all(names.iter().map(|name| query_seal_status(pods, name))).await;
+
+    for (name, status) in names.iter().zip(statuses) {
+        let status = match status {
+            Ok(status) => status,
+            Err(e) => {
+                table.add_row(Row::new(vec![
+                    Cell::new(name),
+                    Cell::new(&format!("error: {}", e))
+                        .with_style(Attr::ForegroundColor(color::RED))
+                        .with_hspan(6),
+                ]));
+                continue;
+            }
+        };
+
+        let initialized = Cell::new(&status.initialized.to_string()).with_style(
+            Attr::ForegroundColor(match status.initialized {
+                true => color::GREEN,
+                false => color::RED,
+            }),
+        );
+
+        let sealed = Cell::new(&status.sealed.to_string()).with_style(Attr::ForegroundColor(
+            match status.sealed {
+                true => color::RED,
+                false => color::GREEN,
+            },
+        ));
+
+        let ha_mode = Cell::new(&status.ha_mode).with_style(Attr::ForegroundColor(
+            match status.ha_mode.as_str() {
+                "active" => color::GREEN,
+                "standby" | "perf-standby" => color::WHITE,
+                "sealed" => color::RED,
+                _ => color::YELLOW,
+            },
+        ));
+
+        table.add_row(Row::new(vec![
+            Cell::new(name),
+            initialized,
+            sealed,
+            Cell::new(&status.version),
+            Cell::new(&status.edition.to_string()),
+            ha_mode,
+            Cell::new(
+                &status
+                    .active_time
+                    .map(|t| format_timestamp(t, SystemTime::now(), time_format))
+                    .unwrap_or("-".to_string()),
+            ),
+        ]));
+    }
+
+    Ok(table)
+}
+
+/// Construct a table from the properties of a token
+pub fn construct_token_table(info: &TokenInfo, time_format: TimeFormat) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row!["KEY", "VALUE"]);
+
+    let ttl = match info.ttl {
+        // root tokens do not expire
+        0 => "never expires".to_string(),
+        ttl => format_duration(Duration::from_secs(ttl), time_format),
+    };
+
+    table.add_row(row!["display_name", info.display_name]);
+    table.add_row(row!["accessor", info.accessor]);
+    table.add_row(row!["type", info.type_]);
+    table.add_row(row!["path", info.path]);
+    table.add_row(row!["policies", info.policies.join(", ")]);
+    table.add_row(row!["ttl", ttl]);
+    table.add_row(row!["renewable", info.renewable]);
+    table.add_row(row!["orphan", info.orphan]);
+    table.add_row(row!["num_uses", info.num_uses]);
+
+    table
+}
+
+/// Construct a table from the enabled audit devices
+pub fn construct_audit_table(devices: &BTreeMap<String, AuditDevice>) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row!["PATH", "TYPE", "DESCRIPTION", "LOCAL", "OPTIONS"]);
+
+    for (path, device) in devices.iter() {
+        let options = device
+            .options
+            .iter()
+            .flatten()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        table.add_row(row![
+            path,
+            device.type_,
+            device.description,
+            device.local,
+            options,
+        ]);
+    }
+
+    table
+}
+
+/// Construct a table from the servers in the autopilot state
+pub fn construct_autopilot_state_table(state: &AutopilotState, time_format: TimeFormat) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row![
+        "NAME",
+        "ADDRESS",
+        "STATUS",
+        "NODE STATUS",
+        "HEALTHY",
+        "VERSION",
+        "LAST CONTACT",
+        "LAST INDEX",
+        "STABLE SINCE",
+    ]);
+
+    let now = SystemTime::now();
+
+    for server in state.data.servers.values() {
+        let healthy =
+            Cell::new(&server.healthy.to_string()).with_style(Attr::ForegroundColor(match server
+                .healthy
+            {
+                true => color::GREEN,
+                false => color::RED,
+            }));
+
+        let stable_since = server
+            .stable_since
+            .as_deref()
+            .and_then(parse_vault_timestamp)
+            .map(|t| format_timestamp(t, now, time_format))
+            .unwrap_or_default();
+
+        table.add_row(Row::new(vec![
+            Cell::new(&server.name),
+            Cell::new(&server.address),
+            Cell::new(&server.status),
+            Cell::new(&server.node_status),
+            healthy,
+            Cell::new(server.version.as_deref().unwrap_or_default()),
+            Cell::new(server.last_contact.as_deref().unwrap_or_default()),
+            Cell::new(&server.last_index.map(|i| i.to_string()).unwrap_or_default()),
+            Cell::new(&stable_since),
+        ]));
+    }
+
+    table
+}
+
+/// Construct a table from the autopilot configuration
+pub fn construct_autopilot_configuration_table(config: &AutopilotConfiguration) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row!["KEY", "VALUE"]);
+
+    table.add_row(row!["cleanup_dead_servers", config.cleanup_dead_servers]);
+    table.add_row(row![
+        "dead_server_last_contact_threshold",
+        config.dead_server_last_contact_threshold
+    ]);
+    table.add_row(row![
+        "last_contact_threshold",
+        config.last_contact_threshold
+    ]);
+    table.add_row(row!["max_trailing_logs", config.max_trailing_logs]);
+    table.add_row(row!["min_quorum", config.min_quorum]);
+    table.add_row(row![
+        "server_stabilization_time",
+        config.server_stabilization_time
+    ]);
+    table.add_row(row![
+        "disable_upgrade_migration",
+        config.disable_upgrade_migration
+    ]);
+
+    table
+}
+
+/// Construct a table from the servers of a raft configuration
+pub fn construct_raft_configuration_table(config: &RaftConfiguration) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row![
+        "NODE ID",
+        "ADDRESS",
+        "LEADER",
+        "VOTER",
+        "PROTOCOL VERSION",
+    ]);
+
+    for server in config.data.config.servers.iter() {
+        let leader = Cell::new(&server.leader.to_string()).with_style(Attr::ForegroundColor(
+            match server.leader {
+                true => color::GREEN,
+                false => color::WHITE,
+            },
+        ));
+
+        let voter = Cell::new(&server.voter.to_string()).with_style(Attr::ForegroundColor(
+            match server.voter {
+                true => color::GREEN,
+                false => color::YELLOW,
+            },
+        ));
+
+        table.add_row(Row::new(vec![
+            Cell::new(&server.node_id),
+            Cell::new(&server.address),
+            leader,
+            voter,
+            Cell::new(&server.protocol_version),
         ]));
     }
 
+    table
+}
+
+/// Construct a table of the pods a command acts on, see `--print-target`
+pub fn construct_target_table(pods: &[Pod]) -> anyhow::Result<Table> {
+    let mut table = Table::new();
+    table.set_titles(row!["NAME", "ROLE", "VERSION"]);
+
+    for pod in pods {
+        let name = pod
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+        table.add_row(row![
+            name,
+            pod_state(pod, None, None),
+            VaultVersion::try_from(pod)?.version,
+        ]);
+    }
+
     Ok(table)
 }
+
+/// Construct a table from an upgrade plan
+pub fn construct_upgrade_plan_table(plan: &UpgradePlan) -> Table {
+    let mut table = Table::new();
+    table.set_titles(row![
+        "STEP", "NAME", "ROLE", "CURRENT", "TARGET", "ACTION", "UNSEAL",
+    ]);
+
+    for (i, pod) in plan.pods.iter().enumerate() {
+        let action = Cell::new(&pod.action.to_string()).with_style(Attr::ForegroundColor(
+            match pod.action {
+                PlannedAction::Skip => color::GREEN,
+                _ => color::YELLOW,
+            },
+        ));
+
+        table.add_row(Row::new(vec![
+            Cell::new(&(i + 1).to_string()),
+            Cell::new(&pod.name),
+            Cell::new(if pod.active { "active" } else { "standby" }),
+            Cell::new(&pod.current.version),
+            Cell::new(&plan.target.version),
+            action,
+            Cell::new(if pod.unseal { "internal" } else { "external" }),
+        ]));
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::Pod;
+
+    use crate::{pod_state, AutopilotState, PodState, RaftConfiguration};
+
+    fn pod(name: &str, initialized: &str, sealed: &str, active: &str) -> Pod {
+        serde_json::from_value(serde_json::json!({
+            "metadata": {
+                "name": name,
+                "labels": {
+                    "vault-initialized": initialized,
+                    "vault-sealed": sealed,
+                    "vault-active": active,
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn raft() -> RaftConfiguration {
+        serde_json::from_value(serde_json::json!({
+            "request_id": "",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": {
+                "config": {
+                    "index": 0,
+                    "servers": [
+                        {
+                            "node_id": "vault-0",
+                            "address": "vault-0.vault-internal:8201",
+                            "leader": true,
+                            "protocol_version": "3",
+                            "voter": true,
+                        },
+                        {
+                            "node_id": "vault-1",
+                            "address": "vault-1.vault-internal:8201",
+                            "leader": false,
+                            "protocol_version": "3",
+                            "voter": false,
+                        },
+                        {
+                            "node_id": "vault-2",
+                            "address": "vault-2.vault-internal:8201",
+                            "leader": false,
+                            "protocol_version": "3",
+                            "voter": true,
+                        },
+                    ],
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pod_state_without_raft_configuration_uses_labels() {
+        assert_eq!(
+            pod_state(&pod("vault-0", "true", "false", "true"), None, None),
+            PodState::Active
+        );
+        assert_eq!(
+            pod_state(&pod("vault-1", "true", "false", "false"), None, None),
+            PodState::Standby
+        );
+        assert_eq!(
+            pod_state(&pod("vault-2", "true", "true", "false"), None, None),
+            PodState::Sealed
+        );
+        assert_eq!(
+            pod_state(&pod("vault-3", "false", "true", "false"), None, None),
+            PodState::Uninitialized
+        );
+        assert_eq!(
+            pod_state(
+                &serde_json::from_value(serde_json::json!({})).unwrap(),
+                None,
+                None
+            ),
+            PodState::Unknown
+        );
+    }
+
+    fn autopilot(healthy: bool) -> AutopilotState {
+        let server = |id: &str, status: &str, healthy: bool| {
+            serde_json::json!({
+                "id": id,
+                "name": id,
+                "address": format!("{}.vault-internal:8201", id),
+                "node_status": "alive",
+                "healthy": healthy,
+                "status": status,
+                "last_index": 42,
+            })
+        };
+
+        serde_json::from_value(serde_json::json!({
+            "request_id": "",
+            "lease_id": "",
+            "renewable": false,
+            "lease_duration": 0,
+            "data": {
+                "healthy": healthy,
+                "failure_tolerance": 0,
+                "leader": "vault-0",
+                "voters": ["vault-0", "vault-2"],
+                "servers": {
+                    "vault-0": server("vault-0", "leader", true),
+                    "vault-1": server("vault-1", "non-voter", healthy),
+                    "vault-2": server("vault-2", "voter", true),
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn pod_state_detects_joining_pods() {
+        let raft = raft();
+        let autopilot = autopilot(false);
+
+        // unsealed, but catching up as a non-voter
+        assert_eq!(
+            pod_state(
+                &pod("vault-1", "true", "false", "false"),
+                Some(&raft),
+                Some(&autopilot)
+            ),
+            PodState::Joining
+        );
+        // voter
+        assert_eq!(
+            pod_state(
+                &pod("vault-2", "true", "false", "false"),
+                Some(&raft),
+                Some(&autopilot)
+            ),
+            PodState::Standby
+        );
+        // part of the raft cluster, but not initialized yet
+        assert_eq!(
+            pod_state(
+                &pod("vault-2", "false", "true", "false"),
+                Some(&raft),
+                Some(&autopilot)
+            ),
+            PodState::Joining
+        );
+        // not part of the raft cluster
+        assert_eq!(
+            pod_state(
+                &pod("vault-3", "false", "true", "false"),
+                Some(&raft),
+                Some(&autopilot)
+            ),
+            PodState::Uninitialized
+        );
+    }
+
+    #[test]
+    fn pod_state_detects_non_voters() {
+        let raft = raft();
+        let vault_1 = pod("vault-1", "true", "false", "false");
+
+        // up to date with the leader
+        assert_eq!(
+            pod_state(&vault_1, Some(&raft), Some(&autopilot(true))),
+            PodState::NonVoter
+        );
+        // without the autopilot state it is not known to be catching up
+        assert_eq!(pod_state(&vault_1, Some(&raft), None), PodState::NonVoter);
+    }
+}
@@ -0,0 +1,1969 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Mutex,
+};
+
+use hyper::body::Bytes;
+use k8s_openapi::api::core::v1::{Container, ContainerStatus, Pod, PodSpec, PodStatus};
+use kube::core::ObjectMeta;
+use secrecy::Secret;
+
+use crate::{
+    pod_ordinal, ActiveStrategy, ExecIn, ImagePullFailed, PodSealStatus, RaftConfigurationServer,
+    Takeover, UpgradeDriver, UpgradeEvent, UpgradeHistory, UpgradeProgress, LABEL_KEY_VAULT_ACTIVE,
+    LABEL_KEY_VAULT_SEALED, VAULT_CONTAINER_NAME,
+};
+
+/// Mutating action done by the upgrade state machine on the simulated cluster
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SimAction {
+    StepDown(String),
+    Delete(String),
+    /// the partition of the statefulset was lowered to replace the pod with this ordinal
+    Partition(i32),
+    Unseal(String),
+    Snapshot(String),
+}
+
+/// Scripted change of the simulated cluster, triggered by an action
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SimEvent {
+    /// Leadership moves to the given pod
+    MoveLeader(String),
+    /// The pod gets sealed, e.g. because it crashed
+    Seal(String),
+    /// The statefulset gets scaled to the given replicas, e.g. by an autoscaler
+    Scale(i32),
+}
+
+/// State of a simulated vault pod
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimPod {
+    pub version: String,
+    pub active: bool,
+    pub sealed: bool,
+    /// the pod stays sealed when unsealing it
+    pub never_unseals: bool,
+    /// the pod never applies the raft log committed by the leader
+    pub lags: bool,
+    /// the pod is reported as unhealthy by autopilot once it runs the target version
+    pub degrades: bool,
+    /// the pod is a non-voter of the raft configuration
+    pub non_voter: bool,
+    /// recorded upgrade history, removed when the pod is recreated
+    pub history: Option<UpgradeHistory>,
+}
+
+#[derive(Debug)]
+struct SimState {
+    pods: BTreeMap<String, SimPod>,
+    /// version of pods recreated by the statefulset
+    target: String,
+    actions: Vec<SimAction>,
+    /// published events, kept apart from the actions
+    events: Vec<UpgradeEvent>,
+    script: Vec<(SimAction, SimEvent)>,
+    /// number of following step-downs that do not move leadership
+    ignored_step_downs: usize,
+    /// replicas of the statefulset
+    replicas: i32,
+    /// recreated pods cannot pull the image of the target version
+    pull_fails: bool,
+    /// number of unseal keys needed to unseal a pod
+    threshold: u8,
+    /// persisted progress of the rollout
+    progress: Option<UpgradeProgress>,
+    /// pods that stepped down do not run for leader again until they are recreated
+    stepped_down: BTreeSet<String>,
+    /// pod reported as leader by raft instead of the active pod, i.e. stale labels
+    raft_leader: Option<String>,
+    /// number of following waits for a standby label that time out, although the pod
+    /// already stepped down
+    lagging_labels: usize,
+}
+
+impl SimState {
+    fn pod(&mut self, name: &str) -> anyhow::Result<&mut SimPod> {
+        self.pods
+            .get_mut(name)
+            .ok_or(anyhow::anyhow!("pod {} does not exist", name))
+    }
+
+    fn leader(&self) -> Option<String> {
+        self.pods
+            .iter()
+            .find(|(_, p)| p.active)
+            .map(|(name, _)| name.clone())
+    }
+
+    /// raft needs a majority of the nodes to elect a leader
+    fn has_quorum(&self) -> bool {
+        let unsealed = self.pods.values().filter(|p| !p.sealed).count();
+        unsealed > self.pods.len() / 2
+    }
+
+    /// Elect a new leader if there is none, preferring the lowest ordinal
+    fn elect(&mut self, except: Option<&str>) {
+        if !self.has_quorum() {
+            self.pods.values_mut().for_each(|p| p.active = false);
+            return;
+        }
+
+        if self.leader().is_some() {
+            return;
+        }
+
+        let candidates = self
+            .pods
+            .iter()
+            .filter(|(name, p)| !p.sealed && Some(name.as_str()) != except)
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+
+        if let Some(name) = candidates
+            .iter()
+            .find(|name| !self.stepped_down.contains(*name))
+            .or(candidates.first())
+        {
+            self.pods.get_mut(name).expect("candidate exists").active = true;
+        }
+    }
+
+    fn record(&mut self, action: SimAction) {
+        self.actions.push(action.clone());
+
+        let events: Vec<SimEvent> = self
+            .script
+            .iter()
+            .filter(|(a, _)| a == &action)
+            .map(|(_, e)| e.clone())
+            .collect();
+
+        for event in events {
+            match event {
+                SimEvent::MoveLeader(to) => {
+                    self.pods.values_mut().for_each(|p| p.active = false);
+                    if let Some(pod) = self.pods.get_mut(&to) {
+                        pod.active = true;
+                    }
+                }
+                SimEvent::Seal(name) => {
+                    if let Some(pod) = self.pods.get_mut(&name) {
+                        pod.sealed = true;
+                        pod.active = false;
+                    }
+                }
+                SimEvent::Scale(replicas) => self.replicas = replicas,
+            }
+            self.elect(None);
+        }
+    }
+
+    fn to_pod(&self, name: &str) -> anyhow::Result<Pod> {
+        let pod = self
+            .pods
+            .get(name)
+            .ok_or(anyhow::anyhow!("pod {} does not exist", name))?;
+
+        Ok(Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                labels: Some(
+                    [
+                        ("app.kubernetes.io/name", "vault".to_string()),
+                        (LABEL_KEY_VAULT_ACTIVE, pod.active.to_string()),
+                        (LABEL_KEY_VAULT_SEALED, pod.sealed.to_string()),
+                        ("vault-version", pod.version.clone()),
+                    ]
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+                ),
+                annotations: pod.history.as_ref().map(|h| h.to_annotations()),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: VAULT_CONTAINER_NAME.to_string(),
+                    image: Some(format!("hashicorp/vault:{}", pod.version)),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            status: Some(PodStatus {
+                container_statuses: Some(vec![ContainerStatus {
+                    name: VAULT_CONTAINER_NAME.to_string(),
+                    ready: !pod.sealed,
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+        })
+    }
+}
+
+/// Simulated vault cluster to drive the upgrade state machine deterministically
+///
+/// Waiting for a condition that can never be met in the simulated state (e.g. a pod
+/// that never unseals) fails immediately instead of blocking.
+/// All mutating actions are recorded and can be asserted on with `actions`.
+#[derive(Debug)]
+pub struct SimCluster {
+    state: Mutex<SimState>,
+    takeover: Takeover,
+    active_strategy: ActiveStrategy,
+    partitioned: bool,
+}
+
+impl SimCluster {
+    /// Create a cluster of unsealed pods named `<name>-<ordinal>` with the given version
+    /// The first pod is active
+    pub fn new(name: &str, replicas: usize, version: &str) -> Self {
+        let pods = (0..replicas)
+            .map(|i| {
+                (
+                    format!("{}-{}", name, i),
+                    SimPod {
+                        version: version.to_string(),
+                        active: i == 0,
+                        sealed: false,
+                        never_unseals: false,
+                        lags: false,
+                        degrades: false,
+                        non_voter: false,
+                        history: None,
+                    },
+                )
+            })
+            .collect();
+
+        Self {
+            state: Mutex::new(SimState {
+                pods,
+                target: version.to_string(),
+                actions: vec![],
+                events: vec![],
+                script: vec![],
+                ignored_step_downs: 0,
+                replicas: replicas as i32,
+                pull_fails: false,
+                threshold: 1,
+                progress: None,
+                stepped_down: BTreeSet::new(),
+                raft_leader: None,
+                lagging_labels: 0,
+            }),
+            takeover: Takeover::default(),
+            active_strategy: ActiveStrategy::default(),
+            partitioned: false,
+        }
+    }
+
+    /// Set the version of pods recreated by the statefulset
+    pub fn target(self, version: &str) -> Self {
+        self.state.lock().unwrap().target = version.to_string();
+        self
+    }
+
+    /// Change the version of recreated pods, like setting the image of the statefulset
+    pub fn set_target(&self, version: &str) {
+        self.state.lock().unwrap().target = version.to_string();
+    }
+
+    /// Apply the event after the action was done
+    pub fn on(self, action: SimAction, event: SimEvent) -> Self {
+        self.state.lock().unwrap().script.push((action, event));
+        self
+    }
+
+    /// Let the pod stay sealed when it gets unsealed (also after being recreated)
+    pub fn never_unseals(self, pod: &str) -> Self {
+        if let Some(pod) = self.state.lock().unwrap().pods.get_mut(pod) {
+            pod.never_unseals = true;
+        }
+        self
+    }
+
+    /// Let the pod never catch up with the raft log of the leader
+    pub fn lags(self, pod: &str) -> Self {
+        if let Some(pod) = self.state.lock().unwrap().pods.get_mut(pod) {
+            pod.lags = true;
+        }
+        self
+    }
+
+    /// Let autopilot report the pod as unhealthy once it was upgraded
+    pub fn degrades(self, pod: &str) -> Self {
+        if let Some(pod) = self.state.lock().unwrap().pods.get_mut(pod) {
+            pod.degrades = true;
+        }
+        self
+    }
+
+    /// Let the pod join the raft configuration as non-voter
+    pub fn non_voter(self, pod: &str) -> Self {
+        if let Some(pod) = self.state.lock().unwrap().pods.get_mut(pod) {
+            pod.non_voter = true;
+        }
+        self
+    }
+
+    /// Let raft report the pod as leader, while the labels still show the active pod
+    pub fn raft_leader(self, pod: &str) -> Self {
+        self.state.lock().unwrap().raft_leader = Some(pod.to_string());
+        self
+    }
+
+    /// Let the next waits for the standby label time out, although leadership moved
+    pub fn lagging_labels(self, count: usize) -> Self {
+        self.state.lock().unwrap().lagging_labels = count;
+        self
+    }
+
+    /// Let the next step-downs succeed without moving leadership
+    pub fn ignores_step_downs(self, count: usize) -> Self {
+        self.state.lock().unwrap().ignored_step_downs = count;
+        self
+    }
+
+    /// Set how the state machine waits for another pod to take over
+    pub fn takeover(mut self, takeover: Takeover) -> Self {
+        self.takeover = takeover;
+        self
+    }
+
+    /// Set how the state machine upgrades the active pod
+    pub fn active_strategy(mut self, strategy: ActiveStrategy) -> Self {
+        self.active_strategy = strategy;
+        self
+    }
+
+    /// Recreate pods by lowering the partition of the statefulset instead of deleting them
+    pub fn partitioned(mut self) -> Self {
+        self.partitioned = true;
+        self
+    }
+
+    /// Let recreated pods fail to pull the image of the target version
+    pub fn pull_fails(self) -> Self {
+        self.state.lock().unwrap().pull_fails = true;
+        self
+    }
+
+    /// Set the number of unseal keys needed to unseal a pod
+    pub fn threshold(self, threshold: u8) -> Self {
+        self.state.lock().unwrap().threshold = threshold;
+        self
+    }
+
+    /// Persist the progress of an interrupted rollout
+    pub fn interrupted(self, progress: UpgradeProgress) -> Self {
+        self.state.lock().unwrap().progress = Some(progress);
+        self
+    }
+
+    /// Persisted progress of the rollout
+    pub fn progress(&self) -> Option<UpgradeProgress> {
+        self.state.lock().unwrap().progress.clone()
+    }
+
+    /// Actions done on the cluster in order
+    pub fn actions(&self) -> Vec<SimAction> {
+        self.state.lock().unwrap().actions.clone()
+    }
+
+    /// Events published by the upgrade in order
+    pub fn events(&self) -> Vec<UpgradeEvent> {
+        self.state.lock().unwrap().events.clone()
+    }
+
+    /// Current state of a pod
+    pub fn pod(&self, name: &str) -> Option<SimPod> {
+        self.state.lock().unwrap().pods.get(name).cloned()
+    }
+
+    /// Name of the active pod
+    pub fn leader(&self) -> Option<String> {
+        self.state.lock().unwrap().leader()
+    }
+}
+
+#[async_trait::async_trait]
+impl UpgradeDriver for SimCluster {
+    async fn list_pods(&self, role: ExecIn) -> anyhow::Result<Vec<Pod>> {
+        let state = self.state.lock().unwrap();
+
+        state
+            .pods
+            .iter()
+            .filter(|(_, p)| match role {
+                ExecIn::Active => p.active,
+                ExecIn::Standby => !p.active,
+                ExecIn::Sealed => p.sealed,
+            })
+            .map(|(name, _)| state.to_pod(name))
+            .collect()
+    }
+
+    async fn get_pod(&self, name: &str) -> anyhow::Result<Pod> {
+        self.state.lock().unwrap().to_pod(name)
+    }
+
+    async fn seal_status(&self, name: &str) -> anyhow::Result<PodSealStatus> {
+        let mut state = self.state.lock().unwrap();
+        // every action commits a raft entry, lagging pods do not apply any
+        let committed = 1000 + state.actions.len() as u64;
+        let threshold = state.threshold;
+        let pod = state.pod(name)?;
+        let applied = if pod.lags { 0 } else { committed };
+
+        Ok(serde_json::from_value(serde_json::json!({
+            "type": "shamir",
+            "initialized": true,
+            "sealed": pod.sealed,
+            "t": threshold,
+            "n": threshold,
+            "progress": 0,
+            "nonce": "",
+            "version": pod.version,
+            "build_date": "",
+            "migration": false,
+            "recovery_seal": false,
+            "storage_type": "raft",
+            "raft_committed_index": committed,
+            "raft_applied_index": applied,
+        }))?)
+    }
+
+    async fn step_down(&self, name: &str, _token: Secret<String>) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.record(SimAction::StepDown(name.to_string()));
+
+        if state.pod(name)?.sealed {
+            anyhow::bail!("stepping-down: pod {} is sealed", name);
+        }
+
+        if state.ignored_step_downs > 0 {
+            state.ignored_step_downs -= 1;
+            return Ok(());
+        }
+
+        // standby pods forward the request to the active pod
+        let leader = state
+            .leader()
+            .ok_or(anyhow::anyhow!("stepping-down: no active pod"))?;
+
+        state.pod(&leader)?.active = false;
+        state.stepped_down.insert(leader.clone());
+        state.elect(Some(&leader));
+
+        if state.leader().is_none() {
+            state.pod(&leader)?.active = true;
+            anyhow::bail!("stepping-down: no standby pod can take over");
+        }
+
+        Ok(())
+    }
+
+    async fn await_standby(&self, name: &str) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.lagging_labels > 0 {
+            state.lagging_labels -= 1;
+            anyhow::bail!("pod {} is still labeled as active", name);
+        }
+
+        match state.pod(name)?.active {
+            true => Err(anyhow::anyhow!("pod {} never became standby", name)),
+            false => Ok(()),
+        }
+    }
+
+    async fn is_leader(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self.state.lock().unwrap().pod(name)?.active)
+    }
+
+    fn takeover_settings(&self) -> Takeover {
+        self.takeover
+    }
+
+    fn active_strategy_setting(&self) -> ActiveStrategy {
+        self.active_strategy
+    }
+
+    fn partitioned(&self) -> bool {
+        self.partitioned
+    }
+
+    async fn raft_voters(&self, _name: &str, _token: Secret<String>) -> anyhow::Result<usize> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .pods
+            .values()
+            .filter(|p| !p.non_voter)
+            .count())
+    }
+
+    async fn raft_servers(
+        &self,
+        _name: &str,
+        _token: Secret<String>,
+    ) -> anyhow::Result<Vec<RaftConfigurationServer>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .pods
+            .iter()
+            .map(|(name, p)| RaftConfigurationServer {
+                node_id: name.clone(),
+                address: format!("{}.vault-internal:8201", name),
+                leader: state
+                    .raft_leader
+                    .as_ref()
+                    .map_or(p.active, |leader| leader == name),
+                protocol_version: "3".to_string(),
+                voter: !p.non_voter,
+            })
+            .collect())
+    }
+
+    async fn raft_snapshot(&self, name: &str, _token: Secret<String>) -> anyhow::Result<Bytes> {
+        let mut state = self.state.lock().unwrap();
+        if !state.pod(name)?.active {
+            anyhow::bail!("pod {} is not active", name);
+        }
+        state.record(SimAction::Snapshot(name.to_string()));
+        Ok(Bytes::from_static(b"snapshot"))
+    }
+
+    async fn raft_committed_index(&self, _name: &str) -> anyhow::Result<Option<u64>> {
+        Ok(Some(self.state.lock().unwrap().actions.len() as u64))
+    }
+
+    async fn await_raft_caught_up(
+        &self,
+        name: &str,
+        committed: u64,
+        _max_lag: u64,
+    ) -> anyhow::Result<()> {
+        match self.state.lock().unwrap().pod(name)?.lags {
+            true => Err(anyhow::anyhow!(
+                "pod {} never caught up with raft index {}",
+                name,
+                committed
+            )),
+            false => Ok(()),
+        }
+    }
+
+    async fn replicas(&self) -> anyhow::Result<Option<i32>> {
+        Ok(Some(self.state.lock().unwrap().replicas))
+    }
+
+    async fn load_progress(&self) -> anyhow::Result<Option<UpgradeProgress>> {
+        Ok(self.progress())
+    }
+
+    async fn save_progress(&self, progress: Option<&UpgradeProgress>) -> anyhow::Result<()> {
+        self.state.lock().unwrap().progress = progress.cloned();
+        Ok(())
+    }
+
+    async fn record_history(&self, name: &str, history: &UpgradeHistory) -> anyhow::Result<()> {
+        self.state.lock().unwrap().pod(name)?.history = Some(history.clone());
+        Ok(())
+    }
+
+    async fn publish_event(&self, event: &UpgradeEvent) {
+        self.state.lock().unwrap().events.push(event.clone());
+    }
+
+    async fn await_autopilot_healthy(
+        &self,
+        _name: &str,
+        _token: Secret<String>,
+    ) -> anyhow::Result<()> {
+        let state = self.state.lock().unwrap();
+
+        // every server has to be unsealed and following the leader
+        if state.leader().is_none() || state.pods.values().any(|p| p.sealed) {
+            anyhow::bail!("autopilot never reported a healthy cluster");
+        }
+
+        Ok(())
+    }
+
+    async fn delete_pod(&self, name: &str) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        let target = state.target.clone();
+        let pod = state.pod(name)?;
+
+        // the statefulset recreates the pod with the target version
+        pod.version = target;
+        pod.active = false;
+        pod.sealed = true;
+        pod.history = None;
+
+        state.stepped_down.remove(name);
+        state.elect(None);
+        state.record(match self.partitioned {
+            true => SimAction::Partition(pod_ordinal(name)?),
+            false => SimAction::Delete(name.to_string()),
+        });
+
+        Ok(())
+    }
+
+    async fn await_running(&self, name: &str) -> anyhow::Result<()> {
+        // the recreated pod takes a while to start, let other upgrades proceed meanwhile
+        tokio::task::yield_now().await;
+
+        let mut state = self.state.lock().unwrap();
+        let target = state.target.clone();
+        let pull_fails = state.pull_fails;
+        let pod = state.pod(name)?;
+
+        if pull_fails && pod.version == target {
+            return Err(ImagePullFailed {
+                pod: name.to_string(),
+                image: format!("hashicorp/vault:{}", target),
+                reason: "ErrImagePull".to_string(),
+            }
+            .into());
+        }
+
+        Ok(())
+    }
+
+    async fn unseal(&self, name: &str, keys: &[Secret<String>]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if keys.is_empty() {
+            anyhow::bail!("unsealing pod {}: no keys provided", name);
+        }
+
+        let pod = state.pod(name)?;
+        if !pod.never_unseals {
+            pod.sealed = false;
+        }
+
+        state.elect(None);
+        state.record(SimAction::Unseal(name.to_string()));
+
+        Ok(())
+    }
+
+    async fn await_unsealed(&self, name: &str) -> anyhow::Result<()> {
+        match self.state.lock().unwrap().pod(name)?.sealed {
+            true => Err(anyhow::anyhow!("pod {} never got unsealed", name)),
+            false => Ok(()),
+        }
+    }
+
+    async fn await_ready(&self, pod: &Pod) -> anyhow::Result<()> {
+        let name = pod
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+        let state = self.state.lock().unwrap();
+
+        if state.leader().is_none() {
+            anyhow::bail!("pod {} never got ready: cluster has no leader", name);
+        }
+
+        Ok(())
+    }
+
+    async fn unhealthy_raft_servers(
+        &self,
+        _name: &str,
+        _token: Secret<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state
+            .pods
+            .iter()
+            .filter(|(_, p)| p.sealed || (p.degrades && p.version == state.target))
+            .map(|(name, _)| name.clone())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        str::FromStr,
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use secrecy::Secret;
+
+    use crate::{
+        plan_upgrade, previous_version_of, read_journal, roll_back_pods, rolling_restart,
+        rolling_upgrade, unfinished_actions, ActiveStrategy, ClusterUpgradeOptions,
+        DeadlineExceeded, DowngradeRefused, ExecIn, HealthGate, ImagePullFailed, Journal,
+        JournalState, KeyThresholdNotMet, PodHook, PodSealStatus, PodStep, SimAction, SimCluster,
+        SimEvent, SnapshotDestination, Takeover, UnsealMethod, UpgradeDriver, UpgradeEvent,
+        UpgradeInterrupted, UpgradeLockLost, UpgradeOptions, UpgradePhase, UpgradePlan,
+        UpgradeProgress, UpgradeReporter, VaultVersion,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    fn keys() -> Vec<Secret<String>> {
+        vec![Secret::from_str("key").unwrap()]
+    }
+
+    fn target() -> VaultVersion {
+        VaultVersion::from_str("1.14.0").unwrap()
+    }
+
+    async fn upgrade(cluster: &SimCluster) -> anyhow::Result<()> {
+        upgrade_with(cluster, &ClusterUpgradeOptions::default()).await
+    }
+
+    async fn upgrade_with(
+        cluster: &SimCluster,
+        options: &ClusterUpgradeOptions,
+    ) -> anyhow::Result<()> {
+        rolling_upgrade(
+            cluster,
+            &target(),
+            Secret::from_str("token").unwrap(),
+            &keys(),
+            options,
+        )
+        .await
+    }
+
+    fn pod(n: u8) -> String {
+        format!("vault-{}", n)
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_does_standby_pods_first() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(
+            cluster.actions(),
+            vec![
+                SimAction::Delete(pod(1)),
+                SimAction::Unseal(pod(1)),
+                SimAction::Delete(pod(2)),
+                SimAction::Unseal(pod(2)),
+                SimAction::StepDown(pod(0)),
+                SimAction::Delete(pod(0)),
+                SimAction::Unseal(pod(0)),
+            ]
+        );
+
+        for n in 0..3 {
+            let p = cluster.pod(&pod(n)).unwrap();
+            assert_eq!(p.version, "1.14.0");
+            assert!(!p.sealed);
+        }
+        assert_eq!(cluster.leader(), Some(pod(1)));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_does_non_voters_first() {
+        let cluster = SimCluster::new("vault", 4, "1.13.0")
+            .target("1.14.0")
+            .non_voter(&pod(3));
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(
+            cluster.actions(),
+            vec![
+                SimAction::Delete(pod(3)),
+                SimAction::Unseal(pod(3)),
+                SimAction::Delete(pod(1)),
+                SimAction::Unseal(pod(1)),
+                SimAction::Delete(pod(2)),
+                SimAction::Unseal(pod(2)),
+                SimAction::StepDown(pod(0)),
+                SimAction::Delete(pod(0)),
+                SimAction::Unseal(pod(0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_partitioned_upgrade_goes_down_the_ordinals() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .partitioned()
+            .on(SimAction::Unseal(pod(2)), SimEvent::MoveLeader(pod(1)));
+
+        upgrade(&cluster).await.unwrap();
+
+        // the pods are replaced from the highest ordinal down, stepping down each leader
+        assert_eq!(
+            cluster.actions(),
+            vec![
+                SimAction::Partition(2),
+                SimAction::Unseal(pod(2)),
+                SimAction::StepDown(pod(1)),
+                SimAction::Partition(1),
+                SimAction::Unseal(pod(1)),
+                SimAction::StepDown(pod(0)),
+                SimAction::Partition(0),
+                SimAction::Unseal(pod(0)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_partitioned_restart_is_refused() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").partitioned();
+
+        let restarted = rolling_restart(
+            &cluster,
+            Secret::from_str("token").unwrap(),
+            &[Secret::from_str("key").unwrap()],
+            &ClusterUpgradeOptions::default(),
+        )
+        .await;
+
+        assert!(restarted.is_err());
+        assert_eq!(cluster.actions(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn plan_has_the_order_of_the_rollout() {
+        let cluster = SimCluster::new("vault", 4, "1.13.0")
+            .target("1.14.0")
+            .non_voter(&pod(3));
+        let names = |plan: UpgradePlan| plan.pods.into_iter().map(|p| p.name).collect::<Vec<_>>();
+
+        let plan = plan_upgrade(
+            &cluster,
+            &target(),
+            Some(Secret::from_str("token").unwrap()),
+            &UpgradeOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(names(plan), vec![pod(3), pod(1), pod(2), pod(0)]);
+
+        // without a token the order of the labels is kept
+        let plan = plan_upgrade(&cluster, &target(), None, &UpgradeOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(names(plan), vec![pod(1), pod(2), pod(3), pod(0)]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_refuses_leader_labeled_as_standby() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .raft_leader(&pod(2));
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("labels are stale"));
+        assert_eq!(cluster.actions(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_skips_current_pods() {
+        let cluster = SimCluster::new("vault", 3, "1.14.0");
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(cluster.actions(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_with_leader_moving_mid_rollout() {
+        // the pods are listed once at the start, so the previously active pod is still
+        // listed as active after leadership moved to an upgraded pod.
+        // its role is checked again before stepping it down, so the step-down is skipped.
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .on(SimAction::Unseal(pod(1)), SimEvent::MoveLeader(pod(1)));
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(
+            cluster.actions(),
+            vec![
+                SimAction::Delete(pod(1)),
+                SimAction::Unseal(pod(1)),
+                SimAction::Delete(pod(2)),
+                SimAction::Unseal(pod(2)),
+                SimAction::Delete(pod(0)),
+                SimAction::Unseal(pod(0)),
+            ]
+        );
+        assert_eq!(cluster.leader(), Some(pod(1)));
+        for i in 0..3 {
+            assert_eq!(cluster.pod(&pod(i)).unwrap().version, "1.14.0");
+        }
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_pod_never_unseals() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .never_unseals(&pod(1));
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("never got unsealed"));
+        assert_eq!(
+            cluster.actions(),
+            vec![SimAction::Delete(pod(1)), SimAction::Unseal(pod(1))]
+        );
+        assert_eq!(cluster.leader(), Some(pod(0)));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_waits_for_external_unseal() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .never_unseals(&pod(1));
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .should_unseal(false)
+                    .external_unseal_timeout(Duration::from_secs(1)),
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("waiting for pod vault-1 to be unsealed externally"));
+        // vault-mgmt never unseals the pod itself
+        assert_eq!(cluster.actions(), vec![SimAction::Delete(pod(1))]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_runs_hooks_around_each_pod() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let calls = Arc::new(Mutex::new(vec![]));
+
+        let hook = |kind: &'static str| {
+            let calls = calls.clone();
+            PodHook::new(move |pod| {
+                calls.lock().unwrap().push(format!(
+                    "{} {}",
+                    kind,
+                    pod.metadata.name.unwrap_or_default()
+                ));
+                async { Ok(()) }
+            })
+        };
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .pre_pod_hook(hook("pre"))
+                    .post_pod_hook(hook("post")),
+            ),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![
+                "pre vault-1",
+                "post vault-1",
+                "pre vault-2",
+                "post vault-2",
+                "pre vault-0",
+                "post vault-0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_reports_each_pod() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let reporter = UpgradeReporter::new();
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(UpgradeOptions::default().report(reporter.clone())),
+        )
+        .await
+        .unwrap();
+
+        let report = reporter.report();
+        assert!(report.completed);
+        assert_eq!(report.target.as_deref(), Some("1.14.0"));
+        assert_eq!(report.estimate.map(|e| e.remaining_pods), Some(0));
+        assert_eq!(
+            report
+                .pods
+                .iter()
+                .map(|p| p.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["vault-1", "vault-2", "vault-0"]
+        );
+
+        let active = &report.pods[2];
+        assert_eq!(active.previous_version.as_deref(), Some("1.13.0"));
+        assert_eq!(active.new_version.as_deref(), Some("1.14.0"));
+        assert_eq!(active.unseal, Some(UnsealMethod::Keys));
+        assert!(!active.skipped);
+        assert_eq!(
+            active.steps.iter().map(|s| s.step).collect::<Vec<_>>(),
+            vec![
+                PodStep::StepDown,
+                PodStep::Delete,
+                PodStep::Running,
+                PodStep::Unseal,
+                PodStep::Ready,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_needs_enough_keys_for_the_threshold() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .threshold(2);
+
+        // the same key twice does not meet the threshold
+        let err = rolling_upgrade(
+            &cluster,
+            &target(),
+            Secret::from_str("token").unwrap(),
+            &[
+                Secret::from_str("key").unwrap(),
+                Secret::from_str("key").unwrap(),
+            ],
+            &ClusterUpgradeOptions::default(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<KeyThresholdNotMet>(),
+            Some(&KeyThresholdNotMet {
+                keys: 1,
+                threshold: 2
+            })
+        );
+        assert!(cluster.actions().is_empty());
+
+        rolling_upgrade(
+            &cluster,
+            &target(),
+            Secret::from_str("token").unwrap(),
+            &[
+                Secret::from_str("a").unwrap(),
+                Secret::from_str("b").unwrap(),
+            ],
+            &ClusterUpgradeOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.14.0");
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_at_the_deadline() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let reporter = UpgradeReporter::new();
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(UpgradeOptions::default().report(reporter.clone()))
+                .deadline(Some(SystemTime::now())),
+        )
+        .await
+        .unwrap_err();
+
+        let exceeded = err.downcast_ref::<DeadlineExceeded>().unwrap();
+        assert_eq!((exceeded.done, exceeded.remaining), (0, 3));
+        assert!(cluster.actions().is_empty());
+        // the progress is kept, so the upgrade can be resumed
+        assert!(cluster.progress().is_some());
+
+        let report = reporter.report();
+        assert!(!report.completed);
+        assert!(report
+            .error
+            .unwrap()
+            .contains("stopping before the deadline"));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_finishes_pods_in_progress_when_interrupted() {
+        let cluster = SimCluster::new("vault", 5, "1.13.0").target("1.14.0");
+        let interrupt = CancellationToken::new();
+
+        // interrupted while the first pods are upgraded in parallel
+        let gate = {
+            let interrupt = interrupt.clone();
+            HealthGate::new("interrupt", move |_, _| {
+                interrupt.cancel();
+                async { Ok(true) }
+            })
+        };
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(UpgradeOptions::default().gate(gate))
+                .max_unavailable(2)
+                .interrupt(interrupt),
+        )
+        .await
+        .unwrap_err();
+
+        let interrupted = err.downcast_ref::<UpgradeInterrupted>().unwrap();
+        assert_eq!((interrupted.done, interrupted.remaining), (2, 3));
+        let actions = cluster.actions();
+        assert_eq!(
+            actions[..2],
+            [SimAction::Delete(pod(1)), SimAction::Delete(pod(2))]
+        );
+        assert_eq!(actions.len(), 4);
+        assert!(actions.contains(&SimAction::Unseal(pod(1))));
+        assert!(actions.contains(&SimAction::Unseal(pod(2))));
+
+        let progress = cluster.progress().unwrap();
+        assert_eq!(progress.pods[&pod(1)].phase, UpgradePhase::Done);
+        assert_eq!(progress.pods[&pod(2)].phase, UpgradePhase::Done);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_the_lock_is_lost() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let lost = CancellationToken::new();
+
+        // another process takes over the lock while the first pod is upgraded
+        let gate = {
+            let lost = lost.clone();
+            HealthGate::new("lock", move |_, _| {
+                lost.cancel();
+                async { Ok(true) }
+            })
+        };
+
+        let options = ClusterUpgradeOptions {
+            lock_lost: Some(lost),
+            ..UpgradeOptions::default().gate(gate).into()
+        };
+        let err = upgrade_with(&cluster, &options).await.unwrap_err();
+
+        let lock_lost = err.downcast_ref::<UpgradeLockLost>().unwrap();
+        assert_eq!((lock_lost.done, lock_lost.remaining), (1, 2));
+        assert_eq!(
+            cluster.actions(),
+            [SimAction::Delete(pod(1)), SimAction::Unseal(pod(1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_finishes_pods_in_progress_when_a_pod_fails() {
+        let cluster = SimCluster::new("vault", 5, "1.13.0").target("1.14.0");
+
+        // pod 2 is still upgraded when pod 1 fails
+        let failed = CancellationToken::new();
+        let gate = HealthGate::new("fails", move |p, _| {
+            let failed = failed.clone();
+            async move {
+                if p.metadata.name == Some(pod(1)) {
+                    failed.cancel();
+                    anyhow::bail!("unhealthy");
+                }
+                failed.cancelled().await;
+                Ok(true)
+            }
+        });
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(UpgradeOptions::default().gate(gate)).max_unavailable(2),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(format!("{:#}", err).contains("unhealthy"));
+        let actions = cluster.actions();
+        assert!(actions.contains(&SimAction::Unseal(pod(2))));
+        assert!(!actions.contains(&SimAction::Delete(pod(0))));
+
+        // every other pod that was deleted was finished and recorded
+        let progress = cluster.progress().unwrap();
+        assert_eq!(progress.pods[&pod(1)].phase, UpgradePhase::Started);
+        for action in actions {
+            if let SimAction::Delete(name) = action {
+                if name != pod(1) {
+                    assert_eq!(progress.pods[&name].phase, UpgradePhase::Done);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_pre_pod_hook_fails() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .pre_pod_hook(PodHook::new(|_| async { anyhow::bail!("still draining") })),
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("pre-pod hook failed for pod vault-1"));
+        assert_eq!(cluster.actions(), vec![]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_hooks_hang() {
+        let hang = || PodHook::new(|_| std::future::pending());
+
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .pre_pod_hook(hang())
+                    .hook_timeout(Duration::from_millis(10)),
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(format!("{:#}", err).contains("did not finish within 10ms"));
+        assert_eq!(cluster.actions(), vec![]);
+
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .leader_hook(hang())
+                    .hook_timeout(Duration::from_millis(10)),
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.to_string().contains("leader hook for pod vault-1"));
+        assert_eq!(cluster.leader(), Some(pod(1)));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_awaits_health_gates_after_each_pod() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let checked = Arc::new(Mutex::new(vec![]));
+
+        let gate = {
+            let checked = checked.clone();
+            HealthGate::new("record", move |pod, _| {
+                checked
+                    .lock()
+                    .unwrap()
+                    .push(pod.metadata.name.unwrap_or_default());
+                async { Ok(true) }
+            })
+        };
+        let upgraded = HealthGate::seal_status("upgraded", |s: Option<&PodSealStatus>| {
+            s.is_some_and(|s| s.version == "1.14.0" && !s.sealed)
+        });
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(UpgradeOptions::default().gate(upgraded).gate(gate)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(*checked.lock().unwrap(), vec![pod(1), pod(2), pod(0)]);
+
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .gate(HealthGate::new("never", |_, _| async { Ok(false) }))
+                    .gate_timeout(Duration::from_millis(10)),
+            ),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("waiting for the health gates of pod vault-1"));
+        assert_eq!(cluster.actions().last(), Some(&SimAction::Unseal(pod(1))));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_runs_leader_hook_with_new_leader() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let calls = Arc::new(Mutex::new(vec![]));
+
+        let record = |kind: &'static str| {
+            let calls = calls.clone();
+            move |name: String| calls.lock().unwrap().push(format!("{} {}", kind, name))
+        };
+        let hook = {
+            let record = record("hook");
+            PodHook::new(move |pod| {
+                record(pod.metadata.name.unwrap_or_default());
+                async { Ok(()) }
+            })
+        };
+        let gate = {
+            let record = record("gate");
+            HealthGate::new("lb", move |pod, _| {
+                record(pod.metadata.name.unwrap_or_default());
+                async { Ok(true) }
+            })
+        };
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default()
+                    .leader_hook(hook)
+                    .leader_gate(gate),
+            ),
+        )
+        .await
+        .unwrap();
+
+        // only the step-down of the active pod moves leadership
+        assert_eq!(*calls.lock().unwrap(), vec!["hook vault-1", "gate vault-1"]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_writes_journal() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let path =
+            std::env::temp_dir().join(format!("vault-mgmt-journal-{}", rand::random::<u32>()));
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::from(
+                UpgradeOptions::default().journal(Journal::open(&path).unwrap()),
+            ),
+        )
+        .await
+        .unwrap();
+
+        let entries = read_journal(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let entry = |state, action: &str, pod: Option<&str>| {
+            entries
+                .iter()
+                .position(|e| e.state == state && e.action == action && e.pod.as_deref() == pod)
+                .unwrap()
+        };
+        assert!(
+            entry(JournalState::Intended, "delete", Some("vault-1"))
+                < entry(JournalState::Completed, "delete", Some("vault-1"))
+        );
+        assert_eq!(
+            entries.first().unwrap().detail.as_deref(),
+            Some("target 1.14.0")
+        );
+        assert_eq!(entries.last().unwrap().action, "upgrade");
+        assert_eq!(entries.last().unwrap().state, JournalState::Completed);
+        assert!(unfinished_actions(&entries).is_empty());
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_refuses_downgrade() {
+        let cluster = SimCluster::new("vault", 3, "1.15.0").target("1.14.0");
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<DowngradeRefused>().unwrap().to_string(),
+            "refusing to downgrade pod vault-1 from 1.15.0 to 1.14.0"
+        );
+        assert_eq!(cluster.actions(), vec![]);
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().allow_downgrade(true),
+        )
+        .await
+        .unwrap();
+
+        for n in 0..3 {
+            assert_eq!(cluster.pod(&pod(n)).unwrap().version, "1.14.0");
+        }
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_on_quorum_loss() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .on(SimAction::Delete(pod(1)), SimEvent::Seal(pod(0)))
+            .on(SimAction::Delete(pod(1)), SimEvent::Seal(pod(2)));
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("no leader"));
+        assert_eq!(
+            cluster.actions(),
+            vec![SimAction::Delete(pod(1)), SimAction::Unseal(pod(1))]
+        );
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.13.0");
+    }
+
+    #[tokio::test]
+    async fn simulated_restart_rolls_all_pods() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0");
+
+        rolling_restart(
+            &cluster,
+            Secret::from_str("token").unwrap(),
+            &keys(),
+            &ClusterUpgradeOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            cluster
+                .actions()
+                .iter()
+                .filter(|a| matches!(a, SimAction::Delete(_)))
+                .count(),
+            3
+        );
+        assert!(cluster.leader().is_some());
+    }
+
+    async fn restart_with(
+        cluster: &SimCluster,
+        options: &ClusterUpgradeOptions,
+    ) -> anyhow::Result<()> {
+        rolling_restart(
+            cluster,
+            Secret::from_str("token").unwrap(),
+            &keys(),
+            options,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn simulated_restart_records_progress_when_interrupted() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").never_unseals(&pod(2));
+
+        restart_with(&cluster, &ClusterUpgradeOptions::default())
+            .await
+            .unwrap_err();
+
+        let progress = cluster.progress().unwrap();
+        assert_eq!(progress.rollout, "restart");
+        assert_eq!(progress.pods[&pod(1)].phase, UpgradePhase::Done);
+        assert_eq!(progress.pods[&pod(2)].phase, UpgradePhase::Started);
+        assert!(!progress.pods.contains_key(&pod(0)));
+    }
+
+    #[tokio::test]
+    async fn simulated_restart_resumes_after_done_pods() {
+        let mut progress = UpgradeProgress::new("restart");
+        progress.set(&pod(1), UpgradePhase::Done);
+        progress.set(&pod(2), UpgradePhase::Started);
+        let cluster = SimCluster::new("vault", 3, "1.13.0").interrupted(progress);
+
+        restart_with(&cluster, &ClusterUpgradeOptions::default().resume(true))
+            .await
+            .unwrap();
+
+        let deleted: Vec<_> = cluster
+            .actions()
+            .into_iter()
+            .filter(|a| matches!(a, SimAction::Delete(_)))
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![SimAction::Delete(pod(2)), SimAction::Delete(pod(0))]
+        );
+        assert_eq!(cluster.progress(), None);
+    }
+
+    #[tokio::test]
+    async fn simulated_restart_does_not_resume_other_rollout() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .interrupted(UpgradeProgress::new("upgrade to 1.14.0"));
+
+        let err = restart_with(&cluster, &ClusterUpgradeOptions::default().resume(true))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("cannot resume restart"));
+        assert!(cluster.actions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_steps_down_again_if_leadership_did_not_move() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .ignores_step_downs(1);
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(
+            cluster
+                .actions()
+                .iter()
+                .filter(|a| **a == SimAction::StepDown(pod(0)))
+                .count(),
+            2
+        );
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.14.0");
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_does_not_step_down_new_leader_on_lagging_labels() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .lagging_labels(1);
+
+        upgrade(&cluster).await.unwrap();
+
+        let step_downs = cluster
+            .actions()
+            .into_iter()
+            .filter(|a| matches!(a, SimAction::StepDown(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(step_downs, vec![SimAction::StepDown(pod(0))]);
+        assert_eq!(cluster.leader(), Some(pod(1)));
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.14.0");
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_steps_down_lagging_new_leader() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .lags(&pod(1));
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().max_raft_lag(None),
+        )
+        .await
+        .unwrap();
+
+        let step_downs = cluster
+            .actions()
+            .into_iter()
+            .filter(|a| matches!(a, SimAction::StepDown(_)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            step_downs,
+            vec![SimAction::StepDown(pod(0)), SimAction::StepDown(pod(1))]
+        );
+        assert_eq!(cluster.leader(), Some(pod(2)));
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.14.0");
+
+        // the check can be disabled
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .lags(&pod(1))
+            .takeover(Takeover {
+                healthy_leader: false,
+                ..Default::default()
+            });
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().max_raft_lag(None),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(cluster.leader(), Some(pod(1)));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_gives_up_stepping_down() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .ignores_step_downs(2)
+            .takeover(Takeover {
+                retries: 1,
+                ..Default::default()
+            });
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("after 2 step-downs"));
+        assert_eq!(cluster.leader(), Some(pod(0)));
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.13.0");
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_deletes_active_pod_directly() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .active_strategy(ActiveStrategy::DeleteDirectly);
+
+        upgrade(&cluster).await.unwrap();
+
+        assert!(!cluster
+            .actions()
+            .iter()
+            .any(|a| matches!(a, SimAction::StepDown(_))));
+        assert_eq!(cluster.pod(&pod(0)).unwrap().version, "1.14.0");
+        assert!(cluster.leader().is_some());
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_with_autopilot_waits_for_healthy_cluster() {
+        // a standby pod crashes right before the active pod would be stepped down
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .active_strategy(ActiveStrategy::Autopilot)
+            .on(SimAction::Unseal(pod(2)), SimEvent::Seal(pod(1)));
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("healthy cluster"));
+        assert!(!cluster
+            .actions()
+            .iter()
+            .any(|a| matches!(a, SimAction::StepDown(_))));
+        assert_eq!(cluster.leader(), Some(pod(0)));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_upgrades_standby_pods_in_parallel() {
+        // 5 voters tolerate 2 standby pods being down at the same time
+        let cluster = SimCluster::new("vault", 5, "1.13.0").target("1.14.0");
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().max_unavailable(3),
+        )
+        .await
+        .unwrap();
+
+        let actions = cluster.actions();
+        assert_eq!(
+            actions[..2],
+            [SimAction::Delete(pod(1)), SimAction::Delete(pod(2))]
+        );
+
+        // never more than 2 pods are down at the same time
+        let mut down = 0;
+        for action in &actions {
+            match action {
+                SimAction::Delete(_) | SimAction::Partition(_) => down += 1,
+                SimAction::Unseal(_) => down -= 1,
+                SimAction::StepDown(_) | SimAction::Snapshot(_) => {}
+            }
+            assert!(down <= 2, "{:?}", actions);
+        }
+
+        assert!(!actions[..actions.len() - 3]
+            .iter()
+            .any(|a| matches!(a, SimAction::StepDown(_))));
+        for n in 0..5 {
+            assert_eq!(cluster.pod(&pod(n)).unwrap().version, "1.14.0");
+        }
+        assert!(cluster.leader().is_some());
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_with_three_voters_stays_sequential() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().max_unavailable(2),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            cluster.actions()[..4],
+            [
+                SimAction::Delete(pod(1)),
+                SimAction::Unseal(pod(1)),
+                SimAction::Delete(pod(2)),
+                SimAction::Unseal(pod(2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_replicas_change() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .on(SimAction::Unseal(pod(1)), SimEvent::Scale(5));
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("changed from 3 to 5"));
+        assert_eq!(
+            cluster.actions(),
+            vec![SimAction::Delete(pod(1)), SimAction::Unseal(pod(1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_continues_after_confirmed_replica_change() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .on(SimAction::Unseal(pod(1)), SimEvent::Scale(5));
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().confirm(|_| true),
+        )
+        .await
+        .unwrap();
+
+        for n in 0..3 {
+            assert_eq!(cluster.pod(&pod(n)).unwrap().version, "1.14.0");
+        }
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_image_cannot_be_pulled() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .pull_fails();
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<ImagePullFailed>()
+                .map(|e| e.pod.as_str()),
+            Some("vault-1")
+        );
+        assert_eq!(cluster.actions(), vec![SimAction::Delete(pod(1))]);
+        assert_eq!(cluster.leader(), Some(pod(0)));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_records_history_of_upgraded_pods() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+
+        upgrade(&cluster).await.unwrap();
+
+        for n in 0..3 {
+            let history = cluster.pod(&pod(n)).unwrap().history.unwrap();
+            assert_eq!(history.previous_version, "1.13.0");
+        }
+
+        // pods already running the target version are not upgraded
+        let cluster = SimCluster::new("vault", 3, "1.14.0");
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(cluster.pod(&pod(0)).unwrap().history, None);
+    }
+
+    #[tokio::test]
+    async fn simulated_rollback_restores_upgraded_pods() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .ignores_step_downs(2)
+            .takeover(Takeover {
+                retries: 1,
+                ..Default::default()
+            });
+
+        upgrade(&cluster).await.unwrap_err();
+        assert_eq!(cluster.pod(&pod(1)).unwrap().version, "1.14.0");
+
+        let pods = cluster.list_pods(ExecIn::Standby).await.unwrap();
+        let previous = previous_version_of(&pods).unwrap().unwrap();
+        assert_eq!(previous.version, "1.13.0");
+
+        cluster.set_target("1.13.0");
+        cluster.state.lock().unwrap().actions.clear();
+        roll_back_pods(
+            &cluster,
+            &previous,
+            Secret::from_str("token").unwrap(),
+            &keys(),
+            &UpgradeOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        for n in 0..3 {
+            assert_eq!(cluster.pod(&pod(n)).unwrap().version, "1.13.0");
+        }
+        // the active pod was never upgraded
+        assert!(!cluster.actions().contains(&SimAction::StepDown(pod(0))));
+        assert_eq!(cluster.progress(), None);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_publishes_events() {
+        let cluster = SimCluster::new("vault", 2, "1.13.0").target("1.14.0");
+
+        upgrade(&cluster).await.unwrap();
+
+        assert_eq!(
+            cluster.events(),
+            vec![
+                UpgradeEvent::UpgradeStarted {
+                    target: "1.14.0".to_string()
+                },
+                UpgradeEvent::PodDeleted { pod: pod(1) },
+                UpgradeEvent::Unsealed { pod: pod(1) },
+                UpgradeEvent::SteppedDown { pod: pod(0) },
+                UpgradeEvent::PodDeleted { pod: pod(0) },
+                UpgradeEvent::Unsealed { pod: pod(0) },
+            ]
+        );
+
+        let cluster = SimCluster::new("vault", 2, "1.13.0")
+            .target("1.14.0")
+            .pull_fails();
+
+        upgrade(&cluster).await.unwrap_err();
+
+        let events = cluster.events();
+        assert_eq!(events[1], UpgradeEvent::PodDeleted { pod: pod(1) });
+        assert!(matches!(
+            events.last(),
+            Some(UpgradeEvent::UpgradeFailed { error }) if error.contains("vault-1")
+        ));
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_takes_snapshot_before_deleting_pods() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let path = std::env::temp_dir().join(format!("vault-mgmt-{}.snap", rand::random::<u32>()));
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default()
+                .snapshot_before(Some(SnapshotDestination::File(path.clone()))),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(cluster.actions()[0], SimAction::Snapshot(pod(0)));
+        assert_eq!(std::fs::read(&path).unwrap(), b"snapshot");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_snapshot_cannot_be_stored() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0").target("1.14.0");
+        let path = std::env::temp_dir()
+            .join("vault-mgmt-missing")
+            .join("vault.snap");
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default()
+                .snapshot_before(Some(SnapshotDestination::File(path))),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err.to_string().contains("storing raft snapshot"));
+        assert_eq!(cluster.actions(), vec![SimAction::Snapshot(pod(0))]);
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_continues_after_healthy_canary() {
+        let cluster = SimCluster::new("vault", 5, "1.13.0").target("1.14.0");
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default()
+                .canary(1)
+                .bake_time(Duration::from_millis(10))
+                .max_unavailable(2),
+        )
+        .await
+        .unwrap();
+
+        // the canary is upgraded on its own before the others
+        assert_eq!(
+            cluster.actions()[..3],
+            [
+                SimAction::Delete(pod(1)),
+                SimAction::Unseal(pod(1)),
+                SimAction::Delete(pod(2)),
+            ]
+        );
+        for n in 0..5 {
+            assert_eq!(cluster.pod(&pod(n)).unwrap().version, "1.14.0");
+        }
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_stops_when_canary_degrades() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .degrades(&pod(1));
+
+        let err = upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default()
+                .canary(1)
+                .bake_time(Duration::from_millis(10)),
+        )
+        .await
+        .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .contains("canary pod vault-1 degraded during the bake time"));
+        assert_eq!(
+            cluster.actions(),
+            vec![SimAction::Delete(pod(1)), SimAction::Unseal(pod(1))]
+        );
+    }
+
+    #[tokio::test]
+    async fn simulated_upgrade_waits_for_standby_to_catch_up() {
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .lags(&pod(1));
+
+        let err = upgrade(&cluster).await.unwrap_err();
+
+        assert!(err.to_string().contains("never caught up"));
+        assert_eq!(
+            cluster.actions(),
+            vec![SimAction::Delete(pod(1)), SimAction::Unseal(pod(1))]
+        );
+
+        // without waiting, the lagging pod does not stop the upgrade
+        let cluster = SimCluster::new("vault", 3, "1.13.0")
+            .target("1.14.0")
+            .lags(&pod(1));
+
+        upgrade_with(
+            &cluster,
+            &ClusterUpgradeOptions::default().max_raft_lag(None),
+        )
+        .await
+        .unwrap();
+    }
+}
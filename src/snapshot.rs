@@ -0,0 +1,233 @@
+use std::{path::PathBuf, process::Stdio};
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use secrecy::Secret;
+use tokio::{io::AsyncWriteExt, process::Command};
+
+use crate::{raft_snapshot_request, raft_snapshot_restore_request, BytesBody, HttpRequest};
+
+/// Take snapshots of the raft storage
+#[async_trait::async_trait]
+pub trait RaftSnapshot {
+    /// Take a snapshot of the raft storage, has to be sent to the active node
+    async fn raft_snapshot(&mut self, token: Secret<String>) -> anyhow::Result<Bytes>;
+
+    /// Restore a snapshot of the raft storage, `force` is required for snapshots
+    /// of another cluster, whose keyring does not match
+    async fn raft_snapshot_restore(
+        &mut self,
+        token: Secret<String>,
+        snapshot: Bytes,
+        force: bool,
+    ) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<T> RaftSnapshot for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn raft_snapshot(&mut self, token: Secret<String>) -> anyhow::Result<Bytes> {
+        let http_req = raft_snapshot_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!(
+                "taking raft snapshot: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        if body.is_empty() {
+            anyhow::bail!("taking raft snapshot: snapshot is empty");
+        }
+
+        Ok(body)
+    }
+
+    async fn raft_snapshot_restore(
+        &mut self,
+        token: Secret<String>,
+        snapshot: Bytes,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let http_req = raft_snapshot_restore_request(token, force, Full::new(snapshot).boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!(
+                "restoring raft snapshot: {}",
+                String::from_utf8_lossy(&body)
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Where to store a raft snapshot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotDestination {
+    /// Local file
+    File(PathBuf),
+    /// Object in an S3 bucket, uploaded with the `aws` CLI
+    S3 { bucket: String, key: String },
+}
+
+impl std::str::FromStr for SnapshotDestination {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("s3://") {
+            Some(location) => match location.split_once('/') {
+                Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => Ok(Self::S3 {
+                    bucket: bucket.to_string(),
+                    key: key.to_string(),
+                }),
+                _ => anyhow::bail!("expected s3://bucket/key, got {}", s),
+            },
+            None if s.is_empty() => anyhow::bail!("snapshot destination is empty"),
+            None => Ok(Self::File(PathBuf::from(s))),
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotDestination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::File(path) => path.display().fmt(f),
+            Self::S3 { bucket, key } => write!(f, "s3://{}/{}", bucket, key),
+        }
+    }
+}
+
+impl SnapshotDestination {
+    /// Store the snapshot, replacing an existing file or object
+    pub async fn store(&self, snapshot: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::File(path) => Ok(tokio::fs::write(path, snapshot).await?),
+            Self::S3 { .. } => {
+                let aws = which::which("aws")
+                    .map_err(|e| anyhow::anyhow!("aws CLI is required to upload to S3: {}", e))?;
+
+                let mut cmd = Command::new(aws)
+                    .args(["s3", "cp", "-", &self.to_string()])
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::piped())
+                    .spawn()?;
+
+                let mut stdin = cmd.stdin.take().expect("stdin is piped");
+                stdin.write_all(snapshot).await?;
+                drop(stdin);
+
+                let output = cmd.wait_with_output().await?;
+                if !output.status.success() {
+                    anyhow::bail!(
+                        "uploading snapshot to {}: {}",
+                        self,
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, str::FromStr};
+
+    use http::{Method, StatusCode};
+    use hyper::body::Bytes;
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_bytes, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{HttpForwarderService, RaftSnapshot, SnapshotDestination};
+
+    #[tokio::test]
+    async fn raft_snapshot_returns_body() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/sys/storage/raft/snapshot"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_bytes(b"snapshot".to_vec()),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let snapshot = client
+            .raft_snapshot(Secret::from_str("abc").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(&snapshot[..], b"snapshot");
+    }
+
+    #[tokio::test]
+    async fn raft_snapshot_restore_with_force_calls_api() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/sys/storage/raft/snapshot-force"))
+            .and(header("X-Vault-Token", "abc"))
+            .and(body_bytes(b"snapshot".to_vec()))
+            .respond_with(ResponseTemplate::new(StatusCode::NO_CONTENT))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        client
+            .raft_snapshot_restore(
+                Secret::from_str("abc").unwrap(),
+                Bytes::from_static(b"snapshot"),
+                true,
+            )
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn snapshot_destination_is_parsed() {
+        assert_eq!(
+            SnapshotDestination::from_str("/tmp/vault.snap").unwrap(),
+            SnapshotDestination::File(PathBuf::from("/tmp/vault.snap"))
+        );
+        assert_eq!(
+            SnapshotDestination::from_str("s3://backups/vault/pre-upgrade.snap").unwrap(),
+            SnapshotDestination::S3 {
+                bucket: "backups".to_string(),
+                key: "vault/pre-upgrade.snap".to_string(),
+            }
+        );
+        assert!(SnapshotDestination::from_str("s3://backups").is_err());
+        assert!(SnapshotDestination::from_str("").is_err());
+    }
+}
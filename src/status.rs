@@ -1,11 +1,18 @@
+use std::time::Duration;
+
 use http_body_util::{BodyExt, Empty};
 use hyper::body::Bytes;
+use k8s_openapi::api::core::v1::Pod;
 use kube::runtime::wait::Condition;
 use secrecy::Secret;
+use tracing::*;
 
-use crate::{raft_configuration_request, seal_status_request, BytesBody, HttpRequest};
+use crate::{
+    find_vault_container, is_pod_ready, leader_request, raft_configuration_request,
+    seal_status_request, BytesBody, Edition, HttpRequest, PodApi, VaultVersion, VAULT_PORT,
+};
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct PodSealStatus {
     #[serde(rename = "type")]
     pub type_: String,
@@ -30,6 +37,162 @@ pub struct PodSealStatus {
     pub raft_applied_index: Option<u64>,
 }
 
+impl PodSealStatus {
+    /// Check if the server runs in dev mode (`vault server -dev`), which keeps all data in memory
+    pub fn is_dev_mode(&self) -> bool {
+        self.storage_type == "inmem"
+    }
+
+    /// Check if the server is unsealed automatically (e.g. by a KMS or transit seal)
+    /// instead of with unseal keys
+    pub fn is_auto_unseal(&self) -> bool {
+        self.type_ != "shamir"
+    }
+
+    /// Edition of the running build, see `Edition::of_version`
+    pub fn edition(&self) -> Edition {
+        Edition::of_version(&self.version)
+    }
+}
+
+/// Check if the vault container of the pod is started in dev mode
+/// (`-dev` flag or a configured dev root token), see `find_vault_container`
+pub fn is_dev_mode_pod(pod: &Pod, container: Option<&str>) -> bool {
+    let Some(container) = pod
+        .spec
+        .as_ref()
+        .and_then(|s| find_vault_container(&s.containers, container).ok())
+    else {
+        return false;
+    };
+
+    let has_dev_flag = container
+        .command
+        .iter()
+        .chain(container.args.iter())
+        .flatten()
+        .flat_map(|arg| arg.split_whitespace())
+        .any(|arg| arg == "-dev" || arg == "--dev");
+
+    let has_dev_token = container
+        .env
+        .iter()
+        .flatten()
+        .any(|e| e.name == "VAULT_DEV_ROOT_TOKEN_ID");
+
+    has_dev_flag || has_dev_token
+}
+
+impl PodApi {
+    /// Refuse the operation if the vault pods run in dev mode
+    ///
+    /// Dev-mode servers use in-memory storage and are unsealed with a single key,
+    /// recreating a pod loses all data and the pod comes back as a fresh server.
+    pub async fn ensure_not_dev_mode(&self, operation: &str) -> anyhow::Result<()> {
+        let pods = self.api.list(&self.selector.to_list_params()).await?;
+
+        for pod in pods.iter() {
+            let name = pod
+                .metadata
+                .name
+                .as_ref()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+            if is_dev_mode_pod(pod, self.selector.container.as_deref()) {
+                anyhow::bail!(
+                    "vault pod {} runs in dev mode, refusing to {}: dev-mode servers keep all data in memory",
+                    name,
+                    operation
+                );
+            }
+        }
+
+        // the container might start dev mode in a way we do not recognize, so ask vault as well
+        if let Some(name) = pods.iter().find_map(|p| p.metadata.name.as_ref()) {
+            let status = self.http(name, VAULT_PORT).await?.seal_status().await?;
+
+            if status.is_dev_mode() {
+                anyhow::bail!(
+                    "vault pod {} uses in-memory storage (dev mode), refusing to {}: all data is lost when a pod is recreated",
+                    name,
+                    operation
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Wait until all pods report the version via their seal status and are unsealed and ready
+    ///
+    /// Nothing is changed, e.g. to observe a rollout done by another system.
+    /// With `replicas`, it also waits until that many pods exist.
+    pub async fn await_version(
+        &self,
+        version: &VaultVersion,
+        replicas: Option<i32>,
+        interval: Duration,
+    ) -> anyhow::Result<()> {
+        loop {
+            let pods = self.api.list(&self.selector.to_list_params()).await?;
+
+            let mut pending = vec![];
+            match replicas {
+                Some(replicas) if (pods.items.len() as i32) < replicas => {
+                    pending.push(format!("{} of {} pods exist", pods.items.len(), replicas))
+                }
+                _ if pods.items.is_empty() => pending.push("no vault pods found".to_string()),
+                _ => {}
+            }
+
+            for pod in pods.iter() {
+                let name = pod
+                    .metadata
+                    .name
+                    .as_ref()
+                    .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+                let status = match self.http(name, VAULT_PORT).await {
+                    Ok(mut pf) => pf.seal_status().await.ok(),
+                    Err(_) => None,
+                };
+                if let Some(reason) = version_pending(pod, status.as_ref(), version) {
+                    pending.push(format!("{} {}", name, reason));
+                }
+            }
+
+            if pending.is_empty() {
+                return Ok(());
+            }
+
+            info!(
+                "waiting for version {}: {}",
+                version.version,
+                pending.join(", ")
+            );
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Get the seal status of the first reachable pod, e.g. to check the seal type of the cluster
+    pub async fn any_seal_status(&self) -> anyhow::Result<PodSealStatus> {
+        let pods = self.api.list(&self.selector.to_list_params()).await?;
+
+        let mut last_error = anyhow::anyhow!("no vault pods found");
+        for name in pods.iter().filter_map(|p| p.metadata.name.as_ref()) {
+            match self.http(name, VAULT_PORT).await {
+                Ok(mut pf) => match pf.seal_status().await {
+                    Ok(status) => return Ok(status),
+                    Err(e) => last_error = e,
+                },
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error.context("getting seal status"))
+    }
+}
+
 /// Get vault pod's seal status
 #[async_trait::async_trait]
 pub trait GetSealStatus {
@@ -85,6 +248,43 @@ pub fn is_seal_status_initialized() -> impl Condition<PodSealStatus> {
     }
 }
 
+/// Returns true if the pod applied the raft log up to `max_lag` entries before `committed`.
+/// Pods that do not report their applied index (e.g. without raft storage) are considered caught up.
+#[must_use]
+pub fn is_seal_status_caught_up(committed: u64, max_lag: u64) -> impl Condition<PodSealStatus> {
+    move |obj: Option<&PodSealStatus>| {
+        if let Some(status) = obj {
+            return match status.raft_applied_index {
+                Some(applied) => applied.saturating_add(max_lag) >= committed,
+                None => true,
+            };
+        }
+        false
+    }
+}
+
+/// Why the pod does not run the version yet, `None` once it reports the version
+/// via its seal status and is unsealed and ready
+pub fn version_pending(
+    pod: &Pod,
+    status: Option<&PodSealStatus>,
+    version: &VaultVersion,
+) -> Option<String> {
+    let Some(status) = status else {
+        return Some("does not report its seal status".to_string());
+    };
+
+    if !version.is_reported_by(&status.version) {
+        Some(format!("reports version {}", status.version))
+    } else if status.sealed {
+        Some("is sealed".to_string())
+    } else if !is_pod_ready().matches_object(Some(pod)) {
+        Some("is not ready".to_string())
+    } else {
+        None
+    }
+}
+
 #[must_use]
 pub fn is_seal_status_sealed() -> impl Condition<PodSealStatus> {
     |obj: Option<&PodSealStatus>| {
@@ -95,6 +295,45 @@ pub fn is_seal_status_sealed() -> impl Condition<PodSealStatus> {
     }
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct LeaderStatus {
+    pub ha_enabled: bool,
+    pub is_self: bool,
+    pub active_time: Option<String>,
+    pub leader_address: String,
+    pub leader_cluster_address: String,
+    pub performance_standby: Option<bool>,
+    pub raft_committed_index: Option<u64>,
+    pub raft_applied_index: Option<u64>,
+}
+
+/// Get vault pod's view of the current leader
+#[async_trait::async_trait]
+pub trait GetLeader {
+    /// Get vault pod's view of the current leader
+    async fn leader(&mut self) -> anyhow::Result<LeaderStatus>;
+}
+
+#[async_trait::async_trait]
+impl<T> GetLeader for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn leader(&mut self) -> anyhow::Result<LeaderStatus> {
+        let http_req = leader_request(Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("getting leader: {}", body));
+        }
+
+        Ok(serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?)
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct RaftConfiguration {
     pub request_id: String,
@@ -204,6 +443,8 @@ pub fn raft_configuration_all_voters() -> impl Condition<RaftConfiguration> {
 mod tests {
     use std::str::FromStr;
 
+    use k8s_openapi::api::core::v1::Pod;
+    use kube::runtime::wait::Condition;
     use secrecy::Secret;
     use wiremock::{
         matchers::{header, method, path},
@@ -211,8 +452,10 @@ mod tests {
     };
 
     use crate::{
-        is_seal_status_initialized, raft_configuration_all_voters, raft_configuration_any_leader,
-        GetRaftConfiguration, GetSealStatus, HttpForwarderService, RaftConfiguration,
+        is_dev_mode_pod, is_seal_status_caught_up, is_seal_status_initialized,
+        raft_configuration_all_voters, raft_configuration_any_leader, version_pending, GetLeader,
+        GetRaftConfiguration, GetSealStatus, HttpForwarderService, PodSealStatus,
+        RaftConfiguration, VaultVersion,
     };
 
     fn minimal_seal_status() -> serde_json::Value {
@@ -425,6 +668,45 @@ mod tests {
         assert!(status.initialized);
     }
 
+    #[tokio::test]
+    async fn getting_leader_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(http::Method::GET))
+            .and(path("/v1/sys/leader"))
+            .and(header("X-Vault-Request", "true"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "ha_enabled": true,
+                "is_self": true,
+                "active_time": "2023-05-04T15:03:10.581939163Z",
+                "leader_address": "http://10.42.2.25:8200",
+                "leader_cluster_address": "https://vault-0.vault-internal:8201",
+                "performance_standby": false,
+                "performance_standby_last_remote_wal": 0,
+                "raft_committed_index": 40,
+                "raft_applied_index": 40
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let leader = client.leader().await.unwrap();
+
+        assert!(leader.ha_enabled);
+        assert!(leader.is_self);
+        assert_eq!(leader.leader_address, "http://10.42.2.25:8200");
+        assert_eq!(leader.performance_standby, Some(false));
+        assert_eq!(leader.raft_committed_index, Some(40));
+    }
+
     fn raft_configuration() -> serde_json::Value {
         serde_json::json!({
             "request_id": "7f6fc909-bb7f-e48c-d850-0ad8a22cb434",
@@ -649,4 +931,110 @@ mod tests {
 
         assert!(config.data.config.servers.iter().all(|s| s.voter));
     }
+
+    #[test]
+    fn detecting_dev_mode_from_seal_status_works() {
+        let mut status = initialized_seal_status();
+        let raft: PodSealStatus = serde_json::from_value(status.clone()).unwrap();
+        assert!(!raft.is_dev_mode());
+
+        status["storage_type"] = "inmem".into();
+        let inmem: PodSealStatus = serde_json::from_value(status).unwrap();
+        assert!(inmem.is_dev_mode());
+    }
+
+    #[test]
+    fn seal_status_caught_up_within_lag() {
+        let mut status = initialized_seal_status();
+        let at_40: PodSealStatus = serde_json::from_value(status.clone()).unwrap();
+
+        assert!(is_seal_status_caught_up(40, 0).matches_object(Some(&at_40)));
+        assert!(!is_seal_status_caught_up(45, 0).matches_object(Some(&at_40)));
+        assert!(is_seal_status_caught_up(45, 5).matches_object(Some(&at_40)));
+
+        status["raft_applied_index"] = serde_json::Value::Null;
+        let unknown: PodSealStatus = serde_json::from_value(status).unwrap();
+        assert!(is_seal_status_caught_up(45, 0).matches_object(Some(&unknown)));
+    }
+
+    #[test]
+    fn version_is_pending_until_the_pod_is_unsealed_and_ready() {
+        let version = VaultVersion::from_str("1.14.0").unwrap();
+        let pod = |ready: &str| -> Pod {
+            serde_json::from_value(serde_json::json!({
+                "metadata": { "name": "vault-0" },
+                "status": { "conditions": [{ "type": "Ready", "status": ready }] },
+            }))
+            .unwrap()
+        };
+        let mut status = initialized_seal_status();
+        status["version"] = "1.14.0".into();
+        let unsealed: PodSealStatus = serde_json::from_value(status.clone()).unwrap();
+
+        assert_eq!(
+            version_pending(&pod("True"), Some(&unsealed), &version),
+            None
+        );
+        assert!(version_pending(&pod("False"), Some(&unsealed), &version).is_some());
+        assert!(version_pending(&pod("True"), None, &version).is_some());
+
+        status["version"] = "1.13.0".into();
+        let outdated: PodSealStatus = serde_json::from_value(status).unwrap();
+        assert_eq!(
+            version_pending(&pod("True"), Some(&outdated), &version),
+            Some("reports version 1.13.0".to_string())
+        );
+    }
+
+    #[test]
+    fn detecting_auto_unseal_from_seal_status_works() {
+        let mut status = initialized_seal_status();
+        let shamir: PodSealStatus = serde_json::from_value(status.clone()).unwrap();
+        assert!(!shamir.is_auto_unseal());
+
+        status["type"] = "awskms".into();
+        let kms: PodSealStatus = serde_json::from_value(status).unwrap();
+        assert!(kms.is_auto_unseal());
+    }
+
+    #[test]
+    fn detecting_dev_mode_from_pod_works() {
+        let pod = |container: serde_json::Value| {
+            serde_json::from_value(serde_json::json!({
+                "metadata": { "name": "vault-0" },
+                "spec": { "containers": [container] },
+            }))
+            .unwrap()
+        };
+
+        assert!(!is_dev_mode_pod(
+            &pod(serde_json::json!({
+                "name": "vault",
+                "args": ["server", "-config=/vault/config/extraconfig-from-values.hcl"],
+            })),
+            None
+        ));
+        assert!(is_dev_mode_pod(
+            &pod(serde_json::json!({
+                "name": "vault",
+                "args": ["server", "-dev"],
+            })),
+            None
+        ));
+        assert!(is_dev_mode_pod(
+            &pod(serde_json::json!({
+                "name": "vault",
+                "command": ["/bin/sh", "-ec"],
+                "args": ["/usr/local/bin/docker-entrypoint.sh vault server -dev -dev-listen-address=[::]:8200"],
+            })),
+            None
+        ));
+        assert!(is_dev_mode_pod(
+            &pod(serde_json::json!({
+                "name": "vault",
+                "env": [{ "name": "VAULT_DEV_ROOT_TOKEN_ID", "value": "root" }],
+            })),
+            None
+        ));
+    }
 }
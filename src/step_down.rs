@@ -1,14 +1,25 @@
 use http_body_util::{BodyExt, Empty};
 use hyper::body::Bytes;
 use secrecy::Secret;
+use tracing::*;
 
-use crate::{step_down_request, BytesBody, HttpRequest};
+use crate::{step_down_request, BytesBody, GetLeader, HttpRequest, LeaderStatus};
+
+/// Interval between polling the leader after stepping down
+const LEADER_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
 
 /// Step down vault pod from active to standby
 #[async_trait::async_trait]
 pub trait StepDown {
     /// Step down vault pod from active to standby
     async fn step_down(&mut self, token: Secret<String>) -> anyhow::Result<()>;
+
+    /// Step down vault pod from active to standby and wait until a different node reports being the leader
+    /// Returns the status of the new leader
+    async fn step_down_and_await_new_leader(
+        &mut self,
+        token: Secret<String>,
+    ) -> anyhow::Result<LeaderStatus>;
 }
 
 #[async_trait::async_trait]
@@ -29,6 +40,31 @@ where
 
         Ok(())
     }
+
+    async fn step_down_and_await_new_leader(
+        &mut self,
+        token: Secret<String>,
+    ) -> anyhow::Result<LeaderStatus> {
+        let previous = self.leader().await?;
+
+        self.step_down(token).await?;
+
+        loop {
+            match self.leader().await {
+                Ok(leader)
+                    if !leader.leader_address.is_empty()
+                        && leader.leader_address != previous.leader_address =>
+                {
+                    info!("new leader: {}", leader.leader_address);
+                    return Ok(leader);
+                }
+                Ok(_) => debug!("waiting for a new leader to be elected"),
+                Err(e) => debug!("waiting for a new leader to be elected: {}", e),
+            }
+
+            tokio::time::sleep(LEADER_POLL_INTERVAL).await;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -69,4 +105,57 @@ mod tests {
 
         assert!(outcome.is_ok());
     }
+
+    fn leader(is_self: bool, address: &str) -> serde_json::Value {
+        serde_json::json!({
+            "ha_enabled": true,
+            "is_self": is_self,
+            "active_time": "0001-01-01T00:00:00Z",
+            "leader_address": address,
+            "leader_cluster_address": "",
+            "performance_standby": false,
+        })
+    }
+
+    #[tokio::test]
+    async fn stepdown_waits_for_new_leader() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/step-down"))
+            .respond_with(ResponseTemplate::new(StatusCode::NO_CONTENT))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        for response in [
+            leader(true, "http://10.42.2.25:8200"),
+            leader(false, ""),
+            leader(false, "http://10.42.2.25:8200"),
+            leader(false, "http://10.42.2.26:8200"),
+        ] {
+            Mock::given(method(Method::GET))
+                .and(path("/v1/sys/leader"))
+                .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(response))
+                .up_to_n_times(1)
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let leader = client
+            .step_down_and_await_new_leader(Secret::from_str("abc").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(leader.leader_address, "http://10.42.2.26:8200");
+    }
 }
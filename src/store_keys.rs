@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use k8s_openapi::{api::core::v1::Secret as K8sSecret, ByteString};
+use kube::{api::PostParams, Api, Client};
+use secrecy::{ExposeSecret, Secret};
+use tracing::*;
+
+use crate::{
+    get_unseal_keys_request, put_keys_request, HttpRequest, InitResult, KeyKind, KeyRequest,
+    KeysFrom, KubernetesKeyProvider, VaultKeyProvider,
+};
+
+/// Fields the result of `init` is stored in, read back by the key providers of the same uri
+fn init_result_fields(result: &InitResult) -> BTreeMap<&'static str, Secret<String>> {
+    let join = |keys: &[Secret<String>]| {
+        Secret::new(
+            keys.iter()
+                .map(|k| k.expose_secret().as_str())
+                .collect::<Vec<_>>()
+                .join("\n"),
+        )
+    };
+
+    let mut fields = BTreeMap::new();
+    if !result.keys.is_empty() {
+        fields.insert(KeyKind::Unseal.secret_field(), join(&result.keys));
+    }
+    if !result.recovery_keys.is_empty() {
+        fields.insert(
+            KeyKind::Recovery.secret_field(),
+            join(&result.recovery_keys),
+        );
+    }
+    fields.insert("root_token", result.root_token.clone());
+
+    fields
+}
+
+/// Check that the keys can be stored at the uri before `init` creates them
+///
+/// Fails for unsupported uris and if the secret already exists, so the shares are not
+/// created only to be printed. The vault is connected to with the TLS settings of the provider.
+pub async fn check_store_target(
+    to: &KeysFrom,
+    request: &KeyRequest,
+    vault: &VaultKeyProvider,
+) -> anyhow::Result<()> {
+    match to.scheme() {
+        "k8s" => check_kubernetes_target(to).await,
+        "vault+https" | "vault+http" => check_vault_target(to, request, vault).await,
+        scheme => anyhow::bail!(
+            "keys cannot be stored in {} uris, only in k8s:// and vault+https://",
+            scheme
+        ),
+    }
+    .map_err(|e| e.context(format!("checking {} for storing the keys", to)))
+}
+
+/// Namespace and name of the secret of a `k8s://<namespace>/<secret>` uri
+fn kubernetes_target(to: &KeysFrom) -> anyhow::Result<(&str, &str)> {
+    let (namespace, name, key) = KubernetesKeyProvider::parse(to)?;
+    if key.is_some() {
+        anyhow::bail!(
+            "expected k8s://<namespace>/<secret> without a key, got {}",
+            to
+        );
+    }
+
+    Ok((namespace, name))
+}
+
+async fn check_kubernetes_target(to: &KeysFrom) -> anyhow::Result<()> {
+    let (namespace, name) = kubernetes_target(to)?;
+
+    let api: Api<K8sSecret> = Api::namespaced(Client::try_default().await?, namespace);
+    if api.get_opt(name).await?.is_some() {
+        anyhow::bail!("secret {} already exists, not overwriting it", name);
+    }
+
+    Ok(())
+}
+
+async fn check_vault_target(
+    to: &KeysFrom,
+    request: &KeyRequest,
+    vault: &VaultKeyProvider,
+) -> anyhow::Result<()> {
+    let (uri, client) = vault.client(to)?;
+    let token = match &request.auth {
+        Some(auth) => client.login(auth).await?,
+        None => request.token.clone(),
+    };
+
+    let req = get_unseal_keys_request(uri.path().as_str(), token)?;
+    let (parts, body) = client.client().await?.send_request(req).await?.into_parts();
+
+    match parts.status {
+        hyper::StatusCode::NOT_FOUND => Ok(()),
+        status if status.is_success() => {
+            anyhow::bail!("secret {} already exists, not overwriting it", uri.path())
+        }
+        _ => anyhow::bail!("{}", String::from_utf8_lossy(&body)),
+    }
+}
+
+/// Store the key shares and the root token of `init` at the uri, so they are never printed
+///
+/// Supports `k8s://<namespace>/<secret>` and `vault+https://<host>/v1/<kv v2 secret>`,
+/// with the shares in the fields `keys` or `recovery_keys` read by `--keys-from` and the root
+/// token in `root_token`. An existing secret is never overwritten. The vault is connected
+/// to with the TLS settings of the provider.
+pub async fn store_init_result(
+    to: &KeysFrom,
+    result: &InitResult,
+    request: &KeyRequest,
+    vault: &VaultKeyProvider,
+) -> anyhow::Result<()> {
+    let fields = init_result_fields(result);
+
+    match to.scheme() {
+        "k8s" => store_in_kubernetes(to, fields).await,
+        "vault+https" | "vault+http" => store_in_vault(to, fields, request, vault).await,
+        scheme => anyhow::bail!(
+            "keys cannot be stored in {} uris, only in k8s:// and vault+https://",
+            scheme
+        ),
+    }
+    .map_err(|e| e.context(format!("storing the keys in {}", to)))?;
+
+    info!("stored the keys and the root token in {}", to);
+
+    Ok(())
+}
+
+async fn store_in_kubernetes(
+    to: &KeysFrom,
+    fields: BTreeMap<&'static str, Secret<String>>,
+) -> anyhow::Result<()> {
+    let (namespace, name) = kubernetes_target(to)?;
+
+    let secret = K8sSecret {
+        metadata: kube::api::ObjectMeta {
+            name: Some(name.to_string()),
+            namespace: Some(namespace.to_string()),
+            ..Default::default()
+        },
+        data: Some(
+            fields
+                .into_iter()
+                .map(|(field, value)| {
+                    (
+                        field.to_string(),
+                        ByteString(value.expose_secret().as_bytes().to_vec()),
+                    )
+                })
+                .collect(),
+        ),
+        ..Default::default()
+    };
+
+    let api: Api<K8sSecret> = Api::namespaced(Client::try_default().await?, namespace);
+    match api.create(&PostParams::default(), &secret).await {
+        Err(kube::Error::Api(e)) if e.code == 409 => {
+            anyhow::bail!("secret {} already exists, not overwriting it", name)
+        }
+        created => created.map(|_| ()).map_err(Into::into),
+    }
+}
+
+async fn store_in_vault(
+    to: &KeysFrom,
+    fields: BTreeMap<&'static str, Secret<String>>,
+    request: &KeyRequest,
+    vault: &VaultKeyProvider,
+) -> anyhow::Result<()> {
+    let (uri, client) = vault.client(to)?;
+    let token = match &request.auth {
+        Some(auth) => client.login(auth).await?,
+        None => request.token.clone(),
+    };
+
+    // kv v2, `cas: 0` only writes the secret if it does not exist yet
+    let body = serde_json::json!({
+        "options": { "cas": 0 },
+        "data": fields
+            .iter()
+            .map(|(field, value)| (*field, value.expose_secret().as_str()))
+            .collect::<BTreeMap<_, _>>(),
+    });
+    let req = put_keys_request(
+        uri.path().as_str(),
+        token,
+        Full::new(Bytes::from(body.to_string())).boxed(),
+    )?;
+
+    let (parts, body) = client.client().await?.send_request(req).await?.into_parts();
+
+    if !parts.status.is_success() {
+        anyhow::bail!("{}", String::from_utf8_lossy(&body));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{
+        check_store_target, store_init_result, InitResult, KeyKind, KeyRequest, KeysFrom,
+        VaultKeyProvider,
+    };
+
+    fn init_result() -> InitResult {
+        InitResult {
+            keys: vec![
+                Secret::from_str("a").unwrap(),
+                Secret::from_str("b").unwrap(),
+            ],
+            keys_base64: vec![],
+            recovery_keys: vec![],
+            recovery_keys_base64: vec![],
+            root_token: Secret::from_str("root").unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn init_result_is_stored_in_a_new_vault_secret() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("POST"))
+            .and(path("/v1/secret/data/unseal"))
+            .and(header("X-Vault-Token", "token"))
+            .and(body_json(serde_json::json!({
+                "options": { "cas": 0 },
+                "data": { "keys": "a\nb", "root_token": "root" },
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let to = KeysFrom::from_str(&format!(
+            "vault+{}/v1/secret/data/unseal",
+            mock_server.uri()
+        ))
+        .unwrap();
+        let request = KeyRequest::new(KeyKind::Unseal, Secret::from_str("token").unwrap());
+
+        store_init_result(&to, &init_result(), &request, &VaultKeyProvider::default())
+            .await
+            .unwrap();
+
+        let to = KeysFrom::from_str("env://VAULT_UNSEAL_KEYS").unwrap();
+        assert!(
+            store_init_result(&to, &init_result(), &request, &VaultKeyProvider::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn store_target_is_checked_before_init() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method("GET"))
+            .and(path("/v1/secret/data/new"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&mock_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/v1/secret/data/existing"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "data": { "data": { "keys": "a" } }
+            })))
+            .mount(&mock_server)
+            .await;
+
+        let request = KeyRequest::new(KeyKind::Unseal, Secret::from_str("token").unwrap());
+        let target = |secret: &str| {
+            KeysFrom::from_str(&format!(
+                "vault+{}/v1/secret/data/{}",
+                mock_server.uri(),
+                secret
+            ))
+            .unwrap()
+        };
+
+        check_store_target(&target("new"), &request, &VaultKeyProvider::default())
+            .await
+            .unwrap();
+
+        let err = check_store_target(&target("existing"), &request, &VaultKeyProvider::default())
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("already exists"));
+
+        for to in ["env://VAULT_UNSEAL_KEYS", "k8s://vault/unseal-keys/keys"] {
+            let to = KeysFrom::from_str(to).unwrap();
+            assert!(
+                check_store_target(&to, &request, &VaultKeyProvider::default())
+                    .await
+                    .is_err()
+            );
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use http_body_util::{BodyExt, Empty, Full};
+use hyper::body::Bytes;
+use secrecy::Secret;
+
+use crate::{
+    token_lookup_self_request, token_renew_self_request, token_revoke_self_request, BytesBody,
+    HttpRequest,
+};
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TokenLookupResponse {
+    pub data: TokenInfo,
+}
+
+/// Properties of a token, the token itself is not included
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TokenInfo {
+    pub accessor: String,
+    pub creation_time: u64,
+    pub creation_ttl: u64,
+    pub display_name: String,
+    #[serde(default)]
+    pub entity_id: String,
+    pub expire_time: Option<String>,
+    pub explicit_max_ttl: u64,
+    pub meta: Option<BTreeMap<String, String>>,
+    pub num_uses: u64,
+    pub orphan: bool,
+    pub path: String,
+    pub policies: Vec<String>,
+    #[serde(default)]
+    pub renewable: bool,
+    pub ttl: u64,
+    #[serde(rename = "type", default)]
+    pub type_: String,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TokenRenewResponse {
+    pub auth: TokenAuth,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct TokenAuth {
+    pub accessor: String,
+    pub policies: Vec<String>,
+    pub lease_duration: u64,
+    pub renewable: bool,
+}
+
+/// Look up the token used for the request
+#[async_trait::async_trait]
+pub trait TokenLookup {
+    /// Look up the token used for the request
+    async fn token_lookup_self(&mut self, token: Secret<String>) -> anyhow::Result<TokenInfo>;
+}
+
+#[async_trait::async_trait]
+impl<T> TokenLookup for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn token_lookup_self(&mut self, token: Secret<String>) -> anyhow::Result<TokenInfo> {
+        let http_req = token_lookup_self_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("looking up token: {}", body));
+        }
+
+        let lookup: TokenLookupResponse =
+            serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?;
+
+        Ok(lookup.data)
+    }
+}
+
+/// Look up the accessor of the token
+///
+/// The accessor identifies the token in the audit devices of vault without revealing it,
+/// set it with `HttpForwarderService::set_token_accessor` or `PodApi::token_accessor` to log
+/// the mutating requests made with the token with its accessor.
+pub async fn token_accessor(
+    client: &mut (impl TokenLookup + Send),
+    token: Secret<String>,
+) -> anyhow::Result<String> {
+    Ok(client.token_lookup_self(token).await?.accessor)
+}
+
+/// Renew the token used for the request
+#[async_trait::async_trait]
+pub trait TokenRenew {
+    /// Renew the token used for the request, optionally requesting a TTL
+    async fn token_renew_self(
+        &mut self,
+        token: Secret<String>,
+        increment: Option<Duration>,
+    ) -> anyhow::Result<TokenAuth>;
+}
+
+#[async_trait::async_trait]
+impl<T> TokenRenew for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn token_renew_self(
+        &mut self,
+        token: Secret<String>,
+        increment: Option<Duration>,
+    ) -> anyhow::Result<TokenAuth> {
+        let body = match increment {
+            Some(increment) => serde_json::json!({
+                "increment": format!("{}s", increment.as_secs()),
+            }),
+            None => serde_json::json!({}),
+        };
+
+        let http_req =
+            token_renew_self_request(token, Full::new(Bytes::from(body.to_string())).boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if parts.status != hyper::StatusCode::OK {
+            return Err(anyhow::anyhow!("renewing token: {}", body));
+        }
+
+        let renewal: TokenRenewResponse =
+            serde_json::from_str(&body).map_err(|e| anyhow::anyhow!("{}: {}", e, body))?;
+
+        Ok(renewal.auth)
+    }
+}
+
+/// Revoke the token used for the request
+#[async_trait::async_trait]
+pub trait TokenRevoke {
+    /// Revoke the token used for the request
+    async fn token_revoke_self(&mut self, token: Secret<String>) -> anyhow::Result<()>;
+}
+
+#[async_trait::async_trait]
+impl<T> TokenRevoke for T
+where
+    T: HttpRequest<BytesBody> + Send + Sync + 'static,
+{
+    async fn token_revoke_self(&mut self, token: Secret<String>) -> anyhow::Result<()> {
+        let http_req = token_revoke_self_request(token, Empty::<Bytes>::new().boxed())?;
+
+        let (parts, body) = self.send_request(http_req).await?.into_parts();
+
+        let body = String::from_utf8(body.to_vec())?;
+
+        if !parts.status.is_success() {
+            return Err(anyhow::anyhow!("revoking token: {}", body));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{str::FromStr, time::Duration};
+
+    use http::{Method, StatusCode};
+    use secrecy::Secret;
+    use wiremock::{
+        matchers::{body_json, header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    use crate::{token_accessor, HttpForwarderService, TokenLookup, TokenRenew, TokenRevoke};
+
+    async fn client(mock_server: &MockServer) -> HttpForwarderService<crate::BytesBody> {
+        HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn looking_up_token_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/auth/token/lookup-self"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "request_id": "",
+                    "lease_id": "",
+                    "renewable": false,
+                    "lease_duration": 0,
+                    "data": {
+                        "accessor": "8609694a-cdbc-db9b-d345-e782dbb562ed",
+                        "creation_time": 1523979354,
+                        "creation_ttl": 2764800,
+                        "display_name": "ldap2-tesla",
+                        "entity_id": "7d2e3179-f69b-450c-7179-ac8ee8bd8ca9",
+                        "expire_time": "2018-05-19T11:35:54.466476215-04:00",
+                        "explicit_max_ttl": 0,
+                        "id": "cf64a70f-3a12-3f6c-791d-6cef6d390eed",
+                        "identity_policies": ["dev-group-policy"],
+                        "issue_time": "2018-04-17T11:35:54.466476078-04:00",
+                        "meta": { "username": "tesla" },
+                        "num_uses": 0,
+                        "orphan": true,
+                        "path": "auth/ldap2/login/tesla",
+                        "policies": ["default", "testgroup2-policy"],
+                        "renewable": true,
+                        "ttl": 2764790,
+                        "type": "service",
+                    },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let info = client(&mock_server)
+            .await
+            .token_lookup_self(Secret::from_str("abc").unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(info.display_name, "ldap2-tesla");
+        assert_eq!(info.policies, vec!["default", "testgroup2-policy"]);
+        assert_eq!(info.ttl, 2764790);
+        assert!(info.renewable);
+    }
+
+    #[tokio::test]
+    async fn token_accessor_is_looked_up() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/auth/token/lookup-self"))
+            .and(header("X-Vault-Token", "attributed"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "data": {
+                        "accessor": "8609694a-cdbc-db9b-d345-e782dbb562ed",
+                        "creation_time": 1523979354,
+                        "creation_ttl": 2764800,
+                        "display_name": "token",
+                        "explicit_max_ttl": 0,
+                        "num_uses": 0,
+                        "orphan": true,
+                        "path": "auth/token/create",
+                        "policies": ["default"],
+                        "ttl": 2764790,
+                    },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let accessor = token_accessor(
+            &mut client(&mock_server).await,
+            Secret::from_str("attributed").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(accessor, "8609694a-cdbc-db9b-d345-e782dbb562ed");
+    }
+
+    #[tokio::test]
+    async fn renewing_token_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/auth/token/renew-self"))
+            .and(header("X-Vault-Token", "abc"))
+            .and(body_json(serde_json::json!({ "increment": "3600s" })))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "auth": {
+                        "client_token": "abc",
+                        "accessor": "8609694a-cdbc-db9b-d345-e782dbb562ed",
+                        "policies": ["default"],
+                        "lease_duration": 3600,
+                        "renewable": true,
+                    },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let auth = client(&mock_server)
+            .await
+            .token_renew_self(
+                Secret::from_str("abc").unwrap(),
+                Some(Duration::from_secs(3600)),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(auth.lease_duration, 3600);
+    }
+
+    #[tokio::test]
+    async fn revoking_token_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::POST))
+            .and(path("/v1/auth/token/revoke-self"))
+            .and(header("X-Vault-Token", "abc"))
+            .respond_with(ResponseTemplate::new(StatusCode::NO_CONTENT))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let outcome = client(&mock_server)
+            .await
+            .token_revoke_self(Secret::from_str("abc").unwrap())
+            .await;
+
+        assert!(outcome.is_ok());
+    }
+}
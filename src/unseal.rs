@@ -1,3 +1,11 @@
+use std::{
+    future::Future,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use futures_util::{stream, StreamExt};
 use http::uri::Scheme;
 use http_body_util::{BodyExt, Full};
 use hyper::body::Bytes;
@@ -5,12 +13,84 @@ use k8s_openapi::api::core::v1::Pod;
 use kube::api::Api;
 use secrecy::{ExposeSecret, Secret};
 use tokio::process::Command;
+use tracing::*;
 
 use crate::{
-    get_unseal_keys_request, list_vault_pods, unseal_request, BytesBody, ExecIn,
-    HttpForwarderService, HttpRequest,
+    get_unseal_keys_request, keys_from_field, unbracketed_host, unseal_request, BytesBody, ExecIn,
+    GetSealStatus, HttpForwarderService, HttpRequest, KubernetesAuth, PodApi, PodSealStatus,
+    PodSelector, TlsOptions, VAULT_PORT,
 };
 
+/// Kind of the key shares held by a key source
+///
+/// Clusters with a shamir seal are unsealed with unseal keys. Clusters with auto-unseal
+/// (`recovery_seal: true`) unseal themselves, operations like generate-root or rekey
+/// need the recovery keys instead.
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeyKind {
+    #[default]
+    Unseal,
+    Recovery,
+}
+
+impl std::fmt::Display for KeyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+impl KeyKind {
+    /// Kind of keys the cluster expects for key-consuming operations
+    pub fn required_by(status: &PodSealStatus) -> Self {
+        match status.recovery_seal {
+            true => KeyKind::Recovery,
+            false => KeyKind::Unseal,
+        }
+    }
+
+    /// Field of the vault kv secret storing the keys of this kind
+    pub fn secret_field(&self) -> &'static str {
+        match self {
+            KeyKind::Unseal => "keys",
+            KeyKind::Recovery => "recovery_keys",
+        }
+    }
+}
+
+/// Key shares labelled with their kind
+#[derive(Clone, Debug, Default)]
+pub struct Keys {
+    pub kind: KeyKind,
+    pub keys: Vec<Secret<String>>,
+}
+
+impl Keys {
+    /// Get the keys for unsealing, recovery keys can not unseal vault
+    pub fn unseal_keys(&self) -> anyhow::Result<&[Secret<String>]> {
+        match self.kind {
+            KeyKind::Unseal => Ok(&self.keys),
+            KeyKind::Recovery => Err(anyhow::anyhow!("recovery keys can not be used to unseal")),
+        }
+    }
+
+    /// Check that the keys are of the kind the cluster expects
+    pub fn ensure_required_by(&self, status: &PodSealStatus) -> anyhow::Result<()> {
+        let required = KeyKind::required_by(status);
+        if self.kind != required {
+            anyhow::bail!(
+                "cluster expects {} keys, but {} keys were provided",
+                required,
+                self.kind
+            );
+        }
+
+        Ok(())
+    }
+}
+
 /// Get the unseal keys by running the specified command
 #[tracing::instrument()]
 pub async fn get_unseal_keys(key_cmd: &str) -> anyhow::Result<Vec<Secret<String>>> {
@@ -27,22 +107,97 @@ pub async fn get_unseal_keys(key_cmd: &str) -> anyhow::Result<Vec<Secret<String>
     Ok(keys)
 }
 
-/// List all pods that are sealed
+/// List all pods that are sealed, see `list_sealed_pods_with`
+#[deprecated = "use `list_sealed_pods_with` and `PodSelector` instead"]
 pub async fn list_sealed_pods(api: &Api<Pod>) -> anyhow::Result<Vec<Pod>> {
+    list_sealed_pods_with(api, &PodSelector::default()).await
+}
+
+/// List all selected pods that are sealed
+pub async fn list_sealed_pods_with(
+    api: &Api<Pod>,
+    selector: &PodSelector,
+) -> anyhow::Result<Vec<Pod>> {
     let pods = api
-        .list(&list_vault_pods().labels(&ExecIn::Sealed.to_label_selector()))
+        .list(&selector.clone().role(ExecIn::Sealed).to_list_params())
         .await?;
 
     Ok(pods.items)
 }
 
+/// Pods unsealed at once by default, see `unseal_concurrently`
+pub const DEFAULT_UNSEAL_CONCURRENCY: usize = 4;
+
+/// Run `unseal` for the pods, at most `concurrency` at a time
+///
+/// Returns the result of each pod in the given order, with the time the unseal took.
+/// A failed pod does not stop the others.
+pub async fn unseal_concurrently<F, Fut>(
+    pods: Vec<String>,
+    concurrency: usize,
+    unseal: F,
+) -> Vec<(String, anyhow::Result<Duration>)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    stream::iter(pods)
+        .map(|name| {
+            let unsealed = unseal(name.clone());
+            async move {
+                let started = Instant::now();
+                let unsealed = unsealed.await.map(|()| started.elapsed());
+                (name, unsealed)
+            }
+        })
+        .buffered(concurrency.max(1))
+        .collect()
+        .await
+}
+
+impl PodApi {
+    /// Unseal the pods with the keys, at most `concurrency` at a time,
+    /// each over its own connection, see `unseal_concurrently`
+    pub async fn unseal_pods(
+        &self,
+        pods: Vec<String>,
+        keys: &[Secret<String>],
+        concurrency: usize,
+    ) -> Vec<(String, anyhow::Result<Duration>)> {
+        unseal_concurrently(pods, concurrency, |name| async move {
+            self.http(&name, VAULT_PORT).await?.unseal(keys).await
+        })
+        .await
+    }
+}
+
+/// Times a key submission answered with a server error is retried, see `Unseal`
+const UNSEAL_RETRIES: u32 = 5;
+
+/// Delay before the first retry of a key submission, doubled for each further retry
+const UNSEAL_RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
 /// Unseal a vault process using the provided keys
 #[async_trait::async_trait]
 pub trait Unseal {
     /// Unseal a vault process using the provided keys
+    ///
+    /// Nothing is submitted if the process is already unsealed, and no more keys are submitted
+    /// once the unseal threshold is reached. Submissions answered with a server error, e.g.
+    /// while vault is starting, are retried with backoff, continuing after the reported progress.
     async fn unseal(&mut self, keys: &[Secret<String>]) -> anyhow::Result<()>;
 }
 
+/// Part of the response of an unseal request, the same as the seal status
+#[derive(Debug, serde::Deserialize)]
+struct UnsealProgress {
+    sealed: bool,
+    #[serde(default)]
+    t: u8,
+    #[serde(default)]
+    progress: u8,
+}
+
 #[async_trait::async_trait]
 impl<T> Unseal for T
 where
@@ -53,11 +208,25 @@ where
             return Err(anyhow::anyhow!("no keys provided"));
         }
 
-        for key in keys {
+        // submitting keys anyway if the seal status cannot be read
+        let mut initial_progress = 0;
+        if let Ok(status) = self.seal_status().await {
+            if !status.sealed {
+                debug!("already unsealed, not submitting any keys");
+                return Ok(());
+            }
+            initial_progress = status.progress;
+        }
+
+        // progress of other unseal attempts, the progress beyond it was made by these keys
+        let mut base = initial_progress;
+        let mut next = 0;
+        let mut retries = 0;
+        while next < keys.len() {
             self.ready().await?;
 
             let body = serde_json::json!({
-                "key": key.expose_secret(),
+                "key": keys[next].expose_secret(),
                 "reset": false,
                 "migrate": false,
             });
@@ -66,26 +235,95 @@ where
 
             let (parts, body) = self.send_request(http_req).await?.into_parts();
 
-            let body = String::from_utf8(body.into()).unwrap();
+            let body = String::from_utf8_lossy(&body);
+
+            // vault answers with 5xx while it is still starting up
+            if parts.status.is_server_error() && retries < UNSEAL_RETRIES {
+                let delay = UNSEAL_RETRY_BACKOFF * 2u32.pow(retries);
+                retries += 1;
+                warn!(
+                    "unsealing: {}, retrying in {} ({} of {})",
+                    parts.status,
+                    humantime::format_duration(delay),
+                    retries,
+                    UNSEAL_RETRIES
+                );
+                tokio::time::sleep(delay).await;
+
+                // the key may have been accepted before the error, or the progress may have
+                // been reset by a restart, so continue with the key after the reported progress
+                if let Ok(status) = self.seal_status().await {
+                    if !status.sealed {
+                        debug!("unsealed while retrying");
+                        return Ok(());
+                    }
+                    if status.progress < base {
+                        base = 0;
+                    }
+                    next = usize::from(status.progress - base).min(next + 1);
+                }
+                continue;
+            }
 
             if !(parts.status.is_success() || parts.status.is_redirection()) {
                 return Err(anyhow::anyhow!("unsealing: {}", body));
             }
+
+            retries = 0;
+            next += 1;
+
+            match serde_json::from_str::<UnsealProgress>(&body) {
+                Ok(progress) if !progress.sealed => {
+                    debug!("unsealed after {} of {} keys", next, keys.len());
+                    break;
+                }
+                Ok(progress) => debug!(
+                    "unseal progress {} of {} keys",
+                    progress.progress, progress.t
+                ),
+                Err(_) => {}
+            }
         }
 
         Ok(())
     }
 }
 
-/// Get the unseal keys from a Vault secret
+/// Get the unseal or recovery keys from a Vault secret
 #[async_trait::async_trait]
 pub trait GetUnsealKeys {
+    /// Get the keys of the given kind from a field of a Vault secret
+    ///
+    /// The field holds the keys separated by newlines or as a json array,
+    /// the secret can be stored in a kv v1 or v2 engine.
+    async fn get_keys_of_field(
+        &mut self,
+        path: &http::uri::PathAndQuery,
+        token: Secret<String>,
+        kind: KeyKind,
+        field: &str,
+    ) -> anyhow::Result<Keys>;
+
+    /// Get the keys of the given kind from their default field of a Vault secret,
+    /// see `KeyKind::secret_field`
+    async fn get_keys(
+        &mut self,
+        path: &http::uri::PathAndQuery,
+        token: Secret<String>,
+        kind: KeyKind,
+    ) -> anyhow::Result<Keys> {
+        self.get_keys_of_field(path, token, kind, kind.secret_field())
+            .await
+    }
+
     /// Get the unseal keys from a Vault secret
     async fn get_unseal_keys(
         &mut self,
         path: &http::uri::PathAndQuery,
         token: Secret<String>,
-    ) -> anyhow::Result<Vec<Secret<String>>>;
+    ) -> anyhow::Result<Vec<Secret<String>>> {
+        Ok(self.get_keys(path, token, KeyKind::Unseal).await?.keys)
+    }
 }
 
 #[async_trait::async_trait]
@@ -93,11 +331,13 @@ impl<T> GetUnsealKeys for T
 where
     T: HttpRequest<BytesBody> + Send + Sync + 'static,
 {
-    async fn get_unseal_keys(
+    async fn get_keys_of_field(
         &mut self,
         path: &http::uri::PathAndQuery,
         token: Secret<String>,
-    ) -> anyhow::Result<Vec<Secret<String>>> {
+        kind: KeyKind,
+        field: &str,
+    ) -> anyhow::Result<Keys> {
         let req = get_unseal_keys_request(path.as_str(), token)?;
 
         let (parts, body) = self.send_request(req).await?.into_parts();
@@ -105,18 +345,162 @@ where
         let body = String::from_utf8(body.to_vec())?;
 
         if !(parts.status.is_success()) {
-            return Err(anyhow::anyhow!("retrieving unseal keys: {}", body));
+            return Err(anyhow::anyhow!("retrieving {} keys: {}", kind, body));
+        }
+
+        keys_from_field(kv_secret_data(&body)?, field, kind)
+    }
+}
+
+/// Time to resolve and connect to the vault storing the keys
+const KEYS_SECRET_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Retrieving the keys from a vault secret failed before a request could be sent
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeysSecretError {
+    /// the uri of the secret is not valid
+    InvalidUri { uri: String, reason: String },
+    /// the host of the uri could not be resolved
+    Resolve { host: String, reason: String },
+    /// no connection to the host could be established
+    Connect { host: String, reason: String },
+}
+
+impl std::fmt::Display for KeysSecretError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidUri { uri, reason } => write!(
+                f,
+                "keys secret uri {} is not valid: {}, expected e.g. https://vault.example.com/v1/secret/data/vault/unseal-keys",
+                uri, reason
+            ),
+            Self::Resolve { host, reason } => write!(f, "resolving {}: {}", host, reason),
+            Self::Connect { host, reason } => write!(f, "connecting to {}: {}", host, reason),
+        }
+    }
+}
+
+impl std::error::Error for KeysSecretError {}
+
+/// Uri of a vault kv secret storing the keys, e.g. `https://vault.example.com/v1/secret/data/vault/unseal-keys`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeysSecretUri {
+    uri: http::Uri,
+    /// path and query sent to vault, without `field`
+    path: http::uri::PathAndQuery,
+    field: Option<String>,
+}
+
+impl KeysSecretUri {
+    /// Path of the secret including the `/v1` prefix
+    pub fn path(&self) -> &http::uri::PathAndQuery {
+        &self.path
+    }
+
+    /// Field of the secret holding the keys, given with `?field=<name>`,
+    /// `keys` or `recovery_keys` if unset
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref()
+    }
+
+    /// Client retrieving the keys from the vault of the uri
+    pub fn client(&self) -> GetUnsealKeysFromVault {
+        GetUnsealKeysFromVault::new(&self.uri).expect("authority is validated when parsing")
+    }
+}
+
+impl FromStr for KeysSecretUri {
+    type Err = KeysSecretError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| KeysSecretError::InvalidUri {
+            uri: s.to_string(),
+            reason: reason.to_string(),
+        };
+
+        if !s.contains("://") {
+            return Err(invalid("missing scheme"));
+        }
+        let uri = http::Uri::from_str(s).map_err(|e| invalid(&e.to_string()))?;
+
+        match uri.scheme_str() {
+            Some("http" | "https") => {}
+            Some(scheme) => return Err(invalid(&format!("unsupported scheme {}", scheme))),
+            None => return Err(invalid("missing scheme")),
+        }
+        if uri.host().is_none_or(str::is_empty) {
+            return Err(invalid("missing host"));
+        }
+        if uri.path().trim_start_matches('/').is_empty() {
+            return Err(invalid("missing path of the secret"));
+        }
+        if !uri.path().starts_with("/v1/") {
+            return Err(invalid("path does not start with /v1/"));
+        }
+
+        // `field` is for vault-mgmt, the rest of the query (e.g. `version`) is sent to vault
+        let (mut field, mut query) = (None, vec![]);
+        for param in uri.query().unwrap_or_default().split('&') {
+            match param.strip_prefix("field=") {
+                Some("") => return Err(invalid("empty field")),
+                Some(name) => {
+                    let name =
+                        percent_decode(name).ok_or(invalid("invalid percent-encoding of field"))?;
+                    field = Some(name)
+                }
+                None if param.is_empty() => {}
+                None => query.push(param),
+            }
         }
+        let path = match query.is_empty() {
+            true => uri.path().to_string(),
+            false => format!("{}?{}", uri.path(), query.join("&")),
+        };
+        let path = http::uri::PathAndQuery::from_str(&path).map_err(|e| invalid(&e.to_string()))?;
 
-        let response: vault_kvget::Response = serde_json::from_str(&body)?;
+        Ok(Self { uri, path, field })
+    }
+}
 
-        Ok(response.keys())
+/// Percent-encode a value of the query of a uri, e.g. the field of a `KeysSecretUri`
+pub(crate) fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Decode a percent-encoded value of the query of a uri, `None` if it is not valid
+fn percent_decode(value: &str) -> Option<String> {
+    let mut bytes = value.bytes();
+    let mut decoded = vec![];
+    while let Some(b) = bytes.next() {
+        decoded.push(match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            b => b,
+        });
+    }
+    String::from_utf8(decoded).ok()
+}
+
+impl std::fmt::Display for KeysSecretUri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.uri.fmt(f)
     }
 }
 
 pub struct GetUnsealKeysFromVault {
     scheme: http::uri::Scheme,
     authority: http::uri::Authority,
+    tls: TlsOptions,
 }
 
 impl GetUnsealKeysFromVault {
@@ -131,99 +515,160 @@ impl GetUnsealKeysFromVault {
                 .clone(),
             authority: uri
                 .authority()
-                .ok_or(anyhow::anyhow!(
-                    "keys secret uri does not include an authority"
-                ))?
+                .ok_or(KeysSecretError::InvalidUri {
+                    uri: uri.to_string(),
+                    reason: "missing host".to_string(),
+                })?
                 .clone(),
+            tls: TlsOptions::default(),
         })
     }
-}
 
-#[async_trait::async_trait]
-impl GetUnsealKeys for GetUnsealKeysFromVault {
-    async fn get_unseal_keys(
-        &mut self,
-        path: &http::uri::PathAndQuery,
-        token: Secret<String>,
-    ) -> anyhow::Result<Vec<Secret<String>>> {
-        let stream = tokio::net::TcpStream::connect((
-            self.authority.host(),
-            self.authority
-                .port_u16()
-                .unwrap_or_else(|| match self.scheme.as_str() {
-                    "https" => 443,
-                    _ => 80,
-                }),
-        ))
+    /// TLS settings of `https` connections, independent of the connections to the pods
+    pub fn tls(mut self, tls: TlsOptions) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Resolve the host and connect to it, both within `KEYS_SECRET_CONNECT_TIMEOUT`
+    async fn connect(&self) -> Result<tokio::net::TcpStream, KeysSecretError> {
+        let host = self.authority.host();
+        let port = self
+            .authority
+            .port_u16()
+            .unwrap_or_else(|| match self.scheme.as_str() {
+                "https" => 443,
+                _ => 80,
+            });
+        let timed_out = format!(
+            "timed out after {}",
+            humantime::format_duration(KEYS_SECRET_CONNECT_TIMEOUT)
+        );
+
+        let resolve_error = |reason: String| KeysSecretError::Resolve {
+            host: host.to_string(),
+            reason,
+        };
+        let addrs = tokio::time::timeout(
+            KEYS_SECRET_CONNECT_TIMEOUT,
+            tokio::net::lookup_host((unbracketed_host(host), port)),
+        )
         .await
-        .unwrap();
+        .map_err(|_| resolve_error(timed_out.clone()))?
+        .map_err(|e| resolve_error(e.to_string()))?
+        .collect::<Vec<_>>();
 
-        let mut client = match self.scheme.as_str() {
-            "https" => HttpForwarderService::https(self.authority.host(), stream)
-                .await
-                .unwrap(),
-            "http" => HttpForwarderService::http(stream).await.unwrap(),
+        if addrs.is_empty() {
+            return Err(resolve_error("no addresses found".to_string()));
+        }
+
+        let connect_error = |reason: String| KeysSecretError::Connect {
+            host: self.authority.to_string(),
+            reason,
+        };
+        tokio::time::timeout(
+            KEYS_SECRET_CONNECT_TIMEOUT,
+            tokio::net::TcpStream::connect(&addrs[..]),
+        )
+        .await
+        .map_err(|_| connect_error(timed_out))?
+        .map_err(|e| connect_error(e.to_string()))
+    }
+
+    /// Connect to the vault storing the keys
+    pub(crate) async fn client(&self) -> anyhow::Result<HttpForwarderService<BytesBody>> {
+        let stream = self.connect().await?;
+
+        match self.scheme.as_str() {
+            "https" => {
+                let host = self.authority.host();
+                Ok(HttpForwarderService::https_with(host, stream, &self.tls)
+                    .await
+                    .map_err(|e| KeysSecretError::Connect {
+                        host: self.authority.to_string(),
+                        reason: e.to_string(),
+                    })?)
+            }
+            "http" => HttpForwarderService::http(stream).await,
             _ => {
                 anyhow::bail!("unsupported scheme {}", self.scheme.as_str())
             }
-        };
-
-        client.get_unseal_keys(path, token).await
+        }
     }
-}
-
-mod vault_kvget {
-    use secrecy::Secret;
-    use serde::{Deserialize, Serialize};
 
-    #[derive(Deserialize, Serialize, Debug)]
-    pub struct Response {
-        data: DataMetadata,
+    /// Connect to the vault storing the keys without reading them, e.g. to validate the config
+    pub async fn check_reachable(&self) -> anyhow::Result<()> {
+        self.client().await.map(|_| ())
     }
 
-    impl Response {
-        pub fn keys(&self) -> Vec<Secret<String>> {
-            self.data
-                .data
-                .keys
-                .lines()
-                .collect::<Vec<_>>()
-                .iter()
-                .map(|k| Secret::new(k.to_string()))
-                .collect()
-        }
+    /// Get a token for reading the keys with the kubernetes auth method of the vault storing them
+    pub async fn login(&self, auth: &KubernetesAuth) -> anyhow::Result<Secret<String>> {
+        auth.login(&mut self.client().await?).await
     }
+}
 
-    #[derive(Deserialize, Serialize, Debug)]
-    struct DataMetadata {
-        data: Data,
+#[async_trait::async_trait]
+impl GetUnsealKeys for GetUnsealKeysFromVault {
+    async fn get_keys_of_field(
+        &mut self,
+        path: &http::uri::PathAndQuery,
+        token: Secret<String>,
+        kind: KeyKind,
+        field: &str,
+    ) -> anyhow::Result<Keys> {
+        self.client()
+            .await?
+            .get_keys_of_field(path, token, kind, field)
+            .await
     }
+}
 
-    #[derive(Deserialize, Serialize, Debug)]
-    struct Data {
-        keys: String,
+/// Fields of a secret read from a kv engine
+///
+/// Kv v2 nests the fields in `data.data` next to the `metadata`, kv v1 returns them in `data`.
+fn kv_secret_data(body: &str) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let response: serde_json::Value = serde_json::from_str(body)?;
+
+    let data = match response.get("data") {
+        Some(serde_json::Value::Object(data)) => data,
+        _ => anyhow::bail!("response does not contain the data of a secret"),
+    };
+
+    match (data.get("data"), data.get("metadata")) {
+        (Some(serde_json::Value::Object(fields)), Some(_)) => Ok(fields.clone()),
+        _ => Ok(data.clone()),
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
 
     use http::{Method, Request, Response, StatusCode};
     use hyper::body::Bytes;
     use k8s_openapi::{api::core::v1::Pod, List};
     use kube::{client::Body, Api, Client};
-    use secrecy::Secret;
+    use secrecy::{ExposeSecret, Secret};
     use tokio::task::JoinHandle;
     use tokio_util::sync::CancellationToken;
     use tower_test::mock::{self, Handle};
     use wiremock::{
-        matchers::{header, method, path},
+        matchers::{header, method, path, query_param},
         Mock, MockServer, ResponseTemplate,
     };
 
+    #[allow(deprecated)]
+    use crate::list_sealed_pods;
     use crate::{
-        list_sealed_pods, GetUnsealKeys, GetUnsealKeysFromVault, HttpForwarderService, Unseal,
+        unseal_concurrently, GetUnsealKeys, GetUnsealKeysFromVault, HttpForwarderService, KeyKind,
+        Keys, KeysSecretError, KeysSecretUri, PodSealStatus, Unseal,
     };
 
     async fn mock_list_sealed(
@@ -244,7 +689,7 @@ mod tests {
                     println!("{} {} {} ", method, uri, query);
 
                     let body = match (method.as_str(), uri.as_str(), query.as_str(), watch) {
-                        ("GET", "/api/v1/namespaces/vault-mgmt-e2e/pods", "&labelSelector=vault-sealed%3Dtrue", false) => {
+                        ("GET", "/api/v1/namespaces/vault-mgmt-e2e/pods", "&labelSelector=app.kubernetes.io%2Fname%3Dvault%2Cvault-sealed%3Dtrue", false) => {
                             let mut list = List::<Pod>::default();
 
                             for id in 0..=2 {
@@ -292,6 +737,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[allow(deprecated)]
     async fn get_sealed_pods_returns_sealed_pods() {
         let (api, service, cancel) = setup().await;
 
@@ -370,6 +816,204 @@ mod tests {
         assert!(outcome.is_ok());
     }
 
+    #[tokio::test]
+    async fn unseal_stops_at_the_threshold() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/sys/seal-status"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "type": "shamir",
+                    "initialized": true,
+                    "sealed": true,
+                    "t": 2,
+                    "n": 3,
+                    "progress": 0,
+                    "nonce": "",
+                    "version": "1.14.0",
+                    "build_date": "2023-06-19T11:40:23Z",
+                    "migration": false,
+                    "recovery_seal": false,
+                    "storage_type": "raft",
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        for (key, sealed, progress) in [("abc", true, 1), ("def", false, 0)] {
+            Mock::given(method(Method::PUT))
+                .and(path("/v1/sys/unseal"))
+                .and(UnsealBodyMatcher(key.to_string()))
+                .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(
+                    serde_json::json!({ "sealed": sealed, "t": 2, "n": 3, "progress": progress }),
+                ))
+                .expect(1)
+                .mount(&mock_server)
+                .await;
+        }
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/unseal"))
+            .and(UnsealBodyMatcher("ghi".to_string()))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let outcome = client
+            .unseal(&[
+                Secret::from_str("abc").unwrap(),
+                Secret::from_str("def").unwrap(),
+                Secret::from_str("ghi").unwrap(),
+            ])
+            .await;
+
+        assert!(outcome.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unseal_retries_server_errors_after_the_reported_progress() {
+        let mock_server = MockServer::start().await;
+
+        let status = |progress: u8| {
+            ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                "type": "shamir",
+                "initialized": true,
+                "sealed": true,
+                "t": 2,
+                "n": 3,
+                "progress": progress,
+                "nonce": "",
+                "version": "1.14.0",
+                "build_date": "2023-06-19T11:40:23Z",
+                "migration": false,
+                "recovery_seal": false,
+                "storage_type": "raft",
+            }))
+        };
+        Mock::given(method(Method::GET))
+            .and(path("/v1/sys/seal-status"))
+            .respond_with(status(0))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method(Method::GET))
+            .and(path("/v1/sys/seal-status"))
+            .respond_with(status(1))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // the first key is accepted despite the error, so it is not submitted again
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/unseal"))
+            .and(UnsealBodyMatcher("abc".to_string()))
+            .respond_with(ResponseTemplate::new(StatusCode::SERVICE_UNAVAILABLE))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        // the second key is not accepted, so it is submitted again
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/unseal"))
+            .and(UnsealBodyMatcher("def".to_string()))
+            .respond_with(ResponseTemplate::new(StatusCode::SERVICE_UNAVAILABLE))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/unseal"))
+            .and(UnsealBodyMatcher("def".to_string()))
+            .respond_with(ResponseTemplate::new(StatusCode::OK).set_body_json(
+                serde_json::json!({ "sealed": false, "t": 2, "n": 3, "progress": 0 }),
+            ))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/unseal"))
+            .and(UnsealBodyMatcher("ghi".to_string()))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let outcome = client
+            .unseal(&[
+                Secret::from_str("abc").unwrap(),
+                Secret::from_str("def").unwrap(),
+                Secret::from_str("ghi").unwrap(),
+            ])
+            .await;
+
+        assert!(outcome.is_ok());
+    }
+
+    #[tokio::test]
+    async fn unseal_skips_unsealed_pods() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/sys/seal-status"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "type": "shamir",
+                    "initialized": true,
+                    "sealed": false,
+                    "t": 2,
+                    "n": 3,
+                    "progress": 0,
+                    "nonce": "",
+                    "version": "1.14.0",
+                    "build_date": "2023-06-19T11:40:23Z",
+                    "migration": false,
+                    "recovery_seal": false,
+                    "storage_type": "raft",
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(method(Method::PUT))
+            .and(path("/v1/sys/unseal"))
+            .respond_with(ResponseTemplate::new(StatusCode::OK))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        assert!(client
+            .unseal(&[Secret::from_str("abc").unwrap()])
+            .await
+            .is_ok());
+    }
+
     async fn mock_get_unseal_keys() -> MockServer {
         let mock_server = MockServer::start().await;
 
@@ -458,4 +1102,244 @@ mod tests {
 
         assert!(outcome.is_ok());
     }
+
+    #[tokio::test]
+    async fn retrieving_keys_from_a_field_of_a_kv_v1_secret_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/secret/unseal"))
+            .and(query_param("version", "2"))
+            .and(header("X-Vault-Token", "token"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "lease_duration": 2764800,
+                    "data": {
+                        "shares": ["abc", "def"],
+                        "keys": "not the keys",
+                    },
+                })),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let uri = KeysSecretUri::from_str(&format!(
+            "{}/v1/secret/unseal?field=shares&version=2",
+            mock_server.uri()
+        ))
+        .unwrap();
+        assert_eq!(uri.field(), Some("shares"));
+        assert_eq!(uri.path().as_str(), "/v1/secret/unseal?version=2");
+
+        let keys = uri
+            .client()
+            .get_keys_of_field(
+                uri.path(),
+                Secret::new("token".to_string()),
+                KeyKind::Unseal,
+                uri.field().unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            keys.keys
+                .iter()
+                .map(|k| k.expose_secret().as_str())
+                .collect::<Vec<_>>(),
+            vec!["abc", "def"]
+        );
+    }
+
+    #[test]
+    fn keys_secret_uri_is_validated() {
+        let uri = KeysSecretUri::from_str("https://vault.example.com/v1/secret/data/unseal-keys")
+            .unwrap();
+        assert_eq!(uri.path().as_str(), "/v1/secret/data/unseal-keys");
+
+        for (uri, reason) in [
+            (
+                "vault.example.com/v1/secret/data/unseal-keys",
+                "missing scheme",
+            ),
+            (
+                "ftp://vault.example.com/v1/secret",
+                "unsupported scheme ftp",
+            ),
+            ("https://vault.example.com", "missing path of the secret"),
+            ("https://vault.example.com/", "missing path of the secret"),
+            (
+                "https://vault.example.com/secret/data/unseal-keys",
+                "path does not start with /v1/",
+            ),
+        ] {
+            match KeysSecretUri::from_str(uri).unwrap_err() {
+                KeysSecretError::InvalidUri { reason: r, .. } => assert_eq!(r, reason, "{}", uri),
+                e => panic!("unexpected error for {}: {}", uri, e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv6_literal_in_keys_secret_uri_is_resolved() {
+        let uri = KeysSecretUri::from_str("http://[::1]:1/v1/kv/data/test").unwrap();
+
+        let err = uri
+            .client()
+            .get_unseal_keys(uri.path(), Secret::new("token".to_string()))
+            .await
+            .unwrap_err();
+
+        // the brackets are not part of the address, so resolving succeeds and connecting fails
+        assert!(matches!(
+            err.downcast_ref::<KeysSecretError>(),
+            Some(KeysSecretError::Connect { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retrieving_keys_fails_without_panic_if_host_refuses_connections() {
+        // bind and drop a listener to get a port nobody listens on
+        let port = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let uri =
+            KeysSecretUri::from_str(&format!("http://127.0.0.1:{}/v1/kv/data/test", port)).unwrap();
+
+        let err = uri
+            .client()
+            .get_unseal_keys(uri.path(), Secret::new("token".to_string()))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            err.downcast_ref::<KeysSecretError>(),
+            Some(KeysSecretError::Connect { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn retrieving_recovery_keys_works() {
+        let mock_server = MockServer::start().await;
+
+        Mock::given(method(Method::GET))
+            .and(path("/v1/kv/data/test"))
+            .and(header("X-Vault-Token", "token"))
+            .respond_with(
+                ResponseTemplate::new(StatusCode::OK).set_body_json(serde_json::json!({
+                    "data": {
+                        "data": {
+                            "recovery_keys": "jkl\nmno"
+                        },
+                        "metadata": {}
+                    },
+                })),
+            )
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let mut client = HttpForwarderService::http(
+            tokio::net::TcpStream::connect(mock_server.uri().strip_prefix("http://").unwrap())
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let path = http::uri::PathAndQuery::from_static("/v1/kv/data/test");
+
+        let keys = client
+            .get_keys(&path, Secret::new("token".to_string()), KeyKind::Recovery)
+            .await
+            .unwrap();
+
+        assert_eq!(keys.kind, KeyKind::Recovery);
+        assert_eq!(keys.keys.len(), 2);
+        assert!(keys.unseal_keys().is_err());
+
+        let unseal = client
+            .get_unseal_keys(&path, Secret::new("token".to_string()))
+            .await;
+
+        assert!(unseal.is_err());
+    }
+
+    #[test]
+    fn keys_must_match_seal_of_cluster() {
+        let status = |recovery_seal: bool| -> PodSealStatus {
+            serde_json::from_value(serde_json::json!({
+                "type": if recovery_seal { "awskms" } else { "shamir" },
+                "initialized": true,
+                "sealed": false,
+                "t": 2,
+                "n": 3,
+                "progress": 0,
+                "nonce": "",
+                "version": "1.13.0",
+                "build_date": "2023-03-01T14:58:13Z",
+                "migration": false,
+                "recovery_seal": recovery_seal,
+                "storage_type": "raft",
+            }))
+            .unwrap()
+        };
+
+        let unseal = Keys {
+            kind: KeyKind::Unseal,
+            keys: vec![],
+        };
+        let recovery = Keys {
+            kind: KeyKind::Recovery,
+            keys: vec![],
+        };
+
+        assert!(unseal.ensure_required_by(&status(false)).is_ok());
+        assert!(unseal.ensure_required_by(&status(true)).is_err());
+        assert!(recovery.ensure_required_by(&status(true)).is_ok());
+        assert!(recovery.ensure_required_by(&status(false)).is_err());
+    }
+
+    #[tokio::test]
+    async fn pods_are_unsealed_concurrently_up_to_the_limit() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let pods = (0..5).map(|i| format!("vault-{}", i)).collect();
+        let results = unseal_concurrently(pods, 2, |name| {
+            let (running, max_running) = (running.clone(), max_running.clone());
+            async move {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                running.fetch_sub(1, Ordering::SeqCst);
+
+                match name.as_str() {
+                    "vault-1" => anyhow::bail!("connection refused"),
+                    _ => Ok(()),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        // the results keep the order of the pods and a failed pod does not stop the others
+        assert_eq!(
+            results
+                .iter()
+                .map(|(name, r)| (name.as_str(), r.is_ok()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("vault-0", true),
+                ("vault-1", false),
+                ("vault-2", true),
+                ("vault-3", true),
+                ("vault-4", true),
+            ]
+        );
+    }
 }
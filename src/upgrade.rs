@@ -1,231 +1,3778 @@
-use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
-use kube::{api::DeleteParams, runtime::wait::conditions::is_pod_running};
-use secrecy::Secret;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::ValueEnum;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use hyper::body::Bytes;
+use k8s_openapi::api::{
+    apps::v1::StatefulSet,
+    core::v1::{Endpoints, Pod},
+};
+use kube::{
+    api::{DeleteParams, EvictParams, Patch, PatchParams},
+    runtime::wait::{
+        conditions::{is_deleted, is_pod_running},
+        Condition,
+    },
+    Api,
+};
+use secrecy::{ExposeSecret, Secret};
 use tokio_retry::{
     strategy::{jitter, ExponentialBackoff},
     Retry,
 };
+use tokio_util::sync::CancellationToken;
 use tracing::*;
 
 use crate::{
-    is_active, is_pod_exporting_seal_status, ExecIn, StepDown, Unseal, VaultVersion, VAULT_PORT,
-    {is_pod_ready, is_pod_standby, is_pod_unsealed}, {is_seal_status_initialized, GetSealStatus},
-    {is_sealed, list_vault_pods, PodApi, StatefulSetApi},
+    await_condition, find_vault_container, image_pull_failure, image_with_version, is_active,
+    is_endpoints_moved_from, is_pod_container_ready, is_pod_exporting_seal_status,
+    is_pod_failing_image_pull, is_raft_server_of_pod, previous_version_of, registration_label,
+    template_changes, token_accessor, vault_container_name, ExecIn, GetAutopilotState, GetLeader,
+    GetRaftConfiguration, HealthGate, Journal, Mesh, PodHook, PodReport, PodSealStatus, PodStep,
+    RaftConfigurationServer, RaftSnapshot, SemVer, SnapshotDestination, StepDown, TemplateChange,
+    Unseal, UnsealMethod, UpgradeEvent, UpgradeHistory, UpgradeLock, UpgradePhase, UpgradeProgress,
+    UpgradeReporter, VaultVersion, FIELD_MANAGER, VAULT_PORT,
+    {is_pod_ready, is_pod_standby, is_pod_unsealed},
+    {is_seal_status_caught_up, is_seal_status_initialized, GetSealStatus},
+    {is_sealed, PodApi, StatefulSetApi},
 };
 
-impl PodApi {
-    /// Check if the vault pod has the specified version
-    pub fn is_current(pod: &Pod, target: &VaultVersion) -> anyhow::Result<bool> {
-        let pod_version = VaultVersion::try_from(pod)?;
-        Ok(&pod_version == target)
+/// How to determine that another pod took over after stepping down the active pod
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TakeoverCondition {
+    /// wait for the `vault-active` label of the pod to become false
+    #[default]
+    Label,
+    /// wait for the leader API of the pod to report another pod as leader
+    Leader,
+    /// wait for a fixed delay, for charts without service registration
+    Delay,
+}
+
+impl std::fmt::Display for TakeoverCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// How to upgrade or restart the active pod
+#[derive(ValueEnum, Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ActiveStrategy {
+    /// step down the active pod and wait for another pod to take over before deleting it
+    #[default]
+    StepDownFirst,
+    /// delete the active pod directly and rely on the raft election
+    DeleteDirectly,
+    /// wait for autopilot to report a healthy cluster before stepping down,
+    /// so leadership is handed over to an up-to-date voter
+    Autopilot,
+}
+
+impl std::fmt::Display for ActiveStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value()
+            .expect("no values are skipped")
+            .get_name()
+            .fmt(f)
+    }
+}
+
+/// Waiting for another pod to take over after a step-down
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Takeover {
+    pub condition: TakeoverCondition,
+    /// delay for `TakeoverCondition::Delay`
+    pub delay: Duration,
+    /// time to wait for the takeover before stepping down again
+    pub timeout: Duration,
+    /// how often to step down again if leadership did not move
+    pub retries: usize,
+    /// step down a new leader that is sealed, not ready or lagging, sharing `retries`
+    pub healthy_leader: bool,
+    /// raft entries the new leader may not have applied yet
+    pub max_leader_lag: u64,
+}
+
+impl Default for Takeover {
+    fn default() -> Self {
+        Self {
+            condition: TakeoverCondition::default(),
+            delay: Duration::from_secs(10),
+            timeout: Duration::from_secs(120),
+            retries: 2,
+            healthy_leader: true,
+            max_leader_lag: 1000,
+        }
+    }
+}
+
+/// How to upgrade or restart a pod
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeOptions {
+    /// unseal the pod, otherwise wait for an external unseal
+    pub should_unseal: bool,
+    /// recreate the pod even if it already has the target version
+    pub force_upgrade: bool,
+    /// time to wait for the step-down request to the active pod
+    pub stepdown_timeout: Duration,
+    /// time to wait for the pod to be deleted
+    pub delete_timeout: Duration,
+    /// time to wait for the recreated pod to be running and exporting its seal status
+    pub running_timeout: Duration,
+    /// time to wait for the pod to be unsealed, by vault-mgmt or externally
+    pub unseal_timeout: Duration,
+    /// time to wait for an external unseal of the pod, `unseal_timeout` if not set
+    pub external_unseal_timeout: Option<Duration>,
+    /// time to wait for the pod to be ready after it was unsealed
+    pub pod_ready_timeout: Duration,
+    /// runs before the pod is stepped down and deleted
+    pub pre_pod_hook: Option<PodHook>,
+    /// runs after the recreated pod was unsealed and is ready
+    pub post_pod_hook: Option<PodHook>,
+    /// time to wait for each hook, including the leader hook
+    pub hook_timeout: Duration,
+    /// have to pass after the pod, before the next pod is touched
+    pub gates: Vec<HealthGate>,
+    /// time to wait for the gates to pass
+    pub gate_timeout: Duration,
+    /// runs with the new active pod after a step-down, e.g. to update an external load balancer
+    pub leader_hook: Option<PodHook>,
+    /// have to pass for the new active pod after a step-down, within `gate_timeout`
+    pub leader_gates: Vec<HealthGate>,
+    /// collects the versions, unseal method and duration of the steps of each pod
+    pub report: Option<UpgradeReporter>,
+    /// records each step before and after it is executed
+    pub journal: Option<Journal>,
+}
+
+impl Default for UpgradeOptions {
+    fn default() -> Self {
+        Self {
+            should_unseal: true,
+            force_upgrade: false,
+            stepdown_timeout: Duration::from_secs(60),
+            delete_timeout: Duration::from_secs(300),
+            running_timeout: Duration::from_secs(600),
+            unseal_timeout: Duration::from_secs(600),
+            external_unseal_timeout: None,
+            pod_ready_timeout: Duration::from_secs(600),
+            pre_pod_hook: None,
+            post_pod_hook: None,
+            hook_timeout: Duration::from_secs(300),
+            gates: vec![],
+            gate_timeout: Duration::from_secs(600),
+            leader_hook: None,
+            leader_gates: vec![],
+            report: None,
+            journal: None,
+        }
+    }
+}
+
+impl UpgradeOptions {
+    /// Unseal the pod, otherwise wait for an external unseal
+    pub fn should_unseal(mut self, should_unseal: bool) -> Self {
+        self.should_unseal = should_unseal;
+        self
+    }
+
+    /// Recreate the pod even if it already has the target version
+    pub fn force_upgrade(mut self, force_upgrade: bool) -> Self {
+        self.force_upgrade = force_upgrade;
+        self
+    }
+
+    /// Set the time to wait for the step-down request to the active pod
+    pub fn stepdown_timeout(mut self, timeout: Duration) -> Self {
+        self.stepdown_timeout = timeout;
+        self
+    }
+
+    /// Set the time to wait for the pod to be deleted
+    pub fn delete_timeout(mut self, timeout: Duration) -> Self {
+        self.delete_timeout = timeout;
+        self
+    }
+
+    /// Set the time to wait for the recreated pod to be running
+    pub fn running_timeout(mut self, timeout: Duration) -> Self {
+        self.running_timeout = timeout;
+        self
+    }
+
+    /// Set the time to wait for the pod to be unsealed
+    pub fn unseal_timeout(mut self, timeout: Duration) -> Self {
+        self.unseal_timeout = timeout;
+        self
+    }
+
+    /// Set the time to wait for an external unseal of the pod
+    pub fn external_unseal_timeout(mut self, timeout: Duration) -> Self {
+        self.external_unseal_timeout = Some(timeout);
+        self
+    }
+
+    /// Set the time to wait for the pod to be ready after it was unsealed
+    pub fn pod_ready_timeout(mut self, timeout: Duration) -> Self {
+        self.pod_ready_timeout = timeout;
+        self
+    }
+
+    /// Run the hook before each pod is stepped down and deleted
+    pub fn pre_pod_hook(mut self, hook: PodHook) -> Self {
+        self.pre_pod_hook = Some(hook);
+        self
+    }
+
+    /// Run the hook after each recreated pod was unsealed and is ready
+    pub fn post_pod_hook(mut self, hook: PodHook) -> Self {
+        self.post_pod_hook = Some(hook);
+        self
+    }
+
+    /// Set the time to wait for each hook
+    pub fn hook_timeout(mut self, timeout: Duration) -> Self {
+        self.hook_timeout = timeout;
+        self
+    }
+
+    /// Add a gate that has to pass after each pod, before the next pod is touched
+    pub fn gate(mut self, gate: HealthGate) -> Self {
+        self.gates.push(gate);
+        self
+    }
+
+    /// Set the time to wait for the gates to pass
+    pub fn gate_timeout(mut self, timeout: Duration) -> Self {
+        self.gate_timeout = timeout;
+        self
+    }
+
+    /// Run the hook with the new active pod after each step-down
+    pub fn leader_hook(mut self, hook: PodHook) -> Self {
+        self.leader_hook = Some(hook);
+        self
+    }
+
+    /// Add a gate that has to pass for the new active pod after each step-down
+    pub fn leader_gate(mut self, gate: HealthGate) -> Self {
+        self.leader_gates.push(gate);
+        self
+    }
+
+    /// Collect a report of the upgrade, read it from the reporter afterwards
+    pub fn report(mut self, reporter: UpgradeReporter) -> Self {
+        self.report = Some(reporter);
+        self
+    }
+
+    /// Record each step in the journal before and after it is executed
+    pub fn journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Add to the report of the pod, if a report is collected
+    fn report_pod(&self, name: &str, f: impl FnOnce(&mut PodReport)) {
+        if let Some(reporter) = &self.report {
+            reporter.pod(name, f);
+        }
+    }
+}
+
+/// Asks whether to continue with the given question, e.g. on the terminal
+pub type Confirm = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// How to upgrade or restart all pods of a cluster
+#[derive(Clone)]
+pub struct ClusterUpgradeOptions {
+    /// how to upgrade or restart each pod
+    pub pod: UpgradeOptions,
+    /// how many standby pods may be upgraded at the same time, limited by the raft quorum
+    pub max_unavailable: usize,
+    /// wait for an upgraded standby pod to apply the raft log the leader committed,
+    /// up to this many entries, before the next pod is upgraded (`None` to skip)
+    pub max_raft_lag: Option<u64>,
+    /// time to wait for a standby pod to catch up with the leader
+    pub raft_catch_up_timeout: Duration,
+    /// take a raft snapshot of the active pod and store it here before any pod is upgraded
+    pub snapshot_before: Option<SnapshotDestination>,
+    /// skip the pods an interrupted rollout of the same kind already finished
+    pub resume: bool,
+    /// take over the upgrade lock of the statefulset even if another upgrade holds it
+    pub force_unlock: bool,
+    /// upgrade this many standby pods first and watch them for `bake_time`
+    /// before upgrading the remaining pods (0 to disable)
+    pub canary: usize,
+    /// time the canary pods have to stay unsealed, ready and healthy
+    pub bake_time: Duration,
+    /// upgrade even if the target version is older than the version of a pod
+    pub allow_downgrade: bool,
+    /// recreate outdated pods even if their template also changed besides the version,
+    /// see `template_changes`
+    pub ack_template_changes: bool,
+    /// stop before the next pod if the remaining pods cannot be done before this time,
+    /// estimated from the pods done so far
+    pub deadline: Option<SystemTime>,
+    /// once cancelled (e.g. on SIGTERM), the rollout stops before the next pod,
+    /// the pods in progress are finished and the progress is kept for `resume`
+    pub interrupt: Option<CancellationToken>,
+    /// asked whether to continue after the replicas of the statefulset changed,
+    /// without it the rollout stops
+    pub(crate) confirm: Option<Confirm>,
+    /// cancelled once the upgrade lock is lost, the rollout stops before the next pod,
+    /// see `UpgradeLock::lost`
+    pub(crate) lock_lost: Option<CancellationToken>,
+}
+
+impl Default for ClusterUpgradeOptions {
+    fn default() -> Self {
+        Self {
+            pod: UpgradeOptions::default(),
+            max_unavailable: 1,
+            max_raft_lag: Some(0),
+            raft_catch_up_timeout: Duration::from_secs(300),
+            snapshot_before: None,
+            resume: false,
+            force_unlock: false,
+            canary: 0,
+            bake_time: Duration::from_secs(600),
+            allow_downgrade: false,
+            ack_template_changes: false,
+            deadline: None,
+            interrupt: None,
+            confirm: None,
+            lock_lost: None,
+        }
+    }
+}
+
+impl From<UpgradeOptions> for ClusterUpgradeOptions {
+    fn from(pod: UpgradeOptions) -> Self {
+        Self {
+            pod,
+            ..Default::default()
+        }
+    }
+}
+
+impl ClusterUpgradeOptions {
+    /// Set how to upgrade or restart each pod
+    pub fn pod(mut self, pod: UpgradeOptions) -> Self {
+        self.pod = pod;
+        self
+    }
+
+    /// Upgrade up to this many standby pods at the same time, limited by the raft quorum
+    pub fn max_unavailable(mut self, max_unavailable: usize) -> Self {
+        self.max_unavailable = max_unavailable;
+        self
+    }
+
+    /// Wait for upgraded standby pods to catch up with the leader up to this many raft entries,
+    /// `None` skips the wait
+    pub fn max_raft_lag(mut self, max_raft_lag: Option<u64>) -> Self {
+        self.max_raft_lag = max_raft_lag;
+        self
+    }
+
+    /// Set the time to wait for a standby pod to catch up with the leader
+    pub fn raft_catch_up_timeout(mut self, timeout: Duration) -> Self {
+        self.raft_catch_up_timeout = timeout;
+        self
+    }
+
+    /// Store a raft snapshot of the active pod before any pod is upgraded,
+    /// the upgrade stops if the snapshot cannot be taken or stored
+    pub fn snapshot_before(mut self, destination: Option<SnapshotDestination>) -> Self {
+        self.snapshot_before = destination;
+        self
+    }
+
+    /// Continue an interrupted rollout, skipping the pods it already finished
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// Take over the upgrade lock of the statefulset, e.g. after a crashed upgrade
+    pub fn force_unlock(mut self, force_unlock: bool) -> Self {
+        self.force_unlock = force_unlock;
+        self
+    }
+
+    /// Upgrade this many standby pods first and only continue if they stay healthy for `bake_time`
+    pub fn canary(mut self, canary: usize) -> Self {
+        self.canary = canary;
+        self
+    }
+
+    /// Set the time the canary pods have to stay unsealed, ready and healthy
+    pub fn bake_time(mut self, bake_time: Duration) -> Self {
+        self.bake_time = bake_time;
+        self
     }
 
-    /// Upgrade a vault pod
+    /// Upgrade even if the target version is older than the version of a pod
+    pub fn allow_downgrade(mut self, allow_downgrade: bool) -> Self {
+        self.allow_downgrade = allow_downgrade;
+        self
+    }
+
+    /// Recreate outdated pods even if it also applies template changes besides the version,
+    /// e.g. a new env or resources
+    pub fn ack_template_changes(mut self, ack_template_changes: bool) -> Self {
+        self.ack_template_changes = ack_template_changes;
+        self
+    }
+
+    /// Stop before the next pod if the rollout cannot finish before the deadline,
+    /// the progress is kept so the rollout can be resumed
+    pub fn deadline(mut self, deadline: Option<SystemTime>) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Stop before the next pod once the token is cancelled
+    pub fn interrupt(mut self, interrupt: CancellationToken) -> Self {
+        self.interrupt = Some(interrupt);
+        self
+    }
+
+    /// Ask whether to continue a rollout after the replicas of the statefulset changed
     ///
-    ///  - a.1. if Pod version is outdated
-    ///     - a.1.1. Delete pod
-    ///     - a.1.2. Wait for pod to be deleted
-    ///     - a.1.3. Wait for pod to be running
-    ///  - a.2. if Pod version is current
-    ///     - a.2.1. Pod is sealed
-    ///         - a.2.1.1 Unseal pod
-    ///     - a.2.2. Wait for pod to be unsealed
-    ///     - a.2.3. Wait for pod to be ready
-    pub async fn upgrade(
+    /// The question is asked on a blocking thread, so it may wait for input.
+    pub fn confirm(mut self, confirm: impl Fn(&str) -> bool + Send + Sync + 'static) -> Self {
+        self.confirm = Some(Arc::new(confirm));
+        self
+    }
+}
+
+/// Interval between the log lines of a pod catching up with the raft log
+const CATCH_UP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time without applied raft entries after which a catching up pod is reported as stalled
+const CATCH_UP_STALL_WARNING: Duration = Duration::from_secs(30);
+
+/// Progress of a pod applying the raft log, to tell catching up from being stuck
+#[derive(Clone, Debug)]
+struct CatchUpProgress {
+    started: Instant,
+    initial: u64,
+    applied: u64,
+    committed: u64,
+    changed: Instant,
+}
+
+impl CatchUpProgress {
+    fn new(now: Instant, applied: u64, committed: u64) -> Self {
+        Self {
+            started: now,
+            initial: applied,
+            applied,
+            committed,
+            changed: now,
+        }
+    }
+
+    fn update(&mut self, now: Instant, applied: u64) {
+        if applied != self.applied {
+            self.applied = applied;
+            self.changed = now;
+        }
+    }
+
+    /// Time since the applied index last changed
+    fn stalled_for(&self, now: Instant) -> Duration {
+        now - self.changed
+    }
+
+    /// Applied entries, percentage, rate and estimated time left
+    fn describe(&self, now: Instant) -> String {
+        let percent = match self.committed {
+            0 => 100,
+            committed => self.applied.min(committed) * 100 / committed,
+        };
+        let mut description = format!(
+            "applied {} of {} ({}%)",
+            self.applied, self.committed, percent
+        );
+
+        let elapsed = (now - self.started).as_secs_f64();
+        let rate = self.applied.saturating_sub(self.initial) as f64 / elapsed;
+        if elapsed > 0.0 && rate > 0.0 {
+            let left = self.committed.saturating_sub(self.applied) as f64 / rate;
+            description.push_str(&format!(
+                ", {:.0} entries/s, about {} left",
+                rate,
+                humantime::format_duration(Duration::from_secs(left.ceil() as u64))
+            ));
+        }
+
+        description
+    }
+}
+
+/// Interval between the reports while waiting for an external unseal
+const EXTERNAL_UNSEAL_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval between the health checks of the canary pods
+const CANARY_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval between the evictions of a pod blocked by a PodDisruptionBudget
+const EVICTION_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A recreated pod cannot pull its image, so the rollout cannot continue
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImagePullFailed {
+    pub pod: String,
+    pub image: String,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ImagePullFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "pod {} cannot pull image {}: {}",
+            self.pod, self.image, self.reason
+        )
+    }
+}
+
+impl std::error::Error for ImagePullFailed {}
+
+/// The target version is older than the version of a pod, see `ClusterUpgradeOptions::allow_downgrade`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DowngradeRefused {
+    pub pod: String,
+    pub current: VaultVersion,
+    pub target: VaultVersion,
+}
+
+impl std::fmt::Display for DowngradeRefused {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "refusing to downgrade pod {} from {} to {}",
+            self.pod, self.current.version, self.target.version
+        )
+    }
+}
+
+impl std::error::Error for DowngradeRefused {}
+
+/// Recreating the outdated pods would also apply template changes besides the version,
+/// see `ClusterUpgradeOptions::ack_template_changes`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TemplateChangesNotAcknowledged {
+    pub changes: Vec<TemplateChange>,
+}
+
+impl std::fmt::Display for TemplateChangesNotAcknowledged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "recreating the pods would also apply changes of the pod template besides the version: {}",
+            self.changes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
+
+impl std::error::Error for TemplateChangesNotAcknowledged {}
+
+/// The retrieved keys cannot unseal the pods again after they are recreated,
+/// see `check_key_threshold`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyThresholdNotMet {
+    pub keys: usize,
+    pub threshold: u8,
+}
+
+impl std::fmt::Display for KeyThresholdNotMet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} distinct unseal keys were retrieved, but {} are needed to unseal the pods",
+            self.keys, self.threshold
+        )
+    }
+}
+
+impl std::error::Error for KeyThresholdNotMet {}
+
+/// Raft peers speak an older protocol than the target version requires,
+/// see `check_raft_protocol`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RaftProtocolIncompatible {
+    pub target: VaultVersion,
+    /// first version requiring the protocol
+    pub since: SemVer,
+    pub required: u64,
+    /// node ids and protocols of the peers speaking an older protocol
+    pub servers: Vec<(String, String)>,
+}
+
+impl std::fmt::Display for RaftProtocolIncompatible {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} needs raft protocol {} on all peers, but {} speak an older protocol, upgrade to a version before {} first",
+            self.target.version,
+            self.required,
+            self.servers
+                .iter()
+                .map(|(node, protocol)| format!("{} ({})", node, protocol))
+                .collect::<Vec<_>>()
+                .join(", "),
+            self.since
+        )
+    }
+}
+
+impl std::error::Error for RaftProtocolIncompatible {}
+
+/// The rollout was stopped because the remaining pods could not be done before the deadline,
+/// see `ClusterUpgradeOptions::deadline`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadlineExceeded {
+    pub deadline: SystemTime,
+    /// pods done by this rollout
+    pub done: usize,
+    /// pods not started yet
+    pub remaining: usize,
+    /// estimated time the remaining pods need
+    pub estimate: Duration,
+}
+
+impl std::fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "stopping before the deadline {}: {} pods done, the remaining {} pods need about {}, continue with --resume",
+            humantime::format_rfc3339_seconds(self.deadline),
+            self.done,
+            self.remaining,
+            humantime::format_duration(Duration::from_secs(self.estimate.as_secs()))
+        )
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+/// The rollout was stopped before the next pod because it was interrupted,
+/// see `ClusterUpgradeOptions::interrupt`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeInterrupted {
+    /// pods done by this rollout
+    pub done: usize,
+    /// pods not started yet
+    pub remaining: usize,
+}
+
+impl std::fmt::Display for UpgradeInterrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "interrupted: {} pods done, {} pods remaining, continue with --resume",
+            self.done, self.remaining
+        )
+    }
+}
+
+impl std::error::Error for UpgradeInterrupted {}
+
+/// The rollout was stopped before the next pod because another process took over
+/// the upgrade lock or it expired, see `UpgradeLock`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpgradeLockLost {
+    /// pods done by this rollout
+    pub done: usize,
+    /// pods not started yet
+    pub remaining: usize,
+}
+
+impl std::fmt::Display for UpgradeLockLost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "lost the upgrade lock: {} pods done, {} pods remaining",
+            self.done, self.remaining
+        )
+    }
+}
+
+impl std::error::Error for UpgradeLockLost {}
+
+/// Measures how long the pods of a rollout take, to estimate when it finishes
+/// and to stop before the deadline
+struct RolloutClock {
+    deadline: Option<SystemTime>,
+    interrupt: Option<CancellationToken>,
+    lock_lost: Option<CancellationToken>,
+    report: Option<UpgradeReporter>,
+    started: Instant,
+    done: usize,
+    remaining: usize,
+}
+
+impl RolloutClock {
+    fn new(options: &ClusterUpgradeOptions, pods: usize) -> Self {
+        Self {
+            deadline: options.deadline,
+            interrupt: options.interrupt.clone(),
+            lock_lost: options.lock_lost.clone(),
+            report: options.pod.report.clone(),
+            started: Instant::now(),
+            done: 0,
+            remaining: pods,
+        }
+    }
+
+    /// Estimated time the remaining pods need, based on the average of the pods done so far
+    fn estimate(&self) -> Option<Duration> {
+        if self.done == 0 {
+            return None;
+        }
+
+        Some(self.started.elapsed() / self.done as u32 * self.remaining as u32)
+    }
+
+    /// Called before the next pod starts, fails if the upgrade lock was lost,
+    /// the rollout was interrupted or the remaining pods would miss the deadline
+    fn start_pod(&mut self) -> anyhow::Result<()> {
+        if self.lock_lost.as_ref().is_some_and(|l| l.is_cancelled()) {
+            return Err(UpgradeLockLost {
+                done: self.done,
+                remaining: self.remaining,
+            }
+            .into());
+        }
+
+        if self.interrupt.as_ref().is_some_and(|i| i.is_cancelled()) {
+            return Err(UpgradeInterrupted {
+                done: self.done,
+                remaining: self.remaining,
+            }
+            .into());
+        }
+
+        if let Some(deadline) = self.deadline {
+            let estimate = self.estimate().unwrap_or_default();
+            if SystemTime::now() + estimate > deadline {
+                return Err(DeadlineExceeded {
+                    deadline,
+                    done: self.done,
+                    remaining: self.remaining,
+                    estimate,
+                }
+                .into());
+            }
+        }
+
+        self.remaining = self.remaining.saturating_sub(1);
+        Ok(())
+    }
+
+    /// Called after a pod finished, logs and reports the estimate for the remaining pods
+    fn finish_pod(&mut self) {
+        self.done += 1;
+
+        let Some(estimate) = self.estimate() else {
+            return;
+        };
+        if let Some(reporter) = &self.report {
+            reporter.estimate(self.remaining, estimate);
+        }
+        if self.remaining > 0 {
+            info!(
+                "{} pods done, the remaining {} pods need about {} (until {})",
+                self.done,
+                self.remaining,
+                humantime::format_duration(Duration::from_secs(estimate.as_secs())),
+                humantime::format_rfc3339_seconds(SystemTime::now() + estimate)
+            );
+        }
+    }
+}
+
+/// Fail if the wait for `what` does not finish within the timeout
+async fn within<T>(
+    timeout: Duration,
+    what: impl std::fmt::Display,
+    wait: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(timeout, wait).await.map_err(|_| {
+        anyhow::anyhow!(
+            "{} did not finish within {}",
+            what,
+            humantime::format_duration(timeout)
+        )
+    })?
+}
+
+/// Run a step of the pod, recording its duration in the report of the options
+async fn timed<T>(
+    options: &UpgradeOptions,
+    name: &str,
+    step: PodStep,
+    run: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    let action = step.to_string();
+    if let Some(journal) = &options.journal {
+        journal.intend(&action, Some(name), None)?;
+    }
+
+    let started = Instant::now();
+    let result = run.await;
+
+    if let Some(reporter) = &options.report {
+        reporter.step(name, step, started.elapsed());
+    }
+    finish_journal(options, &action, Some(name), &result);
+
+    result
+}
+
+/// Record the result of the action in the journal, failing to write it is only logged
+fn finish_journal<T>(
+    options: &UpgradeOptions,
+    action: &str,
+    pod: Option<&str>,
+    result: &anyhow::Result<T>,
+) {
+    if let Some(journal) = &options.journal {
+        if let Err(e) = journal.finish(action, pod, result) {
+            warn!("writing journal: {}", e);
+        }
+    }
+}
+
+/// Operations on the cluster used by the upgrade state machine
+///
+/// `PodApi` implements this for a real cluster, the simulation (feature `test-util`)
+/// implements it for scripted cluster behaviors.
+#[async_trait::async_trait]
+pub trait UpgradeDriver {
+    /// List the vault pods with the given role
+    async fn list_pods(&self, role: ExecIn) -> anyhow::Result<Vec<Pod>>;
+
+    /// Get the current state of a pod
+    async fn get_pod(&self, name: &str) -> anyhow::Result<Pod>;
+
+    /// Get the seal status the pod reports
+    async fn seal_status(&self, name: &str) -> anyhow::Result<PodSealStatus>;
+
+    /// Step down the pod from active to standby
+    async fn step_down(&self, name: &str, token: Secret<String>) -> anyhow::Result<()>;
+
+    /// Wait for another pod to take over from the pod
+    async fn await_standby(&self, name: &str) -> anyhow::Result<()>;
+
+    /// Check if the pod itself reports to be the leader, regardless of its labels
+    async fn is_leader(&self, name: &str) -> anyhow::Result<bool>;
+
+    /// Wait for clients to be routed away from the pod after another pod took over
+    async fn await_active_service(&self, _name: &str) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// How to wait for another pod to take over after a step-down
+    fn takeover_settings(&self) -> Takeover {
+        Takeover::default()
+    }
+
+    /// How to upgrade or restart the active pod
+    fn active_strategy_setting(&self) -> ActiveStrategy {
+        ActiveStrategy::default()
+    }
+
+    /// Name of the vault container in the pods, detected if `None`, see `find_vault_container`
+    fn vault_container(&self) -> Option<&str> {
+        None
+    }
+
+    /// Count the raft voters (queried on the given pod)
+    async fn raft_voters(&self, name: &str, token: Secret<String>) -> anyhow::Result<usize>;
+
+    /// Servers of the raft configuration (queried on the given pod), empty if it is unknown
+    async fn raft_servers(
         &self,
-        pod: Pod,
-        target: &VaultVersion,
-        token: Secret<String>,
-        should_unseal: bool,
+        _name: &str,
+        _token: Secret<String>,
+    ) -> anyhow::Result<Vec<RaftConfigurationServer>> {
+        Ok(vec![])
+    }
+
+    /// Take a raft snapshot, the pod has to be active
+    async fn raft_snapshot(&self, name: &str, token: Secret<String>) -> anyhow::Result<Bytes>;
+
+    /// Raft index committed by the pod, if it reports it
+    async fn raft_committed_index(&self, _name: &str) -> anyhow::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Wait for the pod to apply the raft log up to `max_lag` entries before `committed`
+    async fn await_raft_caught_up(
+        &self,
+        _name: &str,
+        _committed: u64,
+        _max_lag: u64,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Current number of replicas of the statefulset, if it is known
+    async fn replicas(&self) -> anyhow::Result<Option<i32>> {
+        Ok(None)
+    }
+
+    /// Read the progress of an interrupted rollout, `None` if there is none
+    async fn load_progress(&self) -> anyhow::Result<Option<UpgradeProgress>> {
+        Ok(None)
+    }
+
+    /// Persist the progress of the rollout, `None` once it finished
+    async fn save_progress(&self, _progress: Option<&UpgradeProgress>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Wait for autopilot (queried on the given pod) to report a healthy cluster
+    async fn await_autopilot_healthy(
+        &self,
+        name: &str,
+        token: Secret<String>,
+    ) -> anyhow::Result<()>;
+
+    /// Delete the pod and wait for it to be gone, it is recreated by the statefulset
+    async fn delete_pod(&self, name: &str) -> anyhow::Result<()>;
+
+    /// Pods are recreated by lowering the partition of the statefulset instead of deleting
+    /// them, so they have to be upgraded from the highest ordinal down
+    fn partitioned(&self) -> bool {
+        false
+    }
+
+    /// Wait for the pod to be running and exporting its seal status
+    async fn await_running(&self, name: &str) -> anyhow::Result<()>;
+
+    /// Unseal the pod with the given keys
+    async fn unseal(&self, name: &str, keys: &[Secret<String>]) -> anyhow::Result<()>;
+
+    /// Wait for the pod to be unsealed
+    async fn await_unsealed(&self, name: &str) -> anyhow::Result<()>;
+
+    /// Wait for the pod to be ready
+    async fn await_ready(&self, pod: &Pod) -> anyhow::Result<()>;
+
+    /// Names of the servers autopilot reports as unhealthy, asking the given pod
+    async fn unhealthy_raft_servers(
+        &self,
+        _name: &str,
+        _token: Secret<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// Accessor of the token, looked up once so the requests made with the token can be
+    /// attributed to it, see `token_accessor`
+    async fn token_accessor(&self, _token: Secret<String>) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// Accessor of the token, if `token_accessor` looked it up before
+    fn known_token_accessor(&self) -> Option<String> {
+        None
+    }
+
+    /// Record the upgrade of the pod, e.g. in annotations of the pod and the statefulset
+    async fn record_history(&self, _name: &str, _history: &UpgradeHistory) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Publish what the upgrade did, failures must not stop the upgrade
+    async fn publish_event(&self, _event: &UpgradeEvent) {}
+}
+
+#[async_trait::async_trait]
+impl UpgradeDriver for PodApi {
+    async fn list_pods(&self, role: ExecIn) -> anyhow::Result<Vec<Pod>> {
+        Ok(self
+            .api
+            .list(&self.selector.clone().role(role).to_list_params())
+            .await?
+            .items)
+    }
+
+    async fn get_pod(&self, name: &str) -> anyhow::Result<Pod> {
+        Ok(self.api.get(name).await?)
+    }
+
+    async fn seal_status(&self, name: &str) -> anyhow::Result<PodSealStatus> {
+        self.http(name, VAULT_PORT).await?.seal_status().await
+    }
+
+    async fn step_down(&self, name: &str, token: Secret<String>) -> anyhow::Result<()> {
+        self.http(name, VAULT_PORT).await?.step_down(token).await
+    }
+
+    async fn token_accessor(&self, token: Secret<String>) -> anyhow::Result<Option<String>> {
+        if let Some(accessor) = PodApi::known_token_accessor(self) {
+            return Ok(Some(accessor));
+        }
+
+        let active = self.list_pods(ExecIn::Active).await?;
+        let name = active
+            .first()
+            .and_then(|p| p.metadata.name.as_ref())
+            .ok_or(anyhow::anyhow!("no active pod to look up the token"))?;
+
+        let mut http = self.http(name, VAULT_PORT).await?;
+        let accessor = token_accessor(&mut http, token).await?;
+        PodApi::token_accessor(self, accessor.clone());
+
+        Ok(Some(accessor))
+    }
+
+    fn known_token_accessor(&self) -> Option<String> {
+        PodApi::known_token_accessor(self)
+    }
+
+    async fn is_leader(&self, name: &str) -> anyhow::Result<bool> {
+        Ok(self.http(name, VAULT_PORT).await?.leader().await?.is_self)
+    }
+
+    async fn await_standby(&self, name: &str) -> anyhow::Result<()> {
+        match self.takeover.condition {
+            TakeoverCondition::Label => {
+                await_condition(self.api.clone(), name, is_pod_standby(), &self.tuning).await?;
+            }
+            TakeoverCondition::Leader => loop {
+                let leader = async { self.http(name, VAULT_PORT).await?.leader().await }.await;
+
+                match leader {
+                    Ok(leader) if !leader.is_self && !leader.leader_address.is_empty() => break,
+                    Ok(_) => {}
+                    Err(e) => debug!("getting leader of pod {}: {}", name, e),
+                }
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            },
+            TakeoverCondition::Delay => tokio::time::sleep(self.takeover.delay).await,
+        }
+
+        Ok(())
+    }
+
+    async fn await_active_service(&self, name: &str) -> anyhow::Result<()> {
+        let Some(service) = &self.active_service else {
+            return Ok(());
+        };
+
+        let namespace = self
+            .api
+            .get(name)
+            .await?
+            .metadata
+            .namespace
+            .ok_or(anyhow::anyhow!("pod {} does not have a namespace", name))?;
+        let endpoints: Api<Endpoints> = Api::namespaced(self.api.clone().into_client(), &namespace);
+
+        info!(
+            "waiting for service {} to move away from pod {}",
+            service, name
+        );
+        await_condition(
+            endpoints,
+            service,
+            is_endpoints_moved_from(name.to_string()),
+            &self.tuning,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    fn takeover_settings(&self) -> Takeover {
+        self.takeover
+    }
+
+    fn active_strategy_setting(&self) -> ActiveStrategy {
+        self.active_strategy
+    }
+
+    fn vault_container(&self) -> Option<&str> {
+        self.selector.container.as_deref()
+    }
+
+    fn partitioned(&self) -> bool {
+        self.partition
+    }
+
+    async fn replicas(&self) -> anyhow::Result<Option<i32>> {
+        match &self.statefulset {
+            Some((api, name)) => Ok(Some(
+                api.get(name)
+                    .await?
+                    .spec
+                    .and_then(|s| s.replicas)
+                    .unwrap_or(1),
+            )),
+            None => Ok(None),
+        }
+    }
+
+    async fn load_progress(&self) -> anyhow::Result<Option<UpgradeProgress>> {
+        self.read_progress().await
+    }
+
+    async fn save_progress(&self, progress: Option<&UpgradeProgress>) -> anyhow::Result<()> {
+        self.write_progress(progress).await
+    }
+
+    async fn record_history(&self, name: &str, history: &UpgradeHistory) -> anyhow::Result<()> {
+        self.write_history(name, history).await
+    }
+
+    async fn publish_event(&self, event: &UpgradeEvent) {
+        self.record_event(event).await
+    }
+
+    async fn raft_committed_index(&self, name: &str) -> anyhow::Result<Option<u64>> {
+        Ok(self
+            .http(name, VAULT_PORT)
+            .await?
+            .seal_status()
+            .await?
+            .raft_committed_index)
+    }
+
+    async fn await_raft_caught_up(
+        &self,
+        name: &str,
+        committed: u64,
+        max_lag: u64,
+    ) -> anyhow::Result<()> {
+        let caught_up = is_seal_status_caught_up(committed, max_lag);
+        let mut pf = self.http(name, VAULT_PORT).await?;
+
+        let mut progress: Option<CatchUpProgress> = None;
+        let mut reported: Option<Instant> = None;
+
+        loop {
+            let status = pf.seal_status().await?;
+            if caught_up.matches_object(Some(&status)) {
+                if progress.is_some() {
+                    info!("pod {} caught up with raft index {}", name, committed);
+                }
+                return Ok(());
+            }
+
+            let now = Instant::now();
+            let applied = status.raft_applied_index.unwrap_or_default();
+            let progress =
+                progress.get_or_insert_with(|| CatchUpProgress::new(now, applied, committed));
+            progress.update(now, applied);
+
+            if reported.is_none_or(|r| now - r >= CATCH_UP_REPORT_INTERVAL) {
+                reported = Some(now);
+
+                let stalled = progress.stalled_for(now);
+                if stalled >= CATCH_UP_STALL_WARNING {
+                    warn!(
+                        "pod {} did not apply any raft entries for {} ({}), it is installing a raft snapshot or stuck",
+                        name,
+                        humantime::format_duration(Duration::from_secs(stalled.as_secs())),
+                        progress.describe(now)
+                    );
+                } else {
+                    info!("pod {} catching up: {}", name, progress.describe(now));
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    async fn raft_voters(&self, name: &str, token: Secret<String>) -> anyhow::Result<usize> {
+        let config = self
+            .http(name, VAULT_PORT)
+            .await?
+            .raft_configuration(token)
+            .await?;
+
+        Ok(config
+            .data
+            .config
+            .servers
+            .iter()
+            .filter(|s| s.voter)
+            .count())
+    }
+
+    async fn raft_servers(
+        &self,
+        name: &str,
+        token: Secret<String>,
+    ) -> anyhow::Result<Vec<RaftConfigurationServer>> {
+        let config = self
+            .http(name, VAULT_PORT)
+            .await?
+            .raft_configuration(token)
+            .await?;
+
+        Ok(config.data.config.servers)
+    }
+
+    async fn raft_snapshot(&self, name: &str, token: Secret<String>) -> anyhow::Result<Bytes> {
+        self.http(name, VAULT_PORT)
+            .await?
+            .raft_snapshot(token)
+            .await
+    }
+
+    async fn await_autopilot_healthy(
+        &self,
+        name: &str,
+        token: Secret<String>,
+    ) -> anyhow::Result<()> {
+        loop {
+            let state = async {
+                self.http(name, VAULT_PORT)
+                    .await?
+                    .autopilot_state(token.clone())
+                    .await
+            }
+            .await;
+
+            match state {
+                Ok(state) if state.data.healthy => return Ok(()),
+                Ok(state) => info!(
+                    "waiting for autopilot to report a healthy cluster, unhealthy servers: {}",
+                    state
+                        .data
+                        .servers
+                        .values()
+                        .filter(|s| !s.healthy)
+                        .map(|s| s.name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                Err(e) => debug!("getting autopilot state from pod {}: {}", name, e),
+            }
+
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+    }
+
+    async fn unhealthy_raft_servers(
+        &self,
+        name: &str,
+        token: Secret<String>,
+    ) -> anyhow::Result<Vec<String>> {
+        let state = self
+            .http(name, VAULT_PORT)
+            .await?
+            .autopilot_state(token)
+            .await?;
+
+        Ok(state
+            .data
+            .servers
+            .values()
+            .filter(|s| !s.healthy)
+            .map(|s| s.name.clone())
+            .collect())
+    }
+
+    async fn delete_pod(&self, name: &str) -> anyhow::Result<()> {
+        if self.partition {
+            return self.lower_partition(name).await;
+        }
+        if self.use_eviction {
+            return self.evict_pod(name).await;
+        }
+
+        kube::runtime::wait::delete::delete_and_finalize(
+            self.api.clone(),
+            name,
+            &DeleteParams::default(),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("deleting pod {}: {}", name, e.to_string()))
+    }
+
+    async fn await_running(&self, name: &str) -> anyhow::Result<()> {
+        // Wait for pod to be running, failing early if the image cannot be pulled
+        let pod = await_condition(
+            self.api.clone(),
+            name,
+            is_pod_running().or(is_pod_failing_image_pull()),
+            &self.tuning,
+        )
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!("waiting for pod {} to be running: {}", name, e.to_string())
+        })?;
+
+        if let Some((image, reason)) = pod.as_ref().and_then(image_pull_failure) {
+            return Err(ImagePullFailed {
+                pod: name.to_string(),
+                image,
+                reason,
+            }
+            .into());
+        }
+
+        // Wait for pod to export its seal status
+        await_condition(
+            self.api.clone(),
+            name,
+            is_pod_exporting_seal_status(),
+            &self.tuning,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn unseal(&self, name: &str, keys: &[Secret<String>]) -> anyhow::Result<()> {
+        let mut pf = Retry::spawn(
+            ExponentialBackoff::from_millis(50).map(jitter).take(5),
+            || async move { self.http(name, VAULT_PORT).await },
+        )
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "attempting to forward http requests to {}: {}",
+                name,
+                e.to_string()
+            )
+        })?;
+
+        // Wait for pod to have determined its seal status
+        pf.await_seal_status(is_seal_status_initialized())
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "waiting for pod to have required seal status {}: {}",
+                    name,
+                    e.to_string()
+                )
+            })?;
+
+        // Unseal pod
+        pf.unseal(keys)
+            .await
+            .map_err(|e| anyhow::anyhow!("unsealing pod {}: {}", name, e.to_string()))
+    }
+
+    async fn await_unsealed(&self, name: &str) -> anyhow::Result<()> {
+        await_condition(self.api.clone(), name, is_pod_unsealed(), &self.tuning).await?;
+
+        Ok(())
+    }
+
+    async fn await_ready(&self, pod: &Pod) -> anyhow::Result<()> {
+        let name = pod
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+        match Mesh::detect(pod) {
+            Some(mesh) if !self.waits_for_sidecars() => {
+                info!(
+                    "pod {} has a {} sidecar, only waiting for the vault container to be ready",
+                    name, mesh
+                );
+                await_condition(
+                    self.api.clone(),
+                    name,
+                    is_pod_container_ready(vault_container_name(
+                        pod,
+                        self.selector.container.as_deref(),
+                    )?),
+                    &self.tuning,
+                )
+                .await?;
+            }
+            _ => {
+                await_condition(self.api.clone(), name, is_pod_ready(), &self.tuning).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl PodApi {
+    /// Lower the partition of the statefulset to the ordinal of the pod and wait until
+    /// the statefulset controller deleted it, it is recreated from the update revision
+    async fn lower_partition(&self, name: &str) -> anyhow::Result<()> {
+        let (stss, statefulset) = self.statefulset.as_ref().ok_or(anyhow::anyhow!(
+            "partitioned rollouts need the statefulset of pod {}",
+            name
+        ))?;
+        let ordinal = pod_ordinal(name)?;
+        let uid = self
+            .api
+            .get(name)
+            .await?
+            .metadata
+            .uid
+            .ok_or(anyhow::anyhow!("pod {} does not have a uid", name))?;
+
+        info!(
+            "lowering partition of statefulset {} to {} for pod {}",
+            statefulset, ordinal, name
+        );
+        stss.patch(
+            statefulset,
+            &PatchParams::apply(FIELD_MANAGER).force(),
+            &Patch::Apply(partition_patch(statefulset, ordinal)),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("setting partition of statefulset {}: {}", statefulset, e))?;
+
+        await_condition(self.api.clone(), name, is_deleted(&uid), &self.tuning)
+            .await
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "waiting for pod {} to be replaced by the statefulset: {}",
+                    name,
+                    e
+                )
+            })?;
+
+        Ok(())
+    }
+
+    /// Evict the pod and wait until it is gone
+    ///
+    /// An eviction blocked by a PodDisruptionBudget is retried until it is allowed,
+    /// the caller bounds the time with the delete timeout.
+    async fn evict_pod(&self, name: &str) -> anyhow::Result<()> {
+        let uid = self
+            .api
+            .get(name)
+            .await?
+            .metadata
+            .uid
+            .ok_or(anyhow::anyhow!("pod {} does not have a uid", name))?;
+
+        loop {
+            match self.api.evict(name, &EvictParams::default()).await {
+                Ok(_) => break,
+                Err(kube::Error::Api(e)) if e.code == 429 => {
+                    info!(
+                        "eviction of pod {} is not allowed yet, retrying: {}",
+                        name, e.message
+                    );
+                    tokio::time::sleep(EVICTION_RETRY_INTERVAL).await;
+                }
+                Err(e) => anyhow::bail!("evicting pod {}: {}", name, e),
+            }
+        }
+
+        await_condition(self.api.clone(), name, is_deleted(&uid), &self.tuning)
+            .await
+            .map_err(|e| anyhow::anyhow!("waiting for pod {} to be evicted: {}", name, e))?;
+
+        Ok(())
+    }
+
+    /// Check if the vault pod has the specified version, detecting the vault container
+    pub fn is_current(pod: &Pod, target: &VaultVersion) -> anyhow::Result<bool> {
+        let pod_version = VaultVersion::try_from(pod)?;
+        Ok(&pod_version == target)
+    }
+
+    /// Upgrade a vault pod, see `upgrade_pod`
+    pub async fn upgrade_with(
+        &self,
+        pod: Pod,
+        target: &VaultVersion,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        options: &UpgradeOptions,
+    ) -> anyhow::Result<()> {
+        if options.force_upgrade || !is_current(self, &pod, target)? {
+            check_key_threshold(self, &pod, keys, options).await?;
+        }
+        upgrade_pod(self, pod, target, token, keys, options).await
+    }
+
+    /// Restart a vault pod regardless of its version, see `restart_pod`
+    pub async fn restart_with(
+        &self,
+        pod: Pod,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        options: &UpgradeOptions,
+    ) -> anyhow::Result<()> {
+        check_key_threshold(self, &pod, keys, options).await?;
+        restart_pod(self, pod, token, keys, options).await
+    }
+
+    /// Upgrade a vault pod, see `upgrade_pod`
+    #[deprecated = "use `upgrade_with` and `UpgradeOptions` instead"]
+    pub async fn upgrade(
+        &self,
+        pod: Pod,
+        target: &VaultVersion,
+        token: Secret<String>,
+        should_unseal: bool,
+        force_upgrade: bool,
+        keys: &[Secret<String>],
+    ) -> anyhow::Result<()> {
+        let options = UpgradeOptions::default()
+            .should_unseal(should_unseal)
+            .force_upgrade(force_upgrade);
+
+        self.upgrade_with(pod, target, token, keys, &options).await
+    }
+
+    /// Restart a vault pod regardless of its version, see `restart_pod`
+    #[deprecated = "use `restart_with` and `UpgradeOptions` instead"]
+    pub async fn restart(
+        &self,
+        pod: Pod,
+        token: Secret<String>,
+        should_unseal: bool,
+        keys: &[Secret<String>],
+    ) -> anyhow::Result<()> {
+        let options = UpgradeOptions::default().should_unseal(should_unseal);
+
+        self.restart_with(pod, token, keys, &options).await
+    }
+}
+
+/// Upgrade a vault pod
+///
+///  - a.1. if Pod version is outdated
+///     - a.1.0. Step down pod if it is still active
+///     - a.1.1. Delete pod
+///     - a.1.2. Wait for pod to be deleted
+///     - a.1.3. Wait for pod to be running
+///  - a.2. if Pod version is current
+///     - a.2.1. Pod is sealed
+///         - a.2.1.1 Unseal pod
+///     - a.2.2. Wait for pod to be unsealed
+///     - a.2.3. Wait for pod to be ready
+pub async fn upgrade_pod(
+    driver: &(impl UpgradeDriver + Sync),
+    pod: Pod,
+    target: &VaultVersion,
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let name = pod
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    // if Pod version is outdated (or upgrade is forced)
+    let recreated = !is_current(driver, &pod, target)? || options.force_upgrade;
+    let previous = VaultVersion::of_pod(&pod, driver.vault_container())?;
+    let history =
+        UpgradeHistory::now(&previous.version).token_accessor(driver.known_token_accessor());
+    options.report_pod(name, |report| {
+        report.previous_version = Some(previous.version);
+        report.skipped = !recreated;
+    });
+    if recreated {
+        recreate(driver, &pod, token, options).await?;
+    }
+
+    await_running(driver, name, options).await?;
+
+    // Refresh pod
+    let pod = driver.get_pod(name).await?;
+
+    if is_current(driver, &pod, target)? {
+        unseal_and_await_ready(driver, &pod, keys, options).await?;
+
+        if recreated {
+            // the pod is upgraded even if the annotations cannot be written
+            if let Err(e) = driver.record_history(name, &history).await {
+                warn!("recording upgrade history of pod {}: {}", name, e);
+            }
+
+            run_hook(
+                "post-pod",
+                options.post_pod_hook.as_ref(),
+                PodStep::PostPodHook,
+                pod,
+                options,
+            )
+            .await?;
+
+            await_gates(driver, name, options).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restart a vault pod regardless of its version
+///
+///  - Step down pod if it is still active
+///  - Delete pod
+///  - Wait for pod to be running
+///  - Pod is sealed
+///     - Unseal pod
+///  - Wait for pod to be unsealed
+///  - Wait for pod to be ready
+pub async fn restart_pod(
+    driver: &(impl UpgradeDriver + Sync),
+    pod: Pod,
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let name = pod
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    let previous = VaultVersion::of_pod(&pod, driver.vault_container())?;
+    options.report_pod(name, |report| {
+        report.previous_version = Some(previous.version)
+    });
+    recreate(driver, &pod, token, options).await?;
+
+    await_running(driver, name, options).await?;
+
+    // Refresh pod
+    let pod = driver.get_pod(name).await?;
+
+    unseal_and_await_ready(driver, &pod, keys, options).await?;
+
+    run_hook(
+        "post-pod",
+        options.post_pod_hook.as_ref(),
+        PodStep::PostPodHook,
+        pod,
+        options,
+    )
+    .await?;
+
+    await_gates(driver, name, options).await
+}
+
+/// Time between the checks of gates that did not pass yet
+const GATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Wait until all gates pass for the pod
+async fn await_gates(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    if options.gates.is_empty() {
+        return Ok(());
+    }
+
+    info!("waiting for the health gates of pod {}", name);
+    timed(
+        options,
+        name,
+        PodStep::HealthGates,
+        within(
+            options.gate_timeout,
+            format!("waiting for the health gates of pod {}", name),
+            poll_gates(driver, name, &options.gates),
+        ),
+    )
+    .await
+}
+
+/// Check the gates for the pod until all of them pass
+async fn poll_gates(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    gates: &[HealthGate],
+) -> anyhow::Result<()> {
+    loop {
+        let pod = driver.get_pod(name).await?;
+        let status = driver.seal_status(name).await?;
+
+        let mut pending = None;
+        for gate in gates {
+            if !gate.check(pod.clone(), status.clone()).await? {
+                pending = Some(gate.name());
+                break;
+            }
+        }
+
+        match pending {
+            None => return Ok(()),
+            Some(gate) => debug!("health gate {} did not pass for pod {}", gate, name),
+        }
+        tokio::time::sleep(GATE_INTERVAL).await;
+    }
+}
+
+/// Run the leader hook and wait for the leader gates with the pod that took over from the
+/// pod that stepped down, waiting for it to be labeled as active first
+///
+/// Some deployments route clients through load balancers outside of kubernetes,
+/// which have to be pointed at the new leader.
+pub async fn after_leader_change(
+    driver: &(impl UpgradeDriver + Sync),
+    stepped_down: &str,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    if options.leader_hook.is_none() && options.leader_gates.is_empty() {
+        return Ok(());
+    }
+
+    let leader = within(
+        options.gate_timeout,
+        format!("waiting for a pod to take over from pod {}", stepped_down),
+        async {
+            loop {
+                let active = driver.list_pods(ExecIn::Active).await?;
+                if let Some(pod) = active
+                    .into_iter()
+                    .find(|p| p.metadata.name.as_deref() != Some(stepped_down))
+                {
+                    return Ok(pod);
+                }
+                tokio::time::sleep(GATE_INTERVAL).await;
+            }
+        },
+    )
+    .await?;
+    let name = leader
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    if let Some(hook) = &options.leader_hook {
+        info!("running the leader hook for pod {}", name);
+        within(
+            options.hook_timeout,
+            format!("leader hook for pod {}", name),
+            hook.run(leader),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("leader hook for pod {}: {}", name, e))?;
+    }
+
+    if !options.leader_gates.is_empty() {
+        info!("waiting for the leader gates of pod {}", name);
+        within(
+            options.gate_timeout,
+            format!("waiting for the leader gates of pod {}", name),
+            poll_gates(driver, &name, &options.leader_gates),
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Wait for the recreated pod to be running and record its new version
+async fn await_running(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    timed(
+        options,
+        name,
+        PodStep::Running,
+        within(
+            options.running_timeout,
+            format!("waiting for pod {} to be running", name),
+            driver.await_running(name),
+        ),
+    )
+    .await?;
+
+    if options.report.is_some() {
+        let version = VaultVersion::of_pod(&driver.get_pod(name).await?, driver.vault_container())?;
+        options.report_pod(name, |report| report.new_version = Some(version.version));
+    }
+
+    Ok(())
+}
+
+/// Run the hook for the pod if one is set, recording its duration as `step`
+async fn run_hook(
+    kind: &str,
+    hook: Option<&PodHook>,
+    step: PodStep,
+    pod: Pod,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let Some(hook) = hook else {
+        return Ok(());
+    };
+
+    let name = pod
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    info!("running {} hook for pod {}", kind, name);
+    let run = within(
+        options.hook_timeout,
+        format!("{} hook for pod {}", kind, name),
+        hook.run(pod),
+    );
+    timed(options, &name, step, run)
+        .await
+        .map_err(|e| e.context(format!("{} hook failed for pod {}", kind, name)))
+}
+
+/// The partition of the RollingUpdate strategy keeps all pods from being replaced
+fn is_partition_held(sts: &StatefulSet) -> bool {
+    let Some(spec) = sts.spec.as_ref() else {
+        return false;
+    };
+
+    spec.update_strategy
+        .as_ref()
+        .and_then(|s| s.rolling_update.as_ref())
+        .and_then(|r| r.partition)
+        .is_some_and(|partition| partition >= spec.replicas.unwrap_or(1))
+}
+
+/// Ordinal of a pod of a statefulset, the number after the last `-` of its name
+pub fn pod_ordinal(name: &str) -> anyhow::Result<i32> {
+    name.rsplit_once('-')
+        .and_then(|(_, ordinal)| ordinal.parse().ok())
+        .ok_or(anyhow::anyhow!(
+            "pod {} does not end with the ordinal of a statefulset",
+            name
+        ))
+}
+
+/// Server-side apply patch setting the partition of the RollingUpdate strategy
+fn partition_patch(statefulset: &str, partition: i32) -> serde_json::Value {
+    serde_json::json!({
+        "apiVersion": "apps/v1",
+        "kind": "StatefulSet",
+        "metadata": {
+            "name": statefulset,
+        },
+        "spec": {
+            "updateStrategy": {
+                "type": "RollingUpdate",
+                "rollingUpdate": {
+                    "partition": partition,
+                },
+            },
+        },
+    })
+}
+
+/// Step down the pod if it is active and delete it, so it gets recreated by the statefulset
+async fn recreate(
+    driver: &(impl UpgradeDriver + Sync),
+    pod: &Pod,
+    token: Secret<String>,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let name = pod
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    run_hook(
+        "pre-pod",
+        options.pre_pod_hook.as_ref(),
+        PodStep::PrePodHook,
+        pod.clone(),
+        options,
+    )
+    .await?;
+
+    // if Pod is active
+    if is_active(pod)? {
+        // the role might have changed since the pods were listed
+        if !is_active(&driver.get_pod(name).await?)? {
+            info!("pod {} is no longer active, skipping step-down", name);
+            return delete(driver, name, options).await;
+        }
+
+        match driver.active_strategy_setting() {
+            ActiveStrategy::StepDownFirst => {
+                timed(
+                    options,
+                    name,
+                    PodStep::StepDown,
+                    step_down_and_await_takeover(driver, name, token, options),
+                )
+                .await?
+            }
+            ActiveStrategy::DeleteDirectly => {
+                info!("deleting active pod {} without stepping down", name)
+            }
+            ActiveStrategy::Autopilot => {
+                timed(options, name, PodStep::StepDown, async {
+                    let timeout = driver.takeover_settings().timeout;
+                    tokio::time::timeout(
+                        timeout,
+                        driver.await_autopilot_healthy(name, token.clone()),
+                    )
+                    .await
+                    .map_err(|_| {
+                        anyhow::anyhow!(
+                            "autopilot did not report a healthy cluster within {}",
+                            humantime::format_duration(timeout)
+                        )
+                    })??;
+
+                    step_down_and_await_takeover(driver, name, token, options).await
+                })
+                .await?
+            }
+        }
+    }
+
+    // Delete pod
+    delete(driver, name, options).await
+}
+
+/// Delete the pod and wait for it to be gone
+async fn delete(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    timed(
+        options,
+        name,
+        PodStep::Delete,
+        within(
+            options.delete_timeout,
+            format!("deleting pod {}", name),
+            driver.delete_pod(name),
+        ),
+    )
+    .await?;
+
+    driver
+        .publish_event(&UpgradeEvent::PodDeleted {
+            pod: name.to_string(),
+        })
+        .await;
+
+    Ok(())
+}
+
+/// Step down the pod and wait for another pod to take over,
+/// stepping down again if leadership did not move in time
+///
+/// A pod is only stepped down again while it still reports to be the leader, because
+/// standby pods forward the step-down to the pod that just took over.
+async fn step_down_and_await_takeover(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    token: Secret<String>,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let takeover = driver.takeover_settings();
+    // an unhealthy pod that won the election is stepped down as well
+    let mut stepping_down = name.to_string();
+    let mut step_downs = 0;
+
+    for attempt in 0..=takeover.retries {
+        let still_leader = match attempt {
+            0 => true,
+            _ => match driver.is_leader(&stepping_down).await {
+                Ok(is_leader) => is_leader,
+                Err(e) => {
+                    warn!(
+                        "checking if pod {} is still the leader: {}",
+                        stepping_down, e
+                    );
+                    false
+                }
+            },
+        };
+
+        if still_leader {
+            if attempt > 0 && stepping_down == name {
+                warn!(
+                    "leadership did not move away from pod {}, stepping down again ({}/{})",
+                    name, attempt, takeover.retries
+                );
+            }
+
+            // Step down active pod
+            within(
+                options.stepdown_timeout,
+                format!("stepping down pod {}", stepping_down),
+                driver.step_down(&stepping_down, token.clone()),
+            )
+            .await?;
+            step_downs += 1;
+        } else {
+            info!(
+                "pod {} is no longer the leader, waiting for it to become standby again ({}/{})",
+                stepping_down, attempt, takeover.retries
+            );
+        }
+
+        // Wait for other pod to take over
+        match tokio::time::timeout(takeover.timeout, driver.await_standby(&stepping_down)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("waiting for pod {} to become standby: {}", stepping_down, e);
+                continue;
+            }
+            Err(_) => {
+                warn!(
+                    "pod {} did not become standby within {}",
+                    stepping_down,
+                    humantime::format_duration(takeover.timeout)
+                );
+                continue;
+            }
+        }
+
+        // the pod might win the election again after an unhealthy leader stepped down
+        if stepping_down != name && is_active(&driver.get_pod(name).await?).unwrap_or(false) {
+            stepping_down = name.to_string();
+            continue;
+        }
+
+        if let Some((leader, reason)) = unhealthy_leader(driver, name, &takeover).await {
+            warn!(
+                "pod {} took over from pod {}, but {}, stepping it down",
+                leader, name, reason
+            );
+            stepping_down = leader;
+            continue;
+        }
+
+        tokio::time::timeout(takeover.timeout, driver.await_active_service(name))
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "active service did not move away from pod {} within {}",
+                    name,
+                    humantime::format_duration(takeover.timeout)
+                )
+            })??;
+
+        after_leader_change(driver, name, options).await?;
+
+        driver
+            .publish_event(&UpgradeEvent::SteppedDown {
+                pod: name.to_string(),
+            })
+            .await;
+
+        return Ok(());
+    }
+
+    anyhow::bail!(
+        "pod {} never became standby after {} step-downs ({} condition)",
+        name,
+        step_downs,
+        takeover.condition
+    )
+}
+
+/// Why the pod should not stay the leader, `None` if it is unsealed, its vault container is
+/// ready and it applied the raft log it committed up to `max_lag` entries
+///
+/// The vault container is the one named `container`, or detected if it is not set.
+pub fn leader_unhealthy_reason(
+    pod: &Pod,
+    container: Option<&str>,
+    status: &PodSealStatus,
+    max_lag: u64,
+) -> Option<String> {
+    if status.sealed {
+        return Some("it is sealed".to_string());
+    }
+
+    let ready = vault_container_name(pod, container)
+        .is_ok_and(|container| is_pod_container_ready(container).matches_object(Some(pod)));
+    if !ready {
+        return Some("it is not ready".to_string());
+    }
+
+    match (status.raft_committed_index, status.raft_applied_index) {
+        (Some(committed), Some(applied)) if committed.saturating_sub(applied) > max_lag => Some(
+            format!("it lags {} raft entries behind", committed - applied),
+        ),
+        _ => None,
+    }
+}
+
+/// The leader that took over from the pod and why it is unhealthy, `None` if it is healthy
+/// or cannot be checked
+async fn unhealthy_leader(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    takeover: &Takeover,
+) -> Option<(String, String)> {
+    if !takeover.healthy_leader {
+        return None;
+    }
+
+    let check = async {
+        let active = driver.list_pods(ExecIn::Active).await?;
+        let Some(leader) = active
+            .iter()
+            .find(|p| p.metadata.name.as_deref() != Some(name))
+        else {
+            return anyhow::Ok(None);
+        };
+        let leader_name = leader
+            .metadata
+            .name
+            .clone()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+        let status = driver.seal_status(&leader_name).await?;
+        Ok(leader_unhealthy_reason(
+            leader,
+            driver.vault_container(),
+            &status,
+            takeover.max_leader_lag,
+        )
+        .map(|reason| (leader_name, reason)))
+    };
+
+    match check.await {
+        Ok(unhealthy) => unhealthy,
+        Err(e) => {
+            warn!("checking the health of the new leader: {}", e);
+            None
+        }
+    }
+}
+
+/// Unseal the pod if it is sealed and wait for it to be ready
+async fn unseal_and_await_ready(
+    driver: &(impl UpgradeDriver + Sync),
+    pod: &Pod,
+    keys: &[Secret<String>],
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let name = pod
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    let method = match (options.should_unseal, is_sealed(pod)?) {
+        (true, true) => UnsealMethod::Keys,
+        (true, false) => UnsealMethod::AlreadyUnsealed,
+        (false, _) => UnsealMethod::External,
+    };
+    options.report_pod(name, |report| report.unseal = Some(method));
+
+    timed(options, name, PodStep::Unseal, async {
+        if options.should_unseal {
+            // Pod is sealed
+            if method == UnsealMethod::Keys {
+                within(
+                    options.unseal_timeout,
+                    format!("unsealing pod {}", name),
+                    driver.unseal(name, keys),
+                )
+                .await?;
+            }
+            // Wait for pod to be unsealed
+            within(
+                options.unseal_timeout,
+                format!("waiting for pod {} to be unsealed", name),
+                driver.await_unsealed(name),
+            )
+            .await?;
+
+            if method == UnsealMethod::Keys {
+                driver
+                    .publish_event(&UpgradeEvent::Unsealed {
+                        pod: name.to_string(),
+                    })
+                    .await;
+            }
+
+            Ok(())
+        } else {
+            await_external_unseal(driver, name, options).await
+        }
+    })
+    .await?;
+
+    // Wait for pod to be ready
+    timed(
+        options,
+        name,
+        PodStep::Ready,
+        within(
+            options.pod_ready_timeout,
+            format!("waiting for pod {} to be ready", name),
+            driver.await_ready(pod),
+        ),
+    )
+    .await
+}
+
+/// Wait for the pod to be unsealed by someone else, reporting periodically what we are waiting for
+async fn await_external_unseal(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let timeout = options
+        .external_unseal_timeout
+        .unwrap_or(options.unseal_timeout);
+    let started = Instant::now();
+
+    let sealed = |pod: &Pod| {
+        registration_label(pod, "sealed")
+            .unwrap_or("missing")
+            .to_string()
+    };
+
+    let pod = driver.get_pod(name).await?;
+    if !is_sealed(&pod).unwrap_or(true) {
+        return Ok(());
+    }
+
+    info!(
+        "waiting up to {} for pod {} to be unsealed externally (sealed label: {})",
+        humantime::format_duration(timeout),
+        name,
+        sealed(&pod)
+    );
+
+    let wait = driver.await_unsealed(name);
+    tokio::pin!(wait);
+    let mut report = tokio::time::interval_at(
+        (started + EXTERNAL_UNSEAL_REPORT_INTERVAL).into(),
+        EXTERNAL_UNSEAL_REPORT_INTERVAL,
+    );
+
+    let what = format!("waiting for pod {} to be unsealed externally", name);
+
+    within(timeout, &what, async {
+        loop {
+            tokio::select! {
+                result = &mut wait => return result.map_err(|e| e.context(what.clone())),
+                _ = report.tick() => {
+                    let waited = Duration::from_secs(started.elapsed().as_secs());
+                    let state = match driver.get_pod(name).await {
+                        Ok(pod) => sealed(&pod),
+                        Err(e) => format!("unknown, {}", e),
+                    };
+                    info!(
+                        "pod {} is still sealed after {}, waiting for its sealed label to become false (currently: {})",
+                        name,
+                        humantime::format_duration(waited),
+                        state
+                    );
+                }
+            }
+        }
+    })
+    .await
+}
+
+/// What happens to a pod during an upgrade
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlannedAction {
+    /// pod is outdated (or the upgrade is forced) and will be deleted
+    Upgrade,
+    /// pod is active and outdated, it will be stepped down before being deleted
+    StepDownAndUpgrade,
+    /// pod already has the target version
+    Skip,
+}
+
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlannedAction::Upgrade => "delete".fmt(f),
+            PlannedAction::StepDownAndUpgrade => "step down, delete".fmt(f),
+            PlannedAction::Skip => "skip".fmt(f),
+        }
+    }
+}
+
+/// Planned upgrade of a single pod
+#[derive(Clone, Debug)]
+pub struct PlannedPod {
+    pub name: String,
+    pub active: bool,
+    pub current: VaultVersion,
+    pub action: PlannedAction,
+    /// the pod will be unsealed by vault-mgmt, otherwise an external unseal is awaited
+    pub unseal: bool,
+}
+
+/// Ordered list of the pods that would be touched by an upgrade
+#[derive(Clone, Debug)]
+pub struct UpgradePlan {
+    pub target: VaultVersion,
+    pub pods: Vec<PlannedPod>,
+}
+
+impl StatefulSetApi {
+    /// Upgrade a vault cluster
+    ///
+    /// - Acquire the upgrade lock of the statefulset, see `UpgradeLock`
+    /// - Verify that the statefulset is ready to be upgraded or in the process of being upgraded
+    ///     - if statefulset is ready and all pods are ready, initialized and unsealed
+    ///         - start upgrade process
+    /// - Detect the target version from statefulset
+    /// - Repeat for all standby pods
+    ///     - Do a.1
+    ///     - Do a.2
+    /// - Upgrade active pods
+    ///     - if Pod version is outdated
+    ///         - Step down active pod
+    ///         - Wait for other pod to take over
+    ///     - Do a.1
+    ///     - Do a.2
+    ///
+    /// - a.1. if Pod version is outdated
+    ///     - a.1.1. Delete pod
+    ///     - a.1.2. Wait for pod to be deleted
+    ///     - a.1.3. Wait for pod to be running
+    /// - a.2. if Pod version is current
+    ///     - a.2.1. Pod is sealed
+    ///         - a.2.1.1 Unseal pod
+    ///     - a.2.2. Wait for pod to be unsealed
+    ///     - a.2.3. Wait for pod to be ready
+    pub async fn upgrade_with(
+        &self,
+        sts: StatefulSet,
+        pods: &PodApi,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        options: &ClusterUpgradeOptions,
+    ) -> anyhow::Result<()> {
+        let target = VaultVersion::of_statefulset(&sts, pods.selector.container.as_deref())?;
+
+        let name = sts
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a name"))?;
+        let namespace = sts
+            .metadata
+            .namespace
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a namespace"))?;
+
+        // forced upgrades recreate all pods on purpose
+        if !options.pod.force_upgrade {
+            let listed = pods.api.list(&pods.selector.to_list_params()).await?;
+            check_template_changes(
+                &sts,
+                &listed.items,
+                pods.selector.container.as_deref(),
+                &target,
+                options,
+            )?;
+        }
+
+        let lock = UpgradeLock::acquire(
+            self.api.clone().into_client(),
+            namespace,
+            name,
+            options.force_unlock,
+        )
+        .await?;
+
+        let pods = pods.clone().statefulset(self.api.clone(), name);
+        let options = ClusterUpgradeOptions {
+            lock_lost: Some(lock.lost()),
+            ..options.clone()
+        };
+        let upgraded = rolling_upgrade(&pods, &target, token, keys, &options).await;
+
+        // release the lock even if the upgrade failed, `--resume` continues it
+        let released = lock.release().await;
+        upgraded?;
+        released
+    }
+
+    /// Upgrade a vault cluster, see `upgrade_with`
+    #[deprecated = "use `upgrade_with` and `ClusterUpgradeOptions` instead"]
+    pub async fn upgrade(
+        &self,
+        sts: StatefulSet,
+        pods: &PodApi,
+        token: Secret<String>,
+        should_unseal: bool,
+        force_upgrade: bool,
+        keys: &[Secret<String>],
+    ) -> anyhow::Result<()> {
+        let options = UpgradeOptions::default()
+            .should_unseal(should_unseal)
+            .force_upgrade(force_upgrade);
+
+        self.upgrade_with(sts, pods, token, keys, &options.into())
+            .await
+    }
+
+    /// Revert the image of the statefulset to the one of `previous` after a pod failed to pull
+    /// the new image, then recreate the failed pod with the previous image and unseal it
+    pub async fn revert_image(
+        &self,
+        previous: &StatefulSet,
+        pods: &PodApi,
+        failed: &ImagePullFailed,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        options: &UpgradeOptions,
+    ) -> anyhow::Result<()> {
+        let version = VaultVersion::of_statefulset(previous, self.container.as_deref())?;
+
+        info!(
+            "reverting statefulset to version {} after pod {} failed to pull {}",
+            version.version, failed.pod, failed.image
+        );
+        let sts = self.set_version(previous, &version).await?;
+
+        // the failed pod never got its labels, so it is deleted without a step-down
+        delete(pods, &failed.pod, options).await?;
+        within(
+            options.running_timeout,
+            format!("waiting for pod {} to be running", failed.pod),
+            pods.await_running(&failed.pod),
+        )
+        .await?;
+
+        let pod = pods.get_pod(&failed.pod).await?;
+        let target = VaultVersion::of_statefulset(&sts, self.container.as_deref())?;
+
+        upgrade_pod(pods, pod, &target, token, keys, options).await
+    }
+
+    /// Roll back a failed upgrade, see `roll_back_pods`
+    ///
+    /// The version is taken from the `vault-mgmt.io/previous-version` annotation of the pods
+    /// this process upgraded, or from `previous` if it did not finish any pod.
+    /// Returns the version the statefulset was rolled back to.
+    pub async fn roll_back(
+        &self,
+        previous: &StatefulSet,
+        pods: &PodApi,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        options: &UpgradeOptions,
+    ) -> anyhow::Result<VaultVersion> {
+        let listed = pods.api.list(&pods.selector.to_list_params()).await?;
+        let version = match previous_version_of(&listed.items)? {
+            Some(version) => version,
+            None => VaultVersion::of_statefulset(previous, self.container.as_deref())?,
+        };
+
+        info!("rolling back statefulset to version {}", version.version);
+        self.set_version(previous, &version).await?;
+
+        roll_back_pods(pods, &version, token, keys, options).await?;
+
+        Ok(version)
+    }
+
+    /// Set the image of the vault container in the pod template using server-side apply
+    /// Returns the updated statefulset
+    pub async fn set_image(&self, sts: &StatefulSet, image: &str) -> anyhow::Result<StatefulSet> {
+        let name = sts
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a name"))?;
+
+        let containers = &sts
+            .spec
+            .as_ref()
+            .and_then(|s| s.template.spec.as_ref())
+            .ok_or(anyhow::anyhow!("statefulset does not have a pod spec"))?
+            .containers;
+
+        let container = find_vault_container(containers, self.container.as_deref())?;
+
+        let is_on_delete = sts
+            .spec
+            .as_ref()
+            .and_then(|s| s.update_strategy.as_ref())
+            .and_then(|s| s.type_.as_deref())
+            == Some("OnDelete");
+        if !is_on_delete && !is_partition_held(sts) {
+            warn!(
+                "statefulset {} does not use the OnDelete update strategy, pods will be replaced by kubernetes",
+                name
+            );
+        }
+
+        info!(
+            "setting image of container {} in statefulset {} to {}",
+            container.name, name, image
+        );
+
+        let patch = serde_json::json!({
+            "apiVersion": "apps/v1",
+            "kind": "StatefulSet",
+            "metadata": {
+                "name": name,
+            },
+            "spec": {
+                "template": {
+                    "spec": {
+                        "containers": [{
+                            "name": container.name,
+                            "image": image,
+                        }],
+                    },
+                },
+            },
+        });
+
+        Ok(self
+            .api
+            .patch(
+                name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(patch),
+            )
+            .await?)
+    }
+
+    /// Set the partition of the RollingUpdate strategy to the replicas, so changing the pod
+    /// template does not replace any pod until a partitioned rollout lowers it (see
+    /// `PodApi::partition`). Returns the updated statefulset
+    pub async fn hold_partition(&self, sts: &StatefulSet) -> anyhow::Result<StatefulSet> {
+        let name = sts
+            .metadata
+            .name
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a name"))?;
+        let spec = sts
+            .spec
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a spec"))?;
+
+        // kubernetes defaults to RollingUpdate
+        let strategy = spec
+            .update_strategy
+            .as_ref()
+            .and_then(|s| s.type_.as_deref());
+        if strategy.is_some_and(|s| s != "RollingUpdate") {
+            anyhow::bail!(
+                "statefulset {} does not use the RollingUpdate update strategy, partitioned rollouts need it",
+                name
+            );
+        }
+
+        let replicas = spec.replicas.unwrap_or(1);
+        info!("setting partition of statefulset {} to {}", name, replicas);
+
+        Ok(self
+            .api
+            .patch(
+                name,
+                &PatchParams::apply(FIELD_MANAGER).force(),
+                &Patch::Apply(partition_patch(name, replicas)),
+            )
+            .await?)
+    }
+
+    /// Set the version of the vault container image in the pod template using server-side apply
+    /// Returns the updated statefulset
+    pub async fn set_version(
+        &self,
+        sts: &StatefulSet,
+        target: &VaultVersion,
+    ) -> anyhow::Result<StatefulSet> {
+        let containers = &sts
+            .spec
+            .as_ref()
+            .and_then(|s| s.template.spec.as_ref())
+            .ok_or(anyhow::anyhow!("statefulset does not have a pod spec"))?
+            .containers;
+        let image = find_vault_container(containers, self.container.as_deref())?
+            .image
+            .clone()
+            .ok_or(anyhow::anyhow!("statefulset does not have a vault image"))?;
+
+        self.set_image(sts, &image_with_version(&image, target))
+            .await
+    }
+
+    /// Plan an upgrade of a vault cluster without changing anything, see `plan_upgrade`
+    pub async fn plan_with(
+        &self,
+        sts: &StatefulSet,
+        pods: &PodApi,
+        token: Option<Secret<String>>,
+        options: &UpgradeOptions,
+    ) -> anyhow::Result<UpgradePlan> {
+        let target = VaultVersion::of_statefulset(sts, self.container.as_deref())?;
+
+        plan_upgrade(pods, &target, token, options).await
+    }
+
+    /// Plan an upgrade of a vault cluster without changing anything, see `plan_upgrade`
+    #[deprecated = "use `plan_with` and `UpgradeOptions` instead"]
+    pub async fn plan(
+        &self,
+        sts: &StatefulSet,
+        pods: &PodApi,
+        should_unseal: bool,
         force_upgrade: bool,
+    ) -> anyhow::Result<UpgradePlan> {
+        let options = UpgradeOptions::default()
+            .should_unseal(should_unseal)
+            .force_upgrade(force_upgrade);
+
+        self.plan_with(sts, pods, None, &options).await
+    }
+
+    /// Restart all pods of a vault cluster without downtime, see `rolling_restart`
+    ///
+    /// If the pods belong to a statefulset (see `PodApi::statefulset`), the upgrade lock
+    /// of the statefulset is held while restarting, see `UpgradeLock`.
+    pub async fn restart_with(
+        &self,
+        pods: &PodApi,
+        token: Secret<String>,
+        keys: &[Secret<String>],
+        options: &ClusterUpgradeOptions,
+    ) -> anyhow::Result<()> {
+        let Some((stss, name)) = &pods.statefulset else {
+            return rolling_restart(pods, token, keys, options).await;
+        };
+        let sts = stss.get(name).await?;
+        let namespace = sts
+            .metadata
+            .namespace
+            .as_ref()
+            .ok_or(anyhow::anyhow!("statefulset does not have a namespace"))?;
+
+        let lock = UpgradeLock::acquire(
+            stss.clone().into_client(),
+            namespace,
+            name,
+            options.force_unlock,
+        )
+        .await?;
+
+        let options = ClusterUpgradeOptions {
+            lock_lost: Some(lock.lost()),
+            ..options.clone()
+        };
+        let restarted = rolling_restart(pods, token, keys, &options).await;
+
+        // release the lock even if the restart failed, `--resume` continues it
+        let released = lock.release().await;
+        restarted?;
+        released
+    }
+
+    /// Restart all pods of a vault cluster without downtime, see `rolling_restart`
+    #[deprecated = "use `restart_with` and `ClusterUpgradeOptions` instead"]
+    pub async fn restart(
+        &self,
+        pods: &PodApi,
+        token: Secret<String>,
+        should_unseal: bool,
         keys: &[Secret<String>],
     ) -> anyhow::Result<()> {
+        let options = UpgradeOptions::default().should_unseal(should_unseal);
+
+        self.restart_with(pods, token, keys, &options.into()).await
+    }
+}
+
+/// Upgrade all pods to the target version, standby pods first and the active pod last
+///
+/// Standby pods that are no raft voters are upgraded before the voters.
+/// A partitioned driver upgrades the pods from the highest ordinal down instead.
+/// If `options.pod.report` is set, the report is finished when the upgrade returns,
+/// including the error of a failed upgrade.
+pub async fn rolling_upgrade(
+    driver: &(impl UpgradeDriver + Sync),
+    target: &VaultVersion,
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    if let Some(reporter) = &options.pod.report {
+        reporter.start(target);
+    }
+    if let Some(journal) = &options.pod.journal {
+        journal.intend("upgrade", None, Some(format!("target {}", target.version)))?;
+    }
+    attribute_token(driver, token.clone(), options).await;
+    driver
+        .publish_event(&UpgradeEvent::UpgradeStarted {
+            target: target.version.clone(),
+        })
+        .await;
+
+    let upgraded = upgrade_in_rollout_order(driver, target, token, keys, options).await;
+
+    if let Err(e) = &upgraded {
+        driver
+            .publish_event(&UpgradeEvent::UpgradeFailed {
+                error: format!("{:#}", e),
+            })
+            .await;
+    }
+    if let Some(reporter) = &options.pod.report {
+        reporter.finish(&upgraded);
+    }
+    finish_journal(&options.pod, "upgrade", None, &upgraded);
+
+    upgraded
+}
+
+async fn upgrade_in_rollout_order(
+    driver: &(impl UpgradeDriver + Sync),
+    target: &VaultVersion,
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    if driver.partitioned() {
+        return upgrade_in_partition_order(driver, target, token, keys, options).await;
+    }
+
+    let (standby, active) = match pods_in_rollout_order(driver).await? {
+        Some(pods) => pods,
+        None => return Ok(()),
+    };
+    let standby = order_by_raft_role(driver, standby, &active, token.clone()).await?;
+
+    check_upgrade_path(
+        standby.iter().chain(&active),
+        driver.vault_container(),
+        target,
+        options,
+    )?;
+    check_raft_protocol_of(driver, &active[0], target, token.clone()).await?;
+    check_key_threshold(driver, &active[0], keys, &options.pod).await?;
+
+    if let Some(destination) = &options.snapshot_before {
+        snapshot(driver, &active[0], token.clone(), destination).await?;
+    }
+
+    let parallelism = match options.max_unavailable {
+        0 | 1 => 1,
+        max_unavailable => {
+            let name = active[0]
+                .metadata
+                .name
+                .as_ref()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+            let voters = driver.raft_voters(name, token.clone()).await?;
+
+            // the active pod stays up, so as many standby pods as raft tolerates
+            // failures can be down without losing quorum
+            let tolerated = voters.saturating_sub(1) / 2;
+            if tolerated < max_unavailable {
+                warn!(
+                    "limiting parallel upgrades to {} to keep quorum of {} voters",
+                    tolerated.max(1),
+                    voters
+                );
+            }
+            max_unavailable.min(tolerated).max(1)
+        }
+    };
+
+    let mut replicas = driver.replicas().await?;
+    let leader = active[0].metadata.name.clone();
+    let mut progress =
+        start_progress(driver, &format!("upgrade to {}", target.version), options).await?;
+
+    let mut clock = RolloutClock::new(options, left(&progress, &standby, &active));
+    let mut standby = standby.into_iter();
+
+    if options.canary > 0 {
+        let mut canaries = vec![];
+        while canaries.len() < options.canary {
+            let Some(pod) = standby.next() else {
+                break;
+            };
+            let Some(name) = pending(&progress, &pod, &options.pod)? else {
+                continue;
+            };
+            clock.start_pod()?;
+            info!("upgrading canary pod {}", name);
+            ensure_replicas_unchanged(driver, &mut replicas, options).await?;
+            record(driver, &mut progress, &name, UpgradePhase::Started).await?;
+            upgrade_pod(driver, pod, target, token.clone(), keys, &options.pod).await?;
+            await_caught_up(driver, Some(&name), leader.as_deref(), options).await?;
+            record(driver, &mut progress, &name, UpgradePhase::Done).await?;
+            clock.finish_pod();
+            canaries.push(name);
+        }
+
+        bake(driver, &canaries, leader.as_deref(), token.clone(), options).await?;
+    }
+
+    info!("upgrading standby pods");
+    let mut upgrading = FuturesUnordered::new();
+    let mut stopped = None;
+    // the first error stops scheduling pods, the pods in progress are finished first,
+    // so their progress is recorded
+    let mut failed = None;
+    loop {
+        while stopped.is_none() && failed.is_none() && upgrading.len() < parallelism {
+            let Some(pod) = standby.next() else {
+                break;
+            };
+            let name = match pending(&progress, &pod, &options.pod) {
+                Ok(Some(name)) => name,
+                Ok(None) => continue,
+                Err(e) => {
+                    failed = Some(e);
+                    break;
+                }
+            };
+            if let Err(e) = clock.start_pod() {
+                stopped = Some(e);
+                break;
+            }
+            let started = async {
+                ensure_replicas_unchanged(driver, &mut replicas, options).await?;
+                record(driver, &mut progress, &name, UpgradePhase::Started).await
+            };
+            if let Err(e) = started.await {
+                failed = Some(e);
+                break;
+            }
+            let token = token.clone();
+            let leader = leader.as_deref();
+            upgrading.push(async move {
+                upgrade_pod(driver, pod, target, token, keys, &options.pod).await?;
+                await_caught_up(driver, Some(&name), leader, options).await?;
+                anyhow::Ok(name)
+            });
+        }
+
+        let Some(upgraded) = upgrading.next().await else {
+            break;
+        };
+        let done = match upgraded {
+            Ok(name) => record(driver, &mut progress, &name, UpgradePhase::Done).await,
+            Err(e) => Err(e),
+        };
+        match done {
+            Ok(()) => clock.finish_pod(),
+            Err(e) => {
+                failed.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    if let Some(e) = stopped {
+        // counting the pods that finished meanwhile
+        return Err(clock.start_pod().err().unwrap_or(e));
+    }
+
+    info!("upgrading active pods");
+    for pod in active {
+        let Some(name) = pending(&progress, &pod, &options.pod)? else {
+            continue;
+        };
+        clock.start_pod()?;
+        ensure_replicas_unchanged(driver, &mut replicas, options).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Started).await?;
+        upgrade_pod(driver, pod, target, token.clone(), keys, &options.pod).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Done).await?;
+        clock.finish_pod();
+    }
+
+    driver.save_progress(None).await
+}
+
+/// Upgrade the pods one at a time from the highest ordinal down, as the statefulset
+/// controller replaces the pods from the highest ordinal down to the partition
+///
+/// The active pod is stepped down when its ordinal is reached, so leadership may move
+/// more than once. Canary pods and parallel upgrades are not supported.
+async fn upgrade_in_partition_order(
+    driver: &(impl UpgradeDriver + Sync),
+    target: &VaultVersion,
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    if options.pod.force_upgrade {
+        anyhow::bail!(
+            "the statefulset does not replace pods of the update revision, partitioned rollouts cannot force upgrades"
+        );
+    }
+    if options.max_unavailable > 1 || options.canary > 0 {
+        warn!("partitioned rollouts upgrade one pod at a time, ignoring max unavailable and canary pods");
+    }
+
+    let (standby, active) = match pods_in_rollout_order(driver).await? {
+        Some(pods) => pods,
+        None => return Ok(()),
+    };
+
+    check_upgrade_path(
+        standby.iter().chain(&active),
+        driver.vault_container(),
+        target,
+        options,
+    )?;
+    check_raft_protocol_of(driver, &active[0], target, token.clone()).await?;
+    check_key_threshold(driver, &active[0], keys, &options.pod).await?;
+
+    if let Some(destination) = &options.snapshot_before {
+        snapshot(driver, &active[0], token.clone(), destination).await?;
+    }
+
+    let mut pods = standby
+        .iter()
+        .chain(&active)
+        .map(|pod| {
+            let name = pod
+                .metadata
+                .name
+                .as_ref()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+            Ok((pod_ordinal(name)?, pod.clone()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    pods.sort_by_key(|(ordinal, _)| std::cmp::Reverse(*ordinal));
+
+    let mut replicas = driver.replicas().await?;
+    let mut progress =
+        start_progress(driver, &format!("upgrade to {}", target.version), options).await?;
+    let mut clock = RolloutClock::new(options, left(&progress, &standby, &active));
+
+    info!("upgrading pods by lowering the partition");
+    for (_, pod) in pods {
+        let Some(name) = pending(&progress, &pod, &options.pod)? else {
+            continue;
+        };
+        clock.start_pod()?;
+        ensure_replicas_unchanged(driver, &mut replicas, options).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Started).await?;
+        // the role changes with every step-down, so the listed pod may be stale
+        let pod = driver.get_pod(&name).await?;
+        upgrade_pod(driver, pod, target, token.clone(), keys, &options.pod).await?;
+
+        // leadership moves when the active pod is reached
+        let leader = driver
+            .list_pods(ExecIn::Active)
+            .await?
+            .into_iter()
+            .find_map(|p| p.metadata.name);
+        if leader.as_ref() != Some(&name) {
+            await_caught_up(driver, Some(&name), leader.as_deref(), options).await?;
+        }
+
+        record(driver, &mut progress, &name, UpgradePhase::Done).await?;
+        clock.finish_pod();
+    }
+
+    driver.save_progress(None).await
+}
+
+/// Roll back the pods of a failed rollout to the previous version
+///
+/// The pods are taken from the progress of the rollout, standby pods first and the active pod
+/// last. The statefulset has to recreate pods with the previous version already.
+pub async fn roll_back_pods(
+    driver: &(impl UpgradeDriver + Sync),
+    previous: &VaultVersion,
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    let Some(progress) = driver.load_progress().await? else {
+        warn!("no progress of the failed rollout found, nothing to roll back");
+        return Ok(());
+    };
+
+    let mut pods = vec![];
+    for name in progress.pods.keys() {
+        let pod = driver.get_pod(name).await?;
+        if !is_current(driver, &pod, previous)? {
+            pods.push(pod);
+        }
+    }
+    pods.sort_by_key(|p| is_active(p).unwrap_or(false));
+
+    for pod in pods {
+        let name = pod
+            .metadata
+            .name
+            .clone()
+            .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+        info!("rolling back pod {} to {}", name, previous.version);
+
+        let pod = if is_active(&pod).is_err() {
+            // the pod never got its labels, so it is deleted without a step-down
+            delete(driver, &name, options).await?;
+            within(
+                options.running_timeout,
+                format!("waiting for pod {} to be running", name),
+                driver.await_running(&name),
+            )
+            .await?;
+            driver.get_pod(&name).await?
+        } else {
+            pod
+        };
+
+        upgrade_pod(driver, pod, previous, token.clone(), keys, options).await?;
+    }
+
+    driver.save_progress(None).await
+}
+
+/// Plan an upgrade of a vault cluster without changing anything
+///
+/// The pods are returned in the order `rolling_upgrade` would process them.
+/// Without a token the raft configuration cannot be read, so the standby pods are in the
+/// order of their labels, which may differ from the rollout.
+/// If the cluster cannot be upgraded without downtime, the plan does not contain any pods.
+pub async fn plan_upgrade(
+    driver: &(impl UpgradeDriver + Sync),
+    target: &VaultVersion,
+    token: Option<Secret<String>>,
+    options: &UpgradeOptions,
+) -> anyhow::Result<UpgradePlan> {
+    let (standby, active) = match pods_in_rollout_order(driver).await? {
+        Some(pods) => pods,
+        None => {
+            return Ok(UpgradePlan {
+                target: target.clone(),
+                pods: vec![],
+            })
+        }
+    };
+    let standby = match token {
+        Some(token) => order_by_raft_role(driver, standby, &active, token).await?,
+        None => standby,
+    };
+
+    let pods = standby
+        .iter()
+        .map(|p| (p, false))
+        .chain(active.iter().map(|p| (p, true)))
+        .map(|(pod, active)| {
+            let name = pod
+                .metadata
+                .name
+                .clone()
+                .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+            let action = match (
+                is_current(driver, pod, target)? && !options.force_upgrade,
+                active,
+            ) {
+                (true, _) => PlannedAction::Skip,
+                (false, true) => PlannedAction::StepDownAndUpgrade,
+                (false, false) => PlannedAction::Upgrade,
+            };
+
+            Ok(PlannedPod {
+                name,
+                active,
+                current: VaultVersion::of_pod(pod, driver.vault_container())?,
+                action,
+                unseal: options.should_unseal,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(UpgradePlan {
+        target: target.clone(),
+        pods,
+    })
+}
+
+/// Restart all pods of a vault cluster without downtime
+///
+/// Uses the same order as `rolling_upgrade` (standby pods first, the active pod last),
+/// but restarts every pod regardless of its version.
+pub async fn rolling_restart(
+    driver: &(impl UpgradeDriver + Sync),
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    if let Some(journal) = &options.pod.journal {
+        journal.intend("restart", None, None)?;
+    }
+
+    let restarted = restart_in_rollout_order(driver, token, keys, options).await;
+    finish_journal(&options.pod, "restart", None, &restarted);
+
+    restarted
+}
+
+async fn restart_in_rollout_order(
+    driver: &(impl UpgradeDriver + Sync),
+    token: Secret<String>,
+    keys: &[Secret<String>],
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    if driver.partitioned() {
+        anyhow::bail!(
+            "the statefulset does not replace pods of the update revision, partitioned rollouts cannot restart pods"
+        );
+    }
+
+    let (standby, active) = match pods_in_rollout_order(driver).await? {
+        Some(pods) => pods,
+        None => return Ok(()),
+    };
+    let standby = order_by_raft_role(driver, standby, &active, token.clone()).await?;
+    attribute_token(driver, token.clone(), options).await;
+    check_key_threshold(driver, &active[0], keys, &options.pod).await?;
+
+    let mut replicas = driver.replicas().await?;
+
+    let leader = active[0].metadata.name.clone();
+    let mut progress = start_progress(driver, "restart", options).await?;
+    let mut clock = RolloutClock::new(options, left(&progress, &standby, &active));
+
+    info!("restarting standby pods");
+    for pod in standby {
+        let Some(name) = pending(&progress, &pod, &options.pod)? else {
+            continue;
+        };
+        clock.start_pod()?;
+        ensure_replicas_unchanged(driver, &mut replicas, options).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Started).await?;
+        restart_pod(driver, pod, token.clone(), keys, &options.pod).await?;
+        await_caught_up(driver, Some(&name), leader.as_deref(), options).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Done).await?;
+        clock.finish_pod();
+    }
+
+    info!("restarting active pods");
+    for pod in active {
+        let Some(name) = pending(&progress, &pod, &options.pod)? else {
+            continue;
+        };
+        clock.start_pod()?;
+        ensure_replicas_unchanged(driver, &mut replicas, options).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Started).await?;
+        restart_pod(driver, pod, token.clone(), keys, &options.pod).await?;
+        record(driver, &mut progress, &name, UpgradePhase::Done).await?;
+        clock.finish_pod();
+    }
+
+    driver.save_progress(None).await
+}
+
+/// Refuse to delete pods if the keys cannot unseal them afterwards, i.e. there are fewer
+/// distinct keys than the threshold `t` the pod reports in its seal status
+///
+/// Nothing is checked if vault-mgmt does not unseal the pods or the cluster uses auto-unseal.
+pub async fn check_key_threshold(
+    driver: &(impl UpgradeDriver + Sync),
+    pod: &Pod,
+    keys: &[Secret<String>],
+    options: &UpgradeOptions,
+) -> anyhow::Result<()> {
+    if !options.should_unseal {
+        return Ok(());
+    }
+
+    let name = pod
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+    let status = driver.seal_status(name).await?;
+    if status.is_auto_unseal() {
+        return Ok(());
+    }
+
+    let distinct: HashSet<_> = keys.iter().map(|k| k.expose_secret()).collect();
+    if distinct.len() < status.t as usize {
+        return Err(KeyThresholdNotMet {
+            keys: distinct.len(),
+            threshold: status.t,
+        }
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Refuse to upgrade if raft peers speak an older protocol than the target version requires,
+/// they would not be able to talk to the upgraded pods. Warn if the peers speak different protocols.
+///
+/// Protocols that are no number are ignored with a warning.
+pub fn check_raft_protocol(
+    servers: &[RaftConfigurationServer],
+    target: &VaultVersion,
+) -> anyhow::Result<()> {
+    let mut protocols = vec![];
+    for server in servers {
+        match server.protocol_version.parse::<u64>() {
+            Ok(protocol) => protocols.push((server, protocol)),
+            Err(_) => warn!(
+                "raft server {} reports protocol {}, which is no number",
+                server.node_id, server.protocol_version
+            ),
+        }
+    }
+
+    if protocols.iter().any(|(_, p)| *p != protocols[0].1) {
+        warn!(
+            "raft peers speak different protocols: {}",
+            protocols
+                .iter()
+                .map(|(server, protocol)| format!("{} ({})", server.node_id, protocol))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    let Some((since, required)) = target.required_raft_protocol() else {
+        return Ok(());
+    };
+    let older: Vec<_> = protocols
+        .iter()
+        .filter(|(_, protocol)| *protocol < required)
+        .map(|(server, _)| (server.node_id.clone(), server.protocol_version.clone()))
+        .collect();
+
+    match older.is_empty() {
+        true => Ok(()),
+        false => Err(RaftProtocolIncompatible {
+            target: target.clone(),
+            since,
+            required,
+            servers: older,
+        }
+        .into()),
+    }
+}
+
+/// Read the raft configuration from the active pod and check the protocols of the peers,
+/// see `check_raft_protocol`. Nothing is checked if the configuration cannot be read.
+async fn check_raft_protocol_of(
+    driver: &(impl UpgradeDriver + Sync),
+    active: &Pod,
+    target: &VaultVersion,
+    token: Secret<String>,
+) -> anyhow::Result<()> {
+    let name = active
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    match driver.raft_servers(name, token).await {
+        Ok(servers) => check_raft_protocol(&servers, target),
+        Err(e) => {
+            warn!(
+                "reading raft configuration, not checking the raft protocol: {}",
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Refuse to recreate outdated pods whose template also changed besides the version,
+/// unless the changes are acknowledged
+///
+/// The vault container is the one named `container`, or detected if it is not set.
+pub fn check_template_changes(
+    sts: &StatefulSet,
+    pods: &[Pod],
+    container: Option<&str>,
+    target: &VaultVersion,
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    let mut changes = vec![];
+    for pod in pods {
+        if VaultVersion::of_pod(pod, container)? != *target {
+            changes.extend(template_changes(sts, pod, container)?);
+        }
+    }
+
+    match (changes.is_empty(), options.ack_template_changes) {
+        (true, _) => Ok(()),
+        (false, true) => {
+            for change in &changes {
+                warn!("recreating the pod also applies the changed {}", change);
+            }
+            Ok(())
+        }
+        (false, false) => Err(TemplateChangesNotAcknowledged { changes }.into()),
+    }
+}
+
+/// Check if the vault container of the pod has the specified version
+fn is_current(
+    driver: &impl UpgradeDriver,
+    pod: &Pod,
+    target: &VaultVersion,
+) -> anyhow::Result<bool> {
+    Ok(&VaultVersion::of_pod(pod, driver.vault_container())? == target)
+}
+
+/// Refuse to downgrade the pods unless allowed and warn about skipped minor versions
+fn check_upgrade_path<'a>(
+    pods: impl Iterator<Item = &'a Pod>,
+    container: Option<&str>,
+    target: &VaultVersion,
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    let mut skipped = 0;
+
+    for pod in pods {
         let name = pod
             .metadata
             .name
             .as_ref()
             .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+        let current = VaultVersion::of_pod(pod, container)?;
 
-        // if Pod version is outdated (or upgrade is forced)
-        if !Self::is_current(&pod, target)? || force_upgrade {
-            // if Pod is active
-            if is_active(&pod)? {
-                // Step down active pod
-                self.http(name, VAULT_PORT).await?.step_down(token).await?;
-
-                // Wait for other pod to take over
-                kube::runtime::wait::await_condition(self.api.clone(), name, is_pod_standby())
-                    .await?;
+        if current.is_downgrade_to(target) {
+            if !options.allow_downgrade {
+                return Err(DowngradeRefused {
+                    pod: name.clone(),
+                    current,
+                    target: target.clone(),
+                }
+                .into());
             }
+            warn!(
+                "downgrading pod {} from {} to {}",
+                name, current.version, target.version
+            );
+        }
 
-            // Delete pod
-            kube::runtime::wait::delete::delete_and_finalize(
-                self.api.clone(),
+        if current.edition() != target.edition() {
+            warn!(
+                "changing pod {} from edition {} to {}{}",
                 name,
-                &DeleteParams::default(),
-            )
-            .await
-            .map_err(|e| anyhow::anyhow!("deleting pod {}: {}", name, e.to_string()))?;
+                current.edition(),
+                target.edition(),
+                match target.edition().requires_license() && !current.edition().requires_license() {
+                    true => ", it only starts with a license",
+                    false => "",
+                }
+            );
         }
 
-        // Wait for pod to be running
-        kube::runtime::wait::await_condition(self.api.clone(), name, is_pod_running())
-            .await
-            .map_err(|e| {
-                anyhow::anyhow!("waiting for pod {} to be running: {}", name, e.to_string())
-            })?;
+        skipped = skipped.max(current.skipped_minor_versions(target));
+    }
 
-        // Wait for pod to export its seal status
-        kube::runtime::wait::await_condition(
-            self.api.clone(),
-            name,
-            is_pod_exporting_seal_status(),
-        )
+    if skipped > 0 {
+        warn!(
+            "upgrading to {} skips {} minor version(s), check the upgrade notes of each skipped version",
+            target.version, skipped
+        );
+    }
+
+    Ok(())
+}
+
+/// Watch the canary pods for `bake_time`, failing as soon as one of them degrades
+async fn bake(
+    driver: &(impl UpgradeDriver + Sync),
+    canaries: &[String],
+    leader: Option<&str>,
+    token: Secret<String>,
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    if canaries.is_empty() {
+        return Ok(());
+    }
+
+    info!(
+        "watching canary pods {} for {}",
+        canaries.join(", "),
+        humantime::format_duration(options.bake_time)
+    );
+    let deadline = Instant::now() + options.bake_time;
+
+    loop {
+        for name in canaries {
+            check_canary(driver, name, leader, token.clone())
+                .await
+                .map_err(|e| {
+                    e.context(format!(
+                        "canary pod {} degraded during the bake time, stopping the upgrade",
+                        name
+                    ))
+                })?;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            info!("canary pods stayed healthy, upgrading the remaining pods");
+            return Ok(());
+        }
+
+        tokio::time::sleep((deadline - now).min(CANARY_CHECK_INTERVAL)).await;
+    }
+}
+
+/// Check that the canary pod is unsealed and ready and that raft is healthy
+async fn check_canary(
+    driver: &(impl UpgradeDriver + Sync),
+    name: &str,
+    leader: Option<&str>,
+    token: Secret<String>,
+) -> anyhow::Result<()> {
+    let pod = driver.get_pod(name).await?;
+
+    if is_sealed(&pod)? {
+        anyhow::bail!("pod {} is sealed", name);
+    }
+
+    if !is_pod_container_ready(vault_container_name(&pod, driver.vault_container())?)
+        .matches_object(Some(&pod))
+    {
+        anyhow::bail!("pod {} is not ready", name);
+    }
+
+    let unhealthy = driver
+        .unhealthy_raft_servers(leader.unwrap_or(name), token)
         .await?;
+    if !unhealthy.is_empty() {
+        anyhow::bail!(
+            "autopilot reports unhealthy servers: {}",
+            unhealthy.join(", ")
+        );
+    }
 
-        // Refresh pod
-        let pod = self.api.get(name).await?;
+    Ok(())
+}
 
-        if Self::is_current(&pod, target)? {
-            // Pod is sealed
-            if is_sealed(&pod)? {
-                if should_unseal {
-                    let mut pf = Retry::spawn(
-                        ExponentialBackoff::from_millis(50).map(jitter).take(5),
-                        || async move { self.http(name, VAULT_PORT).await },
-                    )
-                    .await
-                    .map_err(|e| {
-                        anyhow::anyhow!(
-                            "attempting to forward http requests to {}: {}",
-                            name,
-                            e.to_string()
-                        )
-                    })?;
-
-                    // Wait for pod to have determined its seal status
-                    pf.await_seal_status(is_seal_status_initialized())
-                        .await
-                        .map_err(|e| {
-                            anyhow::anyhow!(
-                                "waiting for pod to have required seal status {}: {}",
-                                name,
-                                e.to_string()
-                            )
-                        })?;
-
-                    // Unseal pod
-                    pf.unseal(keys).await.map_err(|e| {
-                        anyhow::anyhow!("unsealing pod {}: {}", name, e.to_string())
-                    })?;
-                } else {
-                    info!("pod {} is sealed, waiting for external unseal", name);
-                }
+/// Log and report the accessor of the token, failures do not stop the rollout
+async fn attribute_token(
+    driver: &(impl UpgradeDriver + Sync),
+    token: Secret<String>,
+    options: &ClusterUpgradeOptions,
+) {
+    match driver.token_accessor(token).await {
+        Ok(Some(accessor)) => {
+            info!("rolling out with the token with accessor {}", accessor);
+            if let Some(reporter) = &options.pod.report {
+                reporter.token_accessor(&accessor);
             }
-            // Wait for pod to be unsealed
-            kube::runtime::wait::await_condition(self.api.clone(), name, is_pod_unsealed()).await?;
-            // Wait for pod to be ready
-            kube::runtime::wait::await_condition(self.api.clone(), name, is_pod_ready()).await?;
         }
+        Ok(None) => {}
+        Err(e) => warn!("looking up the accessor of the token: {}", e),
+    }
+}
 
-        Ok(())
+/// Continue the progress of an interrupted rollout if `resume` is set, otherwise start over
+async fn start_progress(
+    driver: &(impl UpgradeDriver + Sync),
+    rollout: &str,
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<UpgradeProgress> {
+    if options.resume {
+        match driver.load_progress().await? {
+            Some(progress) if progress.rollout == rollout => {
+                info!(
+                    "resuming {} started at {}, {} pods already done",
+                    rollout,
+                    progress.started,
+                    progress.pods.keys().filter(|p| progress.is_done(p)).count()
+                );
+                return Ok(progress);
+            }
+            Some(progress) => anyhow::bail!(
+                "cannot resume {}, the interrupted rollout was {}",
+                rollout,
+                progress.rollout
+            ),
+            None => warn!("no interrupted rollout found, starting from the beginning"),
+        }
     }
+
+    let progress = UpgradeProgress::new(rollout);
+    driver.save_progress(Some(&progress)).await?;
+    Ok(progress)
 }
 
-impl StatefulSetApi {
-    /// Upgrade a vault cluster
-    ///
-    /// - Verify that the statefulset is ready to be upgraded or in the process of being upgraded
-    ///     - if statefulset is ready and all pods are ready, initialized and unsealed
-    ///         - start upgrade process
-    /// - Detect the target version from statefulset
-    /// - Repeat for all standby pods
-    ///     - Do a.1
-    ///     - Do a.2
-    /// - Upgrade active pods
-    ///     - if Pod version is outdated
-    ///         - Step down active pod
-    ///         - Wait for other pod to take over
-    ///     - Do a.1
-    ///     - Do a.2
-    ///
-    /// - a.1. if Pod version is outdated
-    ///     - a.1.1. Delete pod
-    ///     - a.1.2. Wait for pod to be deleted
-    ///     - a.1.3. Wait for pod to be running
-    /// - a.2. if Pod version is current
-    ///     - a.2.1. Pod is sealed
-    ///         - a.2.1.1 Unseal pod
-    ///     - a.2.2. Wait for pod to be unsealed
-    ///     - a.2.3. Wait for pod to be ready
-    pub async fn upgrade(
-        &self,
-        sts: StatefulSet,
-        pods: &PodApi,
-        token: Secret<String>,
-        should_unseal: bool,
-        force_upgrade: bool,
-        keys: &[Secret<String>],
-    ) -> anyhow::Result<()> {
-        let target = VaultVersion::try_from(&sts)?;
+/// Name of the pod, or `None` if the resumed rollout already finished it
+fn pending(
+    progress: &UpgradeProgress,
+    pod: &Pod,
+    options: &UpgradeOptions,
+) -> anyhow::Result<Option<String>> {
+    let name = pod
+        .metadata
+        .name
+        .clone()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
 
-        let standby = pods
-            .api
-            .list(&list_vault_pods().labels(&ExecIn::Standby.to_label_selector()))
-            .await?;
+    if progress.is_done(&name) {
+        info!(
+            "pod {} was already done before the rollout was interrupted",
+            name
+        );
+        options.report_pod(&name, |report| report.skipped = true);
+        return Ok(None);
+    }
 
-        if standby.items.is_empty() {
-            warn!("no standby pods found, skipping upgrade");
-            return Ok(());
-        }
+    Ok(Some(name))
+}
 
-        let active = pods
-            .api
-            .list(&list_vault_pods().labels(&ExecIn::Active.to_label_selector()))
-            .await?;
+/// Number of pods the rollout still has to do
+fn left(progress: &UpgradeProgress, standby: &[Pod], active: &[Pod]) -> usize {
+    standby
+        .iter()
+        .chain(active)
+        .filter(|pod| {
+            !pod.metadata
+                .name
+                .as_ref()
+                .is_some_and(|name| progress.is_done(name))
+        })
+        .count()
+}
 
-        if active.items.is_empty() {
-            warn!("no active pods found, skipping upgrade");
-            return Ok(());
-        }
+/// Set the phase of the pod and persist the progress
+async fn record(
+    driver: &(impl UpgradeDriver + Sync),
+    progress: &mut UpgradeProgress,
+    name: &str,
+    phase: UpgradePhase,
+) -> anyhow::Result<()> {
+    progress.set(name, phase);
+    driver.save_progress(Some(progress)).await
+}
 
-        info!("upgrading standby pods");
-        for pod in standby.iter() {
-            pods.upgrade(
-                pod.clone(),
-                &target,
-                token.clone(),
-                should_unseal,
-                force_upgrade,
-                keys,
-            )
-            .await?;
+/// Take a raft snapshot of the active pod and store it, see `snapshot_before`
+async fn snapshot(
+    driver: &(impl UpgradeDriver + Sync),
+    active: &Pod,
+    token: Secret<String>,
+    destination: &SnapshotDestination,
+) -> anyhow::Result<()> {
+    let name = active
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    info!("taking raft snapshot of pod {}", name);
+    let snapshot = driver
+        .raft_snapshot(name, token)
+        .await
+        .map_err(|e| e.context("taking raft snapshot before the upgrade, stopping"))?;
+
+    destination.store(&snapshot).await.map_err(|e| {
+        e.context(format!(
+            "storing raft snapshot in {}, stopping",
+            destination
+        ))
+    })?;
+    info!(
+        "stored raft snapshot of {} bytes in {}",
+        snapshot.len(),
+        destination
+    );
+
+    Ok(())
+}
+
+/// Wait for the pod to apply the raft log the leader committed, see `max_raft_lag`
+async fn await_caught_up(
+    driver: &(impl UpgradeDriver + Sync),
+    name: Option<&str>,
+    leader: Option<&str>,
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    let (Some(name), Some(leader), Some(max_lag)) = (name, leader, options.max_raft_lag) else {
+        return Ok(());
+    };
+
+    let Some(committed) = driver.raft_committed_index(leader).await? else {
+        debug!("pod {} does not report its raft index, not waiting", leader);
+        return Ok(());
+    };
+
+    within(
+        options.raft_catch_up_timeout,
+        format!(
+            "waiting for pod {} to catch up with raft index {} of pod {}",
+            name, committed, leader
+        ),
+        driver.await_raft_caught_up(name, committed, max_lag),
+    )
+    .await
+}
+
+/// Stop the rollout if the replicas of the statefulset changed since it started,
+/// as the pods and the quorum computed at the start may no longer be valid.
+/// The rollout continues with the new replicas if the change is confirmed.
+async fn ensure_replicas_unchanged(
+    driver: &(impl UpgradeDriver + Sync),
+    expected: &mut Option<i32>,
+    options: &ClusterUpgradeOptions,
+) -> anyhow::Result<()> {
+    let (Some(before), Some(current)) = (*expected, driver.replicas().await?) else {
+        return Ok(());
+    };
+
+    if before == current {
+        return Ok(());
+    }
+
+    warn!(
+        "replicas of the statefulset changed from {} to {} during the rollout",
+        before, current
+    );
+
+    let question = format!(
+        "replicas of the statefulset changed from {} to {}, continue?",
+        before, current
+    );
+    // confirming may block on the terminal
+    let confirmed = match options.confirm.clone() {
+        Some(confirm) => tokio::task::spawn_blocking(move || confirm(&question)).await?,
+        None => false,
+    };
+    if !confirmed {
+        anyhow::bail!(
+            "replicas of the statefulset changed from {} to {} during the rollout, stopping",
+            before,
+            current
+        );
+    }
+
+    *expected = Some(current);
+
+    Ok(())
+}
+
+/// List the standby and active pods
+/// Returns `None` if either of them is missing, as the cluster cannot be rolled without downtime
+async fn pods_in_rollout_order(
+    driver: &(impl UpgradeDriver + Sync),
+) -> anyhow::Result<Option<(Vec<Pod>, Vec<Pod>)>> {
+    let standby = driver.list_pods(ExecIn::Standby).await?;
+
+    if standby.is_empty() {
+        warn!("no standby pods found, skipping");
+        return Ok(None);
+    }
+
+    let active = driver.list_pods(ExecIn::Active).await?;
+
+    if active.is_empty() {
+        warn!("no active pods found, skipping");
+        return Ok(None);
+    }
+
+    Ok(Some((standby, active)))
+}
+
+/// Order the standby pods by their role in the raft configuration
+///
+/// Pods that are no voters (or did not join yet) go first, as they do not count for quorum,
+/// then the voters. A pod raft reports as leader despite being labeled as standby would be
+/// deleted without stepping down, so the rollout is refused until the labels are updated.
+/// The order of the labels is kept if the raft configuration cannot be read.
+async fn order_by_raft_role(
+    driver: &(impl UpgradeDriver + Sync),
+    mut standby: Vec<Pod>,
+    active: &[Pod],
+    token: Secret<String>,
+) -> anyhow::Result<Vec<Pod>> {
+    let name = active[0]
+        .metadata
+        .name
+        .as_ref()
+        .ok_or(anyhow::anyhow!("pod does not have a name"))?;
+
+    let servers = match driver.raft_servers(name, token).await {
+        Ok(servers) => servers,
+        Err(e) => {
+            warn!(
+                "reading raft configuration, ordering pods by labels only: {}",
+                e
+            );
+            return Ok(standby);
         }
+    };
 
-        info!("upgrading active pods");
-        for pod in active.iter() {
-            pods.upgrade(
-                pod.clone(),
-                &target,
-                token.clone(),
-                should_unseal,
-                force_upgrade,
-                keys,
-            )
-            .await?;
+    let names = standby
+        .iter()
+        .chain(active)
+        .filter_map(|p| p.metadata.name.as_deref())
+        .collect::<Vec<_>>();
+    for server in &servers {
+        if !names.iter().any(|n| is_raft_server_of_pod(server, n)) {
+            warn!(
+                "raft server {} ({}) has no pod, it is not upgraded",
+                server.node_id, server.address
+            );
         }
+    }
 
-        Ok(())
+    let server = |pod: &Pod| {
+        pod.metadata
+            .name
+            .as_deref()
+            .and_then(|n| servers.iter().find(|s| is_raft_server_of_pod(s, n)))
+    };
+    if let Some(leader) = standby.iter().filter_map(server).find(|s| s.leader) {
+        anyhow::bail!(
+            "pod {} is raft leader but labeled as standby, the labels are stale, wait for them to be updated",
+            leader.node_id
+        );
     }
+
+    standby.sort_by_cached_key(|pod| server(pod).is_some_and(|s| s.voter));
+
+    Ok(standby)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::str::FromStr;
+    use std::{
+        str::FromStr,
+        time::{Duration, Instant},
+    };
 
     use http::{Request, Response, StatusCode};
     use hyper::body::Bytes;
-    use k8s_openapi::{api::core::v1::Pod, List};
+    use k8s_openapi::{
+        api::{apps::v1::StatefulSet, core::v1::Pod},
+        List,
+    };
     use kube::{client::Body, Api, Client};
     use secrecy::Secret;
     use serde_yaml::Value;
@@ -233,7 +3780,12 @@ mod tests {
     use tokio_util::sync::CancellationToken;
     use tower_test::mock::{self, Handle};
 
-    use crate::{PodApi, VaultVersion};
+    use super::{within, CatchUpProgress};
+    use crate::{
+        check_raft_protocol, check_template_changes, ClusterUpgradeOptions, PlannedAction, PodApi,
+        RaftConfigurationServer, RaftProtocolIncompatible, SemVer, StatefulSetApi,
+        TemplateChangesNotAcknowledged, UpgradeOptions, VaultVersion,
+    };
 
     #[tokio::test]
     async fn is_current_returns_true_if_pod_version_is_current() {
@@ -289,6 +3841,111 @@ mod tests {
         assert!(!PodApi::is_current(&pod, &target).unwrap());
     }
 
+    #[tokio::test]
+    async fn template_changes_of_outdated_pods_have_to_be_acknowledged() {
+        let read = |path: &str| {
+            tokio::fs::read_to_string(format!("tests/resources/installed/{}.yaml", path))
+        };
+        let mut sts: StatefulSet = serde_yaml::from_str(
+            &read("apis/apps/v1/namespaces/vault-mgmt-e2e/statefulsets/vault-mgmt-e2e-2274")
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+        let pod: Pod = serde_yaml::from_str(
+            &read("api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-0")
+                .await
+                .unwrap(),
+        )
+        .unwrap();
+
+        let vault = &mut sts
+            .spec
+            .as_mut()
+            .unwrap()
+            .template
+            .spec
+            .as_mut()
+            .unwrap()
+            .containers[0];
+        vault.resources = Some(
+            serde_json::from_value(serde_json::json!({ "limits": { "memory": "256Mi" } })).unwrap(),
+        );
+        vault.image = Some("hashicorp/vault:1.14.0".to_string());
+
+        let pods = [pod];
+        let outdated = VaultVersion {
+            version: "1.14.0".to_string(),
+        };
+        let current = VaultVersion {
+            version: "1.13.0".to_string(),
+        };
+        let options = ClusterUpgradeOptions::default();
+
+        let err = check_template_changes(&sts, &pods, None, &outdated, &options).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TemplateChangesNotAcknowledged>()
+                .unwrap()
+                .changes
+                .iter()
+                .map(|c| c.field)
+                .collect::<Vec<_>>(),
+            vec!["resources"]
+        );
+
+        assert!(check_template_changes(
+            &sts,
+            &pods,
+            None,
+            &outdated,
+            &options.clone().ack_template_changes(true)
+        )
+        .is_ok());
+        // pods with the target version are not recreated
+        assert!(check_template_changes(&sts, &pods, None, &current, &options).is_ok());
+    }
+
+    #[test]
+    fn peers_on_older_raft_protocols_are_refused() {
+        let server = |node_id: &str, protocol_version: &str| RaftConfigurationServer {
+            node_id: node_id.to_string(),
+            address: format!("{}.vault-internal:8201", node_id),
+            leader: false,
+            protocol_version: protocol_version.to_string(),
+            voter: true,
+        };
+        let target = VaultVersion::from_str("1.14.0").unwrap();
+
+        check_raft_protocol(&[server("vault-0", "3"), server("vault-1", "3")], &target).unwrap();
+        // protocols that are no number are only warned about
+        check_raft_protocol(&[server("vault-0", "3"), server("vault-1", "x")], &target).unwrap();
+        // versions older than all requirements are not checked
+        check_raft_protocol(
+            &[server("vault-0", "2")],
+            &VaultVersion::from_str("1.3.0").unwrap(),
+        )
+        .unwrap();
+
+        let err = check_raft_protocol(&[server("vault-0", "3"), server("vault-1", "2")], &target)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<RaftProtocolIncompatible>(),
+            Some(&RaftProtocolIncompatible {
+                target,
+                since: SemVer {
+                    major: 1,
+                    minor: 4,
+                    patch: 0
+                },
+                required: 3,
+                servers: vec![("vault-1".to_string(), "2".to_string())],
+            })
+        );
+        assert!(err
+            .to_string()
+            .contains("upgrade to a version before 1.4.0"));
+    }
+
     async fn mock_list_sealed(
         cancel: CancellationToken,
         handle: &mut Handle<Request<Body>, Response<Body>>,
@@ -386,13 +4043,12 @@ mod tests {
 
         let pod = pods.api.get("vault-mgmt-e2e-2274-1").await.unwrap();
 
-        pods.upgrade(
+        pods.upgrade_with(
             pod,
             &target,
             Secret::from_str("token").unwrap(),
-            false,
-            false,
             &[],
+            &UpgradeOptions::default().should_unseal(false),
         )
         .await
         .unwrap_err();
@@ -416,13 +4072,74 @@ mod tests {
 
         let pod = pods.api.get("vault-mgmt-e2e-2274-1").await.unwrap();
 
-        pods.upgrade(
+        pods.upgrade_with(
             pod,
             &target,
             Secret::from_str("token").unwrap(),
-            false,
-            true,
             &[],
+            &UpgradeOptions::default()
+                .should_unseal(false)
+                .force_upgrade(true),
+        )
+        .await
+        .unwrap_err();
+
+        cancel.cancel();
+
+        let delete_called = service.await.unwrap();
+
+        assert!(delete_called);
+    }
+
+    #[test]
+    fn catch_up_progress_reports_rate_and_stalls() {
+        let start = Instant::now();
+        let mut progress = CatchUpProgress::new(start, 100, 1100);
+
+        assert_eq!(progress.describe(start), "applied 100 of 1100 (9%)");
+
+        progress.update(start + Duration::from_secs(10), 600);
+        assert_eq!(
+            progress.describe(start + Duration::from_secs(10)),
+            "applied 600 of 1100 (54%), 50 entries/s, about 10s left"
+        );
+
+        progress.update(start + Duration::from_secs(40), 600);
+        assert_eq!(
+            progress.stalled_for(start + Duration::from_secs(40)),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[tokio::test]
+    async fn within_fails_if_wait_does_not_finish() {
+        let err = within(
+            std::time::Duration::from_millis(10),
+            "waiting for pod vault-0 to be unsealed",
+            std::future::pending::<anyhow::Result<()>>(),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "waiting for pod vault-0 to be unsealed did not finish within 10ms"
+        );
+    }
+
+    #[tokio::test]
+    async fn restart_does_delete_pod_regardless_of_version() {
+        let (api, service, cancel) = setup().await;
+
+        let pods = PodApi::new(api, false, "vault-mgmt-e2e".to_string());
+
+        let pod = pods.api.get("vault-mgmt-e2e-2274-1").await.unwrap();
+
+        pods.restart_with(
+            pod,
+            Secret::from_str("token").unwrap(),
+            &[],
+            &UpgradeOptions::default().should_unseal(false),
         )
         .await
         .unwrap_err();
@@ -433,4 +4150,132 @@ mod tests {
 
         assert!(delete_called);
     }
+
+    async fn mock_rollout(
+        cancel: CancellationToken,
+        handle: &mut Handle<Request<Body>, Response<Body>>,
+    ) -> bool {
+        let mut mutated = false;
+
+        let pod = |n: u8| async move {
+            let mut pod: Pod = serde_yaml::from_str(
+                &tokio::fs::read_to_string(format!(
+                    "tests/resources/installed/{}{}.yaml",
+                    "api/v1/namespaces/vault-mgmt-e2e/pods/vault-mgmt-e2e-2274-", n
+                ))
+                .await
+                .unwrap(),
+            )
+            .unwrap();
+
+            let labels = pod.metadata.labels.as_mut().unwrap();
+            labels.insert("vault-active".to_string(), (n == 0).to_string());
+            labels.insert("vault-sealed".to_string(), "false".to_string());
+
+            if n == 2 {
+                pod.spec.as_mut().unwrap().containers[0].image =
+                    Some("hashicorp/vault:1.14.0".to_string());
+            }
+
+            pod
+        };
+
+        loop {
+            tokio::select! {
+                request = handle.next_request() => {
+                    let (request, send) = request.expect("Service not called");
+
+                    let query = request.uri().query().unwrap_or_default().to_string();
+
+                    if request.method() != "GET" {
+                        mutated = true;
+                    }
+
+                    let mut list = List::<Pod>::default();
+                    if query.contains("vault-active%3Dtrue") {
+                        list.items.push(pod(0).await);
+                    } else if query.contains("vault-active%3Dfalse") {
+                        list.items.push(pod(1).await);
+                        list.items.push(pod(2).await);
+                    }
+
+                    send.send_response(Response::builder().body(Bytes::from(serde_json::to_string(&list).unwrap()).into()).unwrap());
+                }
+                _ = cancel.cancelled() => {
+                    return mutated;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn plan_orders_pods_and_does_not_mutate() {
+        let (mock_service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let cancel = CancellationToken::new();
+        let cloned_token = cancel.clone();
+
+        let spawned = tokio::spawn(async move { mock_rollout(cloned_token, &mut handle).await });
+
+        let client = Client::new(mock_service, "vault-mgmt-e2e");
+        let pods = PodApi::new(
+            Api::default_namespaced(client.clone()),
+            false,
+            "".to_string(),
+        );
+
+        let mut sts: StatefulSet = serde_yaml::from_str(
+            &tokio::fs::read_to_string(format!(
+                "tests/resources/installed/{}.yaml",
+                "apis/apps/v1/namespaces/vault-mgmt-e2e/statefulsets/vault-mgmt-e2e-2274"
+            ))
+            .await
+            .unwrap(),
+        )
+        .unwrap();
+        sts.spec
+            .as_mut()
+            .unwrap()
+            .template
+            .spec
+            .as_mut()
+            .unwrap()
+            .containers[0]
+            .image = Some("hashicorp/vault:1.14.0".to_string());
+
+        let plan = StatefulSetApi::from(Api::default_namespaced(client))
+            .plan_with(
+                &sts,
+                &pods,
+                None,
+                &UpgradeOptions::default().should_unseal(false),
+            )
+            .await
+            .unwrap();
+
+        cancel.cancel();
+        let mutated = spawned.await.unwrap();
+
+        assert!(!mutated);
+        assert_eq!(plan.target.version, "1.14.0");
+
+        let steps: Vec<_> = plan
+            .pods
+            .iter()
+            .map(|p| (p.name.as_str(), p.action.clone(), p.unseal))
+            .collect();
+
+        assert_eq!(
+            steps,
+            vec![
+                ("vault-mgmt-e2e-2274-1", PlannedAction::Upgrade, false),
+                ("vault-mgmt-e2e-2274-2", PlannedAction::Skip, false),
+                (
+                    "vault-mgmt-e2e-2274-0",
+                    PlannedAction::StepDownAndUpgrade,
+                    false
+                ),
+            ]
+        );
+    }
 }
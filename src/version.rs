@@ -1,47 +1,245 @@
 use std::str::FromStr;
 
-use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
+use k8s_openapi::api::{
+    apps::v1::StatefulSet,
+    core::v1::{Container, Pod},
+};
+
+use crate::find_vault_container;
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub struct VaultVersion {
     pub version: String,
 }
 
+/// Numeric part of a vault version, e.g. `1.14.2` of `1.14.2-ent.hsm`
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SemVer {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+}
+
+impl std::fmt::Display for SemVer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Oldest raft protocol the peers have to speak for a version to join them, as the first
+/// version with the requirement and the protocol, ordered by version. Vault and OpenBao share
+/// the raft library, so the requirements apply to both.
+const RAFT_PROTOCOL_REQUIREMENTS: &[(SemVer, u64)] = &[
+    // integrated storage became generally available with raft protocol 3
+    (
+        SemVer {
+            major: 1,
+            minor: 4,
+            patch: 0,
+        },
+        3,
+    ),
+];
+
+/// Split a version like `v1.14.2-ent.hsm` into `1.14.2` and `ent.hsm`
+fn parse_semver(version: &str) -> Option<(SemVer, Option<&str>)> {
+    let version = version.strip_prefix('v').unwrap_or(version);
+    let (numbers, suffix) = match version.split_once(['-', '+']) {
+        Some((numbers, suffix)) => (numbers, Some(suffix)),
+        None => (version, None),
+    };
+
+    let mut numbers = numbers.split('.').map(u64::from_str);
+    let semver = SemVer {
+        major: numbers.next()?.ok()?,
+        minor: numbers.next()?.ok()?,
+        patch: numbers.next()?.ok()?,
+    };
+    if numbers.next().is_some() {
+        return None;
+    }
+
+    Some((semver, suffix))
+}
+
+/// Build edition of vault, read from the suffix of the version (`1.14.2+ent.hsm`)
+#[derive(Copy, Clone, Debug, Default, Hash, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Edition {
+    /// Community edition (and OpenBao), versions without `ent` suffix
+    #[default]
+    Community,
+    /// Enterprise, `+ent`
+    Enterprise,
+    /// Enterprise with HSM support, `+ent.hsm`
+    EnterpriseHsm,
+}
+
+impl std::fmt::Display for Edition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Edition::Community => "ce".fmt(f),
+            Edition::Enterprise => "ent".fmt(f),
+            Edition::EnterpriseHsm => "ent.hsm".fmt(f),
+        }
+    }
+}
+
+impl Edition {
+    /// Edition of a version reported by vault or used as image tag,
+    /// further suffixes like `fips1402` are ignored
+    pub fn of_version(version: &str) -> Self {
+        let parts = parse_semver(version)
+            .and_then(|(_, suffix)| suffix)
+            .map(|suffix| suffix.split('.').collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        match (parts.contains(&"ent"), parts.contains(&"hsm")) {
+            (true, true) => Edition::EnterpriseHsm,
+            (true, false) => Edition::Enterprise,
+            (false, _) => Edition::Community,
+        }
+    }
+
+    pub fn is_enterprise(&self) -> bool {
+        matches!(self, Edition::Enterprise | Edition::EnterpriseHsm)
+    }
+
+    /// The server only starts with a valid license
+    pub fn requires_license(&self) -> bool {
+        self.is_enterprise()
+    }
+
+    /// Standby servers can serve read requests as performance standbys
+    pub fn has_performance_standbys(&self) -> bool {
+        self.is_enterprise()
+    }
+
+    /// Performance and disaster recovery replication between clusters
+    pub fn supports_replication(&self) -> bool {
+        self.is_enterprise()
+    }
+}
+
+impl VaultVersion {
+    /// Numeric part of the version, `None` if the tag is not a version (e.g. `latest`)
+    pub fn semver(&self) -> Option<SemVer> {
+        parse_semver(&self.version).map(|(semver, _)| semver)
+    }
+
+    /// Suffix of the version, e.g. `ent.hsm` of `1.14.2-ent.hsm`
+    pub fn suffix(&self) -> Option<&str> {
+        parse_semver(&self.version).and_then(|(_, suffix)| suffix)
+    }
+
+    /// Edition of the build, see `Edition::of_version`
+    pub fn edition(&self) -> Edition {
+        Edition::of_version(&self.version)
+    }
+
+    /// Returns true if `target` has a lower version, both versions have to be valid
+    pub fn is_downgrade_to(&self, target: &VaultVersion) -> bool {
+        matches!((self.semver(), target.semver()), (Some(current), Some(target)) if target < current)
+    }
+
+    /// Returns true if vault reports this version, e.g. `1.14.2+ent` for the tag `1.14.2-ent`
+    pub fn is_reported_by(&self, reported: &str) -> bool {
+        match (self.semver(), parse_semver(reported)) {
+            (Some(expected), Some((reported, _))) => expected == reported,
+            _ => self.version == reported,
+        }
+    }
+
+    /// Oldest raft protocol the peers have to speak for this version, with the first
+    /// version that requires it, `None` if the version is older than all requirements
+    pub fn required_raft_protocol(&self) -> Option<(SemVer, u64)> {
+        let semver = self.semver()?;
+
+        RAFT_PROTOCOL_REQUIREMENTS
+            .iter()
+            .rev()
+            .find(|(since, _)| *since <= semver)
+            .copied()
+    }
+
+    /// Minor versions skipped when upgrading to `target`, e.g. 1 from 1.13.x to 1.15.x
+    pub fn skipped_minor_versions(&self, target: &VaultVersion) -> u64 {
+        match (self.semver(), target.semver()) {
+            (Some(current), Some(target)) if current.major == target.major => {
+                target.minor.saturating_sub(current.minor + 1)
+            }
+            _ => 0,
+        }
+    }
+}
+
 impl FromStr for VaultVersion {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if parse_semver(s).is_none() {
+            anyhow::bail!("expected a version like 1.14.2 or 1.14.2-ent, got {}", s);
+        }
+
         Ok(Self {
             version: s.to_string(),
         })
     }
 }
 
-/// Construct VaultVersion from statefulset
-impl TryFrom<&StatefulSet> for VaultVersion {
-    type Error = anyhow::Error;
+/// Versions are ordered by their numeric part, tags that are no version come first
+impl Ord for VaultVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.semver()
+            .cmp(&other.semver())
+            .then_with(|| self.version.cmp(&other.version))
+    }
+}
 
-    fn try_from(statefulset: &StatefulSet) -> Result<Self, Self::Error> {
-        let container = statefulset
+impl PartialOrd for VaultVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl VaultVersion {
+    /// Version of the image of the vault container in the pod template of the statefulset,
+    /// see `find_vault_container`
+    pub fn of_statefulset(
+        statefulset: &StatefulSet,
+        container: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let containers = &statefulset
             .spec
-            .clone()
+            .as_ref()
             .ok_or(anyhow::anyhow!("statefulset does not have a spec"))?
             .template
             .spec
+            .as_ref()
             .ok_or(anyhow::anyhow!("statefulset does not have a pod spec"))?
             .containers;
-        let container = container
-            .first()
-            .ok_or(anyhow::anyhow!("statefulset does not have a container"))?;
 
+        Self::of_container(find_vault_container(containers, container)?)
+    }
+
+    /// Version of the image of the vault container in the pod, see `find_vault_container`
+    pub fn of_pod(pod: &Pod, container: Option<&str>) -> anyhow::Result<Self> {
+        let containers = &pod
+            .spec
+            .as_ref()
+            .ok_or(anyhow::anyhow!("pod does not have a spec"))?
+            .containers;
+
+        Self::of_container(find_vault_container(containers, container)?)
+    }
+
+    fn of_container(container: &Container) -> anyhow::Result<Self> {
         let image = container
             .image
             .clone()
             .ok_or(anyhow::anyhow!("container does not have an image"))?;
 
-        let version = image
-            .split(':')
-            .nth(1)
+        let version = image_tag(&image)
             .ok_or(anyhow::anyhow!("image does not have a tag"))?
             .to_string();
 
@@ -49,40 +247,88 @@ impl TryFrom<&StatefulSet> for VaultVersion {
     }
 }
 
+/// Construct VaultVersion from statefulset
+impl TryFrom<&StatefulSet> for VaultVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(statefulset: &StatefulSet) -> Result<Self, Self::Error> {
+        Self::of_statefulset(statefulset, None)
+    }
+}
+
 /// Construct VaultVersion from pod spec
 impl TryFrom<&Pod> for VaultVersion {
     type Error = anyhow::Error;
 
     fn try_from(pod: &Pod) -> Result<Self, Self::Error> {
-        let container = pod
-            .spec
-            .clone()
-            .ok_or(anyhow::anyhow!("pod does not have a spec"))?
-            .containers;
-        let container = container
-            .first()
-            .ok_or(anyhow::anyhow!("pod does not have a container"))?;
+        Self::of_pod(pod, None)
+    }
+}
 
-        let image = container
-            .image
-            .clone()
-            .ok_or(anyhow::anyhow!("container does not have an image"))?;
+/// Split a container image without digest into repository and tag
+fn split_image_tag(image: &str) -> (&str, Option<&str>) {
+    let image = image.split('@').next().unwrap_or(image);
 
-        let version = image
-            .split(':')
-            .nth(1)
-            .ok_or(anyhow::anyhow!("image does not have a tag"))?
-            .to_string();
-
-        Ok(Self { version })
+    // a colon after the last slash separates the tag, otherwise it belongs to the registry
+    // port or an IPv6 registry address like `[fd00::1]:5000/vault`
+    match image.rfind(':') {
+        Some(i) if i > image.rfind('/').unwrap_or(0) => (&image[..i], Some(&image[i + 1..])),
+        _ => (image, None),
     }
 }
 
+/// Tag of a container image, ignoring the registry port and digest
+fn image_tag(image: &str) -> Option<&str> {
+    split_image_tag(image).1.filter(|t| !t.is_empty())
+}
+
+/// Replace the tag (or digest) of a container image with the version
+pub fn image_with_version(image: &str, version: &VaultVersion) -> String {
+    format!("{}:{}", split_image_tag(image).0, version.version)
+}
+
 #[cfg(test)]
 mod tests {
+    use std::str::FromStr;
+
     use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
 
-    use crate::VaultVersion;
+    use super::image_tag;
+    use crate::{image_with_version, Edition, SemVer, VaultVersion};
+
+    #[test]
+    fn reported_version_ignores_the_suffix() {
+        let version = VaultVersion::from_str("1.14.2-ent").unwrap();
+
+        assert!(version.is_reported_by("1.14.2+ent"));
+        assert!(version.is_reported_by("v1.14.2"));
+        assert!(!version.is_reported_by("1.14.1+ent"));
+    }
+
+    #[test]
+    fn edition_is_parsed_from_the_suffix() {
+        for (version, edition) in [
+            ("1.14.2", Edition::Community),
+            ("1.14.2-ent", Edition::Enterprise),
+            ("1.14.2+ent", Edition::Enterprise),
+            ("1.14.2+ent.fips1402", Edition::Enterprise),
+            ("1.14.2-ent.hsm", Edition::EnterpriseHsm),
+            ("1.14.2+ent.hsm.fips1402", Edition::EnterpriseHsm),
+            ("latest", Edition::Community),
+        ] {
+            assert_eq!(Edition::of_version(version), edition, "{}", version);
+        }
+
+        assert!(!Edition::Community.has_performance_standbys());
+        assert!(Edition::EnterpriseHsm.supports_replication());
+        assert_eq!(
+            VaultVersion::from_str("1.14.2-ent.hsm")
+                .unwrap()
+                .edition()
+                .to_string(),
+            "ent.hsm"
+        );
+    }
 
     #[tokio::test]
     async fn constructing_vault_version_from_statefulset_works() {
@@ -158,5 +404,97 @@ mod tests {
         assert!(current != outdated);
         assert!(current != newer);
         assert!(outdated != newer);
+
+        assert!(outdated < current);
+        assert!(current < newer);
+        assert!(current.is_downgrade_to(&outdated));
+        assert!(!current.is_downgrade_to(&newer));
+    }
+
+    #[test]
+    fn vault_versions_are_parsed_as_semver() {
+        let version = VaultVersion::from_str("1.14.2-ent.hsm").unwrap();
+        assert_eq!(
+            version.semver(),
+            Some(SemVer {
+                major: 1,
+                minor: 14,
+                patch: 2
+            })
+        );
+        assert_eq!(version.suffix(), Some("ent.hsm"));
+
+        // numeric ordering, not lexicographic
+        assert!(
+            VaultVersion::from_str("1.9.0").unwrap() < VaultVersion::from_str("1.10.0").unwrap()
+        );
+        assert!(
+            VaultVersion::from_str("1.14.2").unwrap()
+                < VaultVersion::from_str("1.14.2-ent").unwrap()
+        );
+
+        assert_eq!(
+            VaultVersion::from_str("1.13.4")
+                .unwrap()
+                .skipped_minor_versions(&VaultVersion::from_str("1.15.0").unwrap()),
+            1
+        );
+
+        assert!(VaultVersion::from_str("latest").is_err());
+        assert!(VaultVersion::from_str("1.14").is_err());
+        assert!(VaultVersion::from_str("1.14.0.1").is_err());
+    }
+
+    #[test]
+    fn replacing_image_version_works() {
+        let version = VaultVersion {
+            version: "1.17.0".to_string(),
+        };
+
+        for (image, expected) in [
+            ("hashicorp/vault:1.13.0", "hashicorp/vault:1.17.0"),
+            ("hashicorp/vault", "hashicorp/vault:1.17.0"),
+            (
+                "registry.example.com:5000/hashicorp/vault:1.13.0",
+                "registry.example.com:5000/hashicorp/vault:1.17.0",
+            ),
+            (
+                "registry.example.com:5000/vault",
+                "registry.example.com:5000/vault:1.17.0",
+            ),
+            (
+                "hashicorp/vault:1.13.0@sha256:0123456789abcdef",
+                "hashicorp/vault:1.17.0",
+            ),
+            (
+                "[fd00::1]:5000/hashicorp/vault:1.13.0",
+                "[fd00::1]:5000/hashicorp/vault:1.17.0",
+            ),
+            ("[fd00::1]:5000/vault", "[fd00::1]:5000/vault:1.17.0"),
+        ] {
+            assert_eq!(image_with_version(image, &version), expected);
+        }
+    }
+
+    #[test]
+    fn image_tag_ignores_registry_port_and_digest() {
+        for (image, expected) in [
+            ("hashicorp/vault:1.13.0", Some("1.13.0")),
+            ("hashicorp/vault", None),
+            ("hashicorp/vault:", None),
+            (
+                "registry.example.com:5000/hashicorp/vault:1.13.0",
+                Some("1.13.0"),
+            ),
+            ("registry.example.com:5000/vault", None),
+            ("[fd00::1]:5000/hashicorp/vault:1.13.0", Some("1.13.0")),
+            ("[fd00::1]:5000/vault", None),
+            (
+                "hashicorp/vault:1.13.0@sha256:0123456789abcdef",
+                Some("1.13.0"),
+            ),
+        ] {
+            assert_eq!(image_tag(image), expected, "{}", image);
+        }
     }
 }
@@ -1,5 +1,179 @@
-use k8s_openapi::api::{apps::v1::StatefulSet, core::v1::Pod};
-use kube::runtime::wait::Condition;
+use k8s_openapi::api::{
+    apps::v1::StatefulSet,
+    core::v1::{Endpoints, Pod},
+};
+use std::{fmt::Debug, pin::pin, time::Duration};
+
+use futures_util::StreamExt;
+use kube::{
+    runtime::{wait::Condition, watcher, WatchStreamExt},
+    Api, Resource,
+};
+use serde::de::DeserializeOwned;
+use tracing::*;
+
+use crate::{image_pull_failure, registration_label, KubeTuning, VaultVersion};
+
+/// Wait until the object fulfills the condition, `None` if it was deleted.
+///
+/// Unlike `kube::runtime::wait::await_condition`, a watch failing with a transient error
+/// (e.g. a lost connection) does not fail the wait, but is restarted with the backoff of
+/// the tuning. Other errors (e.g. forbidden or not found) fail the wait, see `is_transient`.
+pub async fn await_condition<K>(
+    api: Api<K>,
+    name: &str,
+    cond: impl Condition<K>,
+    tuning: &KubeTuning,
+) -> anyhow::Result<Option<K>>
+where
+    K: Clone + Debug + Send + DeserializeOwned + Resource + 'static,
+{
+    watch_until(api, name, cond, tuning).await
+}
+
+/// How `wait_for` waits for a condition
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WaitPolicy {
+    /// fail with `WaitTimedOut` if the condition is not fulfilled by then, wait forever if unset
+    pub timeout: Option<Duration>,
+    /// watch timeout and backoff of the restarted watches
+    pub tuning: KubeTuning,
+}
+
+impl WaitPolicy {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn tuning(mut self, tuning: KubeTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+}
+
+/// The object did not fulfill the condition within the timeout of the `WaitPolicy`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WaitTimedOut {
+    pub kind: String,
+    pub name: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for WaitTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} did not fulfill the condition within {}",
+            self.kind,
+            self.name,
+            humantime::format_duration(self.timeout)
+        )
+    }
+}
+
+impl std::error::Error for WaitTimedOut {}
+
+/// Wait until any resource fulfills the condition, `None` if it was deleted, e.g. a PVC,
+/// Service or Job next to vault.
+///
+/// Like `await_condition`, watches failing with a transient error are restarted
+/// with the backoff of the policy.
+pub async fn wait_for<K>(
+    api: Api<K>,
+    name: &str,
+    cond: impl Condition<K>,
+    policy: &WaitPolicy,
+) -> anyhow::Result<Option<K>>
+where
+    K: Clone + Debug + Send + DeserializeOwned + Resource + 'static,
+    K::DynamicType: Default,
+{
+    let wait = watch_until(api, name, cond, &policy.tuning);
+    let Some(timeout) = policy.timeout else {
+        return wait.await;
+    };
+
+    tokio::time::timeout(timeout, wait)
+        .await
+        .map_err(|_| WaitTimedOut {
+            kind: K::kind(&Default::default()).to_string(),
+            name: name.to_string(),
+            timeout,
+        })?
+}
+
+async fn watch_until<K>(
+    api: Api<K>,
+    name: &str,
+    cond: impl Condition<K>,
+    tuning: &KubeTuning,
+) -> anyhow::Result<Option<K>>
+where
+    K: Clone + Debug + Send + DeserializeOwned + Resource + 'static,
+{
+    let fields = format!("metadata.name={name}");
+    let mut events =
+        pin!(watcher(api, tuning.watcher_config().fields(&fields)).backoff(tuning.backoff()));
+    // the object is gone if the initial list does not contain it
+    let mut listed = false;
+
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(watcher::Event::Apply(obj)) => {
+                if cond.matches_object(Some(&obj)) {
+                    return Ok(Some(obj));
+                }
+            }
+            Ok(watcher::Event::InitApply(obj)) => {
+                listed = true;
+                if cond.matches_object(Some(&obj)) {
+                    return Ok(Some(obj));
+                }
+            }
+            Ok(watcher::Event::Delete(_)) => {
+                if cond.matches_object(None) {
+                    return Ok(None);
+                }
+            }
+            Ok(watcher::Event::Init) => listed = false,
+            Ok(watcher::Event::InitDone) => {
+                if !listed && cond.matches_object(None) {
+                    return Ok(None);
+                }
+            }
+            Err(e) if is_transient(&e) => {
+                warn!("watching {}, restarting the watch: {}", name, e)
+            }
+            Err(e) => anyhow::bail!("watching {}: {}", name, e),
+        }
+    }
+
+    anyhow::bail!("watch of {} ended", name)
+}
+
+/// Whether the watch may succeed when it is restarted: lost connections, expired resource
+/// versions, throttling and server errors. Errors like forbidden, not found or an invalid
+/// request fail again.
+fn is_transient(error: &watcher::Error) -> bool {
+    let client_error = match error {
+        watcher::Error::InitialListFailed(e)
+        | watcher::Error::WatchStartFailed(e)
+        | watcher::Error::WatchFailed(e) => e,
+        watcher::Error::WatchError(response) => return is_transient_status(response.code),
+        watcher::Error::NoResourceVersion => return false,
+    };
+
+    match client_error {
+        kube::Error::Api(response) => is_transient_status(response.code),
+        kube::Error::HyperError(_) | kube::Error::Service(_) | kube::Error::ReadEvents(_) => true,
+        _ => false,
+    }
+}
+
+fn is_transient_status(code: u16) -> bool {
+    matches!(code, 408 | 410 | 429 | 500..=599)
+}
 
 /// Returns true if the StatefulSet is considered ready.
 /// This means that all replicas are available and ready.
@@ -39,25 +213,19 @@ pub fn is_statefulset_updated() -> impl Condition<StatefulSet> {
 /// Returns true if the StatefulSet template is using the given version.
 #[must_use]
 pub fn statefulset_has_version(version: String) -> impl Condition<StatefulSet> {
+    statefulset_container_has_version(version, None)
+}
+
+/// Returns true if the vault container of the StatefulSet template is using the given version,
+/// see `find_vault_container`
+#[must_use]
+pub fn statefulset_container_has_version(
+    version: String,
+    container: Option<String>,
+) -> impl Condition<StatefulSet> {
     move |obj: Option<&StatefulSet>| {
-        if let Some(sts) = &obj {
-            if let Some(spec) = &sts.spec {
-                if let Some(tpl_spec) = &spec.template.spec {
-                    return tpl_spec
-                        .containers
-                        .iter()
-                        .filter_map(|c| {
-                            if c.name == "vault" {
-                                c.image.clone()
-                            } else {
-                                None
-                            }
-                        })
-                        .all(|image| image.ends_with(&format!(":{}", version)));
-                }
-            }
-        }
-        false
+        obj.and_then(|sts| VaultVersion::of_statefulset(sts, container.as_deref()).ok())
+            .is_some_and(|current| current.version == version)
     }
 }
 
@@ -78,15 +246,41 @@ pub fn is_pod_ready() -> impl Condition<Pod> {
     }
 }
 
+/// Returns true if the named container of the Pod is ready.
+/// In contrast to `is_pod_ready`, this ignores the readiness of other containers (e.g. sidecars).
+#[must_use]
+pub fn is_pod_container_ready(container: String) -> impl Condition<Pod> {
+    move |obj: Option<&Pod>| {
+        if let Some(pod) = &obj {
+            if let Some(status) = &pod.status {
+                if let Some(ref statuses) = status.container_statuses {
+                    return statuses.iter().any(|c| c.name == container && c.ready);
+                }
+            }
+        }
+        false
+    }
+}
+
 /// Returns true if the Pod has the seal status label.
 /// This is determined by looking if the `vault-sealed` label exists.
 #[must_use]
 pub fn is_pod_exporting_seal_status() -> impl Condition<Pod> {
     |obj: Option<&Pod>| {
         if let Some(pod) = &obj {
-            if let Some(labels) = &pod.metadata.labels {
-                return labels.get("vault-sealed").is_some();
-            }
+            return registration_label(pod, "sealed").is_some();
+        }
+        false
+    }
+}
+
+/// Returns true if a container of the Pod cannot pull its image.
+/// See `image_pull_failure` for the reasons.
+#[must_use]
+pub fn is_pod_failing_image_pull() -> impl Condition<Pod> {
+    |obj: Option<&Pod>| {
+        if let Some(pod) = &obj {
+            return image_pull_failure(pod).is_some();
         }
         false
     }
@@ -105,11 +299,7 @@ pub fn is_pod_unsealed() -> impl Condition<Pod> {
 pub fn is_pod_sealed() -> impl Condition<Pod> {
     |obj: Option<&Pod>| {
         if let Some(pod) = &obj {
-            if let Some(labels) = &pod.metadata.labels {
-                if let Some(sealed) = labels.get("vault-sealed") {
-                    return sealed.as_str() == "true";
-                }
-            }
+            return registration_label(pod, "sealed") == Some("true");
         }
         false
     }
@@ -121,11 +311,7 @@ pub fn is_pod_sealed() -> impl Condition<Pod> {
 pub fn is_pod_active() -> impl Condition<Pod> {
     |obj: Option<&Pod>| {
         if let Some(pod) = &obj {
-            if let Some(labels) = &pod.metadata.labels {
-                if let Some(active) = labels.get("vault-active") {
-                    return active.as_str() == "true";
-                }
-            }
+            return registration_label(pod, "active") == Some("true");
         }
         false
     }
@@ -138,6 +324,26 @@ pub fn is_pod_standby() -> impl Condition<Pod> {
     Condition::not(is_pod_active())
 }
 
+/// Returns true if the Endpoints have ready addresses, none of which belongs to the Pod.
+/// For the `-active` Service this means that the endpoints moved to the new leader.
+#[must_use]
+pub fn is_endpoints_moved_from(pod: String) -> impl Condition<Endpoints> {
+    move |obj: Option<&Endpoints>| {
+        if let Some(endpoints) = &obj {
+            let targets: Vec<_> = endpoints
+                .subsets
+                .iter()
+                .flatten()
+                .flat_map(|s| s.addresses.iter().flatten())
+                .map(|a| a.target_ref.as_ref().and_then(|t| t.name.as_deref()))
+                .collect();
+
+            return !targets.is_empty() && targets.iter().all(|t| *t != Some(pod.as_str()));
+        }
+        false
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use http::{Request, Response};
@@ -145,17 +351,21 @@ mod tests {
     use k8s_openapi::{
         api::{
             apps::v1::{StatefulSet, StatefulSetStatus},
-            core::v1::Pod,
+            core::v1::{EndpointAddress, EndpointSubset, Endpoints, ObjectReference, Pod},
         },
         apimachinery::pkg::apis::meta::v1::WatchEvent,
         List,
     };
-    use kube::{client::Body, Api, Client, ResourceExt};
+    use kube::{client::Body, runtime::wait::Condition, Api, Client, ResourceExt};
     use serde_json::Value;
     use tokio_util::sync::CancellationToken;
     use tower_test::mock::{self, Handle};
 
-    use crate::is_statefulset_ready;
+    use crate::{
+        await_condition, image_pull_failure, is_endpoints_moved_from, is_pod_failing_image_pull,
+        is_statefulset_ready, statefulset_container_has_version, statefulset_has_version, wait_for,
+        KubeTuning, WaitPolicy, WaitTimedOut,
+    };
 
     async fn mock_get_pod(handle: &mut Handle<Request<Body>, Response<Body>>) {
         let (request, send) = handle.next_request().await.expect("Service not called");
@@ -456,4 +666,269 @@ uri.as_str(),
 
         spawned.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn wait_for_fails_after_the_timeout_of_the_policy() {
+        let (mock_service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let api: Api<StatefulSet> =
+            Api::default_namespaced(Client::new(mock_service, "vault-mgmt-e2e"));
+
+        let cancel = CancellationToken::new();
+        let cloned_token = cancel.clone();
+
+        let spawned = tokio::spawn(async move {
+            mock_list_sts(
+                cloned_token,
+                &mut handle,
+                &[vec![StatefulSetStatus {
+                    replicas: 1,
+                    available_replicas: Some(0),
+                    ready_replicas: Some(0),
+                    ..Default::default()
+                }]],
+            )
+            .await;
+        });
+
+        // the clone keeps the mock service open until the mock is cancelled
+        let err = wait_for(
+            api.clone(),
+            "vault-mgmt-e2e-2274",
+            is_statefulset_ready(),
+            &WaitPolicy::default().timeout(std::time::Duration::from_millis(50)),
+        )
+        .await
+        .unwrap_err();
+        cancel.cancel();
+
+        assert_eq!(
+            err.downcast_ref::<WaitTimedOut>().unwrap().to_string(),
+            "StatefulSet vault-mgmt-e2e-2274 did not fulfill the condition within 50ms"
+        );
+
+        spawned.await.unwrap();
+    }
+
+    async fn mock_failure(handle: &mut Handle<Request<Body>, Response<Body>>, code: u16) {
+        let (_, send) = handle.next_request().await.expect("Service not called");
+
+        let status = serde_json::json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "status": "Failure",
+            "message": "mocked failure",
+            "reason": "",
+            "code": code,
+        });
+        send.send_response(
+            Response::builder()
+                .status(code)
+                .body(Bytes::from(status.to_string()).into())
+                .unwrap(),
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_fails_on_errors_that_are_not_transient() {
+        let (mock_service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let api: Api<StatefulSet> =
+            Api::default_namespaced(Client::new(mock_service, "vault-mgmt-e2e"));
+
+        let spawned = tokio::spawn(async move {
+            mock_failure(&mut handle, 403).await;
+        });
+
+        let err = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            await_condition(
+                api,
+                "vault-mgmt-e2e-2274",
+                is_statefulset_ready(),
+                &KubeTuning::default(),
+            ),
+        )
+        .await
+        .expect("forbidden watches are not restarted")
+        .unwrap_err();
+        assert!(err.to_string().contains("mocked failure"), "{}", err);
+
+        spawned.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_restarts_the_watch_on_transient_errors() {
+        let (mock_service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let api: Api<StatefulSet> =
+            Api::default_namespaced(Client::new(mock_service, "vault-mgmt-e2e"));
+
+        let cancel = CancellationToken::new();
+        let cloned_token = cancel.clone();
+
+        let spawned = tokio::spawn(async move {
+            mock_failure(&mut handle, 503).await;
+            mock_list_sts(
+                cloned_token,
+                &mut handle,
+                &[vec![StatefulSetStatus {
+                    replicas: 1,
+                    available_replicas: Some(1),
+                    ready_replicas: Some(1),
+                    ..Default::default()
+                }]],
+            )
+            .await;
+        });
+
+        let tuning = KubeTuning {
+            initial_backoff: std::time::Duration::from_millis(1),
+            ..Default::default()
+        };
+        wait_for(
+            api.clone(),
+            "vault-mgmt-e2e-2274",
+            is_statefulset_ready(),
+            &WaitPolicy::default()
+                .timeout(std::time::Duration::from_secs(1))
+                .tuning(tuning),
+        )
+        .await
+        .unwrap();
+        cancel.cancel();
+
+        spawned.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wait_for_deletion_of_missing_object() {
+        let (mock_service, mut handle) = mock::pair::<Request<Body>, Response<Body>>();
+
+        let api: Api<StatefulSet> =
+            Api::default_namespaced(Client::new(mock_service, "vault-mgmt-e2e"));
+
+        let cancel = CancellationToken::new();
+        let cloned_token = cancel.clone();
+
+        let spawned = tokio::spawn(async move {
+            mock_list_sts(cloned_token, &mut handle, &[vec![]]).await;
+        });
+
+        let gone = wait_for(
+            api.clone(),
+            "vault-mgmt-e2e-2274",
+            kube::runtime::conditions::is_deleted("uid"),
+            &WaitPolicy::default().timeout(std::time::Duration::from_secs(1)),
+        )
+        .await
+        .unwrap();
+        cancel.cancel();
+
+        assert!(gone.is_none());
+
+        spawned.await.unwrap();
+    }
+
+    fn active_endpoints(pods: &[&str]) -> Endpoints {
+        Endpoints {
+            subsets: Some(vec![EndpointSubset {
+                addresses: Some(
+                    pods.iter()
+                        .map(|pod| EndpointAddress {
+                            ip: "10.0.0.1".to_string(),
+                            target_ref: Some(ObjectReference {
+                                kind: Some("Pod".to_string()),
+                                name: Some(pod.to_string()),
+                                ..Default::default()
+                            }),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                ..Default::default()
+            }]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn endpoints_moved_only_if_new_leader_is_ready() {
+        let cond = is_endpoints_moved_from("vault-0".to_string());
+
+        assert!(!cond.matches_object(None));
+        assert!(!cond.matches_object(Some(&Endpoints::default())));
+        assert!(!cond.matches_object(Some(&active_endpoints(&[]))));
+        assert!(!cond.matches_object(Some(&active_endpoints(&["vault-0"]))));
+        assert!(!cond.matches_object(Some(&active_endpoints(&["vault-0", "vault-1"]))));
+        assert!(cond.matches_object(Some(&active_endpoints(&["vault-1"]))));
+    }
+
+    #[test]
+    fn pod_failing_image_pull_is_detected() {
+        let pod = |reason: &str| -> Pod {
+            serde_json::from_value(serde_json::json!({
+                "metadata": { "name": "vault-1" },
+                "status": {
+                    "containerStatuses": [{
+                        "name": "vault",
+                        "image": "hashicorp/vault:1.99.0",
+                        "imageID": "",
+                        "ready": false,
+                        "restartCount": 0,
+                        "state": {
+                            "waiting": {
+                                "reason": reason,
+                                "message": "manifest unknown",
+                            },
+                        },
+                    }],
+                },
+            }))
+            .unwrap()
+        };
+        let cond = is_pod_failing_image_pull();
+
+        assert!(!cond.matches_object(None));
+        assert!(!cond.matches_object(Some(&pod("ContainerCreating"))));
+        assert!(cond.matches_object(Some(&pod("ImagePullBackOff"))));
+        assert_eq!(
+            image_pull_failure(&pod("ErrImagePull")),
+            Some((
+                "hashicorp/vault:1.99.0".to_string(),
+                "ErrImagePull: manifest unknown".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn statefulset_version_is_read_from_the_vault_container() {
+        let sts = |containers: serde_json::Value| -> StatefulSet {
+            serde_json::from_value(serde_json::json!({
+                "spec": {
+                    "selector": {},
+                    "serviceName": "openbao-internal",
+                    "template": { "spec": { "containers": containers } },
+                },
+            }))
+            .unwrap()
+        };
+        let openbao = sts(serde_json::json!([
+            { "name": "vault-agent", "image": "hashicorp/vault:1.14.0" },
+            { "name": "openbao", "image": "quay.io/openbao/openbao:2.0.0" },
+        ]));
+        let server = sts(serde_json::json!([
+            { "name": "fluent-bit", "image": "fluent/fluent-bit:3.0.0" },
+            { "name": "server", "image": "hashicorp/vault:1.14.0" },
+        ]));
+
+        assert!(statefulset_has_version("2.0.0".to_string()).matches_object(Some(&openbao)));
+        assert!(!statefulset_has_version("1.14.0".to_string()).matches_object(Some(&openbao)));
+        assert!(!statefulset_has_version("1.14.0".to_string()).matches_object(Some(&server)));
+        assert!(statefulset_container_has_version(
+            "1.14.0".to_string(),
+            Some("server".to_string())
+        )
+        .matches_object(Some(&server)));
+    }
 }
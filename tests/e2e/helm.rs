@@ -1,16 +1,19 @@
 use std::process::Stdio;
 use tokio::{io::AsyncWriteExt, process::Command};
+use vault_mgmt_lib::Flavor;
+
+use crate::values::ClusterValues;
+
+pub async fn add_repo(flavor: Flavor) -> anyhow::Result<String> {
+    let (repo, url) = match flavor {
+        Flavor::Vault => ("hashicorp", "https://helm.releases.hashicorp.com"),
+        Flavor::Openbao => ("openbao", "https://openbao.github.io/openbao-helm"),
+    };
 
-pub async fn add_repo() -> anyhow::Result<String> {
     let helm = which::which("helm")?;
 
     let cmd = Command::new(helm)
-        .args([
-            "repo",
-            "add",
-            "hashicorp",
-            "https://helm.releases.hashicorp.com",
-        ])
+        .args(["repo", "add", repo, url])
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -29,36 +32,22 @@ pub async fn add_repo() -> anyhow::Result<String> {
     }
 }
 
-pub async fn install_chart(
-    namespace: &str,
-    name: &str,
-    version: Option<&str>,
-) -> anyhow::Result<String> {
+pub async fn install_chart(namespace: &str, values: &ClusterValues) -> anyhow::Result<String> {
     let helm = which::which("helm")?;
 
-    let fullname_override = format!("fullnameOverride={}", name);
+    let rendered = values.render()?;
 
-    let mut args = vec![
+    let args = [
         "upgrade",
         "--install",
-        name,
-        "hashicorp/vault",
+        &values.name,
+        values.chart(),
         "--namespace",
         namespace,
         "-f",
         "-",
-        "--set",
-        &fullname_override,
     ];
 
-    let vers;
-
-    if let Some(version) = version {
-        vers = format!("server.image.tag={}", version);
-        args.push("--set");
-        args.push(&vers);
-    }
-
     let mut cmd = Command::new(helm)
         .args(args)
         .stdin(Stdio::piped())
@@ -68,10 +57,7 @@ pub async fn install_chart(
 
     let mut stdin = cmd.stdin.take().expect("failed to take stdin");
     tokio::spawn(async move {
-        stdin
-            .write_all(include_str!("helm/values.yaml").as_bytes())
-            .await
-            .unwrap();
+        stdin.write_all(rendered.as_bytes()).await.unwrap();
     });
 
     let output = cmd.wait_with_output().await?;
@@ -3,3 +3,4 @@ mod prepare;
 mod setup;
 mod show;
 mod upgrade;
+mod values;
@@ -6,7 +6,7 @@ use vault_mgmt_lib::{
     raft_configuration_all_voters, GetRaftConfiguration, InitResult, PodApi, VAULT_PORT,
 };
 
-use crate::{helm, prepare};
+use crate::{helm, prepare, values::ClusterValues};
 
 pub(crate) fn get_namespace() -> String {
     std::env::var("VAULT_MGMT_E2E_NAMESPACE").unwrap_or_else(|_| "vault-mgmt-e2e".to_string())
@@ -47,10 +47,10 @@ pub(crate) async fn setup(
     let suffix = rand::random::<u16>();
     let name = dbg!(format!("{}-{}", prefix, suffix));
 
-    helm::add_repo().await.unwrap();
-    helm::install_chart(&namespace, &name, Some(version))
-        .await
-        .unwrap();
+    let values = ClusterValues::new(&name).tag(version);
+
+    helm::add_repo(values.flavor).await.unwrap();
+    helm::install_chart(&namespace, &values).await.unwrap();
 
     let pods = Api::namespaced(client.clone(), &namespace);
     let stss = Api::namespaced(client.clone(), &namespace);
@@ -1,4 +1,4 @@
-use vault_mgmt_lib::construct_table;
+use vault_mgmt_lib::{construct_table, PodSelector, TimeFormat};
 
 use crate::setup::{setup, teardown, VAULT_VERSION_CURRENT};
 
@@ -7,7 +7,15 @@ use crate::setup::{setup, teardown, VAULT_VERSION_CURRENT};
 async fn show_succeeds() {
     let (namespace, name, pods, _, _, _) = setup("show", VAULT_VERSION_CURRENT).await;
 
-    let table = construct_table(&pods).await.unwrap();
+    let table = construct_table(
+        &pods,
+        &PodSelector::default(),
+        None,
+        None,
+        TimeFormat::Relative,
+    )
+    .await
+    .unwrap();
 
     let mut buf = Vec::new();
     table.print(&mut buf).unwrap();
@@ -6,7 +6,7 @@ use kube::{
     ResourceExt,
 };
 
-use vault_mgmt_lib::{is_pod_sealed, Unseal, VaultVersion, VAULT_PORT};
+use vault_mgmt_lib::{is_pod_sealed, Unseal, UpgradeOptions, VaultVersion, VAULT_PORT};
 
 use crate::setup::{setup, teardown, VAULT_IMAGE_NAME, VAULT_VERSION_CURRENT, VAULT_VERSION_OLD};
 
@@ -18,13 +18,12 @@ async fn upgrade_pod_succeeds_if_already_current() {
     let sts = stss.get(&name).await.unwrap();
     let pod = pods.api.get(&format!("{}-0", name)).await.unwrap();
 
-    pods.upgrade(
+    pods.upgrade_with(
         pod,
         &VaultVersion::try_from(&sts).unwrap(),
         init.root_token,
-        true,
-        false,
         &init.keys,
+        &UpgradeOptions::default(),
     )
     .await
     .unwrap();
@@ -41,13 +40,12 @@ async fn upgrade_pod_succeeds_if_already_current_with_force_upgrade() {
     let sts = stss.get(&name).await.unwrap();
     let pod = pods.api.get(&format!("{}-1", name)).await.unwrap();
 
-    pods.upgrade(
+    pods.upgrade_with(
         pod,
         &VaultVersion::try_from(&sts).unwrap(),
         init.root_token,
-        true,
-        true,
         &init.keys,
+        &UpgradeOptions::default().force_upgrade(true),
     )
     .await
     .unwrap();
@@ -96,13 +94,12 @@ async fn upgrade_pod_succeeds_if_outdated_and_standby() {
         VaultVersion::from_str(VAULT_VERSION_OLD).unwrap()
     );
 
-    pods.upgrade(
+    pods.upgrade_with(
         pod,
         &VaultVersion::try_from(&sts).unwrap(),
         init.root_token,
-        true,
-        false,
         &init.keys,
+        &UpgradeOptions::default(),
     )
     .await
     .unwrap();
@@ -157,13 +154,12 @@ async fn upgrade_pod_succeeds_if_outdated_and_active() {
         VaultVersion::from_str(VAULT_VERSION_OLD).unwrap()
     );
 
-    pods.upgrade(
+    pods.upgrade_with(
         pod,
         &VaultVersion::try_from(&sts).unwrap(),
         init.root_token,
-        true,
-        false,
         &init.keys,
+        &UpgradeOptions::default(),
     )
     .await
     .unwrap();
@@ -219,19 +215,22 @@ async fn upgrade_pod_succeeds_fails_with_missing_external_unseal() {
         VaultVersion::from_str(VAULT_VERSION_OLD).unwrap()
     );
 
-    tokio::time::timeout(
-        tokio::time::Duration::from_secs(30),
-        pods.upgrade(
+    let err = pods
+        .upgrade_with(
             pod,
             &VaultVersion::try_from(&sts).unwrap(),
             init.root_token,
-            false,
-            false,
             &init.keys,
-        ),
-    )
-    .await
-    .expect_err("upgrade should timeout");
+            &UpgradeOptions::default()
+                .should_unseal(false)
+                .unseal_timeout(std::time::Duration::from_secs(30)),
+        )
+        .await
+        .expect_err("upgrade should timeout");
+
+    assert!(err
+        .to_string()
+        .contains("to be unsealed did not finish within"));
 
     teardown(&namespace, &name).await;
 }
@@ -309,13 +308,12 @@ async fn upgrade_pod_succeeds_with_external_unseal() {
             .unwrap();
     });
 
-    pods.upgrade(
+    pods.upgrade_with(
         pod,
         &VaultVersion::try_from(&sts).unwrap(),
         init.root_token,
-        false,
-        false,
         &[],
+        &UpgradeOptions::default().should_unseal(false),
     )
     .await
     .unwrap();
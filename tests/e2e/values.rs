@@ -0,0 +1,300 @@
+use serde::Serialize;
+use vault_mgmt_lib::Flavor;
+
+/// Settings of a test cluster, rendered into the values of the helm chart
+#[derive(Clone, Debug)]
+pub(crate) struct ClusterValues {
+    pub name: String,
+    pub flavor: Flavor,
+    pub replicas: u8,
+    pub tls: bool,
+    pub image: Option<String>,
+    pub tag: Option<String>,
+}
+
+impl ClusterValues {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            flavor: Flavor::default(),
+            replicas: 3,
+            tls: false,
+            image: None,
+            tag: None,
+        }
+    }
+
+    pub fn flavor(mut self, flavor: Flavor) -> Self {
+        self.flavor = flavor;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn replicas(mut self, replicas: u8) -> Self {
+        self.replicas = replicas;
+        self
+    }
+
+    /// Serve the listener with the certificate of the secret `<name>-tls`,
+    /// which has to exist before the chart is installed
+    #[allow(dead_code)]
+    pub fn tls(mut self, tls: bool) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    #[allow(dead_code)]
+    pub fn image(mut self, image: &str) -> Self {
+        self.image = Some(image.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    /// Chart to install, e.g. `hashicorp/vault`
+    pub fn chart(&self) -> &'static str {
+        match self.flavor {
+            Flavor::Vault => "hashicorp/vault",
+            Flavor::Openbao => "openbao/openbao",
+        }
+    }
+
+    /// Name of the secret holding `tls.crt`, `tls.key` and `ca.crt`
+    pub fn tls_secret(&self) -> String {
+        format!("{}-tls", self.name)
+    }
+
+    fn data_path(&self) -> String {
+        format!("/{}/data", self.flavor.name())
+    }
+
+    fn userconfig_path(&self) -> String {
+        format!("/{}/userconfig/{}", self.flavor.name(), self.tls_secret())
+    }
+
+    fn server_config(&self) -> String {
+        let (listener_tls, scheme, leader_ca) = match self.tls {
+            true => (
+                format!(
+                    "tls_cert_file = \"{path}/tls.crt\"\n  tls_key_file = \"{path}/tls.key\"",
+                    path = self.userconfig_path()
+                ),
+                "https",
+                format!(
+                    "\n    leader_ca_cert_file = \"{}/ca.crt\"",
+                    self.userconfig_path()
+                ),
+            ),
+            false => ("tls_disable = 1".to_string(), "http", String::new()),
+        };
+
+        format!(
+            r#"ui = true
+
+log_requests_level = "trace"
+
+listener "tcp" {{
+  {listener_tls}
+  address = "[::]:8200"
+  cluster_address = "[::]:8201"
+}}
+
+storage "raft" {{
+  path = "{path}"
+
+  retry_join {{
+    auto_join = "provider=k8s label_selector=\"app.kubernetes.io/name={name},component=server,app.kubernetes.io/instance={{{{ .Release.Name }}}}\" namespace=\"{{{{ .Release.Namespace }}}}\""
+    auto_join_scheme = "{scheme}"{leader_ca}
+  }}
+}}
+
+service_registration "kubernetes" {{}}
+"#,
+            path = self.data_path(),
+            name = self.flavor.name(),
+        )
+    }
+
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut extra_volumes = vec![];
+        if self.tls {
+            extra_volumes.push(ExtraVolume {
+                r#type: "secret",
+                name: self.tls_secret(),
+            });
+        }
+
+        let values = Values {
+            fullname_override: self.name.clone(),
+            global: Global {
+                tls_disable: !self.tls,
+            },
+            server: Server {
+                affinity: "",
+                image: Image {
+                    registry: match self.flavor {
+                        Flavor::Vault => None,
+                        Flavor::Openbao => Some("quay.io"),
+                    },
+                    repository: self.image.clone(),
+                    tag: self.tag.clone(),
+                },
+                ha: Ha {
+                    enabled: true,
+                    replicas: self.replicas,
+                    raft: Raft {
+                        enabled: true,
+                        config: self.server_config(),
+                    },
+                },
+                data_storage: Enabled { enabled: false },
+                volumes: vec![Volume {
+                    name: "data",
+                    empty_dir: Empty {},
+                }],
+                volume_mounts: vec![VolumeMount {
+                    name: "data",
+                    mount_path: self.data_path(),
+                }],
+                extra_volumes,
+            },
+            injector: Enabled { enabled: false },
+        };
+
+        Ok(serde_yaml::to_string(&values)?)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Values {
+    fullname_override: String,
+    global: Global,
+    server: Server,
+    injector: Enabled,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Global {
+    tls_disable: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Server {
+    affinity: &'static str,
+    image: Image,
+    ha: Ha,
+    data_storage: Enabled,
+    volumes: Vec<Volume>,
+    volume_mounts: Vec<VolumeMount>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extra_volumes: Vec<ExtraVolume>,
+}
+
+#[derive(Serialize)]
+struct Image {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    registry: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repository: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Ha {
+    enabled: bool,
+    replicas: u8,
+    raft: Raft,
+}
+
+#[derive(Serialize)]
+struct Raft {
+    enabled: bool,
+    config: String,
+}
+
+#[derive(Serialize)]
+struct Enabled {
+    enabled: bool,
+}
+
+#[derive(Serialize)]
+struct Empty {}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Volume {
+    name: &'static str,
+    empty_dir: Empty,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VolumeMount {
+    name: &'static str,
+    mount_path: String,
+}
+
+#[derive(Serialize)]
+struct ExtraVolume {
+    r#type: &'static str,
+    name: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use vault_mgmt_lib::Flavor;
+
+    use super::ClusterValues;
+
+    #[test]
+    fn default_values_disable_tls() {
+        let values: serde_yaml::Value =
+            serde_yaml::from_str(&ClusterValues::new("test").tag("1.17.0").render().unwrap())
+                .unwrap();
+
+        assert_eq!(values["fullnameOverride"], "test");
+        assert_eq!(values["global"]["tlsDisable"], true);
+        assert_eq!(values["server"]["image"]["tag"], "1.17.0");
+        assert_eq!(values["server"]["ha"]["replicas"], 3);
+
+        let config = values["server"]["ha"]["raft"]["config"].as_str().unwrap();
+        assert!(config.contains("tls_disable = 1"));
+        assert!(config.contains("auto_join_scheme = \"http\""));
+        assert!(config.contains("app.kubernetes.io/instance={{ .Release.Name }}"));
+        assert!(values["server"].get("extraVolumes").is_none());
+    }
+
+    #[test]
+    fn tls_values_mount_the_certificate() {
+        let values: serde_yaml::Value = serde_yaml::from_str(
+            &ClusterValues::new("test")
+                .flavor(Flavor::Openbao)
+                .replicas(5)
+                .tls(true)
+                .render()
+                .unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(values["global"]["tlsDisable"], false);
+        assert_eq!(values["server"]["ha"]["replicas"], 5);
+        assert_eq!(values["server"]["image"]["registry"], "quay.io");
+        assert_eq!(values["server"]["extraVolumes"][0]["name"], "test-tls");
+        assert_eq!(
+            values["server"]["volumeMounts"][0]["mountPath"],
+            "/openbao/data"
+        );
+
+        let config = values["server"]["ha"]["raft"]["config"].as_str().unwrap();
+        assert!(config.contains("tls_cert_file = \"/openbao/userconfig/test-tls/tls.crt\""));
+        assert!(config.contains("auto_join_scheme = \"https\""));
+        assert!(config.contains("leader_ca_cert_file = \"/openbao/userconfig/test-tls/ca.crt\""));
+    }
+}